@@ -0,0 +1,6 @@
+/* QEMU's virt machine: the image is run from its flash, with data in RAM
+   after the 2 MiB OpenSBI takes. */
+MEMORY {
+    FLASH : ORIGIN = KERNEL_OFFSET + 0x20000000, LENGTH = 16M
+    RAM : ORIGIN = KERNEL_OFFSET + 0x80200000, LENGTH = 16M
+}
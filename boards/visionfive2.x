@@ -0,0 +1,8 @@
+/* The StarFive VisionFive 2 (JH7110). There's no flash to run from, so the
+   whole image is loaded into RAM where OpenSBI's payload goes, with data
+   following it. Only the UART and PLIC are driven: its other devices aren't
+   cache coherent, which `mm::dma` assumes. */
+MEMORY {
+    FLASH : ORIGIN = KERNEL_OFFSET + 0x40200000, LENGTH = 16M
+    RAM : ORIGIN = KERNEL_OFFSET + 0x41200000, LENGTH = 16M
+}
@@ -1,4 +1,41 @@
+use std::path::PathBuf;
+use std::{env, fs};
+
+/// The boards there are a memory layout for in boards/, by feature.
+const BOARDS: [&str; 2] = ["qemu-virt", "visionfive2"];
+
 fn main() {
     println!("cargo::rerun-if-changed=src/start.s");
     println!("cargo::rerun-if-changed=link.x");
+    println!("cargo::rerun-if-changed=boards");
+
+    let enabled: Vec<&str> = BOARDS
+        .into_iter()
+        .filter(|board| {
+            let feature = format!("CARGO_FEATURE_BOARD_{}", board.replace('-', "_"));
+            env::var_os(feature.to_uppercase()).is_some()
+        })
+        .collect();
+    let [board] = enabled[..] else {
+        panic!("enable exactly one board feature, not {:?}", enabled);
+    };
+
+    // The kernel's offset from physical memory, as src/mm.rs has it:
+    // the top of the Sv39 address space, or 1 GiB up in Sv32's.
+    let kernel_offset = match env::var("CARGO_CFG_TARGET_ARCH").unwrap().as_str() {
+        "riscv64" => "0xffffffc000000000",
+        "riscv32" if board == "visionfive2" => panic!("the VisionFive 2 is RV64"),
+        "riscv32" => "0x40000000",
+        arch => panic!("unsupported architecture {}", arch),
+    };
+
+    // link.x includes them as board.x and arch.x, from the output directory.
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::copy(format!("boards/{}.x", board), out.join("board.x")).unwrap();
+    fs::write(
+        out.join("arch.x"),
+        format!("KERNEL_OFFSET = {};\n", kernel_offset),
+    )
+    .unwrap();
+    println!("cargo::rustc-link-search={}", out.display());
 }
@@ -1,44 +1,64 @@
-MEMORY {
-    FLASH : ORIGIN = 0x20000000, LENGTH = 16M
-    RAM : ORIGIN = 0x80200000, LENGTH = 16M
-}
+/* The kernel is linked at KERNEL_OFFSET from its load address, which
+   build.rs sets for the target's XLEN. Must match PHYS_OFFSET in src/mm.rs. */
+INCLUDE arch.x
+
+/* The kernel is entered at its physical address, with the MMU off. */
+_start_phys = _start - KERNEL_OFFSET;
+ENTRY(_start_phys)
+
+/* Where the board has FLASH and RAM, at their addresses plus KERNEL_OFFSET.
+   build.rs copies it from boards/. */
+INCLUDE board.x
 
 SECTIONS {
-    .text ORIGIN(FLASH) : {
+    .text ORIGIN(FLASH) : AT(ORIGIN(FLASH) - KERNEL_OFFSET) {
         _stext = .;
         KEEP(*(.text.init))
         *(.text .text.*)
     } > FLASH
 
-    .rodata : ALIGN(4K) {
+    .rodata : AT(ADDR(.rodata) - KERNEL_OFFSET) ALIGN(4K) {
         _srodata = .;
         *(.srodata .srodata.*)
         *(.rodata .rodata.*)
+        *(.eh_frame .eh_frame.*)
+        /* Kernel tests, see src/ktest.rs. */
+        . = ALIGN(8);
+        _sktests = .;
+        KEEP(*(.ktests .ktests.*))
+        _ektests = .;
     } > FLASH
 
-    .data : ALIGN(8) {
-        _sidata = LOADADDR(.data);
+    .data : AT(ALIGN(LOADADDR(.rodata) + SIZEOF(.rodata), 8)) ALIGN(8) {
+        _sidata = LOADADDR(.data) + KERNEL_OFFSET;
         _sdata = .;
         PROVIDE(__global_pointer$ = . + 0x800);
         *(.sdata .sdata.* .sdata2 .sdata2.*)
         *(.data .data.*)
-        . = ALIGN(8);
+        /* Initial values of per-hart statics, see src/percpu.rs. */
+        . = ALIGN(64);
+        _spercpu = .;
+        *(.percpu .percpu.*)
+        . = ALIGN(64);
+        _epercpu = .;
         _edata = .;
-    } > RAM AT > FLASH
+    } > RAM
 
-    .bss (NOLOAD) : ALIGN(8) {
+    _eflash = _sidata + SIZEOF(.data);
+
+    .bss (NOLOAD) : AT(ADDR(.bss) - KERNEL_OFFSET) ALIGN(8) {
         _sbss = .;
         *(.sbss .sbss.*)
         *(.bss .bss.*)
         _ebss = .;
     } > RAM
 
-    .stack (NOLOAD) : ALIGN(8) {
+    .stack (NOLOAD) : AT(ADDR(.stack) - KERNEL_OFFSET) ALIGN(8) {
         . = . + 8K;
         _sstack = .;
     } > RAM
 
-    .heap (NOLOAD) : ALIGN(4K) {
+    .heap (NOLOAD) : AT(ADDR(.heap) - KERNEL_OFFSET) ALIGN(4K) {
         _sheap = .;
     } > RAM
-}
\ No newline at end of file
+}
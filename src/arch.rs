@@ -0,0 +1,61 @@
+//! RISC-V specifics that don't belong to any one subsystem, and what differs
+//! between RV64 and RV32, which is built with `--target
+//! riscv32imac-unknown-none-elf`. Registers, CSRs and addresses are `usize`
+//! throughout; the rest is here, in `mm::paging` (Sv39 or Sv32), and in the
+//! `.s` files through `asm_prelude!`.
+
+pub mod csr;
+pub mod fpu;
+pub mod insn;
+
+#[cfg(target_arch = "riscv32")]
+mod atomic64;
+
+#[cfg(target_arch = "riscv32")]
+pub use atomic64::AtomicU64;
+/// A 64-bit atomic. RV32 has none, so there it takes a lock instead.
+#[cfg(target_arch = "riscv64")]
+pub use core::sync::atomic::AtomicU64;
+
+/// The size of a register in bytes.
+pub const REGBYTES: usize = size_of::<usize>();
+
+/// Assembler definitions for the `.s` files, which are included after it:
+/// `REGBYTES`, and `REG_L` and `REG_S` to load and store a whole register,
+/// as in `REG_S ra, 1*REGBYTES(sp)`. The offset mustn't have spaces, or
+/// it's taken for more than one argument.
+#[cfg(target_arch = "riscv64")]
+macro_rules! asm_prelude {
+    () => {
+        "
+        .ifndef REGBYTES
+        .equ REGBYTES, 8
+        .macro REG_L reg, addr
+            ld \\reg, \\addr
+        .endm
+        .macro REG_S reg, addr
+            sd \\reg, \\addr
+        .endm
+        .endif
+        "
+    };
+}
+
+#[cfg(target_arch = "riscv32")]
+macro_rules! asm_prelude {
+    () => {
+        "
+        .ifndef REGBYTES
+        .equ REGBYTES, 4
+        .macro REG_L reg, addr
+            lw \\reg, \\addr
+        .endm
+        .macro REG_S reg, addr
+            sw \\reg, \\addr
+        .endm
+        .endif
+        "
+    };
+}
+
+pub(crate) use asm_prelude;
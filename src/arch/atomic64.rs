@@ -0,0 +1,30 @@
+//! `AtomicU64` for RV32, which has no 64-bit atomics: the value is behind a
+//! lock, taken with interrupts disabled as the timer uses them from its
+//! interrupt. Orderings are all as strong as the lock's.
+
+use core::sync::atomic::Ordering;
+
+use crate::sync::SpinLockIrqSave;
+
+pub struct AtomicU64(SpinLockIrqSave<u64>);
+
+impl AtomicU64 {
+    pub const fn new(value: u64) -> Self {
+        Self(SpinLockIrqSave::new(value))
+    }
+
+    pub fn load(&self, _order: Ordering) -> u64 {
+        *self.0.lock()
+    }
+
+    pub fn store(&self, value: u64, _order: Ordering) {
+        *self.0.lock() = value;
+    }
+
+    pub fn fetch_add(&self, value: u64, _order: Ordering) -> u64 {
+        let mut guard = self.0.lock();
+        let old = *guard;
+        *guard = old.wrapping_add(value);
+        old
+    }
+}
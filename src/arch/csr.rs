@@ -0,0 +1,259 @@
+//! Typed access to the supervisor CSRs, so that `asm!` for them lives in one
+//! place.
+//!
+//! Each CSR has a module with `read`, and unless it's read-only, `write`,
+//! `set` and `clear`. `set` and `clear` only touch the given bits and return
+//! the previous value. Writes are unsafe since they can change how the hart
+//! traps, translates addresses or accesses memory.
+
+use core::ops::{BitAnd, BitOr, Not};
+
+/// A CSR value type, converted to and from the raw bits.
+pub trait Bits: Copy {
+    fn from_bits(bits: usize) -> Self;
+    fn bits(self) -> usize;
+}
+
+impl Bits for usize {
+    fn from_bits(bits: usize) -> Self {
+        bits
+    }
+
+    fn bits(self) -> usize {
+        self
+    }
+}
+
+macro_rules! bitfield {
+    ($(#[$attr:meta])* $name:ident { $($(#[$flag_attr:meta])* $flag:ident = $bit:expr,)* }) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+        pub struct $name(usize);
+
+        impl $name {
+            $($(#[$flag_attr])* pub const $flag: Self = Self(1 << $bit);)*
+
+            #[allow(unused)]
+            pub const fn contains(self, other: Self) -> bool {
+                self.0 & other.0 == other.0
+            }
+        }
+
+        impl Bits for $name {
+            fn from_bits(bits: usize) -> Self {
+                Self(bits)
+            }
+
+            fn bits(self) -> usize {
+                self.0
+            }
+        }
+
+        impl BitOr for $name {
+            type Output = Self;
+            fn bitor(self, rhs: Self) -> Self {
+                Self(self.0 | rhs.0)
+            }
+        }
+
+        impl BitAnd for $name {
+            type Output = Self;
+            fn bitand(self, rhs: Self) -> Self {
+                Self(self.0 & rhs.0)
+            }
+        }
+
+        impl Not for $name {
+            type Output = Self;
+            fn not(self) -> Self {
+                Self(!self.0)
+            }
+        }
+    };
+}
+
+bitfield! {
+    /// Fields of `sstatus`.
+    Sstatus {
+        /// Interrupts are enabled.
+        SIE = 1,
+        /// Interrupts were enabled before the last trap.
+        SPIE = 5,
+        /// The last trap was taken from S-mode.
+        SPP = 8,
+    }
+}
+
+impl Sstatus {
+    /// The state of the floating-point registers, a two-bit field that's
+    /// zero when they're off, then initial, clean, and all set when dirty.
+    pub const FS: Self = Self(3 << 13);
+    pub const FS_CLEAN: Self = Self(2 << 13);
+    /// The state of the vector registers, as for `FS`.
+    pub const VS: Self = Self(3 << 9);
+    pub const VS_CLEAN: Self = Self(2 << 9);
+}
+
+bitfield! {
+    /// Supervisor interrupts, as bits of `sie` and `sip`.
+    Interrupts {
+        SOFTWARE = 1,
+        TIMER = 5,
+        EXTERNAL = 9,
+    }
+}
+
+bitfield! {
+    /// Counters U-mode can read, as bits of `scounteren`.
+    Counteren {
+        /// `cycle`.
+        CY = 0,
+        /// `time`.
+        TM = 1,
+        /// `instret`.
+        IR = 2,
+    }
+}
+
+impl Counteren {
+    /// The counter CSR numbered `csr`, from `cycle` at 0xc00 to
+    /// `hpmcounter31`.
+    pub const fn for_csr(csr: u16) -> Self {
+        Self(1 << (csr - 0xc00))
+    }
+}
+
+macro_rules! csr {
+    ($(#[$attr:meta])* $name:ident: $ty:ty) => {
+        $(#[$attr])*
+        #[allow(unused)]
+        pub mod $name {
+            use core::arch::asm;
+
+            use super::*;
+
+            pub fn read() -> $ty {
+                let bits: usize;
+                // SAFETY: reading this CSR has no side effects.
+                unsafe { asm!(concat!("csrr {}, ", stringify!($name)), out(reg) bits) };
+                <$ty>::from_bits(bits)
+            }
+
+            /// SAFETY: see the module documentation.
+            pub unsafe fn write(value: $ty) {
+                asm!(concat!("csrw ", stringify!($name), ", {}"), in(reg) value.bits());
+            }
+
+            /// SAFETY: see the module documentation.
+            pub unsafe fn set(value: $ty) -> $ty {
+                let bits: usize;
+                asm!(
+                    concat!("csrrs {}, ", stringify!($name), ", {}"),
+                    out(reg) bits,
+                    in(reg) value.bits(),
+                );
+                <$ty>::from_bits(bits)
+            }
+
+            /// SAFETY: see the module documentation.
+            pub unsafe fn clear(value: $ty) -> $ty {
+                let bits: usize;
+                asm!(
+                    concat!("csrrc {}, ", stringify!($name), ", {}"),
+                    out(reg) bits,
+                    in(reg) value.bits(),
+                );
+                <$ty>::from_bits(bits)
+            }
+        }
+    };
+}
+
+csr!(sstatus: Sstatus);
+csr!(
+    /// Enabled interrupts.
+    sie: Interrupts
+);
+csr!(
+    /// Pending interrupts. Only `SOFTWARE` can be cleared by S-mode.
+    sip: Interrupts
+);
+csr!(
+    /// The trap vector base address, with the mode in the low bits.
+    stvec: usize
+);
+csr!(
+    /// Address translation mode, ASID and root page table.
+    satp: usize
+);
+csr!(
+    /// The counters U-mode can read.
+    scounteren: Counteren
+);
+csr!(sscratch: usize);
+csr!(sepc: usize);
+csr!(scause: usize);
+csr!(stval: usize);
+
+/// The read-only `time` CSR, which on RV32 has its top half in `timeh`.
+pub mod time {
+    use core::arch::asm;
+
+    #[cfg(target_arch = "riscv64")]
+    pub fn read() -> u64 {
+        let time: u64;
+        // SAFETY: reading the time has no side effects.
+        unsafe { asm!("rdtime {}", out(reg) time) };
+        time
+    }
+
+    #[cfg(target_arch = "riscv32")]
+    pub fn read() -> u64 {
+        loop {
+            let (high, low, again): (u32, u32, u32);
+            // SAFETY: as above.
+            unsafe {
+                asm!(
+                    "rdtimeh {}",
+                    "rdtime {}",
+                    "rdtimeh {}",
+                    out(reg) high,
+                    out(reg) low,
+                    out(reg) again,
+                )
+            };
+            // Unless the bottom half carried into the top in between.
+            if high == again {
+                return u64::from(high) << 32 | u64::from(low);
+            }
+        }
+    }
+}
+
+/// The Sstc timer compare register, which on RV32 has its top half in
+/// `stimecmph`. They're named by number, as the assembler only knows the
+/// names with Sstc enabled.
+pub mod stimecmp {
+    use core::arch::asm;
+
+    /// SAFETY: see the module documentation, and Sstc must be present.
+    #[cfg(target_arch = "riscv64")]
+    pub unsafe fn write(value: u64) {
+        asm!("csrw 0x14d, {}", in(reg) value);
+    }
+
+    /// SAFETY: as above.
+    #[cfg(target_arch = "riscv32")]
+    pub unsafe fn write(value: u64) {
+        // Taking the low half to the maximum first, so that no value in
+        // between is earlier than both the old and new times.
+        asm!(
+            "csrw 0x14d, {max}",
+            "csrw 0x15d, {high}",
+            "csrw 0x14d, {low}",
+            max = in(reg) usize::MAX,
+            high = in(reg) (value >> 32) as usize,
+            low = in(reg) value as usize,
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! The floating-point and vector registers of user threads. The kernel is
+//! built without F, D or V, so the registers only ever hold a thread's
+//! values, and they're left in place over traps.
+//!
+//! Whether a thread may use them, and whether it has changed them, is in
+//! `sstatus.FS` and `sstatus.VS`. Threads start with both off, and the
+//! illegal instruction their first use traps with gives them an `ExtState`
+//! to save them in (see `task::set_ext_state`). From then on they're only
+//! saved when switching away from a thread that has dirtied them, and only
+//! loaded when switching to one whose registers aren't the hart's already.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::arch::csr::{self, Bits, Sstatus};
+use crate::dtb::{DeviceTree, DtNode};
+use crate::{ktest, log_info};
+
+global_asm!(concat!(crate::arch::asm_prelude!(), include_str!("fpu.s")));
+
+extern "C" {
+    fn fpu_save(regs: *mut FpRegs);
+    fn fpu_restore(regs: *const FpRegs);
+    fn vector_save(regs: *mut u8, csrs: *mut VectorCsrs);
+    fn vector_restore(regs: *const u8, csrs: *const VectorCsrs);
+}
+
+/// Whether every hart has F and D.
+static HAS_FPU: AtomicBool = AtomicBool::new(false);
+
+/// The size of a vector register in bytes, or zero if not every hart has V.
+static VLENB: AtomicUsize = AtomicUsize::new(0);
+
+/// The layout must match fpu.s.
+#[repr(C)]
+#[derive(Clone)]
+struct FpRegs {
+    f: [u64; 32],
+    fcsr: u32,
+}
+
+/// The layout must match fpu.s.
+#[repr(C)]
+#[derive(Clone)]
+struct VectorCsrs {
+    vstart: usize,
+    vl: usize,
+    vtype: usize,
+    vcsr: usize,
+}
+
+#[derive(Clone)]
+struct VectorState {
+    /// `v0` to `v31`, `vlenb` bytes each.
+    regs: Box<[u8]>,
+    csrs: VectorCsrs,
+}
+
+/// A thread's floating-point registers, and its vector registers if the
+/// harts have them.
+#[derive(Clone)]
+pub struct ExtState {
+    fp: FpRegs,
+    vector: Option<VectorState>,
+}
+
+impl ExtState {
+    /// Zeroed registers, as a program starts with. `vtype` is invalid until
+    /// the program sets it.
+    pub fn new() -> Box<Self> {
+        let vlenb = VLENB.load(Ordering::Relaxed);
+        Box::new(Self {
+            fp: FpRegs {
+                f: [0; 32],
+                fcsr: 0,
+            },
+            vector: (vlenb != 0).then(|| VectorState {
+                regs: vec![0; 32 * vlenb].into_boxed_slice(),
+                csrs: VectorCsrs {
+                    vstart: 0,
+                    vl: 0,
+                    vtype: 1 << (usize::BITS - 1),
+                    vcsr: 0,
+                },
+            }),
+        })
+    }
+
+    /// Saves whichever registers `sstatus` says are dirty, and marks them
+    /// clean.
+    ///
+    /// SAFETY: the hart's registers must be this state's, loaded by
+    /// `restore` and since only changed by its thread. Interrupts must be
+    /// disabled, so that the thread can't be switched away from meanwhile.
+    pub unsafe fn save(&mut self) {
+        let sstatus = csr::sstatus::read();
+        if sstatus.contains(Sstatus::FS) {
+            fpu_save(&mut self.fp);
+            csr::sstatus::clear(Sstatus::FS);
+            csr::sstatus::set(Sstatus::FS_CLEAN);
+        }
+        if let Some(vector) = &mut self.vector {
+            if sstatus.contains(Sstatus::VS) {
+                vector_save(vector.regs.as_mut_ptr(), &mut vector.csrs);
+                csr::sstatus::clear(Sstatus::VS);
+                csr::sstatus::set(Sstatus::VS_CLEAN);
+            }
+        }
+    }
+
+    /// Loads the registers, and marks them clean.
+    ///
+    /// SAFETY: interrupts must be disabled, and the hart's registers then
+    /// belong to this state's thread.
+    pub unsafe fn restore(&self) {
+        csr::sstatus::set(Sstatus::FS);
+        fpu_restore(&self.fp);
+        csr::sstatus::clear(Sstatus::FS);
+        csr::sstatus::set(Sstatus::FS_CLEAN);
+        if let Some(vector) = &self.vector {
+            csr::sstatus::set(Sstatus::VS);
+            vector_restore(vector.regs.as_ptr(), &vector.csrs);
+            csr::sstatus::clear(Sstatus::VS);
+            csr::sstatus::set(Sstatus::VS_CLEAN);
+        }
+    }
+}
+
+/// Whether user threads can have floating-point registers.
+pub fn supported() -> bool {
+    HAS_FPU.load(Ordering::Relaxed)
+}
+
+/// Whether a thread trapping with `sstatus` has its floating-point or
+/// vector registers off, so that an illegal instruction might be its first
+/// use of them.
+pub fn is_off(sstatus: usize) -> bool {
+    let sstatus = Sstatus::from_bits(sstatus);
+    let vector = VLENB.load(Ordering::Relaxed) != 0;
+    supported()
+        && ((sstatus & Sstatus::FS).bits() == 0 || vector && (sstatus & Sstatus::VS).bits() == 0)
+}
+
+/// Turns the registers off on this hart, for a thread that doesn't have
+/// any, so it can't read another's.
+pub fn disable() {
+    // SAFETY: the kernel doesn't use the registers.
+    unsafe { csr::sstatus::clear(Sstatus::FS | Sstatus::VS) };
+}
+
+/// Marks the registers clean on this hart, for the thread they belong to
+/// being switched back to without them having been saved since.
+///
+/// SAFETY: the registers must hold the thread's values, as last saved or
+/// restored.
+pub unsafe fn mark_clean() {
+    csr::sstatus::clear(Sstatus::FS | Sstatus::VS);
+    let vector = VLENB.load(Ordering::Relaxed) != 0;
+    csr::sstatus::set(if vector {
+        Sstatus::FS_CLEAN | Sstatus::VS_CLEAN
+    } else {
+        Sstatus::FS_CLEAN
+    });
+}
+
+/// Copies the hart's `FS` and `VS` into `sstatus`, from the frame a thread
+/// is about to return to U-mode with, as they may have changed while it
+/// was in the kernel.
+pub fn update_frame(sstatus: &mut usize) {
+    let live = csr::sstatus::read() & (Sstatus::FS | Sstatus::VS);
+    *sstatus = (Sstatus::from_bits(*sstatus) & !(Sstatus::FS | Sstatus::VS) | live).bits();
+}
+
+/// Whether `cpu`'s ISA has the single-letter extension `letter`.
+fn has_extension(cpu: &DtNode<'_>, letter: char) -> bool {
+    if let Some(mut extensions) = cpu.prop_string_list("riscv,isa-extensions") {
+        return extensions.any(|ext| ext.len() == 1 && ext.starts_with(letter));
+    }
+    let Some(isa) = cpu.prop_str("riscv,isa") else {
+        return false;
+    };
+    let base = isa.split('_').next().unwrap_or("");
+    let Some(letters) = base.strip_prefix("rv64").or(base.strip_prefix("rv32")) else {
+        return false;
+    };
+    letters.contains(letter) || "imafd".contains(letter) && letters.contains('g')
+}
+
+/// Finds out whether every hart listed under `/cpus` has F and D, and V,
+/// so that user threads can use them.
+pub fn init(dt: &DeviceTree<'_>) {
+    let mut cpus = dt
+        .find_node("/cpus")
+        .into_iter()
+        .flat_map(|cpus| cpus.children())
+        .filter(|node| node.prop_str("device_type") == Some("cpu"))
+        .filter(DtNode::is_enabled)
+        .peekable();
+    if cpus.peek().is_none() {
+        return;
+    }
+    let (mut fpu, mut vector) = (true, true);
+    for cpu in cpus {
+        fpu &= has_extension(&cpu, 'f') && has_extension(&cpu, 'd');
+        vector &= has_extension(&cpu, 'v');
+    }
+    HAS_FPU.store(fpu, Ordering::Relaxed);
+    if fpu && vector {
+        // SAFETY: `vlenb` (0xc22, named only with V) can only be read with
+        // the vector unit on, and it's turned off again right after.
+        let vlenb = unsafe {
+            csr::sstatus::set(Sstatus::VS_CLEAN);
+            let vlenb: usize;
+            asm!("csrr {}, 0xc22", out(reg) vlenb);
+            disable();
+            vlenb
+        };
+        VLENB.store(vlenb, Ordering::Relaxed);
+    }
+    log_info!(
+        target: "fpu",
+        "user floating point: {}, vector registers: {} bytes",
+        fpu,
+        VLENB.load(Ordering::Relaxed)
+    );
+}
+
+ktest! {
+    fn parses_isa_extensions() {
+        use crate::dtb::{FdtBuilder, FdtNode};
+
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.root_mut();
+        root.add_child(FdtNode::new("g")).set_str("riscv,isa", "rv64gc_zicsr");
+        root.add_child(FdtNode::new("v")).set_str("riscv,isa", "rv32imafdcv");
+        root.add_child(FdtNode::new("int")).set_str("riscv,isa", "rv64imac_zfh");
+        let list = root.add_child(FdtNode::new("list"));
+        list.set_str("riscv,isa", "rv64imac");
+        list.set_string_list("riscv,isa-extensions", &["i", "m", "f", "d", "zve32x"]);
+        root.add_child(FdtNode::new("none"));
+
+        let blob = fdt.to_aligned_blob();
+        let dt = blob.parse().unwrap();
+        let has = |node: &str, letter| has_extension(&dt.root_node().child(node).unwrap(), letter);
+
+        assert!(has("g", 'f') && has("g", 'd') && !has("g", 'v'));
+        assert!(has("v", 'd') && has("v", 'v'));
+        assert!(!has("int", 'f') && !has("int", 'z'));
+        // The list is preferred, and only single letters count.
+        assert!(has("list", 'f') && !has("list", 'c') && !has("list", 'z'));
+        assert!(!has("none", 'i'));
+    }
+}
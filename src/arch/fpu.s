@@ -0,0 +1,77 @@
+.section .text
+.global fpu_save
+.global fpu_restore
+.global vector_save
+.global vector_restore
+
+# The kernel is built without F, D or V, so they're enabled just here.
+.option push
+.option arch, +d, +v
+
+# Saves f0-f31 and fcsr to the FpRegs at a0. The layout must match
+# src/arch/fpu.rs.
+.balign 4
+fpu_save:
+    .irp n, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    fsd f\n, \n*8(a0)
+    .endr
+    frcsr t0
+    sw t0, 32*8(a0)
+    ret
+
+# Loads f0-f31 and fcsr from the FpRegs at a0.
+.balign 4
+fpu_restore:
+    .irp n, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    fld f\n, \n*8(a0)
+    .endr
+    lw t0, 32*8(a0)
+    fscsr t0
+    ret
+
+# Saves v0-v31 to the 32*vlenb bytes at a0, and vstart, vl, vtype and vcsr
+# to the VectorCsrs at a1.
+.balign 4
+vector_save:
+    csrr t0, vstart
+    REG_S t0, 0*REGBYTES(a1)
+    csrr t0, vl
+    REG_S t0, 1*REGBYTES(a1)
+    csrr t0, vtype
+    REG_S t0, 2*REGBYTES(a1)
+    csrr t0, vcsr
+    REG_S t0, 3*REGBYTES(a1)
+
+    # t0 = the bytes in a group of eight registers
+    vsetvli t0, zero, e8, m8, ta, ma
+    vs8r.v v0, (a0)
+    add a0, a0, t0
+    vs8r.v v8, (a0)
+    add a0, a0, t0
+    vs8r.v v16, (a0)
+    add a0, a0, t0
+    vs8r.v v24, (a0)
+    ret
+
+# Loads what vector_save saved. vstart goes last, as vsetvl clears it.
+.balign 4
+vector_restore:
+    vsetvli t0, zero, e8, m8, ta, ma
+    vl8re8.v v0, (a0)
+    add a0, a0, t0
+    vl8re8.v v8, (a0)
+    add a0, a0, t0
+    vl8re8.v v16, (a0)
+    add a0, a0, t0
+    vl8re8.v v24, (a0)
+
+    REG_L t0, 1*REGBYTES(a1)
+    REG_L t1, 2*REGBYTES(a1)
+    vsetvl zero, t0, t1
+    REG_L t0, 3*REGBYTES(a1)
+    csrw vcsr, t0
+    REG_L t0, 0*REGBYTES(a1)
+    csrw vstart, t0
+    ret
+
+.option pop
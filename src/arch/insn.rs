@@ -0,0 +1,126 @@
+//! Decoding just enough of an instruction to tell where execution goes
+//! after it, for stepping by breakpoints, as RISC-V has no single-step
+//! below M-mode.
+
+use crate::ktest;
+
+/// `ebreak` and `c.ebreak`.
+pub const EBREAK: u32 = 0x0010_0073;
+pub const C_EBREAK: u16 = 0x9002;
+
+/// The length of the instruction whose first halfword is `low`: 2 for a
+/// compressed instruction and 4 otherwise.
+pub fn len(low: u16) -> usize {
+    if low & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+fn sign_extend(value: u32, bits: u32) -> usize {
+    let shift = 32 - bits;
+    ((value << shift) as i32 >> shift) as isize as usize
+}
+
+/// `x<n>` in `regs`, where `x0` is always zero.
+pub fn reg(regs: &[usize; 32], n: u32) -> usize {
+    match n {
+        0 => 0,
+        n => regs[n as usize],
+    }
+}
+
+/// The address of the instruction executed after `inst`, of length `len`,
+/// at `pc`, given the registers `regs`, `x0` to `x31`.
+pub fn next_pc(regs: &[usize; 32], pc: usize, inst: u32, len: usize) -> usize {
+    let bits = |inst: u32, hi: u32, lo: u32| (inst >> lo) & ((1 << (hi - lo + 1)) - 1);
+    match (len, inst & 0x7f) {
+        // jal
+        (4, 0x6f) => {
+            let imm = bits(inst, 31, 31) << 20
+                | bits(inst, 19, 12) << 12
+                | bits(inst, 20, 20) << 11
+                | bits(inst, 30, 21) << 1;
+            pc.wrapping_add(sign_extend(imm, 21))
+        }
+        // jalr
+        (4, 0x67) => reg(regs, bits(inst, 19, 15)).wrapping_add(sign_extend(inst >> 20, 12)) & !1,
+        // branches
+        (4, 0x63) => {
+            let (a, b) = (reg(regs, bits(inst, 19, 15)), reg(regs, bits(inst, 24, 20)));
+            let taken = match bits(inst, 14, 12) {
+                0 => a == b,
+                1 => a != b,
+                4 => (a as isize) < (b as isize),
+                5 => (a as isize) >= (b as isize),
+                6 => a < b,
+                7 => a >= b,
+                _ => false,
+            };
+            let imm = bits(inst, 31, 31) << 12
+                | bits(inst, 7, 7) << 11
+                | bits(inst, 30, 25) << 5
+                | bits(inst, 11, 8) << 1;
+            if taken {
+                pc.wrapping_add(sign_extend(imm, 13))
+            } else {
+                pc + 4
+            }
+        }
+        (4, _) => pc + 4,
+        _ => {
+            let (op, funct3) = (bits(inst, 1, 0), bits(inst, 15, 13));
+            let rs1 = bits(inst, 11, 7);
+            match (op, funct3) {
+                // c.j
+                (0b01, 0b101) => {
+                    let imm = bits(inst, 12, 12) << 11
+                        | bits(inst, 11, 11) << 4
+                        | bits(inst, 10, 9) << 8
+                        | bits(inst, 8, 8) << 10
+                        | bits(inst, 7, 7) << 6
+                        | bits(inst, 6, 6) << 7
+                        | bits(inst, 5, 3) << 1
+                        | bits(inst, 2, 2) << 5;
+                    pc.wrapping_add(sign_extend(imm, 12))
+                }
+                // c.beqz and c.bnez
+                (0b01, 0b110 | 0b111) => {
+                    let value = reg(regs, 8 + bits(inst, 9, 7));
+                    let imm = bits(inst, 12, 12) << 8
+                        | bits(inst, 11, 10) << 3
+                        | bits(inst, 6, 5) << 6
+                        | bits(inst, 4, 3) << 1
+                        | bits(inst, 2, 2) << 5;
+                    if (value == 0) == (funct3 == 0b110) {
+                        pc.wrapping_add(sign_extend(imm, 9))
+                    } else {
+                        pc + 2
+                    }
+                }
+                // c.jr and c.jalr
+                (0b10, 0b100) if rs1 != 0 && bits(inst, 6, 2) == 0 => reg(regs, rs1),
+                _ => pc + 2,
+            }
+        }
+    }
+}
+
+ktest! {
+    fn next_pc_follows_jumps_and_branches() {
+        let mut regs = [0; 32];
+        // jal ra, +16 and c.j -4
+        assert_eq!(next_pc(&regs, 0x1000, 0x010000ef, 4), 0x1010);
+        assert_eq!(next_pc(&regs, 0x1000, 0xbff5, 2), 0x0ffc);
+        // beq a0, a1, +8, taken and not
+        regs[11] = 1;
+        assert_eq!(next_pc(&regs, 0x1000, 0x00b50463, 4), 0x1004);
+        regs[10] = 1;
+        assert_eq!(next_pc(&regs, 0x1000, 0x00b50463, 4), 0x1008);
+        // c.jr ra, and addi, which just goes on
+        regs[1] = 0x2000;
+        assert_eq!(next_pc(&regs, 0x1000, 0x8082, 2), 0x2000);
+        assert_eq!(next_pc(&regs, 0x1000, 0x00150513, 4), 0x1004);
+    }
+}
@@ -0,0 +1,192 @@
+//! Stack backtraces, by walking the frame records that the kernel is built
+//! to keep (see `force-frame-pointers` in .cargo/config.toml).
+//!
+//! On RISC-V `fp` (`s0`) points just past a function's frame record, so its
+//! return address is one register below it and its caller's `fp` two below
+//! (`fp - 8` and `fp - 16` on RV64). start.s clears `fp` before entering
+//! Rust so the walk ends there.
+//!
+//! Addresses are resolved to function names if the root filesystem has the
+//! kernel's symbols at /boot/System.map, as written by `nm -nC annwn`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::asm;
+
+use crate::arch::REGBYTES;
+use crate::sync::Once;
+use crate::{fs, ktest, log_info, log_warn, println};
+
+/// Stop after this many frames, in case the chain is corrupt.
+const MAX_FRAMES: usize = 64;
+
+/// No kernel stack is larger than this, so a frame pointer further than this
+/// from the first one is bogus.
+const MAX_STACK_SIZE: usize = 64 << 10;
+
+const SYMBOLS_PATH: &str = "/boot/System.map";
+
+extern "C" {
+    static _stext: u8;
+    static _srodata: u8;
+}
+
+/// A function in the kernel image, from the symbol map.
+struct Symbol {
+    address: usize,
+    name: String,
+}
+
+/// The kernel's functions by address, once loaded.
+static SYMBOLS: Once<Vec<Symbol>> = Once::new();
+
+/// Parses the text symbols, in ascending order, from the output of `nm`.
+/// Other lines are skipped.
+fn parse_symbols(map: &str) -> Vec<Symbol> {
+    let mut symbols: Vec<Symbol> = map
+        .lines()
+        .filter_map(|line| {
+            let (address, rest) = line.split_once(' ')?;
+            let (kind, name) = rest.split_once(' ')?;
+            if !matches!(kind, "t" | "T" | "w" | "W") || name.is_empty() {
+                return None;
+            }
+            Some(Symbol {
+                address: usize::from_str_radix(address, 16).ok()?,
+                name: String::from(name),
+            })
+        })
+        .collect();
+    symbols.sort_by_key(|symbol| symbol.address);
+    symbols
+}
+
+/// Loads the kernel's symbols, if they're there and match the running
+/// kernel, so that backtraces name functions.
+pub fn init() {
+    let map = match fs::read_to_end(SYMBOLS_PATH) {
+        Ok(map) => map,
+        Err(_) => return,
+    };
+    let symbols = parse_symbols(&String::from_utf8_lossy(&map));
+    let stext = &raw const _stext as usize;
+    // A map of some other build would name the wrong functions.
+    if !symbols
+        .iter()
+        .any(|symbol| symbol.address == stext && symbol.name == "_stext")
+    {
+        log_warn!("{} is for a different kernel, ignoring it", SYMBOLS_PATH);
+        return;
+    }
+    log_info!("{} symbols from {}", symbols.len(), SYMBOLS_PATH);
+    SYMBOLS.call_once(|| symbols);
+}
+
+/// The function containing `addr` and the offset into it, if the symbols
+/// are loaded and `addr` is kernel code.
+fn resolve(symbols: &[Symbol], addr: usize) -> Option<(&str, usize)> {
+    let text = &raw const _stext as usize..&raw const _srodata as usize;
+    if !text.contains(&addr) {
+        return None;
+    }
+    let index = symbols
+        .partition_point(|symbol| symbol.address <= addr)
+        .checked_sub(1)?;
+    let symbol = &symbols[index];
+    Some((&symbol.name, addr - symbol.address))
+}
+
+/// The function containing `addr` and the offset into it, if the kernel's
+/// symbols are loaded.
+pub fn symbol(addr: usize) -> Option<(&'static str, usize)> {
+    resolve(SYMBOLS.get()?, addr)
+}
+
+/// Prints a frame at `addr`, which is looked up as `lookup`: a return
+/// address is just past the call, which may be the end of the function.
+fn print_frame(addr: usize, lookup: usize) {
+    match symbol(lookup) {
+        Some((name, offset)) => {
+            println!(
+                "    {:#018x} {}+{:#x}",
+                addr,
+                name,
+                offset + (addr - lookup)
+            )
+        }
+        None => println!("    {:#018x}", addr),
+    }
+}
+
+/// Prints the return addresses of the current call stack.
+#[inline(never)]
+pub fn print() {
+    let fp: usize;
+    // SAFETY: reading `s0` has no side effects.
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+    println!("backtrace:");
+    walk(fp);
+}
+
+/// Prints a backtrace of code interrupted by a trap at `pc`, with frame
+/// pointer `fp`.
+pub fn print_from(pc: usize, fp: usize) {
+    println!("backtrace:");
+    print_frame(pc, pc);
+    walk(fp);
+}
+
+fn walk(mut fp: usize) {
+    let start = fp;
+    for _ in 0..MAX_FRAMES {
+        if fp == 0 {
+            return;
+        }
+        // Frame records are aligned, and callers' frames are further up the
+        // same stack.
+        if !fp.is_multiple_of(REGBYTES) || fp < start || fp - start > MAX_STACK_SIZE {
+            println!("    (bad frame pointer {:#x})", fp);
+            return;
+        }
+        // SAFETY: `fp` points into the same stack as the first frame record.
+        let (ra, next) = unsafe {
+            (
+                *((fp - REGBYTES) as *const usize),
+                *((fp - 2 * REGBYTES) as *const usize),
+            )
+        };
+        print_frame(ra, ra.wrapping_sub(1));
+        if next != 0 && next <= fp {
+            println!("    (bad frame pointer {:#x})", next);
+            return;
+        }
+        fp = next;
+    }
+    println!("    ...");
+}
+
+ktest! {
+    fn resolves_symbols() {
+        let stext = &raw const _stext as usize;
+        let map = alloc::format!(
+            "{:016x} T _stext\n{:016x} t annwn::trap::handle\n\
+             {:016x} R RODATA\n{:016x} T <T as core::fmt::Display>::fmt\n\
+             garbage\n{:016x} W \n",
+            stext,
+            stext + 0x40,
+            stext + 0x80,
+            stext + 0x20,
+            stext + 0x60,
+        );
+        let symbols = parse_symbols(&map);
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(resolve(&symbols, stext), Some(("_stext", 0)));
+        assert_eq!(
+            resolve(&symbols, stext + 0x24),
+            Some(("<T as core::fmt::Display>::fmt", 4))
+        );
+        assert_eq!(resolve(&symbols, stext + 0x100), Some(("annwn::trap::handle", 0xc0)));
+        assert_eq!(resolve(&symbols, stext - 1), None);
+        assert_eq!(resolve(&[], stext), None);
+    }
+}
@@ -0,0 +1,193 @@
+//! Block devices, and the list of those the drivers have found.
+
+pub mod cache;
+pub mod completion;
+pub mod queue;
+pub mod ramdisk;
+pub mod writeback;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+
+use crate::fs::devfs::{self, BlockDeviceNode};
+use crate::log_error;
+use crate::sync::SpinLock;
+
+pub use completion::{IoHandle, IoRequest};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockError {
+    /// The buffer isn't a whole number of blocks.
+    Unaligned,
+    /// The blocks are past the end of the device.
+    OutOfRange,
+    ReadOnly,
+    /// The device reported an error.
+    Io,
+    /// The device didn't finish the request in time.
+    TimedOut,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unaligned => f.write_str("not a whole number of blocks"),
+            Self::OutOfRange => f.write_str("past the end of the device"),
+            Self::ReadOnly => f.write_str("read-only device"),
+            Self::Io => f.write_str("I/O error"),
+            Self::TimedOut => f.write_str("timed out"),
+        }
+    }
+}
+
+/// How soon a request should be issued, among those queued for a device.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum IoPriority {
+    Normal,
+    /// For writing back what nothing is waiting for.
+    Low,
+}
+
+/// A transfer to or from a block device, as a future.
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = Result<(), BlockError>> + Send + 'a>>;
+
+/// A device storing fixed-size blocks, numbered from 0. Reads and writes
+/// block the calling task until they're done, unless they're awaited.
+pub trait BlockDevice: Send + Sync {
+    /// The size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads the blocks starting at `start` into `buf`, whose length must be
+    /// a multiple of the block size.
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf`, whose length must be a multiple of the block size, to
+    /// the blocks starting at `start`.
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// `read_blocks`, at `priority` among the requests queued for the
+    /// device, for devices with a queue (see `queue`).
+    fn read_blocks_at(
+        &self,
+        start: u64,
+        buf: &mut [u8],
+        _priority: IoPriority,
+    ) -> Result<(), BlockError> {
+        self.read_blocks(start, buf)
+    }
+
+    /// `write_blocks`, at `priority`, as above.
+    fn write_blocks_at(
+        &self,
+        start: u64,
+        buf: &[u8],
+        _priority: IoPriority,
+    ) -> Result<(), BlockError> {
+        self.write_blocks(start, buf)
+    }
+
+    /// Starts `request` without waiting for it to be done, returning a
+    /// handle to wait for it with or to have a callback run. By default
+    /// it's carried out before returning.
+    fn submit(&self, request: IoRequest) -> IoHandle {
+        let (handle, completion) = completion::pair();
+        let checked = check_request(self, &request);
+        let IoRequest {
+            write,
+            start,
+            mut buf,
+            priority,
+        } = request;
+        let result = checked.and_then(|()| {
+            if write {
+                self.write_blocks_at(start, &buf, priority)
+            } else {
+                self.read_blocks_at(start, &mut buf, priority)
+            }
+        });
+        completion.complete(result, buf);
+        handle
+    }
+
+    /// `read_blocks` as a future, for the executor. By default it blocks
+    /// whatever polls it.
+    fn read_blocks_async<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move { self.read_blocks(start, buf) })
+    }
+
+    /// `write_blocks` as a future, as above.
+    fn write_blocks_async<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move { self.write_blocks(start, buf) })
+    }
+}
+
+/// Checks that `len` bytes starting at block `start` are whole blocks on
+/// `device`.
+pub fn check_range(device: &dyn BlockDevice, start: u64, len: usize) -> Result<(), BlockError> {
+    if !len.is_multiple_of(device.block_size()) {
+        return Err(BlockError::Unaligned);
+    }
+    let count = (len / device.block_size()) as u64;
+    match start.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Checks that `request` is for whole blocks on `device`, and isn't a
+/// write to a read-only one.
+pub fn check_request<D: BlockDevice + ?Sized>(
+    device: &D,
+    request: &IoRequest,
+) -> Result<(), BlockError> {
+    let len = request.buf.len();
+    if !len.is_multiple_of(device.block_size()) {
+        return Err(BlockError::Unaligned);
+    }
+    let count = (len / device.block_size()) as u64;
+    match request.start.checked_add(count) {
+        Some(end) if end <= device.block_count() => {}
+        _ => return Err(BlockError::OutOfRange),
+    }
+    if request.write && device.is_read_only() {
+        return Err(BlockError::ReadOnly);
+    }
+    Ok(())
+}
+
+static DEVICES: SpinLock<Vec<(String, Arc<dyn BlockDevice>)>> = SpinLock::new(Vec::new());
+
+/// Adds `device` to the list under `name`, and to `/dev`, behind a request
+/// queue.
+pub fn register(name: String, device: Arc<dyn BlockDevice>) {
+    let device: Arc<dyn BlockDevice> = queue::RequestQueue::new(device);
+    if let Err(err) = devfs::register(&name, BlockDeviceNode::new(device.clone())) {
+        log_error!("couldn't add /dev/{}: {}", name, err);
+    }
+    DEVICES.lock().push((name, device));
+}
+
+/// The device called `name`.
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(device_name, _)| device_name == name)
+        .map(|(_, device)| device.clone())
+}
+
+/// Every device, with its name.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES.lock().clone()
+}
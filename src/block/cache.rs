@@ -0,0 +1,426 @@
+//! A write-back cache of the blocks of a device, for filesystems to read and
+//! modify blocks through. Blocks are written back when they're evicted, the
+//! least recently used first, by `sync`, by the writeback task once they've
+//! been modified for long enough or too many are (see `writeback`), or when
+//! the cache is dropped.
+//!
+//! Neither writing back nor reading ahead holds up the task using the
+//! cache: an evicted block is written in the background, and waited for
+//! only if it's needed again before it's written, or by `sync`. A miss for
+//! the block after the last one missed reads the next `READ_AHEAD` blocks
+//! too, into a side table as their reads complete.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{writeback, BlockDevice, BlockError, IoHandle, IoPriority, IoRequest};
+use crate::sync::{Mutex, SpinLock, WaitQueue};
+use crate::time::Instant;
+use crate::{ktest, log_error};
+
+/// How many blocks are read ahead of a sequential miss.
+const READ_AHEAD: u64 = 8;
+
+struct Entry {
+    data: Vec<u8>,
+    /// When the block was modified, if it hasn't been written back since.
+    dirty_since: Option<Instant>,
+    /// When the block was last used, in calls to `BlockCache::get`.
+    last_used: u64,
+}
+
+struct Inner {
+    entries: BTreeMap<u64, Entry>,
+    clock: u64,
+    /// Evicted blocks being written back.
+    writing: BTreeMap<u64, IoHandle>,
+    /// The last block that wasn't cached.
+    last_miss: Option<u64>,
+}
+
+/// Blocks read ahead, `None` until their reads complete, for the callbacks
+/// to fill in.
+struct ReadAhead {
+    blocks: SpinLock<BTreeMap<u64, Option<Vec<u8>>>>,
+    /// Woken as reads complete.
+    done: WaitQueue,
+}
+
+impl ReadAhead {
+    /// Takes `block`, waiting for it if it's being read. Returns `None` if
+    /// it isn't being read ahead, or the read failed.
+    fn take(&self, block: u64) -> Option<Vec<u8>> {
+        if !self.blocks.lock().contains_key(&block) {
+            return None;
+        }
+        self.done
+            .wait_until(|| self.blocks.lock().get(&block).is_none_or(Option::is_some));
+        self.blocks.lock().remove(&block).flatten()
+    }
+}
+
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    /// How many blocks are kept.
+    capacity: usize,
+    /// Held while reading and writing the device, so that a block can't be
+    /// read in twice.
+    inner: Mutex<Inner>,
+    read_ahead: Arc<ReadAhead>,
+    /// How many blocks are modified and not yet written back, including
+    /// those being written back after they were evicted.
+    dirty: AtomicUsize,
+}
+
+/// Every cache, for `sync_all` and writeback.
+static CACHES: SpinLock<Vec<Weak<BlockCache>>> = SpinLock::new(Vec::new());
+
+impl BlockCache {
+    /// A cache of up to `capacity` blocks of `device`.
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Arc<Self> {
+        assert!(capacity > 0);
+        let cache = Arc::new(Self {
+            device,
+            capacity,
+            inner: Mutex::new(Inner {
+                entries: BTreeMap::new(),
+                clock: 0,
+                writing: BTreeMap::new(),
+                last_miss: None,
+            }),
+            read_ahead: Arc::new(ReadAhead {
+                blocks: SpinLock::new(BTreeMap::new()),
+                done: WaitQueue::new(),
+            }),
+            dirty: AtomicUsize::new(0),
+        });
+        let mut caches = CACHES.lock();
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.push(Arc::downgrade(&cache));
+        cache
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    /// Calls `f` with the contents of `block`.
+    pub fn read<R>(&self, block: u64, f: impl FnOnce(&[u8]) -> R) -> Result<R, BlockError> {
+        let mut inner = self.inner.lock();
+        let entry = self.get(&mut inner, block)?;
+        Ok(f(&entry.data))
+    }
+
+    /// Calls `f` to change the contents of `block`, which is written back
+    /// later.
+    pub fn modify<R>(&self, block: u64, f: impl FnOnce(&mut [u8]) -> R) -> Result<R, BlockError> {
+        if self.device.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        let mut inner = self.inner.lock();
+        let entry = self.get(&mut inner, block)?;
+        if entry.dirty_since.is_none() {
+            entry.dirty_since = Some(Instant::now());
+            self.add_dirty();
+        }
+        Ok(f(&mut entry.data))
+    }
+
+    /// Counts another modified block, having them written back if there
+    /// are too many.
+    fn add_dirty(&self) {
+        let dirty = self.dirty.fetch_add(1, Ordering::Relaxed) + 1;
+        if dirty * 100 >= self.capacity * writeback::dirty_ratio() {
+            writeback::wake();
+        }
+    }
+
+    /// Whether so many blocks are modified that they should all be written
+    /// back.
+    pub fn is_over_dirty_ratio(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed) * 100 >= self.capacity * writeback::dirty_ratio()
+    }
+
+    /// Reads `buf.len()` bytes starting `offset` bytes into the device.
+    pub fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let block_size = self.block_size() as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % block_size) as usize;
+            let len = (block_size as usize - start).min(buf.len() - done);
+            self.read(pos / block_size, |data| {
+                buf[done..done + len].copy_from_slice(&data[start..start + len])
+            })?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` starting `offset` bytes into the device.
+    pub fn write_bytes(&self, offset: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let block_size = self.block_size() as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % block_size) as usize;
+            let len = (block_size as usize - start).min(buf.len() - done);
+            self.modify(pos / block_size, |data| {
+                data[start..start + len].copy_from_slice(&buf[done..done + len])
+            })?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes every modified block back to the device, and waits for the
+    /// blocks being written back.
+    pub fn sync(&self) -> Result<(), BlockError> {
+        self.write_back(None)
+    }
+
+    /// Writes back the blocks modified before `before`, or every modified
+    /// block and those being written back if `None`, all at once behind
+    /// whatever else is queued for the device. Blocks that fail to be
+    /// written stay modified.
+    pub fn write_back(&self, before: Option<Instant>) -> Result<(), BlockError> {
+        let mut inner = self.inner.lock();
+        let mut writes: Vec<_> = match before {
+            Some(_) => Vec::new(),
+            None => core::mem::take(&mut inner.writing).into_iter().collect(),
+        };
+        for (&block, entry) in inner.entries.iter_mut() {
+            let Some(since) = entry.dirty_since else {
+                continue;
+            };
+            if before.is_some_and(|before| since >= before) {
+                continue;
+            }
+            let request =
+                IoRequest::write(block, entry.data.clone()).with_priority(IoPriority::Low);
+            writes.push((block, self.device.submit(request)));
+            entry.dirty_since = None;
+        }
+        let mut result = Ok(());
+        for (block, write) in writes {
+            let (written, data) = write.wait();
+            if written.is_ok() {
+                self.dirty.fetch_sub(1, Ordering::Relaxed);
+            } else if let Some(entry) = inner.entries.get_mut(&block) {
+                entry.dirty_since.get_or_insert_with(Instant::now);
+            } else {
+                // It was evicted, so there's nowhere else to keep it.
+                let dirty = Entry {
+                    data,
+                    dirty_since: Some(Instant::now()),
+                    last_used: 0,
+                };
+                inner.entries.insert(block, dirty);
+            }
+            result = result.and(written);
+        }
+        result
+    }
+
+    /// The entry for `block`, reading it in and evicting another if it isn't
+    /// cached.
+    fn get<'a>(&self, inner: &'a mut Inner, block: u64) -> Result<&'a mut Entry, BlockError> {
+        inner.clock += 1;
+        let now = inner.clock;
+        if !inner.entries.contains_key(&block) {
+            if inner.entries.len() >= self.capacity {
+                self.evict(inner);
+            }
+            let (data, dirty) = self.fetch(inner, block)?;
+            inner.entries.insert(
+                block,
+                Entry {
+                    data,
+                    dirty_since: dirty.then(Instant::now),
+                    last_used: now,
+                },
+            );
+        }
+        let entry = inner.entries.get_mut(&block).unwrap();
+        entry.last_used = now;
+        Ok(entry)
+    }
+
+    /// The contents of `block`, which isn't cached, and whether they're
+    /// newer than what's on the device, from its write back if it's being
+    /// written back, from reading ahead if it's been read ahead, or read
+    /// now.
+    fn fetch(&self, inner: &mut Inner, block: u64) -> Result<(Vec<u8>, bool), BlockError> {
+        let sequential = inner.last_miss == block.checked_sub(1);
+        inner.last_miss = Some(block);
+        if let Some(write) = inner.writing.remove(&block) {
+            let (result, data) = write.wait();
+            if result.is_ok() {
+                self.dirty.fetch_sub(1, Ordering::Relaxed);
+            }
+            return Ok((data, result.is_err()));
+        }
+        if let Some(data) = self.read_ahead.take(block) {
+            if sequential {
+                self.read_ahead(inner, block);
+            }
+            return Ok((data, false));
+        }
+        let mut data = vec![0; self.block_size()];
+        self.device.read_blocks(block, &mut data)?;
+        if sequential {
+            self.read_ahead(inner, block);
+        }
+        Ok((data, false))
+    }
+
+    /// Starts reading the blocks after `block` that aren't cached or being
+    /// read already.
+    fn read_ahead(&self, inner: &Inner, block: u64) {
+        let end = (block + 1 + READ_AHEAD).min(self.device.block_count());
+        for next in block + 1..end {
+            if inner.entries.contains_key(&next) || inner.writing.contains_key(&next) {
+                continue;
+            }
+            let mut blocks = self.read_ahead.blocks.lock();
+            if blocks.contains_key(&next) {
+                continue;
+            }
+            blocks.insert(next, None);
+            drop(blocks);
+            let read_ahead = self.read_ahead.clone();
+            let request = IoRequest::read(next, self.block_size()).with_priority(IoPriority::Low);
+            self.device
+                .submit(request)
+                .on_complete(move |result, data| {
+                    let mut blocks = read_ahead.blocks.lock();
+                    match result {
+                        Ok(()) => blocks.insert(next, Some(data)),
+                        Err(_) => blocks.remove(&next),
+                    };
+                    drop(blocks);
+                    read_ahead.done.wake_all();
+                });
+        }
+    }
+
+    /// Drops the least recently used block, starting to write it back if it
+    /// has been modified.
+    fn evict(&self, inner: &mut Inner) {
+        let done: Vec<u64> = inner
+            .writing
+            .iter()
+            .filter(|(_, write)| write.is_done())
+            .map(|(&block, _)| block)
+            .collect();
+        for block in done {
+            self.dirty.fetch_sub(1, Ordering::Relaxed);
+            if let (Err(err), _) = inner.writing.remove(&block).unwrap().wait() {
+                log_error!("failed to write back block {}: {}", block, err);
+            }
+        }
+
+        let Some(&block) = inner
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(block, _)| block)
+        else {
+            return;
+        };
+        let entry = inner.entries.remove(&block).unwrap();
+        if entry.dirty_since.is_some() {
+            let write = self.device.submit(IoRequest::write(block, entry.data));
+            inner.writing.insert(block, write);
+        }
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            log_error!("failed to sync block cache: {}", err);
+        }
+    }
+}
+
+/// Every cache.
+pub fn all() -> Vec<Arc<BlockCache>> {
+    CACHES.lock().iter().filter_map(Weak::upgrade).collect()
+}
+
+/// Writes back the modified blocks of every cache. Errors are logged, and
+/// the first returned once every cache has been tried.
+pub fn sync_all() -> Result<(), BlockError> {
+    let mut result = Ok(());
+    for cache in all() {
+        if let Err(err) = cache.sync() {
+            log_error!("failed to sync block cache: {}", err);
+            result = result.and(Err(err));
+        }
+    }
+    result
+}
+
+ktest! {
+    fn writes_back_blocks_modified_before() {
+        use super::ramdisk::RamDisk;
+
+        let disk = RamDisk::new(16 * 512);
+        let cache = BlockCache::new(disk.clone(), 16);
+        let on_disk = |block| {
+            let mut buf = [0; 512];
+            disk.read_blocks(block, &mut buf).unwrap();
+            buf[0]
+        };
+        cache.modify(1, |data| data[0] = 1).unwrap();
+        let modified = Instant::now();
+        while Instant::now() <= modified {
+            core::hint::spin_loop();
+        }
+        let before = Instant::now();
+        cache.modify(2, |data| data[0] = 2).unwrap();
+        assert_eq!(cache.dirty.load(Ordering::Relaxed), 2);
+
+        cache.write_back(Some(before)).unwrap();
+        assert_eq!((on_disk(1), on_disk(2)), (1, 0));
+        assert_eq!(cache.dirty.load(Ordering::Relaxed), 1);
+        cache.sync().unwrap();
+        assert_eq!((on_disk(1), on_disk(2)), (1, 2));
+        assert_eq!(cache.dirty.load(Ordering::Relaxed), 0);
+    }
+}
+
+ktest! {
+    fn evicts_least_recently_used() {
+        use super::ramdisk::RamDisk;
+
+        let disk = RamDisk::new(8 * 512);
+        let cache = BlockCache::new(disk.clone(), 2);
+        let cached = || cache.inner.lock().entries.keys().copied().collect::<Vec<_>>();
+        cache.modify(0, |data| data[0] = 1).unwrap();
+        cache.read(1, |_| ()).unwrap();
+        cache.read(0, |_| ()).unwrap();
+        cache.read(3, |_| ()).unwrap();
+        assert_eq!(cached(), [0, 3]);
+
+        // Evicting the modified block writes it back, and reading it again
+        // gets it from the write.
+        cache.read(4, |_| ()).unwrap();
+        assert_eq!(cached(), [3, 4]);
+        assert_eq!(cache.dirty.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.read(0, |data| data[0]).unwrap(), 1);
+        assert_eq!(cached(), [0, 4]);
+        assert_eq!(cache.dirty.load(Ordering::Relaxed), 0);
+        let mut buf = [0; 512];
+        disk.read_blocks(0, &mut buf).unwrap();
+        assert_eq!(buf[0], 1);
+    }
+}
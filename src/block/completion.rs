@@ -0,0 +1,182 @@
+//! Block I/O that doesn't wait: `BlockDevice::submit` starts an `IoRequest`
+//! and returns an `IoHandle` for it, to wait for later, from a task or a
+//! future, or to have a callback run when it's done. Either way the request's
+//! buffer is handed back with the result, read into for a read.
+//!
+//! Callbacks run wherever the request completes, on the executor for a
+//! queued device, so mustn't block.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{BlockError, IoPriority};
+use crate::ktest;
+use crate::sync::{SpinLock, WaitQueue};
+
+/// What a callback is given: the result, and the request's buffer.
+pub type Callback = Box<dyn FnOnce(Result<(), BlockError>, Vec<u8>) + Send>;
+
+/// A read or write of whole blocks, owning its buffer.
+pub struct IoRequest {
+    pub write: bool,
+    pub start: u64,
+    pub buf: Vec<u8>,
+    pub priority: IoPriority,
+}
+
+impl IoRequest {
+    /// A read of `len` bytes of blocks starting at `start`.
+    pub fn read(start: u64, len: usize) -> Self {
+        Self {
+            write: false,
+            start,
+            buf: vec![0; len],
+            priority: IoPriority::Normal,
+        }
+    }
+
+    /// A write of `buf` to the blocks starting at `start`.
+    pub fn write(start: u64, buf: Vec<u8>) -> Self {
+        Self {
+            write: true,
+            start,
+            buf,
+            priority: IoPriority::Normal,
+        }
+    }
+
+    pub fn with_priority(self, priority: IoPriority) -> Self {
+        Self { priority, ..self }
+    }
+}
+
+enum Status {
+    /// Not done yet, with the callback to run when it is, if any.
+    Pending(Option<Callback>),
+    Done(Result<(), BlockError>, Vec<u8>),
+    /// Done, and the result handed out.
+    Taken,
+}
+
+struct Shared {
+    status: SpinLock<Status>,
+    /// Woken when the request is done.
+    done: WaitQueue,
+}
+
+/// The submitter's side of a request.
+pub struct IoHandle(Arc<Shared>);
+
+/// The device's side of a request, to complete it with.
+pub struct Completion(Arc<Shared>);
+
+/// A handle for a new request, and what completes it.
+pub fn pair() -> (IoHandle, Completion) {
+    let shared = Arc::new(Shared {
+        status: SpinLock::new(Status::Pending(None)),
+        done: WaitQueue::new(),
+    });
+    (IoHandle(shared.clone()), Completion(shared))
+}
+
+impl IoHandle {
+    pub fn is_done(&self) -> bool {
+        matches!(*self.0.status.lock(), Status::Done(..))
+    }
+
+    /// Takes the result and buffer, if the request is done.
+    fn take(&self) -> Option<(Result<(), BlockError>, Vec<u8>)> {
+        let mut status = self.0.status.lock();
+        if !matches!(*status, Status::Done(..)) {
+            return None;
+        }
+        match core::mem::replace(&mut *status, Status::Taken) {
+            Status::Done(result, buf) => Some((result, buf)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Blocks until the request is done.
+    pub fn wait(self) -> (Result<(), BlockError>, Vec<u8>) {
+        let mut taken = None;
+        self.0.done.wait_until(|| {
+            taken = self.take();
+            taken.is_some()
+        });
+        taken.unwrap()
+    }
+
+    /// `wait` as a future.
+    pub async fn wait_async(self) -> (Result<(), BlockError>, Vec<u8>) {
+        let mut taken = None;
+        self.0
+            .done
+            .until(|| {
+                taken = self.take();
+                taken.is_some()
+            })
+            .await;
+        taken.unwrap()
+    }
+
+    /// Has `callback` run once the request is done, right away if it is.
+    pub fn on_complete(
+        self,
+        callback: impl FnOnce(Result<(), BlockError>, Vec<u8>) + Send + 'static,
+    ) {
+        let mut status = self.0.status.lock();
+        if let Status::Pending(pending) = &mut *status {
+            *pending = Some(Box::new(callback));
+            return;
+        }
+        drop(status);
+        let (result, buf) = self.take().unwrap();
+        callback(result, buf);
+    }
+}
+
+impl Completion {
+    /// Finishes the request with `result`, handing back its buffer.
+    pub fn complete(self, result: Result<(), BlockError>, buf: Vec<u8>) {
+        let mut status = self.0.status.lock();
+        let Status::Pending(callback) = core::mem::replace(&mut *status, Status::Taken) else {
+            unreachable!("block request completed twice");
+        };
+        match callback {
+            Some(callback) => {
+                drop(status);
+                callback(result, buf);
+            }
+            None => {
+                *status = Status::Done(result, buf);
+                drop(status);
+                self.0.done.wake_all();
+            }
+        }
+    }
+}
+
+ktest! {
+    fn completions_reach_waiters_and_callbacks() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        let (handle, completion) = pair();
+        assert!(!handle.is_done());
+        completion.complete(Ok(()), vec![1, 2]);
+        assert!(handle.is_done());
+        assert_eq!(handle.wait(), (Ok(()), vec![1, 2]));
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let (handle, completion) = pair();
+        let flag = ran.clone();
+        handle.on_complete(move |result, buf| {
+            assert_eq!((result, buf), (Err(BlockError::Io), vec![3]));
+            flag.store(true, Ordering::Relaxed);
+        });
+        assert!(!ran.load(Ordering::Relaxed));
+        completion.complete(Err(BlockError::Io), vec![3]);
+        assert!(ran.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,337 @@
+//! A request queue in front of each registered block device, so that what
+//! filesystems ask of it is issued in a better order and in fewer, larger
+//! transfers than one synchronous request at a time.
+//!
+//! Requests wait in the queue until one of `DEPTH` slots is free, and are
+//! then issued by the executor, which lets a device like virtio-blk work on
+//! several at once. The next request is the one of the highest priority
+//! nearest past the last one issued, sweeping across the device and
+//! wrapping around to its start. Requests of the same kind and priority
+//! for the blocks right after it are merged into the same transfer, of up
+//! to `MAX_MERGE` bytes, through a buffer of its own. A busy queue of
+//! normal priority requests keeps low priority ones waiting.
+//!
+//! A transfer the device hasn't finished within `TIMEOUT` fails its
+//! requests, though it holds on to its slot until the device is done.
+//!
+//! Requests are submitted as `IoRequest`s, with their own buffers (see
+//! `completion`); blocking reads and writes copy to and from one.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::Poll;
+
+use super::completion::{self, Completion};
+use super::{BlockDevice, BlockError, BlockFuture, IoHandle, IoPriority, IoRequest};
+use crate::sync::{SpinLock, WaitQueue};
+use crate::time::{Duration, Instant, Timer};
+use crate::{executor, ktest, log_error};
+
+/// How many transfers are issued to the device at once.
+const DEPTH: usize = 16;
+
+/// The largest transfer requests are merged into.
+const MAX_MERGE: usize = 128 * 1024;
+
+/// How long a transfer may take before its requests fail.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A request waiting to be issued.
+struct Queued {
+    request: IoRequest,
+    completion: Completion,
+}
+
+#[derive(Default)]
+struct State {
+    /// The requests not yet issued, in the order they can be.
+    pending: BTreeMap<(IoPriority, u64, u64), Queued>,
+    /// How many transfers have been issued and aren't done.
+    in_flight: usize,
+    /// The block after the last transfer issued.
+    head: u64,
+    next_seq: u64,
+}
+
+impl State {
+    fn push(&mut self, request: IoRequest, completion: Completion) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let key = (request.priority, request.start, seq);
+        let queued = Queued {
+            request,
+            completion,
+        };
+        self.pending.insert(key, queued);
+    }
+
+    /// Takes the next requests to issue as one transfer, starting at the
+    /// nearest request of the highest priority past `head`.
+    fn next_batch(&mut self, block_size: usize) -> Option<Vec<Queued>> {
+        let priority = self.pending.keys().next()?.0;
+        let key = self
+            .pending
+            .range((priority, self.head, 0)..)
+            .next()
+            .map(|(&key, _)| key)
+            .filter(|key| key.0 == priority)
+            .unwrap_or_else(|| *self.pending.keys().next().unwrap());
+        let first = self.pending.remove(&key).unwrap();
+        let write = first.request.write;
+        let mut len = first.request.buf.len();
+        let mut end = first.request.start + (len / block_size) as u64;
+        let mut batch = vec![first];
+        while let Some((&key, next)) = self.pending.range((priority, end, 0)..).next() {
+            let next_len = next.request.buf.len();
+            if key.0 != priority
+                || key.1 != end
+                || next.request.write != write
+                || len + next_len > MAX_MERGE
+            {
+                break;
+            }
+            batch.push(self.pending.remove(&key).unwrap());
+            len += next_len;
+            end += (next_len / block_size) as u64;
+        }
+        self.head = end;
+        Some(batch)
+    }
+}
+
+pub struct RequestQueue {
+    device: Arc<dyn BlockDevice>,
+    /// The queue itself, for the futures it spawns.
+    this: Weak<Self>,
+    state: SpinLock<State>,
+}
+
+impl RequestQueue {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            device,
+            this: this.clone(),
+            state: SpinLock::new(State::default()),
+        })
+    }
+
+    /// Issues the next transfers while there are free slots.
+    fn dispatch(&self) {
+        let Some(this) = self.this.upgrade() else {
+            return;
+        };
+        let block_size = self.device.block_size();
+        let mut state = self.state.lock();
+        while state.in_flight < DEPTH {
+            let Some(batch) = state.next_batch(block_size) else {
+                break;
+            };
+            state.in_flight += 1;
+            executor::spawn(this.clone().issue(batch));
+        }
+    }
+
+    /// Carries out `batch` as one transfer, failing its requests if it
+    /// takes longer than `TIMEOUT`.
+    async fn issue(self: Arc<Self>, batch: Vec<Queued>) {
+        let (mut requests, completions): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .map(|queued| (queued.request, queued.completion))
+            .unzip();
+        let lens: Vec<usize> = requests.iter().map(|request| request.buf.len()).collect();
+        let (start, write) = (requests[0].start, requests[0].write);
+        let wake = Arc::new(WaitQueue::new());
+        let timer = Timer::start(Instant::now() + TIMEOUT, {
+            let wake = wake.clone();
+            move || wake.wake_all()
+        });
+
+        let mut completions = Some(completions);
+        let result = {
+            let mut transfer = pin!(self.transfer(&mut requests));
+            let mut expired = pin!(wake.until(|| timer.has_fired()));
+            let first = poll_fn(|cx| match transfer.as_mut().poll(cx) {
+                Poll::Ready(result) => Poll::Ready(Some(result)),
+                Poll::Pending => expired.as_mut().poll(cx).map(|()| None),
+            })
+            .await;
+            match first {
+                Some(result) => result,
+                None => {
+                    log_error!(
+                        target: "block",
+                        "{} of {} bytes at block {} timed out",
+                        if write { "write" } else { "read" },
+                        lens.iter().sum::<usize>(),
+                        start
+                    );
+                    let completions = completions.take().unwrap();
+                    for (completion, &len) in completions.into_iter().zip(&lens) {
+                        completion.complete(Err(BlockError::TimedOut), vec![0; len]);
+                    }
+                    transfer.await
+                }
+            }
+        };
+
+        self.state.lock().in_flight -= 1;
+        if let Some(completions) = completions {
+            for (completion, request) in completions.into_iter().zip(requests) {
+                completion.complete(result, request.buf);
+            }
+        }
+        self.dispatch();
+    }
+
+    /// Carries out `requests`, for adjacent blocks and of the same kind, as
+    /// one transfer.
+    async fn transfer(&self, requests: &mut [IoRequest]) -> Result<(), BlockError> {
+        if let [request] = requests {
+            if request.write {
+                self.device
+                    .write_blocks_async(request.start, &request.buf)
+                    .await
+            } else {
+                self.device
+                    .read_blocks_async(request.start, &mut request.buf)
+                    .await
+            }
+        } else {
+            self.transfer_merged(requests).await
+        }
+    }
+
+    /// Carries out `requests` through a buffer for all of them.
+    async fn transfer_merged(&self, requests: &mut [IoRequest]) -> Result<(), BlockError> {
+        let start = requests[0].start;
+        if requests[0].write {
+            let bounce: Vec<u8> = requests
+                .iter()
+                .flat_map(|request| request.buf.iter().copied())
+                .collect();
+            self.device.write_blocks_async(start, &bounce).await
+        } else {
+            let len = requests.iter().map(|request| request.buf.len()).sum();
+            let mut bounce = vec![0; len];
+            self.device.read_blocks_async(start, &mut bounce).await?;
+            let mut offset = 0;
+            for request in requests {
+                let len = request.buf.len();
+                request.buf.copy_from_slice(&bounce[offset..offset + len]);
+                offset += len;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl BlockDevice for RequestQueue {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.read_blocks_at(start, buf, IoPriority::Normal)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.write_blocks_at(start, buf, IoPriority::Normal)
+    }
+
+    fn read_blocks_at(
+        &self,
+        start: u64,
+        buf: &mut [u8],
+        priority: IoPriority,
+    ) -> Result<(), BlockError> {
+        let request = IoRequest::read(start, buf.len()).with_priority(priority);
+        let (result, data) = self.submit(request).wait();
+        result?;
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write_blocks_at(
+        &self,
+        start: u64,
+        buf: &[u8],
+        priority: IoPriority,
+    ) -> Result<(), BlockError> {
+        let request = IoRequest::write(start, buf.to_vec()).with_priority(priority);
+        self.submit(request).wait().0
+    }
+
+    fn read_blocks_async<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            let (result, data) = self
+                .submit(IoRequest::read(start, buf.len()))
+                .wait_async()
+                .await;
+            result?;
+            buf.copy_from_slice(&data);
+            Ok(())
+        })
+    }
+
+    fn write_blocks_async<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            let request = IoRequest::write(start, buf.to_vec());
+            self.submit(request).wait_async().await.0
+        })
+    }
+
+    fn submit(&self, request: IoRequest) -> IoHandle {
+        let (handle, completion) = completion::pair();
+        if let Err(err) = super::check_request(self, &request) {
+            completion.complete(Err(err), request.buf);
+            return handle;
+        }
+        self.state.lock().push(request, completion);
+        self.dispatch();
+        handle
+    }
+}
+
+ktest! {
+    fn adjacent_requests_merge_by_priority() {
+        let mut state = State::default();
+        let mut queue = |write, start, blocks: usize, priority| {
+            let request = IoRequest {
+                write,
+                start,
+                buf: vec![0; blocks * 512],
+                priority,
+            };
+            state.push(request, completion::pair().1);
+        };
+        queue(false, 10, 2, IoPriority::Normal);
+        queue(false, 12, 1, IoPriority::Normal);
+        queue(true, 13, 1, IoPriority::Normal);
+        queue(false, 2, 1, IoPriority::Normal);
+        queue(false, 13, 1, IoPriority::Low);
+        queue(false, 50, 1, IoPriority::Normal);
+
+        state.head = 14;
+        let mut batches = Vec::new();
+        while let Some(batch) = state.next_batch(512) {
+            let starts: Vec<u64> = batch.iter().map(|queued| queued.request.start).collect();
+            batches.push(starts);
+        }
+        // The sweep from the head, wrapping around, with the adjacent reads
+        // merged but not the write, then low.
+        assert_eq!(batches, [vec![50], vec![2], vec![10, 12], vec![13], vec![13]]);
+    }
+}
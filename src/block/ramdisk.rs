@@ -0,0 +1,225 @@
+//! RAM disks: block devices kept in memory, for scratch space and for
+//! testing filesystems and the block cache without a disk.
+//!
+//! `ramdisk=<size>` on the command line, with an optional `K`, `M` or `G`
+//! suffix, registers an empty one as `ram0`. `ramdisk=initrd` copies the
+//! initrd into it instead, which is then a disk image rather than the
+//! initramfs. A list, such as `ramdisk=initrd,64M`, registers `ram0`,
+//! `ram1` and so on, one for each. Memory is only allocated for pages that
+//! are written to; the rest read as zeroes.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{BlockDevice, BlockError};
+use crate::dtb::DeviceTree;
+use crate::mm::frame::FRAME_SIZE;
+use crate::mm::phys_to_virt;
+use crate::sync::SpinLock;
+use crate::{cmdline, ktest, log_error, log_info};
+
+const BLOCK_SIZE: usize = 512;
+
+type Page = [u8; FRAME_SIZE];
+
+pub struct RamDisk {
+    /// `None` for pages that have never been written.
+    pages: SpinLock<Vec<Option<Box<Page>>>>,
+    blocks: u64,
+}
+
+impl RamDisk {
+    /// An empty disk of `size` bytes, rounded up to a whole block.
+    pub fn new(size: usize) -> Arc<Self> {
+        let mut pages = Vec::new();
+        pages.resize_with(size.div_ceil(FRAME_SIZE), || None);
+        Arc::new(Self {
+            pages: SpinLock::new(pages),
+            blocks: size.div_ceil(BLOCK_SIZE) as u64,
+        })
+    }
+
+    /// A disk holding a copy of `image`.
+    pub fn from_image(image: &[u8]) -> Result<Arc<Self>, BlockError> {
+        let disk = Self::new(image.len());
+        let mut buf = image.to_vec();
+        buf.resize(buf.len().next_multiple_of(BLOCK_SIZE), 0);
+        disk.write_blocks(0, &buf)?;
+        Ok(disk)
+    }
+
+    /// Calls `f` with each page touched by `len` bytes at `offset`, the
+    /// offset into it and the range of the buffer it's for.
+    fn for_each_page(
+        &self,
+        offset: usize,
+        len: usize,
+        mut f: impl FnMut(&mut Option<Box<Page>>, usize, core::ops::Range<usize>),
+    ) {
+        let mut pages = self.pages.lock();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let start = pos % FRAME_SIZE;
+            let chunk = (FRAME_SIZE - start).min(len - done);
+            f(&mut pages[pos / FRAME_SIZE], start, done..done + chunk);
+            done += chunk;
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_range(self, start, buf.len())?;
+        self.for_each_page(
+            start as usize * BLOCK_SIZE,
+            buf.len(),
+            |page, offset, range| {
+                let len = range.len();
+                match page {
+                    Some(page) => buf[range].copy_from_slice(&page[offset..offset + len]),
+                    None => buf[range].fill(0),
+                }
+            },
+        );
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        super::check_range(self, start, buf.len())?;
+        self.for_each_page(
+            start as usize * BLOCK_SIZE,
+            buf.len(),
+            |page, offset, range| {
+                let page = page.get_or_insert_with(|| Box::new([0; FRAME_SIZE]));
+                page[offset..offset + range.len()].copy_from_slice(&buf[range]);
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Parses a size in bytes, with an optional `K`, `M` or `G` suffix.
+fn parse_size(s: &str) -> Option<usize> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+/// The disks `ramdisk=` asks for, in order.
+fn args() -> impl Iterator<Item = &'static str> {
+    cmdline::get("ramdisk")
+        .into_iter()
+        .flat_map(|arg| arg.split(','))
+}
+
+/// Whether `ramdisk=initrd` has taken the initrd for a RAM disk, so that
+/// it isn't unpacked as the initramfs.
+pub fn holds_initrd() -> bool {
+    args().any(|arg| arg == "initrd")
+}
+
+/// Registers the disks the command line asks for.
+pub fn init(dt: &DeviceTree<'_>) {
+    let mut count = 0;
+    for arg in args() {
+        let Some(disk) = new_disk(dt, arg) else {
+            continue;
+        };
+        let name = format!("ram{}", count);
+        log_info!(
+            target: "ramdisk",
+            "{}: {} KiB",
+            name,
+            disk.block_count() as usize * BLOCK_SIZE / 1024
+        );
+        super::register(name, disk);
+        count += 1;
+    }
+}
+
+/// A disk as `arg` of `ramdisk=` describes it.
+fn new_disk(dt: &DeviceTree<'_>, arg: &str) -> Option<Arc<RamDisk>> {
+    if arg == "initrd" {
+        let Some(initrd) = dt.chosen().and_then(|chosen| chosen.initrd) else {
+            log_error!(target: "ramdisk", "ramdisk=initrd, but there's no initrd");
+            return None;
+        };
+        let start = phys_to_virt(initrd.start as usize) as *const u8;
+        let len = (initrd.end - initrd.start) as usize;
+        // SAFETY: the initrd is in RAM, which is all mapped, and the frame
+        // allocator never hands it out.
+        let image = unsafe { core::slice::from_raw_parts(start, len) };
+        match RamDisk::from_image(image) {
+            Ok(disk) => Some(disk),
+            Err(err) => {
+                log_error!(target: "ramdisk", "couldn't copy the initrd: {}", err);
+                None
+            }
+        }
+    } else {
+        match parse_size(arg) {
+            Some(size) if size > 0 => Some(RamDisk::new(size)),
+            _ => {
+                log_error!(target: "ramdisk", "invalid size {:?}", arg);
+                None
+            }
+        }
+    }
+}
+
+ktest! {
+    fn reads_back_writes() {
+        use crate::block::cache::BlockCache;
+
+        assert_eq!(parse_size("16M"), Some(16 << 20));
+        assert_eq!(parse_size("3k"), Some(3 << 10));
+        assert_eq!(parse_size("1000"), Some(1000));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("12Q"), None);
+
+        let disk = RamDisk::new(3 * FRAME_SIZE + 100);
+        assert_eq!(disk.block_count(), 25);
+        let mut buf = alloc::vec![0xff; 2 * BLOCK_SIZE];
+        disk.read_blocks(7, &mut buf).unwrap();
+        assert!(buf.iter().all(|&byte| byte == 0));
+
+        // Across the end of the first page.
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect();
+        disk.write_blocks(7, &data).unwrap();
+        let mut back = alloc::vec![0; 3 * BLOCK_SIZE];
+        disk.read_blocks(7, &mut back).unwrap();
+        assert_eq!(back, data);
+
+        assert_eq!(disk.read_blocks(24, &mut buf), Err(BlockError::OutOfRange));
+        assert_eq!(disk.write_blocks(0, &buf[..100]), Err(BlockError::Unaligned));
+
+        let cache = BlockCache::new(disk.clone(), 2);
+        cache.write_bytes(10, b"cached").unwrap();
+        cache.write_bytes(24 * BLOCK_SIZE as u64, b"end").unwrap();
+        cache.sync().unwrap();
+        disk.read_blocks(0, &mut buf[..BLOCK_SIZE]).unwrap();
+        assert_eq!(&buf[10..16], b"cached");
+        disk.read_blocks(24, &mut buf[..BLOCK_SIZE]).unwrap();
+        assert_eq!(&buf[..3], b"end");
+
+        let image = RamDisk::from_image(b"image").unwrap();
+        assert_eq!(image.block_count(), 1);
+        image.read_blocks(0, &mut buf[..BLOCK_SIZE]).unwrap();
+        assert_eq!(&buf[..6], b"image\0");
+    }
+}
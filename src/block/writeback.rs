@@ -0,0 +1,96 @@
+//! Background writeback of the block caches, so that modified blocks reach
+//! their devices without waiting for eviction or `sync`.
+//!
+//! Every `writeback_interval=<ms>` (5 s by default; 0 turns writeback off)
+//! the writeback task writes back the blocks modified more than
+//! `dirty_expire=<ms>` ago (30 s by default). A cache with more than
+//! `dirty_ratio=<percent>` of its blocks modified (20 by default) wakes it
+//! early, and has all of them written back.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use super::cache;
+use crate::arch::AtomicU64;
+use crate::sync::WaitQueue;
+use crate::task::{self, Priority};
+use crate::time::{Instant, Timer};
+use crate::{cmdline, log_warn, percpu};
+
+static INTERVAL_MS: AtomicU64 = AtomicU64::new(5000);
+static EXPIRE_MS: AtomicU64 = AtomicU64::new(30_000);
+static DIRTY_RATIO: AtomicUsize = AtomicUsize::new(20);
+
+/// Set to have the writeback task run now.
+static WOKEN: AtomicBool = AtomicBool::new(false);
+static WAKE: WaitQueue = WaitQueue::new();
+
+/// Reads the controls from the command line and starts the writeback task
+/// on this hart, once it runs tasks.
+pub fn init() {
+    parse("writeback_interval", |value| {
+        INTERVAL_MS.store(value, Ordering::Relaxed)
+    });
+    parse("dirty_expire", |value| {
+        EXPIRE_MS.store(value, Ordering::Relaxed)
+    });
+    parse("dirty_ratio", |value| match value {
+        1..=100 => DIRTY_RATIO.store(value as usize, Ordering::Relaxed),
+        _ => log_warn!(target: "writeback", "invalid dirty_ratio {}", value),
+    });
+    if INTERVAL_MS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    if task::spawn_on(percpu::hart_id(), Priority::Normal, writeback).is_none() {
+        log_warn!(target: "writeback", "no memory for the writeback task");
+    }
+}
+
+fn parse(key: &str, set: impl FnOnce(u64)) {
+    let Some(value) = cmdline::get(key) else {
+        return;
+    };
+    match value.parse() {
+        Ok(value) => set(value),
+        Err(_) => log_warn!(target: "writeback", "invalid {} {:?}", key, value),
+    }
+}
+
+/// The percentage of a cache's blocks that can be modified before they're
+/// all written back.
+pub fn dirty_ratio() -> usize {
+    DIRTY_RATIO.load(Ordering::Relaxed)
+}
+
+/// Has the writeback task run now rather than at its next interval.
+pub fn wake() {
+    if !WOKEN.swap(true, Ordering::Relaxed) {
+        WAKE.wake_all();
+    }
+}
+
+fn writeback() {
+    let interval = Duration::from_millis(INTERVAL_MS.load(Ordering::Relaxed));
+    let expire = Duration::from_millis(EXPIRE_MS.load(Ordering::Relaxed));
+    loop {
+        let timer = Timer::start(Instant::now() + interval, || WAKE.wake_all());
+        WAKE.wait_until(|| WOKEN.load(Ordering::Relaxed) || timer.has_fired());
+        WOKEN.store(false, Ordering::Relaxed);
+        drop(timer);
+
+        let now = Instant::now();
+        let expired = (now.since_boot() >= expire).then(|| now - expire);
+        for cache in cache::all() {
+            let result = if cache.is_over_dirty_ratio() {
+                cache.write_back(None)
+            } else if let Some(expired) = expired {
+                cache.write_back(Some(expired))
+            } else {
+                continue;
+            };
+            if let Err(err) = result {
+                log_warn!(target: "writeback", "failed to write back blocks: {}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! The kernel command line, taken from `bootargs` in `/chosen` (set by
+//! QEMU's `-append`).
+//!
+//! The command line is a whitespace separated list of `key=value` pairs and
+//! bare `flag`s. Values may be double-quoted to include whitespace.
+
+use crate::ktest;
+use crate::sync::Once;
+
+static CMDLINE: Once<&'static str> = Once::new();
+
+/// Sets the command line. Called once at boot, before any lookups.
+pub fn init(cmdline: &'static str) {
+    CMDLINE.call_once(|| cmdline);
+}
+
+/// Returns the whole command line, or the empty string if there is none.
+pub fn as_str() -> &'static str {
+    CMDLINE.get().copied().unwrap_or("")
+}
+
+/// Returns the value of the last `key=value` parameter named `key`, or `""`
+/// if `key` is given as a bare flag.
+pub fn get(key: &str) -> Option<&'static str> {
+    params()
+        .filter(|&(k, _)| k == key)
+        .last()
+        .map(|(_, value)| value.unwrap_or(""))
+}
+
+/// Returns true if `flag` is present, with or without a value.
+pub fn has(flag: &str) -> bool {
+    params().any(|(k, _)| k == flag)
+}
+
+/// Iterates over the parameters as `(key, value)` pairs, in order.
+pub fn params() -> Params {
+    Params { rest: as_str() }
+}
+
+pub struct Params {
+    rest: &'static str,
+}
+
+impl Iterator for Params {
+    type Item = (&'static str, Option<&'static str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+
+        let mut in_quotes = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                c.is_whitespace() && !in_quotes
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let (param, rest) = rest.split_at(end);
+        self.rest = rest;
+
+        Some(match param.split_once('=') {
+            Some((key, value)) => (key, Some(unquote(value))),
+            None => (param, None),
+        })
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+ktest! {
+    fn parses_odd_command_lines() {
+        let parse =
+            |cmdline: &'static str| Params { rest: cmdline }.collect::<alloc::vec::Vec<_>>();
+        assert_eq!(parse(""), []);
+        assert_eq!(parse(" \t\n "), []);
+        assert_eq!(
+            parse("  quiet\tlog=debug\nroot=/dev/vda "),
+            [("quiet", None), ("log", Some("debug")), ("root", Some("/dev/vda"))]
+        );
+        assert_eq!(parse("a=b=c =x y="), [("a", Some("b=c")), ("", Some("x")), ("y", Some(""))]);
+        assert_eq!(
+            parse(r#"init="/bin/sh -l" x="" q=""#),
+            [("init", Some("/bin/sh -l")), ("x", Some("")), ("q", Some("\""))]
+        );
+        // An unterminated quote runs to the end, and is kept.
+        assert_eq!(parse(r#"a="b c d"#), [("a", Some("\"b c d"))]);
+        assert_eq!(parse(r#"" a""#), [("\" a\"", None)]);
+    }
+}
@@ -0,0 +1,57 @@
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{io, print};
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+/// Ctrl-U, which erases the whole line.
+const KILL_LINE: u8 = 0x15;
+/// Ctrl-W, which erases the last word.
+const ERASE_WORD: u8 = 0x17;
+
+/// The last line ended at a carriage return, so a newline straight after it
+/// is the rest of a CRLF rather than an empty line.
+static AFTER_CR: AtomicBool = AtomicBool::new(false);
+
+/// Reads a line of input from the console, echoing it back. Backspace erases
+/// the last character, Ctrl-W the last word and Ctrl-U the whole line; the
+/// line ends at a carriage return, newline or both, which aren't included.
+pub fn read_line() -> String {
+    let mut line = String::new();
+    loop {
+        let byte = io::wait_byte();
+        let after_cr = AFTER_CR.swap(byte == b'\r', Ordering::Relaxed);
+        match byte {
+            b'\n' if after_cr => {}
+            b'\r' | b'\n' => {
+                print!("\n");
+                return line;
+            }
+            BACKSPACE | DELETE if !line.is_empty() => {
+                let len = line.len() - 1;
+                erase(&mut line, len);
+            }
+            KILL_LINE => erase(&mut line, 0),
+            ERASE_WORD => {
+                let word = line.trim_end_matches(' ');
+                let start = word.rfind(' ').map_or(0, |space| space + 1);
+                erase(&mut line, start);
+            }
+            // Only printable ASCII is accepted, so each byte is a character.
+            0x20..=0x7e => {
+                line.push(byte as char);
+                print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Erases `line` from `len` characters on, and from the screen.
+fn erase(line: &mut String, len: usize) {
+    for _ in len..line.len() {
+        print!("\x08 \x08");
+    }
+    line.truncate(len);
+}
@@ -0,0 +1,162 @@
+//! Core dumps of user processes, taken when one is killed by a signal
+//! whose default action dumps core, such as the `SIGSEGV` of a fault it
+//! has no handler for. The dump is an ELF core file, as Linux writes: a
+//! `NT_PRSTATUS` note with the registers of the thread that took the
+//! signal, then a `PT_LOAD` segment for each run of pages the process had
+//! touched. GDB reads it along with the executable.
+//!
+//! Nothing is dumped unless the command line says where to:
+//!
+//! - `coredump=<dir>` writes `<dir>/core.<pid>`.
+//! - `coredump=console` prints the file to the console in hex, between
+//!   marker lines, for machines without a filesystem to write to. The
+//!   lines between the markers turn back into the file with `xxd -r -p`.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::arch::REGBYTES;
+use crate::elf::{self, CoreSegment};
+use crate::file::File;
+use crate::mm::paging::PAGE_SIZE;
+use crate::process::Process;
+use crate::syscall::Errno;
+use crate::trap::TrapFrame;
+use crate::util::align_up;
+use crate::{cmdline, fs, log_info, log_warn, println, task};
+
+const NT_PRSTATUS: u32 = 1;
+
+/// Bytes printed on each line of a dump to the console.
+const HEX_LINE: usize = 32;
+
+/// Where a dump goes.
+enum Sink {
+    File(Arc<dyn File>),
+    Console,
+}
+
+impl Sink {
+    fn write(&self, mut bytes: &[u8]) -> Result<(), Errno> {
+        match self {
+            Self::File(file) => {
+                while !bytes.is_empty() {
+                    match file.write(bytes)? {
+                        0 => return Err(Errno::EIO),
+                        n => bytes = &bytes[n..],
+                    }
+                }
+            }
+            Self::Console => {
+                let mut line = String::with_capacity(HEX_LINE * 2);
+                for chunk in bytes.chunks(HEX_LINE) {
+                    line.clear();
+                    for byte in chunk {
+                        let _ = write!(line, "{:02x}", byte);
+                    }
+                    println!("{}", line);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Dumps the core of the running process, which `signal`, taken at
+/// `frame`, is about to kill. Returns whether it was dumped.
+pub fn dump(frame: &TrapFrame, signal: u8) -> bool {
+    let Some(target) = cmdline::get("coredump") else {
+        return false;
+    };
+    let process = task::process().expect("core dump from a task without a process");
+    let pid = process.pid();
+    let (sink, path) = if target == "console" {
+        println!("----- BEGIN CORE DUMP OF PROCESS {} -----", pid);
+        (Sink::Console, String::from("the console"))
+    } else {
+        let path = format!("{}/core.{}", target.trim_end_matches('/'), pid);
+        match fs::open(&path, fs::O_WRONLY | fs::O_CREAT | fs::O_TRUNC) {
+            Ok(file) => (Sink::File(file), path),
+            Err(err) => {
+                log_warn!(target: "coredump", "can't create {}: {}", path, err);
+                return false;
+            }
+        }
+    };
+    let result = write_core(&process, frame, signal, &sink);
+    if let Sink::Console = sink {
+        println!("----- END CORE DUMP OF PROCESS {} -----", pid);
+    }
+    match result {
+        Ok(()) => {
+            log_info!(target: "coredump", "process {}: core dumped to {}", pid, path);
+            true
+        }
+        Err(err) => {
+            log_warn!(target: "coredump", "process {}: dumping to {} failed: {}", pid, path, err);
+            false
+        }
+    }
+}
+
+fn write_core(process: &Process, frame: &TrapFrame, signal: u8, sink: &Sink) -> Result<(), Errno> {
+    let space = process.address_space();
+    let segments: Vec<_> = space
+        .resident_ranges()
+        .into_iter()
+        .map(|(start, end, flags)| CoreSegment {
+            vaddr: start,
+            size: end - start,
+            flags,
+        })
+        .collect();
+    let notes = elf::note("CORE", NT_PRSTATUS, &prstatus(process, frame, signal));
+    sink.write(&elf::core_headers(notes.len(), &segments))?;
+    sink.write(&notes)?;
+
+    let mut page = alloc::vec![0; PAGE_SIZE];
+    for segment in &segments {
+        for vaddr in (segment.vaddr..segment.vaddr + segment.size).step_by(PAGE_SIZE) {
+            // Unmapped since the ranges were taken, by another thread.
+            if !space.read_resident(vaddr, &mut page) {
+                page.fill(0);
+            }
+            sink.write(&page)?;
+        }
+    }
+    Ok(())
+}
+
+/// Linux's `struct elf_prstatus` for the thread that took `signal` at
+/// `frame`. Its layout only depends on XLEN, as `long` and the registers
+/// are `REGBYTES` long: the signal, the pending and blocked signals, the
+/// process IDs, CPU times, which are left zero, then the registers as
+/// `ptrace` has them, `pc` first.
+fn prstatus(process: &Process, frame: &TrapFrame, signal: u8) -> Vec<u8> {
+    const SIGPEND: usize = 16;
+    const PID: usize = SIGPEND + 2 * REGBYTES;
+    const REGS: usize = PID + 16 + 8 * REGBYTES;
+    const SIZE: usize = REGS + 32 * REGBYTES + 4;
+
+    let mut desc = alloc::vec![0; align_up(SIZE, REGBYTES)];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        desc[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    // `si_signo`, then `pr_cursig`.
+    put(0, &u32::from(signal).to_le_bytes());
+    put(12, &u16::from(signal).to_le_bytes());
+    let (pending, blocked) = process.signals().masks();
+    put(SIGPEND, &(pending as usize).to_le_bytes());
+    put(SIGPEND + REGBYTES, &(blocked as usize).to_le_bytes());
+    let ppid = process.parent().map_or(0, |parent| parent.pid().as_raw());
+    put(PID, &(process.pid().as_raw() as u32).to_le_bytes());
+    put(PID + 4, &(ppid as u32).to_le_bytes());
+    put(REGS, &frame.sepc.to_le_bytes());
+    for (i, reg) in frame.regs.iter().enumerate().skip(1) {
+        put(REGS + i * REGBYTES, &reg.to_le_bytes());
+    }
+    desc
+}
@@ -0,0 +1,124 @@
+use core::fmt::{self, Write};
+
+use crate::dtb::DeviceTree;
+use crate::sbi::hsm::{self, HartState};
+use crate::{io, sbi, smp};
+
+/// Prints `write`'s description to the console.
+pub fn print(dt: &DeviceTree<'_>, boot_hart: usize) {
+    io::with_console(|console| write(console, dt, boot_hart)).unwrap();
+}
+
+/// Writes a description of every hart listed under `/cpus` to `out`, in the
+/// spirit of Linux's `/proc/cpuinfo`.
+pub fn write(out: &mut impl Write, dt: &DeviceTree<'_>, boot_hart: usize) -> fmt::Result {
+    let version = sbi::spec_version();
+    let impl_id = sbi::impl_id();
+    writeln!(
+        out,
+        "SBI v{}.{}, implementation: {} v{:#x}",
+        version.major,
+        version.minor,
+        sbi::impl_name(impl_id),
+        sbi::impl_version()
+    )?;
+
+    let Some(cpus) = dt.find_node("/cpus") else {
+        return writeln!(out, "cpuinfo: no /cpus node in device tree");
+    };
+    let timebase = cpus.prop_u32("timebase-frequency");
+
+    // SBI reports the ID registers of the calling hart only; harts without
+    // their own boot path yet are assumed to be identical to the boot hart.
+    let mvendorid = sbi::mvendorid();
+    let marchid = sbi::marchid();
+    let mimpid = sbi::mimpid();
+
+    let harts = cpus
+        .children()
+        .filter(|node| node.prop_str("device_type") == Some("cpu"));
+    for (processor, cpu) in harts.enumerate() {
+        let hart = cpu
+            .reg()
+            .and_then(|mut reg| reg.next())
+            .map(|reg| reg.address);
+
+        writeln!(out, "processor\t: {}", processor)?;
+        match hart {
+            Some(hart) => writeln!(out, "hart\t\t: {}", hart),
+            None => writeln!(out, "hart\t\t: unknown"),
+        }?;
+        writeln!(
+            out,
+            "isa\t\t: {}",
+            cpu.prop_str("riscv,isa").unwrap_or("unknown")
+        )?;
+        if let Some(uarch) = cpu
+            .prop_string_list("compatible")
+            .and_then(|mut c| c.next())
+        {
+            writeln!(out, "uarch\t\t: {}", uarch)?;
+        }
+        if let Some(mmu) = cpu.prop_str("mmu-type") {
+            writeln!(out, "mmu\t\t: {}", mmu.trim_start_matches("riscv,"))?;
+        }
+        writeln!(out, "mvendorid\t: {:#x}", mvendorid)?;
+        writeln!(out, "marchid\t\t: {:#x}", marchid)?;
+        writeln!(out, "mimpid\t\t: {:#x}", mimpid)?;
+
+        let clock = cpu
+            .prop_u32("clock-frequency")
+            .map(u64::from)
+            .or_else(|| cpu.prop_u64("clock-frequency"));
+        if let Some(freq) = clock {
+            writeln!(out, "clock\t\t: {} Hz", freq)?;
+        }
+        if let Some(freq) = cpu.prop_u32("timebase-frequency").or(timebase) {
+            writeln!(out, "timebase\t: {} Hz", freq)?;
+        }
+        for (name, label) in [
+            ("i-cache-size", "i-cache"),
+            ("d-cache-size", "d-cache"),
+            ("cache-size", "cache"),
+        ] {
+            if let Some(size) = cpu.prop_u32(name) {
+                writeln!(out, "{}\t\t: {} KiB", label, size / 1024)?;
+            }
+        }
+
+        let status = match hart {
+            Some(hart) => status(hart as usize, boot_hart),
+            None => "unknown",
+        };
+        let disabled = !cpu.is_enabled();
+        let boot = hart == Some(boot_hart as u64);
+        writeln!(
+            out,
+            "status\t\t: {}{}{}",
+            status,
+            if disabled { " (disabled)" } else { "" },
+            if boot { " (boot)" } else { "" }
+        )?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// What `hart` is doing now: online if it's running the kernel, otherwise
+/// what SBI says it's doing.
+fn status(hart: usize, boot_hart: usize) -> &'static str {
+    // Before `smp::init`, only the boot hart is running the kernel.
+    if hart == boot_hart || hart < smp::MAX_HARTS && smp::online_mask() & 1 << hart != 0 {
+        return "online";
+    }
+    match hsm::hart_get_status(hart) {
+        Ok(HartState::Started) => "started",
+        Ok(HartState::Stopped) => "stopped",
+        Ok(HartState::StartPending) => "starting",
+        Ok(HartState::StopPending) => "stopping",
+        Ok(HartState::Suspended) => "suspended",
+        Ok(HartState::SuspendPending) => "suspending",
+        Ok(HartState::ResumePending) => "resuming",
+        Err(_) => "offline",
+    }
+}
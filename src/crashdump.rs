@@ -0,0 +1,194 @@
+//! Crash dumps: the end of the kernel log, saved by the panic handler to
+//! memory that survives a warm reboot and printed on the next boot, for
+//! machines with nothing to debug them with but a console. The log has
+//! what the panic printed, including the trap frame of an unhandled trap
+//! and the backtrace, and what led up to it.
+//!
+//! The memory is a `/reserved-memory` node compatible with
+//! `annwn,crash-dump`, which keeps the frame allocator off it:
+//!
+//! ```text
+//! reserved-memory {
+//!     #address-cells = <2>;
+//!     #size-cells = <2>;
+//!     ranges;
+//!
+//!     crash-dump@8ff00000 {
+//!         compatible = "annwn,crash-dump";
+//!         reg = <0x0 0x8ff00000 0x0 0x10000>;
+//!     };
+//! };
+//! ```
+//!
+//! Without one, nothing is saved.
+//!
+//! The previous boot's dump stays readable, as `/proc/crashdump`, until
+//! this boot saves its own.
+
+use core::mem::size_of;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use crate::dtb::DeviceTree;
+use crate::mm::phys_to_virt;
+use crate::{io, kmsg, ktest, log_info, log_warn};
+
+/// Marks a dump as complete. Anything else in the header is left over from
+/// before, or isn't a dump at all.
+const MAGIC: u64 = u64::from_le_bytes(*b"ANNWNDMP");
+
+/// Less room than this for the log isn't worth saving into.
+const MIN_SIZE: usize = 4096;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    magic: u64,
+    /// The bytes of log after the header.
+    len: u64,
+    checksum: u64,
+}
+
+/// Where the dump goes, mapped, and its size; zero if there's nowhere.
+static ADDR: AtomicUsize = AtomicUsize::new(0);
+static SIZE: AtomicUsize = AtomicUsize::new(0);
+/// The bytes of log the previous boot saved; zero if it didn't crash.
+static PREVIOUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Finds the memory for dumps, printing and then clearing the one the
+/// previous boot left there, if any. RAM must be mapped.
+pub fn init(dt: &DeviceTree<'_>) {
+    let Some(reg) = dt
+        .nodes_compatible("annwn,crash-dump")
+        .find_map(|node| node.reg()?.next())
+    else {
+        return;
+    };
+    let (Ok(paddr), Ok(size)) = (usize::try_from(reg.address), usize::try_from(reg.size)) else {
+        return;
+    };
+    if size < MIN_SIZE || !paddr.is_multiple_of(align_of::<Header>()) {
+        log_warn!(target: "crashdump", "unusable region at {:#x}, {:#x} bytes", paddr, size);
+        return;
+    }
+    let addr = phys_to_virt(paddr);
+    ADDR.store(addr, Ordering::Relaxed);
+    SIZE.store(size, Ordering::Relaxed);
+    log_info!(target: "crashdump", "saving to {:#x}, {} KiB", paddr, size / 1024);
+
+    if let Some(len) = saved_len(addr, size) {
+        let log = log(addr, size);
+        log_warn!(target: "crashdump", "the previous boot crashed:");
+        // It's already been recorded once, by the boot it's from.
+        io::with_console(|console| console.write_bytes(&log[..len]));
+        log_warn!(target: "crashdump", "end of crash dump");
+        PREVIOUS.store(len, Ordering::Relaxed);
+    }
+    let header = addr as *mut Header;
+    // SAFETY: the memory is reserved for dumps, and is RAM, so it's mapped.
+    unsafe { core::ptr::addr_of_mut!((*header).magic).write_volatile(0) };
+}
+
+/// The length of the log in the dump at `addr`, if there's a complete one
+/// there.
+fn saved_len(addr: usize, size: usize) -> Option<usize> {
+    // SAFETY: the memory is reserved for dumps, and is RAM, so it's mapped.
+    let header = unsafe { (addr as *const Header).read_volatile() };
+    let log = log(addr, size);
+    let len = usize::try_from(header.len).ok()?;
+    (header.magic == MAGIC && len <= log.len() && checksum(&log[..len]) == header.checksum)
+        .then_some(len)
+}
+
+/// The log the previous boot saved when it crashed, if it did.
+pub fn previous() -> Option<&'static [u8]> {
+    let len = PREVIOUS.load(Ordering::Relaxed);
+    if len == 0 {
+        return None;
+    }
+    let (addr, size) = (ADDR.load(Ordering::Relaxed), SIZE.load(Ordering::Relaxed));
+    Some(&log(addr, size)[..len])
+}
+
+/// Saves the end of the kernel log as a dump, for the panic handler.
+pub fn save() {
+    PREVIOUS.store(0, Ordering::Relaxed);
+    let (addr, size) = (ADDR.load(Ordering::Relaxed), SIZE.load(Ordering::Relaxed));
+    if size == 0 {
+        return;
+    }
+    let log = log(addr, size);
+    let position = kmsg::position();
+    let mut len = 0;
+    kmsg::read_from(position.saturating_sub(log.len()), |bytes| {
+        log[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+    });
+    // Start at a line, unless the log so far all fit.
+    if len < position {
+        let start = log[..len]
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(0, |newline| newline + 1);
+        log.copy_within(start..len, 0);
+        len -= start;
+    }
+    let header = Header {
+        magic: MAGIC,
+        len: len as u64,
+        checksum: checksum(&log[..len]),
+    };
+    // The header is written last, so a dump cut short by a reset isn't
+    // taken as complete; the log isn't written volatilely, so this keeps it
+    // from being written after.
+    fence(Ordering::SeqCst);
+    // SAFETY: the memory is reserved for dumps.
+    unsafe { (addr as *mut Header).write_volatile(header) };
+}
+
+/// The part of the memory at `addr` after the header.
+fn log(addr: usize, size: usize) -> &'static mut [u8] {
+    // SAFETY: the memory is reserved for dumps. Only `save` writes it, once
+    // the panic handler has stopped the other harts, and it clears
+    // `PREVIOUS` first so that nothing reads it after.
+    unsafe {
+        core::slice::from_raw_parts_mut(
+            (addr + size_of::<Header>()) as *mut u8,
+            size - size_of::<Header>(),
+        )
+    }
+}
+
+/// FNV-1a, to tell a dump from memory that only happens to start with
+/// `MAGIC`.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+ktest! {
+    fn saves_and_finds_dumps() {
+        let region = alloc::vec![0u64; 1024].leak();
+        let (addr, size) = (region.as_mut_ptr() as usize, 8 * region.len());
+        let saved = (
+            ADDR.swap(addr, Ordering::Relaxed),
+            SIZE.swap(size, Ordering::Relaxed),
+            PREVIOUS.load(Ordering::Relaxed),
+        );
+        assert_eq!(saved_len(addr, size), None);
+
+        log_info!(target: "crashdump", "saving a ktest dump");
+        save();
+        let len = saved_len(addr, size).unwrap();
+        let dump = &log(addr, size)[..len];
+        assert!(dump.ends_with(b"saving a ktest dump\n"));
+
+        // A change to the log isn't taken for a dump.
+        log(addr, size)[0] ^= 1;
+        assert_eq!(saved_len(addr, size), None);
+
+        ADDR.store(saved.0, Ordering::Relaxed);
+        SIZE.store(saved.1, Ordering::Relaxed);
+        PREVIOUS.store(saved.2, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,210 @@
+//! Drivers for the devices in the device tree. Each one in `DRIVERS` names
+//! the `compatible` strings it handles, and `probe_all` hands it every
+//! enabled node they match, after the interrupt controllers the node
+//! depends on.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::dtb::{DeviceTree, DtNode};
+use crate::mm::paging::MapError;
+use crate::sync::SpinLock;
+use crate::{ktest, log_debug, log_error};
+
+pub mod goldfish_rtc;
+pub mod ns16550;
+pub mod pci;
+pub mod plic;
+pub mod sifive_test;
+pub mod virtio;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProbeError {
+    /// Nothing is there, e.g. an empty virtio-mmio slot.
+    NoDevice,
+    /// The node is missing a property the driver needs, or has a bad one.
+    BadNode,
+    Map(MapError),
+    Unsupported,
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::NoDevice => write!(f, "no device"),
+            ProbeError::BadNode => write!(f, "malformed node"),
+            ProbeError::Map(err) => write!(f, "failed to map registers: {:?}", err),
+            ProbeError::Unsupported => write!(f, "unsupported device"),
+        }
+    }
+}
+
+impl From<MapError> for ProbeError {
+    fn from(err: MapError) -> Self {
+        ProbeError::Map(err)
+    }
+}
+
+pub type Probe = fn(&DeviceTree<'static>, &DtNode<'static>) -> Result<(), ProbeError>;
+
+pub struct Driver {
+    pub name: &'static str,
+    /// The `compatible` strings the driver handles.
+    pub compatible: &'static [&'static str],
+    pub probe: Probe,
+}
+
+const DRIVERS: &[Driver] = &[
+    Driver {
+        name: "goldfish-rtc",
+        compatible: &[goldfish_rtc::COMPATIBLE],
+        probe: goldfish_rtc::probe,
+    },
+    Driver {
+        name: "plic",
+        compatible: &plic::COMPATIBLE,
+        probe: plic::probe,
+    },
+    Driver {
+        name: "pci-host-ecam",
+        compatible: &[pci::COMPATIBLE],
+        probe: pci::probe,
+    },
+    Driver {
+        name: "sifive-test",
+        compatible: &[sifive_test::COMPATIBLE],
+        probe: sifive_test::probe,
+    },
+    Driver {
+        name: "virtio-mmio",
+        compatible: &[virtio::COMPATIBLE],
+        probe: virtio::probe,
+    },
+];
+
+/// A node and the driver that probed it.
+#[derive(Clone, Copy)]
+pub struct Binding {
+    pub node: &'static str,
+    pub driver: &'static str,
+}
+
+static BOUND: SpinLock<Vec<Binding>> = SpinLock::new(Vec::new());
+
+/// The driver for `node`: the first one handling any of its `compatible`
+/// strings, which go from most to least specific.
+fn driver_for(node: &DtNode<'_>) -> Option<&'static Driver> {
+    node.prop_string_list("compatible")?.find_map(|compatible| {
+        DRIVERS
+            .iter()
+            .find(|driver| driver.compatible.contains(&compatible))
+    })
+}
+
+/// The interrupt controllers `node` delivers interrupts to, including those
+/// its `interrupt-map` routes its children's interrupts to.
+fn dependencies<'a>(dt: &DeviceTree<'a>, node: &DtNode<'a>) -> Vec<DtNode<'a>> {
+    let mut deps: Vec<DtNode<'a>> = dt.interrupts(node).map(|irq| irq.parent).collect();
+    if let Some(map) = node.property("interrupt-map") {
+        let child_cells = node.prop_u32("#address-cells").unwrap_or(2)
+            + node.prop_u32("#interrupt-cells").unwrap_or(1);
+        let mut map = map
+            .value
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()));
+        while map.nth(child_cells as usize - 1).is_some() {
+            let Some(controller) = map.next().and_then(|phandle| dt.node_by_phandle(phandle))
+            else {
+                break;
+            };
+            let Some(irq_cells) = controller.prop_u32("#interrupt-cells") else {
+                break;
+            };
+            let parent_cells = controller.prop_u32("#address-cells").unwrap_or(0) + irq_cells;
+            if parent_cells > 0 && map.nth(parent_cells as usize - 1).is_none() {
+                break;
+            }
+            deps.push(controller);
+        }
+    }
+    deps.dedup();
+    deps
+}
+
+/// Probes every node in the device tree that a driver handles. A node goes
+/// once the controllers it depends on have been probed, if they have a
+/// driver.
+pub fn probe_all(dt: &DeviceTree<'static>) {
+    let mut pending: Vec<(DtNode<'static>, &Driver)> = dt
+        .nodes()
+        .filter(DtNode::is_enabled)
+        .filter_map(|node| Some((node, driver_for(&node)?)))
+        .collect();
+    loop {
+        // The first node none of whose dependencies are still to be probed;
+        // when there isn't one, because they're circular, just the first.
+        let ready = pending.iter().position(|(node, _)| {
+            dependencies(dt, node)
+                .iter()
+                .all(|dep| !pending.iter().any(|(waiting, _)| waiting == dep))
+        });
+        let (node, driver) = match ready {
+            Some(index) => pending.remove(index),
+            None if !pending.is_empty() => pending.remove(0),
+            None => break,
+        };
+        match (driver.probe)(dt, &node) {
+            Ok(()) => {
+                log_debug!(target: "drivers", "{} bound to {}", node.name, driver.name);
+                BOUND.lock().push(Binding {
+                    node: node.name,
+                    driver: driver.name,
+                });
+            }
+            Err(ProbeError::NoDevice) => {}
+            Err(err) => {
+                log_error!(
+                    target: "drivers",
+                    "{}: {} failed to probe: {}",
+                    node.name,
+                    driver.name,
+                    err
+                )
+            }
+        }
+    }
+}
+
+/// Every node a driver has probed.
+pub fn bound() -> Vec<Binding> {
+    BOUND.lock().clone()
+}
+
+ktest! {
+    fn picks_drivers_by_compatible() {
+        use crate::dtb::{FdtBuilder, FdtNode};
+
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.root_mut();
+        root.add_child(FdtNode::new("virtio_mmio@10001000"))
+            .set_string_list("compatible", &["vendor,unknown", virtio::COMPATIBLE]);
+        root.add_child(FdtNode::new("test@100000"))
+            .set_string_list("compatible", &[sifive_test::COMPATIBLE]);
+        let rtc = root.add_child(FdtNode::new("rtc@101000"));
+        rtc.set_string_list("compatible", &[goldfish_rtc::COMPATIBLE]);
+        rtc.set_str("status", "disabled");
+        root.add_child(FdtNode::new("thing@0"))
+            .set_string_list("compatible", &["vendor,unknown"]);
+
+        let blob = fdt.to_aligned_blob();
+        let dt = blob.parse().unwrap();
+
+        let driver = |path| driver_for(&dt.find_node(path).unwrap()).map(|driver| driver.name);
+        assert_eq!(driver("/virtio_mmio@10001000"), Some("virtio-mmio"));
+        assert_eq!(driver("/test@100000"), Some("sifive-test"));
+        assert_eq!(driver("/thing@0"), None);
+        let rtc = dt.find_node("/rtc@101000").unwrap();
+        assert!(!rtc.is_enabled());
+        assert!(dt.find_node("/test@100000").unwrap().is_enabled());
+    }
+}
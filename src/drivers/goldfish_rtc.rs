@@ -0,0 +1,33 @@
+//! The Goldfish RTC, which QEMU's virt machine has for the time of day. It's
+//! read once, to set the realtime clock; the `time` CSR keeps it after.
+
+use super::ProbeError;
+use crate::dtb::{DeviceTree, DtNode};
+use crate::log_info;
+use crate::mm;
+use crate::time::{self, Duration};
+
+pub const COMPATIBLE: &str = "google,goldfish-rtc";
+
+/// Nanoseconds since the Unix epoch. Reading the low half latches the high
+/// half, so it must be read first.
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+pub fn probe(dt: &DeviceTree<'static>, node: &DtNode<'static>) -> Result<(), ProbeError> {
+    let reg = node
+        .reg_translated(dt)
+        .and_then(|mut reg| reg.next())
+        .ok_or(ProbeError::BadNode)?;
+    let io = mm::ioremap(reg.address as usize, reg.size as usize)?;
+    let low: u32 = io.read(TIME_LOW);
+    let high: u32 = io.read(TIME_HIGH);
+    let now = Duration::from_nanos(u64::from(high) << 32 | u64::from(low));
+    time::set_realtime(now);
+    log_info!(
+        "RTC at {:#x}, {} s since the epoch",
+        reg.address,
+        now.as_secs()
+    );
+    Ok(())
+}
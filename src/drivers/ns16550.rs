@@ -0,0 +1,155 @@
+//! NS16550-compatible UARTs, as found on QEMU's virt machine and most boards,
+//! including the DesignWare ones on the VisionFive 2.
+
+use crate::dtb::{DeviceTree, DtNode};
+use crate::ktest;
+use crate::mm::{self, mmio::IoMem};
+
+pub const COMPATIBLE: [&str; 3] = ["ns16550a", "ns16550", "snps,dw-apb-uart"];
+
+const DEFAULT_BAUD: u32 = 115200;
+
+// Register numbers, scaled by `reg-shift`.
+const RBR_THR: usize = 0;
+const IER: usize = 1;
+const FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+/// The divisor latch overlays RBR/THR and IER while LCR.DLAB is set.
+const DLL: usize = 0;
+const DLM: usize = 1;
+
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 0x80;
+const FCR_ENABLE_CLEAR: u8 = 0x07;
+const IER_RX: u8 = 0x01;
+const MCR_DTR_RTS: u8 = 0x03;
+/// Gates the interrupt line on many 16550 implementations.
+const MCR_OUT2: u8 = 0x08;
+const LSR_DR: u8 = 0x01;
+const LSR_THRE: u8 = 0x20;
+
+/// How many times to try writing LCR before giving up on it.
+const MAX_LCR_TRIES: usize = 1000;
+
+#[derive(Clone, Copy)]
+pub struct Uart {
+    io: IoMem,
+    reg_shift: u32,
+    reg_io_width: u32,
+}
+
+impl Uart {
+    /// Maps and programs the UART described by `node` for 8N1 at `baud`, or
+    /// at `current-speed` or 115200 if `None`. The baud rate is left alone
+    /// if the node has no `clock-frequency`. Fails if LCR never takes.
+    pub fn init(dt: &DeviceTree<'_>, node: &DtNode<'_>, baud: Option<u32>) -> Option<Self> {
+        let reg = node.reg_translated(dt)?.next()?;
+        let io = mm::ioremap(reg.address as usize, reg.size as usize).ok()?;
+        let uart = Self {
+            io,
+            reg_shift: node.prop_u32("reg-shift").unwrap_or(0),
+            reg_io_width: node.prop_u32("reg-io-width").unwrap_or(1),
+        };
+
+        uart.write_reg(IER, 0);
+        let baud = baud
+            .or(node.prop_u32("current-speed"))
+            .filter(|&baud| baud != 0)
+            .unwrap_or(DEFAULT_BAUD);
+        if let Some(clock) = node.property("clock-frequency").and_then(|p| p.as_number()) {
+            let divisor = divisor(clock, baud);
+            if !uart.write_lcr(LCR_DLAB) {
+                return None;
+            }
+            uart.write_reg(DLL, divisor as u8);
+            uart.write_reg(DLM, (divisor >> 8) as u8);
+        }
+        if !uart.write_lcr(LCR_8N1) {
+            return None;
+        }
+        uart.write_reg(FCR, FCR_ENABLE_CLEAR);
+        uart.write_reg(MCR, MCR_DTR_RTS);
+        Some(uart)
+    }
+
+    fn read_reg(&self, reg: usize) -> u8 {
+        let offset = reg << self.reg_shift;
+        match self.reg_io_width {
+            4 => self.io.read::<u32>(offset) as u8,
+            _ => self.io.read(offset),
+        }
+    }
+
+    fn write_reg(&self, reg: usize, value: u8) {
+        let offset = reg << self.reg_shift;
+        match self.reg_io_width {
+            4 => self.io.write::<u32>(offset, value.into()),
+            _ => self.io.write(offset, value),
+        }
+    }
+
+    /// Writes LCR, which a DesignWare UART ignores while it's busy, as it is
+    /// with input waiting that the boot loader left: the FIFOs are cleared
+    /// and it's written again until it takes. Returns whether it did.
+    fn write_lcr(&self, value: u8) -> bool {
+        for _ in 0..MAX_LCR_TRIES {
+            self.write_reg(LCR, value);
+            let lcr = self.read_reg(LCR);
+            if lcr == value {
+                return true;
+            }
+            self.write_reg(FCR, FCR_ENABLE_CLEAR);
+            // With DLAB still set this would read DLL rather than drain RBR.
+            if lcr & LCR_DLAB == 0 {
+                self.read_reg(RBR_THR);
+            }
+        }
+        false
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        while self.read_reg(LSR) & LSR_THRE == 0 {
+            core::hint::spin_loop();
+        }
+        self.write_reg(RBR_THR, byte);
+    }
+
+    /// Raises an interrupt whenever a byte is received.
+    pub fn enable_rx_interrupt(&self) {
+        self.write_reg(MCR, MCR_DTR_RTS | MCR_OUT2);
+        self.write_reg(IER, IER_RX);
+    }
+
+    /// Returns the next received byte, if there is one.
+    pub fn read_byte(&self) -> Option<u8> {
+        if self.read_reg(LSR) & LSR_DR != 0 {
+            Some(self.read_reg(RBR_THR))
+        } else {
+            None
+        }
+    }
+}
+
+/// The divisor latch value for `baud` from a `clock` Hz input clock, to the
+/// nearest, as the UART divides by 16 and then by it.
+fn divisor(clock: u64, baud: u32) -> u16 {
+    let rate = 16 * u64::from(baud);
+    ((clock + rate / 2) / rate).clamp(1, u16::MAX.into()) as u16
+}
+
+ktest! {
+    fn rounds_divisors() {
+        assert_eq!(divisor(1_843_200, 115_200), 1);
+        assert_eq!(divisor(1_843_200, 9600), 12);
+        // 13.02 and 26.04, from the 24 MHz clock of a DesignWare UART.
+        assert_eq!(divisor(24_000_000, 115_200), 13);
+        assert_eq!(divisor(24_000_000, 57_600), 26);
+        // 10.85, which truncating would make 10, 8.5% fast.
+        assert_eq!(divisor(20_000_000, 115_200), 11);
+        assert_eq!(divisor(1000, 115_200), 1);
+        assert_eq!(divisor(u64::from(u32::MAX), 1), u16::MAX);
+        assert_eq!(divisor(u64::from(u32::MAX), u32::MAX), 1);
+    }
+}
@@ -0,0 +1,581 @@
+//! PCI Express behind a generic ECAM host bridge (`pci-host-ecam-generic`),
+//! as on QEMU's virt machine.
+//!
+//! Nothing sets up PCI before the kernel on virt, so `probe` does what
+//! firmware would elsewhere: it sizes every BAR and assigns it an address
+//! from the bridge's windows, then turns on decoding. Bridges are only
+//! followed if their bus numbers have already been set.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::ProbeError;
+use crate::dtb::{DeviceTree, DtNode};
+use crate::mm;
+use crate::mm::mmio::IoMem;
+use crate::sync::SpinLock;
+use crate::{ktest, log_error, log_info, log_warn};
+
+pub const COMPATIBLE: &str = "pci-host-ecam-generic";
+
+/// Each function's configuration space, and the space for each bus.
+const FUNCTION_SIZE: usize = 4096;
+const BUS_SIZE: usize = 32 * 8 * FUNCTION_SIZE;
+
+/// Configuration registers common to every header type.
+pub const VENDOR_ID: usize = 0x00;
+pub const DEVICE_ID: usize = 0x02;
+pub const COMMAND: usize = 0x04;
+pub const REVISION: usize = 0x08;
+pub const HEADER_TYPE: usize = 0x0e;
+pub const BAR0: usize = 0x10;
+pub const INTERRUPT_PIN: usize = 0x3d;
+
+/// The secondary bus number of a bridge's header.
+const SECONDARY_BUS: usize = 0x19;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const HEADER_BRIDGE: u8 = 0x01;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// Address spaces, from the top cell of a `ranges` entry.
+const SPACE_IO: u32 = 1;
+const SPACE_MEM32: u32 = 2;
+const SPACE_MEM64: u32 = 3;
+
+/// Where a function is: bus, device and function numbers.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    /// The top cell of the address in the device tree's encoding, as used
+    /// by `interrupt-map`.
+    fn phys_hi(self) -> u32 {
+        u32::from(self.bus) << 16 | u32::from(self.device) << 11 | u32::from(self.function) << 8
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BarKind {
+    Memory,
+    Io,
+}
+
+/// A region a base address register decodes.
+#[derive(Clone, Copy, Debug)]
+pub struct Bar {
+    pub kind: BarKind,
+    /// The CPU physical address of the region.
+    pub addr: u64,
+    pub size: u64,
+    pub prefetchable: bool,
+}
+
+/// A function's configuration space.
+#[derive(Clone, Copy)]
+struct Config(IoMem);
+
+impl Config {
+    fn read_u8(self, offset: usize) -> u8 {
+        self.0.read(offset)
+    }
+
+    fn read_u16(self, offset: usize) -> u16 {
+        self.0.read(offset)
+    }
+
+    fn read_u32(self, offset: usize) -> u32 {
+        self.0.read(offset)
+    }
+
+    fn write_u16(self, offset: usize, value: u16) {
+        self.0.write(offset, value)
+    }
+
+    fn write_u32(self, offset: usize, value: u32) {
+        self.0.write(offset, value)
+    }
+}
+
+/// A PCI function found by `probe`.
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// The class code, subclass and programming interface.
+    pub class: (u8, u8, u8),
+    pub revision: u8,
+    bars: [Option<Bar>; 6],
+    irq: Option<u32>,
+}
+
+impl Device {
+    /// The region of BAR `index`, if it's implemented.
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.bars.get(index).copied().flatten()
+    }
+
+    /// The PLIC interrupt source the function's legacy interrupt is routed
+    /// to.
+    pub fn irq(&self) -> Option<u32> {
+        self.irq
+    }
+}
+
+/// A window of the bridge's `ranges`, through which the CPU reaches BARs.
+struct Window {
+    space: u32,
+    prefetchable: bool,
+    pci_addr: u64,
+    cpu_addr: u64,
+    size: u64,
+    /// Where the next BAR assigned from the window goes, as a PCI address.
+    next: u64,
+}
+
+impl Window {
+    fn contains(&self, pci_addr: u64) -> bool {
+        pci_addr >= self.pci_addr && pci_addr - self.pci_addr < self.size
+    }
+
+    /// Takes `size` bytes, a power of two, aligned to their size. The
+    /// addresses are worked out in 64 bits even on RV32, where the 64-bit
+    /// window is above 4 GiB.
+    fn allocate(&mut self, size: u64) -> Option<u64> {
+        let addr = self.next.checked_next_multiple_of(size)?;
+        if addr.checked_add(size)? > self.pci_addr + self.size {
+            return None;
+        }
+        self.next = addr + size;
+        Some(addr)
+    }
+}
+
+/// An entry of `interrupt-map`: the unit address in `phys.hi` encoding and
+/// the pin, and the PLIC source they're routed to.
+struct IrqMapEntry {
+    addr: u32,
+    pin: u32,
+    irq: u32,
+}
+
+struct Host {
+    /// The physical address of the configuration space of the first bus.
+    ecam: usize,
+    first_bus: u8,
+    last_bus: u8,
+    /// Each bus's configuration space, once mapped.
+    buses: Vec<Option<IoMem>>,
+    windows: Vec<Window>,
+    irq_map: Vec<IrqMapEntry>,
+    /// The `interrupt-map-mask` cells for the address and the pin.
+    irq_mask: (u32, u32),
+}
+
+static DEVICES: SpinLock<Vec<Arc<Device>>> = SpinLock::new(Vec::new());
+
+fn cells(bytes: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    bytes
+        .chunks_exact(4)
+        .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+}
+
+fn join(high: u32, low: u32) -> u64 {
+    u64::from(high) << 32 | u64::from(low)
+}
+
+/// Reads `count` cells from `iter` as one number.
+fn number(iter: &mut impl Iterator<Item = u32>, count: u32) -> Option<u64> {
+    (0..count).try_fold(0, |acc, _| Some(acc << 32 | u64::from(iter.next()?)))
+}
+
+impl Host {
+    fn from_node(dt: &DeviceTree<'_>, node: &DtNode<'_>) -> Option<Self> {
+        let reg = node.reg_translated(dt)?.next()?;
+        let (first_bus, last_bus) = match node.property("bus-range") {
+            Some(range) => {
+                let mut range = cells(range.value);
+                (range.next()? as u8, range.next()? as u8)
+            }
+            None => (0, 255),
+        };
+        if last_bus < first_bus {
+            return None;
+        }
+        let buses = usize::from(last_bus - first_bus) + 1;
+        if (reg.size as usize) < buses * BUS_SIZE {
+            return None;
+        }
+        let parent = dt.parent(node)?;
+        let parent_cells = parent.prop_u32("#address-cells").unwrap_or(2);
+        let size_cells = node.prop_u32("#size-cells").unwrap_or(2);
+
+        let mut windows = Vec::new();
+        if let Some(ranges) = node.property("ranges") {
+            let mut ranges = cells(ranges.value).peekable();
+            while ranges.peek().is_some() {
+                let phys_hi = ranges.next()?;
+                let pci_addr = join(ranges.next()?, ranges.next()?);
+                let parent_addr = number(&mut ranges, parent_cells)?;
+                let size = number(&mut ranges, size_cells)?;
+                windows.push(Window {
+                    space: phys_hi >> 24 & 3,
+                    prefetchable: phys_hi & 1 << 30 != 0,
+                    pci_addr,
+                    cpu_addr: dt.translate_address(&parent, parent_addr)?,
+                    size,
+                    // Leave address 0 free, which reads as unassigned.
+                    next: pci_addr.max(1),
+                });
+            }
+        }
+
+        let mut irq_map = Vec::new();
+        let mut irq_mask = (u32::MAX, u32::MAX);
+        if let Some(mask) = node.property("interrupt-map-mask") {
+            let mask: Vec<u32> = cells(mask.value).collect();
+            irq_mask = (*mask.first()?, *mask.get(3)?);
+        }
+        let child_irq_cells = node.prop_u32("#interrupt-cells").unwrap_or(1);
+        if let Some(map) = node.property("interrupt-map") {
+            let mut map = cells(map.value).peekable();
+            while map.peek().is_some() {
+                // The child's unit address and interrupt specifier, of which
+                // the top cell and the pin matter, then the controller's.
+                let addr = map.next()?;
+                number(&mut map, 2)?;
+                let pin = map.next()?;
+                number(&mut map, child_irq_cells.checked_sub(1)?)?;
+                let controller = dt.node_by_phandle(map.next()?)?;
+                let addr_cells = controller.prop_u32("#address-cells").unwrap_or(0);
+                let irq_cells = controller.prop_u32("#interrupt-cells")?;
+                number(&mut map, addr_cells)?;
+                let irq = map.next()?;
+                number(&mut map, irq_cells.checked_sub(1)?)?;
+                irq_map.push(IrqMapEntry { addr, pin, irq });
+            }
+        }
+
+        Some(Self {
+            ecam: reg.address as usize,
+            first_bus,
+            last_bus,
+            buses: (0..buses).map(|_| None).collect(),
+            windows,
+            irq_map,
+            irq_mask,
+        })
+    }
+
+    /// The configuration space of `address`, mapping its bus if need be.
+    fn config(&mut self, address: Address) -> Option<Config> {
+        let bus = usize::from(address.bus.checked_sub(self.first_bus)?);
+        let io = match self.buses.get(bus)? {
+            Some(io) => *io,
+            None => match mm::ioremap(self.ecam + bus * BUS_SIZE, BUS_SIZE) {
+                Ok(io) => *self.buses[bus].insert(io),
+                Err(err) => {
+                    log_error!(target: "pci", "failed to map bus {}: {:?}", address.bus, err);
+                    return None;
+                }
+            },
+        };
+        let function = usize::from(address.device) * 8 + usize::from(address.function);
+        Some(Config(
+            io.subregion(function * FUNCTION_SIZE, FUNCTION_SIZE),
+        ))
+    }
+
+    fn scan_bus(&mut self, bus: u8) {
+        for device in 0..32 {
+            for function in 0..8 {
+                let address = Address {
+                    bus,
+                    device,
+                    function,
+                };
+                let Some(config) = self.config(address) else {
+                    return;
+                };
+                if config.read_u16(VENDOR_ID) == 0xffff {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                let header_type = config.read_u8(HEADER_TYPE);
+                self.add_function(address, config, header_type & !HEADER_MULTIFUNCTION);
+                if function == 0 && header_type & HEADER_MULTIFUNCTION == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn add_function(&mut self, address: Address, config: Config, header_type: u8) {
+        let class = config.read_u32(REVISION);
+        let bar_count = match header_type {
+            0 => 6,
+            HEADER_BRIDGE => 2,
+            _ => 0,
+        };
+        let device = Device {
+            address,
+            vendor_id: config.read_u16(VENDOR_ID),
+            device_id: config.read_u16(DEVICE_ID),
+            class: ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8),
+            revision: class as u8,
+            bars: self.assign_bars(address, config, bar_count),
+            irq: self.route_irq(address, config.read_u8(INTERRUPT_PIN)),
+        };
+        log_info!(
+            target: "pci",
+            "{} {:04x}:{:04x} {}{}",
+            address,
+            device.vendor_id,
+            device.device_id,
+            class_name(device.class.0),
+            match device.irq {
+                Some(irq) => format!(", irq {}", irq),
+                None => String::new(),
+            }
+        );
+        DEVICES.lock().push(Arc::new(device));
+
+        if header_type == HEADER_BRIDGE {
+            let secondary = config.read_u8(SECONDARY_BUS);
+            if secondary > address.bus && secondary <= self.last_bus {
+                self.scan_bus(secondary);
+            } else {
+                log_warn!(target: "pci", "{}: bridge has no bus numbers, skipping", address);
+            }
+        }
+    }
+
+    /// Sizes the BARs, gives any without an address one, and turns on
+    /// decoding for those there are.
+    fn assign_bars(&mut self, address: Address, config: Config, count: usize) -> [Option<Bar>; 6] {
+        let mut bars = [None; 6];
+        let command = config.read_u16(COMMAND) & !(COMMAND_IO | COMMAND_MEMORY);
+        // Don't decode while the BARs are being probed.
+        config.write_u16(COMMAND, command);
+
+        let mut enable = 0;
+        let mut index = 0;
+        while index < count {
+            let offset = BAR0 + 4 * index;
+            let original = config.read_u32(offset);
+            let is_64 = original & BAR_IO == 0 && original & (0b11 << 1) == BAR_64;
+            config.write_u32(offset, u32::MAX);
+            let mut mask = u64::from(config.read_u32(offset));
+            let mut current = u64::from(original);
+            if is_64 {
+                let high = config.read_u32(offset + 4);
+                config.write_u32(offset + 4, u32::MAX);
+                mask |= u64::from(config.read_u32(offset + 4)) << 32;
+                current |= u64::from(high) << 32;
+            }
+            let (kind, flags) = match original & BAR_IO {
+                0 => (BarKind::Memory, 0xf),
+                _ => (BarKind::Io, 0x3),
+            };
+            // The size is the lowest address bit that can be set, as the
+            // bits below it are hardwired to zero.
+            let bits = mask & !flags;
+            let bar = Bar {
+                kind,
+                addr: current & !flags,
+                size: bits & bits.wrapping_neg(),
+                prefetchable: original & BAR_PREFETCHABLE != 0,
+            };
+            let placed = if bits == 0 {
+                None
+            } else {
+                self.place_bar(address, index, is_64, &bar)
+            };
+            let pci_addr = placed.map_or(bar.addr, |(pci_addr, _)| pci_addr);
+            config.write_u32(offset, pci_addr as u32 | (original & flags as u32));
+            if is_64 {
+                config.write_u32(offset + 4, (pci_addr >> 32) as u32);
+            }
+            if let Some((_, cpu_addr)) = placed {
+                bars[index] = Some(Bar {
+                    addr: cpu_addr,
+                    ..bar
+                });
+                enable |= match kind {
+                    BarKind::Memory => COMMAND_MEMORY,
+                    BarKind::Io => COMMAND_IO,
+                };
+            }
+            index += if is_64 { 2 } else { 1 };
+        }
+        config.write_u16(COMMAND, command | enable);
+        bars
+    }
+
+    /// Finds the window `bar`, at its PCI address, is in, or gives it an
+    /// address if it has none, returning its PCI and CPU addresses.
+    fn place_bar(
+        &mut self,
+        address: Address,
+        index: usize,
+        is_64: bool,
+        bar: &Bar,
+    ) -> Option<(u64, u64)> {
+        let fits = |window: &Window| match bar.kind {
+            BarKind::Io => window.space == SPACE_IO,
+            BarKind::Memory => {
+                window.space == SPACE_MEM32 || (is_64 && window.space == SPACE_MEM64)
+            }
+        };
+        if bar.addr != 0 {
+            if let Some(window) = self
+                .windows
+                .iter()
+                .find(|w| fits(w) && w.contains(bar.addr))
+            {
+                return Some((bar.addr, window.cpu_addr + (bar.addr - window.pci_addr)));
+            }
+        }
+        // Prefer the 64-bit window for what can go there, to leave the
+        // 32-bit one for what can't, and prefetchable windows for
+        // prefetchable BARs.
+        let rank = |window: &Window| {
+            (
+                window.space == SPACE_MEM64,
+                window.prefetchable == bar.prefetchable,
+            )
+        };
+        let mut candidates: Vec<&mut Window> =
+            self.windows.iter_mut().filter(|w| fits(w)).collect();
+        candidates.sort_by_key(|window| core::cmp::Reverse(rank(window)));
+        for window in candidates {
+            if let Some(pci_addr) = window.allocate(bar.size) {
+                return Some((pci_addr, window.cpu_addr + (pci_addr - window.pci_addr)));
+            }
+        }
+        log_warn!(
+            target: "pci",
+            "{}: no room for BAR {} ({:#x} bytes)",
+            address,
+            index,
+            bar.size
+        );
+        None
+    }
+
+    /// The PLIC source a function's interrupt pin is routed to.
+    fn route_irq(&self, address: Address, pin: u8) -> Option<u32> {
+        if pin == 0 {
+            return None;
+        }
+        let (addr_mask, pin_mask) = self.irq_mask;
+        let addr = address.phys_hi() & addr_mask;
+        let pin = u32::from(pin) & pin_mask;
+        self.irq_map
+            .iter()
+            .find(|entry| entry.addr & addr_mask == addr && entry.pin & pin_mask == pin)
+            .map(|entry| entry.irq)
+    }
+}
+
+/// A name for a class code.
+pub fn class_name(class: u8) -> &'static str {
+    match class {
+        0x00 => "unclassified",
+        0x01 => "storage",
+        0x02 => "network",
+        0x03 => "display",
+        0x04 => "multimedia",
+        0x05 => "memory",
+        0x06 => "bridge",
+        0x07 => "communication",
+        0x08 => "system",
+        0x09 => "input",
+        0x0c => "serial bus",
+        _ => "other",
+    }
+}
+
+/// Enumerates what's behind the ECAM host bridge at `node`.
+pub fn probe(dt: &DeviceTree<'static>, node: &DtNode<'static>) -> Result<(), ProbeError> {
+    let mut host = Host::from_node(dt, node).ok_or(ProbeError::BadNode)?;
+    log_info!(
+        target: "pci",
+        "host bridge at {:#x}, buses {}-{}",
+        host.ecam,
+        host.first_bus,
+        host.last_bus
+    );
+    let first_bus = host.first_bus;
+    host.scan_bus(first_bus);
+    Ok(())
+}
+
+/// Every function the host bridges have.
+pub fn devices() -> Vec<Arc<Device>> {
+    DEVICES.lock().clone()
+}
+
+ktest! {
+    fn allocates_bars_and_routes_irqs() {
+        let mut window = Window {
+            space: SPACE_MEM64,
+            prefetchable: true,
+            pci_addr: 0x4_0000_0000,
+            cpu_addr: 0x4_0000_0000,
+            size: 0x4_0000_0000,
+            next: 0x4_0000_0000,
+        };
+        assert_eq!(window.allocate(0x1000), Some(0x4_0000_0000));
+        assert_eq!(window.allocate(0x10_0000), Some(0x4_0010_0000));
+        assert_eq!(window.allocate(0x8_0000_0000), None);
+        assert_eq!(window.allocate(0x1000), Some(0x4_0020_0000));
+        assert!(window.contains(0x7_ffff_ffff));
+        assert!(!window.contains(0x8_0000_0000));
+
+        // As QEMU's virt machine swizzles them, over four PLIC sources.
+        let mut irq_map = Vec::new();
+        for device in 0..4 {
+            for pin in 1..=4 {
+                let irq = 32 + (device + pin - 1) % 4;
+                irq_map.push(IrqMapEntry { addr: device << 11, pin, irq });
+            }
+        }
+        let host = Host {
+            ecam: 0,
+            first_bus: 0,
+            last_bus: 0,
+            buses: Vec::new(),
+            windows: Vec::new(),
+            irq_map,
+            irq_mask: (0x1800, 7),
+        };
+        let address = |device, function| Address { bus: 0, device, function };
+        assert_eq!(host.route_irq(address(1, 0), 1), Some(33));
+        assert_eq!(host.route_irq(address(1, 3), 2), Some(34));
+        // Device 5 is masked to device 1.
+        assert_eq!(host.route_irq(address(5, 0), 4), Some(32));
+        assert_eq!(host.route_irq(address(1, 0), 0), None);
+    }
+}
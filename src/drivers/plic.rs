@@ -0,0 +1,190 @@
+//! The RISC-V Platform-Level Interrupt Controller.
+
+use alloc::vec::Vec;
+
+use super::ProbeError;
+use crate::arch::csr::{self, Interrupts};
+use crate::dtb::{DeviceTree, DtNode};
+use crate::mm::{self, mmio::IoMem};
+use crate::percpu;
+use crate::sync::SpinLockIrqSave;
+use crate::{log_error, log_info, log_warn};
+
+pub const COMPATIBLE: [&str; 2] = ["sifive,plic-1.0.0", "riscv,plic0"];
+
+/// Interrupt sources are numbered 1 to 1023; 0 means "no interrupt".
+const MAX_IRQS: usize = 1024;
+
+const PRIORITY: usize = 0x0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// The interrupt number of a supervisor external interrupt, as used in the
+/// `interrupts-extended` of the PLIC to name S-mode contexts.
+const IRQ_S_EXT: u32 = 9;
+
+/// Called with the number of the interrupt source that fired.
+pub type Handler = fn(u32);
+
+static PLIC: SpinLockIrqSave<Option<Plic>> = SpinLockIrqSave::new(None);
+static HANDLERS: SpinLockIrqSave<[Option<Handler>; MAX_IRQS]> =
+    SpinLockIrqSave::new([None; MAX_IRQS]);
+
+struct Plic {
+    io: IoMem,
+    /// Number of interrupt sources.
+    ndev: u32,
+    /// The S-mode context of each hart, as `(hart id, context)`.
+    contexts: Vec<(u64, usize)>,
+    /// The hart interrupts are routed to.
+    hart: u64,
+}
+
+impl Plic {
+    fn read(&self, offset: usize) -> u32 {
+        self.io.read(offset)
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        self.io.write(offset, value)
+    }
+
+    fn context(&self, hart: u64) -> Option<usize> {
+        self.contexts
+            .iter()
+            .find(|&&(id, _)| id == hart)
+            .map(|&(_, context)| context)
+    }
+
+    fn set_priority(&self, irq: u32, priority: u32) {
+        self.write(PRIORITY + 4 * irq as usize, priority);
+    }
+
+    fn set_enabled(&self, context: usize, irq: u32, enabled: bool) {
+        let offset = ENABLE + ENABLE_STRIDE * context + 4 * (irq as usize / 32);
+        let bit = 1 << (irq % 32);
+        let value = self.read(offset);
+        self.write(offset, if enabled { value | bit } else { value & !bit });
+    }
+
+    fn set_threshold(&self, context: usize, threshold: u32) {
+        self.write(
+            CONTEXT + CONTEXT_STRIDE * context + CONTEXT_THRESHOLD,
+            threshold,
+        );
+    }
+
+    fn claim(&self, context: usize) -> u32 {
+        self.read(CONTEXT + CONTEXT_STRIDE * context + CONTEXT_CLAIM)
+    }
+
+    fn complete(&self, context: usize, irq: u32) {
+        self.write(CONTEXT + CONTEXT_STRIDE * context + CONTEXT_CLAIM, irq);
+    }
+}
+
+/// Sets up the PLIC at `node`, routing its interrupts to this hart and
+/// enabling external interrupts on it.
+pub fn probe(dt: &DeviceTree<'static>, node: &DtNode<'static>) -> Result<(), ProbeError> {
+    if PLIC.lock().is_some() {
+        log_warn!("{} ignored, only one PLIC is used", node.name);
+        return Err(ProbeError::Unsupported);
+    }
+    let reg = node
+        .reg_translated(dt)
+        .and_then(|mut reg| reg.next())
+        .ok_or(ProbeError::BadNode)?;
+    let io = mm::ioremap(reg.address as usize, reg.size as usize)?;
+    let hart = percpu::hart_id();
+
+    // Contexts are numbered by their position in `interrupts-extended`, each
+    // entry naming the interrupt controller of a hart and the privilege mode.
+    let contexts = dt
+        .interrupts(node)
+        .enumerate()
+        .filter(|(_, interrupt)| interrupt.cell(0) == Some(IRQ_S_EXT))
+        .filter_map(|(context, interrupt)| {
+            let cpu = dt.parent(&interrupt.parent)?;
+            Some((cpu.reg()?.next()?.address, context))
+        })
+        .collect();
+
+    let plic = Plic {
+        io,
+        ndev: node
+            .prop_u32("riscv,ndev")
+            .map_or(MAX_IRQS as u32 - 1, |ndev| ndev.min(MAX_IRQS as u32 - 1)),
+        contexts,
+        hart: hart as u64,
+    };
+    let Some(context) = plic.context(plic.hart) else {
+        log_error!("no S-mode context for hart {}", hart);
+        return Err(ProbeError::Unsupported);
+    };
+    for irq in 1..=plic.ndev {
+        plic.set_priority(irq, 0);
+        plic.set_enabled(context, irq, false);
+    }
+    plic.set_threshold(context, 0);
+    log_info!(
+        "{} sources at {:#x}, hart {} context {}",
+        plic.ndev,
+        reg.address,
+        hart,
+        context
+    );
+    *PLIC.lock() = Some(plic);
+
+    // SAFETY: the trap handler dispatches external interrupts to us.
+    unsafe { csr::sie::set(Interrupts::EXTERNAL) };
+    Ok(())
+}
+
+/// Calls `handler` whenever interrupt source `irq` fires, and enables it.
+/// Returns false if there's no PLIC to deliver the interrupt.
+pub fn register(irq: u32, handler: Handler) -> bool {
+    let guard = PLIC.lock();
+    let Some(plic) = guard.as_ref() else {
+        return false;
+    };
+    assert!(irq != 0 && irq <= plic.ndev, "invalid irq {}", irq);
+    HANDLERS.lock()[irq as usize] = Some(handler);
+    plic.set_priority(irq, 1);
+    if let Some(context) = plic.context(plic.hart) {
+        plic.set_enabled(context, irq, true);
+    }
+    true
+}
+
+/// Called by the trap handler on a supervisor external interrupt; runs the
+/// handler of every pending interrupt.
+pub fn handle_interrupt() {
+    let guard = PLIC.lock();
+    let Some(plic) = guard.as_ref() else {
+        return;
+    };
+    let Some(context) = plic.context(plic.hart) else {
+        return;
+    };
+    loop {
+        let irq = plic.claim(context);
+        if irq == 0 {
+            break;
+        }
+        let handler = HANDLERS.lock().get(irq as usize).copied().flatten();
+        match handler {
+            Some(handler) => handler(irq),
+            None => {
+                // A level-triggered source would fire again as soon as
+                // it's completed, with nothing to quiet it.
+                log_warn!("unexpected irq {}, disabling it", irq);
+                plic.set_enabled(context, irq, false);
+            }
+        }
+        plic.complete(context, irq);
+    }
+}
@@ -0,0 +1,61 @@
+//! SiFive's test finisher, which QEMU's virt machine has for ending the
+//! emulation with an exit status.
+
+use super::ProbeError;
+use crate::dtb::{DeviceTree, DtNode};
+use crate::mm::{self, mmio::IoMem};
+use crate::sync::Once;
+use crate::{ktest, log_info, log_warn};
+
+pub const COMPATIBLE: &str = "sifive,test0";
+
+/// Values of the register. `FAIL` carries the exit status in its top half.
+const FAIL: u32 = 0x3333;
+const PASS: u32 = 0x5555;
+
+static FINISHER: Once<IoMem> = Once::new();
+
+pub fn probe(dt: &DeviceTree<'static>, node: &DtNode<'static>) -> Result<(), ProbeError> {
+    if FINISHER.get().is_some() {
+        log_warn!("{} ignored, only one test finisher is used", node.name);
+        return Err(ProbeError::Unsupported);
+    }
+    let reg = node
+        .reg_translated(dt)
+        .and_then(|mut reg| reg.next())
+        .ok_or(ProbeError::BadNode)?;
+    let io = mm::ioremap(reg.address as usize, reg.size as usize)?;
+    FINISHER.call_once(|| io);
+    log_info!("test finisher at {:#x}", reg.address);
+    Ok(())
+}
+
+/// What to write to exit with status `code`. QEMU only keeps the low 16
+/// bits, so a failure with none of them set exits with 1 instead, rather
+/// than looking like a success.
+fn value(code: u32) -> u32 {
+    match code {
+        0 => PASS,
+        code if code & 0xffff == 0 => 1 << 16 | FAIL,
+        code => code << 16 | FAIL,
+    }
+}
+
+/// Ends the emulation with exit status `code`. Returns if there's no test
+/// finisher.
+pub fn finish(code: u32) {
+    let Some(io) = FINISHER.get() else {
+        return;
+    };
+    io.write(0, value(code));
+}
+
+ktest! {
+    fn encodes_exit_status() {
+        assert_eq!(value(0), PASS);
+        assert_eq!(value(1), 0x1_3333);
+        assert_eq!(value(0xabcd), 0xabcd_3333);
+        assert_eq!(value(0x1_0000), 0x1_3333);
+        assert_eq!(value(0x1_0002), 0x2_3333);
+    }
+}
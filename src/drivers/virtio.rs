@@ -0,0 +1,351 @@
+//! virtio devices on the virtio-mmio transport, as found on QEMU's virt
+//! machine. `probe` is given each transport in the device tree and hands the
+//! device behind it to the driver for its type.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::ProbeError;
+use crate::dtb::{DeviceTree, DtNode};
+use crate::mm::paging::{self, PAGE_SIZE};
+use crate::mm::{self, dma};
+use crate::sync::SpinLock;
+use crate::util::align_down;
+use crate::{log_debug, log_error, log_info};
+
+mod blk;
+mod console;
+mod gpu;
+mod input;
+mod mmio;
+mod net;
+mod ninep;
+mod queue;
+mod rng;
+
+pub use blk::request_cache_stats;
+use mmio::Mmio;
+pub use queue::{Buffer, VirtQueue};
+
+pub const COMPATIBLE: &str = "virtio,mmio";
+
+/// Device types.
+pub const DEVICE_NET: u32 = 1;
+pub const DEVICE_BLOCK: u32 = 2;
+pub const DEVICE_CONSOLE: u32 = 3;
+pub const DEVICE_ENTROPY: u32 = 4;
+pub const DEVICE_9P: u32 = 9;
+pub const DEVICE_GPU: u32 = 16;
+pub const DEVICE_INPUT: u32 = 18;
+
+/// Device status bits.
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// Set by devices that follow the virtio 1.0 spec rather than the legacy
+/// interface, which the version 2 transport requires.
+const F_VERSION_1: u64 = 1 << 32;
+
+/// The bit of the interrupt status saying a used ring was updated.
+pub const INTERRUPT_VRING: u32 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VirtioError {
+    /// The device doesn't accept the features we asked for.
+    FeaturesRejected,
+    /// The queue doesn't exist or has already been set up.
+    NoQueue(u16),
+    NoMemory,
+    /// There aren't enough free descriptors for the buffers.
+    QueueFull,
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FeaturesRejected => f.write_str("features rejected"),
+            Self::NoQueue(index) => write!(f, "queue {} unavailable", index),
+            Self::NoMemory => f.write_str("out of memory"),
+            Self::QueueFull => f.write_str("queue full"),
+        }
+    }
+}
+
+/// A virtio device, with the transport's registers and the interrupt it's
+/// wired to. Drivers bring it up with `negotiate`, then `setup_queue` for
+/// each of its queues, then `driver_ok`.
+pub struct VirtioDevice {
+    mmio: Mmio,
+    irq: Option<u32>,
+}
+
+impl VirtioDevice {
+    /// The PLIC interrupt source the device raises.
+    pub fn irq(&self) -> Option<u32> {
+        self.irq
+    }
+
+    /// Resets the device and agrees on the features it and the driver both
+    /// `supported`, which are returned. Transport features are taken care
+    /// of here.
+    pub fn negotiate(&self, supported: u64) -> Result<u64, VirtioError> {
+        self.mmio.set_status(0);
+        self.mmio.set_status(STATUS_ACKNOWLEDGE);
+        self.mmio.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let offered = self.mmio.device_features();
+        let mut features = offered & supported & !F_VERSION_1;
+        if !self.mmio.is_legacy() {
+            if offered & F_VERSION_1 == 0 {
+                self.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+            features |= F_VERSION_1;
+        }
+        self.mmio.set_driver_features(features);
+
+        // Legacy devices take whatever they're given.
+        if !self.mmio.is_legacy() {
+            let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+            self.mmio.set_status(status);
+            if self.mmio.status() & STATUS_FEATURES_OK == 0 {
+                self.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        Ok(features & !F_VERSION_1)
+    }
+
+    /// Sets up queue `index` with up to `size` entries, or as many as the
+    /// device allows.
+    pub fn setup_queue(&self, index: u16, size: u16) -> Result<VirtQueue, VirtioError> {
+        let max = match self.mmio.queue_max(index) {
+            Some(0) | None => return Err(VirtioError::NoQueue(index)),
+            Some(max) => max,
+        };
+        // Sizes must be powers of two for the legacy interface.
+        let size = size.min(max).min(queue::MAX_SIZE);
+        let size = 1 << size.ilog2();
+        let (queue, addrs) = VirtQueue::new(index, size)?;
+        self.mmio.set_queue(index, size, &addrs);
+        Ok(queue)
+    }
+
+    /// Tells the device the driver is ready for it to start working.
+    pub fn driver_ok(&self) {
+        let status = self.mmio.status();
+        self.mmio.set_status(status | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device the driver has given up on it.
+    pub fn fail(&self) {
+        let status = self.mmio.status();
+        self.mmio.set_status(status | STATUS_FAILED);
+    }
+
+    /// Tells the device there are new buffers in `queue`.
+    pub fn notify(&self, queue: &VirtQueue) {
+        self.mmio.notify(queue.index());
+    }
+
+    /// Acknowledges the device's interrupt, returning its `INTERRUPT_*` bits.
+    pub fn ack_interrupt(&self) -> u32 {
+        self.mmio.ack_interrupt()
+    }
+
+    /// Whether the device uses the legacy interface, which some
+    /// device-specific structures differ for.
+    pub fn is_legacy(&self) -> bool {
+        self.mmio.is_legacy()
+    }
+
+    /// Reads the field at `offset` in the device-specific configuration.
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        self.mmio.read_config(|config| config.read(offset))
+    }
+
+    /// Writes the field at `offset` in the device-specific configuration,
+    /// for devices where that chooses what the configuration shows.
+    pub fn set_config_u8(&self, offset: usize, value: u8) {
+        self.mmio.write_config(offset, value);
+    }
+
+    pub fn config_u16(&self, offset: usize) -> u16 {
+        self.mmio.read_config(|config| config.read(offset))
+    }
+
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        self.mmio.read_config(|config| config.read(offset))
+    }
+
+    /// 64-bit fields are read in two halves, as not every implementation
+    /// allows wider accesses.
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        self.mmio.read_config(|config| {
+            let low: u32 = config.read(offset);
+            let high: u32 = config.read(offset + 4);
+            u64::from(high) << 32 | u64::from(low)
+        })
+    }
+}
+
+/// Adds buffers covering the `len` bytes of kernel memory at `addr` to
+/// `buffers`, one for each physically contiguous run of pages.
+pub fn push_buffers(buffers: &mut Vec<Buffer>, addr: usize, len: usize, writable: bool) {
+    let end = addr + len;
+    let mut addr = addr;
+    while addr < end {
+        let paddr = paging::translate(addr).expect("buffer not mapped");
+        let mut run = (align_down(addr, PAGE_SIZE) + PAGE_SIZE).min(end) - addr;
+        while addr + run < end && paging::translate(addr + run) == Some(paddr + run) {
+            run = (run + PAGE_SIZE).min(end - addr);
+        }
+        let bus_addr = dma::phys_to_bus(paddr, run).expect("buffer out of reach of devices");
+        buffers.push(Buffer {
+            addr: bus_addr,
+            len: run as u32,
+            writable,
+        });
+        addr += run;
+    }
+}
+
+/// A driver for one type of device, which takes ownership of each device of
+/// that type.
+struct Driver {
+    name: &'static str,
+    device_type: u32,
+    probe: fn(VirtioDevice) -> Result<(), VirtioError>,
+}
+
+const DRIVERS: &[Driver] = &[
+    Driver {
+        name: "virtio-blk",
+        device_type: DEVICE_BLOCK,
+        probe: blk::probe,
+    },
+    Driver {
+        name: "virtio-console",
+        device_type: DEVICE_CONSOLE,
+        probe: console::probe,
+    },
+    Driver {
+        name: "virtio-gpu",
+        device_type: DEVICE_GPU,
+        probe: gpu::probe,
+    },
+    Driver {
+        name: "virtio-input",
+        device_type: DEVICE_INPUT,
+        probe: input::probe,
+    },
+    Driver {
+        name: "virtio-net",
+        device_type: DEVICE_NET,
+        probe: net::probe,
+    },
+    Driver {
+        name: "virtio-9p",
+        device_type: DEVICE_9P,
+        probe: ninep::probe,
+    },
+    Driver {
+        name: "virtio-rng",
+        device_type: DEVICE_ENTROPY,
+        probe: rng::probe,
+    },
+];
+
+/// A transport found by `probe`, and what's behind it.
+#[derive(Clone, Copy)]
+pub struct DeviceInfo {
+    /// Physical address of the registers.
+    pub paddr: usize,
+    pub version: u32,
+    pub device_type: u32,
+    pub vendor: u32,
+    pub irq: Option<u32>,
+    /// The driver that took the device, if any did.
+    pub driver: Option<&'static str>,
+}
+
+static DEVICES: SpinLock<Vec<DeviceInfo>> = SpinLock::new(Vec::new());
+
+/// A name for a device type.
+pub fn type_name(device_type: u32) -> &'static str {
+    match device_type {
+        DEVICE_NET => "network",
+        DEVICE_BLOCK => "block",
+        DEVICE_CONSOLE => "console",
+        DEVICE_ENTROPY => "entropy",
+        DEVICE_9P => "9p",
+        DEVICE_GPU => "gpu",
+        DEVICE_INPUT => "input",
+        _ => "unknown",
+    }
+}
+
+/// Probes the device behind the virtio-mmio transport at `node`.
+pub fn probe(dt: &DeviceTree<'static>, node: &DtNode<'static>) -> Result<(), ProbeError> {
+    let reg = node
+        .reg_translated(dt)
+        .and_then(|mut reg| reg.next())
+        .ok_or(ProbeError::BadNode)?;
+    let paddr = reg.address as usize;
+    let io = mm::ioremap(paddr, (reg.size as usize).max(mmio::SIZE))?;
+    let mmio = Mmio::probe(io).ok_or(ProbeError::Unsupported)?;
+    let device_type = mmio.device_id();
+    if device_type == 0 {
+        // QEMU has a transport for every slot, most of them empty.
+        return Err(ProbeError::NoDevice);
+    }
+
+    let irq = dt.interrupts(node).next().and_then(|irq| irq.cell(0));
+    let mut info = DeviceInfo {
+        paddr,
+        version: mmio.version(),
+        device_type,
+        vendor: mmio.vendor_id(),
+        irq,
+        driver: None,
+    };
+    let device = VirtioDevice { mmio, irq };
+    match DRIVERS
+        .iter()
+        .find(|driver| driver.device_type == device_type)
+    {
+        Some(driver) => {
+            log_info!(
+                target: "virtio",
+                "{} device at {:#x}, using {}",
+                type_name(device_type),
+                paddr,
+                driver.name
+            );
+            match (driver.probe)(device) {
+                Ok(()) => info.driver = Some(driver.name),
+                Err(err) => {
+                    log_error!(target: "virtio", "{} failed to probe: {}", driver.name, err)
+                }
+            }
+        }
+        None => {
+            log_debug!(
+                target: "virtio",
+                "{} device at {:#x} has no driver",
+                type_name(device_type),
+                paddr
+            );
+        }
+    }
+    DEVICES.lock().push(info);
+    Ok(())
+}
+
+/// Every device `probe` found.
+pub fn devices() -> Vec<DeviceInfo> {
+    DEVICES.lock().clone()
+}
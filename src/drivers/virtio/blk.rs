@@ -0,0 +1,322 @@
+//! virtio-blk disks, which are registered as block devices `vda`, `vdb` and
+//! so on. Requests are futures: awaited, disks with an interrupt are woken
+//! by it, and blocking transfers wait for the same futures with
+//! `executor::block_on`. The request queue each disk is registered behind
+//! (see `block::queue`) keeps several in flight at once.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::offset_of;
+use core::ptr::NonNull;
+
+use super::{VirtQueue, VirtioDevice, VirtioError, INTERRUPT_VRING};
+use crate::block::{self, BlockDevice, BlockError, BlockFuture};
+use crate::drivers::plic;
+use crate::mm::slab::{Cache, CacheStats};
+use crate::sync::{SpinLockIrqSave, WaitQueue};
+use crate::{executor, ktest, log_error, log_info};
+
+const SECTOR_SIZE: usize = 512;
+
+/// The device only allows reads.
+const F_RO: u64 = 1 << 5;
+
+/// Where the capacity, in sectors, is in the device configuration.
+const CONFIG_CAPACITY: usize = 0;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+/// How large a request can be. Each page of the buffer may need its own
+/// descriptor, besides the header and status.
+const MAX_REQUEST: usize = 64 * 1024;
+
+const QUEUE_SIZE: u16 = 128;
+
+#[repr(C)]
+struct Request {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+    /// Written by the device.
+    status: u8,
+}
+
+/// The headers of the requests in flight, on every disk.
+static REQUESTS: Cache<Request> = Cache::new("virtio-blk-request");
+
+/// How many request headers there are, and how many are in use.
+pub fn request_cache_stats() -> CacheStats {
+    REQUESTS.stats()
+}
+
+struct Inner {
+    queue: VirtQueue,
+    /// The ID of the request using each descriptor chain, by its head.
+    in_flight: Vec<Option<u64>>,
+    /// Requests the device has finished.
+    completed: BTreeSet<u64>,
+    next_id: u64,
+}
+
+impl Inner {
+    fn pop_completed(&mut self) -> bool {
+        let mut any = false;
+        while let Some(used) = self.queue.pop_used() {
+            if let Some(id) = self.in_flight[usize::from(used.head)].take() {
+                self.completed.insert(id);
+                any = true;
+            }
+        }
+        any
+    }
+}
+
+struct Disk {
+    device: VirtioDevice,
+    inner: SpinLockIrqSave<Inner>,
+    /// Woken when requests complete, if the device has an interrupt;
+    /// otherwise requests poll the queue.
+    completions: WaitQueue,
+    polled: bool,
+    sectors: u64,
+    read_only: bool,
+}
+
+static DISKS: SpinLockIrqSave<Vec<Arc<Disk>>> = SpinLockIrqSave::new(Vec::new());
+
+pub fn probe(device: VirtioDevice) -> Result<(), VirtioError> {
+    let features = device.negotiate(F_RO)?;
+    let queue = device.setup_queue(0, QUEUE_SIZE)?;
+    let size = queue.size();
+    let polled = !device
+        .irq()
+        .is_some_and(|irq| plic::register(irq, interrupt));
+    let disk = Arc::new(Disk {
+        sectors: device.config_u64(CONFIG_CAPACITY),
+        read_only: features & F_RO != 0,
+        inner: SpinLockIrqSave::new(Inner {
+            queue,
+            in_flight: (0..size).map(|_| None).collect(),
+            completed: BTreeSet::new(),
+            next_id: 0,
+        }),
+        completions: WaitQueue::new(),
+        polled,
+        device,
+    });
+
+    let name = {
+        let mut disks = DISKS.lock();
+        disks.push(disk.clone());
+        disk_name(disks.len() - 1)
+    };
+    disk.device.driver_ok();
+    log_info!(
+        target: "virtio",
+        "{}: {} MiB{}{}",
+        name,
+        disk.sectors * SECTOR_SIZE as u64 / (1024 * 1024),
+        if disk.read_only { ", read-only" } else { "" },
+        if disk.polled { ", polled" } else { "" }
+    );
+    block::register(name, disk);
+    Ok(())
+}
+
+/// The name of the disk probed `index`th: `vda` to `vdz`, then `vdaa`,
+/// `vdab` and so on, as Linux names them.
+fn disk_name(index: usize) -> String {
+    let mut letters = Vec::new();
+    let mut n = index + 1;
+    while n > 0 {
+        n -= 1;
+        letters.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    letters.reverse();
+    let mut name = String::from("vd");
+    name.extend(letters.into_iter().map(char::from));
+    name
+}
+
+fn interrupt(irq: u32) {
+    for disk in DISKS.lock().iter() {
+        if disk.device.irq() != Some(irq) {
+            continue;
+        }
+        if disk.device.ack_interrupt() & INTERRUPT_VRING != 0 && disk.inner.lock().pop_completed() {
+            disk.completions.wake_all();
+        }
+    }
+}
+
+impl Disk {
+    /// Waits until `cond` holds for the queue.
+    async fn wait(&self, mut cond: impl FnMut(&mut Inner) -> bool) {
+        if !self.polled {
+            self.completions
+                .until(|| cond(&mut self.inner.lock()))
+                .await;
+            return;
+        }
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                inner.pop_completed();
+                if cond(&mut inner) {
+                    return;
+                }
+            }
+            executor::yield_now().await;
+        }
+    }
+
+    /// Transfers `len` bytes at `addr` to or from the device, starting at
+    /// `sector`.
+    async fn request(
+        &self,
+        kind: u32,
+        sector: u64,
+        addr: usize,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        // An address rather than a pointer, which couldn't be held across
+        // the awaits.
+        let Some(request_addr) = REQUESTS
+            .alloc(Request {
+                kind,
+                reserved: 0,
+                sector,
+                status: 0xff,
+            })
+            .map(|request| request.as_ptr() as usize)
+        else {
+            log_error!(target: "virtio", "no memory for a request");
+            return Err(BlockError::Io);
+        };
+        let header_len = offset_of!(Request, status);
+
+        let mut buffers = Vec::new();
+        super::push_buffers(&mut buffers, request_addr, header_len, false);
+        super::push_buffers(&mut buffers, addr, len, kind == T_IN);
+        super::push_buffers(&mut buffers, request_addr + header_len, 1, true);
+
+        let mut id = None;
+        self.wait(|inner| match inner.queue.add(&buffers) {
+            Ok(head) => {
+                let next = inner.next_id;
+                inner.next_id += 1;
+                inner.in_flight[usize::from(head)] = Some(next);
+                self.device.notify(&inner.queue);
+                id = Some(next);
+                true
+            }
+            Err(_) => false,
+        })
+        .await;
+        let id = id.unwrap();
+        self.wait(|inner| inner.completed.remove(&id)).await;
+
+        // Only freed once the device is done with it, so if the future is
+        // dropped first it's left for the device to write to.
+        let request = request_addr as *mut Request;
+        // SAFETY: the device has finished writing the status.
+        let status = unsafe { (&raw const (*request).status).read_volatile() };
+        // SAFETY: the request came from `REQUESTS`, and nothing uses it now.
+        unsafe { REQUESTS.free(NonNull::new_unchecked(request)) };
+        match status {
+            S_OK => Ok(()),
+            status => {
+                let reason = match status {
+                    S_IOERR => "I/O error",
+                    S_UNSUPP => "unsupported",
+                    _ => "bad status",
+                };
+                log_error!(
+                    target: "virtio",
+                    "request for sector {} failed: {} ({})",
+                    sector,
+                    reason,
+                    status
+                );
+                Err(BlockError::Io)
+            }
+        }
+    }
+
+    /// Splits a transfer into requests of at most `MAX_REQUEST` bytes.
+    async fn transfer(
+        &self,
+        kind: u32,
+        start: u64,
+        addr: usize,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        for offset in (0..len).step_by(MAX_REQUEST) {
+            let sector = start + (offset / SECTOR_SIZE) as u64;
+            self.request(kind, sector, addr + offset, (len - offset).min(MAX_REQUEST))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        executor::block_on(self.read_blocks_async(start, buf))
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        executor::block_on(self.write_blocks_async(start, buf))
+    }
+
+    fn read_blocks_async<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check_range(self, start, buf.len())?;
+            self.transfer(T_IN, start, buf.as_mut_ptr() as usize, buf.len())
+                .await
+        })
+    }
+
+    fn write_blocks_async<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check_range(self, start, buf.len())?;
+            if self.read_only {
+                return Err(BlockError::ReadOnly);
+            }
+            self.transfer(T_OUT, start, buf.as_ptr() as usize, buf.len())
+                .await
+        })
+    }
+}
+
+ktest! {
+    fn names_disks() {
+        assert_eq!(disk_name(0), "vda");
+        assert_eq!(disk_name(25), "vdz");
+        assert_eq!(disk_name(26), "vdaa");
+        assert_eq!(disk_name(27), "vdab");
+        assert_eq!(disk_name(701), "vdzz");
+        assert_eq!(disk_name(702), "vdaaa");
+    }
+}
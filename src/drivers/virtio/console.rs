@@ -0,0 +1,273 @@
+//! virtio-console serial ports, which appear as `/dev/hvc0`, `/dev/hvc1`
+//! and so on, one for each device, and can be console sinks with
+//! `consoles=hvc0`. Only a device's first port is used, through the queues
+//! it has without the multiport feature.
+//!
+//! Output goes through buffers set aside at probe time, so that writing to
+//! the console never allocates.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{Buffer, VirtQueue, VirtioDevice, VirtioError, INTERRUPT_VRING};
+use crate::drivers::plic;
+use crate::file::File;
+use crate::fs::devfs::{self, CharDevice};
+use crate::io::{self, ConsoleDevice};
+use crate::sync::{SpinLockIrqSave, WaitQueue};
+use crate::syscall::Errno;
+use crate::{log_info, log_warn, task};
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+const QUEUE_SIZE: u16 = 16;
+
+/// The size of each receive and transmit buffer.
+const BUFFER_SIZE: usize = 256;
+
+/// Input not yet read is dropped beyond this.
+const MAX_INPUT: usize = 4096;
+
+/// How long a write spins for a transmit buffer to come back, in polls of
+/// the queue.
+const MAX_POLLS: usize = 1_000_000;
+
+/// A receive or transmit buffer, aligned so that it's never split across
+/// pages and always takes one descriptor.
+#[repr(C, align(256))]
+struct Chunk([u8; BUFFER_SIZE]);
+
+fn new_chunk() -> Box<Chunk> {
+    Box::new(Chunk([0; BUFFER_SIZE]))
+}
+
+struct Inner {
+    rx: VirtQueue,
+    tx: VirtQueue,
+    /// The buffer given to the device for each descriptor chain, by its
+    /// head.
+    rx_buffers: Vec<Option<Box<Chunk>>>,
+    tx_buffers: Vec<Option<Box<Chunk>>>,
+    /// Transmit buffers the device has given back. They're boxed, like the
+    /// ones in use, so they stay put as they're moved between the two.
+    #[allow(clippy::vec_box)]
+    tx_free: Vec<Box<Chunk>>,
+    /// Room for the descriptor of a buffer being added.
+    descriptors: Vec<Buffer>,
+    input: VecDeque<u8>,
+}
+
+impl Inner {
+    /// Moves what the device has received into `input`, giving it the
+    /// buffers back. Returns whether anything arrived.
+    fn receive(&mut self) -> bool {
+        let mut any = false;
+        while let Some(used) = self.rx.pop_used() {
+            let buffer = self.rx_buffers[usize::from(used.head)]
+                .take()
+                .expect("no buffer for received data");
+            let len = (used.len as usize).min(BUFFER_SIZE);
+            let room = MAX_INPUT - self.input.len();
+            self.input.extend(&buffer.0[..len.min(room)]);
+            any |= len > 0;
+            // The descriptor just freed is enough for it.
+            self.add_rx_buffer(buffer).unwrap();
+        }
+        any
+    }
+
+    fn add_rx_buffer(&mut self, mut buffer: Box<Chunk>) -> Result<(), VirtioError> {
+        self.descriptors.clear();
+        let addr = buffer.0.as_mut_ptr() as usize;
+        super::push_buffers(&mut self.descriptors, addr, BUFFER_SIZE, true);
+        let head = self.rx.add(&self.descriptors)?;
+        self.rx_buffers[usize::from(head)] = Some(buffer);
+        Ok(())
+    }
+
+    fn reclaim_tx(&mut self) {
+        while let Some(used) = self.tx.pop_used() {
+            if let Some(buffer) = self.tx_buffers[usize::from(used.head)].take() {
+                self.tx_free.push(buffer);
+            }
+        }
+    }
+}
+
+struct Port {
+    name: &'static str,
+    device: VirtioDevice,
+    inner: SpinLockIrqSave<Inner>,
+    /// Woken when input arrives, if the device has an interrupt; otherwise
+    /// reads poll the queue.
+    readable: WaitQueue,
+    polled: bool,
+}
+
+static PORTS: SpinLockIrqSave<Vec<&'static Port>> = SpinLockIrqSave::new(Vec::new());
+
+pub fn probe(device: VirtioDevice) -> Result<(), VirtioError> {
+    device.negotiate(0)?;
+    let rx = device.setup_queue(RX_QUEUE, QUEUE_SIZE)?;
+    let tx = device.setup_queue(TX_QUEUE, QUEUE_SIZE)?;
+    let polled = !device
+        .irq()
+        .is_some_and(|irq| plic::register(irq, interrupt));
+
+    let rx_size = rx.size();
+    let tx_size = tx.size();
+    let mut inner = Inner {
+        rx_buffers: (0..rx_size).map(|_| None).collect(),
+        tx_buffers: (0..tx_size).map(|_| None).collect(),
+        tx_free: (0..tx_size).map(|_| new_chunk()).collect(),
+        descriptors: Vec::with_capacity(1),
+        input: VecDeque::with_capacity(MAX_INPUT),
+        rx,
+        tx,
+    };
+    for _ in 0..rx_size {
+        inner.add_rx_buffer(new_chunk())?;
+    }
+    device.notify(&inner.rx);
+
+    // Ports are never removed, so they can be handed out for good.
+    let port: &'static Port = {
+        let mut ports = PORTS.lock();
+        let port = Box::leak(Box::new(Port {
+            name: format!("hvc{}", ports.len()).leak(),
+            device,
+            inner: SpinLockIrqSave::new(inner),
+            readable: WaitQueue::new(),
+            polled,
+        }));
+        ports.push(port);
+        port
+    };
+    port.device.driver_ok();
+    log_info!(
+        target: "virtio",
+        "{}: console port{}",
+        port.name,
+        if port.polled { ", polled" } else { "" }
+    );
+
+    if let Err(err) = devfs::register(port.name, CharDevice::new(Arc::new(PortFile(port)))) {
+        log_warn!(target: "virtio", "couldn't add /dev/{}: {}", port.name, err);
+    }
+    io::add_device(port.name, port, false);
+    Ok(())
+}
+
+fn interrupt(irq: u32) {
+    for port in PORTS.lock().iter() {
+        if port.device.irq() != Some(irq) {
+            continue;
+        }
+        if port.device.ack_interrupt() & INTERRUPT_VRING == 0 {
+            continue;
+        }
+        let received = {
+            let mut inner = port.inner.lock();
+            let received = inner.receive();
+            if received {
+                port.device.notify(&inner.rx);
+            }
+            inner.reclaim_tx();
+            received
+        };
+        if received {
+            port.readable.wake_all();
+        }
+    }
+}
+
+impl Port {
+    /// Takes what input there is into `buf`.
+    fn read_input(&self, buf: &mut [u8]) -> usize {
+        let mut inner = self.inner.lock();
+        if self.polled && inner.receive() {
+            self.device.notify(&inner.rx);
+        }
+        let len = buf.len().min(inner.input.len());
+        for (byte, input) in buf.iter_mut().zip(inner.input.drain(..len)) {
+            *byte = input;
+        }
+        len
+    }
+
+    /// Sends `bytes`, waiting for buffers to come back if they're all in
+    /// use. Returns how much was sent, which is less than all of it if the
+    /// device stops taking them.
+    fn send(&self, bytes: &[u8]) -> usize {
+        let mut inner = self.inner.lock();
+        let mut sent = 0;
+        for chunk in bytes.chunks(BUFFER_SIZE) {
+            let mut polls = 0;
+            let mut buffer = loop {
+                if let Some(buffer) = inner.tx_free.pop() {
+                    break buffer;
+                }
+                inner.reclaim_tx();
+                polls += 1;
+                if polls == MAX_POLLS {
+                    return sent;
+                }
+                core::hint::spin_loop();
+            };
+            buffer.0[..chunk.len()].copy_from_slice(chunk);
+            let inner = &mut *inner;
+            inner.descriptors.clear();
+            let addr = buffer.0.as_ptr() as usize;
+            super::push_buffers(&mut inner.descriptors, addr, chunk.len(), false);
+            // There's a descriptor for every buffer, so this can't fail.
+            let head = inner.tx.add(&inner.descriptors).unwrap();
+            inner.tx_buffers[usize::from(head)] = Some(buffer);
+            self.device.notify(&inner.tx);
+            sent += chunk.len();
+        }
+        sent
+    }
+}
+
+impl ConsoleDevice for Port {
+    fn write(&self, bytes: &[u8]) -> bool {
+        self.send(bytes) == bytes.len()
+    }
+}
+
+/// The port as seen through `/dev`, shared by everything that opens it.
+struct PortFile(&'static Port);
+
+impl File for PortFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let port = self.0;
+        loop {
+            let len = port.read_input(buf);
+            if len > 0 {
+                return Ok(len);
+            }
+            if port.polled {
+                task::yield_now();
+            } else {
+                port.readable
+                    .wait_until(|| !port.inner.lock().input.is_empty());
+            }
+        }
+    }
+
+    /// A short write if the device stops taking output partway, so that
+    /// what was sent isn't sent again.
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        match self.0.send(buf) {
+            0 if !buf.is_empty() => Err(Errno::EIO),
+            sent => Ok(sent),
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! virtio-gpu displays, driven in 2D mode: the first enabled scanout of
+//! each device is given a framebuffer in guest memory, which becomes a text
+//! console. Commands are made one at a time and waited for.
+
+use alloc::boxed::Box;
+use core::mem::size_of;
+
+use super::{Buffer, VirtQueue, VirtioDevice, VirtioError};
+use crate::fb::{self, Framebuffer, Rect};
+use crate::mm::dma::{self, DmaBuffer};
+use crate::mm::paging::PAGE_SIZE;
+use crate::{log_info, log_warn};
+
+const CONTROL_QUEUE: u16 = 0;
+
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Blue, green, red and an unused byte, in that order in memory, which is
+/// what `fb` draws.
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+const MAX_SCANOUTS: usize = 16;
+
+/// The ID of the framebuffer's resource. It's the only one.
+const RESOURCE_ID: u32 = 1;
+
+/// Where responses go in the command buffer, after the request.
+const RESPONSE_OFFSET: usize = 512;
+
+/// How long to spin for the device to answer, in polls of the queue.
+const MAX_POLLS: usize = 1_000_000;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Header {
+    kind: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    ring_idx: u8,
+    padding: [u8; 3],
+}
+
+impl Header {
+    fn new(kind: u32) -> Self {
+        Self {
+            kind,
+            ..Self::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct GpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl From<Rect> for GpuRect {
+    fn from(rect: Rect) -> Self {
+        Self {
+            x: rect.x as u32,
+            y: rect.y as u32,
+            width: rect.width as u32,
+            height: rect.height as u32,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayOne {
+    rect: GpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayInfo {
+    header: Header,
+    pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceCreate2d {
+    header: Header,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AttachBacking {
+    header: Header,
+    resource_id: u32,
+    nr_entries: u32,
+    /// The framebuffer is contiguous, so it takes one entry.
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SetScanout {
+    header: Header,
+    rect: GpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TransferToHost2d {
+    header: Header,
+    rect: GpuRect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceFlush {
+    header: Header,
+    rect: GpuRect,
+    resource_id: u32,
+    padding: u32,
+}
+
+/// The device, and memory for the requests made to it and its responses.
+struct Gpu {
+    device: VirtioDevice,
+    queue: VirtQueue,
+    commands: DmaBuffer,
+}
+
+impl Gpu {
+    /// Sends `request` and waits for the response, or returns `None` if
+    /// the device doesn't answer.
+    fn command<T: Copy, R: Copy>(&mut self, request: T) -> Option<R> {
+        const { assert!(size_of::<T>() <= RESPONSE_OFFSET) };
+        const { assert!(RESPONSE_OFFSET + size_of::<R>() <= PAGE_SIZE) };
+        let base = self.commands.vaddr();
+        // SAFETY: the buffer is big enough, as checked above, and the
+        // device isn't using it.
+        unsafe { core::ptr::write_volatile(base as *mut T, request) };
+        let bus_addr = self.commands.bus_addr();
+        let buffers = [
+            Buffer {
+                addr: bus_addr,
+                len: size_of::<T>() as u32,
+                writable: false,
+            },
+            Buffer {
+                addr: bus_addr + RESPONSE_OFFSET as u64,
+                len: size_of::<R>() as u32,
+                writable: true,
+            },
+        ];
+        // Only one command is ever in flight, so there's room for it.
+        self.queue.add(&buffers).ok()?;
+        self.device.notify(&self.queue);
+        (0..MAX_POLLS).find_map(|_| {
+            core::hint::spin_loop();
+            self.queue.pop_used()
+        })?;
+        dma::from_device();
+        // SAFETY: the device has written the response.
+        Some(unsafe { core::ptr::read_volatile((base + RESPONSE_OFFSET) as *const R) })
+    }
+
+    /// Makes a command with no response but its header, returning whether
+    /// it succeeded.
+    fn command_ok<T: Copy>(&mut self, request: T) -> bool {
+        self.command::<T, Header>(request)
+            .is_some_and(|response| response.kind == RESP_OK_NODATA)
+    }
+}
+
+/// A scanout showing a framebuffer in guest memory.
+struct Display {
+    gpu: Gpu,
+    pixels: DmaBuffer,
+    width: usize,
+    height: usize,
+}
+
+impl Framebuffer for Display {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn pixels(&mut self) -> &mut [u32] {
+        // SAFETY: the buffer holds `width * height` pixels, and the device
+        // only reads it when asked to below.
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.pixels.vaddr() as *mut u32,
+                self.width * self.height,
+            )
+        }
+    }
+
+    fn flush(&mut self, rect: Rect) {
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        dma::to_device();
+        let offset = ((rect.y * self.width + rect.x) * size_of::<u32>()) as u64;
+        self.gpu.command_ok(TransferToHost2d {
+            header: Header::new(CMD_TRANSFER_TO_HOST_2D),
+            rect: rect.into(),
+            offset,
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        });
+        self.gpu.command_ok(ResourceFlush {
+            header: Header::new(CMD_RESOURCE_FLUSH),
+            rect: rect.into(),
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        });
+    }
+}
+
+pub fn probe(device: VirtioDevice) -> Result<(), VirtioError> {
+    device.negotiate(0)?;
+    let queue = device.setup_queue(CONTROL_QUEUE, 2)?;
+    device.driver_ok();
+    let commands = dma::alloc_coherent(PAGE_SIZE).ok_or(VirtioError::NoMemory)?;
+    let mut gpu = Gpu {
+        device,
+        queue,
+        commands,
+    };
+
+    let info = gpu.command::<_, DisplayInfo>(Header::new(CMD_GET_DISPLAY_INFO));
+    let Some(info) = info.filter(|info| info.header.kind == RESP_OK_DISPLAY_INFO) else {
+        log_warn!(target: "virtio", "display didn't say what it has");
+        // The device may yet write to the command buffer.
+        core::mem::forget(gpu);
+        return Ok(());
+    };
+    let Some((scanout, mode)) = info
+        .pmodes
+        .iter()
+        .enumerate()
+        .find(|(_, mode)| mode.enabled != 0 && mode.rect.width > 0 && mode.rect.height > 0)
+    else {
+        log_info!(target: "virtio", "display has nothing connected");
+        return Ok(());
+    };
+    let (width, height) = (mode.rect.width as usize, mode.rect.height as usize);
+    let pixels =
+        dma::alloc_coherent(width * height * size_of::<u32>()).ok_or(VirtioError::NoMemory)?;
+
+    let rect = GpuRect {
+        width: mode.rect.width,
+        height: mode.rect.height,
+        ..GpuRect::default()
+    };
+    let ok = gpu.command_ok(ResourceCreate2d {
+        header: Header::new(CMD_RESOURCE_CREATE_2D),
+        resource_id: RESOURCE_ID,
+        format: FORMAT_B8G8R8X8_UNORM,
+        width: rect.width,
+        height: rect.height,
+    }) && gpu.command_ok(AttachBacking {
+        header: Header::new(CMD_RESOURCE_ATTACH_BACKING),
+        resource_id: RESOURCE_ID,
+        nr_entries: 1,
+        addr: pixels.bus_addr(),
+        length: pixels.len() as u32,
+        padding: 0,
+    }) && gpu.command_ok(SetScanout {
+        header: Header::new(CMD_SET_SCANOUT),
+        rect,
+        scanout_id: scanout as u32,
+        resource_id: RESOURCE_ID,
+    });
+    if !ok {
+        log_warn!(target: "virtio", "couldn't set up a framebuffer on the display");
+        // The device may be using the memory.
+        core::mem::forget(gpu);
+        core::mem::forget(pixels);
+        return Ok(());
+    }
+    log_info!(target: "virtio", "display {}: {}x{}", scanout, width, height);
+
+    fb::add(Box::new(Display {
+        gpu,
+        pixels,
+        width,
+        height,
+    }));
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! virtio-input devices: keyboards, mice and tablets, like those QEMU
+//! gives its graphical window. Their events are already evdev's, so they're
+//! passed to `input` as they are, with the ranges of any absolute axes.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use super::{Buffer, VirtQueue, VirtioDevice, VirtioError, INTERRUPT_VRING};
+use crate::drivers::plic;
+use crate::input::{self, AbsInfo, InputDevice, EV_ABS, EV_KEY, EV_REL};
+use crate::mm::dma::{self, DmaBuffer};
+use crate::sync::SpinLockIrqSave;
+use crate::{log_info, log_warn};
+
+const EVENT_QUEUE: u16 = 0;
+
+const QUEUE_SIZE: u16 = 64;
+
+/// Where the fields are in the device configuration. Writing `select` and
+/// `subsel` chooses what `size` and `data` show.
+const CONFIG_SELECT: usize = 0;
+const CONFIG_SUBSEL: usize = 1;
+const CONFIG_SIZE: usize = 2;
+const CONFIG_DATA: usize = 8;
+
+const CFG_ID_NAME: u8 = 0x01;
+/// A bitmap of the codes the device sends for the event type in `subsel`.
+const CFG_EV_BITS: u8 = 0x11;
+/// The range of the absolute axis in `subsel`.
+const CFG_ABS_INFO: u8 = 0x12;
+
+/// Where `struct virtio_input_absinfo`'s fields are in `data`.
+const ABS_MIN: usize = 0;
+const ABS_MAX: usize = 4;
+const ABS_FUZZ: usize = 8;
+const ABS_FLAT: usize = 12;
+const ABS_RES: usize = 16;
+
+/// The highest absolute axis, `ABS_MAX` in Linux.
+const ABS_LAST: u16 = 0x3f;
+
+const KEY_A: u16 = 30;
+const BTN_LEFT: u16 = 0x110;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Event {
+    kind: u16,
+    code: u16,
+    value: u32,
+}
+
+struct Inner {
+    queue: VirtQueue,
+    /// A buffer for each event, given to the device in turn.
+    events: DmaBuffer,
+    /// The event buffer in each descriptor chain, by its head.
+    slots: Vec<Option<usize>>,
+}
+
+impl Inner {
+    fn add_event_buffer(&mut self, slot: usize) -> Result<(), VirtioError> {
+        let buffer = Buffer {
+            addr: self.events.bus_addr() + (slot * size_of::<Event>()) as u64,
+            len: size_of::<Event>() as u32,
+            writable: true,
+        };
+        let head = self.queue.add(&[buffer])?;
+        self.slots[usize::from(head)] = Some(slot);
+        Ok(())
+    }
+
+    /// Reports the events the device has sent to `input`, giving it the
+    /// buffers back.
+    fn receive(&mut self, input: &InputDevice) {
+        while let Some(used) = self.queue.pop_used() {
+            let Some(slot) = self.slots[usize::from(used.head)].take() else {
+                continue;
+            };
+            dma::from_device();
+            let addr = self.events.vaddr() + slot * size_of::<Event>();
+            // SAFETY: the slot is within the buffer, and the device is done
+            // with it.
+            let event = unsafe { core::ptr::read_volatile(addr as *const Event) };
+            input.report(event.kind, event.code, event.value as i32);
+            // The descriptor just freed is enough for it.
+            self.add_event_buffer(slot).unwrap();
+        }
+    }
+}
+
+struct Input {
+    device: VirtioDevice,
+    input: Arc<InputDevice>,
+    inner: SpinLockIrqSave<Inner>,
+}
+
+static INPUTS: SpinLockIrqSave<Vec<&'static Input>> = SpinLockIrqSave::new(Vec::new());
+
+/// Chooses what the device configuration shows, returning its size.
+fn select_config(device: &VirtioDevice, select: u8, subsel: u8) -> usize {
+    device.set_config_u8(CONFIG_SELECT, select);
+    device.set_config_u8(CONFIG_SUBSEL, subsel);
+    usize::from(device.config_u8(CONFIG_SIZE))
+}
+
+/// Whether the device sends events of type `kind` with code `code`.
+fn sends(device: &VirtioDevice, kind: u16, code: u16) -> bool {
+    let byte = usize::from(code / 8);
+    select_config(device, CFG_EV_BITS, kind as u8) > byte
+        && device.config_u8(CONFIG_DATA + byte) & (1 << (code % 8)) != 0
+}
+
+/// The range of each absolute axis the device sends.
+fn axes(device: &VirtioDevice) -> BTreeMap<u16, AbsInfo> {
+    (0..=ABS_LAST)
+        .filter(|&axis| sends(device, EV_ABS, axis))
+        .filter(|&axis| select_config(device, CFG_ABS_INFO, axis as u8) > 0)
+        .map(|axis| {
+            let field = |offset| device.config_u32(CONFIG_DATA + offset) as i32;
+            let info = AbsInfo {
+                value: 0,
+                min: field(ABS_MIN),
+                max: field(ABS_MAX),
+                fuzz: field(ABS_FUZZ),
+                flat: field(ABS_FLAT),
+                resolution: field(ABS_RES),
+            };
+            (axis, info)
+        })
+        .collect()
+}
+
+pub fn probe(device: VirtioDevice) -> Result<(), VirtioError> {
+    device.negotiate(0)?;
+
+    let len = select_config(&device, CFG_ID_NAME, 0);
+    let name: String = (0..len)
+        .map(|i| char::from(device.config_u8(CONFIG_DATA + i)))
+        .collect();
+    let kind = if sends(&device, EV_KEY, KEY_A) {
+        "keyboard"
+    } else if select_config(&device, CFG_EV_BITS, EV_ABS as u8) > 0 {
+        "tablet"
+    } else if select_config(&device, CFG_EV_BITS, EV_REL as u8) > 0 {
+        "mouse"
+    } else if sends(&device, EV_KEY, BTN_LEFT) {
+        "pointer"
+    } else {
+        log_info!(target: "virtio", "input device {:?} has no keys or axes", name);
+        return Ok(());
+    };
+    let axes = axes(&device);
+
+    let queue = device.setup_queue(EVENT_QUEUE, QUEUE_SIZE)?;
+    let Some(irq) = device.irq().filter(|&irq| plic::register(irq, interrupt)) else {
+        log_warn!(target: "virtio", "{} {:?} has no interrupt", kind, name);
+        return Ok(());
+    };
+    let size = queue.size();
+    let events =
+        dma::alloc_coherent(usize::from(size) * size_of::<Event>()).ok_or(VirtioError::NoMemory)?;
+    let mut inner = Inner {
+        queue,
+        events,
+        slots: (0..size).map(|_| None).collect(),
+    };
+    for slot in 0..usize::from(size) {
+        inner.add_event_buffer(slot)?;
+    }
+
+    let log_name = format!("{} {:?}", kind, name);
+    // Input devices are never removed, so they can be handed out for good.
+    let input: &'static Input = Box::leak(Box::new(Input {
+        device,
+        input: input::register(name, axes),
+        inner: SpinLockIrqSave::new(inner),
+    }));
+    INPUTS.lock().push(input);
+    input.device.driver_ok();
+    input.device.notify(&input.inner.lock().queue);
+    log_info!(target: "virtio", "{}, irq {}", log_name, irq);
+    Ok(())
+}
+
+fn interrupt(irq: u32) {
+    for input in INPUTS.lock().iter() {
+        if input.device.irq() != Some(irq) {
+            continue;
+        }
+        if input.device.ack_interrupt() & INTERRUPT_VRING == 0 {
+            continue;
+        }
+        let mut inner = input.inner.lock();
+        inner.receive(&input.input);
+        input.device.notify(&inner.queue);
+    }
+}
@@ -0,0 +1,219 @@
+//! The registers of the virtio-mmio transport, in both the legacy (version 1)
+//! layout QEMU uses by default and the version 2 layout.
+
+use core::mem::{offset_of, size_of};
+
+use crate::mm::dma;
+use crate::mm::mmio::{IoMem, RegisterBlock, Volatile};
+use crate::mm::paging::PAGE_SIZE;
+
+/// "virt" in little-endian.
+const MAGIC: u32 = 0x7472_6976;
+
+/// The registers before the device configuration. The legacy and version 2
+/// layouts share most of them: `guest_page_size`, `queue_align` and
+/// `queue_pfn` are only in the legacy one, and `queue_ready`, the queue
+/// addresses and `config_generation` only in version 2.
+#[repr(C)]
+struct Registers {
+    magic_value: Volatile<u32>,
+    version: Volatile<u32>,
+    device_id: Volatile<u32>,
+    vendor_id: Volatile<u32>,
+    device_features: Volatile<u32>,
+    device_features_sel: Volatile<u32>,
+    _reserved0: [u32; 2],
+    driver_features: Volatile<u32>,
+    driver_features_sel: Volatile<u32>,
+    guest_page_size: Volatile<u32>,
+    _reserved1: u32,
+    queue_sel: Volatile<u32>,
+    queue_num_max: Volatile<u32>,
+    queue_num: Volatile<u32>,
+    queue_align: Volatile<u32>,
+    queue_pfn: Volatile<u32>,
+    queue_ready: Volatile<u32>,
+    _reserved2: [u32; 2],
+    queue_notify: Volatile<u32>,
+    _reserved3: [u32; 3],
+    interrupt_status: Volatile<u32>,
+    interrupt_ack: Volatile<u32>,
+    _reserved4: [u32; 2],
+    status: Volatile<u32>,
+    _reserved5: [u32; 3],
+    queue_desc_low: Volatile<u32>,
+    queue_desc_high: Volatile<u32>,
+    _reserved6: [u32; 2],
+    queue_driver_low: Volatile<u32>,
+    queue_driver_high: Volatile<u32>,
+    _reserved7: [u32; 2],
+    queue_device_low: Volatile<u32>,
+    queue_device_high: Volatile<u32>,
+    _reserved8: [u32; 21],
+    config_generation: Volatile<u32>,
+}
+
+// SAFETY: `Registers` is `repr(C)` and made of registers and padding.
+unsafe impl RegisterBlock for Registers {}
+
+const _: () = assert!(offset_of!(Registers, queue_notify) == 0x050);
+const _: () = assert!(offset_of!(Registers, status) == 0x070);
+const _: () = assert!(size_of::<Registers>() == CONFIG);
+
+const CONFIG: usize = 0x100;
+
+/// The size of the register window, including some device configuration.
+pub const SIZE: usize = 0x200;
+
+/// Where a queue's rings are, as bus addresses.
+pub struct QueueAddrs {
+    pub desc: u64,
+    pub avail: u64,
+    pub used: u64,
+}
+
+pub struct Mmio {
+    io: IoMem,
+    version: u32,
+}
+
+impl Mmio {
+    /// Returns `None` if there are no virtio-mmio registers in `io`, or
+    /// they're a version we don't know.
+    pub fn probe(io: IoMem) -> Option<Self> {
+        if io.len() < SIZE {
+            return None;
+        }
+        let regs = io.block::<Registers>();
+        if regs.magic_value.read() != MAGIC {
+            return None;
+        }
+        let version = regs.version.read();
+        matches!(version, 1 | 2).then_some(Self { io, version })
+    }
+
+    fn regs(&self) -> &Registers {
+        self.io.block()
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// 0 if there's no device behind the transport.
+    pub fn device_id(&self) -> u32 {
+        self.regs().device_id.read()
+    }
+
+    pub fn vendor_id(&self) -> u32 {
+        self.regs().vendor_id.read()
+    }
+
+    pub fn status(&self) -> u8 {
+        self.regs().status.read() as u8
+    }
+
+    /// Writing 0 resets the device.
+    pub fn set_status(&self, status: u8) {
+        self.regs().status.write(status.into());
+    }
+
+    pub fn device_features(&self) -> u64 {
+        let regs = self.regs();
+        regs.device_features_sel.write(0);
+        let low = regs.device_features.read();
+        regs.device_features_sel.write(1);
+        let high = regs.device_features.read();
+        u64::from(high) << 32 | u64::from(low)
+    }
+
+    pub fn set_driver_features(&self, features: u64) {
+        let regs = self.regs();
+        regs.driver_features_sel.write(0);
+        regs.driver_features.write(features as u32);
+        regs.driver_features_sel.write(1);
+        regs.driver_features.write((features >> 32) as u32);
+        if self.is_legacy() {
+            regs.guest_page_size.write(PAGE_SIZE as u32);
+        }
+    }
+
+    /// The largest size of queue `index`, 0 if it doesn't exist, or `None` if
+    /// it's already set up.
+    pub fn queue_max(&self, index: u16) -> Option<u16> {
+        let regs = self.regs();
+        regs.queue_sel.write(index.into());
+        let in_use = if self.is_legacy() {
+            regs.queue_pfn.read() != 0
+        } else {
+            regs.queue_ready.read() != 0
+        };
+        let max = regs.queue_num_max.read().min(u16::MAX.into()) as u16;
+        (!in_use).then_some(max)
+    }
+
+    /// Sets up queue `index` with `size` entries in the rings at `addrs`.
+    /// The legacy layout requires the available ring to directly follow the
+    /// descriptors and the used ring to be at the next page.
+    pub fn set_queue(&self, index: u16, size: u16, addrs: &QueueAddrs) {
+        let regs = self.regs();
+        // The rings were zeroed, which the device must see.
+        dma::to_device();
+        regs.queue_sel.write(index.into());
+        regs.queue_num.write(size.into());
+        if self.is_legacy() {
+            regs.queue_align.write(PAGE_SIZE as u32);
+            regs.queue_pfn.write((addrs.desc / PAGE_SIZE as u64) as u32);
+        } else {
+            regs.queue_desc_low.write(addrs.desc as u32);
+            regs.queue_desc_high.write((addrs.desc >> 32) as u32);
+            regs.queue_driver_low.write(addrs.avail as u32);
+            regs.queue_driver_high.write((addrs.avail >> 32) as u32);
+            regs.queue_device_low.write(addrs.used as u32);
+            regs.queue_device_high.write((addrs.used >> 32) as u32);
+            regs.queue_ready.write(1);
+        }
+    }
+
+    pub fn notify(&self, index: u16) {
+        // Make the buffers added to the rings visible before the device
+        // looks for them.
+        dma::to_device();
+        self.regs().queue_notify.write(index.into());
+    }
+
+    /// Acknowledges the device's interrupt, returning why it was raised.
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.regs().interrupt_status.read();
+        self.regs().interrupt_ack.write(status);
+        status
+    }
+
+    /// Calls `read` with the device configuration until it gets a
+    /// consistent result, for fields the device may change while they're
+    /// being read.
+    pub fn read_config<T>(&self, read: impl Fn(&IoMem) -> T) -> T {
+        let config = self.io.subregion(CONFIG, self.io.len() - CONFIG);
+        if self.is_legacy() {
+            return read(&config);
+        }
+        let regs = self.regs();
+        loop {
+            let generation = regs.config_generation.read();
+            let value = read(&config);
+            if regs.config_generation.read() == generation {
+                return value;
+            }
+        }
+    }
+
+    /// Writes the byte at `offset` in the device configuration.
+    pub fn write_config(&self, offset: usize, value: u8) {
+        let config = self.io.subregion(CONFIG, self.io.len() - CONFIG);
+        config.write(offset, value);
+    }
+}
@@ -0,0 +1,172 @@
+//! virtio-net network cards, which are registered with the network stack.
+//! A card's interrupt wakes the network task to take in what it received;
+//! a card without one is polled by the task.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{VirtQueue, VirtioDevice, VirtioError, INTERRUPT_VRING};
+use crate::drivers::plic;
+use crate::net::{self, MacAddr, NetDevice, NetError};
+use crate::sync::{SpinLock, SpinLockIrqSave};
+use crate::util::hexdump;
+use crate::{log_info, log_trace};
+
+/// The device has a MAC address in its configuration.
+const F_MAC: u64 = 1 << 5;
+
+/// Where the MAC address is in the device configuration.
+const CONFIG_MAC: usize = 0;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+const QUEUE_SIZE: u16 = 64;
+
+/// The space for a received frame, after the header: the largest frame
+/// without a frame check sequence or VLAN tag, rounded up.
+const RX_BUFFER_SIZE: usize = 1536;
+
+/// The size of the header before every frame, which is left zeroed as no
+/// offloads are negotiated. Legacy devices leave off its last field,
+/// `num_buffers`, when the driver doesn't merge receive buffers.
+const HEADER_SIZE: usize = 12;
+const LEGACY_HEADER_SIZE: usize = 10;
+
+struct Inner {
+    rx: VirtQueue,
+    tx: VirtQueue,
+    /// The buffer given to the device for each descriptor chain, by its
+    /// head, kept until the device is done with it.
+    rx_buffers: Vec<Option<Vec<u8>>>,
+    tx_buffers: Vec<Option<Vec<u8>>>,
+}
+
+struct Nic {
+    device: VirtioDevice,
+    mac: MacAddr,
+    header_size: usize,
+    inner: SpinLock<Inner>,
+    polled: bool,
+}
+
+static NICS: SpinLockIrqSave<Vec<Arc<Nic>>> = SpinLockIrqSave::new(Vec::new());
+
+impl Nic {
+    /// Gives the device an empty buffer to receive a frame into.
+    fn add_rx_buffer(&self, inner: &mut Inner) -> Result<(), VirtioError> {
+        let buffer = vec![0; self.header_size + RX_BUFFER_SIZE];
+        let mut buffers = Vec::new();
+        super::push_buffers(&mut buffers, buffer.as_ptr() as usize, buffer.len(), true);
+        let head = inner.rx.add(&buffers)?;
+        inner.rx_buffers[usize::from(head)] = Some(buffer);
+        Ok(())
+    }
+
+    /// Drops the frames the device has finished sending.
+    fn reclaim_tx(inner: &mut Inner) {
+        while let Some(used) = inner.tx.pop_used() {
+            inner.tx_buffers[usize::from(used.head)] = None;
+        }
+    }
+}
+
+pub fn probe(device: VirtioDevice) -> Result<(), VirtioError> {
+    let features = device.negotiate(F_MAC)?;
+    let rx = device.setup_queue(RX_QUEUE, QUEUE_SIZE)?;
+    let tx = device.setup_queue(TX_QUEUE, QUEUE_SIZE)?;
+    let mac = if features & F_MAC != 0 {
+        MacAddr(core::array::from_fn(|i| device.config_u8(CONFIG_MAC + i)))
+    } else {
+        // QEMU's prefix, with the locally administered bit set.
+        MacAddr([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])
+    };
+    let polled = !device
+        .irq()
+        .is_some_and(|irq| plic::register(irq, interrupt));
+    let header_size = if device.is_legacy() {
+        LEGACY_HEADER_SIZE
+    } else {
+        HEADER_SIZE
+    };
+    let nic = Arc::new(Nic {
+        mac,
+        header_size,
+        inner: SpinLock::new(Inner {
+            rx_buffers: (0..rx.size()).map(|_| None).collect(),
+            tx_buffers: (0..tx.size()).map(|_| None).collect(),
+            rx,
+            tx,
+        }),
+        polled,
+        device,
+    });
+    NICS.lock().push(nic.clone());
+
+    {
+        let mut inner = nic.inner.lock();
+        while nic.add_rx_buffer(&mut inner).is_ok() {}
+        nic.device.notify(&inner.rx);
+    }
+    nic.device.driver_ok();
+    log_info!(
+        target: "virtio",
+        "network card {}{}",
+        mac,
+        if polled { ", polled" } else { "" }
+    );
+    net::register(nic);
+    Ok(())
+}
+
+fn interrupt(irq: u32) {
+    for nic in NICS.lock().iter() {
+        if nic.device.irq() == Some(irq) && nic.device.ack_interrupt() & INTERRUPT_VRING != 0 {
+            net::wake();
+        }
+    }
+}
+
+impl NetDevice for Nic {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        log_trace!(target: "virtio", "tx {} bytes\n{}", frame.len(), hexdump(0, frame));
+        let mut buffer = vec![0; self.header_size];
+        buffer.extend_from_slice(frame);
+        let mut buffers = Vec::new();
+        super::push_buffers(&mut buffers, buffer.as_ptr() as usize, buffer.len(), false);
+
+        let mut inner = self.inner.lock();
+        Self::reclaim_tx(&mut inner);
+        let head = inner.tx.add(&buffers).map_err(|_| NetError::Busy)?;
+        inner.tx_buffers[usize::from(head)] = Some(buffer);
+        self.device.notify(&inner.tx);
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock();
+        let used = inner.rx.pop_used()?;
+        let mut buffer = inner.rx_buffers[usize::from(used.head)]
+            .take()
+            .expect("no buffer for received frame");
+        // Replace the buffer taken; if the queue has no room for another,
+        // the device makes do with fewer.
+        if self.add_rx_buffer(&mut inner).is_ok() {
+            self.device.notify(&inner.rx);
+        }
+        drop(inner);
+        buffer.truncate(used.len as usize);
+        buffer.drain(..self.header_size.min(buffer.len()));
+        log_trace!(target: "virtio", "rx {} bytes\n{}", buffer.len(), hexdump(0, &buffer));
+        Some(buffer)
+    }
+
+    fn is_polled(&self) -> bool {
+        self.polled
+    }
+}
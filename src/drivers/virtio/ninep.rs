@@ -0,0 +1,163 @@
+//! virtio-9p transports, each sharing a directory of the host under a mount
+//! tag, such as QEMU's `-virtfs local,path=.,mount_tag=host,...`. The
+//! 9P2000.L client that mounts them is `fs::ninep`; here a request and the
+//! buffer for its reply just go on the device's one queue together.
+
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{VirtQueue, VirtioDevice, VirtioError, INTERRUPT_VRING};
+use crate::drivers::plic;
+use crate::fs::ninep::{self, Transport};
+use crate::sync::{SpinLockIrqSave, WaitQueue};
+use crate::{executor, log_info};
+
+/// The device has a mount tag in its configuration.
+const F_MOUNT_TAG: u64 = 1;
+
+/// Where the tag's length, then the tag, are in the device configuration.
+const CONFIG_TAG_LEN: usize = 0;
+const CONFIG_TAG: usize = 2;
+
+const QUEUE_SIZE: u16 = 128;
+
+struct Inner {
+    queue: VirtQueue,
+    /// The ID of the request using each descriptor chain, by its head.
+    in_flight: Vec<Option<u64>>,
+    /// Requests the device has answered.
+    completed: BTreeSet<u64>,
+    next_id: u64,
+}
+
+impl Inner {
+    fn pop_completed(&mut self) -> bool {
+        let mut any = false;
+        while let Some(used) = self.queue.pop_used() {
+            if let Some(id) = self.in_flight[usize::from(used.head)].take() {
+                self.completed.insert(id);
+                any = true;
+            }
+        }
+        any
+    }
+}
+
+struct Channel {
+    device: VirtioDevice,
+    inner: SpinLockIrqSave<Inner>,
+    /// Woken when requests are answered, if the device has an interrupt;
+    /// otherwise requests poll the queue.
+    completions: WaitQueue,
+    polled: bool,
+}
+
+static CHANNELS: SpinLockIrqSave<Vec<Arc<Channel>>> = SpinLockIrqSave::new(Vec::new());
+
+pub fn probe(device: VirtioDevice) -> Result<(), VirtioError> {
+    if device.negotiate(F_MOUNT_TAG)? & F_MOUNT_TAG == 0 {
+        // Without a tag there's no way to ask for the share.
+        device.fail();
+        return Err(VirtioError::FeaturesRejected);
+    }
+    let queue = device.setup_queue(0, QUEUE_SIZE)?;
+    let size = queue.size();
+    let polled = !device
+        .irq()
+        .is_some_and(|irq| plic::register(irq, interrupt));
+    let len = usize::from(device.config_u16(CONFIG_TAG_LEN));
+    let tag: Vec<u8> = (0..len).map(|i| device.config_u8(CONFIG_TAG + i)).collect();
+    let tag = String::from_utf8_lossy(&tag).into_owned();
+
+    let channel = Arc::new(Channel {
+        inner: SpinLockIrqSave::new(Inner {
+            queue,
+            in_flight: (0..size).map(|_| None).collect(),
+            completed: BTreeSet::new(),
+            next_id: 0,
+        }),
+        completions: WaitQueue::new(),
+        polled,
+        device,
+    });
+    CHANNELS.lock().push(channel.clone());
+    channel.device.driver_ok();
+    log_info!(
+        target: "virtio",
+        "9p share {:?}{}",
+        tag,
+        if channel.polled { ", polled" } else { "" }
+    );
+    ninep::register(tag, channel);
+    Ok(())
+}
+
+fn interrupt(irq: u32) {
+    for channel in CHANNELS.lock().iter() {
+        if channel.device.irq() != Some(irq) {
+            continue;
+        }
+        if channel.device.ack_interrupt() & INTERRUPT_VRING != 0
+            && channel.inner.lock().pop_completed()
+        {
+            channel.completions.wake_all();
+        }
+    }
+}
+
+impl Channel {
+    /// Waits until `cond` holds for the queue.
+    async fn wait(&self, mut cond: impl FnMut(&mut Inner) -> bool) {
+        if !self.polled {
+            self.completions
+                .until(|| cond(&mut self.inner.lock()))
+                .await;
+            return;
+        }
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                inner.pop_completed();
+                if cond(&mut inner) {
+                    return;
+                }
+            }
+            executor::yield_now().await;
+        }
+    }
+
+    async fn exchange(&self, request: &[u8], reply: &mut [u8]) {
+        let mut buffers = Vec::new();
+        super::push_buffers(
+            &mut buffers,
+            request.as_ptr() as usize,
+            request.len(),
+            false,
+        );
+        super::push_buffers(&mut buffers, reply.as_mut_ptr() as usize, reply.len(), true);
+
+        let mut id = None;
+        self.wait(|inner| match inner.queue.add(&buffers) {
+            Ok(head) => {
+                let next = inner.next_id;
+                inner.next_id += 1;
+                inner.in_flight[usize::from(head)] = Some(next);
+                self.device.notify(&inner.queue);
+                id = Some(next);
+                true
+            }
+            Err(_) => false,
+        })
+        .await;
+        let id = id.unwrap();
+        self.wait(|inner| inner.completed.remove(&id)).await;
+    }
+}
+
+impl Transport for Channel {
+    fn request(&self, request: &[u8], reply: &mut [u8]) {
+        executor::block_on(self.exchange(request, reply));
+    }
+}
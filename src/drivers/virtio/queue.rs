@@ -0,0 +1,238 @@
+//! Split virtqueues: a table of buffer descriptors, a ring of descriptor
+//! chains available to the device, and a ring of those it has used.
+
+use core::arch::asm;
+
+use super::mmio::QueueAddrs;
+use super::VirtioError;
+use crate::mm::dma::{self, DmaBuffer};
+use crate::mm::paging::PAGE_SIZE;
+use crate::util::align_up;
+use crate::{ktest, log_error};
+
+/// The largest queue we set up, which keeps the rings in three pages.
+pub const MAX_SIZE: u16 = 256;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// Where the two rings start, before their entries.
+const RING_IDX: usize = 2;
+const RING_ENTRIES: usize = 4;
+
+/// A device-readable or device-writable buffer, by its bus address.
+#[derive(Clone, Copy)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    pub writable: bool,
+}
+
+/// A descriptor chain the device has finished with.
+#[derive(Clone, Copy, Debug)]
+pub struct Used {
+    /// What `VirtQueue::add` returned for the chain.
+    pub head: u16,
+    /// How many bytes the device wrote to the chain's writable buffers.
+    pub len: u32,
+}
+
+/// A split virtqueue. Its memory is freed with it, but devices keep their
+/// queues for as long as the kernel runs, so that only happens when a probe
+/// fails before the device is started.
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    /// The descriptor table, followed by the rings.
+    rings: DmaBuffer,
+    /// Virtual addresses of the rings.
+    avail: usize,
+    used: usize,
+    /// The first of the free descriptors, which are chained through `next`.
+    free_head: u16,
+    num_free: u16,
+    /// Our copy of the available ring's index.
+    avail_idx: u16,
+    /// The used ring's index when we last looked at it.
+    last_used: u16,
+}
+
+// SAFETY: the rings are only accessed through `&mut self`, besides by the
+// device.
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    /// Allocates the rings of queue `index` with `size` entries, a power of
+    /// two no larger than `MAX_SIZE`, laid out as the legacy transport needs.
+    pub(super) fn new(index: u16, size: u16) -> Result<(Self, QueueAddrs), VirtioError> {
+        assert!(size.is_power_of_two() && size <= MAX_SIZE);
+        let n = usize::from(size);
+        let avail_offset = n * size_of::<Descriptor>();
+        let used_offset = align_up(avail_offset + RING_ENTRIES + 2 * n + 2, PAGE_SIZE);
+        let len = used_offset + align_up(RING_ENTRIES + n * size_of::<UsedElem>() + 2, PAGE_SIZE);
+
+        let rings = dma::alloc_coherent(len).ok_or(VirtioError::NoMemory)?;
+        let vaddr = rings.vaddr();
+        let addr = rings.bus_addr();
+
+        let mut queue = Self {
+            index,
+            size,
+            rings,
+            avail: vaddr + avail_offset,
+            used: vaddr + used_offset,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used: 0,
+        };
+        for i in 0..size - 1 {
+            queue.descriptor(i).next = i + 1;
+        }
+        let addrs = QueueAddrs {
+            desc: addr,
+            avail: addr + avail_offset as u64,
+            used: addr + used_offset as u64,
+        };
+        Ok((queue, addrs))
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn descriptor(&mut self, i: u16) -> &mut Descriptor {
+        debug_assert!(i < self.size);
+        // SAFETY: the table has `size` descriptors, which the device only
+        // reads while they're on the available ring.
+        unsafe { &mut *(self.rings.vaddr() as *mut Descriptor).add(i.into()) }
+    }
+
+    /// Makes a chain of `buffers` available to the device, returning its
+    /// head to match with `pop_used`. The device-readable buffers must come
+    /// before the writable ones. The caller must then notify the device.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        assert!(!buffers.is_empty(), "empty descriptor chain");
+        debug_assert!(buffers.is_sorted_by_key(|buffer| buffer.writable));
+        if buffers.len() > self.num_free.into() {
+            return Err(VirtioError::QueueFull);
+        }
+
+        let head = self.free_head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let more = i + 1 < buffers.len();
+            let index = self.free_head;
+            let desc = self.descriptor(index);
+            let next = desc.next;
+            desc.addr = buffer.addr;
+            desc.len = buffer.len;
+            desc.flags =
+                if buffer.writable { DESC_F_WRITE } else { 0 } | if more { DESC_F_NEXT } else { 0 };
+            self.free_head = next;
+        }
+        self.num_free -= buffers.len() as u16;
+
+        let slot = RING_ENTRIES + 2 * usize::from(self.avail_idx % self.size);
+        // SAFETY: the slot is within the available ring, which only we write.
+        unsafe { ((self.avail + slot) as *mut u16).write_volatile(head) };
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        // The descriptors and ring entry must be visible before the index.
+        // SAFETY: a fence has no other effects.
+        unsafe { asm!("fence rw, w") };
+        // SAFETY: as above.
+        unsafe { ((self.avail + RING_IDX) as *mut u16).write_volatile(self.avail_idx) };
+        Ok(head)
+    }
+
+    /// Takes the next chain the device has finished with, returning its
+    /// descriptors to the free list. Entries naming a descriptor that
+    /// doesn't exist are skipped.
+    pub fn pop_used(&mut self) -> Option<Used> {
+        let (head, len) = loop {
+            // SAFETY: the index is within the used ring.
+            let idx = unsafe { ((self.used + RING_IDX) as *const u16).read_volatile() };
+            if idx == self.last_used {
+                return None;
+            }
+            // Read the entry only after seeing the index that covers it.
+            // SAFETY: a fence has no other effects.
+            unsafe { asm!("fence r, rw") };
+            let slot =
+                RING_ENTRIES + size_of::<UsedElem>() * usize::from(self.last_used % self.size);
+            // SAFETY: the slot is within the used ring, and the device is
+            // done with it as it's behind `idx`.
+            let UsedElem { id, len } =
+                unsafe { ((self.used + slot) as *const UsedElem).read_volatile() };
+            self.last_used = self.last_used.wrapping_add(1);
+            match u16::try_from(id) {
+                Ok(head) if head < self.size => break (head, len),
+                _ => log_error!(target: "virtio", "queue {}: bad used entry {}", self.index, id),
+            }
+        };
+
+        let mut index = head;
+        loop {
+            self.num_free += 1;
+            let desc = *self.descriptor(index);
+            if desc.flags & DESC_F_NEXT == 0 {
+                self.descriptor(index).next = self.free_head;
+                break;
+            }
+            index = desc.next;
+        }
+        self.free_head = head;
+        Some(Used { head, len })
+    }
+}
+
+ktest! {
+    fn recycles_used_descriptors() {
+        let (mut queue, _) = VirtQueue::new(0, 4).unwrap();
+        let buffer = |writable| Buffer { addr: 0x1000, len: 16, writable };
+        let first = queue.add(&[buffer(false), buffer(true)]).unwrap();
+        let second = queue.add(&[buffer(false), buffer(true)]).unwrap();
+        assert_eq!(queue.num_free, 0);
+        assert!(matches!(queue.add(&[buffer(true)]), Err(VirtioError::QueueFull)));
+        assert!(queue.pop_used().is_none());
+
+        // Play the device, finishing the second chain, after an entry for a
+        // descriptor that doesn't exist.
+        let used = |i: usize, id: u32, len: u32| {
+            let slot = queue.used + RING_ENTRIES + i * size_of::<UsedElem>();
+            // SAFETY: the slot is within the used ring.
+            unsafe { (slot as *mut UsedElem).write_volatile(UsedElem { id, len }) };
+        };
+        used(0, 9, 0);
+        used(1, second.into(), 8);
+        // SAFETY: the index is within the used ring.
+        unsafe { ((queue.used + RING_IDX) as *mut u16).write_volatile(2) };
+        let popped = queue.pop_used().unwrap();
+        assert_eq!((popped.head, popped.len), (second, 8));
+        assert!(queue.pop_used().is_none());
+        assert_eq!(queue.num_free, 2);
+
+        // The freed descriptors are reused.
+        let third = queue.add(&[buffer(false), buffer(true)]).unwrap();
+        assert_ne!(third, first);
+        assert_eq!(queue.num_free, 0);
+    }
+}
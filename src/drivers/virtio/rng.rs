@@ -0,0 +1,45 @@
+//! virtio-rng entropy devices, which seed the kernel's random number
+//! generator when they're probed.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{VirtioDevice, VirtioError};
+use crate::{log_info, log_warn, rand};
+
+/// How many bytes are asked for, which is plenty for a 256-bit key.
+const SEED_SIZE: usize = 64;
+
+/// How long to spin for the device to answer, in polls of the queue.
+const MAX_POLLS: usize = 1_000_000;
+
+pub fn probe(device: VirtioDevice) -> Result<(), VirtioError> {
+    device.negotiate(0)?;
+    let mut queue = device.setup_queue(0, 1)?;
+    device.driver_ok();
+
+    let mut seed = vec![0; SEED_SIZE];
+    let mut buffers = Vec::new();
+    super::push_buffers(&mut buffers, seed.as_mut_ptr() as usize, seed.len(), true);
+    queue.add(&buffers)?;
+    device.notify(&queue);
+    // Only this request is ever made, so it's just waited for.
+    let used = (0..MAX_POLLS).find_map(|_| {
+        core::hint::spin_loop();
+        queue.pop_used()
+    });
+    match used {
+        Some(used) if used.len > 0 => {
+            let len = (used.len as usize).min(seed.len());
+            rand::add_entropy(&seed[..len]);
+            log_info!(target: "virtio", "entropy device gave {} bytes", len);
+        }
+        Some(_) => log_warn!(target: "virtio", "entropy device gave nothing"),
+        None => {
+            // The device may still write to the buffer.
+            core::mem::forget(seed);
+            log_warn!(target: "virtio", "entropy device didn't answer");
+        }
+    }
+    Ok(())
+}
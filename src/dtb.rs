@@ -1,8 +1,21 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use core::ffi::CStr;
+use core::ops::Range;
 
+use crate::ktest;
 use crate::util::align_up;
 
+mod builder;
+mod dts;
+
+#[cfg(feature = "ktest")]
+pub use builder::FdtNode;
+pub use builder::{AlignedBlob, FdtBuilder};
+
 #[allow(unused)]
+#[derive(Clone, Copy)]
 struct DtHeader {
     magic: u32,
     totalsize: u32,
@@ -17,12 +30,17 @@ struct DtHeader {
 }
 
 impl DtHeader {
-    unsafe fn from_ptr(ptr: *const u8) -> Option<Self> {
+    const SIZE: usize = 40;
+
+    unsafe fn from_ptr(ptr: *const u8) -> Result<Self, FdtError> {
+        if ptr.is_null() || ptr.align_offset(8) != 0 {
+            return Err(FdtError::BadPointer(ptr as usize));
+        }
         let ptr: *const u32 = ptr.cast();
 
         let magic = u32::from_be(ptr.add(0).read());
-        if magic != 0xd00dfeed {
-            return None;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
         }
 
         let totalsize = u32::from_be(ptr.add(1).read());
@@ -33,14 +51,17 @@ impl DtHeader {
         let version = u32::from_be(ptr.add(5).read());
         let last_comp_version = u32::from_be(ptr.add(6).read());
         if version < 17 || last_comp_version > 17 {
-            return None;
+            return Err(FdtError::UnsupportedVersion {
+                version,
+                last_comp_version,
+            });
         }
 
         let boot_cpuid_phys = u32::from_be(ptr.add(7).read());
         let size_dt_strings = u32::from_be(ptr.add(8).read());
         let size_dt_struct = u32::from_be(ptr.add(9).read());
 
-        Some(Self {
+        let header = Self {
             magic,
             totalsize,
             off_dt_struct,
@@ -51,20 +72,250 @@ impl DtHeader {
             boot_cpuid_phys,
             size_dt_strings,
             size_dt_struct,
-        })
+        };
+        header.check_bounds()?;
+        Ok(header)
+    }
+
+    /// Checks that every block lies within `totalsize` and is aligned.
+    fn check_bounds(&self) -> Result<(), FdtError> {
+        if (self.totalsize as usize) < Self::SIZE {
+            return Err(FdtError::HeaderTooSmall {
+                totalsize: self.totalsize,
+            });
+        }
+        let blocks = [
+            ("memory reservation", self.off_mem_rsvmap, 0, 8),
+            ("structure", self.off_dt_struct, self.size_dt_struct, 4),
+            ("strings", self.off_dt_strings, self.size_dt_strings, 1),
+        ];
+        for (block, offset, size, align) in blocks {
+            let in_bounds = offset
+                .checked_add(size)
+                .is_some_and(|end| offset as usize >= Self::SIZE && end <= self.totalsize);
+            if !in_bounds || offset % align != 0 {
+                return Err(FdtError::BadBlock {
+                    block,
+                    offset,
+                    size,
+                });
+            }
+        }
+        Ok(())
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub enum FdtError {
+    /// The blob pointer is null or not 8-byte aligned.
+    BadPointer(usize),
+    BadMagic(u32),
+    UnsupportedVersion {
+        version: u32,
+        last_comp_version: u32,
+    },
+    /// `totalsize` doesn't leave room for the header itself.
+    HeaderTooSmall {
+        totalsize: u32,
+    },
+    /// A header field places a block outside of the blob.
+    BadBlock {
+        block: &'static str,
+        offset: u32,
+        size: u32,
+    },
+    /// The memory reservation block has no terminating entry before the end
+    /// of the blob.
+    UnterminatedReservations {
+        offset: u32,
+    },
+    /// The structure block ended before `FDT_END`. Offsets here and below
+    /// are relative to the start of the structure block.
+    Truncated {
+        offset: usize,
+    },
+    BadToken {
+        offset: usize,
+        token: u32,
+    },
+    BadNodeName {
+        offset: usize,
+    },
+    BadPropName {
+        offset: usize,
+        nameoff: u32,
+    },
+    BadPropLength {
+        offset: usize,
+        len: u32,
+    },
+    /// The structure block doesn't contain exactly one, properly nested,
+    /// root node.
+    BadNesting {
+        offset: usize,
+    },
+}
+
+impl core::fmt::Display for FdtError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            FdtError::BadPointer(ptr) => write!(f, "bad blob address {:#x}", ptr),
+            FdtError::BadMagic(magic) => write!(f, "bad magic {:#x}", magic),
+            FdtError::UnsupportedVersion {
+                version,
+                last_comp_version,
+            } => write!(
+                f,
+                "unsupported version {} (compatible with {})",
+                version, last_comp_version
+            ),
+            FdtError::HeaderTooSmall { totalsize } => {
+                write!(f, "total size {:#x} smaller than the header", totalsize)
+            }
+            FdtError::BadBlock {
+                block,
+                offset,
+                size,
+            } => write!(
+                f,
+                "{} block out of bounds (offset {:#x}, size {:#x})",
+                block, offset, size
+            ),
+            FdtError::UnterminatedReservations { offset } => {
+                write!(f, "memory reservation block at {:#x} unterminated", offset)
+            }
+            FdtError::Truncated { offset } => {
+                write!(f, "structure block truncated at {:#x}", offset)
+            }
+            FdtError::BadToken { offset, token } => {
+                write!(f, "unrecognized token {:#x} at {:#x}", token, offset)
+            }
+            FdtError::BadNodeName { offset } => write!(f, "bad node name at {:#x}", offset),
+            FdtError::BadPropName { offset, nameoff } => write!(
+                f,
+                "bad property name offset {:#x} at {:#x}",
+                nameoff, offset
+            ),
+            FdtError::BadPropLength { offset, len } => {
+                write!(f, "bad property length {:#x} at {:#x}", len, offset)
+            }
+            FdtError::BadNesting { offset } => {
+                write!(f, "unbalanced nodes at {:#x}", offset)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct DeviceTree<'a> {
     header: DtHeader,
     data: &'a [u8],
+    index: Option<&'a Index<'a>>,
+}
+
+/// Every node in depth-first order, with what `node_by_phandle` and `parent`
+/// look up, so they don't have to search the tree.
+struct Index<'a> {
+    nodes: Vec<DtNode<'a>>,
+    /// The index in `nodes` of each node's parent.
+    parents: Vec<Option<usize>>,
+    phandles: BTreeMap<u32, usize>,
+}
+
+impl<'a> Index<'a> {
+    fn build(dt: &DeviceTree<'a>) -> Self {
+        let mut index = Index {
+            nodes: Vec::new(),
+            parents: Vec::new(),
+            phandles: BTreeMap::new(),
+        };
+        // The nodes enclosing the next one.
+        let mut open = Vec::new();
+        let mut nodes = dt.nodes();
+        while let Some(node) = nodes.next() {
+            open.truncate(nodes.depth - 1);
+            index.parents.push(open.last().copied());
+            open.push(index.nodes.len());
+            if let Some(phandle) = node.phandle() {
+                index.phandles.entry(phandle).or_insert(index.nodes.len());
+            }
+            index.nodes.push(node);
+        }
+        index
+    }
+
+    /// Where `node` is in `nodes`, which are in the order they're in the
+    /// structure block.
+    fn position(&self, node: &DtNode<'a>) -> Option<usize> {
+        self.nodes
+            .binary_search_by_key(&node.iter.offset, |node| node.iter.offset)
+            .ok()
+    }
 }
 
 impl<'a> DeviceTree<'a> {
-    pub unsafe fn from_ptr(ptr: *const u8) -> Option<Self> {
+    /// Parses the header of the blob at `ptr` and validates the whole
+    /// structure block, so that the accessors below can't fail.
+    ///
+    /// SAFETY: `ptr` must point to readable memory containing at least the
+    /// header, and `totalsize` bytes if the header is valid.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, FdtError> {
         let header = DtHeader::from_ptr(ptr)?;
         let data = core::slice::from_raw_parts(ptr, header.totalsize as usize);
-        Some(Self { header, data })
+        let dt = Self {
+            header,
+            data,
+            index: None,
+        };
+        dt.validate()?;
+        Ok(dt)
+    }
+
+    /// Indexes the tree for `node_by_phandle` and `parent`, which search it
+    /// until then. It needs the heap, which needs the tree to find memory,
+    /// so it can't be done as the tree is parsed. The index is leaked, as
+    /// the tree the kernel boots with is never freed.
+    pub fn build_index(&mut self) {
+        self.index = Some(Box::leak(Box::new(Index::build(self))));
+    }
+
+    fn validate(&self) -> Result<(), FdtError> {
+        // Otherwise whatever follows the map would be taken for reservations.
+        let offset = self.header.off_mem_rsvmap;
+        if !self.data[offset as usize..]
+            .chunks_exact(16)
+            .any(|entry| entry.iter().all(|&byte| byte == 0))
+        {
+            return Err(FdtError::UnterminatedReservations { offset });
+        }
+
+        let mut iter = self.struct_items();
+        let mut depth = 0;
+        let mut roots = 0;
+        loop {
+            let offset = iter.offset;
+            match iter.next_item()? {
+                Some(StructItem::BeginNode { .. }) => {
+                    if depth == 0 {
+                        roots += 1;
+                    }
+                    depth += 1;
+                }
+                Some(StructItem::EndNode) if depth > 0 => depth -= 1,
+                Some(StructItem::Prop { .. }) if depth > 0 => {}
+                Some(_) => return Err(FdtError::BadNesting { offset }),
+                None if depth == 0 && roots == 1 => return Ok(()),
+                None => return Err(FdtError::BadNesting { offset }),
+            }
+            if roots > 1 {
+                return Err(FdtError::BadNesting { offset });
+            }
+        }
+    }
+
+    /// The raw blob, `totalsize` bytes long.
+    pub fn blob(&self) -> &'a [u8] {
+        self.data
     }
 
     pub fn memory_reservations(&self) -> impl Iterator<Item = MemoryReservation> + 'a {
@@ -83,8 +334,186 @@ impl<'a> DeviceTree<'a> {
     pub fn root_node(&self) -> DtNode<'a> {
         let mut iter = self.struct_items();
         match iter.next() {
-            Some(StructItem::BeginNode { name }) => DtNode { name, iter },
-            _ => panic!("expected FDT_BEGIN_NODE"),
+            Some(StructItem::BeginNode { name }) => DtNode {
+                name,
+                iter,
+                address_cells: 2,
+                size_cells: 1,
+            },
+            _ => unreachable!("validated by DeviceTree::from_ptr"),
+        }
+    }
+
+    /// Iterates over every node in the tree in depth-first order, starting
+    /// with the root node.
+    pub fn nodes(&self) -> Nodes<'a> {
+        Nodes {
+            iter: self.struct_items(),
+            depth: 0,
+            cells: [(2, 1); MAX_DEPTH],
+        }
+    }
+
+    /// Iterates over every node whose `compatible` property contains
+    /// `compatible`.
+    pub fn nodes_compatible<'s>(&self, compatible: &'s str) -> impl Iterator<Item = DtNode<'a>> + 's
+    where
+        'a: 's,
+    {
+        self.nodes()
+            .filter(move |node| node.is_compatible(compatible))
+    }
+
+    /// Looks up a node by its full path (e.g. `/soc/uart@10000000`). Paths not
+    /// starting with `/` begin with an alias from the `/aliases` node.
+    ///
+    /// The unit address may be omitted from a path component, in which case
+    /// the first node with a matching name is returned.
+    pub fn find_node(&self, path: &str) -> Option<DtNode<'a>> {
+        let (mut node, rest) = match path.strip_prefix('/') {
+            Some(rest) => (self.root_node(), rest),
+            None => {
+                let (alias, rest) = path.split_once('/').unwrap_or((path, ""));
+                let target = self.alias(alias)?;
+                (self.find_node(target)?, rest)
+            }
+        };
+        for component in rest.split('/').filter(|c| !c.is_empty()) {
+            node = node.child(component)?;
+        }
+        Some(node)
+    }
+
+    /// Returns the path an alias refers to. Aliases must be full paths.
+    pub fn alias(&self, name: &str) -> Option<&'a str> {
+        let path = self.root_node().child("aliases")?.prop_str(name)?;
+        path.starts_with('/').then_some(path)
+    }
+
+    /// Translates `address`, an address on the bus formed by the children of
+    /// `bus`, into a CPU physical address by walking the `ranges` properties
+    /// of `bus` and its ancestors. Returns `None` if some bus on the way has
+    /// no `ranges` or no range containing the address.
+    pub fn translate_address(&self, bus: &DtNode<'a>, mut address: u64) -> Option<u64> {
+        let mut bus = *bus;
+        while let Some(parent) = self.parent(&bus) {
+            let ranges = bus.property("ranges")?;
+            // An empty `ranges` is an identity mapping.
+            if !ranges.value.is_empty() {
+                let child_cells = bus.prop_u32("#address-cells").unwrap_or(2) as usize;
+                let parent_cells = bus.address_cells as usize;
+                let size_cells = bus.prop_u32("#size-cells").unwrap_or(1) as usize;
+                if child_cells > 2 || parent_cells > 2 || size_cells > 2 {
+                    return None;
+                }
+
+                let len = 4 * (child_cells + parent_cells + size_cells);
+                if len == 0 {
+                    return None;
+                }
+                address = ranges.value.chunks_exact(len).find_map(|range| {
+                    let (child, rest) = range.split_at(4 * child_cells);
+                    let (parent, size) = rest.split_at(4 * parent_cells);
+                    let offset = address.checked_sub(read_cells(child))?;
+                    if offset >= read_cells(size) {
+                        return None;
+                    }
+                    read_cells(parent).checked_add(offset)
+                })?;
+            }
+            bus = parent;
+        }
+        Some(address)
+    }
+
+    /// Decodes the properties of `/chosen` that the kernel understands.
+    pub fn chosen(&self) -> Option<Chosen<'a>> {
+        let chosen = self.find_node("/chosen")?;
+        let initrd_start = chosen
+            .property("linux,initrd-start")
+            .and_then(|p| p.as_number());
+        let initrd_end = chosen
+            .property("linux,initrd-end")
+            .and_then(|p| p.as_number());
+        Some(Chosen {
+            bootargs: chosen.prop_str("bootargs"),
+            stdout_path: chosen
+                .prop_str("stdout-path")
+                .or_else(|| chosen.prop_str("linux,stdout-path")),
+            initrd: match (initrd_start, initrd_end) {
+                (Some(start), Some(end)) if start < end => Some(start..end),
+                _ => None,
+            },
+        })
+    }
+
+    /// Finds the node whose `phandle` (or legacy `linux,phandle`) property is
+    /// `phandle`.
+    pub fn node_by_phandle(&self, phandle: u32) -> Option<DtNode<'a>> {
+        match self.index {
+            Some(index) => index.phandles.get(&phandle).map(|&i| index.nodes[i]),
+            None => self.nodes().find(|node| node.phandle() == Some(phandle)),
+        }
+    }
+
+    /// Returns the parent of `node`, or `None` for the root node.
+    pub fn parent(&self, node: &DtNode<'a>) -> Option<DtNode<'a>> {
+        if let Some(index) = self.index {
+            let parent = index.parents[index.position(node)?]?;
+            return Some(index.nodes[parent]);
+        }
+        fn search<'a>(parent: DtNode<'a>, node: &DtNode<'a>) -> Option<DtNode<'a>> {
+            parent.children().find_map(|child| {
+                if child == *node {
+                    Some(parent)
+                } else {
+                    search(child, node)
+                }
+            })
+        }
+        search(self.root_node(), node)
+    }
+
+    /// Finds the interrupt domain `node`'s interrupts are delivered to: the
+    /// node referenced by the nearest `interrupt-parent`, walking up the tree
+    /// when a node has none, that has an `#interrupt-cells` property. A
+    /// malformed tree whose `interrupt-parent`s form a cycle has none.
+    pub fn interrupt_parent(&self, node: &DtNode<'a>) -> Option<DtNode<'a>> {
+        let mut node = *node;
+        for _ in 0..MAX_INTERRUPT_HOPS {
+            node = match node.prop_u32("interrupt-parent") {
+                Some(phandle) => self.node_by_phandle(phandle)?,
+                None => self.parent(&node)?,
+            };
+            if node.property("#interrupt-cells").is_some() {
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    /// Resolves the interrupts of `node` from either its
+    /// `interrupts-extended` property or its `interrupts` property and
+    /// interrupt parent.
+    pub fn interrupts(&self, node: &DtNode<'a>) -> Interrupts<'a> {
+        if let Some(prop) = node.property("interrupts-extended") {
+            return Interrupts {
+                dt: *self,
+                value: prop.value,
+                parent: None,
+            };
+        }
+        match (node.property("interrupts"), self.interrupt_parent(node)) {
+            (Some(prop), Some(parent)) => Interrupts {
+                dt: *self,
+                value: prop.value,
+                parent: Some(parent),
+            },
+            _ => Interrupts {
+                dt: *self,
+                value: &[],
+                parent: None,
+            },
         }
     }
 
@@ -96,24 +525,40 @@ impl<'a> DeviceTree<'a> {
         StructItemIter {
             dt_struct,
             dt_strings,
+            offset: 0,
         }
     }
 }
 
+pub struct Chosen<'a> {
+    pub bootargs: Option<&'a str>,
+    /// Path (or alias) of the boot console, optionally followed by `:` and
+    /// console options such as the baud rate. Older trees call it
+    /// `linux,stdout-path`.
+    pub stdout_path: Option<&'a str>,
+    /// Physical address range of the initial ramdisk.
+    pub initrd: Option<Range<u64>>,
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct MemoryReservation {
     pub address: u64,
     pub size: u64,
 }
 
+#[derive(Clone, Copy)]
 pub struct DtNode<'a> {
     pub name: &'a str,
     iter: StructItemIter<'a>,
+    /// `#address-cells` and `#size-cells` of the parent node, which describe
+    /// the layout of this node's `reg` property.
+    address_cells: u32,
+    size_cells: u32,
 }
 
 impl<'a> DtNode<'a> {
     pub fn properties(&self) -> impl Iterator<Item = Property<'a>> {
         self.iter
-            .clone()
             .map_while(|item| match item {
                 StructItem::Prop { name, value } => Some(Property { name, value }),
                 _ => None,
@@ -121,22 +566,251 @@ impl<'a> DtNode<'a> {
             .fuse()
     }
 
+    pub fn property(&self, name: &str) -> Option<Property<'a>> {
+        self.properties().find(|prop| prop.name == name)
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        self.property(name)?.as_u32()
+    }
+
+    pub fn prop_u64(&self, name: &str) -> Option<u64> {
+        self.property(name)?.as_u64()
+    }
+
+    pub fn prop_str(&self, name: &str) -> Option<&'a str> {
+        self.property(name)?.as_str()
+    }
+
+    pub fn prop_string_list(&self, name: &str) -> Option<StringList<'a>> {
+        Some(self.property(name)?.as_string_list())
+    }
+
+    /// Whether the device is there to use: its `status` is `okay`, or it
+    /// has none.
+    pub fn is_enabled(&self) -> bool {
+        matches!(self.prop_str("status"), None | Some("okay" | "ok"))
+    }
+
+    /// Decodes the `reg` property using the parent's `#address-cells` and
+    /// `#size-cells`. Returns `None` if there is no `reg` property or the
+    /// cell counts don't fit in a `u64`.
+    pub fn reg(&self) -> Option<RegIter<'a>> {
+        if self.address_cells > 2 || self.size_cells > 2 {
+            return None;
+        }
+        let value = self.property("reg")?.value;
+        Some(RegIter {
+            value,
+            address_cells: self.address_cells as usize,
+            size_cells: self.size_cells as usize,
+        })
+    }
+
+    pub fn phandle(&self) -> Option<u32> {
+        self.prop_u32("phandle")
+            .or_else(|| self.prop_u32("linux,phandle"))
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.prop_string_list("compatible")
+            .is_some_and(|mut list| list.any(|c| c == compatible))
+    }
+
+    /// Returns the child node called `name`. If `name` has no unit address
+    /// the first child whose name matches, ignoring its unit address, is
+    /// returned.
+    pub fn child(&self, name: &str) -> Option<DtNode<'a>> {
+        self.children()
+            .find(|child| child.name == name || (!name.contains('@') && child.base_name() == name))
+    }
+
+    /// The node name without its unit address.
+    pub fn base_name(&self) -> &'a str {
+        self.name
+            .split_once('@')
+            .map_or(self.name, |(name, _)| name)
+    }
+
+    /// Like [`DtNode::reg`], but with addresses translated to CPU physical
+    /// addresses through the `ranges` of every parent bus. Iteration stops at
+    /// the first entry that can't be translated.
+    pub fn reg_translated(&self, dt: &DeviceTree<'a>) -> Option<impl Iterator<Item = Reg> + 'a> {
+        let dt = *dt;
+        let bus = dt.parent(self)?;
+        Some(self.reg()?.map_while(move |reg| {
+            Some(Reg {
+                address: dt.translate_address(&bus, reg.address)?,
+                size: reg.size,
+            })
+        }))
+    }
+
     pub fn children(&self) -> Children<'a> {
         Children {
-            iter: self.iter.clone(),
+            iter: self.iter,
             depth: 1,
+            address_cells: self.prop_u32("#address-cells").unwrap_or(2),
+            size_cells: self.prop_u32("#size-cells").unwrap_or(1),
         }
     }
 }
 
+/// Two `DtNode`s are equal if they refer to the same node of the same tree.
+impl PartialEq for DtNode<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        core::ptr::eq(self.iter.dt_struct, other.iter.dt_struct)
+    }
+}
+
+/// A resolved interrupt specifier: the interrupt controller (or nexus) the
+/// interrupt is delivered to and the `#interrupt-cells` cells describing it.
+pub struct Interrupt<'a> {
+    pub parent: DtNode<'a>,
+    specifier: &'a [u8],
+}
+
+impl Interrupt<'_> {
+    pub fn cells(&self) -> impl Iterator<Item = u32> + '_ {
+        self.specifier
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+    }
+
+    pub fn cell(&self, index: usize) -> Option<u32> {
+        self.cells().nth(index)
+    }
+}
+
+/// Iterator over the interrupts of a node, see [`DeviceTree::interrupts`].
+pub struct Interrupts<'a> {
+    dt: DeviceTree<'a>,
+    value: &'a [u8],
+    /// The parent of every specifier for `interrupts`, or `None` when
+    /// decoding `interrupts-extended` where each specifier names its parent.
+    parent: Option<DtNode<'a>>,
+}
+
+impl<'a> Iterator for Interrupts<'a> {
+    type Item = Interrupt<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let parent = match self.parent {
+            Some(parent) => parent,
+            None => {
+                let phandle = self.value.get(0..4)?;
+                self.value = &self.value[4..];
+                self.dt
+                    .node_by_phandle(u32::from_be_bytes(phandle.try_into().unwrap()))?
+            }
+        };
+        let cells = parent.prop_u32("#interrupt-cells")? as usize;
+        if cells == 0 || self.value.len() < 4 * cells {
+            return None;
+        }
+        let (specifier, rest) = self.value.split_at(4 * cells);
+        self.value = rest;
+        Some(Interrupt { parent, specifier })
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct Property<'a> {
     pub name: &'a str,
     pub value: &'a [u8],
 }
 
+impl<'a> Property<'a> {
+    pub fn as_u32(&self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.value.try_into().ok()?))
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.value.try_into().ok()?))
+    }
+
+    /// Decodes a one or two cell number, as used by properties whose width
+    /// varies between platforms.
+    pub fn as_number(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => self.as_u32().map(u64::from),
+            8 => self.as_u64(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        let s = CStr::from_bytes_with_nul(self.value).ok()?;
+        s.to_str().ok()
+    }
+
+    pub fn as_string_list(&self) -> StringList<'a> {
+        StringList { value: self.value }
+    }
+}
+
+/// Iterator over the NUL-separated strings of a `<stringlist>` property.
+/// Strings that aren't UTF-8 are skipped, rather than ending the list.
+#[derive(Clone, Copy)]
+pub struct StringList<'a> {
+    value: &'a [u8],
+}
+
+impl<'a> Iterator for StringList<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let s = CStr::from_bytes_until_nul(self.value).ok()?;
+            self.value = &self.value[s.count_bytes() + 1..];
+            if let Ok(s) = s.to_str() {
+                return Some(s);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Reg {
+    pub address: u64,
+    pub size: u64,
+}
+
+pub struct RegIter<'a> {
+    value: &'a [u8],
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl<'a> Iterator for RegIter<'a> {
+    type Item = Reg;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = 4 * (self.address_cells + self.size_cells);
+        if len == 0 || self.value.len() < len {
+            return None;
+        }
+        let (address, rest) = self.value.split_at(4 * self.address_cells);
+        let (size, rest) = rest.split_at(4 * self.size_cells);
+        self.value = rest;
+        Some(Reg {
+            address: read_cells(address),
+            size: read_cells(size),
+        })
+    }
+}
+
+fn read_cells(bytes: &[u8]) -> u64 {
+    bytes.chunks_exact(4).fold(0, |acc, cell| {
+        (acc << 32) | u32::from_be_bytes(cell.try_into().unwrap()) as u64
+    })
+}
+
 pub struct Children<'a> {
     iter: StructItemIter<'a>,
     depth: usize,
+    address_cells: u32,
+    size_cells: u32,
 }
 
 impl<'a> Iterator for Children<'a> {
@@ -149,7 +823,9 @@ impl<'a> Iterator for Children<'a> {
                     if self.depth == 2 {
                         return Some(DtNode {
                             name,
-                            iter: self.iter.clone(),
+                            iter: self.iter,
+                            address_cells: self.address_cells,
+                            size_cells: self.size_cells,
                         });
                     }
                 }
@@ -161,6 +837,59 @@ impl<'a> Iterator for Children<'a> {
     }
 }
 
+/// Nodes nested deeper than this share the `#address-cells` and `#size-cells`
+/// of their ancestor at this depth.
+const MAX_DEPTH: usize = 16;
+
+/// More steps than this from a node to its interrupt domain, up the tree
+/// or along `interrupt-parent`, is taken to be a cycle.
+const MAX_INTERRUPT_HOPS: usize = 64;
+
+pub struct Nodes<'a> {
+    iter: StructItemIter<'a>,
+    depth: usize,
+    /// `#address-cells` and `#size-cells` of each currently open node.
+    cells: [(u32, u32); MAX_DEPTH],
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = DtNode<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next()? {
+                StructItem::BeginNode { name } => {
+                    let (address_cells, size_cells) = match self.depth {
+                        0 => (2, 1),
+                        depth => self.cells[(depth - 1).min(MAX_DEPTH - 1)],
+                    };
+                    if self.depth < MAX_DEPTH {
+                        self.cells[self.depth] = (2, 1);
+                    }
+                    self.depth += 1;
+                    return Some(DtNode {
+                        name,
+                        iter: self.iter,
+                        address_cells,
+                        size_cells,
+                    });
+                }
+                StructItem::EndNode => self.depth = self.depth.saturating_sub(1),
+                StructItem::Prop { name, value } => {
+                    let Some(cells) = self.cells.get_mut(self.depth.wrapping_sub(1)) else {
+                        continue;
+                    };
+                    let value = Property { name, value }.as_u32();
+                    match (name, value) {
+                        ("#address-cells", Some(value)) => cells.0 = value,
+                        ("#size-cells", Some(value)) => cells.1 = value,
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
 enum StructItem<'a> {
     BeginNode { name: &'a str },
     EndNode,
@@ -171,52 +900,381 @@ enum StructItem<'a> {
 struct StructItemIter<'a> {
     dt_struct: &'a [u8],
     dt_strings: &'a [u8],
+    /// Offset of `dt_struct` from the start of the structure block.
+    offset: usize,
 }
 
-impl<'a> Iterator for StructItemIter<'a> {
-    type Item = StructItem<'a>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        while !self.dt_struct.is_empty() {
-            let node = u32::from_be_bytes(self.dt_struct[0..4].try_into().unwrap());
-            match node {
+impl<'a> StructItemIter<'a> {
+    /// Returns the next item, or `None` once `FDT_END` has been reached.
+    fn next_item(&mut self) -> Result<Option<StructItem<'a>>, FdtError> {
+        loop {
+            let offset = self.offset;
+            let token = self.read_u32(0).ok_or(FdtError::Truncated { offset })?;
+            match token {
                 FDT_BEGIN_NODE => {
-                    let name = CStr::from_bytes_until_nul(&self.dt_struct[4..]).unwrap();
-                    let name = name.to_str().unwrap();
-                    let sz_name = align_up(name.len() + 1, 4);
-                    self.dt_struct = &self.dt_struct[4 + sz_name..];
-                    return Some(StructItem::BeginNode { name });
+                    let name = CStr::from_bytes_until_nul(&self.dt_struct[4..])
+                        .ok()
+                        .and_then(|name| name.to_str().ok())
+                        .ok_or(FdtError::BadNodeName { offset })?;
+                    self.advance(4 + align_up(name.len() + 1, 4))?;
+                    return Ok(Some(StructItem::BeginNode { name }));
                 }
                 FDT_END_NODE => {
-                    self.dt_struct = &self.dt_struct[4..];
-                    return Some(StructItem::EndNode);
+                    self.advance(4)?;
+                    return Ok(Some(StructItem::EndNode));
                 }
                 FDT_PROP => {
-                    let len = u32::from_be_bytes(self.dt_struct[4..8].try_into().unwrap());
-                    let nameoff = u32::from_be_bytes(self.dt_struct[8..12].try_into().unwrap());
-                    let name =
-                        CStr::from_bytes_until_nul(&self.dt_strings[nameoff as usize..]).unwrap();
-                    let name = name.to_str().unwrap();
-                    let value = &self.dt_struct[12..][..len as usize];
-
-                    let aligned = align_up(len as usize, 4);
-                    self.dt_struct = &self.dt_struct[12 + aligned..];
-                    return Some(StructItem::Prop { name, value });
-                }
-                FDT_NOP => self.dt_struct = &self.dt_struct[4..],
-                FDT_END => {
-                    self.dt_struct = &self.dt_struct[4..];
-                    debug_assert!(self.dt_struct.is_empty());
+                    let (Some(len), Some(nameoff)) = (self.read_u32(4), self.read_u32(8)) else {
+                        return Err(FdtError::Truncated { offset });
+                    };
+                    let name = self
+                        .dt_strings
+                        .get(nameoff as usize..)
+                        .and_then(|s| CStr::from_bytes_until_nul(s).ok())
+                        .and_then(|name| name.to_str().ok())
+                        .ok_or(FdtError::BadPropName { offset, nameoff })?;
+                    let value = self.dt_struct[12..]
+                        .get(..len as usize)
+                        .ok_or(FdtError::BadPropLength { offset, len })?;
+
+                    self.advance(12 + align_up(len as usize, 4))?;
+                    return Ok(Some(StructItem::Prop { name, value }));
                 }
-                _ => panic!("unrecognized FDT node"),
+                FDT_NOP => self.advance(4)?,
+                // Don't advance past FDT_END so that it's returned again.
+                FDT_END => return Ok(None),
+                token => return Err(FdtError::BadToken { offset, token }),
             }
         }
-        None
+    }
+
+    fn read_u32(&self, at: usize) -> Option<u32> {
+        let bytes = self.dt_struct.get(at..at + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn advance(&mut self, len: usize) -> Result<(), FdtError> {
+        self.dt_struct = self.dt_struct.get(len..).ok_or(FdtError::Truncated {
+            offset: self.offset + self.dt_struct.len(),
+        })?;
+        self.offset += len;
+        Ok(())
     }
 }
 
+impl<'a> Iterator for StructItemIter<'a> {
+    type Item = StructItem<'a>;
+
+    /// The structure block has been validated, so errors can only mean the
+    /// end of the block.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_item().ok().flatten()
+    }
+}
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
 const FDT_BEGIN_NODE: u32 = 0x1;
 const FDT_END_NODE: u32 = 0x2;
 const FDT_PROP: u32 = 0x3;
 const FDT_NOP: u32 = 0x4;
 const FDT_END: u32 = 0x9;
+
+ktest! {
+    fn index_matches_search() {
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.root_mut();
+        let soc = root.add_child(FdtNode::new("soc"));
+        soc.set_u32("phandle", 1);
+        let intc = soc.add_child(FdtNode::new("intc"));
+        intc.set_u32("phandle", 2);
+        intc.add_child(FdtNode::new("leaf"));
+        root.add_child(FdtNode::new("chosen")).set_u32("linux,phandle", 3);
+
+        let blob = fdt.to_aligned_blob();
+        let searched = blob.parse().unwrap();
+        let mut indexed = searched;
+        indexed.build_index();
+
+        for dt in [searched, indexed] {
+            let leaf = dt.find_node("/soc/intc/leaf").unwrap();
+            let intc = dt.parent(&leaf).unwrap();
+            assert!(intc == dt.node_by_phandle(2).unwrap());
+            assert!(dt.parent(&intc).unwrap() == dt.node_by_phandle(1).unwrap());
+            assert!(dt.parent(&dt.root_node()).is_none());
+            assert_eq!(dt.node_by_phandle(3).unwrap().name, "chosen");
+            assert!(dt.node_by_phandle(4).is_none());
+        }
+    }
+}
+
+ktest! {
+    fn finds_nodes_by_path() {
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.root_mut();
+        let soc = root.add_child(FdtNode::new("soc"));
+        soc.add_child(FdtNode::new("uart@10000000"));
+        soc.add_child(FdtNode::new("uart@10001000"));
+        let aliases = root.add_child(FdtNode::new("aliases"));
+        aliases.set_str("serial1", "/soc/uart@10001000");
+        aliases.set_str("bus", "/soc");
+        aliases.set_str("relative", "soc");
+
+        let blob = fdt.to_aligned_blob();
+        let dt = blob.parse().unwrap();
+
+        assert!(dt.find_node("/").unwrap() == dt.root_node());
+        assert_eq!(dt.find_node("/soc/uart@10001000").unwrap().name, "uart@10001000");
+        // The first match, without a unit address.
+        assert_eq!(dt.find_node("/soc/uart").unwrap().name, "uart@10000000");
+        assert_eq!(dt.find_node("serial1").unwrap().name, "uart@10001000");
+        assert_eq!(dt.find_node("bus/uart@10001000").unwrap().name, "uart@10001000");
+        assert!(dt.find_node("/soc/uart@10002000").is_none());
+        assert!(dt.find_node("/soc/uart@10000000/missing").is_none());
+        assert!(dt.find_node("serial0").is_none());
+        assert!(dt.find_node("relative").is_none());
+    }
+}
+
+ktest! {
+    fn finds_nodes_by_compatible() {
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.root_mut();
+        let soc = root.add_child(FdtNode::new("soc"));
+        soc.set_string_list("compatible", &["simple-bus"]);
+        soc.add_child(FdtNode::new("uart@10000000"))
+            .set_string_list("compatible", &["sifive,uart0", "ns16550a"]);
+        soc.add_child(FdtNode::new("uart@10001000"))
+            .set_property("compatible", b"vendor,\xff\0ns16550a\0");
+        soc.add_child(FdtNode::new("rtc@101000"))
+            .set_string_list("compatible", &["google,goldfish-rtc"]);
+        // Only a prefix of a compatible string.
+        soc.add_child(FdtNode::new("uart@10002000"))
+            .set_string_list("compatible", &["ns16550"]);
+
+        let blob = fdt.to_aligned_blob();
+        let dt = blob.parse().unwrap();
+
+        let names: Vec<_> = dt.nodes_compatible("ns16550a").map(|node| node.name).collect();
+        assert_eq!(names, ["uart@10000000", "uart@10001000"]);
+        assert_eq!(dt.nodes_compatible("sifive,uart0").count(), 1);
+        assert_eq!(dt.nodes_compatible("simple-bus").next().unwrap().name, "soc");
+        assert_eq!(dt.nodes_compatible("ns16550").count(), 1);
+        assert_eq!(dt.nodes_compatible("sifive").count(), 0);
+    }
+}
+
+ktest! {
+    fn resolves_interrupts() {
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.root_mut();
+        let cpu = root.add_child(FdtNode::new("cpu-intc"));
+        cpu.set_u32("phandle", 1);
+        cpu.set_u32("#interrupt-cells", 1);
+        let soc = root.add_child(FdtNode::new("soc"));
+        soc.set_u32("interrupt-parent", 2);
+        let plic = soc.add_child(FdtNode::new("plic@c000000"));
+        plic.set_u32("phandle", 2);
+        plic.set_u32("#interrupt-cells", 2);
+        plic.set_cells("interrupts-extended", &[1, 11, 1, 9]);
+        soc.add_child(FdtNode::new("uart@10000000")).set_cells("interrupts", &[10, 4, 12, 4]);
+        let looped = root.add_child(FdtNode::new("looped"));
+        looped.set_u32("phandle", 3);
+        looped.set_u32("interrupt-parent", 3);
+        looped.set_cells("interrupts", &[5]);
+
+        let blob = fdt.to_aligned_blob();
+        let searched = blob.parse().unwrap();
+        let mut indexed = searched;
+        indexed.build_index();
+
+        for dt in [searched, indexed] {
+            let plic = dt.node_by_phandle(2).unwrap();
+            let uart = dt.find_node("/soc/uart").unwrap();
+            assert!(dt.interrupt_parent(&uart).unwrap() == plic);
+            let irqs: Vec<_> = dt.interrupts(&uart).collect();
+            assert_eq!(irqs.len(), 2);
+            assert!(irqs.iter().all(|irq| irq.parent == plic));
+            assert_eq!(irqs[1].cells().collect::<Vec<_>>(), [12, 4]);
+
+            let harts: Vec<_> = dt.interrupts(&plic).collect();
+            assert_eq!(harts.len(), 2);
+            assert_eq!(harts[0].parent.name, "cpu-intc");
+            assert_eq!((harts[0].cell(0), harts[1].cell(0)), (Some(11), Some(9)));
+            assert_eq!(harts[1].cell(1), None);
+
+            let looped = dt.find_node("/looped").unwrap();
+            assert!(dt.interrupt_parent(&looped).is_none());
+            assert_eq!(dt.interrupts(&looped).count(), 0);
+        }
+    }
+}
+
+ktest! {
+    fn parses_chosen() {
+        let parse = |fdt: &FdtBuilder, test: &dyn Fn(Chosen<'_>)| {
+            let blob = fdt.to_aligned_blob();
+            let dt = blob.parse().unwrap();
+            test(dt.chosen().unwrap());
+        };
+
+        let mut fdt = FdtBuilder::new();
+        let chosen = fdt.root_mut().add_child(FdtNode::new("chosen"));
+        chosen.set_str("bootargs", "quiet loglevel=7");
+        chosen.set_str("stdout-path", "serial0:115200n8");
+        chosen.set_u32("linux,initrd-start", 0x8800_0000);
+        chosen.set_u64("linux,initrd-end", 0x8820_0000);
+        parse(&fdt, &|chosen| {
+            assert_eq!(chosen.bootargs, Some("quiet loglevel=7"));
+            assert_eq!(chosen.stdout_path, Some("serial0:115200n8"));
+            assert_eq!(chosen.initrd, Some(0x8800_0000..0x8820_0000));
+        });
+
+        let chosen = fdt.node_mut("/chosen").unwrap();
+        chosen.remove_property("bootargs");
+        chosen.remove_property("stdout-path");
+        chosen.set_str("linux,stdout-path", "/soc/uart@10000000");
+        // Empty, so there's no initrd.
+        chosen.set_u32("linux,initrd-end", 0x8800_0000);
+        parse(&fdt, &|chosen| {
+            assert_eq!(chosen.bootargs, None);
+            assert_eq!(chosen.stdout_path, Some("/soc/uart@10000000"));
+            assert_eq!(chosen.initrd, None);
+        });
+    }
+}
+
+ktest! {
+    fn translates_through_ranges() {
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.root_mut();
+        root.set_u32("#address-cells", 2);
+        root.set_u32("#size-cells", 2);
+        let soc = root.add_child(FdtNode::new("soc"));
+        soc.set_u32("#address-cells", 1);
+        soc.set_u32("#size-cells", 1);
+        // 0x0-0x10000 of the bus is at 0x4_1000_0000, and nothing else is.
+        soc.set_cells("ranges", &[0x0, 0x4, 0x1000_0000, 0x1_0000]);
+        let bridge = soc.add_child(FdtNode::new("bridge@8000"));
+        bridge.set_u32("#address-cells", 1);
+        bridge.set_u32("#size-cells", 1);
+        bridge.set_property("ranges", &[]);
+        bridge
+            .add_child(FdtNode::new("uart@8100"))
+            .set_cells("reg", &[0x8100, 0x100, 0x2_0000, 0x100]);
+        let closed = soc.add_child(FdtNode::new("closed@0"));
+        closed.set_cells("reg", &[0x0, 0x10]);
+        closed.add_child(FdtNode::new("leaf@4")).set_cells("reg", &[0x4, 0x4]);
+        let wrapping = root.add_child(FdtNode::new("wrapping"));
+        wrapping.set_u32("#address-cells", 1);
+        wrapping.set_u32("#size-cells", 1);
+        wrapping.set_cells("ranges", &[0x0, 0xffff_ffff, 0xffff_ffff, 0x1000]);
+        wrapping.add_child(FdtNode::new("dev@10")).set_cells("reg", &[0x10, 0x10]);
+        // No cells at all, so no size for a range.
+        let zero = root.add_child(FdtNode::new("zero"));
+        zero.set_u32("#address-cells", 0);
+        zero.set_u32("#size-cells", 0);
+        let empty = zero.add_child(FdtNode::new("empty"));
+        empty.set_u32("#address-cells", 0);
+        empty.set_u32("#size-cells", 0);
+        empty.set_cells("ranges", &[0]);
+
+        let blob = fdt.to_aligned_blob();
+        let dt = blob.parse().unwrap();
+
+        let uart = dt.find_node("/soc/bridge/uart").unwrap();
+        let regs: Vec<_> = uart
+            .reg_translated(&dt)
+            .unwrap()
+            .map(|reg| (reg.address, reg.size))
+            .collect();
+        // The second is outside the range, which ends the list.
+        assert_eq!(regs, [(0x4_1000_8100, 0x100)]);
+        // `closed@0` has no `ranges`, so nothing behind it is visible.
+        let leaf = dt.find_node("/soc/closed/leaf").unwrap();
+        assert_eq!(leaf.reg_translated(&dt).unwrap().count(), 0);
+        let dev = dt.find_node("/wrapping/dev").unwrap();
+        assert_eq!(dev.reg_translated(&dt).unwrap().count(), 0);
+        let empty = dt.find_node("/zero/empty").unwrap();
+        assert_eq!(dt.translate_address(&empty, 0), None);
+    }
+}
+
+ktest! {
+    fn rejects_malformed_blobs() {
+        let mut fdt = FdtBuilder::new();
+        fdt.root_mut().set_u32("a", 1);
+        fdt.root_mut().add_child(FdtNode::new("c"));
+        let blob = fdt.to_blob();
+
+        let parse = |blob: &[u8]| AlignedBlob::new(blob).parse().map(|_| ());
+        let with = |at: usize, value: u32| {
+            let mut blob = blob.clone();
+            blob[at..at + 4].copy_from_slice(&value.to_be_bytes());
+            blob
+        };
+        let field = |at: usize| u32::from_be_bytes(blob[at..at + 4].try_into().unwrap());
+        assert!(parse(&blob).is_ok());
+
+        // The structure block is the root's name, its property, the child
+        // and the two ends, then FDT_END.
+        let dt_struct = field(8) as usize;
+        let size_dt_struct = field(36);
+        let tokens = [
+            (0, FDT_BEGIN_NODE),
+            (8, FDT_PROP),
+            (24, FDT_BEGIN_NODE),
+            (32, FDT_END_NODE),
+            (36, FDT_END_NODE),
+            (40, FDT_END),
+        ];
+        for (at, token) in tokens {
+            assert_eq!(field(dt_struct + at), token);
+        }
+
+        // SAFETY: the pointer is only checked.
+        let misaligned = unsafe { DeviceTree::from_ptr(blob.as_ptr().wrapping_add(1)) };
+        assert!(matches!(misaligned, Err(FdtError::BadPointer(_))));
+        assert!(matches!(parse(&with(0, 0)), Err(FdtError::BadMagic(0))));
+        assert!(matches!(
+            parse(&with(20, 16)),
+            Err(FdtError::UnsupportedVersion { version: 16, .. })
+        ));
+        assert!(matches!(
+            parse(&with(4, 39)),
+            Err(FdtError::HeaderTooSmall { totalsize: 39 })
+        ));
+        assert!(matches!(
+            parse(&with(4, dt_struct as u32 + 8)),
+            Err(FdtError::BadBlock { block: "structure", .. })
+        ));
+        // Too near the end for even the terminating entry.
+        let totalsize = field(4);
+        assert!(matches!(
+            parse(&with(16, totalsize & !7)),
+            Err(FdtError::UnterminatedReservations { .. })
+        ));
+        assert!(matches!(parse(&with(36, 40)), Err(FdtError::Truncated { offset: 40 })));
+        assert!(matches!(parse(&with(36, size_dt_struct - 2)), Err(FdtError::Truncated { .. })));
+        assert!(matches!(
+            parse(&with(dt_struct + 8, 7)),
+            Err(FdtError::BadToken { offset: 8, token: 7 })
+        ));
+        assert!(matches!(
+            parse(&with(dt_struct + 16, 0x1000)),
+            Err(FdtError::BadPropName { offset: 8, nameoff: 0x1000 })
+        ));
+        assert!(matches!(
+            parse(&with(dt_struct + 12, 0x1000)),
+            Err(FdtError::BadPropLength { offset: 8, len: 0x1000 })
+        ));
+        // The root left open, and the child.
+        assert!(matches!(
+            parse(&with(dt_struct + 36, FDT_NOP)),
+            Err(FdtError::BadNesting { offset: 36 })
+        ));
+        assert!(matches!(
+            parse(&with(dt_struct + 32, FDT_END)),
+            Err(FdtError::BadNesting { offset: 32 })
+        ));
+    }
+}
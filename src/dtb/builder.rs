@@ -0,0 +1,355 @@
+//! A device tree that can be changed and written back out as a blob, for
+//! handing a trimmed or patched copy of the tree on rather than the one the
+//! kernel was booted with. The tree is copied out of the blob in full, so
+//! unlike `DeviceTree` it needs the heap.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::{
+    DeviceTree, DtHeader, DtNode, MemoryReservation, FDT_BEGIN_NODE, FDT_END, FDT_END_NODE,
+    FDT_MAGIC, FDT_PROP,
+};
+use crate::ktest;
+use crate::util::align_up;
+
+/// An owned, writable device tree.
+pub struct FdtBuilder {
+    root: FdtNode,
+    reservations: Vec<MemoryReservation>,
+    boot_cpuid_phys: u32,
+}
+
+impl FdtBuilder {
+    /// A copy of all of `dt`, including its memory reservations.
+    pub fn from_tree(dt: &DeviceTree<'_>) -> Self {
+        Self {
+            root: FdtNode::from_node(dt.root_node()),
+            reservations: dt.memory_reservations().collect(),
+            boot_cpuid_phys: dt.header.boot_cpuid_phys,
+        }
+    }
+
+    /// Looks up a node by its full path, with unit addresses optional as for
+    /// [`DeviceTree::find_node`]. Aliases aren't followed.
+    pub fn node_mut(&mut self, path: &str) -> Option<&mut FdtNode> {
+        let mut node = &mut self.root;
+        for component in path.strip_prefix('/')?.split('/').filter(|c| !c.is_empty()) {
+            node = node.child_mut(component)?;
+        }
+        Some(node)
+    }
+
+    /// Serializes the tree as a version 17 blob.
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut dt_struct = Vec::new();
+        let mut strings = Strings::default();
+        self.root.serialize(&mut dt_struct, &mut strings);
+        put_u32(&mut dt_struct, FDT_END);
+
+        let off_mem_rsvmap = DtHeader::SIZE;
+        let off_dt_struct = off_mem_rsvmap + (self.reservations.len() + 1) * 16;
+        let off_dt_strings = off_dt_struct + dt_struct.len();
+        let totalsize = off_dt_strings + strings.data.len();
+
+        let mut blob = Vec::with_capacity(totalsize);
+        for field in [
+            FDT_MAGIC,
+            totalsize as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            17,
+            16,
+            self.boot_cpuid_phys,
+            strings.data.len() as u32,
+            dt_struct.len() as u32,
+        ] {
+            put_u32(&mut blob, field);
+        }
+        for reservation in &self.reservations {
+            blob.extend_from_slice(&reservation.address.to_be_bytes());
+            blob.extend_from_slice(&reservation.size.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&dt_struct);
+        blob.extend_from_slice(&strings.data);
+        blob
+    }
+
+    /// Serializes the tree into memory it can be parsed back out of.
+    pub fn to_aligned_blob(&self) -> AlignedBlob {
+        AlignedBlob::new(&self.to_blob())
+    }
+}
+
+/// Building trees from scratch, which so far only tests do.
+#[cfg(feature = "ktest")]
+impl FdtBuilder {
+    /// A tree with nothing but an empty root node.
+    pub fn new() -> Self {
+        Self {
+            root: FdtNode::new(""),
+            reservations: Vec::new(),
+            boot_cpuid_phys: 0,
+        }
+    }
+
+    pub fn root_mut(&mut self) -> &mut FdtNode {
+        &mut self.root
+    }
+
+    /// Removes the node at `path` and everything under it. The root can't be
+    /// removed.
+    pub fn remove_node(&mut self, path: &str) -> Option<FdtNode> {
+        let (parent, name) = path.trim_end_matches('/').rsplit_once('/')?;
+        let parent = if parent.is_empty() { "/" } else { parent };
+        self.node_mut(parent)?.remove_child(name)
+    }
+
+    pub fn reservations_mut(&mut self) -> &mut Vec<MemoryReservation> {
+        &mut self.reservations
+    }
+
+    /// Sets the hart ID the blob says it's booted on, for handing the tree to
+    /// something started on a different hart.
+    pub fn set_boot_cpuid_phys(&mut self, hart: u32) {
+        self.boot_cpuid_phys = hart;
+    }
+}
+
+#[cfg(feature = "ktest")]
+impl Default for FdtBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A copy of a blob, 8-byte aligned as [`DeviceTree`] wants it, so that a
+/// built tree can be parsed back out.
+pub struct AlignedBlob(Vec<u64>);
+
+impl AlignedBlob {
+    /// Copies `blob`, padded out to at least a header so that a truncated
+    /// one is rejected rather than read past.
+    pub fn new(blob: &[u8]) -> Self {
+        let mut words = alloc::vec![0u64; blob.len().max(DtHeader::SIZE).div_ceil(8)];
+        // SAFETY: `words` is at least as long as `blob`.
+        unsafe {
+            core::ptr::copy_nonoverlapping(blob.as_ptr(), words.as_mut_ptr().cast(), blob.len())
+        };
+        Self(words)
+    }
+
+    pub fn parse(&self) -> Result<DeviceTree<'_>, super::FdtError> {
+        // SAFETY: the copy holds the whole blob, and at least a header.
+        unsafe { DeviceTree::from_ptr(self.0.as_ptr().cast()) }
+    }
+}
+
+/// A node of an [`FdtBuilder`]. Properties and children are kept in the
+/// order they were added.
+pub struct FdtNode {
+    name: String,
+    properties: Vec<(String, Vec<u8>)>,
+    children: Vec<FdtNode>,
+}
+
+impl FdtNode {
+    fn from_node(node: DtNode<'_>) -> Self {
+        Self {
+            name: node.name.to_string(),
+            properties: node
+                .properties()
+                .map(|prop| (prop.name.to_string(), prop.value.to_vec()))
+                .collect(),
+            children: node.children().map(Self::from_node).collect(),
+        }
+    }
+
+    /// Removes a property, returning its value.
+    pub fn remove_property(&mut self, name: &str) -> Option<Vec<u8>> {
+        let index = self.properties.iter().position(|(prop, _)| prop == name)?;
+        Some(self.properties.remove(index).1)
+    }
+
+    /// The child called `name`, matched as by [`DtNode::child`].
+    pub fn child_mut(&mut self, name: &str) -> Option<&mut FdtNode> {
+        self.children.iter_mut().find(|child| child.matches(name))
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name == name
+            || (!name.contains('@')
+                && self
+                    .name
+                    .split_once('@')
+                    .is_some_and(|(base, _)| base == name))
+    }
+
+    fn serialize(&self, dt_struct: &mut Vec<u8>, strings: &mut Strings) {
+        put_u32(dt_struct, FDT_BEGIN_NODE);
+        dt_struct.extend_from_slice(self.name.as_bytes());
+        dt_struct.push(0);
+        pad(dt_struct);
+        for (name, value) in &self.properties {
+            put_u32(dt_struct, FDT_PROP);
+            put_u32(dt_struct, value.len() as u32);
+            put_u32(dt_struct, strings.offset(name));
+            dt_struct.extend_from_slice(value);
+            pad(dt_struct);
+        }
+        for child in &self.children {
+            child.serialize(dt_struct, strings);
+        }
+        put_u32(dt_struct, FDT_END_NODE);
+    }
+}
+
+/// Filling in nodes, which so far only tests do.
+#[cfg(feature = "ktest")]
+impl FdtNode {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            properties: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sets a property, replacing its value if the node already has it.
+    pub fn set_property(&mut self, name: &str, value: &[u8]) {
+        match self.properties.iter_mut().find(|(prop, _)| prop == name) {
+            Some((_, old)) => *old = value.to_vec(),
+            None => self.properties.push((name.to_string(), value.to_vec())),
+        }
+    }
+
+    pub fn set_u32(&mut self, name: &str, value: u32) {
+        self.set_property(name, &value.to_be_bytes());
+    }
+
+    pub fn set_u64(&mut self, name: &str, value: u64) {
+        self.set_property(name, &value.to_be_bytes());
+    }
+
+    /// Sets a property to a list of cells.
+    pub fn set_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.set_property(name, &value);
+    }
+
+    pub fn set_str(&mut self, name: &str, value: &str) {
+        self.set_string_list(name, &[value]);
+    }
+
+    pub fn set_string_list(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for s in values {
+            value.extend_from_slice(s.as_bytes());
+            value.push(0);
+        }
+        self.set_property(name, &value);
+    }
+
+    /// Adds `child`, replacing any child with the same name, and returns it
+    /// to be filled in.
+    pub fn add_child(&mut self, child: FdtNode) -> &mut FdtNode {
+        let index = match self.children.iter().position(|c| c.name == child.name) {
+            Some(index) => {
+                self.children[index] = child;
+                index
+            }
+            None => {
+                self.children.push(child);
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[index]
+    }
+
+    pub fn remove_child(&mut self, name: &str) -> Option<FdtNode> {
+        let index = self.children.iter().position(|child| child.matches(name))?;
+        Some(self.children.remove(index))
+    }
+}
+
+/// The strings block, with each property name stored once.
+#[derive(Default)]
+struct Strings {
+    data: Vec<u8>,
+    offsets: BTreeMap<String, u32>,
+}
+
+impl Strings {
+    fn offset(&mut self, name: &str) -> u32 {
+        if let Some(&offset) = self.offsets.get(name) {
+            return offset;
+        }
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        self.offsets.insert(name.to_string(), offset);
+        offset
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Pads the structure block out to the next token.
+fn pad(dt_struct: &mut Vec<u8>) {
+    dt_struct.resize(align_up(dt_struct.len(), 4), 0);
+}
+
+ktest! {
+    fn round_trip() {
+        let mut fdt = FdtBuilder::new();
+        fdt.reservations_mut().push(MemoryReservation {
+            address: 0x8000_0000,
+            size: 0x20_0000,
+        });
+        let root = fdt.root_mut();
+        root.set_u32("#address-cells", 2);
+        root.set_u32("#size-cells", 2);
+        root.set_string_list("compatible", &["vendor,board", "simple"]);
+        let memory = root.add_child(FdtNode::new("memory@80000000"));
+        memory.set_str("device_type", "memory");
+        memory.set_cells("reg", &[0, 0x8000_0000, 0, 0x800_0000]);
+        let chosen = root.add_child(FdtNode::new("chosen"));
+        chosen.set_str("bootargs", "console=ttyS0");
+        chosen.set_property("secret", b"abc");
+        root.add_child(FdtNode::new("soc")).add_child(FdtNode::new("uart@10000000"));
+        fdt.node_mut("/chosen").unwrap().remove_property("secret");
+        fdt.node_mut("/memory").unwrap().set_cells("reg", &[0, 0x8000_0000, 0, 0x400_0000]);
+        let uart = fdt.remove_node("/soc/uart").unwrap();
+        assert_eq!(uart.name(), "uart@10000000");
+        assert!(fdt.remove_node("/soc/uart").is_none());
+        assert!(fdt.remove_node("/").is_none());
+        fdt.set_boot_cpuid_phys(3);
+
+        let blob = fdt.to_aligned_blob();
+        let dt = blob.parse().unwrap();
+
+        let memory = dt.find_node("/memory").unwrap();
+        let reg = memory.reg().unwrap().next().unwrap();
+        assert_eq!((reg.address, reg.size), (0x8000_0000, 0x400_0000));
+        let chosen = dt.chosen().unwrap();
+        assert_eq!(chosen.bootargs, Some("console=ttyS0"));
+        assert!(dt.find_node("/chosen").unwrap().property("secret").is_none());
+        assert!(dt.root_node().is_compatible("simple"));
+        assert_eq!(dt.find_node("/soc").unwrap().children().count(), 0);
+        assert_eq!(dt.header.boot_cpuid_phys, 3);
+        let reservations: Vec<_> = dt.memory_reservations().collect();
+        assert_eq!(reservations.len(), 1);
+        assert_eq!(reservations[0].size, 0x20_0000);
+
+        assert_eq!(FdtBuilder::from_tree(&dt).to_blob(), fdt.to_blob());
+    }
+}
@@ -0,0 +1,298 @@
+//! Printing the device tree back out as DTS source. The blob doesn't record
+//! what type a property is, so values are guessed at from their bytes:
+//! string lists if they look like text, cells if they're a multiple of 4
+//! bytes long and bytes otherwise. Cells of properties known to hold
+//! phandles are printed as references to the path of the node, e.g.
+//! `<&{/soc/plic@c000000} 0xa>`, which dtc accepts back.
+
+use core::fmt;
+
+use super::{DeviceTree, DtNode, Property};
+use crate::ktest;
+
+/// A node and everything under it, displayed as DTS.
+pub struct Dts<'d, 'a> {
+    dt: &'d DeviceTree<'a>,
+    node: DtNode<'a>,
+}
+
+impl<'a> DeviceTree<'a> {
+    /// Formats `node` and its descendants as DTS source.
+    pub fn dts(&self, node: DtNode<'a>) -> Dts<'_, 'a> {
+        Dts { dt: self, node }
+    }
+}
+
+/// How a property's cells refer to other nodes.
+#[derive(Clone, Copy)]
+enum Layout {
+    /// Every cell is a phandle.
+    Phandles,
+    /// Each phandle is followed by a specifier as many cells long as the
+    /// node it refers to says in this property.
+    Specifiers(&'static str),
+    /// Each entry is a child unit address and interrupt specifier, then a
+    /// phandle and the parent's unit address and interrupt specifier.
+    InterruptMap,
+}
+
+impl Layout {
+    fn of(name: &str) -> Option<Self> {
+        let layout = match name {
+            "interrupt-parent" | "msi-parent" | "cpu-idle-states" | "memory-region" | "regmap"
+            | "phy-handle" => Layout::Phandles,
+            "interrupts-extended" => Layout::Specifiers("#interrupt-cells"),
+            "clocks" | "assigned-clocks" => Layout::Specifiers("#clock-cells"),
+            "resets" => Layout::Specifiers("#reset-cells"),
+            "dmas" => Layout::Specifiers("#dma-cells"),
+            "phys" => Layout::Specifiers("#phy-cells"),
+            "iommus" => Layout::Specifiers("#iommu-cells"),
+            "mboxes" => Layout::Specifiers("#mbox-cells"),
+            "power-domains" => Layout::Specifiers("#power-domain-cells"),
+            "gpios" => Layout::Specifiers("#gpio-cells"),
+            name if name.ends_with("-gpios") => Layout::Specifiers("#gpio-cells"),
+            "interrupt-map" => Layout::InterruptMap,
+            _ => return None,
+        };
+        Some(layout)
+    }
+}
+
+/// A cell of a property, as it's printed.
+enum Cell<'a> {
+    Number(u32),
+    Ref(DtNode<'a>),
+}
+
+/// The cells of a property value, which must be a multiple of 4 bytes.
+struct Cells<'a>(&'a [u8]);
+
+impl Cells<'_> {
+    fn next(&mut self) -> Option<u32> {
+        let (cell, rest) = self.0.split_first_chunk::<4>()?;
+        self.0 = rest;
+        Some(u32::from_be_bytes(*cell))
+    }
+}
+
+impl<'a> Dts<'_, 'a> {
+    fn write_node(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        node: DtNode<'a>,
+        depth: usize,
+    ) -> fmt::Result {
+        let name = if depth == 0 && node == self.dt.root_node() {
+            "/"
+        } else {
+            node.name
+        };
+        indent(f, depth)?;
+        writeln!(f, "{} {{", name)?;
+        for prop in node.properties() {
+            indent(f, depth + 1)?;
+            self.write_property(f, &node, prop)?;
+        }
+        for child in node.children() {
+            self.write_node(f, child, depth + 1)?;
+        }
+        indent(f, depth)?;
+        writeln!(f, "}};")
+    }
+
+    fn write_property(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        node: &DtNode<'a>,
+        prop: Property<'a>,
+    ) -> fmt::Result {
+        let value = prop.value;
+        if value.is_empty() {
+            return writeln!(f, "{};", prop.name);
+        }
+        write!(f, "{} = ", prop.name)?;
+        if is_string_list(value) {
+            for (i, s) in prop.as_string_list().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "\"{}\"", s.escape_default())?;
+            }
+        } else if value.len().is_multiple_of(4) {
+            f.write_str("<")?;
+            let mut first = true;
+            let mut emit = |cell: Cell<'a>| {
+                if !first {
+                    f.write_str(" ")?;
+                }
+                first = false;
+                match cell {
+                    Cell::Number(n) => write!(f, "{:#x}", n),
+                    Cell::Ref(target) => {
+                        f.write_str("&{")?;
+                        self.write_path(f, &target)?;
+                        f.write_str("}")
+                    }
+                }
+            };
+            // References are only printed if the whole value makes sense as
+            // the property's layout, so a dry run checks it first.
+            match Layout::of(prop.name) {
+                Some(layout) if self.walk(node, layout, value, &mut |_| Ok(()))? => {
+                    self.walk(node, layout, value, &mut emit)?;
+                }
+                _ => {
+                    let mut cells = Cells(value);
+                    while let Some(cell) = cells.next() {
+                        emit(Cell::Number(cell))?;
+                    }
+                }
+            }
+            f.write_str(">")?;
+        } else {
+            f.write_str("[")?;
+            for (i, byte) in value.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(" ")?;
+                }
+                write!(f, "{:02x}", byte)?;
+            }
+            f.write_str("]")?;
+        }
+        writeln!(f, ";")
+    }
+
+    /// Passes each cell of `value`, a property of `node` laid out as
+    /// `layout`, to `emit`. Returns false, possibly after emitting some, if
+    /// the cells don't fit the layout, e.g. because a phandle is dangling.
+    fn walk(
+        &self,
+        node: &DtNode<'a>,
+        layout: Layout,
+        value: &'a [u8],
+        emit: &mut dyn FnMut(Cell<'a>) -> fmt::Result,
+    ) -> Result<bool, fmt::Error> {
+        fn numbers<'a>(
+            cells: &mut Cells<'a>,
+            count: u32,
+            emit: &mut dyn FnMut(Cell<'a>) -> fmt::Result,
+        ) -> Result<bool, fmt::Error> {
+            for _ in 0..count {
+                match cells.next() {
+                    Some(cell) => emit(Cell::Number(cell))?,
+                    None => return Ok(false),
+                }
+            }
+            Ok(true)
+        }
+
+        let mut cells = Cells(value);
+        while !cells.0.is_empty() {
+            if let Layout::InterruptMap = layout {
+                let count = node.prop_u32("#address-cells").unwrap_or(0)
+                    + node.prop_u32("#interrupt-cells").unwrap_or(0);
+                if !numbers(&mut cells, count, emit)? {
+                    return Ok(false);
+                }
+            }
+            let Some(target) = cells
+                .next()
+                .and_then(|phandle| self.dt.node_by_phandle(phandle))
+            else {
+                return Ok(false);
+            };
+            emit(Cell::Ref(target))?;
+            let count = match layout {
+                Layout::Phandles => Some(0),
+                Layout::Specifiers(cells_name) => target.prop_u32(cells_name),
+                Layout::InterruptMap => target
+                    .prop_u32("#interrupt-cells")
+                    .map(|count| count + target.prop_u32("#address-cells").unwrap_or(0)),
+            };
+            match count {
+                Some(count) if numbers(&mut cells, count, emit)? => {}
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Writes the full path of `node`.
+    fn write_path(&self, f: &mut fmt::Formatter<'_>, node: &DtNode<'a>) -> fmt::Result {
+        match self.dt.parent(node) {
+            None => f.write_str("/"),
+            Some(parent) => {
+                if self.dt.parent(&parent).is_some() {
+                    self.write_path(f, &parent)?;
+                }
+                write!(f, "/{}", node.name)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Dts<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_node(f, self.node, 0)
+    }
+}
+
+fn indent(f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+    for _ in 0..depth {
+        f.write_str("    ")?;
+    }
+    Ok(())
+}
+
+/// Whether `value` looks like one or more NUL-terminated strings of text.
+fn is_string_list(value: &[u8]) -> bool {
+    let Some((&0, strings)) = value.split_last() else {
+        return false;
+    };
+    strings
+        .split(|&b| b == 0)
+        .all(|s| !s.is_empty() && s.iter().all(|&b| b.is_ascii_graphic() || b == b' '))
+}
+
+ktest! {
+    fn prints_dts() {
+        use super::{FdtBuilder, FdtNode};
+
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.root_mut();
+        let plic = root.add_child(FdtNode::new("plic@c000000"));
+        plic.set_u32("phandle", 1);
+        plic.set_u32("#interrupt-cells", 1);
+        plic.set_property("interrupt-controller", b"");
+        let uart = root.add_child(FdtNode::new("uart@10000000"));
+        uart.set_string_list("compatible", &["sifive,uart0", "ns16550a"]);
+        uart.set_u32("interrupt-parent", 1);
+        uart.set_u32("clock-frequency", 0x38_4000);
+        uart.set_property("bytes", &[1, 2, 3]);
+        // Dangling, so just a number.
+        root.add_child(FdtNode::new("bad")).set_u32("interrupt-parent", 7);
+
+        let blob = fdt.to_aligned_blob();
+        let dt = blob.parse().unwrap();
+
+        let dts = alloc::format!("{}", dt.dts(dt.root_node()));
+        let expected = "/ {
+    plic@c000000 {
+        phandle = <0x1>;
+        #interrupt-cells = <0x1>;
+        interrupt-controller;
+    };
+    uart@10000000 {
+        compatible = \"sifive,uart0\", \"ns16550a\";
+        interrupt-parent = <&{/plic@c000000}>;
+        clock-frequency = <0x384000>;
+        bytes = [01 02 03];
+    };
+    bad {
+        interrupt-parent = <0x7>;
+    };
+};
+";
+        assert_eq!(dts, expected);
+    }
+}
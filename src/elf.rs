@@ -0,0 +1,532 @@
+//! Loading ELF executables into user address spaces, and laying out the
+//! headers of core files. Only statically linked RISC-V executables of the
+//! kernel's XLEN are supported: ELF64 on RV64 and ELF32 on RV32, which
+//! differ only in the sizes and offsets below.
+//!
+//! Static-PIE executables (`ET_DYN` without an interpreter) are loaded at a
+//! random base, and their `R_RISCV_RELATIVE` relocations applied, so that
+//! they start running already relocated.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::mm::addr_space::AddressSpace;
+use crate::mm::paging::{MapError, PteFlags, PAGE_SIZE};
+use crate::util::{align_down, align_up};
+use crate::{ktest, rand};
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const ET_CORE: u16 = 4;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_NOTE: u32 = 4;
+
+const DT_NULL: usize = 0;
+const DT_RELA: usize = 7;
+const DT_RELASZ: usize = 8;
+const DT_RELAENT: usize = 9;
+
+const R_RISCV_NONE: usize = 0;
+const R_RISCV_RELATIVE: usize = 3;
+
+/// Static-PIE executables are loaded at a random page in the
+/// `PIE_RANDOM_RANGE` bytes from `PIE_BASE`, well clear of both `brk` of
+/// fixed-address executables and the `mmap` area below the stack.
+const PIE_BASE: usize = 0x1000_0000;
+const PIE_RANDOM_RANGE: usize = 0x400_0000;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+/// Where the fields that are read or written are, in the file header
+/// (`E_*`) and in a program header (`P_*`).
+#[cfg(target_arch = "riscv64")]
+mod layout {
+    pub const CLASS: u8 = 2;
+    pub const EHDR_SIZE: usize = 64;
+    pub const PHDR_SIZE: usize = 56;
+    pub const E_ENTRY: usize = 24;
+    pub const E_PHOFF: usize = 32;
+    pub const E_EHSIZE: usize = 52;
+    pub const E_PHENTSIZE: usize = 54;
+    pub const E_PHNUM: usize = 56;
+    pub const P_FLAGS: usize = 4;
+    pub const P_OFFSET: usize = 8;
+    pub const P_VADDR: usize = 16;
+    pub const P_FILESZ: usize = 32;
+    pub const P_MEMSZ: usize = 40;
+    pub const P_ALIGN: usize = 48;
+    pub const RELA_SIZE: usize = 24;
+    /// `ELF64_R_TYPE`: the relocation type in `r_info`.
+    pub const R_TYPE_MASK: usize = 0xffff_ffff;
+}
+
+#[cfg(target_arch = "riscv32")]
+mod layout {
+    pub const CLASS: u8 = 1;
+    pub const EHDR_SIZE: usize = 52;
+    pub const PHDR_SIZE: usize = 32;
+    pub const E_ENTRY: usize = 24;
+    pub const E_PHOFF: usize = 28;
+    pub const E_EHSIZE: usize = 40;
+    pub const E_PHENTSIZE: usize = 42;
+    pub const E_PHNUM: usize = 44;
+    pub const P_FLAGS: usize = 24;
+    pub const P_OFFSET: usize = 4;
+    pub const P_VADDR: usize = 8;
+    pub const P_FILESZ: usize = 16;
+    pub const P_MEMSZ: usize = 20;
+    pub const P_ALIGN: usize = 28;
+    pub const RELA_SIZE: usize = 12;
+    /// `ELF32_R_TYPE`: the relocation type in `r_info`.
+    pub const R_TYPE_MASK: usize = 0xff;
+}
+
+use layout::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ElfError {
+    /// The image ends before a header or segment it describes.
+    Truncated,
+    BadMagic,
+    /// Not a little-endian RISC-V executable of the kernel's XLEN.
+    Unsupported,
+    /// A segment is outside user memory or overlaps another.
+    BadSegment,
+    /// A relocation of a type other than `R_RISCV_RELATIVE`, or outside the
+    /// loaded segments.
+    BadRelocation,
+    Map(MapError),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => f.write_str("truncated image"),
+            Self::BadMagic => f.write_str("not an ELF file"),
+            Self::Unsupported => f.write_str("not a RISC-V executable"),
+            Self::BadSegment => f.write_str("bad segment"),
+            Self::BadRelocation => f.write_str("bad relocation"),
+            Self::Map(err) => write!(f, "mapping failed: {:?}", err),
+        }
+    }
+}
+
+fn u16_at(image: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = image.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn u32_at(image: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = image.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// An address or offset, the size of a register.
+fn usize_at(image: &[u8], offset: usize) -> Result<usize, ElfError> {
+    const SIZE: usize = size_of::<usize>();
+    let bytes = image
+        .get(offset..offset + SIZE)
+        .ok_or(ElfError::Truncated)?;
+    Ok(usize::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// A `PT_LOAD` program header.
+struct Segment {
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    file_size: usize,
+    mem_size: usize,
+}
+
+impl Segment {
+    /// Where `len` bytes at `vaddr` are in the file, if this segment holds
+    /// them.
+    fn file_offset(&self, vaddr: usize, len: usize) -> Option<usize> {
+        let offset = vaddr.checked_sub(self.vaddr)?;
+        (offset.checked_add(len)? <= self.file_size).then(|| self.offset + offset)
+    }
+}
+
+/// Where a loaded executable starts, where it ends in memory, and what its
+/// auxiliary vector says about it.
+pub struct Loaded {
+    pub entry: usize,
+    /// The end of the highest segment.
+    pub end: usize,
+    /// Where the program headers are in memory, or 0 if they aren't loaded.
+    pub phdr: usize,
+    pub phent: usize,
+    pub phnum: usize,
+}
+
+/// Maps the loadable segments of `image` into `space`, at a random base if
+/// it's position-independent, and relocates them.
+pub fn load(space: &AddressSpace, image: &[u8]) -> Result<Loaded, ElfError> {
+    if image.len() < EHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if image[..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    let kind = u16_at(image, 16)?;
+    if image[4] != CLASS
+        || image[5] != ELFDATA2LSB
+        || (kind != ET_EXEC && kind != ET_DYN)
+        || u16_at(image, 18)? != EM_RISCV
+    {
+        return Err(ElfError::Unsupported);
+    }
+    let entry = usize_at(image, E_ENTRY)?;
+    let phoff = usize_at(image, E_PHOFF)?;
+    let phentsize = usize::from(u16_at(image, E_PHENTSIZE)?);
+    let phnum = usize::from(u16_at(image, E_PHNUM)?);
+    if phentsize < PHDR_SIZE {
+        return Err(ElfError::Unsupported);
+    }
+
+    let mut segments = Vec::new();
+    let mut dynamic = None;
+    let mut align = PAGE_SIZE;
+    for i in 0..phnum {
+        let phdr = phoff
+            .checked_add(i * phentsize)
+            .ok_or(ElfError::Truncated)?;
+        match u32_at(image, phdr)? {
+            PT_LOAD => {
+                segments.push(Segment {
+                    flags: u32_at(image, phdr + P_FLAGS)?,
+                    offset: usize_at(image, phdr + P_OFFSET)?,
+                    vaddr: usize_at(image, phdr + P_VADDR)?,
+                    file_size: usize_at(image, phdr + P_FILESZ)?,
+                    mem_size: usize_at(image, phdr + P_MEMSZ)?,
+                });
+                let segment_align = usize_at(image, phdr + P_ALIGN)?;
+                if segment_align.is_power_of_two() {
+                    align = align.max(segment_align);
+                }
+            }
+            PT_DYNAMIC => {
+                let offset = usize_at(image, phdr + P_OFFSET)?;
+                let size = usize_at(image, phdr + P_FILESZ)?;
+                dynamic = offset
+                    .checked_add(size)
+                    .and_then(|end| image.get(offset..end));
+                if dynamic.is_none() {
+                    return Err(ElfError::Truncated);
+                }
+            }
+            // There's no dynamic linker to hand it to.
+            PT_INTERP => return Err(ElfError::Unsupported),
+            _ => {}
+        }
+    }
+
+    let base = match kind {
+        ET_DYN => pie_base(&segments, align)?,
+        _ => 0,
+    };
+    let mut end = 0;
+    for segment in &segments {
+        end = end.max(load_segment(space, image, segment, base)?);
+    }
+    if let (ET_DYN, Some(dynamic)) = (kind, dynamic) {
+        relocate(space, image, &segments, dynamic, base)?;
+    }
+    let phdr = segments
+        .iter()
+        .find_map(|segment| {
+            let offset = phoff.checked_sub(segment.offset)?;
+            (offset.checked_add(phnum * phentsize)? <= segment.file_size)
+                .then(|| base + segment.vaddr + offset)
+        })
+        .unwrap_or(0);
+    Ok(Loaded {
+        entry: base.wrapping_add(entry),
+        end,
+        phdr,
+        phent: phentsize,
+        phnum,
+    })
+}
+
+/// A random base for a position-independent executable with `segments`,
+/// keeping them aligned to `align`.
+fn pie_base(segments: &[Segment], align: usize) -> Result<usize, ElfError> {
+    let low = segments
+        .iter()
+        .map(|segment| align_down(segment.vaddr, PAGE_SIZE))
+        .min()
+        .unwrap_or(0);
+    let align = align.min(PIE_RANDOM_RANGE);
+    let offset = rand::below((PIE_RANDOM_RANGE / align) as u32) as usize * align;
+    (PIE_BASE + offset)
+        .checked_sub(low)
+        .ok_or(ElfError::BadSegment)
+}
+
+/// Applies the relocations the `dynamic` section of a position-independent
+/// executable loaded at `base` lists.
+fn relocate(
+    space: &AddressSpace,
+    image: &[u8],
+    segments: &[Segment],
+    dynamic: &[u8],
+    base: usize,
+) -> Result<(), ElfError> {
+    const WORD: usize = size_of::<usize>();
+    let mut rela = None;
+    let mut size = 0;
+    let mut entry_size = RELA_SIZE;
+    for entry in dynamic.chunks_exact(2 * WORD) {
+        let value = usize_at(entry, WORD)?;
+        match usize_at(entry, 0)? {
+            DT_NULL => break,
+            DT_RELA => rela = Some(value),
+            DT_RELASZ => size = value,
+            DT_RELAENT => entry_size = value,
+            _ => {}
+        }
+    }
+    let Some(rela) = rela else {
+        return Ok(());
+    };
+    if entry_size < RELA_SIZE {
+        return Err(ElfError::BadRelocation);
+    }
+    let table = segments
+        .iter()
+        .find_map(|segment| segment.file_offset(rela, size))
+        .ok_or(ElfError::BadRelocation)?;
+
+    for entry in (table..table + size).step_by(entry_size) {
+        let offset = usize_at(image, entry)?;
+        let info = usize_at(image, entry + WORD)?;
+        let addend = usize_at(image, entry + 2 * WORD)?;
+        match info & R_TYPE_MASK {
+            R_RISCV_NONE => {}
+            R_RISCV_RELATIVE => {
+                let value = base.wrapping_add(addend);
+                if !space.write(base.wrapping_add(offset), &value.to_le_bytes()) {
+                    return Err(ElfError::BadRelocation);
+                }
+            }
+            _ => return Err(ElfError::BadRelocation),
+        }
+    }
+    Ok(())
+}
+
+/// Maps `segment` `base` bytes above its address. Returns the end of the
+/// segment in memory.
+fn load_segment(
+    space: &AddressSpace,
+    image: &[u8],
+    segment: &Segment,
+    base: usize,
+) -> Result<usize, ElfError> {
+    if segment.file_size > segment.mem_size {
+        return Err(ElfError::BadSegment);
+    }
+    let data = segment
+        .offset
+        .checked_add(segment.file_size)
+        .and_then(|end| image.get(segment.offset..end))
+        .ok_or(ElfError::Truncated)?;
+    let vaddr = segment
+        .vaddr
+        .checked_add(base)
+        .ok_or(ElfError::BadSegment)?;
+    let end = vaddr
+        .checked_add(segment.mem_size)
+        .ok_or(ElfError::BadSegment)?;
+
+    // Pages are always readable: write-only and execute-only mappings
+    // aren't supported.
+    let mut flags = PteFlags::R;
+    if segment.flags & PF_W != 0 {
+        flags = flags | PteFlags::W;
+    }
+    if segment.flags & PF_X != 0 {
+        flags = flags | PteFlags::X;
+    }
+
+    let start = align_down(vaddr, PAGE_SIZE);
+    match space.map_zeroed(start, align_up(end, PAGE_SIZE) - start, flags) {
+        Ok(()) => {}
+        Err(MapError::AlreadyMapped | MapError::Misaligned) => return Err(ElfError::BadSegment),
+        Err(err) => return Err(ElfError::Map(err)),
+    }
+    if !space.write(vaddr, data) {
+        return Err(ElfError::BadSegment);
+    }
+    Ok(end)
+}
+
+fn put_u16(out: &mut [u8], offset: usize, value: u16) {
+    out[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut [u8], offset: usize, value: u32) {
+    out[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_usize(out: &mut [u8], offset: usize, value: usize) {
+    out[offset..offset + size_of::<usize>()].copy_from_slice(&value.to_le_bytes());
+}
+
+/// A `PT_LOAD` segment of a core file: `size` bytes of memory at `vaddr`,
+/// mapped with `flags`.
+pub struct CoreSegment {
+    pub vaddr: usize,
+    pub size: usize,
+    pub flags: PteFlags,
+}
+
+/// The file header and program headers of a core file, which the notes,
+/// `notes_size` bytes of them, follow, then the contents of `segments` in
+/// order.
+pub fn core_headers(notes_size: usize, segments: &[CoreSegment]) -> Vec<u8> {
+    let phnum = segments.len() + 1;
+    let mut out = alloc::vec![0; EHDR_SIZE + phnum * PHDR_SIZE];
+    out[..4].copy_from_slice(&ELF_MAGIC);
+    out[4] = CLASS;
+    out[5] = ELFDATA2LSB;
+    out[6] = EV_CURRENT;
+    put_u16(&mut out, 16, ET_CORE);
+    put_u16(&mut out, 18, EM_RISCV);
+    put_u32(&mut out, 20, u32::from(EV_CURRENT));
+    put_usize(&mut out, E_PHOFF, EHDR_SIZE);
+    put_u16(&mut out, E_EHSIZE, EHDR_SIZE as u16);
+    put_u16(&mut out, E_PHENTSIZE, PHDR_SIZE as u16);
+    put_u16(&mut out, E_PHNUM, phnum as u16);
+
+    let mut offset = out.len();
+    let notes = &mut out[EHDR_SIZE..];
+    put_u32(notes, 0, PT_NOTE);
+    put_usize(notes, P_OFFSET, offset);
+    put_usize(notes, P_FILESZ, notes_size);
+    put_usize(notes, P_ALIGN, 4);
+    offset += notes_size;
+
+    for (i, segment) in segments.iter().enumerate() {
+        let phdr = &mut out[EHDR_SIZE + (i + 1) * PHDR_SIZE..];
+        let mut flags = 0;
+        if segment.flags.contains(PteFlags::R) {
+            flags |= PF_R;
+        }
+        if segment.flags.contains(PteFlags::W) {
+            flags |= PF_W;
+        }
+        if segment.flags.contains(PteFlags::X) {
+            flags |= PF_X;
+        }
+        put_u32(phdr, 0, PT_LOAD);
+        put_u32(phdr, P_FLAGS, flags);
+        put_usize(phdr, P_OFFSET, offset);
+        put_usize(phdr, P_VADDR, segment.vaddr);
+        put_usize(phdr, P_FILESZ, segment.size);
+        put_usize(phdr, P_MEMSZ, segment.size);
+        put_usize(phdr, P_ALIGN, PAGE_SIZE);
+        offset += segment.size;
+    }
+    out
+}
+
+/// An ELF note of type `kind` from `name`, with `desc` as its contents.
+pub fn note(name: &str, kind: u32, desc: &[u8]) -> Vec<u8> {
+    let name_size = name.len() + 1;
+    let mut out = alloc::vec![0; 12 + align_up(name_size, 4) + align_up(desc.len(), 4)];
+    put_u32(&mut out, 0, name_size as u32);
+    put_u32(&mut out, 4, desc.len() as u32);
+    put_u32(&mut out, 8, kind);
+    out[12..12 + name.len()].copy_from_slice(name.as_bytes());
+    let desc_start = 12 + align_up(name_size, 4);
+    out[desc_start..desc_start + desc.len()].copy_from_slice(desc);
+    out
+}
+
+ktest! {
+    fn core_headers_put_segments_after_notes() {
+        let segments = [CoreSegment {
+            vaddr: 0x10000,
+            size: 2 * PAGE_SIZE,
+            flags: PteFlags::R | PteFlags::W,
+        }];
+        let headers = core_headers(100, &segments);
+        assert_eq!(u16_at(&headers, 16), Ok(ET_CORE));
+        assert_eq!(u16_at(&headers, E_PHNUM), Ok(2));
+        let load = EHDR_SIZE + PHDR_SIZE;
+        assert_eq!(u32_at(&headers, load), Ok(PT_LOAD));
+        assert_eq!(u32_at(&headers, load + P_FLAGS), Ok(PF_R | PF_W));
+        assert_eq!(usize_at(&headers, load + P_OFFSET), Ok(headers.len() + 100));
+        assert_eq!(usize_at(&headers, load + P_MEMSZ), Ok(2 * PAGE_SIZE));
+
+        let note = note("CORE", 1, &[1, 2, 3]);
+        assert_eq!(note.len(), 12 + 8 + 4);
+        assert_eq!(&note[12..17], b"CORE\0");
+    }
+}
+
+ktest! {
+    fn relocates_static_pie() {
+        const WORD: usize = size_of::<usize>();
+        let dynamic = EHDR_SIZE + 2 * PHDR_SIZE;
+        let rela = dynamic + 4 * WORD;
+        let data = align_up(rela + RELA_SIZE, WORD);
+        let mut image = alloc::vec![0; data + WORD];
+        image[..4].copy_from_slice(&ELF_MAGIC);
+        image[4] = CLASS;
+        image[5] = ELFDATA2LSB;
+        image[6] = EV_CURRENT;
+        put_u16(&mut image, 16, ET_DYN);
+        put_u16(&mut image, 18, EM_RISCV);
+        put_usize(&mut image, E_ENTRY, 0x40);
+        put_usize(&mut image, E_PHOFF, EHDR_SIZE);
+        put_u16(&mut image, E_PHENTSIZE, PHDR_SIZE as u16);
+        put_u16(&mut image, E_PHNUM, 2);
+
+        let segment = EHDR_SIZE;
+        put_u32(&mut image, segment, PT_LOAD);
+        put_u32(&mut image, segment + P_FLAGS, PF_R | PF_W);
+        put_usize(&mut image, segment + P_FILESZ, data + WORD);
+        put_usize(&mut image, segment + P_MEMSZ, data + WORD);
+        put_usize(&mut image, segment + P_ALIGN, PAGE_SIZE);
+        let phdr = segment + PHDR_SIZE;
+        put_u32(&mut image, phdr, PT_DYNAMIC);
+        put_usize(&mut image, phdr + P_OFFSET, dynamic);
+        put_usize(&mut image, phdr + P_VADDR, dynamic);
+        put_usize(&mut image, phdr + P_FILESZ, 4 * WORD);
+        put_usize(&mut image, dynamic, DT_RELA);
+        put_usize(&mut image, dynamic + WORD, rela);
+        put_usize(&mut image, dynamic + 2 * WORD, DT_RELASZ);
+        put_usize(&mut image, dynamic + 3 * WORD, RELA_SIZE);
+        put_usize(&mut image, rela, data);
+        put_usize(&mut image, rela + WORD, R_RISCV_RELATIVE);
+        put_usize(&mut image, rela + 2 * WORD, 0x40);
+
+        let space = AddressSpace::new().unwrap();
+        let loaded = load(&space, &image).unwrap();
+        let base = loaded.entry - 0x40;
+        assert!((PIE_BASE..PIE_BASE + PIE_RANDOM_RANGE).contains(&base));
+        assert_eq!(base % PAGE_SIZE, 0);
+        assert_eq!(loaded.phdr, base + EHDR_SIZE);
+        let mut word = [0; WORD];
+        assert!(space.debug_read(base + data, &mut word));
+        assert_eq!(usize::from_le_bytes(word), loaded.entry);
+
+        // Other relocations need a dynamic linker.
+        put_usize(&mut image, rela + WORD, 2);
+        let space = AddressSpace::new().unwrap();
+        assert!(matches!(load(&space, &image), Err(ElfError::BadRelocation)));
+    }
+}
@@ -0,0 +1,164 @@
+//! An executor for kernel futures, so that I/O can be awaited instead of
+//! given a task of its own to block: a future keeps only what it holds
+//! across awaits, where a task needs a whole stack.
+//!
+//! Spawned futures are polled by the executor task, on the boot hart, when
+//! they're woken. Interrupt handlers wake them through what they await,
+//! `WaitQueue::until` for anything a `WaitQueue` is woken for, like virtio
+//! completions. Tasks can wait for a future with `block_on`.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::{poll_fn, Future};
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use crate::sync::{Mutex, Once, SpinLockIrqSave, WaitQueue};
+use crate::task::{self, Priority, TaskId};
+use crate::{ktest, log_warn, percpu};
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A spawned future.
+struct Job {
+    /// `None` once it's finished.
+    future: Mutex<Option<BoxFuture>>,
+    /// Whether it's in `READY`, so that waking it again does nothing.
+    queued: AtomicBool,
+}
+
+impl Wake for Job {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            READY.lock().push_back(self.clone());
+            RUNNABLE.wake_one();
+        }
+    }
+}
+
+/// Jobs that have been woken, in order.
+static READY: SpinLockIrqSave<VecDeque<Arc<Job>>> = SpinLockIrqSave::new(VecDeque::new());
+
+/// The executor task waits here for a job to be woken.
+static RUNNABLE: WaitQueue = WaitQueue::new();
+
+/// The executor task, once it's started.
+static EXECUTOR: Once<TaskId> = Once::new();
+
+/// Starts the executor task on this hart, once it runs tasks.
+pub fn init() {
+    match task::spawn_on(percpu::hart_id(), Priority::Normal, run) {
+        Some(id) => {
+            EXECUTOR.call_once(|| id);
+        }
+        None => log_warn!(target: "executor", "no memory for the executor"),
+    }
+}
+
+/// Runs `future` on the executor until it's done.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    let job = Arc::new(Job {
+        future: Mutex::new(Some(Box::pin(future))),
+        queued: AtomicBool::new(false),
+    });
+    job.wake_by_ref();
+}
+
+fn run() {
+    loop {
+        RUNNABLE.wait_until(|| !READY.lock().is_empty());
+        loop {
+            let Some(job) = READY.lock().pop_front() else {
+                break;
+            };
+            // Wakes from now on, even while it's polled, poll it again.
+            job.queued.store(false, Ordering::Release);
+            let waker = Waker::from(job.clone());
+            let mut future = job.future.lock();
+            if let Some(running) = future.as_mut() {
+                if running
+                    .as_mut()
+                    .poll(&mut Context::from_waker(&waker))
+                    .is_ready()
+                {
+                    *future = None;
+                }
+            }
+        }
+    }
+}
+
+/// Wakes the task in `block_on`.
+struct Signal {
+    woken: AtomicBool,
+    queue: WaitQueue,
+}
+
+impl Wake for Signal {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.queue.wake_all();
+    }
+}
+
+/// Polls `future` on this task until it's done, blocking while it waits.
+/// It mustn't be called from a future, as it would block the executor and
+/// with it whatever `future` is waiting for; that panics rather than hangs.
+pub fn block_on<T>(future: impl Future<Output = T>) -> T {
+    if let Some(&executor) = EXECUTOR.get() {
+        assert!(task::current() != executor, "block_on called from a future");
+    }
+    let signal = Arc::new(Signal {
+        woken: AtomicBool::new(false),
+        queue: WaitQueue::new(),
+    });
+    let waker = Waker::from(signal.clone());
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(value) = future.as_mut().poll(&mut Context::from_waker(&waker)) {
+            return value;
+        }
+        signal
+            .queue
+            .wait_until(|| signal.woken.swap(false, Ordering::Acquire));
+    }
+}
+
+/// Lets the other futures that have been woken run before continuing.
+pub async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+ktest! {
+    fn futures_wake_each_other() {
+        static DONE: AtomicBool = AtomicBool::new(false);
+        static QUEUE: WaitQueue = WaitQueue::new();
+        spawn(async {
+            yield_now().await;
+            DONE.store(true, Ordering::Release);
+            QUEUE.wake_all();
+        });
+        block_on(QUEUE.until(|| DONE.load(Ordering::Acquire)));
+        assert!(DONE.load(Ordering::Acquire));
+    }
+}
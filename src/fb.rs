@@ -0,0 +1,294 @@
+//! Text consoles on framebuffers, so the kernel can boot to a screen and
+//! not just to serial. A display driver hands its framebuffer to `add`,
+//! which shows the kernel log so far on it and then adds it as a console
+//! sink: `fb0` for the first, `fb1` for the next and so on. Unlike other
+//! devices, these are used without `consoles=` asking for them.
+//!
+//! Text is drawn with an 8x8 font, doubled on screens wide enough for 80
+//! columns of it, in light grey on black. Only newlines, carriage returns,
+//! tabs and backspaces are understood; other control characters are
+//! dropped, as are escape sequences, such as the colours a program might
+//! ask a terminal for.
+
+mod font;
+
+use alloc::boxed::Box;
+use alloc::format;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::io::{self, ConsoleDevice};
+use crate::sync::SpinLockIrqSave;
+use crate::{kmsg, ktest, log_info};
+
+const FOREGROUND: u32 = 0x00aa_aaaa;
+const BACKGROUND: u32 = 0;
+
+const BACKSPACE: u8 = 0x08;
+const ESCAPE: u8 = 0x1b;
+const TAB_WIDTH: usize = 8;
+
+/// A rectangle of pixels.
+#[derive(Clone, Copy, Debug)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// A display's memory: `height` rows of `width` pixels, each with red in
+/// bits 16 to 23, green in 8 to 15 and blue in 0 to 7.
+pub trait Framebuffer: Send {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    fn pixels(&mut self) -> &mut [u32];
+    /// Makes what's been drawn in `rect` appear on the display.
+    fn flush(&mut self, rect: Rect);
+}
+
+/// How far into an escape sequence the output is.
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    /// Just after the escape character.
+    Started,
+    /// In a control sequence, `ESC [`, which ends at a byte from `@` to
+    /// `~`.
+    Csi,
+}
+
+/// Text on a framebuffer, scrolling up as lines are added at the bottom.
+struct TextConsole {
+    fb: Box<dyn Framebuffer>,
+    /// How many pixels each pixel of the font takes on each side.
+    scale: usize,
+    cols: usize,
+    rows: usize,
+    /// Where the next character goes. `col` can be `cols`, in which case the
+    /// line wraps before the next character is drawn.
+    col: usize,
+    row: usize,
+    escape: Escape,
+}
+
+impl TextConsole {
+    fn new(mut fb: Box<dyn Framebuffer>) -> Self {
+        let scale = if fb.width() >= 80 * 2 * font::WIDTH {
+            2
+        } else {
+            1
+        };
+        let cols = fb.width() / (font::WIDTH * scale);
+        let rows = fb.height() / (font::HEIGHT * scale);
+        fb.pixels().fill(BACKGROUND);
+        let mut console = Self {
+            fb,
+            scale,
+            cols,
+            rows,
+            col: 0,
+            row: 0,
+            escape: Escape::None,
+        };
+        console.flush_rows(0, rows);
+        console
+    }
+
+    fn cell_width(&self) -> usize {
+        font::WIDTH * self.scale
+    }
+
+    fn cell_height(&self) -> usize {
+        font::HEIGHT * self.scale
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.cols == 0 || self.rows == 0 {
+            return;
+        }
+        // The rows changed, as a range: scrolling changes them all.
+        let first = self.row;
+        let mut scrolled = false;
+        for &byte in bytes {
+            match self.escape {
+                Escape::None => {}
+                Escape::Started => {
+                    self.escape = if byte == b'[' {
+                        Escape::Csi
+                    } else {
+                        Escape::None
+                    };
+                    continue;
+                }
+                Escape::Csi => {
+                    if (b'@'..=b'~').contains(&byte) {
+                        self.escape = Escape::None;
+                    }
+                    continue;
+                }
+            }
+            match byte {
+                ESCAPE => self.escape = Escape::Started,
+                b'\n' => scrolled |= self.newline(),
+                b'\r' => self.col = 0,
+                b'\t' => self.col = ((self.col / TAB_WIDTH + 1) * TAB_WIDTH).min(self.cols),
+                BACKSPACE => self.col = self.col.saturating_sub(1),
+                b' '..=b'~' => {
+                    if self.col >= self.cols {
+                        scrolled |= self.newline();
+                    }
+                    self.draw(byte);
+                    self.col += 1;
+                }
+                _ => {}
+            }
+        }
+        if scrolled {
+            self.flush_rows(0, self.rows);
+        } else {
+            self.flush_rows(first, self.row + 1);
+        }
+    }
+
+    /// Moves to the start of the next line, returning whether that scrolled
+    /// the screen.
+    fn newline(&mut self) -> bool {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return false;
+        }
+        let width = self.fb.width();
+        let line = width * self.cell_height();
+        let used = line * self.rows;
+        let pixels = self.fb.pixels();
+        pixels.copy_within(line..used, 0);
+        pixels[used - line..used].fill(BACKGROUND);
+        true
+    }
+
+    /// Draws `c` at the cursor.
+    fn draw(&mut self, c: u8) {
+        let glyph = font::glyph(c);
+        let (scale, width) = (self.scale, self.fb.width());
+        let left = self.col * self.cell_width();
+        let top = self.row * self.cell_height();
+        let pixels = self.fb.pixels();
+        for (y, bits) in glyph.iter().enumerate() {
+            for dy in 0..scale {
+                let start = (top + y * scale + dy) * width + left;
+                let row = &mut pixels[start..start + font::WIDTH * scale];
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let lit = (bits >> (x / scale)) & 1 != 0;
+                    *pixel = if lit { FOREGROUND } else { BACKGROUND };
+                }
+            }
+        }
+    }
+
+    /// Blanks the screen, moving the cursor to the top left.
+    fn clear(&mut self) {
+        self.fb.pixels().fill(BACKGROUND);
+        self.col = 0;
+        self.row = 0;
+        self.flush_rows(0, self.rows);
+    }
+
+    /// Flushes the text rows `start..end`.
+    fn flush_rows(&mut self, start: usize, end: usize) {
+        let rect = Rect {
+            x: 0,
+            y: start * self.cell_height(),
+            width: self.fb.width(),
+            height: (end - start) * self.cell_height(),
+        };
+        self.fb.flush(rect);
+    }
+}
+
+struct FbConsole(SpinLockIrqSave<TextConsole>);
+
+impl ConsoleDevice for FbConsole {
+    fn write(&self, bytes: &[u8]) -> bool {
+        self.0.lock().write(bytes);
+        true
+    }
+
+    fn clear(&self) -> bool {
+        self.0.lock().clear();
+        true
+    }
+}
+
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Puts a text console on `fb` and makes it a console sink.
+pub fn add(fb: Box<dyn Framebuffer>) {
+    let mut text = TextConsole::new(fb);
+    log_info!(
+        target: "fb",
+        "{}x{} text console",
+        text.cols,
+        text.rows
+    );
+    // Anything logged between this and the sink being added is missed.
+    kmsg::read_all(|bytes| text.write(bytes));
+    // Consoles are never removed, so they can be handed out for good.
+    let console: &'static FbConsole = Box::leak(Box::new(FbConsole(SpinLockIrqSave::new(text))));
+    let name = format!("fb{}", COUNT.fetch_add(1, Ordering::Relaxed)).leak();
+    io::add_device(name, console, true);
+}
+
+ktest! {
+    fn draws_and_scrolls_text() {
+        use alloc::vec::Vec;
+
+        struct Memory(Vec<u32>);
+
+        impl Framebuffer for Memory {
+            fn width(&self) -> usize {
+                64
+            }
+
+            fn height(&self) -> usize {
+                20
+            }
+
+            fn pixels(&mut self) -> &mut [u32] {
+                &mut self.0
+            }
+
+            fn flush(&mut self, _rect: Rect) {}
+        }
+
+        let lit = |console: &mut TextConsole, col: usize, row: usize| {
+            let pixels = console.fb.pixels();
+            (row * font::HEIGHT..(row + 1) * font::HEIGHT).any(|y| {
+                let start = y * 64 + col * font::WIDTH;
+                pixels[start..start + font::WIDTH].contains(&FOREGROUND)
+            })
+        };
+        let mut console = TextConsole::new(Box::new(Memory(alloc::vec![1; 64 * 20])));
+        assert_eq!((console.scale, console.cols, console.rows), (1, 8, 2));
+
+        console.write(b"\x1b[1;31mAB\x1b[0m\x07");
+        assert_eq!((console.col, console.row), (2, 0));
+        assert!(lit(&mut console, 0, 0) && lit(&mut console, 1, 0));
+        assert!(!lit(&mut console, 2, 0));
+
+        // The tab runs to the end of the line, so C wraps.
+        console.write(b"\tC");
+        assert_eq!((console.col, console.row), (1, 1));
+        assert!(lit(&mut console, 0, 1));
+
+        console.write(b"\nD");
+        assert_eq!((console.col, console.row), (1, 1));
+        // C scrolled up to where A was, and B is gone.
+        assert!(lit(&mut console, 0, 0) && !lit(&mut console, 1, 0));
+        assert!(lit(&mut console, 0, 1));
+
+        console.clear();
+        assert_eq!((console.col, console.row), (0, 0));
+        assert!(console.fb.pixels().iter().all(|&pixel| pixel == BACKGROUND));
+    }
+}
@@ -0,0 +1,85 @@
+//! Open files, as seen through a process's file descriptors.
+
+use alloc::sync::Arc;
+
+use crate::fs::DirEntry;
+use crate::mm::shm::SharedMemory;
+use crate::syscall::Errno;
+use crate::{mqueue, tty};
+
+/// Where `File::seek` moves the offset relative to.
+#[derive(Clone, Copy, Debug)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// Something a file descriptor can refer to. Buffers are in kernel memory;
+/// the syscall layer copies to and from user memory.
+pub trait File: Send + Sync {
+    /// Reads up to `buf.len()` bytes, blocking until at least one is
+    /// available. Returns 0 at the end of the file.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno>;
+
+    /// Writes up to `buf.len()` bytes, returning how many were written.
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno>;
+
+    /// Moves the offset the next read or write starts at, returning it.
+    /// Only files on a filesystem have one.
+    fn seek(&self, _pos: SeekFrom) -> Result<u64, Errno> {
+        Err(Errno::ESPIPE)
+    }
+
+    /// Calls `emit` with each entry of a directory from the offset on, until
+    /// it returns false to leave the entry for next time.
+    fn read_dir(&self, _emit: &mut dyn FnMut(&DirEntry) -> bool) -> Result<(), Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    /// Carries out an `ioctl` request. Only terminals and pipes take any.
+    fn ioctl(&self, _request: usize, _arg: usize) -> Result<usize, Errno> {
+        Err(Errno::ENOTTY)
+    }
+
+    /// Cuts the file down to `len` bytes, or extends it with zeroes, for
+    /// `ftruncate`. Only files on a filesystem open for writing can be.
+    fn truncate(&self, _len: u64) -> Result<(), Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    /// Writes what's been written to the file back to the device it's on,
+    /// for `fsync`. Only files on a filesystem and block devices can be.
+    fn sync(&self) -> Result<(), Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    /// The pages of the file, for `mmap`, which can write them back to it
+    /// if `write`. Only files on a filesystem can be mapped.
+    fn mmap(&self, _write: bool) -> Result<Arc<SharedMemory>, Errno> {
+        Err(Errno::ENODEV)
+    }
+
+    /// The message queue descriptor this is, if it is one.
+    fn message_queue(&self) -> Option<&mqueue::Descriptor> {
+        None
+    }
+}
+
+/// The kernel console, the standard input and output of processes, as a
+/// terminal.
+pub struct Console;
+
+impl File for Console {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        tty::read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        tty::write(buf)
+    }
+
+    fn ioctl(&self, request: usize, arg: usize) -> Result<usize, Errno> {
+        tty::ioctl(request, arg)
+    }
+}
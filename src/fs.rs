@@ -0,0 +1,444 @@
+//! The virtual filesystem. Filesystems are mounted at directories of one
+//! namespace, and files are found by absolute path through the mount table.
+//! There are no working directories, so relative paths start at the root.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::file::{File, SeekFrom};
+use crate::mm::paging::PAGE_SIZE;
+use crate::mm::shm::SharedMemory;
+use crate::sync::{Mutex, SpinLock};
+use crate::syscall::Errno;
+use crate::{block, ktest};
+
+pub mod devfs;
+pub mod fat;
+pub mod initramfs;
+pub mod ninep;
+pub mod page_cache;
+pub mod procfs;
+
+/// Longest name of a file in a directory.
+pub const NAME_MAX: usize = 255;
+
+/// `open` flags, as on Linux. The access mode is in the low two bits.
+pub const O_ACCMODE: u32 = 0o3;
+pub const O_RDONLY: u32 = 0o0;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_CREAT: u32 = 0o100;
+pub const O_EXCL: u32 = 0o200;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+pub const O_NONBLOCK: u32 = 0o4000;
+pub const O_DIRECTORY: u32 = 0o200000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VnodeKind {
+    File,
+    Directory,
+    /// A device, which is read and written through the file returned by
+    /// `Vnode::open_device`.
+    Device,
+}
+
+/// An entry of a directory.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: VnodeKind,
+}
+
+/// A mounted filesystem.
+pub trait FileSystem: Send + Sync {
+    /// The type of filesystem, e.g. `fat`.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Vnode>;
+
+    /// Writes everything changed back to the underlying device.
+    fn sync(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+/// A file, directory or device on a filesystem. The methods that don't make
+/// sense for a kind of node fail by default.
+pub trait Vnode: Send + Sync {
+    fn kind(&self) -> VnodeKind;
+
+    /// The size of a file in bytes.
+    fn size(&self) -> u64 {
+        0
+    }
+
+    /// Finds the entry of a directory called `name`.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Vnode>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    /// Creates an empty file or directory called `name` in a directory.
+    fn create(&self, _name: &str, _kind: VnodeKind) -> Result<Arc<dyn Vnode>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    /// Reads from a file at `offset`, returning how many bytes were read,
+    /// which is 0 at the end.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EISDIR)
+    }
+
+    /// Writes to a file at `offset`, extending it if need be.
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EISDIR)
+    }
+
+    /// Cuts a file down to `len` bytes, or extends it with zeroes.
+    fn truncate(&self, _len: u64) -> Result<(), Errno> {
+        Err(Errno::EISDIR)
+    }
+
+    /// Removes the file or empty directory called `name` from a directory.
+    fn remove(&self, _name: &str) -> Result<(), Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    /// The file to access a device through.
+    fn open_device(&self) -> Result<Arc<dyn File>, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    /// Writes a file and its entry back to the underlying device.
+    fn sync(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+struct Mount {
+    /// A normalized path, as returned by `normalize`.
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: SpinLock<Vec<Mount>> = SpinLock::new(Vec::new());
+
+/// Turns `path` into an absolute path without `.`, `..` or repeated
+/// slashes. `..` at the root stays at the root.
+pub fn normalize(path: &str) -> Result<String, Errno> {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ if component.len() > NAME_MAX => return Err(Errno::ENAMETOOLONG),
+            _ => components.push(component),
+        }
+    }
+    let mut normalized = String::new();
+    for component in components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Splits a normalized path into its parent directory and last component,
+/// which is empty for the root.
+fn split_last(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((parent, name)) => (parent, name),
+        None => ("/", path),
+    }
+}
+
+/// Whether the normalized `path` is `mount` or below it.
+fn is_under(path: &str, mount: &str) -> bool {
+    mount == "/"
+        || path
+            .strip_prefix(mount)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Mounts `fs` at the directory `path`. The first filesystem is mounted at
+/// `/`.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), Errno> {
+    let path = normalize(path)?;
+    if path != "/" && lookup(&path)?.kind() != VnodeKind::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(Errno::EBUSY);
+    }
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// Unmounts the filesystem at `path`, syncing it first.
+pub fn unmount(path: &str) -> Result<(), Errno> {
+    let path = normalize(path)?;
+    let fs = {
+        let mut mounts = MOUNTS.lock();
+        let index = mounts
+            .iter()
+            .position(|mount| mount.path == path)
+            .ok_or(Errno::EINVAL)?;
+        let busy = mounts
+            .iter()
+            .any(|mount| mount.path != path && is_under(&mount.path, &path));
+        if busy {
+            return Err(Errno::EBUSY);
+        }
+        mounts.remove(index).fs
+    };
+    fs.sync()
+}
+
+/// Every mount, as its path and filesystem type.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (mount.path.clone(), mount.fs.name()))
+        .collect()
+}
+
+/// Syncs every mounted filesystem, then the block caches they've written
+/// to, returning the first error.
+pub fn sync_all() -> Result<(), Errno> {
+    let filesystems: Vec<_> = MOUNTS.lock().iter().map(|mount| mount.fs.clone()).collect();
+    let mut result = page_cache::sync_all();
+    for fs in filesystems {
+        result = result.and(fs.sync());
+    }
+    result.and(block::cache::sync_all().map_err(Errno::from))
+}
+
+/// Finds the node at `path`.
+pub fn lookup(path: &str) -> Result<Arc<dyn Vnode>, Errno> {
+    let path = normalize(path)?;
+    // The innermost mount the path is under.
+    let (mount, fs) = MOUNTS
+        .lock()
+        .iter()
+        .filter(|mount| is_under(&path, &mount.path))
+        .max_by_key(|mount| mount.path.len())
+        .map(|mount| (mount.path.clone(), mount.fs.clone()))
+        .ok_or(Errno::ENOENT)?;
+
+    let rest = &path[mount.len()..];
+    let mut node = fs.root();
+    for name in rest.split('/').filter(|name| !name.is_empty()) {
+        node = node.lookup(name)?;
+    }
+    Ok(node)
+}
+
+/// Opens the file at `path` with the `O_*` `flags`, creating it if asked.
+pub fn open(path: &str, flags: u32) -> Result<Arc<dyn File>, Errno> {
+    let (readable, writable) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(Errno::EINVAL),
+    };
+    let node = match lookup(path) {
+        Ok(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => return Err(Errno::EEXIST),
+        Ok(node) => node,
+        Err(Errno::ENOENT) if flags & O_CREAT != 0 => {
+            let path = normalize(path)?;
+            let (parent, name) = split_last(&path);
+            lookup(parent)?.create(name, VnodeKind::File)?
+        }
+        Err(err) => return Err(err),
+    };
+
+    match node.kind() {
+        VnodeKind::Device => return node.open_device(),
+        VnodeKind::Directory if writable => return Err(Errno::EISDIR),
+        VnodeKind::File if flags & O_DIRECTORY != 0 => return Err(Errno::ENOTDIR),
+        VnodeKind::File if writable && flags & O_TRUNC != 0 => node.truncate(0)?,
+        _ => {}
+    }
+    Ok(Arc::new(VnodeFile {
+        node,
+        offset: Mutex::new(0),
+        readable,
+        writable,
+        append: flags & O_APPEND != 0,
+    }))
+}
+
+/// Reads the whole file at `path`.
+pub fn read_to_end(path: &str) -> Result<Vec<u8>, Errno> {
+    let node = lookup(path)?;
+    if node.kind() != VnodeKind::File {
+        return Err(Errno::EISDIR);
+    }
+    let mut data = vec![0; node.size() as usize];
+    let mut done = 0;
+    while done < data.len() {
+        match node.read_at(done as u64, &mut data[done..])? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    data.truncate(done);
+    Ok(data)
+}
+
+/// Creates a directory at `path`.
+pub fn mkdir(path: &str) -> Result<(), Errno> {
+    let path = normalize(path)?;
+    let (parent, name) = split_last(&path);
+    if name.is_empty() {
+        return Err(Errno::EEXIST);
+    }
+    lookup(parent)?.create(name, VnodeKind::Directory)?;
+    Ok(())
+}
+
+/// Removes the file at `path`, or the empty directory if `dir`.
+pub fn remove(path: &str, dir: bool) -> Result<(), Errno> {
+    let path = normalize(path)?;
+    if MOUNTS.lock().iter().any(|mount| mount.path == path) {
+        return Err(Errno::EBUSY);
+    }
+    let (parent, name) = split_last(&path);
+    match (lookup(&path)?.kind(), dir) {
+        (VnodeKind::Directory, false) => return Err(Errno::EISDIR),
+        (VnodeKind::File | VnodeKind::Device, true) => return Err(Errno::ENOTDIR),
+        _ => {}
+    }
+    lookup(parent)?.remove(name)
+}
+
+/// A file or directory opened through the VFS, with its own offset. For a
+/// directory the offset counts entries.
+struct VnodeFile {
+    node: Arc<dyn Vnode>,
+    offset: Mutex<u64>,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+impl File for VnodeFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if !self.readable {
+            return Err(Errno::EBADF);
+        }
+        // Pick up what's been written to the file's mapped pages.
+        page_cache::write_back(&self.node)?;
+        let mut offset = self.offset.lock();
+        let n = self.node.read_at(*offset, buf)?;
+        *offset += n as u64;
+        Ok(n)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        if !self.writable {
+            return Err(Errno::EBADF);
+        }
+        let cache = page_cache::find(&self.node);
+        if cache.as_ref().is_some_and(|cache| !cache.write_back()) {
+            return Err(Errno::EIO);
+        }
+        let mut offset = self.offset.lock();
+        if self.append {
+            *offset = self.node.size();
+        }
+        let n = self.node.write_at(*offset, buf)?;
+        if let Some(cache) = cache {
+            let start = *offset as usize / PAGE_SIZE;
+            cache.refresh(start, (*offset as usize + n.max(1) - 1) / PAGE_SIZE);
+        }
+        *offset += n as u64;
+        Ok(n)
+    }
+
+    fn seek(&self, pos: SeekFrom) -> Result<u64, Errno> {
+        let mut offset = self.offset.lock();
+        let new = match pos {
+            SeekFrom::Start(new) => Some(new),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.node.size().checked_add_signed(delta),
+        };
+        *offset = new
+            .filter(|&new| new <= i64::MAX as u64)
+            .ok_or(Errno::EINVAL)?;
+        Ok(*offset)
+    }
+
+    fn read_dir(&self, emit: &mut dyn FnMut(&DirEntry) -> bool) -> Result<(), Errno> {
+        let mut offset = self.offset.lock();
+        let entries = self.node.read_dir()?;
+        for entry in entries.iter().skip(*offset as usize) {
+            if !emit(entry) {
+                break;
+            }
+            *offset += 1;
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), Errno> {
+        if !self.writable {
+            return Err(Errno::EINVAL);
+        }
+        page_cache::write_back(&self.node)?;
+        self.node.truncate(len)?;
+        // Mapped pages past the new end read as zeroes.
+        if let Some(cache) = page_cache::find(&self.node) {
+            cache.refresh(len as usize / PAGE_SIZE, usize::MAX);
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        page_cache::write_back(&self.node)?;
+        self.node.sync()
+    }
+
+    fn mmap(&self, write: bool) -> Result<Arc<SharedMemory>, Errno> {
+        if self.node.kind() != VnodeKind::File {
+            return Err(Errno::ENODEV);
+        }
+        if !self.readable || (write && !self.writable) {
+            return Err(Errno::EACCES);
+        }
+        Ok(page_cache::get(&self.node))
+    }
+}
+
+ktest! {
+    fn normalizes_paths() {
+        assert_eq!(normalize("a//b/./../c/").unwrap(), "/a/c");
+        assert_eq!(normalize("/../..").unwrap(), "/");
+        assert_eq!(normalize("").unwrap(), "/");
+        let long = "x".repeat(NAME_MAX + 1);
+        assert_eq!(normalize(&long), Err(Errno::ENAMETOOLONG));
+
+        assert_eq!(split_last("/a/b"), ("/a", "b"));
+        assert_eq!(split_last("/a"), ("/", "a"));
+        assert_eq!(split_last("/"), ("/", ""));
+
+        assert!(is_under("/mnt", "/mnt"));
+        assert!(is_under("/mnt/disk", "/mnt"));
+        assert!(!is_under("/mntx", "/mnt"));
+        assert!(is_under("/anything", "/"));
+    }
+}
@@ -0,0 +1,300 @@
+//! The device filesystem, mounted at `/dev`. Drivers register their devices
+//! here by name, and opening one gives a file to use the device through.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{DirEntry, FileSystem, Vnode, VnodeKind};
+use crate::block::{BlockDevice, BlockError};
+use crate::file::{self, File, SeekFrom};
+use crate::sync::{Mutex, SpinLock};
+use crate::syscall::Errno;
+use crate::{ktest, log_error, rand};
+
+static DEVICES: SpinLock<BTreeMap<String, Arc<dyn Vnode>>> = SpinLock::new(BTreeMap::new());
+
+/// Adds the device `node`, of kind `VnodeKind::Device`, or a directory of
+/// them, as `/dev/<name>`.
+pub fn register(name: &str, node: Arc<dyn Vnode>) -> Result<(), Errno> {
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(Errno::EEXIST);
+    }
+    devices.insert(name.into(), node);
+    Ok(())
+}
+
+/// Registers the built-in devices and mounts the filesystem.
+pub fn init() {
+    let devices: [(&str, Arc<dyn File>); 5] = [
+        ("console", Arc::new(file::Console)),
+        ("null", Arc::new(Null)),
+        ("random", Arc::new(Random)),
+        ("urandom", Arc::new(Random)),
+        ("zero", Arc::new(Zero)),
+    ];
+    for (name, file) in devices {
+        // Nothing else has had a chance to take these names.
+        register(name, CharDevice::new(file)).unwrap();
+    }
+    if let Err(err) = super::mount("/dev", Arc::new(Devfs)) {
+        log_error!(target: "devfs", "couldn't mount at /dev: {}", err);
+    }
+}
+
+struct Devfs;
+
+impl FileSystem for Devfs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Vnode> {
+        Arc::new(Root)
+    }
+}
+
+/// The root directory, listing every device.
+struct Root;
+
+impl Vnode for Root {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, Errno> {
+        DEVICES.lock().get(name).cloned().ok_or(Errno::ENOENT)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Errno> {
+        Ok(DEVICES
+            .lock()
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                kind: node.kind(),
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _kind: VnodeKind) -> Result<Arc<dyn Vnode>, Errno> {
+        Err(Errno::EINVAL)
+    }
+}
+
+/// A device whose file is shared by everything that opens it.
+pub struct CharDevice {
+    file: Arc<dyn File>,
+}
+
+impl CharDevice {
+    pub fn new(file: Arc<dyn File>) -> Arc<Self> {
+        Arc::new(Self { file })
+    }
+}
+
+impl Vnode for CharDevice {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::Device
+    }
+
+    fn open_device(&self) -> Result<Arc<dyn File>, Errno> {
+        Ok(self.file.clone())
+    }
+}
+
+/// Discards writes, and is always at the end for reads. Seeking does
+/// nothing, as on Linux.
+struct Null;
+
+impl File for Null {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        Ok(buf.len())
+    }
+
+    fn seek(&self, _pos: SeekFrom) -> Result<u64, Errno> {
+        Ok(0)
+    }
+}
+
+/// Discards writes, and reads as zeroes. Seeking does nothing, like
+/// `Null`.
+struct Zero;
+
+impl File for Zero {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        Ok(buf.len())
+    }
+
+    fn seek(&self, _pos: SeekFrom) -> Result<u64, Errno> {
+        Ok(0)
+    }
+}
+
+/// Reads from the kernel's random number generator, and mixes writes into
+/// it. The generator never runs dry, so `random` and `urandom` are the same.
+struct Random;
+
+impl File for Random {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        rand::fill_bytes(buf);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        rand::add_data(buf);
+        Ok(buf.len())
+    }
+}
+
+/// A block device, each opening of which has its own offset. Reads and
+/// writes go straight to the device, bypassing the block caches.
+pub struct BlockDeviceNode {
+    device: Arc<dyn BlockDevice>,
+}
+
+impl BlockDeviceNode {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Arc<Self> {
+        Arc::new(Self { device })
+    }
+}
+
+impl Vnode for BlockDeviceNode {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::Device
+    }
+
+    fn size(&self) -> u64 {
+        self.device.block_count() * self.device.block_size() as u64
+    }
+
+    fn open_device(&self) -> Result<Arc<dyn File>, Errno> {
+        Ok(Arc::new(BlockDeviceFile {
+            device: self.device.clone(),
+            offset: Mutex::new(0),
+        }))
+    }
+}
+
+impl From<BlockError> for Errno {
+    fn from(err: BlockError) -> Self {
+        match err {
+            BlockError::ReadOnly => Errno::EROFS,
+            BlockError::Unaligned | BlockError::OutOfRange => Errno::EINVAL,
+            BlockError::Io | BlockError::TimedOut => Errno::EIO,
+        }
+    }
+}
+
+struct BlockDeviceFile {
+    device: Arc<dyn BlockDevice>,
+    offset: Mutex<u64>,
+}
+
+impl BlockDeviceFile {
+    fn size(&self) -> u64 {
+        self.device.block_count() * self.device.block_size() as u64
+    }
+
+    /// The whole blocks covering `len` bytes at `offset`, as the first block
+    /// and a buffer for them.
+    fn blocks(&self, offset: u64, len: usize) -> (u64, Vec<u8>) {
+        let block_size = self.device.block_size() as u64;
+        let start = offset / block_size;
+        let end = (offset + len as u64).div_ceil(block_size);
+        (start, vec![0; ((end - start) * block_size) as usize])
+    }
+}
+
+impl File for BlockDeviceFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let mut offset = self.offset.lock();
+        let len = buf.len().min(self.size().saturating_sub(*offset) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        let (start, mut blocks) = self.blocks(*offset, len);
+        self.device.read_blocks(start, &mut blocks)?;
+        let skip = (*offset % self.device.block_size() as u64) as usize;
+        buf[..len].copy_from_slice(&blocks[skip..skip + len]);
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let mut offset = self.offset.lock();
+        let len = buf.len().min(self.size().saturating_sub(*offset) as usize);
+        if len == 0 {
+            return Err(Errno::ENOSPC);
+        }
+        let (start, mut blocks) = self.blocks(*offset, len);
+        let skip = (*offset % self.device.block_size() as u64) as usize;
+        // Keep the rest of partly written blocks.
+        if skip != 0 || len != blocks.len() {
+            self.device.read_blocks(start, &mut blocks)?;
+        }
+        blocks[skip..skip + len].copy_from_slice(&buf[..len]);
+        self.device.write_blocks(start, &blocks)?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    fn seek(&self, pos: SeekFrom) -> Result<u64, Errno> {
+        let mut offset = self.offset.lock();
+        let new = match pos {
+            SeekFrom::Start(new) => Some(new),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.size().checked_add_signed(delta),
+        };
+        *offset = new
+            .filter(|&new| new <= i64::MAX as u64)
+            .ok_or(Errno::EINVAL)?;
+        Ok(*offset)
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        // Writes go straight to the device, and are done when they return.
+        Ok(())
+    }
+}
+
+ktest! {
+    fn reads_and_writes_devices() {
+        use crate::block::ramdisk::RamDisk;
+
+        let mut buf = [0xff; 8];
+        assert_eq!(Null.read(&mut buf), Ok(0));
+        assert_eq!(Null.seek(SeekFrom::Start(5)), Ok(0));
+        assert_eq!(Zero.read(&mut buf), Ok(8));
+        assert_eq!(buf, [0; 8]);
+        assert_eq!(Zero.seek(SeekFrom::End(-1)), Ok(0));
+
+        let disk = RamDisk::new(4 * 512);
+        let file = BlockDeviceNode::new(disk).open_device().unwrap();
+        // Across the end of the first block.
+        file.seek(SeekFrom::Start(510)).unwrap();
+        assert_eq!(file.write(b"abcd"), Ok(4));
+        file.seek(SeekFrom::Start(508)).unwrap();
+        assert_eq!(file.read(&mut buf), Ok(8));
+        assert_eq!(&buf, b"\0\0abcd\0\0");
+
+        // Cut short at the end of the device.
+        assert_eq!(file.seek(SeekFrom::End(-2)), Ok(2046));
+        assert_eq!(file.write(b"xyz"), Ok(2));
+        assert_eq!(file.write(b"xyz"), Err(Errno::ENOSPC));
+        assert_eq!(file.read(&mut buf), Ok(0));
+        assert_eq!(file.seek(SeekFrom::Current(-4096)), Err(Errno::EINVAL));
+    }
+}
@@ -0,0 +1,1394 @@
+//! FAT32 filesystems, on a whole device or its first FAT32 partition, with
+//! long file names, read and written. Entries are stamped with the real
+//! time when they're created and their files written, or 1980-01-01 if
+//! there's no RTC to take it from. The FSInfo sector's free cluster count
+//! and next free cluster hint are kept up to date, and written on `sync`.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{FileSystem, Vnode, VnodeKind};
+use crate::block::cache::BlockCache;
+use crate::block::{BlockDevice, BlockError};
+use crate::ktest;
+use crate::sync::{Mutex, SpinLock};
+use crate::syscall::Errno;
+use crate::time;
+
+/// Blocks of the device kept in the cache.
+const CACHE_BLOCKS: usize = 256;
+
+/// MBR partition types of FAT32 partitions, with CHS and LBA addressing.
+const PARTITION_TYPES: [u8; 2] = [0x0b, 0x0c];
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// The first byte of the name of an unused entry, and of the entry after the
+/// last used one.
+const ENTRY_FREE: u8 = 0xe5;
+const ENTRY_END: u8 = 0x00;
+
+const ENTRY_SIZE: usize = 32;
+
+/// `NTRes` bits saying the base name or extension of a short name is shown
+/// in lower case.
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+
+/// Marks the ordinal of the last (first stored) long name entry.
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+/// Offsets of the name characters in a long name entry.
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_LEN: usize = 255;
+
+/// FAT entries are 28 bits; the top 4 are reserved.
+const FAT_MASK: u32 = 0x0fff_ffff;
+const FAT_BAD: u32 = 0x0fff_fff7;
+const FAT_EOC: u32 = 0x0fff_ffff;
+
+/// 1980-01-01, the earliest date FAT can store.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUC_SIG: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: u64 = 488;
+const FSINFO_NXT_FREE: u64 = 492;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FatError {
+    Block(BlockError),
+    /// There's no FAT32 filesystem on the device.
+    NotFat,
+    /// The filesystem's structures don't make sense.
+    Corrupt,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    Exists,
+    InvalidName,
+    NoSpace,
+    /// Files can't be larger than 4 GiB.
+    TooLarge,
+    /// Only empty directories can be removed.
+    NotEmpty,
+    /// Open files can't be removed.
+    Busy,
+}
+
+impl From<BlockError> for FatError {
+    fn from(err: BlockError) -> Self {
+        Self::Block(err)
+    }
+}
+
+impl fmt::Display for FatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Block(err) => write!(f, "{}", err),
+            Self::NotFat => f.write_str("not a FAT32 filesystem"),
+            Self::Corrupt => f.write_str("filesystem corrupt"),
+            Self::NotFound => f.write_str("no such file or directory"),
+            Self::NotADirectory => f.write_str("not a directory"),
+            Self::IsADirectory => f.write_str("is a directory"),
+            Self::Exists => f.write_str("file exists"),
+            Self::InvalidName => f.write_str("invalid name"),
+            Self::NoSpace => f.write_str("no space left on device"),
+            Self::TooLarge => f.write_str("file too large"),
+            Self::NotEmpty => f.write_str("directory not empty"),
+            Self::Busy => f.write_str("file is open"),
+        }
+    }
+}
+
+impl From<FatError> for Errno {
+    fn from(err: FatError) -> Self {
+        match err {
+            FatError::Block(BlockError::ReadOnly) => Errno::EROFS,
+            FatError::Block(_) | FatError::NotFat | FatError::Corrupt => Errno::EIO,
+            FatError::NotFound => Errno::ENOENT,
+            FatError::NotADirectory => Errno::ENOTDIR,
+            FatError::IsADirectory => Errno::EISDIR,
+            FatError::Exists => Errno::EEXIST,
+            FatError::InvalidName => Errno::EINVAL,
+            FatError::NoSpace => Errno::ENOSPC,
+            FatError::TooLarge => Errno::EFBIG,
+            FatError::NotEmpty => Errno::ENOTEMPTY,
+            FatError::Busy => Errno::EBUSY,
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// A file or directory, as described by its directory entry.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    name: String,
+    short_name: [u8; 11],
+    attr: u8,
+    /// The first cluster, 0 for an empty file.
+    cluster: u32,
+    size: u32,
+    /// Byte offset of the short entry in the filesystem, or `None` for the
+    /// root directory.
+    location: Option<u64>,
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || format_short_name(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+/// The layout of a mounted filesystem. Offsets are in bytes from the start
+/// of the filesystem.
+pub struct FatFs {
+    cache: Arc<BlockCache>,
+    /// Where the filesystem starts on the device.
+    base: u64,
+    cluster_size: usize,
+    fat_start: u64,
+    fat_len: u64,
+    num_fats: u8,
+    data_start: u64,
+    root_cluster: u32,
+    /// Clusters are numbered from 2 to `cluster_count + 1`.
+    cluster_count: u32,
+    fsinfo: Option<u64>,
+    /// Held by every operation, so they see the filesystem consistent.
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+    /// How many clusters are free, if known: taken from the FSInfo sector,
+    /// and kept up to date to write back to it on `sync`.
+    free_count: Option<u32>,
+    /// Whether the FSInfo sector no longer matches `next_free` and
+    /// `free_count`.
+    fsinfo_dirty: bool,
+}
+
+/// A directory entry slot and what it holds.
+struct Slot {
+    offset: u64,
+    bytes: [u8; ENTRY_SIZE],
+}
+
+impl FatFs {
+    /// Mounts the FAT32 filesystem on `device`, or on the first FAT32
+    /// partition in its MBR.
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, FatError> {
+        let cache = BlockCache::new(device, CACHE_BLOCKS);
+        let mut sector = [0; 512];
+        cache.read_bytes(0, &mut sector)?;
+        if sector[510..] != [0x55, 0xaa] {
+            return Err(FatError::NotFat);
+        }
+        let base = if is_fat32_boot_sector(&sector) {
+            0
+        } else {
+            let lba = (0..4)
+                .map(|i| &sector[446 + 16 * i..][..16])
+                .find(|entry| PARTITION_TYPES.contains(&entry[4]))
+                .map(|entry| u32_at(entry, 8))
+                .ok_or(FatError::NotFat)?;
+            let base = u64::from(lba) * 512;
+            cache.read_bytes(base, &mut sector)?;
+            if !is_fat32_boot_sector(&sector) {
+                return Err(FatError::NotFat);
+            }
+            base
+        };
+
+        let bytes_per_sector = u64::from(u16_at(&sector, 0x0b));
+        let sectors_per_cluster = u64::from(sector[0x0d]);
+        let reserved = u64::from(u16_at(&sector, 0x0e));
+        let num_fats = sector[0x10];
+        let total_sectors = match u16_at(&sector, 0x13) {
+            0 => u64::from(u32_at(&sector, 0x20)),
+            count => u64::from(count),
+        };
+        let fat_sectors = u64::from(u32_at(&sector, 0x24));
+        let root_cluster = u32_at(&sector, 0x2c);
+        let fsinfo_sector = u64::from(u16_at(&sector, 0x30));
+
+        let data_sector = reserved + u64::from(num_fats) * fat_sectors;
+        let cluster_count = total_sectors
+            .checked_sub(data_sector)
+            .ok_or(FatError::Corrupt)?
+            / sectors_per_cluster;
+        // The FAT has to cover every cluster.
+        let cluster_count = cluster_count
+            .min((fat_sectors * bytes_per_sector / 4).saturating_sub(2))
+            .min(u64::from(FAT_BAD - 2)) as u32;
+        let end = base + total_sectors * bytes_per_sector;
+        if num_fats == 0 || end > cache.device().block_count() * cache.block_size() as u64 {
+            return Err(FatError::Corrupt);
+        }
+
+        let fsinfo = (fsinfo_sector != 0 && fsinfo_sector < reserved)
+            .then_some(fsinfo_sector * bytes_per_sector)
+            .filter(|&offset| {
+                let mut sig = [0; 4];
+                let lead = cache.read_bytes(base + offset, &mut sig).is_ok()
+                    && u32::from_le_bytes(sig) == FSINFO_LEAD_SIG;
+                let struc = cache.read_bytes(base + offset + 484, &mut sig).is_ok()
+                    && u32::from_le_bytes(sig) == FSINFO_STRUC_SIG;
+                lead && struc
+            });
+        // The hints, if they're there and make sense; 0xffffffff is unknown.
+        let (mut free_count, mut next_free) = (None, 2);
+        if let Some(offset) = fsinfo {
+            let mut hints = [0; 8];
+            cache.read_bytes(base + offset + FSINFO_FREE_COUNT, &mut hints)?;
+            free_count = Some(u32_at(&hints, 0)).filter(|&count| count <= cluster_count);
+            next_free = Some(u32_at(&hints, 4))
+                .filter(|next| (2..cluster_count + 2).contains(next))
+                .unwrap_or(2);
+        }
+
+        let fs = Self {
+            cache,
+            base,
+            cluster_size: (sectors_per_cluster * bytes_per_sector) as usize,
+            fat_start: reserved * bytes_per_sector,
+            fat_len: fat_sectors * bytes_per_sector,
+            num_fats,
+            data_start: data_sector * bytes_per_sector,
+            root_cluster,
+            cluster_count,
+            fsinfo,
+            state: Mutex::new(State {
+                next_free,
+                free_count,
+                fsinfo_dirty: false,
+            }),
+        };
+        if !fs.is_cluster(root_cluster) {
+            return Err(FatError::Corrupt);
+        }
+        Ok(Arc::new(fs))
+    }
+
+    /// The root directory.
+    pub fn root(&self) -> DirEntry {
+        DirEntry {
+            name: String::from("/"),
+            short_name: [b' '; 11],
+            attr: ATTR_DIRECTORY,
+            cluster: self.root_cluster,
+            size: 0,
+            location: None,
+        }
+    }
+
+    /// Writes everything changed back to the device, with the FSInfo
+    /// sector brought up to date.
+    pub fn sync(&self) -> Result<(), FatError> {
+        let mut state = self.state.lock();
+        if let Some(fsinfo) = self.fsinfo.filter(|_| state.fsinfo_dirty) {
+            let free_count = state.free_count.unwrap_or(u32::MAX);
+            self.write_at(fsinfo + FSINFO_FREE_COUNT, &free_count.to_le_bytes())?;
+            self.write_at(fsinfo + FSINFO_NXT_FREE, &state.next_free.to_le_bytes())?;
+            state.fsinfo_dirty = false;
+        }
+        Ok(self.cache.sync()?)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), FatError> {
+        Ok(self.cache.read_bytes(self.base + offset, buf)?)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<(), FatError> {
+        Ok(self.cache.write_bytes(self.base + offset, buf)?)
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - 2) * self.cluster_size as u64
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FatError> {
+        let mut entry = [0; 4];
+        self.read_at(self.fat_start + 4 * u64::from(cluster), &mut entry)?;
+        Ok(u32::from_le_bytes(entry) & FAT_MASK)
+    }
+
+    /// Sets the entry for `cluster` in every copy of the FAT.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FatError> {
+        let offset = self.fat_start + 4 * u64::from(cluster);
+        let mut entry = [0; 4];
+        self.read_at(offset, &mut entry)?;
+        let entry = (u32::from_le_bytes(entry) & !FAT_MASK) | value;
+        for fat in 0..u64::from(self.num_fats) {
+            self.write_at(offset + fat * self.fat_len, &entry.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, or `None` at the end.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FatError> {
+        match self.fat_entry(cluster)? {
+            next if next > FAT_BAD => Ok(None),
+            next if self.is_cluster(next) => Ok(Some(next)),
+            _ => Err(FatError::Corrupt),
+        }
+    }
+
+    /// The clusters of the chain starting at `first`, which may be 0 for an
+    /// empty chain.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FatError> {
+        let mut clusters = Vec::new();
+        let mut next = (first != 0).then_some(first);
+        while let Some(cluster) = next {
+            if !self.is_cluster(cluster) || clusters.len() > self.cluster_count as usize {
+                return Err(FatError::Corrupt);
+            }
+            clusters.push(cluster);
+            next = self.next_cluster(cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    /// Allocates a zeroed cluster, appending it to the chain ending at
+    /// `last` if there is one.
+    fn alloc_cluster(&self, state: &mut State, last: Option<u32>) -> Result<u32, FatError> {
+        let start = state.next_free.clamp(2, self.cluster_count + 1);
+        let cluster = (0..self.cluster_count)
+            .map(|i| 2 + (start - 2 + i) % self.cluster_count)
+            .find_map(|cluster| match self.fat_entry(cluster) {
+                Ok(0) => Some(Ok(cluster)),
+                Ok(_) => None,
+                Err(err) => Some(Err(err)),
+            })
+            .ok_or(FatError::NoSpace)??;
+        self.set_fat_entry(cluster, FAT_EOC)?;
+        if let Some(last) = last {
+            self.set_fat_entry(last, cluster)?;
+        }
+        self.write_at(self.cluster_offset(cluster), &vec![0; self.cluster_size])?;
+        state.next_free = cluster + 1;
+        state.free_count = state.free_count.map(|count| count.saturating_sub(1));
+        state.fsinfo_dirty = true;
+        Ok(cluster)
+    }
+
+    /// Frees the chain starting at `first`.
+    fn free_chain(&self, state: &mut State, first: u32) -> Result<(), FatError> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, 0)?;
+            state.next_free = state.next_free.min(cluster);
+            state.free_count = state.free_count.map(|count| count + 1);
+            state.fsinfo_dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Every entry slot of the directory starting at `cluster`.
+    fn slots(&self, cluster: u32) -> Result<Vec<Slot>, FatError> {
+        let mut slots = Vec::new();
+        let mut data = vec![0; self.cluster_size];
+        for cluster in self.chain(cluster)? {
+            let start = self.cluster_offset(cluster);
+            self.read_at(start, &mut data)?;
+            for (i, bytes) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+                slots.push(Slot {
+                    offset: start + (i * ENTRY_SIZE) as u64,
+                    bytes: bytes.try_into().unwrap(),
+                });
+            }
+        }
+        Ok(slots)
+    }
+
+    /// The files and directories in `dir`, including `.` and `..` unless it's
+    /// the root.
+    fn entries(&self, dir: &DirEntry) -> Result<Vec<DirEntry>, FatError> {
+        if !dir.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+        let mut entries = Vec::new();
+        let mut lfn = LongName::default();
+        for slot in self.slots(dir.cluster)? {
+            let bytes = &slot.bytes;
+            match bytes[0] {
+                ENTRY_END => break,
+                ENTRY_FREE => {
+                    lfn = LongName::default();
+                    continue;
+                }
+                _ => {}
+            }
+            let attr = bytes[11];
+            if attr & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                lfn.add(bytes);
+                continue;
+            }
+            let long_name = core::mem::take(&mut lfn).finish(bytes[..11].try_into().unwrap());
+            if attr & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            let short_name: [u8; 11] = bytes[..11].try_into().unwrap();
+            entries.push(DirEntry {
+                name: long_name.unwrap_or_else(|| format_short_name(&short_name, bytes[12])),
+                short_name,
+                attr,
+                cluster: u32::from(u16_at(bytes, 20)) << 16 | u32::from(u16_at(bytes, 26)),
+                size: u32_at(bytes, 28),
+                location: Some(slot.offset),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// The files and directories in `dir`.
+    pub fn read_dir(&self, dir: &DirEntry) -> Result<Vec<DirEntry>, FatError> {
+        let _state = self.state.lock();
+        let mut entries = self.entries(dir)?;
+        entries.retain(|entry| entry.name != "." && entry.name != "..");
+        Ok(entries)
+    }
+
+    fn find(&self, dir: &DirEntry, name: &str) -> Result<DirEntry, FatError> {
+        self.entries(dir)?
+            .into_iter()
+            .find(|entry| entry.matches(name))
+            .map(|entry| self.resolve_dot_dot(entry))
+            .ok_or(FatError::NotFound)
+    }
+
+    /// `..` entries of directories in the root have cluster 0.
+    fn resolve_dot_dot(&self, entry: DirEntry) -> DirEntry {
+        if entry.is_dir() && entry.cluster == 0 {
+            self.root()
+        } else {
+            entry
+        }
+    }
+
+    /// Finds the entry named `name` in `dir`, ignoring ASCII case.
+    pub fn lookup(&self, dir: &DirEntry, name: &str) -> Result<DirEntry, FatError> {
+        let _state = self.state.lock();
+        self.find(dir, name)
+    }
+
+    /// Reads from `file` at `offset` into `buf`, returning how many bytes
+    /// were read, which is 0 at the end of the file.
+    pub fn read(&self, file: &DirEntry, offset: u64, buf: &mut [u8]) -> Result<usize, FatError> {
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        let _state = self.state.lock();
+        let size = u64::from(file.size);
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let cluster_size = self.cluster_size as u64;
+        let clusters = self.chain(file.cluster)?;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let &cluster = clusters
+                .get((pos / cluster_size) as usize)
+                .ok_or(FatError::Corrupt)?;
+            let start = (pos % cluster_size) as usize;
+            let n = (self.cluster_size - start).min(len - done);
+            self.read_at(
+                self.cluster_offset(cluster) + start as u64,
+                &mut buf[done..done + n],
+            )?;
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// Writes `buf` to `file` at `offset`, extending it if need be. A gap
+    /// left before `offset` reads as zeroes.
+    pub fn write(&self, file: &mut DirEntry, offset: u64, buf: &[u8]) -> Result<usize, FatError> {
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        let end = offset + buf.len() as u64;
+        if end > u64::from(u32::MAX) {
+            return Err(FatError::TooLarge);
+        }
+        let mut state = self.state.lock();
+        let cluster_size = self.cluster_size as u64;
+        let mut clusters = self.chain(file.cluster)?;
+        if offset > u64::from(file.size) {
+            let allocated = clusters.len() as u64 * cluster_size;
+            self.zero(&clusters, file.size.into(), offset.min(allocated))?;
+        }
+        self.extend(&mut state, file, &mut clusters, end)?;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let cluster = clusters[(pos / cluster_size) as usize];
+            let start = (pos % cluster_size) as usize;
+            let n = (self.cluster_size - start).min(buf.len() - done);
+            self.write_at(
+                self.cluster_offset(cluster) + start as u64,
+                &buf[done..done + n],
+            )?;
+            done += n;
+        }
+        file.size = file.size.max(end as u32);
+        self.update_entry(file)?;
+        Ok(buf.len())
+    }
+
+    /// Cuts `file` down to `len` bytes, freeing the clusters it no longer
+    /// needs, or extends it to `len` bytes with zeroes.
+    pub fn truncate(&self, file: &mut DirEntry, len: u64) -> Result<(), FatError> {
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        if len > u64::from(u32::MAX) {
+            return Err(FatError::TooLarge);
+        }
+        let mut state = self.state.lock();
+        let cluster_size = self.cluster_size as u64;
+        let mut clusters = self.chain(file.cluster)?;
+        let needed = len.div_ceil(cluster_size) as usize;
+        if needed < clusters.len() {
+            match needed.checked_sub(1) {
+                Some(last) => self.set_fat_entry(clusters[last], FAT_EOC)?,
+                None => file.cluster = 0,
+            }
+            self.free_chain(&mut state, clusters[needed])?;
+            clusters.truncate(needed);
+        }
+        // Whatever's past the end of the file in its last cluster reads as
+        // zeroes if the file grows over it.
+        let kept = clusters.len() as u64 * cluster_size;
+        self.zero(&clusters, len.min(file.size.into()), kept)?;
+        self.extend(&mut state, file, &mut clusters, len)?;
+        file.size = len as u32;
+        self.update_entry(file)
+    }
+
+    /// Allocates clusters for `file`, whose chain is `clusters`, until
+    /// they hold `len` bytes.
+    fn extend(
+        &self,
+        state: &mut State,
+        file: &mut DirEntry,
+        clusters: &mut Vec<u32>,
+        len: u64,
+    ) -> Result<(), FatError> {
+        let needed = len.div_ceil(self.cluster_size as u64) as usize;
+        while clusters.len() < needed {
+            let cluster = self.alloc_cluster(state, clusters.last().copied())?;
+            clusters.push(cluster);
+            if file.cluster == 0 {
+                file.cluster = cluster;
+            }
+        }
+        Ok(())
+    }
+
+    /// Zeroes the bytes from `start` to `end` of the file made of
+    /// `clusters`.
+    fn zero(&self, clusters: &[u32], start: u64, end: u64) -> Result<(), FatError> {
+        let cluster_size = self.cluster_size as u64;
+        let mut pos = start;
+        while pos < end {
+            let cluster = clusters[(pos / cluster_size) as usize];
+            let offset = pos % cluster_size;
+            let n = (cluster_size - offset).min(end - pos);
+            self.write_at(self.cluster_offset(cluster) + offset, &vec![0; n as usize])?;
+            pos += n;
+        }
+        Ok(())
+    }
+
+    /// Writes the first cluster and size of `entry` to its directory entry,
+    /// stamping it as written now.
+    fn update_entry(&self, entry: &DirEntry) -> Result<(), FatError> {
+        let Some(location) = entry.location else {
+            return Ok(());
+        };
+        let (time, date) = timestamp();
+        self.write_at(location + 18, &date.to_le_bytes())?;
+        self.write_at(location + 20, &((entry.cluster >> 16) as u16).to_le_bytes())?;
+        self.write_at(location + 22, &time.to_le_bytes())?;
+        self.write_at(location + 24, &date.to_le_bytes())?;
+        self.write_at(location + 26, &(entry.cluster as u16).to_le_bytes())?;
+        self.write_at(location + 28, &entry.size.to_le_bytes())
+    }
+
+    /// Creates an empty file, or a directory, called `name` in `dir`.
+    pub fn create(&self, dir: &DirEntry, name: &str, is_dir: bool) -> Result<DirEntry, FatError> {
+        if !dir.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+        if !is_valid_name(name) {
+            return Err(FatError::InvalidName);
+        }
+        let mut state = self.state.lock();
+        let existing = self.entries(dir)?;
+        if existing.iter().any(|entry| entry.matches(name)) {
+            return Err(FatError::Exists);
+        }
+
+        let (short_name, ntres, lfn) = match exact_short_name(name) {
+            Some((short_name, ntres)) => (short_name, ntres, None),
+            None => {
+                let short_name = generate_short_name(name, |candidate| {
+                    existing.iter().any(|entry| entry.short_name == *candidate)
+                })
+                .ok_or(FatError::Exists)?;
+                let utf16: Vec<u16> = name.encode_utf16().collect();
+                (short_name, 0, Some(utf16))
+            }
+        };
+        let lfn_count = lfn.as_ref().map_or(0, |lfn| lfn.len().div_ceil(LFN_CHARS));
+        let slots = self.free_slots(&mut state, dir, lfn_count + 1)?;
+
+        let cluster = if is_dir {
+            self.alloc_cluster(&mut state, None)?
+        } else {
+            0
+        };
+        if let Some(lfn) = &lfn {
+            let checksum = short_name_checksum(&short_name);
+            for (i, &offset) in slots[..lfn_count].iter().enumerate() {
+                let ord = (lfn_count - i) as u8;
+                let flag = if i == 0 { LFN_LAST } else { 0 };
+                let chars = &lfn[(usize::from(ord) - 1) * LFN_CHARS..];
+                self.write_at(offset, &long_name_entry(ord | flag, checksum, chars))?;
+            }
+        }
+        let attr = if is_dir { ATTR_DIRECTORY } else { ATTR_ARCHIVE };
+        let location = slots[lfn_count];
+        self.write_at(location, &short_entry(&short_name, attr, ntres, cluster, 0))?;
+
+        if is_dir {
+            let parent = if dir.location.is_none() {
+                0
+            } else {
+                dir.cluster
+            };
+            let start = self.cluster_offset(cluster);
+            self.write_at(
+                start,
+                &short_entry(b".          ", ATTR_DIRECTORY, 0, cluster, 0),
+            )?;
+            self.write_at(
+                start + ENTRY_SIZE as u64,
+                &short_entry(b"..         ", ATTR_DIRECTORY, 0, parent, 0),
+            )?;
+        }
+        Ok(DirEntry {
+            name: String::from(name),
+            short_name,
+            attr,
+            cluster,
+            size: 0,
+            location: Some(location),
+        })
+    }
+
+    /// Removes the file or empty directory called `name` from `dir`, with
+    /// its long name, and frees its clusters. `is_open` is asked whether
+    /// anything still has the entry at a location open.
+    pub fn remove(
+        &self,
+        dir: &DirEntry,
+        name: &str,
+        is_open: impl Fn(u64) -> bool,
+    ) -> Result<(), FatError> {
+        if !dir.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+        if name == "." || name == ".." {
+            return Err(FatError::InvalidName);
+        }
+        let mut state = self.state.lock();
+        let entry = self.find(dir, name)?;
+        let location = entry.location.ok_or(FatError::InvalidName)?;
+        if is_open(location) {
+            return Err(FatError::Busy);
+        }
+        if entry.is_dir()
+            && self
+                .entries(&entry)?
+                .iter()
+                .any(|entry| entry.name != "." && entry.name != "..")
+        {
+            return Err(FatError::NotEmpty);
+        }
+
+        // The long name entries are the ones right before the short entry
+        // that carry its checksum.
+        let checksum = short_name_checksum(&entry.short_name);
+        let slots = self.slots(dir.cluster)?;
+        let index = slots
+            .iter()
+            .position(|slot| slot.offset == location)
+            .ok_or(FatError::Corrupt)?;
+        let long_name = slots[..index].iter().rev().take_while(|slot| {
+            slot.bytes[0] != ENTRY_FREE
+                && slot.bytes[11] & ATTR_LONG_NAME == ATTR_LONG_NAME
+                && slot.bytes[13] == checksum
+        });
+        for slot in long_name.chain([&slots[index]]) {
+            self.write_at(slot.offset, &[ENTRY_FREE])?;
+        }
+        self.free_chain(&mut state, entry.cluster)
+    }
+
+    /// Finds `count` consecutive unused slots in `dir`, growing it if there
+    /// aren't any.
+    fn free_slots(
+        &self,
+        state: &mut State,
+        dir: &DirEntry,
+        count: usize,
+    ) -> Result<Vec<u64>, FatError> {
+        let mut run = Vec::new();
+        let slots = self.slots(dir.cluster)?;
+        for slot in &slots {
+            if matches!(slot.bytes[0], ENTRY_FREE | ENTRY_END) {
+                run.push(slot.offset);
+                if run.len() == count {
+                    return Ok(run);
+                }
+            } else {
+                run.clear();
+            }
+        }
+
+        let mut last = self.chain(dir.cluster)?.last().copied();
+        while run.len() < count {
+            let cluster = self.alloc_cluster(state, last)?;
+            let start = self.cluster_offset(cluster);
+            let needed = count - run.len();
+            run.extend(
+                (0..self.cluster_size / ENTRY_SIZE)
+                    .take(needed)
+                    .map(|i| start + (i * ENTRY_SIZE) as u64),
+            );
+            last = Some(cluster);
+        }
+        Ok(run)
+    }
+}
+
+fn is_fat32_boot_sector(sector: &[u8]) -> bool {
+    let bytes_per_sector = u16_at(sector, 0x0b);
+    let sectors_per_cluster = sector[0x0d];
+    matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        && sectors_per_cluster.is_power_of_two()
+        && u16_at(sector, 0x11) == 0
+        && u16_at(sector, 0x16) == 0
+        && u32_at(sector, 0x24) != 0
+}
+
+/// Collects the long name entries before a short entry.
+#[derive(Default)]
+struct LongName {
+    chars: Vec<u16>,
+    checksum: u8,
+    /// The ordinal of the next entry expected, or 0 if there's no name in
+    /// progress.
+    next: u8,
+    valid: bool,
+}
+
+impl LongName {
+    fn add(&mut self, entry: &[u8]) {
+        let ord = entry[0] & !LFN_LAST;
+        if entry[0] & LFN_LAST != 0 {
+            *self = Self {
+                chars: vec![0xffff; usize::from(ord) * LFN_CHARS],
+                checksum: entry[13],
+                next: ord,
+                valid: ord != 0,
+            };
+        } else if ord != self.next || entry[13] != self.checksum {
+            self.valid = false;
+        }
+        if !self.valid {
+            return;
+        }
+        let start = (usize::from(ord) - 1) * LFN_CHARS;
+        for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+            self.chars[start + i] = u16_at(entry, offset);
+        }
+        self.next = ord - 1;
+    }
+
+    /// The name, if it's complete and belongs with `short_name`.
+    fn finish(self, short_name: &[u8; 11]) -> Option<String> {
+        if !self.valid || self.next != 0 || self.checksum != short_name_checksum(short_name) {
+            return None;
+        }
+        let len = self
+            .chars
+            .iter()
+            .position(|&c| c == 0 || c == 0xffff)
+            .unwrap_or(self.chars.len());
+        let name = char::decode_utf16(self.chars[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        Some(name)
+    }
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// Shows a short name as `NAME.EXT`, lowering the case of the parts `ntres`
+/// says to.
+fn format_short_name(short_name: &[u8; 11], ntres: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let mut part = String::new();
+        for &c in bytes.iter().take_while(|&&c| c != b' ') {
+            // 0x05 stands for a leading 0xe5.
+            let c = if c == 0x05 { 0xe5 } else { c };
+            let c = if lower { c.to_ascii_lowercase() } else { c };
+            part.push(if c.is_ascii() { c as char } else { '_' });
+        }
+        part
+    };
+    let mut name = part(&short_name[..8], ntres & NTRES_LOWER_BASE != 0);
+    let ext = part(&short_name[8..], ntres & NTRES_LOWER_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.encode_utf16().count() <= MAX_NAME_LEN
+        && name != "."
+        && name != ".."
+        && !name.ends_with(['.', ' '])
+        && !name
+            .chars()
+            .any(|c| c.is_control() || "\"*/:<>?\\|".contains(c))
+}
+
+/// Characters allowed in short names besides letters and digits.
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+/// The short name `name` is, if it's one, with the case bits saying which
+/// parts are in lower case.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || ext.contains('.') {
+        return None;
+    }
+    let mut ntres = 0;
+    for (part, lower) in [(base, NTRES_LOWER_BASE), (ext, NTRES_LOWER_EXT)] {
+        let upper = part.to_ascii_uppercase();
+        if !upper.bytes().all(is_short_name_char) {
+            return None;
+        }
+        if part == part.to_ascii_lowercase() && part != upper {
+            ntres |= lower;
+        } else if part != upper {
+            // Mixed case needs a long name.
+            return None;
+        }
+    }
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short_name[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+    Some((short_name, ntres))
+}
+
+/// Makes a short name like `LONGNA~1.TXT` for `name`, that `taken` says
+/// isn't used.
+fn generate_short_name(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+    let convert = |part: &str, len: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c.is_ascii() && is_short_name_char(c as u8) {
+                    c as u8
+                } else {
+                    b'_'
+                }
+            })
+            .take(len)
+            .collect()
+    };
+    let base = convert(base, 8);
+    let ext = convert(ext, 3);
+
+    let mut short_name = [b' '; 11];
+    short_name[8..8 + ext.len()].copy_from_slice(&ext);
+    for n in 1..1_000_000u32 {
+        let mut tail = [0; 7];
+        let tail = {
+            let digits = n.ilog10() as usize + 1;
+            tail[0] = b'~';
+            let mut n = n;
+            for i in (1..=digits).rev() {
+                tail[i] = b'0' + (n % 10) as u8;
+                n /= 10;
+            }
+            &tail[..=digits]
+        };
+        let keep = base.len().min(8 - tail.len());
+        short_name[..8].fill(b' ');
+        short_name[..keep].copy_from_slice(&base[..keep]);
+        short_name[keep..keep + tail.len()].copy_from_slice(tail);
+        if !taken(&short_name) {
+            return Some(short_name);
+        }
+    }
+    None
+}
+
+/// The time and date now, in FAT's format, or midnight on 1980-01-01 if
+/// the real time isn't in the range FAT can hold, as without an RTC.
+fn timestamp() -> (u16, u16) {
+    let secs = time::realtime().as_secs();
+    // The civil date of a day since the Unix epoch, after Howard Hinnant's
+    // `civil_from_days`, with years starting in March.
+    let days = secs / 86400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    if !(1980..2108).contains(&year) {
+        return (0, DEFAULT_DATE);
+    }
+    let date = (((year - 1980) << 9) | (month << 5) | day) as u16;
+    let secs = secs % 86400;
+    let time = ((secs / 3600) << 11 | ((secs / 60 % 60) << 5) | (secs % 60 / 2)) as u16;
+    (time, date)
+}
+
+fn short_entry(
+    short_name: &[u8; 11],
+    attr: u8,
+    ntres: u8,
+    cluster: u32,
+    size: u32,
+) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[..11].copy_from_slice(short_name);
+    entry[11] = attr;
+    entry[12] = ntres;
+    let (time, date) = timestamp();
+    for time_at in [14, 22] {
+        entry[time_at..time_at + 2].copy_from_slice(&time.to_le_bytes());
+    }
+    for date_at in [16, 18, 24] {
+        entry[date_at..date_at + 2].copy_from_slice(&date.to_le_bytes());
+    }
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// A long name entry holding the first 13 of `chars`, ending the name with a
+/// NUL and padding if there are fewer.
+fn long_name_entry(ord: u8, checksum: u8, chars: &[u16]) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[0] = ord;
+    entry[11] = ATTR_LONG_NAME;
+    entry[13] = checksum;
+    for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+        let c = match i.cmp(&chars.len()) {
+            core::cmp::Ordering::Less => chars[i],
+            core::cmp::Ordering::Equal => 0,
+            core::cmp::Ordering::Greater => 0xffff,
+        };
+        entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+    }
+    entry
+}
+
+/// The nodes of a filesystem that are in use, by the location of their
+/// directory entries, so that everything using a file sees its size change.
+type VnodeTable = SpinLock<BTreeMap<u64, Weak<FatVnode>>>;
+
+/// A FAT32 filesystem, for mounting in the VFS.
+pub struct FatFileSystem {
+    fs: Arc<FatFs>,
+    vnodes: Arc<VnodeTable>,
+}
+
+impl FatFileSystem {
+    pub fn new(fs: Arc<FatFs>) -> Arc<Self> {
+        Arc::new(Self {
+            fs,
+            vnodes: Arc::new(SpinLock::new(BTreeMap::new())),
+        })
+    }
+}
+
+impl FileSystem for FatFileSystem {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+
+    fn root(&self) -> Arc<dyn Vnode> {
+        vnode(&self.fs, &self.vnodes, self.fs.root())
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        Ok(self.fs.sync()?)
+    }
+}
+
+struct FatVnode {
+    fs: Arc<FatFs>,
+    vnodes: Arc<VnodeTable>,
+    entry: Mutex<DirEntry>,
+}
+
+/// The node for `entry`, shared with anything else using it.
+fn vnode(fs: &Arc<FatFs>, vnodes: &Arc<VnodeTable>, entry: DirEntry) -> Arc<dyn Vnode> {
+    let new = |entry| {
+        Arc::new(FatVnode {
+            fs: fs.clone(),
+            vnodes: vnodes.clone(),
+            entry: Mutex::new(entry),
+        })
+    };
+    // The root directory's entry never changes.
+    let Some(location) = entry.location else {
+        return new(entry);
+    };
+    let mut table = vnodes.lock();
+    if let Some(vnode) = table.get(&location).and_then(Weak::upgrade) {
+        return vnode;
+    }
+    table.retain(|_, vnode| vnode.strong_count() > 0);
+    let vnode = new(entry);
+    table.insert(location, Arc::downgrade(&vnode));
+    vnode
+}
+
+impl FatVnode {
+    fn entry(&self) -> DirEntry {
+        self.entry.lock().clone()
+    }
+}
+
+fn kind(entry: &DirEntry) -> VnodeKind {
+    if entry.is_dir() {
+        VnodeKind::Directory
+    } else {
+        VnodeKind::File
+    }
+}
+
+impl Vnode for FatVnode {
+    fn kind(&self) -> VnodeKind {
+        kind(&self.entry.lock())
+    }
+
+    fn size(&self) -> u64 {
+        self.entry.lock().size().into()
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, Errno> {
+        let entry = self.fs.lookup(&self.entry(), name)?;
+        Ok(vnode(&self.fs, &self.vnodes, entry))
+    }
+
+    fn read_dir(&self) -> Result<Vec<super::DirEntry>, Errno> {
+        let entries = self.fs.read_dir(&self.entry())?;
+        Ok(entries
+            .iter()
+            .map(|entry| super::DirEntry {
+                name: entry.name().into(),
+                kind: kind(entry),
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: VnodeKind) -> Result<Arc<dyn Vnode>, Errno> {
+        let is_dir = match kind {
+            VnodeKind::File => false,
+            VnodeKind::Directory => true,
+            VnodeKind::Device => return Err(Errno::EINVAL),
+        };
+        let entry = self.fs.create(&self.entry(), name, is_dir)?;
+        Ok(vnode(&self.fs, &self.vnodes, entry))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        Ok(self.fs.read(&self.entry(), offset, buf)?)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        Ok(self.fs.write(&mut self.entry.lock(), offset, buf)?)
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), Errno> {
+        Ok(self.fs.truncate(&mut self.entry.lock(), len)?)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Errno> {
+        let is_open = |location| {
+            self.vnodes
+                .lock()
+                .get(&location)
+                .is_some_and(|vnode| vnode.strong_count() > 0)
+        };
+        Ok(self.fs.remove(&self.entry(), name, is_open)?)
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        Ok(self.fs.sync()?)
+    }
+}
+
+ktest! {
+    fn rejects_malformed_images() {
+        use crate::block::ramdisk::RamDisk;
+
+        // 512-byte sectors and clusters, 32 reserved sectors, two FATs of 8
+        // sectors and the root directory in cluster 2, which is sector 48.
+        let image = || {
+            let mut image = alloc::vec![0u8; 2048 * 512];
+            let boot = &mut image[..512];
+            boot[0x0b..0x0d].copy_from_slice(&512u16.to_le_bytes());
+            boot[0x0d] = 1;
+            boot[0x0e..0x10].copy_from_slice(&32u16.to_le_bytes());
+            boot[0x10] = 2;
+            boot[0x20..0x24].copy_from_slice(&2048u32.to_le_bytes());
+            boot[0x24..0x28].copy_from_slice(&8u32.to_le_bytes());
+            boot[0x2c..0x30].copy_from_slice(&2u32.to_le_bytes());
+            boot[510..].copy_from_slice(&[0x55, 0xaa]);
+            for fat in [32, 40] {
+                let fat = &mut image[fat * 512..];
+                let entries = [0x0fff_fff8, FAT_EOC, FAT_EOC, FAT_EOC];
+                for (cluster, entry) in entries.iter().enumerate() {
+                    fat[4 * cluster..][..4].copy_from_slice(&entry.to_le_bytes());
+                }
+            }
+            // HELLO.TXT, in cluster 3.
+            let entry = &mut image[48 * 512..][..ENTRY_SIZE];
+            entry[..11].copy_from_slice(b"HELLO   TXT");
+            entry[11] = ATTR_ARCHIVE;
+            entry[26..28].copy_from_slice(&3u16.to_le_bytes());
+            entry[28..32].copy_from_slice(&5u32.to_le_bytes());
+            image[49 * 512..][..5].copy_from_slice(b"hello");
+            image
+        };
+        let mount = |image: Vec<u8>| FatFs::mount(RamDisk::from_image(&image).unwrap());
+        let read = |image: Vec<u8>| {
+            let fs = mount(image).unwrap();
+            let file = fs.lookup(&fs.root(), "hello.txt")?;
+            let mut buf = [0; 5];
+            fs.read(&file, 0, &mut buf).map(|len| buf[..len].to_vec())
+        };
+        let patch = |at: usize, bytes: &[u8]| {
+            let mut image = image();
+            image[at..at + bytes.len()].copy_from_slice(bytes);
+            image
+        };
+        assert_eq!(read(image()), Ok(b"hello".to_vec()));
+
+        assert_eq!(mount(patch(510, &[0, 0])).err(), Some(FatError::NotFat));
+        // Not FAT32, and no partition table entry for one.
+        let not_fat = Some(FatError::NotFat);
+        assert_eq!(mount(patch(0x0b, &300u16.to_le_bytes())).err(), not_fat);
+        assert_eq!(mount(patch(0x16, &1u16.to_le_bytes())).err(), not_fat);
+        // A FAT32 partition past the end of the device.
+        let mut mbr = patch(0x0b, &0u16.to_le_bytes());
+        mbr[446 + 4] = 0x0c;
+        mbr[446 + 8..446 + 12].copy_from_slice(&4096u32.to_le_bytes());
+        assert_eq!(
+            mount(mbr).err(),
+            Some(FatError::Block(BlockError::OutOfRange))
+        );
+
+        let corrupt = Some(FatError::Corrupt);
+        assert_eq!(mount(patch(0x10, &[0])).err(), corrupt);
+        assert_eq!(mount(patch(0x0e, &0xffffu16.to_le_bytes())).err(), corrupt);
+        assert_eq!(mount(patch(0x20, &4096u32.to_le_bytes())).err(), corrupt);
+        assert_eq!(mount(patch(0x2c, &5000u32.to_le_bytes())).err(), corrupt);
+        assert_eq!(mount(patch(0x2c, &1u32.to_le_bytes())).err(), corrupt);
+
+        // A chain looping on itself, one into a reserved cluster and one
+        // shorter than the file.
+        let fat3 = 32 * 512 + 4 * 3;
+        assert_eq!(read(patch(fat3, &3u32.to_le_bytes())), Err(FatError::Corrupt));
+        assert_eq!(read(patch(fat3, &1u32.to_le_bytes())), Err(FatError::Corrupt));
+        let short = patch(48 * 512 + 28, &5000u32.to_le_bytes());
+        let fs = mount(short).unwrap();
+        let file = fs.lookup(&fs.root(), "hello.txt").unwrap();
+        let mut buf = alloc::vec![0; 1024];
+        assert_eq!(fs.read(&file, 0, &mut buf), Err(FatError::Corrupt));
+        // A long name entry whose checksum doesn't match is ignored.
+        let mut lfn = image();
+        let dir = 48 * 512;
+        lfn.copy_within(dir..dir + ENTRY_SIZE, dir + ENTRY_SIZE);
+        let chars: Vec<u16> = "other.txt".encode_utf16().collect();
+        let entry = long_name_entry(LFN_LAST | 1, 0, &chars);
+        lfn[dir..dir + ENTRY_SIZE].copy_from_slice(&entry);
+        let fs = mount(lfn).unwrap();
+        let entries = fs.read_dir(&fs.root()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name(), "HELLO.TXT");
+    }
+}
+
+ktest! {
+    fn writes_truncates_and_removes_files() {
+        use crate::block::ramdisk::RamDisk;
+
+        // As above, but empty, with an FSInfo sector in sector 1 counting
+        // every cluster but the root directory's as free.
+        let mut image = alloc::vec![0u8; 2048 * 512];
+        let boot = &mut image[..512];
+        boot[0x0b..0x0d].copy_from_slice(&512u16.to_le_bytes());
+        boot[0x0d] = 1;
+        boot[0x0e..0x10].copy_from_slice(&32u16.to_le_bytes());
+        boot[0x10] = 2;
+        boot[0x20..0x24].copy_from_slice(&2048u32.to_le_bytes());
+        boot[0x24..0x28].copy_from_slice(&8u32.to_le_bytes());
+        boot[0x2c..0x30].copy_from_slice(&2u32.to_le_bytes());
+        boot[0x30..0x32].copy_from_slice(&1u16.to_le_bytes());
+        boot[510..].copy_from_slice(&[0x55, 0xaa]);
+        let fsinfo = &mut image[512..1024];
+        fsinfo[..4].copy_from_slice(&FSINFO_LEAD_SIG.to_le_bytes());
+        fsinfo[484..488].copy_from_slice(&FSINFO_STRUC_SIG.to_le_bytes());
+        fsinfo[488..492].copy_from_slice(&999u32.to_le_bytes());
+        fsinfo[492..496].copy_from_slice(&3u32.to_le_bytes());
+        for fat in [32, 40] {
+            let fat = &mut image[fat * 512..];
+            for (cluster, entry) in [0x0fff_fff8, FAT_EOC, FAT_EOC].iter().enumerate() {
+                fat[4 * cluster..][..4].copy_from_slice(&entry.to_le_bytes());
+            }
+        }
+        let disk = RamDisk::from_image(&image).unwrap();
+        let fs = FatFs::mount(disk.clone()).unwrap();
+        let root = fs.root();
+
+        let mut file = fs.create(&root, "A long file name.txt", false).unwrap();
+        assert_eq!(fs.write(&mut file, 0, &[1; 1500]), Ok(1500));
+        assert_eq!(fs.chain(file.cluster).unwrap().len(), 3);
+        fs.truncate(&mut file, 600).unwrap();
+        assert_eq!(fs.chain(file.cluster).unwrap().len(), 2);
+        // What was cut off reads as zeroes once the file grows again.
+        fs.truncate(&mut file, 1024).unwrap();
+        let mut buf = [0xff; 1024];
+        assert_eq!(fs.read(&file, 0, &mut buf), Ok(1024));
+        assert!(buf[..600].iter().all(|&b| b == 1));
+        assert!(buf[600..].iter().all(|&b| b == 0));
+        let found = fs.lookup(&root, "a long file name.TXT").unwrap();
+        assert_eq!(found.size(), 1024);
+
+        // Two clusters in use, written back to FSInfo on sync.
+        fs.sync().unwrap();
+        let free_count = || {
+            let mut sector = [0; 512];
+            disk.read_blocks(1, &mut sector).unwrap();
+            u32_at(&sector, 488)
+        };
+        assert_eq!(free_count(), 997);
+
+        let dir = fs.create(&root, "dir", true).unwrap();
+        fs.create(&dir, "inner", false).unwrap();
+        assert_eq!(fs.remove(&root, "dir", |_| false), Err(FatError::NotEmpty));
+        fs.remove(&dir, "inner", |_| false).unwrap();
+        assert_eq!(
+            fs.remove(&root, "A long file name.txt", |_| true),
+            Err(FatError::Busy)
+        );
+        fs.remove(&root, "A long file name.txt", |_| false).unwrap();
+        fs.remove(&root, "dir", |_| false).unwrap();
+        assert!(fs.read_dir(&root).unwrap().is_empty());
+        fs.sync().unwrap();
+        assert_eq!(free_count(), 999);
+    }
+}
+
+ktest! {
+    fn makes_short_names() {
+        let readme = exact_short_name("readme.txt").unwrap();
+        assert_eq!(readme, (*b"README  TXT", NTRES_LOWER_BASE | NTRES_LOWER_EXT));
+        assert_eq!(format_short_name(&readme.0, readme.1), "readme.txt");
+        assert_eq!(exact_short_name("README"), Some((*b"README     ", 0)));
+        assert_eq!(exact_short_name("Readme.txt"), None);
+        assert_eq!(exact_short_name("toolongname.txt"), None);
+        assert_eq!(exact_short_name("a.b.c"), None);
+        assert_eq!(exact_short_name("a+b"), None);
+
+        let name = "A long file name.txt";
+        assert_eq!(generate_short_name(name, |_| false), Some(*b"ALONGF~1TXT"));
+        assert_eq!(
+            generate_short_name(name, |short| short == b"ALONGF~1TXT"),
+            Some(*b"ALONGF~2TXT")
+        );
+        // Longer tails take more of the base.
+        assert_eq!(
+            generate_short_name(name, |short| short[6] == b'~'),
+            Some(*b"ALONG~10TXT")
+        );
+        assert_eq!(generate_short_name(".bashrc", |_| false), Some(*b"BASHRC~1   "));
+        assert_eq!(generate_short_name("über", |_| false), Some(*b"_BER~1     "));
+
+        assert!(is_valid_name("ok"));
+        assert!(!is_valid_name("a/b"));
+        assert!(!is_valid_name("trailing."));
+        assert!(!is_valid_name(".."));
+        assert!(!is_valid_name(&"x".repeat(MAX_NAME_LEN + 1)));
+    }
+}
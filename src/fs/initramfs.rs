@@ -0,0 +1,403 @@
+//! The initial RAM filesystem: a cpio archive in the "newc" format, loaded
+//! by the bootloader between `linux,initrd-start` and `linux,initrd-end` in
+//! `/chosen`, with or without checksums. It's unpacked into a read-only tree
+//! whose files point into the archive, which stays reserved for good.
+//! Without an initrd, or if it's taken for a RAM disk, the root is just the
+//! directories other filesystems are mounted on.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{DirEntry, FileSystem, Vnode, VnodeKind};
+use crate::block::ramdisk;
+use crate::dtb::DeviceTree;
+use crate::mm::phys_to_virt;
+use crate::syscall::Errno;
+use crate::{ktest, log_error, log_info, log_warn};
+
+/// Each header starts with one of these, the second if it has checksums.
+const MAGIC: &[u8] = b"070701";
+const MAGIC_CRC: &[u8] = b"070702";
+
+/// The magic, then 13 fields of 8 hex digits.
+const HEADER_SIZE: usize = 110;
+
+/// The name of the entry ending the archive.
+const TRAILER: &str = "TRAILER!!!";
+
+/// Directories always in the root, for other filesystems to be mounted on.
+const MOUNT_POINTS: &[&str] = &["dev", "mnt", "proc"];
+
+/// The type bits of a mode, and the types that are unpacked.
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CpioError {
+    BadMagic,
+    BadHeader,
+    /// An entry runs past the end of the archive, or there's no trailer.
+    Truncated,
+    InvalidName,
+    /// A file's data doesn't add up to its header's checksum.
+    BadChecksum,
+}
+
+impl fmt::Display for CpioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => f.write_str("not a newc cpio archive"),
+            Self::BadHeader => f.write_str("invalid header"),
+            Self::Truncated => f.write_str("truncated archive"),
+            Self::InvalidName => f.write_str("invalid file name"),
+            Self::BadChecksum => f.write_str("checksum mismatch"),
+        }
+    }
+}
+
+struct Entry<'a> {
+    name: &'a str,
+    mode: u32,
+    data: &'a [u8],
+}
+
+/// Reads the header field at `index`.
+fn field(header: &[u8], index: usize) -> Result<usize, CpioError> {
+    let start = MAGIC.len() + 8 * index;
+    let digits =
+        core::str::from_utf8(&header[start..start + 8]).map_err(|_| CpioError::BadHeader)?;
+    usize::from_str_radix(digits, 16).map_err(|_| CpioError::BadHeader)
+}
+
+/// Parses the entry at `offset`, returning it and the offset of the next,
+/// or `None` for the trailer.
+fn parse(archive: &[u8], offset: usize) -> Result<Option<(Entry<'_>, usize)>, CpioError> {
+    let header = archive
+        .get(offset..offset + HEADER_SIZE)
+        .ok_or(CpioError::Truncated)?;
+    if &header[..MAGIC.len()] != MAGIC && &header[..MAGIC.len()] != MAGIC_CRC {
+        return Err(CpioError::BadMagic);
+    }
+    let mode = field(header, 1)? as u32;
+    let file_size = field(header, 6)?;
+    let name_size = field(header, 11)?;
+
+    // The name includes its NUL, and it and the data are padded to 4 bytes.
+    let name_start = offset + HEADER_SIZE;
+    let name = archive
+        .get(name_start..name_start + name_size)
+        .ok_or(CpioError::Truncated)?;
+    let name = name
+        .strip_suffix(&[0])
+        .and_then(|name| core::str::from_utf8(name).ok())
+        .ok_or(CpioError::InvalidName)?;
+    if name == TRAILER {
+        return Ok(None);
+    }
+    let data_start = (name_start + name_size).next_multiple_of(4);
+    let data = archive
+        .get(data_start..data_start + file_size)
+        .ok_or(CpioError::Truncated)?;
+    // The checksum is the sum of the data's bytes.
+    if &header[..MAGIC.len()] == MAGIC_CRC {
+        let sum = data
+            .iter()
+            .fold(0u32, |sum, &byte| sum.wrapping_add(u32::from(byte)));
+        if sum != field(header, 12)? as u32 {
+            return Err(CpioError::BadChecksum);
+        }
+    }
+    let next = (data_start + file_size).next_multiple_of(4);
+    Ok(Some((Entry { name, mode, data }, next)))
+}
+
+/// A directory being unpacked.
+#[derive(Default)]
+struct Builder {
+    files: BTreeMap<String, BuilderNode>,
+}
+
+enum BuilderNode {
+    File(&'static [u8]),
+    Directory(Builder),
+}
+
+impl Builder {
+    /// Adds a node at `path`, creating the directories above it. Later
+    /// entries replace earlier ones with the same name.
+    fn insert(&mut self, path: &str, node: BuilderNode) -> Result<(), CpioError> {
+        let mut components = path.split('/').filter(|c| !c.is_empty() && *c != ".");
+        let Some(mut name) = components.next() else {
+            // The root itself.
+            return Ok(());
+        };
+        let mut dir = self;
+        for next in components {
+            if name == ".." {
+                return Err(CpioError::InvalidName);
+            }
+            let child = dir
+                .files
+                .entry(name.into())
+                .or_insert_with(|| BuilderNode::Directory(Builder::default()));
+            if let BuilderNode::File(_) = child {
+                *child = BuilderNode::Directory(Builder::default());
+            }
+            let BuilderNode::Directory(child) = child else {
+                unreachable!();
+            };
+            dir = child;
+            name = next;
+        }
+        if name == ".." || name.len() > super::NAME_MAX {
+            return Err(CpioError::InvalidName);
+        }
+        match (dir.files.get_mut(name), node) {
+            // Keep what's already in a directory listed again.
+            (Some(BuilderNode::Directory(_)), BuilderNode::Directory(_)) => {}
+            (_, node) => {
+                dir.files.insert(name.into(), node);
+            }
+        }
+        Ok(())
+    }
+
+    fn build(self) -> Arc<RamNode> {
+        let files = self
+            .files
+            .into_iter()
+            .map(|(name, node)| {
+                let node = match node {
+                    BuilderNode::File(data) => Arc::new(RamNode::File(data)),
+                    BuilderNode::Directory(dir) => dir.build(),
+                };
+                (name, node)
+            })
+            .collect();
+        Arc::new(RamNode::Directory(files))
+    }
+}
+
+enum RamNode {
+    File(&'static [u8]),
+    Directory(BTreeMap<String, Arc<RamNode>>),
+}
+
+impl Vnode for RamNode {
+    fn kind(&self) -> VnodeKind {
+        match self {
+            Self::File(_) => VnodeKind::File,
+            Self::Directory(_) => VnodeKind::Directory,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self {
+            Self::File(data) => data.len() as u64,
+            Self::Directory(_) => 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, Errno> {
+        match self {
+            Self::File(_) => Err(Errno::ENOTDIR),
+            Self::Directory(files) => match files.get(name) {
+                Some(node) => Ok(node.clone()),
+                None => Err(Errno::ENOENT),
+            },
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Errno> {
+        match self {
+            Self::File(_) => Err(Errno::ENOTDIR),
+            Self::Directory(files) => Ok(files
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    kind: node.kind(),
+                })
+                .collect()),
+        }
+    }
+
+    fn create(&self, _name: &str, _kind: VnodeKind) -> Result<Arc<dyn Vnode>, Errno> {
+        match self {
+            Self::File(_) => Err(Errno::ENOTDIR),
+            Self::Directory(_) => Err(Errno::EROFS),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let Self::File(data) = self else {
+            return Err(Errno::EISDIR);
+        };
+        let start = data
+            .len()
+            .min(usize::try_from(offset).unwrap_or(usize::MAX));
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, Errno> {
+        match self {
+            Self::File(_) => Err(Errno::EROFS),
+            Self::Directory(_) => Err(Errno::EISDIR),
+        }
+    }
+
+    fn truncate(&self, _len: u64) -> Result<(), Errno> {
+        match self {
+            Self::File(_) => Err(Errno::EROFS),
+            Self::Directory(_) => Err(Errno::EISDIR),
+        }
+    }
+}
+
+pub struct Initramfs {
+    root: Arc<RamNode>,
+    files: usize,
+}
+
+impl Initramfs {
+    /// Unpacks `archive`. Entries other than files and directories, such as
+    /// symlinks and device nodes, are skipped.
+    pub fn unpack(archive: &'static [u8]) -> Result<Arc<Self>, CpioError> {
+        let mut root = Builder::default();
+        for name in MOUNT_POINTS {
+            root.insert(name, BuilderNode::Directory(Builder::default()))?;
+        }
+        let mut files = 0;
+        let mut offset = 0;
+        // An empty archive, used when there's no initrd, has no trailer.
+        while !archive.is_empty() {
+            let Some((entry, next)) = parse(archive, offset)? else {
+                break;
+            };
+            offset = next;
+            let node = match entry.mode & S_IFMT {
+                S_IFREG => {
+                    files += 1;
+                    BuilderNode::File(entry.data)
+                }
+                S_IFDIR => BuilderNode::Directory(Builder::default()),
+                _ => {
+                    log_warn!(target: "initramfs", "{}: unsupported file type", entry.name);
+                    continue;
+                }
+            };
+            root.insert(entry.name, node)?;
+        }
+        Ok(Arc::new(Self {
+            root: root.build(),
+            files,
+        }))
+    }
+}
+
+impl FileSystem for Initramfs {
+    fn name(&self) -> &'static str {
+        "initramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Vnode> {
+        self.root.clone()
+    }
+}
+
+/// Unpacks the initrd and mounts it at `/`. If the bootloader didn't load
+/// one, or it can't be unpacked, an empty root is mounted instead.
+pub fn init(dt: &DeviceTree<'_>) {
+    let initrd = dt
+        .chosen()
+        .and_then(|chosen| chosen.initrd)
+        .filter(|_| !ramdisk::holds_initrd());
+    let archive: &'static [u8] = match initrd {
+        Some(initrd) => {
+            let start = phys_to_virt(initrd.start as usize) as *const u8;
+            let len = (initrd.end - initrd.start) as usize;
+            // SAFETY: the initrd is in RAM, which is all mapped, and the frame
+            // allocator never hands it out.
+            unsafe { core::slice::from_raw_parts(start, len) }
+        }
+        None => &[],
+    };
+    let fs = Initramfs::unpack(archive).unwrap_or_else(|err| {
+        log_error!(target: "initramfs", "couldn't unpack initrd: {}", err);
+        Initramfs::unpack(&[]).unwrap()
+    });
+    if !archive.is_empty() {
+        log_info!(target: "initramfs", "{} files, {} KiB", fs.files, archive.len() / 1024);
+    }
+    if let Err(err) = super::mount("/", fs) {
+        log_error!(target: "initramfs", "couldn't mount at /: {}", err);
+    }
+}
+
+ktest! {
+    fn rejects_malformed_archives() {
+        let entry = |name: &str, mode: u32, data: &[u8]| {
+            let zeros = "0".repeat(32);
+            let mut entry = alloc::format!(
+                "070701{:08x}{:08x}{}{:08x}{}{:08x}{:08x}",
+                0,
+                mode,
+                zeros,
+                data.len(),
+                zeros,
+                name.len() + 1,
+                0
+            )
+            .into_bytes();
+            entry.extend_from_slice(name.as_bytes());
+            entry.push(0);
+            entry.resize(entry.len().next_multiple_of(4), 0);
+            entry.extend_from_slice(data);
+            entry.resize(entry.len().next_multiple_of(4), 0);
+            entry
+        };
+        let unpack = |archive: Vec<u8>| Initramfs::unpack(archive.leak()).err();
+
+        let sh = entry("bin/sh", S_IFREG | 0o755, b"#!");
+        let trailer = entry(TRAILER, 0, b"");
+        let archive = [entry("bin", S_IFDIR | 0o755, b""), sh.clone(), trailer.clone()].concat();
+        let fs = Initramfs::unpack(archive.clone().leak()).unwrap();
+        let sh_node = fs.root().lookup("bin").unwrap().lookup("sh").unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(sh_node.read_at(0, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"#!");
+
+        // Cut short in a header, in a name and in data, and without a trailer.
+        for len in [50, HEADER_SIZE + 2, sh.len() - 3, sh.len()] {
+            assert_eq!(unpack(sh[..len].to_vec()), Some(CpioError::Truncated));
+        }
+
+        let mut bad_magic = archive.clone();
+        bad_magic[5] = b'7';
+        assert_eq!(unpack(bad_magic), Some(CpioError::BadMagic));
+        let mut bad_digit = archive.clone();
+        bad_digit[6 + 8 * 6] = b'g';
+        assert_eq!(unpack(bad_digit), Some(CpioError::BadHeader));
+        let mut bad_utf8 = archive.clone();
+        bad_utf8[HEADER_SIZE] = 0xff;
+        assert_eq!(unpack(bad_utf8), Some(CpioError::InvalidName));
+        // A name without its NUL.
+        let mut no_nul = sh.clone();
+        no_nul[6 + 8 * 11..6 + 8 * 12].copy_from_slice(b"00000006");
+        assert_eq!(unpack([no_nul, trailer.clone()].concat()), Some(CpioError::InvalidName));
+        // With a checksum, which for "#!" is 0x23 + 0x21.
+        let mut crc = sh.clone();
+        crc[5] = b'2';
+        crc[6 + 8 * 12..6 + 8 * 13].copy_from_slice(b"00000044");
+        assert_eq!(unpack([crc.clone(), trailer.clone()].concat()), None);
+        crc[6 + 8 * 12..6 + 8 * 13].copy_from_slice(b"00000045");
+        assert_eq!(unpack([crc, trailer.clone()].concat()), Some(CpioError::BadChecksum));
+        let escape = entry("../etc/passwd", S_IFREG, b"");
+        assert_eq!(unpack([escape, trailer].concat()), Some(CpioError::InvalidName));
+    }
+}
@@ -0,0 +1,646 @@
+//! 9P2000.L filesystems, shared by the host through a transport such as
+//! virtio-9p under a mount tag. `9p=<tag>` on the command line mounts the
+//! share at `/mnt`, or `9p=<tag>:<path>` elsewhere, and the shell's
+//! `mount -t 9p <tag> <path>` mounts one later.
+//!
+//! Every node holds a fid walked to it, which is clunked when the node is
+//! dropped, and a file read or written is opened through a second fid of
+//! its own. Nothing is cached: every operation is a request to the server,
+//! so changes on the host show up right away. Errors the server reports are
+//! Linux's, and passed on as they are.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+
+use super::{DirEntry, FileSystem, Vnode, VnodeKind};
+use crate::sync::{Mutex, SpinLock};
+use crate::syscall::Errno;
+use crate::{cmdline, ktest, log_error, log_info};
+
+/// The largest message, requests and replies alike.
+const MSIZE: u32 = 64 * 1024;
+
+/// The header of a `Rread` or `Twrite`, ahead of the data.
+const IO_HEADER_SIZE: u32 = 24;
+
+const VERSION: &str = "9P2000.L";
+
+/// Message types; each reply is the request's plus one.
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TMKDIR: u8 = 72;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+/// The tag of `Tversion`, and the fid meaning none.
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+
+/// The fid of the root, attached to first.
+const ROOT_FID: u32 = 0;
+
+/// A qid type bit: the file is a directory.
+const QTDIR: u8 = 0x80;
+
+/// The `d_type` of a directory in `Rreaddir`.
+const DT_DIR: u8 = 4;
+
+/// Linux's open flags, which `Tlopen` and `Tlcreate` take.
+const O_RDONLY: u32 = 0;
+const O_RDWR: u32 = 2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_DIRECTORY: u32 = 0o200000;
+
+const GETATTR_SIZE: u64 = 0x200;
+const SETATTR_SIZE: u32 = 0x8;
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// A way to send requests to a server.
+pub trait Transport: Send + Sync {
+    /// Sends `request` and waits for the reply to be written to `reply`.
+    fn request(&self, request: &[u8], reply: &mut [u8]);
+}
+
+/// Every transport, by its tag.
+static TRANSPORTS: SpinLock<Vec<(String, Arc<dyn Transport>)>> = SpinLock::new(Vec::new());
+
+/// Makes the share behind `transport` available for mounting as `tag`.
+pub fn register(tag: String, transport: Arc<dyn Transport>) {
+    TRANSPORTS.lock().push((tag, transport));
+}
+
+/// A request being built, with room left for its size and tag.
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new(kind: u8) -> Self {
+        Self(vec![0, 0, 0, 0, kind, 0, 0])
+    }
+
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn str(self, value: &str) -> Self {
+        let mut message = self.u16(value.len() as u16);
+        message.0.extend_from_slice(value.as_bytes());
+        message
+    }
+
+    fn bytes(mut self, value: &[u8]) -> Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+}
+
+/// A reply being read, failing with `EIO` if it's too short.
+struct Reply<'a>(&'a [u8]);
+
+impl<'a> Reply<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Errno> {
+        if self.0.len() < len {
+            return Err(Errno::EIO);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Errno> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Errno> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Errno> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Errno> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str, Errno> {
+        let len = self.u16()?;
+        core::str::from_utf8(self.take(len.into())?).map_err(|_| Errno::EIO)
+    }
+
+    /// A qid's type, skipping its version and path.
+    fn qid(&mut self) -> Result<u8, Errno> {
+        let kind = self.u8()?;
+        self.take(12)?;
+        Ok(kind)
+    }
+}
+
+/// A connection to a server, attached to its root as `ROOT_FID`.
+struct Client {
+    transport: Arc<dyn Transport>,
+    msize: u32,
+    next_tag: AtomicU16,
+    next_fid: AtomicU32,
+}
+
+impl Client {
+    /// Agrees on a version and message size with the server behind
+    /// `transport`, and attaches to its root.
+    fn connect(transport: Arc<dyn Transport>) -> Result<Arc<Self>, Errno> {
+        let mut client = Self {
+            transport,
+            msize: MSIZE,
+            next_tag: AtomicU16::new(0),
+            next_fid: AtomicU32::new(ROOT_FID + 1),
+        };
+        let request = Message::new(TVERSION).u32(MSIZE).str(VERSION);
+        let reply = client.call_tagged(request, NOTAG, 64)?;
+        let mut reply = Reply(&reply);
+        let msize = reply.u32()?;
+        let version = reply.str()?;
+        if version != VERSION {
+            log_error!(target: "9p", "server speaks {:?}, not {}", version, VERSION);
+            return Err(Errno::EINVAL);
+        }
+        client.msize = msize.min(MSIZE);
+        if client.msize <= IO_HEADER_SIZE {
+            return Err(Errno::EINVAL);
+        }
+
+        let request = Message::new(TATTACH)
+            .u32(ROOT_FID)
+            .u32(NOFID)
+            .str("root")
+            .str("")
+            .u32(0);
+        client.call(request, 64)?;
+        Ok(Arc::new(client))
+    }
+
+    /// Makes a request, returning what follows the reply's header.
+    /// `reply_len` is the most the reply can take.
+    fn call(&self, request: Message, reply_len: usize) -> Result<Vec<u8>, Errno> {
+        let mut tag = self.next_tag.fetch_add(1, Ordering::Relaxed);
+        if tag == NOTAG {
+            tag = self.next_tag.fetch_add(1, Ordering::Relaxed);
+        }
+        self.call_tagged(request, tag, reply_len)
+    }
+
+    fn call_tagged(&self, request: Message, tag: u16, reply_len: usize) -> Result<Vec<u8>, Errno> {
+        let Message(mut request) = request;
+        let kind = request[4];
+        let len = request.len() as u32;
+        request[..4].copy_from_slice(&len.to_le_bytes());
+        request[5..7].copy_from_slice(&tag.to_le_bytes());
+        // Room for the header, or for an error instead.
+        let mut reply = vec![0; reply_len.max(4) + 7];
+        self.transport.request(&request, &mut reply);
+
+        let mut header = Reply(&reply);
+        let len = header.u32()? as usize;
+        let reply_kind = header.u8()?;
+        if header.u16()? != tag || !(7..=reply.len()).contains(&len) {
+            return Err(Errno::EIO);
+        }
+        let mut body = Reply(&reply[7..len]);
+        match reply_kind {
+            RLERROR => Err(Errno::from_raw(body.u32()?)),
+            _ if reply_kind == kind + 1 => Ok(reply[7..len].to_vec()),
+            _ => Err(Errno::EIO),
+        }
+    }
+
+    /// The most data a read or write can carry.
+    fn max_io(&self) -> usize {
+        (self.msize - IO_HEADER_SIZE) as usize
+    }
+
+    fn new_fid(&self) -> u32 {
+        self.next_fid.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Walks from `fid` through `names` to a new fid, returning its qid
+    /// type, or `None` if `names` is empty and the new fid is a copy.
+    fn walk(&self, fid: u32, new_fid: u32, names: &[&str]) -> Result<Option<u8>, Errno> {
+        let mut request = Message::new(TWALK)
+            .u32(fid)
+            .u32(new_fid)
+            .u16(names.len() as u16);
+        for name in names {
+            request = request.str(name);
+        }
+        let reply = self.call(request, 2 + 13 * names.len())?;
+        let mut reply = Reply(&reply);
+        let count = usize::from(reply.u16()?);
+        // A walk that stops short doesn't make the new fid.
+        if count < names.len() {
+            return Err(Errno::ENOENT);
+        }
+        let mut kind = None;
+        for _ in 0..count {
+            kind = Some(reply.qid()?);
+        }
+        Ok(kind)
+    }
+
+    fn lopen(&self, fid: u32, flags: u32) -> Result<(), Errno> {
+        self.call(Message::new(TLOPEN).u32(fid).u32(flags), 17)?;
+        Ok(())
+    }
+
+    /// Creates and opens `name` in the directory at `fid`, which becomes the
+    /// new file's.
+    fn lcreate(&self, fid: u32, name: &str, flags: u32, mode: u32) -> Result<(), Errno> {
+        let request = Message::new(TLCREATE)
+            .u32(fid)
+            .str(name)
+            .u32(flags)
+            .u32(mode)
+            .u32(0);
+        self.call(request, 17)?;
+        Ok(())
+    }
+
+    fn mkdir(&self, fid: u32, name: &str, mode: u32) -> Result<(), Errno> {
+        self.call(Message::new(TMKDIR).u32(fid).str(name).u32(mode).u32(0), 13)?;
+        Ok(())
+    }
+
+    fn size(&self, fid: u32) -> Result<u64, Errno> {
+        let reply = self.call(Message::new(TGETATTR).u32(fid).u64(GETATTR_SIZE), 160)?;
+        let mut reply = Reply(&reply);
+        // Past the valid mask, qid, mode, uid, gid, nlink and rdev.
+        reply.take(8 + 13 + 4 + 4 + 4 + 8 + 8)?;
+        reply.u64()
+    }
+
+    fn set_size(&self, fid: u32, size: u64) -> Result<(), Errno> {
+        let request = Message::new(TSETATTR)
+            .u32(fid)
+            .u32(SETATTR_SIZE)
+            .bytes(&[0; 12])
+            .u64(size)
+            .bytes(&[0; 32]);
+        self.call(request, 0)?;
+        Ok(())
+    }
+
+    fn read(&self, fid: u32, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let count = buf.len().min(self.max_io());
+        let request = Message::new(TREAD).u32(fid).u64(offset).u32(count as u32);
+        let reply = self.call(request, 4 + count)?;
+        let mut reply = Reply(&reply);
+        let len = (reply.u32()? as usize).min(count);
+        buf[..len].copy_from_slice(reply.take(len)?);
+        Ok(len)
+    }
+
+    fn write(&self, fid: u32, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        let buf = &buf[..buf.len().min(self.max_io())];
+        let request = Message::new(TWRITE)
+            .u32(fid)
+            .u64(offset)
+            .u32(buf.len() as u32)
+            .bytes(buf);
+        let reply = self.call(request, 4)?;
+        Ok((Reply(&reply).u32()? as usize).min(buf.len()))
+    }
+
+    /// The entries of the open directory at `fid` from `offset`, as much as
+    /// fits in a message.
+    fn readdir(&self, fid: u32, offset: u64) -> Result<Vec<u8>, Errno> {
+        let count = self.max_io();
+        let request = Message::new(TREADDIR)
+            .u32(fid)
+            .u64(offset)
+            .u32(count as u32);
+        let reply = self.call(request, 4 + count)?;
+        let mut reply = Reply(&reply);
+        let len = reply.u32()? as usize;
+        Ok(reply.take(len)?.to_vec())
+    }
+
+    fn unlinkat(&self, fid: u32, name: &str, flags: u32) -> Result<(), Errno> {
+        self.call(Message::new(TUNLINKAT).u32(fid).str(name).u32(flags), 0)?;
+        Ok(())
+    }
+
+    fn fsync(&self, fid: u32) -> Result<(), Errno> {
+        self.call(Message::new(TFSYNC).u32(fid).u32(0), 0)?;
+        Ok(())
+    }
+
+    fn clunk(&self, fid: u32) {
+        // The fid is forgotten either way.
+        let _ = self.call(Message::new(TCLUNK).u32(fid), 0);
+    }
+}
+
+/// The entries in a `Rreaddir`'s data, and the offset to read on from.
+fn parse_dirents(data: &[u8]) -> Result<(Vec<DirEntry>, Option<u64>), Errno> {
+    let mut reply = Reply(data);
+    let mut entries = Vec::new();
+    let mut next = None;
+    while !reply.0.is_empty() {
+        reply.qid()?;
+        next = Some(reply.u64()?);
+        let kind = reply.u8()?;
+        let name = reply.str()?;
+        if name == "." || name == ".." {
+            continue;
+        }
+        entries.push(DirEntry {
+            name: String::from(name),
+            kind: if kind == DT_DIR {
+                VnodeKind::Directory
+            } else {
+                VnodeKind::File
+            },
+        });
+    }
+    Ok((entries, next))
+}
+
+/// A file or directory on the server.
+struct Node {
+    client: Arc<Client>,
+    fid: u32,
+    dir: bool,
+    /// The fid the file's opened through, once it's read or written.
+    open: Mutex<Option<u32>>,
+}
+
+impl Node {
+    /// The node `names` away.
+    fn walk(&self, names: &[&str]) -> Result<Arc<Node>, Errno> {
+        let fid = self.client.new_fid();
+        let kind = self.client.walk(self.fid, fid, names)?;
+        Ok(Arc::new(Node {
+            client: self.client.clone(),
+            fid,
+            dir: kind.map_or(self.dir, |kind| kind & QTDIR != 0),
+            open: Mutex::new(None),
+        }))
+    }
+
+    /// The fid to read and write through, opening it if need be: read and
+    /// write if the server allows, or only read.
+    fn opened(&self) -> Result<u32, Errno> {
+        let mut open = self.open.lock();
+        if let Some(fid) = *open {
+            return Ok(fid);
+        }
+        let fid = self.client.new_fid();
+        self.client.walk(self.fid, fid, &[])?;
+        let result = if self.dir {
+            self.client.lopen(fid, O_RDONLY | O_DIRECTORY)
+        } else {
+            self.client
+                .lopen(fid, O_RDWR)
+                .or_else(|_| self.client.lopen(fid, O_RDONLY))
+        };
+        if let Err(err) = result {
+            self.client.clunk(fid);
+            return Err(err);
+        }
+        *open = Some(fid);
+        Ok(fid)
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if let Some(fid) = *self.open.lock() {
+            self.client.clunk(fid);
+        }
+        self.client.clunk(self.fid);
+    }
+}
+
+impl Vnode for Node {
+    fn kind(&self) -> VnodeKind {
+        if self.dir {
+            VnodeKind::Directory
+        } else {
+            VnodeKind::File
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.client.size(self.fid).unwrap_or(0)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, Errno> {
+        if !self.dir {
+            return Err(Errno::ENOTDIR);
+        }
+        Ok(self.walk(&[name])?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Errno> {
+        if !self.dir {
+            return Err(Errno::ENOTDIR);
+        }
+        let fid = self.opened()?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (more, next) = parse_dirents(&self.client.readdir(fid, offset)?)?;
+            entries.extend(more);
+            match next {
+                Some(next) => offset = next,
+                None => return Ok(entries),
+            }
+        }
+    }
+
+    fn create(&self, name: &str, kind: VnodeKind) -> Result<Arc<dyn Vnode>, Errno> {
+        if !self.dir {
+            return Err(Errno::ENOTDIR);
+        }
+        match kind {
+            VnodeKind::File => {
+                let fid = self.client.new_fid();
+                self.client.walk(self.fid, fid, &[])?;
+                let flags = O_RDWR | O_CREAT | O_EXCL;
+                if let Err(err) = self.client.lcreate(fid, name, flags, 0o644) {
+                    self.client.clunk(fid);
+                    return Err(err);
+                }
+                let node = self.walk(&[name]);
+                match &node {
+                    Ok(node) => *node.open.lock() = Some(fid),
+                    Err(_) => self.client.clunk(fid),
+                }
+                Ok(node?)
+            }
+            VnodeKind::Directory => {
+                self.client.mkdir(self.fid, name, 0o755)?;
+                Ok(self.walk(&[name])?)
+            }
+            VnodeKind::Device => Err(Errno::EINVAL),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        if self.dir {
+            return Err(Errno::EISDIR);
+        }
+        let fid = self.opened()?;
+        let mut done = 0;
+        while done < buf.len() {
+            match self
+                .client
+                .read(fid, offset + done as u64, &mut buf[done..])?
+            {
+                0 => break,
+                n => done += n,
+            }
+        }
+        Ok(done)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        if self.dir {
+            return Err(Errno::EISDIR);
+        }
+        let fid = self.opened()?;
+        let mut done = 0;
+        while done < buf.len() {
+            match self.client.write(fid, offset + done as u64, &buf[done..])? {
+                0 => return Err(Errno::EIO),
+                n => done += n,
+            }
+        }
+        Ok(done)
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), Errno> {
+        if self.dir {
+            return Err(Errno::EISDIR);
+        }
+        self.client.set_size(self.fid, len)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Errno> {
+        if !self.dir {
+            return Err(Errno::ENOTDIR);
+        }
+        let flags = if self.walk(&[name])?.dir {
+            AT_REMOVEDIR
+        } else {
+            0
+        };
+        self.client.unlinkat(self.fid, name, flags)
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        match *self.open.lock() {
+            Some(fid) => self.client.fsync(fid),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A share, for mounting in the VFS. The connection lasts as long as any
+/// of its nodes.
+pub struct Share {
+    root: Arc<Node>,
+}
+
+impl Share {
+    /// Connects to the share registered as `tag`.
+    pub fn connect(tag: &str) -> Result<Arc<Self>, Errno> {
+        let transport = TRANSPORTS
+            .lock()
+            .iter()
+            .find(|(name, _)| name == tag)
+            .map(|(_, transport)| transport.clone())
+            .ok_or(Errno::ENODEV)?;
+        let client = Client::connect(transport)?;
+        let root = Arc::new(Node {
+            client,
+            fid: ROOT_FID,
+            dir: true,
+            open: Mutex::new(None),
+        });
+        Ok(Arc::new(Self { root }))
+    }
+}
+
+impl FileSystem for Share {
+    fn name(&self) -> &'static str {
+        "9p"
+    }
+
+    fn root(&self) -> Arc<dyn Vnode> {
+        self.root.clone()
+    }
+}
+
+/// Mounts the share named by `9p=` on the command line, if there is one.
+pub fn init() {
+    let Some(value) = cmdline::get("9p") else {
+        return;
+    };
+    let (tag, path) = value.split_once(':').unwrap_or((value, "/mnt"));
+    let result = Share::connect(tag).and_then(|share| super::mount(path, share));
+    match result {
+        Ok(()) => log_info!(target: "9p", "mounted {:?} at {}", tag, path),
+        Err(err) => log_error!(target: "9p", "couldn't mount {:?} at {}: {}", tag, path, err),
+    }
+}
+
+ktest! {
+    fn parses_directory_entries() {
+        let entry = |name: &str, offset: u64, kind: u8| {
+            Message(Vec::new())
+                .bytes(&[0; 13])
+                .u64(offset)
+                .bytes(&[kind])
+                .str(name)
+                .0
+        };
+        let mut data = entry(".", 1, DT_DIR);
+        data.extend(entry("dir", 2, DT_DIR));
+        data.extend(entry("file", 3, 8));
+        let (entries, next) = parse_dirents(&data).unwrap();
+        let names: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.kind))
+            .collect();
+        assert_eq!(
+            names,
+            [("dir", VnodeKind::Directory), ("file", VnodeKind::File)]
+        );
+        assert_eq!(next, Some(3));
+        assert_eq!(parse_dirents(&[]).unwrap().1, None);
+        assert_eq!(parse_dirents(&data[..20]).err(), Some(Errno::EIO));
+    }
+}
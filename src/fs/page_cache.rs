@@ -0,0 +1,94 @@
+//! The page cache: the pages of files mapped with `mmap`, shared by every
+//! mapping of a file. Pages are read from the file as they're faulted in,
+//! and those mapped writable are written back by `msync`, `fsync` and
+//! `sync`, and once the last mapping of the file is gone.
+//!
+//! Reads and writes through file descriptors go to the file rather than
+//! through the cache, so to stay coherent with it they write it back first,
+//! and writes then read the pages they changed back into it.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use super::Vnode;
+use crate::mm::paging::PAGE_SIZE;
+use crate::mm::shm::{Backing, SharedMemory};
+use crate::sync::SpinLock;
+use crate::syscall::Errno;
+
+/// Each file's page cache, by the address of its vnode, which the cache
+/// keeps alive.
+static CACHES: SpinLock<BTreeMap<usize, Weak<SharedMemory>>> = SpinLock::new(BTreeMap::new());
+
+struct FilePages(Arc<dyn Vnode>);
+
+impl Backing for FilePages {
+    fn read_page(&self, index: usize, page: &mut [u8]) -> bool {
+        let offset = (index * PAGE_SIZE) as u64;
+        let mut done = 0;
+        while done < page.len() {
+            match self.0.read_at(offset + done as u64, &mut page[done..]) {
+                Ok(0) => break,
+                Ok(n) => done += n,
+                Err(_) => return false,
+            }
+        }
+        page[done..].fill(0);
+        true
+    }
+
+    fn write_page(&self, index: usize, page: &[u8]) -> bool {
+        let offset = (index * PAGE_SIZE) as u64;
+        let len = self.0.size().saturating_sub(offset).min(page.len() as u64) as usize;
+        let mut done = 0;
+        while done < len {
+            match self.0.write_at(offset + done as u64, &page[done..len]) {
+                Ok(0) | Err(_) => return false,
+                Ok(n) => done += n,
+            }
+        }
+        true
+    }
+}
+
+fn key(node: &Arc<dyn Vnode>) -> usize {
+    Arc::as_ptr(node) as *const () as usize
+}
+
+/// The page cache of `node`, created if it has none.
+pub fn get(node: &Arc<dyn Vnode>) -> Arc<SharedMemory> {
+    let mut caches = CACHES.lock();
+    if let Some(cache) = caches.get(&key(node)).and_then(Weak::upgrade) {
+        return cache;
+    }
+    caches.retain(|_, cache| cache.strong_count() > 0);
+    let cache = SharedMemory::with_backing(FilePages(node.clone()));
+    caches.insert(key(node), Arc::downgrade(&cache));
+    cache
+}
+
+/// The page cache of `node`, if it's mapped.
+pub fn find(node: &Arc<dyn Vnode>) -> Option<Arc<SharedMemory>> {
+    CACHES.lock().get(&key(node)).and_then(Weak::upgrade)
+}
+
+/// Writes the page cache of `node` back to it, if it's mapped.
+pub fn write_back(node: &Arc<dyn Vnode>) -> Result<(), Errno> {
+    match find(node) {
+        Some(cache) if !cache.write_back() => Err(Errno::EIO),
+        _ => Ok(()),
+    }
+}
+
+/// Writes back every file's page cache, for `sync`.
+pub fn sync_all() -> Result<(), Errno> {
+    let caches: Vec<_> = CACHES.lock().values().filter_map(Weak::upgrade).collect();
+    let mut result = Ok(());
+    for cache in caches {
+        if !cache.write_back() {
+            result = Err(Errno::EIO);
+        }
+    }
+    result
+}
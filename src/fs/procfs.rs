@@ -0,0 +1,306 @@
+//! The kernel's view of the machine, mounted at `/proc`, so user space can
+//! see what hardware there is without asking the kernel to print it.
+//!
+//! `/proc/device-tree` is the device tree the kernel was booted with laid
+//! out as on Linux: a directory for each node and a file for each property,
+//! holding its raw value. `/proc/fdt` is the whole blob. Both are of a copy
+//! without the seeds the boot loader may leave in `/chosen` for the kernel's
+//! random numbers, which user space has no business reading. `/proc/cpuinfo`
+//! describes the harts, as `cpuinfo` in the shell does, as of when it's
+//! opened. `/proc/crashdump` is the log the previous boot saved when it
+//! crashed, there only if it did.
+//!
+//! `/proc/self` is the process looking at it, of which only
+//! `oom_score_adj` is there, to read and write.
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{DirEntry, FileSystem, Vnode, VnodeKind};
+use crate::dtb::{AlignedBlob, DeviceTree, DtNode, FdtBuilder};
+use crate::process::Process;
+use crate::syscall::Errno;
+use crate::{cpuinfo, crashdump, ktest, log_error, task};
+
+/// Properties of `/chosen` left out of the tree that's shown.
+const SECRET_PROPERTIES: [&str; 2] = ["kaslr-seed", "rng-seed"];
+
+/// Mounts the filesystem, showing `dt`, which `boot_hart` booted with.
+pub fn init(dt: &DeviceTree<'static>, boot_hart: usize) {
+    let procfs = Procfs {
+        dt: sanitize(dt),
+        boot_hart,
+    };
+    if let Err(err) = super::mount("/proc", Arc::new(procfs)) {
+        log_error!(target: "procfs", "couldn't mount at /proc: {}", err);
+    }
+}
+
+/// Rebuilds `dt` without its `SECRET_PROPERTIES`, in a copy that's kept for
+/// good. If the copy can't be parsed, `dt` is shown as it is.
+fn sanitize(dt: &DeviceTree<'static>) -> DeviceTree<'static> {
+    let mut fdt = FdtBuilder::from_tree(dt);
+    if let Some(chosen) = fdt.node_mut("/chosen") {
+        for name in SECRET_PROPERTIES {
+            chosen.remove_property(name);
+        }
+    }
+    let blob: &'static AlignedBlob = Box::leak(Box::new(fdt.to_aligned_blob()));
+    match blob.parse() {
+        Ok(copy) => copy,
+        Err(err) => {
+            log_error!(target: "procfs", "couldn't rebuild the device tree: {}", err);
+            *dt
+        }
+    }
+}
+
+struct Procfs {
+    dt: DeviceTree<'static>,
+    boot_hart: usize,
+}
+
+impl FileSystem for Procfs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Vnode> {
+        Arc::new(Root {
+            dt: self.dt,
+            boot_hart: self.boot_hart,
+        })
+    }
+}
+
+struct Root {
+    dt: DeviceTree<'static>,
+    boot_hart: usize,
+}
+
+impl Vnode for Root {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, Errno> {
+        match name {
+            "cpuinfo" => {
+                let mut text = String::new();
+                cpuinfo::write(&mut text, &self.dt, self.boot_hart).map_err(|_| Errno::ENOMEM)?;
+                Ok(Arc::new(Bytes(Cow::Owned(text.into_bytes()))))
+            }
+            "crashdump" => {
+                let log = crashdump::previous().ok_or(Errno::ENOENT)?;
+                Ok(Arc::new(Bytes(Cow::Borrowed(log))))
+            }
+            "device-tree" => Ok(Arc::new(NodeDir(self.dt.root_node()))),
+            "fdt" => Ok(Arc::new(Bytes(Cow::Borrowed(self.dt.blob())))),
+            "self" => Ok(Arc::new(SelfDir(task::process().ok_or(Errno::ENOENT)?))),
+            _ => Err(Errno::ENOENT),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Errno> {
+        let entries = [
+            ("cpuinfo", VnodeKind::File),
+            ("crashdump", VnodeKind::File),
+            ("device-tree", VnodeKind::Directory),
+            ("fdt", VnodeKind::File),
+            ("self", VnodeKind::Directory),
+        ];
+        Ok(entries
+            .into_iter()
+            .filter(|&(name, _)| name != "crashdump" || crashdump::previous().is_some())
+            .map(|(name, kind)| DirEntry {
+                name: name.to_string(),
+                kind,
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _kind: VnodeKind) -> Result<Arc<dyn Vnode>, Errno> {
+        Err(Errno::EROFS)
+    }
+}
+
+/// A device tree node, holding its properties and then its children. Names
+/// are matched in full, unit address and all.
+struct NodeDir(DtNode<'static>);
+
+impl Vnode for NodeDir {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, Errno> {
+        if let Some(prop) = self.0.property(name) {
+            return Ok(Arc::new(Bytes(Cow::Borrowed(prop.value))));
+        }
+        match self.0.children().find(|child| child.name == name) {
+            Some(child) => Ok(Arc::new(NodeDir(child))),
+            None => Err(Errno::ENOENT),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Errno> {
+        let properties = self.0.properties().map(|prop| DirEntry {
+            name: prop.name.to_string(),
+            kind: VnodeKind::File,
+        });
+        let children = self.0.children().map(|child| DirEntry {
+            name: child.name.to_string(),
+            kind: VnodeKind::Directory,
+        });
+        Ok(properties.chain(children).collect())
+    }
+
+    fn create(&self, _name: &str, _kind: VnodeKind) -> Result<Arc<dyn Vnode>, Errno> {
+        Err(Errno::EROFS)
+    }
+}
+
+/// A read-only file of bytes, from the blob or generated when it's looked
+/// up.
+struct Bytes(Cow<'static, [u8]>);
+
+impl Vnode for Bytes {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::File
+    }
+
+    fn size(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let start = self
+            .0
+            .len()
+            .min(usize::try_from(offset).unwrap_or(usize::MAX));
+        let len = buf.len().min(self.0.len() - start);
+        buf[..len].copy_from_slice(&self.0[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    fn truncate(&self, _len: u64) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+}
+
+/// `/proc/self`, for `process`.
+struct SelfDir(Arc<Process>);
+
+impl Vnode for SelfDir {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, Errno> {
+        match name {
+            "oom_score_adj" => Ok(Arc::new(OomScoreAdj(self.0.clone()))),
+            _ => Err(Errno::ENOENT),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Errno> {
+        Ok(alloc::vec![DirEntry {
+            name: "oom_score_adj".to_string(),
+            kind: VnodeKind::File,
+        }])
+    }
+
+    fn create(&self, _name: &str, _kind: VnodeKind) -> Result<Arc<dyn Vnode>, Errno> {
+        Err(Errno::EROFS)
+    }
+}
+
+/// A process's `oom_score_adj` as a line of text. Writing a number sets it.
+struct OomScoreAdj(Arc<Process>);
+
+impl OomScoreAdj {
+    fn text(&self) -> String {
+        alloc::format!("{}\n", self.0.oom_score_adj())
+    }
+}
+
+impl Vnode for OomScoreAdj {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::File
+    }
+
+    fn size(&self) -> u64 {
+        self.text().len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        Bytes(Cow::Owned(self.text().into_bytes())).read_at(offset, buf)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        let adj = core::str::from_utf8(buf)
+            .ok()
+            .and_then(|text| text.trim().parse().ok())
+            .ok_or(Errno::EINVAL)?;
+        self.0.set_oom_score_adj(adj)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, _len: u64) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+ktest! {
+    fn lays_out_device_tree() {
+        use crate::dtb::{FdtBuilder, FdtNode};
+
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.root_mut();
+        root.set_str("model", "board");
+        let uart = root.add_child(FdtNode::new("uart@10000000"));
+        uart.set_str("compatible", "ns16550a");
+        uart.set_u32("reg-shift", 2);
+        root.add_child(FdtNode::new("chosen")).set_str("bootargs", "quiet");
+
+        // What's shown is the tree without the seed.
+        let blob = fdt.to_blob();
+        let chosen = fdt.node_mut("/chosen").unwrap();
+        chosen.set_u64("rng-seed", 0x1234_5678_9abc_def0);
+        // `Root` keeps the tree, so it's never freed.
+        let words = Box::leak(Box::new(fdt.to_aligned_blob()));
+        let dt = sanitize(&words.parse().unwrap());
+        let root = Root { dt, boot_hart: 0 };
+
+        let fdt = root.lookup("fdt").unwrap();
+        assert_eq!(fdt.size(), blob.len() as u64);
+        let mut buf = alloc::vec![0; blob.len() + 8];
+        assert_eq!(fdt.read_at(0, &mut buf), Ok(blob.len()));
+        assert_eq!(&buf[..blob.len()], &blob[..]);
+
+        let tree = root.lookup("device-tree").unwrap();
+        let names: Vec<_> = tree.read_dir().unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["model", "uart@10000000", "chosen"]);
+        let chosen = tree.lookup("chosen").unwrap();
+        assert!(matches!(chosen.lookup("rng-seed"), Err(Errno::ENOENT)));
+        assert!(chosen.lookup("bootargs").is_ok());
+        let uart = tree.lookup("uart@10000000").unwrap();
+        assert_eq!(uart.kind(), VnodeKind::Directory);
+        assert_eq!(uart.lookup("reg-shift").unwrap().read_at(0, &mut buf), Ok(4));
+        assert_eq!(&buf[..4], &[0, 0, 0, 2]);
+        let compatible = uart.lookup("compatible").unwrap();
+        assert_eq!(compatible.read_at(3, &mut buf), Ok(6));
+        assert_eq!(&buf[..6], b"6550a\0");
+        assert_eq!(compatible.read_at(100, &mut buf), Ok(0));
+        // Names are matched in full.
+        assert!(matches!(tree.lookup("uart"), Err(Errno::ENOENT)));
+        assert!(matches!(compatible.write_at(0, b"x"), Err(Errno::EROFS)));
+    }
+}
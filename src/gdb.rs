@@ -0,0 +1,693 @@
+//! A GDB stub, speaking the remote serial protocol over a UART so the kernel
+//! can be debugged without QEMU's gdbserver. `gdb=<path>` on the command
+//! line names the UART to use, or `gdb=console` shares the console's, in
+//! which case the console's output gets mixed in with the packets while the
+//! kernel runs. `gdbwait` stops the kernel as soon as the stub is set up, so
+//! GDB can attach before anything else happens; otherwise GDB's Ctrl-C or
+//! the shell's `gdb` command stops it.
+//!
+//! The stub runs in the trap handler of the hart that stopped, with the
+//! other harts paused, and shows GDB that hart as the only thread. It
+//! handles registers, memory, software breakpoints and single-stepping.
+//! RISC-V has no way to single-step S-mode, so a step puts a breakpoint on
+//! whichever instruction is executed next, worked out by decoding the one
+//! at `pc`.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::csr::{Bits, Sstatus};
+use crate::arch::insn::{self, C_EBREAK, EBREAK};
+use crate::arch::REGBYTES;
+use crate::drivers::ns16550::{self, Uart};
+use crate::drivers::plic;
+use crate::dtb::DeviceTree;
+use crate::mm::{paging, phys_to_virt};
+use crate::sync::SpinLockIrqSave;
+use crate::trap::TrapFrame;
+use crate::{cmdline, io, ktest, log_info, log_warn, smp};
+
+/// The most bytes in a packet, which GDB is told in `qSupported`.
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+
+/// Signals reported when stopping.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The `pc` in GDB's numbering of the registers, after `x0` to `x31`.
+const REG_PC: usize = 32;
+
+static STUB: SpinLockIrqSave<Option<Stub>> = SpinLockIrqSave::new(None);
+
+/// Set by the UART's interrupt handler when GDB sends Ctrl-C.
+static BREAK_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Set if the stub shares the console's UART.
+static ON_CONSOLE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: usize,
+    /// The length of the instruction replaced, 2 or 4.
+    len: usize,
+    /// The instruction replaced.
+    saved: u32,
+}
+
+/// What to do when a breakpoint the stub put in to step is hit.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Step {
+    /// Stop, as GDB asked for a step.
+    Stop,
+    /// Put the breakpoints back and carry on, as the step was only to get
+    /// past a breakpoint GDB continued from.
+    Continue,
+}
+
+struct Stub {
+    uart: Uart,
+    /// The breakpoints GDB has set, in place while the kernel runs.
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    inserted: bool,
+    /// The breakpoint on the next instruction, while stepping.
+    step: Option<(Breakpoint, Step)>,
+    /// Set if `pc` was left at an `ebreak` in the kernel itself, which is
+    /// skipped when resuming from there.
+    skip_ebreak: Option<usize>,
+    /// The packet being handled, and the reply to it.
+    buf: [u8; PACKET_SIZE],
+    out: [u8; PACKET_SIZE],
+}
+
+/// Sets the stub up on the UART named by `gdb=`, if there is one.
+pub fn init(dt: &DeviceTree<'_>) {
+    let Some(path) = cmdline::get("gdb") else {
+        return;
+    };
+    let uart = if path == "console" {
+        let Some(uart) = io::console_uart() else {
+            log_warn!("the console isn't a UART, not starting the GDB stub");
+            return;
+        };
+        ON_CONSOLE.store(true, Ordering::Relaxed);
+        uart
+    } else {
+        let Some(node) = dt.find_node(path) else {
+            log_warn!("{} not found", path);
+            return;
+        };
+        if !ns16550::COMPATIBLE.iter().any(|c| node.is_compatible(c)) {
+            log_warn!("{} isn't a UART we have a driver for", path);
+            return;
+        }
+        let Some(uart) = Uart::init(dt, &node, None) else {
+            log_warn!("failed to set up {}", node.name);
+            return;
+        };
+        let irq = dt.interrupts(&node).next().and_then(|irq| irq.cell(0));
+        if irq.is_some_and(|irq| plic::register(irq, uart_interrupt)) {
+            uart.enable_rx_interrupt();
+        } else {
+            log_warn!(
+                "{} has no interrupt, Ctrl-C won't stop the kernel",
+                node.name
+            );
+        }
+        uart
+    };
+    *STUB.lock() = Some(Stub {
+        uart,
+        breakpoints: [None; MAX_BREAKPOINTS],
+        inserted: false,
+        step: None,
+        skip_ebreak: None,
+        buf: [0; PACKET_SIZE],
+        out: [0; PACKET_SIZE],
+    });
+    log_info!("GDB stub on {}", path);
+
+    if cmdline::has("gdbwait") {
+        log_info!("waiting for GDB");
+        breakpoint();
+    }
+}
+
+/// Stops in the debugger, if there is one.
+pub fn breakpoint() {
+    // SAFETY: the trap handler steps over breakpoints no debugger handles.
+    unsafe { asm!("ebreak") };
+}
+
+fn uart_interrupt(_irq: u32) {
+    let Some(uart) = STUB.lock().as_ref().map(|stub| stub.uart) else {
+        return;
+    };
+    while let Some(byte) = uart.read_byte() {
+        if byte == 0x03 {
+            request_break();
+        }
+    }
+}
+
+/// Whether the stub shares the console's UART, in which case the console
+/// hands it Ctrl-C with `request_break`.
+pub fn is_on_console() -> bool {
+    ON_CONSOLE.load(Ordering::Relaxed)
+}
+
+/// Stops the kernel at the next interrupt taken in S-mode.
+pub fn request_break() {
+    BREAK_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Called by the trap handler on a breakpoint in S-mode. Returns false if
+/// there's no stub, leaving the breakpoint to the trap handler.
+pub fn handle_breakpoint(frame: &mut TrapFrame) -> bool {
+    let mut stub = STUB.lock();
+    let Some(stub) = stub.as_mut() else {
+        return false;
+    };
+    stub.stop(frame, SIGTRAP);
+    true
+}
+
+/// Called by the trap handler after an interrupt taken in S-mode, to stop
+/// there if GDB asked to.
+pub fn handle_interrupt(frame: &mut TrapFrame) {
+    if BREAK_REQUESTED.swap(false, Ordering::Relaxed) {
+        if let Some(stub) = STUB.lock().as_mut() {
+            stub.stop(frame, SIGINT);
+        }
+    }
+}
+
+/// Where the kernel's `addr` can be written, through the physical memory
+/// mapping, as its own mapping may be read-only.
+fn writable(addr: usize) -> Option<*mut u8> {
+    paging::translate(addr).map(|paddr| phys_to_virt(paddr) as *mut u8)
+}
+
+fn read_byte(addr: usize) -> Option<u8> {
+    // SAFETY: the address is mapped, and GDB asked for whatever is there.
+    writable(addr).map(|ptr| unsafe { ptr.read_volatile() })
+}
+
+fn write_byte(addr: usize, byte: u8) -> Option<()> {
+    // SAFETY: as above, GDB asked for it.
+    writable(addr).map(|ptr| unsafe { ptr.write_volatile(byte) })
+}
+
+fn read_bytes<const N: usize>(addr: usize) -> Option<[u8; N]> {
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = read_byte(addr + i)?;
+    }
+    Some(bytes)
+}
+
+fn write_bytes(addr: usize, bytes: &[u8]) -> Option<()> {
+    for (i, &byte) in bytes.iter().enumerate() {
+        write_byte(addr + i, byte)?;
+    }
+    // SAFETY: a fence has no other effects.
+    unsafe { asm!("fence.i") };
+    Some(())
+}
+
+/// The instruction at `addr`, and its length.
+fn instruction(addr: usize) -> Option<(u32, usize)> {
+    let low = u16::from_le_bytes(read_bytes(addr)?);
+    match insn::len(low) {
+        2 => Some((low.into(), 2)),
+        len => Some((u32::from_le_bytes(read_bytes(addr)?), len)),
+    }
+}
+
+/// The address of the instruction executed after the one at `pc`.
+fn next_pc(frame: &TrapFrame, pc: usize) -> Option<usize> {
+    let (inst, len) = instruction(pc)?;
+    Some(insn::next_pc(&frame.regs, pc, inst, len))
+}
+
+fn is_ebreak(addr: usize) -> bool {
+    instruction(addr).is_some_and(|inst| inst == (EBREAK, 4) || inst == (C_EBREAK.into(), 2))
+}
+
+impl Breakpoint {
+    fn insert(addr: usize, len: usize) -> Option<Self> {
+        let saved = match len {
+            2 => {
+                let saved = u16::from_le_bytes(read_bytes(addr)?);
+                write_bytes(addr, &C_EBREAK.to_le_bytes())?;
+                saved.into()
+            }
+            _ => {
+                let saved = u32::from_le_bytes(read_bytes(addr)?);
+                write_bytes(addr, &EBREAK.to_le_bytes())?;
+                saved
+            }
+        };
+        Some(Self { addr, len, saved })
+    }
+
+    fn remove(&self) {
+        let _ = write_bytes(self.addr, &self.saved.to_le_bytes()[..self.len]);
+    }
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parses a hex number, as GDB sends addresses and lengths.
+fn parse_hex(bytes: &[u8]) -> Option<usize> {
+    if bytes.is_empty() {
+        return None;
+    }
+    bytes.iter().try_fold(0usize, |acc, &byte| {
+        acc.checked_mul(16)?.checked_add(hex_digit(byte)?.into())
+    })
+}
+
+/// Decodes hex pairs into bytes, in place. A digit left over is an error.
+fn decode_hex(bytes: &mut [u8]) -> Option<&[u8]> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let len = bytes.len() / 2;
+    for i in 0..len {
+        bytes[i] = hex_digit(bytes[2 * i])? << 4 | hex_digit(bytes[2 * i + 1])?;
+    }
+    Some(&bytes[..len])
+}
+
+/// A reply being built, to be sent as a packet.
+struct Reply<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Reply<'_> {
+    fn push(&mut self, bytes: &[u8]) {
+        let end = (self.len + bytes.len()).min(self.buf.len());
+        self.buf[self.len..end].copy_from_slice(&bytes[..end - self.len]);
+        self.len = end;
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        for &byte in bytes {
+            self.push(&[
+                DIGITS[usize::from(byte >> 4)],
+                DIGITS[usize::from(byte & 0xf)],
+            ]);
+        }
+    }
+}
+
+fn getc(uart: &Uart) -> u8 {
+    loop {
+        if let Some(byte) = uart.read_byte() {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Receives the next packet into `buf`, acknowledging it, and returns its
+/// length.
+fn recv_packet(uart: &Uart, buf: &mut [u8; PACKET_SIZE]) -> usize {
+    loop {
+        while getc(uart) != b'$' {}
+        let mut len = 0;
+        let mut sum = 0u8;
+        loop {
+            let byte = getc(uart);
+            if byte == b'#' {
+                break;
+            }
+            if len < PACKET_SIZE {
+                buf[len] = byte;
+                len += 1;
+            }
+            sum = sum.wrapping_add(byte);
+        }
+        let check = hex_digit(getc(uart)).zip(hex_digit(getc(uart)));
+        if check == Some((sum >> 4, sum & 0xf)) && len < PACKET_SIZE {
+            uart.write_byte(b'+');
+            return len;
+        }
+        uart.write_byte(b'-');
+    }
+}
+
+/// Sends `data` as a packet until GDB acknowledges it.
+fn send_packet(uart: &Uart, data: &[u8]) {
+    let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    let mut check = [0; 2];
+    Reply {
+        buf: &mut check,
+        len: 0,
+    }
+    .push_hex(&[sum]);
+    loop {
+        uart.write_byte(b'$');
+        for &byte in data {
+            uart.write_byte(byte);
+        }
+        uart.write_byte(b'#');
+        check.iter().for_each(|&byte| uart.write_byte(byte));
+        match getc(uart) {
+            b'+' => return,
+            // Ctrl-C, or the start of a packet: GDB has given up on this one.
+            0x03 | b'$' => return,
+            _ => {}
+        }
+    }
+}
+
+impl Stub {
+    fn insert_breakpoints(&mut self) {
+        for bp in self.breakpoints.iter_mut().flatten() {
+            if let Some(inserted) = Breakpoint::insert(bp.addr, bp.len) {
+                *bp = inserted;
+            }
+        }
+        self.inserted = true;
+    }
+
+    fn remove_breakpoints(&mut self) {
+        if self.inserted {
+            for bp in self.breakpoints.iter().rev().flatten() {
+                bp.remove();
+            }
+            self.inserted = false;
+        }
+    }
+
+    /// Resumes from `frame`, stepping one instruction if `step` says so.
+    /// Returns false if there's nowhere to resume to, having moved `pc` on
+    /// or not.
+    fn resume(&mut self, frame: &mut TrapFrame, step: Option<Step>) -> bool {
+        if self.skip_ebreak.take() == Some(frame.sepc) {
+            frame.sepc += instruction(frame.sepc).map_or(4, |(_, len)| len);
+            if step == Some(Step::Stop) {
+                return false;
+            }
+        }
+        let on_breakpoint = self
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|bp| bp.addr == frame.sepc);
+        let step = match step {
+            None if on_breakpoint => Some(Step::Continue),
+            step => step,
+        };
+        let Some(then) = step else {
+            self.insert_breakpoints();
+            return true;
+        };
+        let Some(next) = next_pc(frame, frame.sepc) else {
+            return false;
+        };
+        let len = instruction(next).map_or(4, |(_, len)| len);
+        let Some(bp) = Breakpoint::insert(next, len) else {
+            return false;
+        };
+        self.step = Some((bp, then));
+        // Stepping into an interrupt handler isn't what GDB wants.
+        if then == Step::Stop {
+            frame.sstatus &= !Sstatus::SPIE.bits();
+        }
+        true
+    }
+
+    /// Stops the kernel at `frame`, having got `signal`, and talks to GDB
+    /// until it resumes.
+    fn stop(&mut self, frame: &mut TrapFrame, signal: u8) {
+        match self.step.take() {
+            Some((bp, then)) if bp.addr == frame.sepc => {
+                bp.remove();
+                if then == Step::Continue {
+                    self.insert_breakpoints();
+                    return;
+                }
+                frame.sstatus |= Sstatus::SPIE.bits();
+            }
+            Some((bp, _)) => bp.remove(),
+            None => {}
+        }
+        self.remove_breakpoints();
+        if signal == SIGTRAP && is_ebreak(frame.sepc) {
+            self.skip_ebreak = Some(frame.sepc);
+        }
+
+        smp::pause_others();
+        let uart = self.uart;
+        let mut stop_reply = [b'S', 0, 0];
+        Reply {
+            buf: &mut stop_reply[1..],
+            len: 0,
+        }
+        .push_hex(&[signal]);
+        send_packet(&uart, &stop_reply);
+        loop {
+            let len = recv_packet(&uart, &mut self.buf);
+            let resume = match self.buf[0] {
+                b'c' => Some(None),
+                b's' => Some(Some(Step::Stop)),
+                b'D' => {
+                    self.breakpoints = [None; MAX_BREAKPOINTS];
+                    send_packet(&uart, b"OK");
+                    Some(None)
+                }
+                b'k' => {
+                    self.breakpoints = [None; MAX_BREAKPOINTS];
+                    Some(None)
+                }
+                _ => None,
+            };
+            let Some(step) = resume else {
+                let mut reply = Reply {
+                    buf: &mut self.out,
+                    len: 0,
+                };
+                handle(
+                    &mut self.breakpoints,
+                    frame,
+                    &mut self.buf[..len],
+                    &mut reply,
+                );
+                let len = reply.len;
+                send_packet(&uart, &self.out[..len]);
+                continue;
+            };
+            if matches!(self.buf[0], b'c' | b's') {
+                if let Some(addr) = parse_hex(&self.buf[1..len]) {
+                    frame.sepc = addr;
+                }
+            }
+            if self.resume(frame, step) {
+                break;
+            }
+            send_packet(&uart, b"S05");
+        }
+        smp::resume_others();
+    }
+}
+
+/// Handles a packet that doesn't resume the kernel, from a stop at `frame`.
+fn handle(
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+    frame: &mut TrapFrame,
+    packet: &mut [u8],
+    reply: &mut Reply<'_>,
+) {
+    let (command, args) = packet.split_at_mut(1);
+    match command[0] {
+        b'?' => reply.push(b"S05"),
+        b'g' => {
+            for n in 0..REG_PC {
+                reply.push_hex(&insn::reg(&frame.regs, n as u32).to_le_bytes());
+            }
+            reply.push_hex(&frame.sepc.to_le_bytes());
+        }
+        b'G' => match decode_hex(args) {
+            Some(values) if values.len() >= REGBYTES * (REG_PC + 1) => {
+                let mut values = values
+                    .chunks_exact(REGBYTES)
+                    .map(|value| usize::from_le_bytes(value.try_into().unwrap()));
+                values.next();
+                for reg in &mut frame.regs[1..] {
+                    *reg = values.next().unwrap();
+                }
+                frame.sepc = values.next().unwrap();
+                reply.push(b"OK");
+            }
+            _ => reply.push(b"E01"),
+        },
+        b'p' => match parse_hex(args) {
+            Some(n) if n < REG_PC => {
+                reply.push_hex(&insn::reg(&frame.regs, n as u32).to_le_bytes())
+            }
+            Some(REG_PC) => reply.push_hex(&frame.sepc.to_le_bytes()),
+            _ => reply.push(b"E01"),
+        },
+        b'P' => {
+            let Some(eq) = args.iter().position(|&byte| byte == b'=') else {
+                reply.push(b"E01");
+                return;
+            };
+            let (n, value) = args.split_at_mut(eq);
+            let value = decode_hex(&mut value[1..])
+                .and_then(|value| value.try_into().ok())
+                .map(usize::from_le_bytes);
+            match (parse_hex(n), value) {
+                (Some(0), Some(_)) => {}
+                (Some(n), Some(value)) if n < REG_PC => frame.regs[n] = value,
+                (Some(REG_PC), Some(value)) => frame.sepc = value,
+                _ => return reply.push(b"E01"),
+            }
+            reply.push(b"OK");
+        }
+        b'm' => {
+            let mut args = args.split(|&byte| byte == b',');
+            let addr = args.next().and_then(parse_hex);
+            let len = args.next().and_then(parse_hex);
+            let (Some(addr), Some(len)) = (addr, len) else {
+                return reply.push(b"E01");
+            };
+            for i in 0..len.min(PACKET_SIZE / 2) {
+                match read_byte(addr.wrapping_add(i)) {
+                    Some(byte) => reply.push_hex(&[byte]),
+                    None if i == 0 => return reply.push(b"E14"),
+                    None => break,
+                }
+            }
+        }
+        b'M' => {
+            let Some(colon) = args.iter().position(|&byte| byte == b':') else {
+                return reply.push(b"E01");
+            };
+            let (target, data) = args.split_at_mut(colon);
+            let addr = target
+                .split(|&byte| byte == b',')
+                .next()
+                .and_then(parse_hex);
+            match (addr, decode_hex(&mut data[1..])) {
+                (Some(addr), Some(data)) => match write_bytes(addr, data) {
+                    Some(()) => reply.push(b"OK"),
+                    None => reply.push(b"E14"),
+                },
+                _ => reply.push(b"E01"),
+            }
+        }
+        b'Z' | b'z' => {
+            let mut fields = args.split(|&byte| byte == b',');
+            let kind = fields.next();
+            let addr = fields.next().and_then(parse_hex);
+            let len = fields.next().and_then(parse_hex);
+            // Only software breakpoints; an empty reply says so for the rest.
+            let (Some(b"0"), Some(addr), Some(len @ (2 | 4))) = (kind, addr, len) else {
+                return;
+            };
+            let existing = breakpoints
+                .iter()
+                .position(|bp| bp.is_some_and(|bp| bp.addr == addr));
+            if command[0] == b'z' {
+                if let Some(slot) = existing {
+                    breakpoints[slot] = None;
+                }
+                return reply.push(b"OK");
+            }
+            let free = breakpoints.iter().position(Option::is_none);
+            match existing.or(free) {
+                Some(slot) if read_byte(addr).is_some() => {
+                    // The instruction is saved when it's replaced.
+                    breakpoints[slot] = Some(Breakpoint {
+                        addr,
+                        len,
+                        saved: 0,
+                    });
+                    reply.push(b"OK");
+                }
+                _ => reply.push(b"E0e"),
+            }
+        }
+        b'H' | b'T' => reply.push(b"OK"),
+        b'q' => {
+            if args.starts_with(b"Supported") {
+                reply.push(b"PacketSize=1000");
+            } else if args == b"Attached" {
+                reply.push(b"1");
+            } else if args == b"C" {
+                reply.push(b"QC1");
+            } else if args == b"fThreadInfo" {
+                reply.push(b"m1");
+            } else if args == b"sThreadInfo" {
+                reply.push(b"l");
+            }
+        }
+        // Anything else is unsupported, which an empty reply says.
+        _ => {}
+    }
+}
+
+ktest! {
+    fn handles_packets() {
+        use alloc::format;
+        use alloc::string::String;
+        use alloc::vec::Vec;
+
+        fn run(frame: &mut TrapFrame, packet: &str) -> Vec<u8> {
+            let mut breakpoints = [None; MAX_BREAKPOINTS];
+            let mut packet = Vec::from(packet.as_bytes());
+            let mut out = [0; 256];
+            let mut reply = Reply { buf: &mut out, len: 0 };
+            handle(&mut breakpoints, frame, &mut packet, &mut reply);
+            let len = reply.len;
+            out[..len].to_vec()
+        }
+        // `value` as a register, in hex.
+        let hex = |value: usize| {
+            let mut out = [0; 2 * REGBYTES];
+            let mut reply = Reply { buf: &mut out, len: 0 };
+            reply.push_hex(&value.to_le_bytes());
+            String::from_utf8(out.to_vec()).unwrap()
+        };
+
+        let mut frame = TrapFrame {
+            regs: [0; 32],
+            sstatus: 0,
+            sepc: 0x8020_0000,
+            scause: 0,
+            stval: 0,
+        };
+        frame.regs[10] = 0x1234;
+        assert_eq!(run(&mut frame, "pa"), hex(0x1234).as_bytes());
+        assert_eq!(run(&mut frame, "p20"), hex(0x8020_0000).as_bytes());
+        assert_eq!(run(&mut frame, &format!("Pb={}", hex(7))), b"OK");
+        assert_eq!(frame.regs[11], 7);
+        // x0 stays zero.
+        assert_eq!(run(&mut frame, &format!("P0={}", hex(7))), b"OK");
+        assert_eq!(frame.regs[0], 0);
+        assert_eq!(run(&mut frame, &format!("Pb={}0", hex(8))), b"E01");
+        assert_eq!(frame.regs[11], 7);
+        assert_eq!(run(&mut frame, "p21"), b"E01");
+
+        let data = [0xde_u8, 0xad];
+        assert_eq!(run(&mut frame, &format!("m{:x},2", data.as_ptr() as usize)), b"dead");
+        assert_eq!(run(&mut frame, "m0,4"), b"E14");
+        // Only software breakpoints on whole instructions.
+        assert_eq!(run(&mut frame, "Z1,80200000,4"), b"");
+        assert_eq!(run(&mut frame, "Z0,80200000,3"), b"");
+        assert_eq!(run(&mut frame, "qC"), b"QC1");
+        assert_eq!(run(&mut frame, "vMustReplyEmpty"), b"");
+    }
+}
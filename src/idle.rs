@@ -0,0 +1,104 @@
+//! What a hart does when it has nothing to run. It waits for an interrupt,
+//! and if the device tree lists idle states under `/cpus/idle-states` it
+//! asks SBI to suspend it in the deepest one it'll be idle long enough for,
+//! which may let the platform save more power than `wfi`. Only retentive
+//! states are used, which resume like `wfi` returns. `idle=wfi` on the
+//! command line sticks to `wfi`.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::cmp::Reverse;
+
+use crate::dtb::DeviceTree;
+use crate::sbi::hsm;
+use crate::sync::Once;
+use crate::time::{self, read_time};
+use crate::{cmdline, ktest, log_debug, log_info, sbi};
+
+/// Suspend types with this bit set don't keep the hart's state.
+const NON_RETENTIVE: u32 = 1 << 31;
+
+struct IdleState {
+    name: &'static str,
+    /// The suspend type given to SBI.
+    param: u32,
+    /// How long the hart has to be idle for the state to be worthwhile, in
+    /// ticks of the `time` CSR.
+    min_residency: u64,
+}
+
+/// The usable idle states, deepest first.
+static STATES: Once<Vec<IdleState>> = Once::new();
+
+/// Reads the idle states from the device tree. Needs the timebase
+/// frequency, from `time::init`.
+pub fn init(dt: &DeviceTree<'static>) {
+    if cmdline::get("idle") == Some("wfi") || !sbi::probe_extension(hsm::SBI_EID_HSM) {
+        return;
+    }
+    let mut states = Vec::new();
+    let nodes = dt
+        .find_node("/cpus/idle-states")
+        .into_iter()
+        .flat_map(|node| node.children())
+        .filter(|node| node.is_compatible("riscv,idle-state"));
+    for node in nodes {
+        let Some(param) = node.prop_u32("riscv,sbi-suspend-param") else {
+            continue;
+        };
+        if param & NON_RETENTIVE != 0 {
+            log_debug!("{}: non-retentive, not used", node.name);
+            continue;
+        }
+        let us: u64 = ["min-residency-us", "entry-latency-us", "exit-latency-us"]
+            .iter()
+            .map(|prop| u64::from(node.prop_u32(prop).unwrap_or(0)))
+            .sum();
+        states.push(IdleState {
+            name: node.name,
+            param,
+            min_residency: us.saturating_mul(time::frequency()) / 1_000_000,
+        });
+    }
+    states.sort_by_key(|state| Reverse(state.min_residency));
+    for state in &states {
+        log_info!("idle state {}: suspend type {:#x}", state.name, state.param);
+    }
+    STATES.call_once(|| states);
+}
+
+/// The deepest of `states`, deepest first, worth entering for `idle_for`
+/// ticks.
+fn deepest(states: &[IdleState], idle_for: u64) -> Option<&IdleState> {
+    states.iter().find(|state| state.min_residency <= idle_for)
+}
+
+fn wfi() {
+    // SAFETY: waiting for an interrupt has no effect on memory. It may return
+    // early, which is fine since callers loop.
+    unsafe { asm!("wfi") };
+}
+
+/// Waits for an interrupt, in the deepest idle state worth entering before
+/// the `time` CSR reaches `until`. Interrupts must be enabled in `sie`, as
+/// they're what wakes the hart.
+pub fn wait_until(until: u64) {
+    let idle_for = until.saturating_sub(read_time());
+    let state = STATES.get().and_then(|states| deepest(states, idle_for));
+    match state {
+        Some(state) if hsm::hart_suspend_retentive(state.param).is_ok() => {}
+        _ => wfi(),
+    }
+}
+
+ktest! {
+    fn picks_deepest_worthwhile_state() {
+        let state = |name, min_residency| IdleState { name, param: 0, min_residency };
+        let states = [state("deep", 1000), state("light", 10)];
+        let name = |idle_for| deepest(&states, idle_for).map(|state| state.name);
+        assert_eq!(name(5000), Some("deep"));
+        assert_eq!(name(1000), Some("deep"));
+        assert_eq!(name(999), Some("light"));
+        assert_eq!(name(9), None);
+    }
+}
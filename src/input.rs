@@ -0,0 +1,414 @@
+//! Input devices, keyboards and pointing devices like mice and tablets,
+//! whose drivers report events with Linux's evdev types and codes. Each
+//! device is `/dev/input/event<n>`, and each opening of it queues the
+//! events reported from then on, read as `struct input_event`s, for
+//! programs that draw their own pointer or want keys as they're pressed
+//! and released. A reader that falls `QUEUE_SIZE` events behind loses the
+//! oldest.
+//!
+//! Key presses are also turned into console input with a US layout, giving
+//! what a serial terminal would send for them: Enter is a carriage return,
+//! Backspace is DEL, and the arrow keys, Home, End, Insert, Delete, Page Up
+//! and Page Down are ANSI escape sequences. Ctrl with a letter gives its
+//! control character; other keys are the same with or without it. The
+//! modifiers are shared by every keyboard.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use crate::file::File;
+use crate::fs::devfs;
+use crate::fs::{DirEntry, Vnode, VnodeKind};
+use crate::mm::uaccess::{copy_to_user, write_user_struct, Plain};
+use crate::sync::{SpinLock, SpinLockIrqSave, WaitQueue};
+use crate::syscall::Errno;
+use crate::{io, ktest, log_error, task, time};
+
+/// Event types.
+pub const EV_KEY: u16 = 1;
+pub const EV_REL: u16 = 2;
+pub const EV_ABS: u16 = 3;
+
+/// How many events each reader keeps.
+const QUEUE_SIZE: usize = 256;
+
+/// `ioctl` requests, in the `'E'` group. `EVIOCGNAME` has the buffer's
+/// length in bits 16 to 29, and `EVIOCGABS` the axis in its low bits.
+const EVIOCGVERSION: usize = 0x80044501;
+const EVIOCGNAME: usize = 0x80004506;
+const EVIOCGABS: usize = 0x80184540;
+const IOC_SIZE_MASK: usize = 0x3fff << 16;
+
+/// The evdev protocol version `EVIOCGVERSION` gives.
+const EV_VERSION: i32 = 0x010001;
+
+/// A value of a key event: released, pressed, or held down long enough to
+/// repeat.
+const KEY_RELEASED: i32 = 0;
+
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_KPENTER: u16 = 96;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_HOME: u16 = 102;
+const KEY_UP: u16 = 103;
+const KEY_PAGEUP: u16 = 104;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_END: u16 = 107;
+const KEY_DOWN: u16 = 108;
+const KEY_PAGEDOWN: u16 = 109;
+const KEY_INSERT: u16 = 110;
+const KEY_DELETE: u16 = 111;
+
+/// What each key up to the space bar types, by code, unshifted and
+/// shifted. Zero is nothing.
+const KEYMAP: &[u8; 58] =
+    b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\r\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const KEYMAP_SHIFT: &[u8; 58] =
+    b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\r\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+static DEVICES: SpinLock<Vec<Arc<InputDevice>>> = SpinLock::new(Vec::new());
+
+static MODIFIERS: SpinLockIrqSave<Modifiers> = SpinLockIrqSave::new(Modifiers {
+    shift: [false; 2],
+    ctrl: [false; 2],
+    caps_lock: false,
+});
+
+/// The range of an absolute axis, as `struct input_absinfo`.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct AbsInfo {
+    pub value: i32,
+    pub min: i32,
+    pub max: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
+
+// SAFETY: `AbsInfo` is all `i32`s, so has no padding.
+unsafe impl Plain for AbsInfo {}
+
+/// An event, as `struct input_event`, stamped with the realtime clock.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Event {
+    sec: u64,
+    usec: u64,
+    kind: u16,
+    code: u16,
+    value: i32,
+}
+
+/// The modifier keys held down, and whether caps lock is on.
+struct Modifiers {
+    shift: [bool; 2],
+    ctrl: [bool; 2],
+    caps_lock: bool,
+}
+
+impl Modifiers {
+    /// Handles a key event, passing what the key types to `emit`.
+    fn key(&mut self, code: u16, value: i32, emit: impl FnOnce(&[u8])) {
+        let pressed = value != KEY_RELEASED;
+        match code {
+            KEY_LEFTSHIFT => self.shift[0] = pressed,
+            KEY_RIGHTSHIFT => self.shift[1] = pressed,
+            KEY_LEFTCTRL => self.ctrl[0] = pressed,
+            KEY_RIGHTCTRL => self.ctrl[1] = pressed,
+            _ if !pressed => {}
+            // It's only toggled by a fresh press, not by repeats.
+            KEY_CAPSLOCK => self.caps_lock ^= value == 1,
+            KEY_KPENTER => emit(b"\r"),
+            KEY_UP => emit(b"\x1b[A"),
+            KEY_DOWN => emit(b"\x1b[B"),
+            KEY_RIGHT => emit(b"\x1b[C"),
+            KEY_LEFT => emit(b"\x1b[D"),
+            KEY_HOME => emit(b"\x1b[H"),
+            KEY_END => emit(b"\x1b[F"),
+            KEY_INSERT => emit(b"\x1b[2~"),
+            KEY_DELETE => emit(b"\x1b[3~"),
+            KEY_PAGEUP => emit(b"\x1b[5~"),
+            KEY_PAGEDOWN => emit(b"\x1b[6~"),
+            _ => {
+                let shift = self.shift.contains(&true);
+                let map = if shift { KEYMAP_SHIFT } else { KEYMAP };
+                let Some(&(mut c)) = map.get(usize::from(code)).filter(|&&c| c != 0) else {
+                    return;
+                };
+                if self.caps_lock && c.is_ascii_alphabetic() {
+                    c ^= 0x20;
+                }
+                if self.ctrl.contains(&true) && matches!(c, b'@'..=b'_' | b'a'..=b'z') {
+                    c &= 0x1f;
+                }
+                emit(&[c]);
+            }
+        }
+    }
+}
+
+/// An opening of a device's events.
+struct Reader {
+    events: SpinLockIrqSave<VecDeque<Event>>,
+    /// Woken when an event is queued.
+    readable: WaitQueue,
+}
+
+impl Reader {
+    fn push(&self, event: Event) {
+        let mut events = self.events.lock();
+        if events.len() == QUEUE_SIZE {
+            events.pop_front();
+        }
+        events.push_back(event);
+        drop(events);
+        self.readable.wake_all();
+    }
+}
+
+pub struct InputDevice {
+    index: usize,
+    name: String,
+    /// The range of each absolute axis the device reports.
+    axes: BTreeMap<u16, AbsInfo>,
+    readers: SpinLockIrqSave<Vec<Arc<Reader>>>,
+}
+
+impl InputDevice {
+    /// Reports an event, maybe in interrupt context. Drivers end each batch
+    /// of events that happened together with an `EV_SYN`.
+    pub fn report(&self, kind: u16, code: u16, value: i32) {
+        let now = time::realtime();
+        let event = Event {
+            sec: now.as_secs(),
+            usec: u64::from(now.subsec_micros()),
+            kind,
+            code,
+            value,
+        };
+        for reader in self.readers.lock().iter() {
+            reader.push(event);
+        }
+        if kind == EV_KEY {
+            MODIFIERS.lock().key(code, value, io::push_input);
+        }
+    }
+
+    fn open(self: &Arc<Self>) -> Arc<EventFile> {
+        let reader = Arc::new(Reader {
+            events: SpinLockIrqSave::new(VecDeque::new()),
+            readable: WaitQueue::new(),
+        });
+        self.readers.lock().push(reader.clone());
+        Arc::new(EventFile {
+            device: self.clone(),
+            reader,
+        })
+    }
+}
+
+/// Adds an input device called `name`, with the ranges of the absolute
+/// axes it reports, if any.
+pub fn register(name: String, axes: BTreeMap<u16, AbsInfo>) -> Arc<InputDevice> {
+    let mut devices = DEVICES.lock();
+    let device = Arc::new(InputDevice {
+        index: devices.len(),
+        name,
+        axes,
+        readers: SpinLockIrqSave::new(Vec::new()),
+    });
+    devices.push(device.clone());
+    device
+}
+
+/// `/dev/input/event<n>`, as opened.
+struct EventFile {
+    device: Arc<InputDevice>,
+    reader: Arc<Reader>,
+}
+
+impl File for EventFile {
+    /// Reads as many whole events as fit in `buf`, waiting for one if there
+    /// are none.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let size = size_of::<Event>();
+        if buf.len() < size {
+            return Err(Errno::EINVAL);
+        }
+        let process = task::process();
+        let mut len = 0;
+        self.reader.readable.wait_until(|| {
+            let mut events = self.reader.events.lock();
+            while len + size <= buf.len() {
+                let Some(event) = events.pop_front() else {
+                    break;
+                };
+                buf[len..len + size].copy_from_slice(event_bytes(&event));
+                len += size;
+            }
+            len > 0
+                || process
+                    .as_ref()
+                    .is_some_and(|process| process.signals().has_deliverable())
+        });
+        match len {
+            0 => Err(Errno::EINTR),
+            len => Ok(len),
+        }
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    fn ioctl(&self, request: usize, arg: usize) -> Result<usize, Errno> {
+        let device = &self.device;
+        match request {
+            EVIOCGVERSION => {
+                write_user_struct(arg, &EV_VERSION)?;
+                Ok(0)
+            }
+            _ if request & !IOC_SIZE_MASK == EVIOCGNAME => {
+                let len = (request & IOC_SIZE_MASK) >> 16;
+                let mut name = Vec::from(device.name.as_bytes());
+                name.push(0);
+                name.truncate(len);
+                copy_to_user(arg, &name)?;
+                Ok(name.len())
+            }
+            _ if (EVIOCGABS..EVIOCGABS + 0x40).contains(&request) => {
+                let axis = (request - EVIOCGABS) as u16;
+                let info = device.axes.get(&axis).ok_or(Errno::EINVAL)?;
+                write_user_struct(arg, info)?;
+                Ok(0)
+            }
+            _ => Err(Errno::ENOTTY),
+        }
+    }
+}
+
+impl Drop for EventFile {
+    fn drop(&mut self) {
+        self.device
+            .readers
+            .lock()
+            .retain(|reader| !Arc::ptr_eq(reader, &self.reader));
+    }
+}
+
+/// The bytes of `event`, as it's read.
+fn event_bytes(event: &Event) -> &[u8] {
+    // SAFETY: `Event` has no padding, so all of its bytes are initialized.
+    unsafe { core::slice::from_raw_parts(event as *const Event as *const u8, size_of::<Event>()) }
+}
+
+/// `/dev/input`, listing the devices.
+struct InputDir;
+
+impl Vnode for InputDir {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, Errno> {
+        let index: usize = name
+            .strip_prefix("event")
+            .and_then(|index| index.parse().ok())
+            .ok_or(Errno::ENOENT)?;
+        let device = DEVICES.lock().get(index).cloned().ok_or(Errno::ENOENT)?;
+        Ok(Arc::new(EventNode(device)))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Errno> {
+        Ok(DEVICES
+            .lock()
+            .iter()
+            .map(|device| DirEntry {
+                name: format!("event{}", device.index),
+                kind: VnodeKind::Device,
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _kind: VnodeKind) -> Result<Arc<dyn Vnode>, Errno> {
+        Err(Errno::EINVAL)
+    }
+}
+
+struct EventNode(Arc<InputDevice>);
+
+impl Vnode for EventNode {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::Device
+    }
+
+    fn open_device(&self) -> Result<Arc<dyn File>, Errno> {
+        Ok(self.0.open())
+    }
+}
+
+/// Adds `/dev/input`.
+pub fn init() {
+    if let Err(err) = devfs::register("input", Arc::new(InputDir)) {
+        log_error!(target: "input", "couldn't add /dev/input: {}", err);
+    }
+}
+
+ktest! {
+    fn keys_become_console_input() {
+        let mut modifiers = Modifiers {
+            shift: [false; 2],
+            ctrl: [false; 2],
+            caps_lock: false,
+        };
+        let mut typed = Vec::new();
+        let mut press = |modifiers: &mut Modifiers, code, value| {
+            modifiers.key(code, value, |bytes| typed.extend_from_slice(bytes))
+        };
+        // a, Shift-a, Ctrl-c, then an arrow key and Page Down.
+        press(&mut modifiers, 30, 1);
+        press(&mut modifiers, 30, KEY_RELEASED);
+        press(&mut modifiers, KEY_LEFTSHIFT, 1);
+        press(&mut modifiers, 30, 1);
+        press(&mut modifiers, KEY_LEFTSHIFT, KEY_RELEASED);
+        press(&mut modifiers, KEY_RIGHTCTRL, 1);
+        press(&mut modifiers, 46, 1);
+        press(&mut modifiers, KEY_RIGHTCTRL, KEY_RELEASED);
+        press(&mut modifiers, KEY_UP, 1);
+        press(&mut modifiers, KEY_PAGEDOWN, 1);
+        // Mouse buttons type nothing.
+        press(&mut modifiers, 0x110, 1);
+        assert_eq!(typed, b"aA\x03\x1b[A\x1b[6~");
+    }
+}
+
+ktest! {
+    fn readers_get_whole_events() {
+        let device = InputDevice {
+            index: usize::MAX,
+            name: String::from("ktest"),
+            axes: BTreeMap::new(),
+            readers: SpinLockIrqSave::new(Vec::new()),
+        };
+        let device = Arc::new(device);
+        let file = device.open();
+        device.report(EV_REL, 0, -3);
+        device.report(EV_REL, 1, 2);
+        let mut buf = [0; 40];
+        assert_eq!(file.read(&mut buf[..10]), Err(Errno::EINVAL));
+        assert_eq!(file.read(&mut buf), Ok(24));
+        assert_eq!(&buf[16..24], &[2, 0, 0, 0, 0xfd, 0xff, 0xff, 0xff]);
+        assert_eq!(file.read(&mut buf), Ok(24));
+        drop(file);
+        assert!(device.readers.lock().is_empty());
+    }
+}
@@ -1,46 +1,748 @@
-use core::{arch::asm, fmt::Write};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::drivers::ns16550::{self, Uart};
+use crate::drivers::plic;
+use crate::dtb::{DeviceTree, DtNode};
+use crate::mm::virt_to_phys;
+use crate::sync::{Once, SpinLockIrqSave, WaitQueue};
+use crate::{cmdline, gdb, kmsg, ktest, log_info, log_warn, sbi, task, vt};
 
-const SBI_EID_BASE: u32 = 0x10;
 const SBI_EID_DBCN: u32 = 0x4442434e;
 
-const SBI_FID_BASE_PROBE_EXTENSION: u32 = 3;
 const SBI_FID_DBCN_CONSOLE_WRITE: u32 = 0;
+const SBI_FID_DBCN_CONSOLE_READ: u32 = 1;
+
+const RX_BUFFER_SIZE: usize = 256;
+
+static CONSOLE: SpinLockIrqSave<Console> = SpinLockIrqSave::new(Console {
+    sinks: [None; MAX_SINKS],
+    input: None,
+    unprinted: None,
+    log_shown: true,
+});
+
+/// Set if the UART's receive interrupt is routed to us, in which case input
+/// is collected in `RX` by the interrupt handler. Input from keyboards is
+/// always collected there.
+static UART_RX_IRQ: AtomicBool = AtomicBool::new(false);
+/// Tasks waiting for `RX` to have something in it.
+static RX_WAITERS: WaitQueue = WaitQueue::new();
+static RX: SpinLockIrqSave<RxBuffer> = SpinLockIrqSave::new(RxBuffer {
+    buf: [0; RX_BUFFER_SIZE],
+    start: 0,
+    len: 0,
+});
 
-fn sbi_probe_extension(eid: u32) -> bool {
-    let value: usize;
-    unsafe {
-        asm!(
-            "ecall",
-            in("a7") SBI_EID_BASE,
-            in("a6") SBI_FID_BASE_PROBE_EXTENSION,
-            inlateout("a0") eid => _,
-            lateout("a1") value,
-        );
+/// Received bytes not yet read. Bytes received while it's full are dropped.
+struct RxBuffer {
+    buf: [u8; RX_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl RxBuffer {
+    fn push(&mut self, byte: u8) {
+        if self.len < RX_BUFFER_SIZE {
+            self.buf[(self.start + self.len) % RX_BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.start];
+        self.start = (self.start + 1) % RX_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
     }
-    value != 0
 }
 
-/// SAFETY: `sbi_probe_extension(SBI_EID_DBCN)` has returned true.
+/// DBCN takes the physical address of what it reads or writes, so data goes
+/// through this buffer in the kernel image, which is in the physical memory
+/// mapping, rather than straight from wherever the caller has it: a kernel
+/// stack isn't.
+static DBCN_BUFFER: SpinLockIrqSave<[u8; DBCN_BUFFER_SIZE]> =
+    SpinLockIrqSave::new([0; DBCN_BUFFER_SIZE]);
+const DBCN_BUFFER_SIZE: usize = 256;
+
+/// How many writes in a row DBCN may take nothing from before the rest of
+/// the output is dropped.
+const MAX_STALLED_WRITES: usize = 1000;
+
+/// Writes some of `buf`, returning how much.
+///
+/// SAFETY: `sbi::probe_extension(SBI_EID_DBCN)` has returned true.
 unsafe fn sbi_debug_console_write(buf: &[u8]) -> Option<usize> {
-    let error: usize;
-    let value: usize;
-    unsafe {
-        asm!(
-            "ecall",
-            in("a7") SBI_EID_DBCN,
-            in("a6") SBI_FID_DBCN_CONSOLE_WRITE,
-            inlateout("a0") buf.len() => error,
-            inlateout("a1") buf.as_ptr() as usize => value,
-            in("a2") 0,
-        )
+    let mut bounce = DBCN_BUFFER.lock();
+    let len = buf.len().min(DBCN_BUFFER_SIZE);
+    bounce[..len].copy_from_slice(&buf[..len]);
+    let addr = virt_to_phys(bounce.as_ptr() as usize);
+    sbi::call(SBI_EID_DBCN, SBI_FID_DBCN_CONSOLE_WRITE, [len, addr, 0]).ok()
+}
+
+/// Reads into the start of `buf`, returning how much.
+///
+/// SAFETY: `sbi::probe_extension(SBI_EID_DBCN)` has returned true.
+unsafe fn sbi_debug_console_read(buf: &mut [u8]) -> Option<usize> {
+    let mut bounce = DBCN_BUFFER.lock();
+    let len = buf.len().min(DBCN_BUFFER_SIZE);
+    let addr = virt_to_phys(bounce.as_mut_ptr() as usize);
+    let read = sbi::call(SBI_EID_DBCN, SBI_FID_DBCN_CONSOLE_READ, [len, addr, 0])
+        .ok()?
+        .min(len);
+    buf[..read].copy_from_slice(&bounce[..read]);
+    Some(read)
+}
+
+/// A console device other than SBI's and the UART, registered by its
+/// driver.
+pub trait ConsoleDevice: Sync {
+    /// Writes all of `bytes`, returning false if the device couldn't.
+    fn write(&self, bytes: &[u8]) -> bool;
+
+    /// Clears the screen, moving the cursor to the top left, as a terminal
+    /// does for the escape sequence.
+    fn clear(&self) -> bool {
+        self.write(CLEAR_SCREEN)
+    }
+}
+
+/// The escape sequence that clears a terminal's screen.
+const CLEAR_SCREEN: &[u8] = b"\x1b[H\x1b[2J";
+
+#[derive(Clone, Copy)]
+enum Backend {
+    Dbcn,
+    /// The UART named by `stdout-path`, if we have a driver for it.
+    Uart(Uart),
+    Device(&'static dyn ConsoleDevice),
+}
+
+impl Backend {
+    fn write(&self, bytes: &[u8]) -> bool {
+        match *self {
+            Backend::Dbcn => {
+                let mut buf = bytes;
+                let mut stalled = 0;
+                while !buf.is_empty() {
+                    // SAFETY: the DBCN extension is present.
+                    match unsafe { sbi_debug_console_write(buf) } {
+                        Some(0) if stalled < MAX_STALLED_WRITES => stalled += 1,
+                        Some(written) if written > 0 => {
+                            buf = &buf[written.min(buf.len())..];
+                            stalled = 0;
+                        }
+                        // Every other hart waits on the console lock, so
+                        // give up rather than spin forever on a console
+                        // that's stopped taking output.
+                        _ => return false,
+                    }
+                }
+                true
+            }
+            Backend::Uart(uart) => {
+                for &byte in bytes {
+                    if byte == b'\n' {
+                        uart.write_byte(b'\r');
+                    }
+                    uart.write_byte(byte);
+                }
+                true
+            }
+            Backend::Device(device) => device.write(bytes),
+        }
     }
-    if error == 0 {
-        Some(value)
-    } else {
-        None
+
+    fn clear(&self) -> bool {
+        match *self {
+            Backend::Dbcn | Backend::Uart(_) => self.write(CLEAR_SCREEN),
+            Backend::Device(device) => device.clear(),
+        }
     }
 }
 
+/// The most sinks output can go to at once.
+const MAX_SINKS: usize = 4;
+
+/// Somewhere console output goes.
+#[derive(Clone, Copy)]
+struct Sink {
+    name: &'static str,
+    backend: Backend,
+    /// The least important log messages written to the sink. Other output
+    /// is always written.
+    level: Option<Level>,
+}
+
+/// The kernel console. Output is written to every sink and always recorded
+/// in the kernel log, which is there to read back even if every sink has
+/// failed.
+pub struct Console {
+    sinks: [Option<Sink>; MAX_SINKS],
+    /// Where input is read from.
+    input: Option<Backend>,
+    /// The kernel log position of the first output that couldn't be written
+    /// anywhere, to be replayed once there's a sink.
+    unprinted: Option<usize>,
+    /// Whether the kernel log is written to the sinks, which it isn't while
+    /// another virtual console is shown on them.
+    log_shown: bool,
+}
+
+impl Console {
+    /// Adds a sink, replacing any other of the same name, returning false
+    /// if there's no room for it or it fails to take the output so far.
+    fn add_sink(&mut self, sink: Sink) -> bool {
+        // Otherwise output would be written to both, and only one could be
+        // found by name.
+        self.remove_sink(sink.name);
+        let Some(slot) = self.sinks.iter_mut().find(|slot| slot.is_none()) else {
+            return false;
+        };
+        *slot = Some(sink);
+        if let Some(unprinted) = self.unprinted.take() {
+            let mut written = !kmsg::is_lost(unprinted)
+                || sink
+                    .backend
+                    .write(b"(earlier output was lost before there was a console)\n");
+            kmsg::read_lines_from(unprinted, |bytes| {
+                written = written && sink.backend.write(bytes);
+            });
+            if !written {
+                // Keep the output for the next sink instead.
+                self.remove_sink(sink.name);
+                self.unprinted = Some(unprinted);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn remove_sink(&mut self, name: &str) {
+        for slot in &mut self.sinks {
+            if slot.is_some_and(|sink| sink.name == name) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Writes `bytes` to the sinks that take messages at `level`, or to
+    /// every sink if it's not a log message. A sink that fails is removed,
+    /// so later output doesn't wait on it again. Returns false if there are
+    /// no sinks left.
+    fn write_level(&mut self, bytes: &[u8], level: Option<Level>) -> bool {
+        let mut any = false;
+        for slot in &mut self.sinks {
+            let Some(sink) = *slot else {
+                continue;
+            };
+            if level.is_none_or(|level| sink.level.is_none_or(|max| level <= max))
+                && !sink.backend.write(bytes)
+            {
+                *slot = None;
+                continue;
+            }
+            any = true;
+        }
+        any
+    }
+
+    /// Writes `bytes` to the console without recording them in the kernel
+    /// log, returning false if there's no sink to write to.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> bool {
+        self.write_level(bytes, None)
+    }
+
+    fn record(&mut self, bytes: &[u8], level: Option<Level>) {
+        let position = kmsg::position();
+        kmsg::record(bytes);
+        if !self.log_shown {
+            return;
+        }
+        if !self.write_level(bytes, level) && self.unprinted.is_none() {
+            self.unprinted = Some(position);
+        }
+    }
+
+    /// Writes `bytes` to the console and records them in the kernel log.
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.record(bytes, None);
+    }
+
+    /// Clears every sink's screen.
+    pub fn clear(&mut self) {
+        for sink in self.sinks.iter().flatten() {
+            sink.backend.clear();
+        }
+    }
+
+    /// Starts or stops writing the kernel log to the sinks.
+    pub fn show_log(&mut self, shown: bool) {
+        self.log_shown = shown;
+    }
+
+    /// Writes the whole kernel log to the sinks again.
+    pub fn replay_log(&mut self) {
+        kmsg::read_all(|bytes| {
+            self.write_bytes(bytes);
+        });
+    }
+}
+
+impl core::fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// The console, writing a log message at `level`.
+struct LogWriter<'c> {
+    console: &'c mut Console,
+    level: Level,
+}
+
+impl core::fmt::Write for LogWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.console.record(s.as_bytes(), Some(self.level));
+        Ok(())
+    }
+}
+
+/// Writes raw bytes to the console, e.g. output from U-mode.
+pub fn write(bytes: &[u8]) {
+    with_console(|console| console.write(bytes));
+}
+
+/// Runs `f` with the console locked. `f` must not print.
+pub fn with_console<R>(f: impl FnOnce(&mut Console) -> R) -> R {
+    f(&mut CONSOLE.lock())
+}
+
+/// Releases the console and kernel log locks, and DBCN's buffer, so a panicking hart can print
+/// even if it or a halted hart was holding them.
+///
+/// SAFETY: see `SpinLock::force_unlock`.
+pub unsafe fn force_unlock() {
+    CONSOLE.force_unlock();
+    DBCN_BUFFER.force_unlock();
+    kmsg::force_unlock();
+}
+
+/// Whether the sink `name` is to be used, and if so the least important
+/// log messages to write to it. `consoles=<name>[:<level>],...` picks the
+/// sinks; without it, every sink is used with no filter.
+fn sink_config(name: &str) -> Option<Option<Level>> {
+    let Some(consoles) = cmdline::get("consoles") else {
+        return Some(None);
+    };
+    consoles.split(',').find_map(|console| {
+        let (sink, level) = match console.split_once(':') {
+            Some((sink, level)) => (sink, Level::parse(level)),
+            None => (console, None),
+        };
+        (sink == name).then_some(level)
+    })
+}
+
+/// Adds the sink `name`, if `consoles=` doesn't leave it out.
+fn add_sink(name: &'static str, backend: Backend) -> bool {
+    let Some(level) = sink_config(name) else {
+        return false;
+    };
+    let added = with_console(|console| {
+        console.add_sink(Sink {
+            name,
+            backend,
+            level,
+        })
+    });
+    if !added {
+        log_warn!(target: "console", "no room for {}", name);
+    }
+    added
+}
+
+/// Adds `device` as a console sink called `name`, if `consoles=` asks for
+/// it or, without `consoles=`, if it's one to use by `default`. Output isn't
+/// replayed to it, but is in the kernel log.
+pub fn add_device(name: &'static str, device: &'static dyn ConsoleDevice, default: bool) -> bool {
+    if cmdline::get("consoles").is_none() && !default {
+        return false;
+    }
+    add_sink(name, Backend::Device(device))
+}
+
+/// Sets the least important log messages written to the sink `name`,
+/// returning false if there's no such sink.
+pub fn set_sink_level(name: &str, level: Option<Level>) -> bool {
+    with_console(|console| {
+        let sink = console
+            .sinks
+            .iter_mut()
+            .flatten()
+            .find(|sink| sink.name == name);
+        sink.map(|sink| sink.level = level).is_some()
+    })
+}
+
+/// The name and log level of every sink.
+pub fn sinks() -> [Option<(&'static str, Option<Level>)>; MAX_SINKS] {
+    with_console(|console| {
+        console
+            .sinks
+            .map(|sink| sink.map(|sink| (sink.name, sink.level)))
+    })
+}
+
+/// Probes for the SBI debug console. Until this is called (or if there's no
+/// debug console and no UART) output is only recorded in the kernel log.
+pub fn init_early() {
+    if sbi::probe_extension(SBI_EID_DBCN) {
+        // The command line isn't known yet, so this is undone by `init` if
+        // `consoles=` leaves SBI out.
+        with_console(|console| {
+            console.add_sink(Sink {
+                name: "sbi",
+                backend: Backend::Dbcn,
+                level: None,
+            });
+            console.input = Some(Backend::Dbcn);
+        });
+    }
+}
+
+/// Switches the console to the device named by `stdout-path` in `/chosen`,
+/// if it's a UART we have a driver for. Options after the path (e.g.
+/// `:115200n8`) may set the baud rate. Unless `consoles=` says otherwise
+/// the UART replaces SBI, which is likely to be the same serial port.
+pub fn init(dt: &DeviceTree<'_>) {
+    let consoles = cmdline::get("consoles");
+    let configs = consoles.unwrap_or("").split(',');
+    for (sink, level) in configs.filter_map(|config| config.split_once(':')) {
+        if Level::parse(level).is_none() {
+            log_warn!(target: "console", "invalid level for {}: {:?}", sink, level);
+        }
+    }
+
+    let uart = stdout_uart(dt);
+    let keep_sbi = match consoles {
+        Some(_) => sink_config("sbi"),
+        None if uart.is_some() => None,
+        None => Some(None),
+    };
+    match keep_sbi {
+        Some(level) => {
+            set_sink_level("sbi", level);
+        }
+        None => with_console(|console| console.remove_sink("sbi")),
+    }
+
+    let Some((uart, node)) = uart else {
+        return;
+    };
+    if !add_sink("uart", Backend::Uart(uart)) {
+        return;
+    }
+    log_info!(target: "console", "using {}", node.name);
+    with_console(|console| console.input = Some(Backend::Uart(uart)));
+
+    let irq = dt.interrupts(&node).next().and_then(|irq| irq.cell(0));
+    if irq.is_some_and(|irq| plic::register(irq, uart_interrupt)) {
+        UART_RX_IRQ.store(true, Ordering::Relaxed);
+        uart.enable_rx_interrupt();
+    }
+}
+
+/// Sets up the UART named by `stdout-path`, returning it and its node.
+fn stdout_uart<'a>(dt: &DeviceTree<'a>) -> Option<(Uart, DtNode<'a>)> {
+    let stdout_path = dt.chosen()?.stdout_path?;
+    let (path, options) = match stdout_path.split_once(':') {
+        Some((path, options)) => (path, Some(options)),
+        None => (stdout_path, None),
+    };
+    let Some(node) = dt.find_node(path) else {
+        log_warn!(target: "console", "{} not found", path);
+        return None;
+    };
+    if !ns16550::COMPATIBLE.iter().any(|c| node.is_compatible(c)) {
+        log_warn!(target: "console", "no driver for {}, using SBI", path);
+        return None;
+    }
+
+    let baud = options.and_then(|options| {
+        let digits = options.find(|c: char| !c.is_ascii_digit());
+        options[..digits.unwrap_or(options.len())].parse().ok()
+    });
+    let Some(uart) = Uart::init(dt, &node, baud) else {
+        log_warn!(target: "console", "failed to set up {}, using SBI", node.name);
+        return None;
+    };
+    Some((uart, node))
+}
+
+/// The console's UART, if input comes from one.
+pub fn console_uart() -> Option<Uart> {
+    match with_console(|console| console.input) {
+        Some(Backend::Uart(uart)) => Some(uart),
+        _ => None,
+    }
+}
+
+fn uart_interrupt(_irq: u32) {
+    let input = CONSOLE.lock().input;
+    if let Some(Backend::Uart(uart)) = input {
+        let mut rx = RX.lock();
+        while let Some(byte) = uart.read_byte() {
+            if byte == 0x03 && gdb::is_on_console() {
+                gdb::request_break();
+                continue;
+            }
+            if vt::intercept(byte) {
+                continue;
+            }
+            rx.push(byte);
+        }
+    }
+    RX_WAITERS.wake_all();
+}
+
+/// Adds input typed on a keyboard, which is read along with the console's.
+/// Input that doesn't fit is dropped.
+pub fn push_input(bytes: &[u8]) {
+    {
+        let mut rx = RX.lock();
+        for &byte in bytes.iter().filter(|&&byte| !vt::intercept(byte)) {
+            rx.push(byte);
+        }
+    }
+    RX_WAITERS.wake_all();
+}
+
+/// Returns the next byte of console input, if any has arrived.
+pub fn read_byte() -> Option<u8> {
+    if let Some(byte) = RX.lock().pop() {
+        return Some(byte);
+    }
+    let byte = match with_console(|console| console.input) {
+        None | Some(Backend::Device(_)) => None,
+        Some(Backend::Dbcn) => {
+            let mut byte = [0];
+            // SAFETY: the DBCN extension is present.
+            match unsafe { sbi_debug_console_read(&mut byte) } {
+                Some(1) => Some(byte[0]),
+                _ => None,
+            }
+        }
+        Some(Backend::Uart(_)) if UART_RX_IRQ.load(Ordering::Relaxed) => None,
+        Some(Backend::Uart(uart)) => uart.read_byte(),
+    };
+    // Polled input only arrives here, rather than in an interrupt.
+    byte.filter(|&byte| !vt::intercept(byte))
+}
+
+/// Waits for the next byte of console input. The task sleeps until the
+/// UART or a keyboard interrupts if the input doesn't need polling,
+/// otherwise it polls.
+pub fn wait_byte() -> u8 {
+    wait_byte_unless(|| false).expect("stopped waiting for input without a reason")
+}
+
+/// `wait_byte`, but giving up and returning `None` once `stop` returns
+/// true, which is checked whenever input arrives or `wake_readers` is
+/// called, with interrupts disabled.
+pub fn wait_byte_unless(mut stop: impl FnMut() -> bool) -> Option<u8> {
+    loop {
+        if let Some(byte) = read_byte() {
+            return Some(byte);
+        }
+        if stop() {
+            return None;
+        }
+        if input_polled() {
+            task::yield_now();
+        } else {
+            RX_WAITERS.wait_until(|| RX.lock().len > 0 || stop());
+        }
+    }
+}
+
+/// Wakes the tasks waiting for input, for them to check whether to stop.
+pub fn wake_readers() {
+    RX_WAITERS.wake_all();
+}
+
+/// Whether console input only arrives when asked for, rather than with an
+/// interrupt.
+fn input_polled() -> bool {
+    match with_console(|console| console.input) {
+        Some(Backend::Dbcn) => true,
+        Some(Backend::Uart(_)) => !UART_RX_IRQ.load(Ordering::Relaxed),
+        None | Some(Backend::Device(_)) => false,
+    }
+}
+
+/// How important a log message is. Messages less important than the log
+/// level aren't printed.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// Parses a level from its name or number, as in `loglevel=debug` or
+    /// `loglevel=4`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "error" | "1" => Some(Level::Error),
+            "warn" | "2" => Some(Level::Warn),
+            "info" | "3" => Some(Level::Info),
+            "debug" | "4" => Some(Level::Debug),
+            "trace" | "5" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// How many tags can have a log level of their own.
+const MAX_TAG_LEVELS: usize = 8;
+
+/// Log levels for particular tags, which override `LOG_LEVEL` for them.
+static TAG_LEVELS: Once<[Option<(&'static str, Level)>; MAX_TAG_LEVELS]> = Once::new();
+
+/// Sets the log level from `loglevel=` on the kernel command line: a level,
+/// and levels for particular tags, such as `loglevel=warn,virtio:debug`.
+pub fn init_log_level() {
+    let Some(value) = cmdline::get("loglevel") else {
+        return;
+    };
+    let mut tags = [None; MAX_TAG_LEVELS];
+    let mut count = 0;
+    for item in value.split(',') {
+        let (tag, level) = match item.split_once(':') {
+            Some((tag, level)) => (Some(tag), level),
+            None => (None, item),
+        };
+        let Some(level) = Level::parse(level) else {
+            log_warn!("invalid loglevel {:?}", item);
+            continue;
+        };
+        match tag {
+            None => set_log_level(level),
+            Some(tag) if count < MAX_TAG_LEVELS => {
+                tags[count] = Some((tag, level));
+                count += 1;
+            }
+            Some(tag) => log_warn!("too many loglevel tags, ignoring {:?}", tag),
+        }
+    }
+    TAG_LEVELS.call_once(|| tags);
+}
+
+pub fn set_log_level(level: Level) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_enabled(level: Level) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Whether messages at `level` tagged `tag` are logged, which its own log
+/// level decides if it has one.
+#[doc(hidden)]
+pub fn tag_enabled(level: Level, tag: &str) -> bool {
+    let own = TAG_LEVELS
+        .get()
+        .and_then(|tags| tags.iter().flatten().find(|&&(t, _)| t == tag));
+    match own {
+        Some(&(_, own)) => level <= own,
+        None => log_enabled(level),
+    }
+}
+
+/// The tag for messages from `module`: the last component of its path, or
+/// `kernel` for the crate root.
+#[doc(hidden)]
+pub fn module_tag(module: &'static str) -> &'static str {
+    match module.rsplit_once("::") {
+        Some((_, last)) => last,
+        None => "kernel",
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, tag: &str, args: core::fmt::Arguments) {
+    with_console(|console| {
+        let mut writer = LogWriter { console, level };
+        writer
+            .write_fmt(format_args!("{:<5} {}: {}\n", level.name(), tag, args))
+            .unwrap()
+    })
+}
+
+/// Logs a message at `level`, tagged with the name of the calling module or
+/// with an explicit `target: "tag",` before the format string.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, target: $tag:expr, $($arg:tt)+) => {{
+        let tag = $tag;
+        if $crate::io::tag_enabled($level, tag) {
+            $crate::io::_log($level, tag, ::core::format_args!($($arg)+))
+        }
+    }};
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log!(
+            $level,
+            target: $crate::io::module_tag(::core::module_path!()),
+            $($arg)+
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)+) => { $crate::log!($crate::io::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::log!($crate::io::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::log!($crate::io::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)+) => { $crate::log!($crate::io::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)+) => { $crate::log!($crate::io::Level::Trace, $($arg)+) };
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => { $crate::io::_print(::core::format_args!($($arg)*)) };
@@ -57,32 +759,97 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    stdout().write_fmt(args).unwrap()
+    // Holding the lock for the whole message keeps lines from different
+    // harts from being interleaved.
+    with_console(|console| console.write_fmt(args).unwrap())
 }
 
-pub struct Stdout {
-    has_dbcn: bool,
-}
+ktest! {
+    fn fans_out_to_sinks() {
+        use alloc::boxed::Box;
+        use alloc::vec::Vec;
 
-pub fn stdout() -> Stdout {
-    Stdout {
-        has_dbcn: sbi_probe_extension(SBI_EID_DBCN),
+        use crate::sync::SpinLock;
+
+        struct Recorder(SpinLock<Vec<u8>>);
+
+        impl ConsoleDevice for Recorder {
+            fn write(&self, bytes: &[u8]) -> bool {
+                self.0.lock().extend_from_slice(bytes);
+                true
+            }
+        }
+
+        let sink = |name, level| {
+            let recorder: &'static Recorder =
+                Box::leak(Box::new(Recorder(SpinLock::new(Vec::new()))));
+            (recorder, Sink { name, backend: Backend::Device(recorder), level })
+        };
+        let mut console = Console {
+            sinks: [None; MAX_SINKS],
+            input: None,
+            unprinted: None,
+            log_shown: true,
+        };
+        assert!(!console.write_bytes(b"nowhere"));
+
+        let (all, all_sink) = sink("all", None);
+        let (quiet, quiet_sink) = sink("quiet", Some(Level::Warn));
+        assert!(console.add_sink(all_sink));
+        assert!(console.add_sink(quiet_sink));
+        console.write_level(b"info ", Some(Level::Info));
+        console.write_level(b"error ", Some(Level::Error));
+        assert!(console.write_bytes(b"output"));
+        assert_eq!(&all.0.lock()[..], b"info error output");
+        assert_eq!(&quiet.0.lock()[..], b"error output");
+
+        // Adding it again replaces it rather than writing to it twice.
+        assert!(console.add_sink(Sink { level: None, ..quiet_sink }));
+        assert_eq!(console.sinks.iter().flatten().count(), 2);
+        quiet.0.lock().clear();
+        console.write_level(b"debug", Some(Level::Debug));
+        assert_eq!(&quiet.0.lock()[..], b"debug");
+
+        assert!(console.add_sink(sink("c", None).1));
+        assert!(console.add_sink(sink("d", None).1));
+        assert!(!console.add_sink(sink("e", None).1));
+        console.remove_sink("all");
+        console.write_bytes(b"!");
+        assert_eq!(&all.0.lock()[..], b"info error outputdebug");
     }
 }
 
-impl core::fmt::Write for Stdout {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        if !self.has_dbcn {
-            return Ok(());
-        }
+ktest! {
+    fn drops_failed_sinks() {
+        use alloc::boxed::Box;
 
-        let mut buf = s.as_bytes();
-        while !buf.is_empty() {
-            // SAFETY: the DBCN extension is present
-            let written = unsafe { sbi_debug_console_write(buf) }.ok_or(core::fmt::Error)?;
-            buf = &buf[written..];
+        struct Stalled;
+
+        impl ConsoleDevice for Stalled {
+            fn write(&self, _bytes: &[u8]) -> bool {
+                false
+            }
         }
 
-        Ok(())
+        let stalled: &'static Stalled = Box::leak(Box::new(Stalled));
+        let sink = Sink { name: "stalled", backend: Backend::Device(stalled), level: None };
+        let mut console = Console {
+            sinks: [None; MAX_SINKS],
+            input: None,
+            unprinted: None,
+            log_shown: true,
+        };
+        assert!(console.add_sink(sink));
+        assert!(!console.write_bytes(b"lost"));
+        assert!(console.sinks.iter().all(Option::is_none));
+
+        // Output that didn't go anywhere is kept for the next sink, and a
+        // sink that can't take it isn't added.
+        console.record(b"ktest: written while there was no console\n", None);
+        let unprinted = console.unprinted;
+        assert!(unprinted.is_some());
+        assert!(!console.add_sink(sink));
+        assert!(console.sinks.iter().all(Option::is_none));
+        assert_eq!(console.unprinted, unprinted);
     }
 }
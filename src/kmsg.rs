@@ -0,0 +1,95 @@
+//! A record of everything written to the console, so that output from before
+//! a console is available isn't lost and can be read back later (`dmesg`).
+
+use crate::sync::SpinLockIrqSave;
+
+const KMSG_SIZE: usize = 16 << 10;
+
+static KMSG: SpinLockIrqSave<Kmsg> = SpinLockIrqSave::new(Kmsg {
+    buf: [0; KMSG_SIZE],
+    written: 0,
+});
+
+/// A ring buffer holding the last `KMSG_SIZE` bytes of output.
+struct Kmsg {
+    buf: [u8; KMSG_SIZE],
+    /// Total bytes ever written; the buffer holds the ones from
+    /// `written - KMSG_SIZE` on.
+    written: usize,
+}
+
+impl Kmsg {
+    fn oldest(&self) -> usize {
+        self.written.saturating_sub(KMSG_SIZE)
+    }
+}
+
+/// Releases the lock on the record, for the panic handler.
+///
+/// SAFETY: see `SpinLock::force_unlock`.
+pub unsafe fn force_unlock() {
+    KMSG.force_unlock();
+}
+
+/// Appends `bytes` to the record.
+pub fn record(bytes: &[u8]) {
+    let mut kmsg = KMSG.lock();
+    for &byte in bytes {
+        let index = kmsg.written % KMSG_SIZE;
+        kmsg.buf[index] = byte;
+        kmsg.written += 1;
+    }
+}
+
+/// The position the next byte will be recorded at, for `read_from`.
+pub fn position() -> usize {
+    KMSG.lock().written
+}
+
+/// Calls `f` with the recorded output from `position` on, in up to two
+/// pieces. Output older than the buffer holds is skipped.
+///
+/// The buffer is locked while `f` runs, so `f` must not print.
+pub fn read_from(position: usize, mut f: impl FnMut(&[u8])) {
+    let kmsg = KMSG.lock();
+    let start = position.clamp(kmsg.oldest(), kmsg.written);
+    if start == kmsg.written {
+        return;
+    }
+    let (first, second) = (start % KMSG_SIZE, kmsg.written % KMSG_SIZE);
+    if first < second {
+        f(&kmsg.buf[first..second]);
+    } else {
+        f(&kmsg.buf[first..]);
+        f(&kmsg.buf[..second]);
+    }
+}
+
+/// Calls `f` with all the recorded output, starting at the first complete
+/// line if the oldest output has been overwritten.
+pub fn read_all(f: impl FnMut(&[u8])) {
+    read_lines_from(0, f);
+}
+
+/// Whether output from `position` on has been overwritten.
+pub fn is_lost(position: usize) -> bool {
+    KMSG.lock().oldest() > position
+}
+
+/// Like `read_from`, but if output from `position` on has been overwritten,
+/// starts at the first complete line after it.
+pub fn read_lines_from(position: usize, mut f: impl FnMut(&[u8])) {
+    let mut skipping = is_lost(position);
+    read_from(position, |mut bytes| {
+        if skipping {
+            match bytes.iter().position(|&byte| byte == b'\n') {
+                Some(newline) => {
+                    bytes = &bytes[newline + 1..];
+                    skipping = false;
+                }
+                None => return,
+            }
+        }
+        f(bytes);
+    });
+}
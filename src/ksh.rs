@@ -0,0 +1,1085 @@
+//! A tiny interactive shell on the kernel console, for poking at a machine
+//! during bring-up.
+
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::block;
+use crate::drivers::{self, pci, virtio};
+use crate::dtb::DeviceTree;
+use crate::fs::fat::{FatFileSystem, FatFs};
+use crate::fs::ninep::Share;
+use crate::fs::{self, VnodeKind};
+use crate::mm::paging::{self, PteFlags};
+use crate::mm::{frame, heap, stack};
+use crate::net::tcp::{TcpListener, TcpStream};
+use crate::net::udp::UdpSocket;
+use crate::net::{self, icmp, Ipv4Addr};
+use crate::process::Pid;
+use crate::sbi::pmu::{self, CounterInfo};
+use crate::sbi::reset::ResetReason;
+use crate::time::{self, Duration};
+use crate::util::{align_down, hexdump};
+use crate::{
+    backtrace, console, cpuinfo, io, kmsg, machine, percpu, print, println, process, profile, sbi,
+    signal, smp, user, vt,
+};
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&Context<'_>, &[&str]),
+}
+
+/// What commands need to know about the machine.
+pub struct Context<'a> {
+    pub dt: DeviceTree<'a>,
+    pub boot_hart: usize,
+}
+
+const MAX_ARGS: usize = 8;
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        help: "list commands",
+        run: help,
+    },
+    Command {
+        name: "dt",
+        usage: "dt [path [property]]",
+        help: "dump the device tree, the subtree at path, or a property's bytes",
+        run: dt,
+    },
+    Command {
+        name: "md",
+        usage: "md <addr> [len]",
+        help: "hexdump len (default 256) bytes of memory at virtual address addr",
+        run: md,
+    },
+    Command {
+        name: "mw",
+        usage: "mw <addr> <value> [width]",
+        help: "write a value of width 1, 2, 4 (default) or 8 bytes to memory at addr",
+        run: mw,
+    },
+    Command {
+        name: "dmesg",
+        usage: "dmesg",
+        help: "print the kernel log",
+        run: dmesg,
+    },
+    Command {
+        name: "consoles",
+        usage: "consoles [sink level|all]",
+        help: "list console sinks, or set the log messages written to one",
+        run: consoles,
+    },
+    Command {
+        name: "vt",
+        usage: "vt [console]",
+        help: "show the virtual console shown, or switch to another",
+        run: vt,
+    },
+    Command {
+        name: "free",
+        usage: "free",
+        help: "show frame allocator, heap and slab cache usage",
+        run: free,
+    },
+    Command {
+        name: "pmu",
+        usage: "pmu",
+        help: "list the SBI PMU's counters",
+        run: pmu,
+    },
+    Command {
+        name: "perf",
+        usage: "perf <command> [args]",
+        help: "count hardware events while running a command",
+        run: perf,
+    },
+    Command {
+        name: "profile",
+        usage: "profile [start [samples]|stop|top [n]]",
+        help: "sample the kernel's pc on every tick, and show the hottest",
+        run: profile,
+    },
+    Command {
+        name: "stacks",
+        usage: "stacks",
+        help: "show how much of each kernel stack has been used",
+        run: stacks,
+    },
+    Command {
+        name: "cpuinfo",
+        usage: "cpuinfo",
+        help: "describe the harts",
+        run: cpuinfo,
+    },
+    Command {
+        name: "ipi",
+        usage: "ipi",
+        help: "have every online hart say hello",
+        run: ipi,
+    },
+    Command {
+        name: "user",
+        usage: "user [PROGRAM]",
+        help: "run a built-in program or an executable file in U-mode, init by default",
+        run: user,
+    },
+    Command {
+        name: "ps",
+        usage: "ps",
+        help: "list user processes",
+        run: ps,
+    },
+    Command {
+        name: "kill",
+        usage: "kill <pid> [signal]",
+        help: "send a signal (default SIGTERM) to a user process",
+        run: kill,
+    },
+    Command {
+        name: "drivers",
+        usage: "drivers",
+        help: "list device tree nodes and the drivers bound to them",
+        run: drivers,
+    },
+    Command {
+        name: "virtio",
+        usage: "virtio",
+        help: "list virtio devices",
+        run: virtio,
+    },
+    Command {
+        name: "lspci",
+        usage: "lspci",
+        help: "list PCI functions and their BARs",
+        run: lspci,
+    },
+    Command {
+        name: "lsblk",
+        usage: "lsblk",
+        help: "list block devices",
+        run: lsblk,
+    },
+    Command {
+        name: "blk",
+        usage: "blk <device> [block]",
+        help: "hexdump a block (default 0) of a block device, bypassing the cache",
+        run: blk,
+    },
+    Command {
+        name: "mount",
+        usage: "mount [[-t 9p] <device> <path>]",
+        help: "mount a FAT32 device, or a 9p share by its tag, at a directory, or list mounts",
+        run: mount,
+    },
+    Command {
+        name: "umount",
+        usage: "umount <path>",
+        help: "unmount the filesystem at a directory",
+        run: umount,
+    },
+    Command {
+        name: "ls",
+        usage: "ls [path]",
+        help: "list a directory (default /)",
+        run: ls,
+    },
+    Command {
+        name: "cat",
+        usage: "cat <path>",
+        help: "print a file",
+        run: cat,
+    },
+    Command {
+        name: "hexdump",
+        usage: "hexdump <path>",
+        help: "hexdump a file",
+        run: hexdump_file,
+    },
+    Command {
+        name: "write",
+        usage: "write <path> <text>",
+        help: "replace the contents of a file with a line of text",
+        run: write,
+    },
+    Command {
+        name: "mkdir",
+        usage: "mkdir <path>",
+        help: "create a directory",
+        run: mkdir,
+    },
+    Command {
+        name: "rm",
+        usage: "rm [-d] <path>",
+        help: "remove a file, or an empty directory with -d",
+        run: rm,
+    },
+    Command {
+        name: "sync",
+        usage: "sync",
+        help: "write changes to filesystems and cached blocks back to their devices",
+        run: sync,
+    },
+    Command {
+        name: "ifconfig",
+        usage: "ifconfig",
+        help: "list network interfaces",
+        run: ifconfig,
+    },
+    Command {
+        name: "arp",
+        usage: "arp",
+        help: "list resolved Ethernet addresses",
+        run: arp,
+    },
+    Command {
+        name: "ping",
+        usage: "ping <addr> [count]",
+        help: "send count (default 4) ICMP echo requests",
+        run: ping,
+    },
+    Command {
+        name: "nc",
+        usage: "nc [-u] <addr> <port> [text] | nc -l <port>",
+        help:
+            "send a line over TCP (or UDP with -u) and print the reply, or accept a TCP connection",
+        run: nc,
+    },
+    Command {
+        name: "gdb",
+        usage: "gdb",
+        help: "stop in the GDB stub, see gdb= on the command line",
+        run: gdb,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        help: "reboot the machine",
+        run: reboot,
+    },
+    Command {
+        name: "shutdown",
+        usage: "shutdown",
+        help: "power off the machine",
+        run: shutdown,
+    },
+    Command {
+        name: "exit",
+        usage: "exit [code]",
+        help: "power off the machine, exiting QEMU with the given status",
+        run: exit,
+    },
+];
+
+/// Reads and runs commands forever.
+pub fn run(ctx: &Context<'_>) -> ! {
+    println!("ksh: type 'help' for a list of commands");
+    loop {
+        print!("ksh> ");
+        let line = console::read_line();
+
+        let mut args = [""; MAX_ARGS];
+        let mut argc = 0;
+        for word in line.split_ascii_whitespace() {
+            if argc == MAX_ARGS {
+                break;
+            }
+            args[argc] = word;
+            argc += 1;
+        }
+        let Some(&name) = args[..argc].first() else {
+            continue;
+        };
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => (command.run)(ctx, &args[1..argc]),
+            None => println!("{}: command not found", name),
+        }
+    }
+}
+
+/// Parses a number in hex (with a `0x` prefix) or decimal.
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn mw(_ctx: &Context<'_>, args: &[&str]) {
+    let (Some(addr), Some(value)) = (
+        args.first().and_then(|arg| parse_number(arg)),
+        args.get(1).and_then(|arg| parse_number(arg)),
+    ) else {
+        println!("usage: mw <addr> <value> [width]");
+        return;
+    };
+    let width = match args.get(2).map(|arg| parse_number(arg)) {
+        None => 4,
+        Some(Some(width @ (1 | 2 | 4 | 8))) => width,
+        Some(_) => {
+            println!("usage: mw <addr> <value> [width]");
+            return;
+        }
+    };
+    if !addr.is_multiple_of(width) {
+        println!("mw: {:#x} isn't aligned to {} bytes", addr, width);
+        return;
+    }
+    if width < size_of::<usize>() && value >> (8 * width) != 0 {
+        println!("mw: {:#x} doesn't fit in {} bytes", value, width);
+        return;
+    }
+    // Writing where there's no page, or a read-only one, would fault.
+    match paging::lookup(addr) {
+        Some((_, flags)) if flags.contains(PteFlags::W) => {}
+        Some(_) => {
+            println!("mw: {:#x} is read-only", addr);
+            return;
+        }
+        None => {
+            println!("mw: {:#x} is not mapped", addr);
+            return;
+        }
+    }
+    // SAFETY: the page is mapped writable, as checked above, and the address
+    // aligned. What writing there does is up to whoever asked.
+    unsafe {
+        match width {
+            1 => (addr as *mut u8).write_volatile(value as u8),
+            2 => (addr as *mut u16).write_volatile(value as u16),
+            4 => (addr as *mut u32).write_volatile(value as u32),
+            _ => (addr as *mut u64).write_volatile(value as u64),
+        }
+    }
+}
+
+fn help(_ctx: &Context<'_>, _args: &[&str]) {
+    for command in COMMANDS {
+        println!("{:<20} {}", command.usage, command.help);
+    }
+}
+
+fn dt(ctx: &Context<'_>, args: &[&str]) {
+    let path = args.first().copied().unwrap_or("/");
+    let Some(node) = ctx.dt.find_node(path) else {
+        println!("dt: {}: no such node", path);
+        return;
+    };
+    match args.get(1) {
+        Some(&name) => match node.property(name) {
+            Some(prop) => println!("{}", hexdump(0, prop.value)),
+            None => println!("dt: {}: no property {}", path, name),
+        },
+        None => print!("{}", ctx.dt.dts(node)),
+    }
+}
+
+fn md(_ctx: &Context<'_>, args: &[&str]) {
+    let Some(addr) = args.first().and_then(|arg| parse_number(arg)) else {
+        println!("usage: md <addr> [len]");
+        return;
+    };
+    let len = match args.get(1) {
+        Some(arg) => match parse_number(arg) {
+            Some(len) => len,
+            None => {
+                println!("usage: md <addr> [len]");
+                return;
+            }
+        },
+        None => 256,
+    };
+    let Some(end) = addr.checked_add(len) else {
+        println!("md: range overflows");
+        return;
+    };
+
+    // Reading an unmapped address would fault, so check every page first.
+    let first_page = align_down(addr, paging::PAGE_SIZE);
+    if let Some(page) = (first_page..end)
+        .step_by(paging::PAGE_SIZE)
+        .find(|&page| paging::translate(page).is_none())
+    {
+        println!("md: {:#x} is not mapped", page.max(addr));
+        return;
+    }
+
+    // Memory is copied out a line at a time with volatile reads, as it may
+    // be changing, so that lines stay aligned.
+    let mut line = [0; 16];
+    let mut start = addr;
+    while start < end {
+        let len = (align_down(start, 16) + 16).min(end) - start;
+        for (i, byte) in line[..len].iter_mut().enumerate() {
+            // SAFETY: the page is mapped, as checked above.
+            *byte = unsafe { ((start + i) as *const u8).read_volatile() };
+        }
+        println!("{}", hexdump(start, &line[..len]));
+        start += len;
+    }
+}
+
+fn dmesg(_ctx: &Context<'_>, _args: &[&str]) {
+    io::with_console(|console| {
+        kmsg::read_all(|bytes| {
+            console.write_bytes(bytes);
+        })
+    });
+}
+
+fn consoles(_ctx: &Context<'_>, args: &[&str]) {
+    let (Some(&name), Some(&level)) = (args.first(), args.get(1)) else {
+        for (name, level) in io::sinks().into_iter().flatten() {
+            println!("{:<8} {}", name, level.map_or("all", |level| level.name()));
+        }
+        return;
+    };
+    let level = match level {
+        "all" => None,
+        level => match io::Level::parse(level) {
+            Some(level) => Some(level),
+            None => {
+                println!("consoles: invalid level {}", level);
+                return;
+            }
+        },
+    };
+    if !io::set_sink_level(name, level) {
+        println!("consoles: {}: no such sink", name);
+    }
+}
+
+fn vt(_ctx: &Context<'_>, args: &[&str]) {
+    let Some(&console) = args.first() else {
+        println!("{}", vt::active());
+        return;
+    };
+    let Some(console) = parse_number(console) else {
+        println!("usage: vt [console]");
+        return;
+    };
+    if let Err(err) = vt::switch(console) {
+        println!("vt: {}: {}", console, err);
+    }
+}
+
+fn free(_ctx: &Context<'_>, _args: &[&str]) {
+    let frames = frame::stats();
+    println!(
+        "frames: {} KiB free of {} KiB",
+        frames.free * frame::FRAME_SIZE / 1024,
+        frames.total * frame::FRAME_SIZE / 1024
+    );
+    let heap = heap::stats();
+    println!(
+        "heap:   {} bytes used, {} bytes free of {} bytes",
+        heap.size - heap.free,
+        heap.free,
+        heap.size
+    );
+    let requests = virtio::request_cache_stats();
+    println!(
+        "slab:   {}: {} of {} objects of {} bytes used, in {} slabs",
+        requests.name, requests.in_use, requests.objects, requests.object_size, requests.slabs
+    );
+}
+
+fn pmu(_ctx: &Context<'_>, _args: &[&str]) {
+    if !pmu::is_present() {
+        println!("pmu: no SBI PMU extension");
+        return;
+    }
+    let count = match pmu::num_counters() {
+        Ok(count) => count,
+        Err(err) => {
+            println!("pmu: {}", err);
+            return;
+        }
+    };
+    for index in 0..count {
+        match pmu::counter_info(index) {
+            Ok(CounterInfo::Hardware { csr, width }) => {
+                println!(
+                    "{:>3}  hardware  csr {:#05x}, {} bits",
+                    index,
+                    csr,
+                    width + 1
+                )
+            }
+            Ok(CounterInfo::Firmware) => println!("{:>3}  firmware", index),
+            Err(err) => println!("{:>3}  {}", index, err),
+        }
+    }
+}
+
+fn perf(ctx: &Context<'_>, args: &[&str]) {
+    let Some(command) = args
+        .first()
+        .and_then(|&name| COMMANDS.iter().find(|command| command.name == name))
+    else {
+        println!("usage: perf <command> [args]");
+        return;
+    };
+    if !pmu::is_present() {
+        println!("perf: no SBI PMU extension");
+        return;
+    }
+    // The shell's task stays on this hart, and so do the counters.
+    let counters: Vec<_> = pmu::Event::ALL
+        .iter()
+        .map(|&event| (event, pmu::Counter::start(event)))
+        .collect();
+    let start = time::Instant::now();
+    (command.run)(ctx, &args[1..]);
+    let elapsed = start.elapsed();
+    println!();
+    for (event, counter) in &counters {
+        match counter
+            .as_ref()
+            .map_err(|&err| err)
+            .and_then(pmu::Counter::read)
+        {
+            Ok(count) => println!("{:>16}  {}", count, event),
+            Err(err) => println!("{:>16}  {} ({})", "-", event, err),
+        }
+    }
+    println!("{:>16}  elapsed", format!("{:?}", elapsed));
+}
+
+fn profile(_ctx: &Context<'_>, args: &[&str]) {
+    match args {
+        ["start"] | ["start", _] => {
+            let samples = match args.get(1) {
+                Some(arg) => match parse_number(arg) {
+                    Some(samples) => samples,
+                    None => {
+                        println!("profile: {}: invalid number of samples", arg);
+                        return;
+                    }
+                },
+                None => profile::DEFAULT_SAMPLES,
+            };
+            profile::start(samples);
+        }
+        ["stop"] => profile::stop(),
+        [] | ["top"] | ["top", _] => {
+            let count = args.get(1).and_then(|arg| parse_number(arg)).unwrap_or(20);
+            let report = profile::report();
+            println!(
+                "{} kernel samples, {} user, {} dropped{}",
+                report.kernel,
+                report.user,
+                report.dropped,
+                if profile::is_running() {
+                    " (running)"
+                } else {
+                    ""
+                }
+            );
+            for &(pc, samples) in report.hot.iter().take(count) {
+                print!(
+                    "{:#018x}  {:>6}  {:>3}%",
+                    pc,
+                    samples,
+                    samples * 100 / report.kernel.max(1)
+                );
+                match backtrace::symbol(pc) {
+                    Some((name, offset)) => println!("  {}+{:#x}", name, offset),
+                    None => println!(),
+                }
+            }
+        }
+        _ => println!("usage: profile [start [samples]|stop|top [n]]"),
+    }
+}
+
+fn stacks(_ctx: &Context<'_>, _args: &[&str]) {
+    let (usages, peak) = stack::usages();
+    for usage in usages {
+        println!(
+            "{:#018x}  {:>5} of {} bytes",
+            usage.top,
+            usage.used,
+            stack::STACK_SIZE
+        );
+    }
+    println!("peak of dropped stacks: {} bytes", peak);
+}
+
+fn cpuinfo(ctx: &Context<'_>, _args: &[&str]) {
+    cpuinfo::print(&ctx.dt, ctx.boot_hart);
+}
+
+fn ipi(_ctx: &Context<'_>, _args: &[&str]) {
+    smp::call_on(usize::MAX, || {
+        println!("hello from hart {}", percpu::hart_id())
+    });
+}
+
+fn user(_ctx: &Context<'_>, args: &[&str]) {
+    let name = args.first().copied().unwrap_or("init");
+    // Paths are files, and other names built-in programs.
+    let file;
+    let image = if name.contains('/') {
+        match fs::read_to_end(name) {
+            Ok(data) => {
+                file = data;
+                &file[..]
+            }
+            Err(err) => {
+                println!("user: {}: {}", name, err);
+                return;
+            }
+        }
+    } else if let Some(image) = user::program(name) {
+        image
+    } else {
+        println!("user: no program called {}", name);
+        println!("programs: {}", user::PROGRAMS.join(" "));
+        return;
+    };
+    match user::run(image) {
+        Some(pid) => println!("started process {}", pid),
+        None => println!("user: couldn't load {}", name),
+    }
+}
+
+fn ps(_ctx: &Context<'_>, _args: &[&str]) {
+    println!("  PID  PPID  THREADS  STATE");
+    for process in process::all() {
+        let ppid = process.parent().map(|parent| parent.pid());
+        print!("{:>5}  ", process.pid());
+        match ppid {
+            Some(ppid) => print!("{:>4}", ppid),
+            None => print!("{:>4}", "-"),
+        }
+        print!("  {:>7}  ", process.thread_count());
+        match process.exit_status() {
+            Some(status) => println!("zombie, {}", status),
+            None => println!("running"),
+        }
+    }
+}
+
+fn kill(_ctx: &Context<'_>, args: &[&str]) {
+    let Some(pid) = args.first().and_then(|arg| parse_number(arg)) else {
+        println!("usage: kill <pid> [signal]");
+        return;
+    };
+    let signal = match args.get(1) {
+        Some(arg) => match parse_number(arg).and_then(|n| u8::try_from(n).ok()) {
+            Some(signal) if (1..=signal::NSIG).contains(&signal) => signal,
+            _ => {
+                println!("kill: {}: invalid signal", arg);
+                return;
+            }
+        },
+        None => signal::SIGTERM,
+    };
+    match process::find(Pid::from_raw(pid)) {
+        Some(process) => process.send_signal(signal),
+        None => println!("kill: {}: no such process", pid),
+    }
+}
+
+fn virtio(_ctx: &Context<'_>, _args: &[&str]) {
+    println!("ADDRESS     VERSION  TYPE      VENDOR      IRQ  DRIVER");
+    for device in virtio::devices() {
+        print!(
+            "{:#010x}  {:>7}  {:<8}  {:#010x}  ",
+            device.paddr,
+            device.version,
+            virtio::type_name(device.device_type),
+            device.vendor
+        );
+        match device.irq {
+            Some(irq) => print!("{:>3}  ", irq),
+            None => print!("{:>3}  ", "-"),
+        }
+        println!("{}", device.driver.unwrap_or("-"));
+    }
+}
+
+fn drivers(_ctx: &Context<'_>, _args: &[&str]) {
+    for binding in drivers::bound() {
+        println!("{:<24} {}", binding.node, binding.driver);
+    }
+}
+
+fn lspci(_ctx: &Context<'_>, _args: &[&str]) {
+    for device in pci::devices() {
+        let (class, subclass, prog_if) = device.class;
+        print!(
+            "{}  {:04x}:{:04x} rev {:02x}  {:02x}{:02x}{:02x} {}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.revision,
+            class,
+            subclass,
+            prog_if,
+            pci::class_name(class)
+        );
+        match device.irq() {
+            Some(irq) => println!("  irq {}", irq),
+            None => println!(),
+        }
+        for index in 0..6 {
+            let Some(bar) = device.bar(index) else {
+                continue;
+            };
+            println!(
+                "    BAR {}: {:?} at {:#x}, {:#x} bytes{}",
+                index,
+                bar.kind,
+                bar.addr,
+                bar.size,
+                if bar.prefetchable {
+                    ", prefetchable"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+}
+
+fn lsblk(_ctx: &Context<'_>, _args: &[&str]) {
+    println!("NAME  BLOCK SIZE      BLOCKS  RO");
+    for (name, device) in block::devices() {
+        println!(
+            "{:<4}  {:>10}  {:>10}  {}",
+            name,
+            device.block_size(),
+            device.block_count(),
+            if device.is_read_only() { "yes" } else { "no" }
+        );
+    }
+}
+
+fn blk(_ctx: &Context<'_>, args: &[&str]) {
+    let Some(&name) = args.first() else {
+        println!("usage: blk <device> [block]");
+        return;
+    };
+    let start = match args.get(1).map(|arg| parse_number(arg)) {
+        Some(Some(start)) => start as u64,
+        Some(None) => {
+            println!("usage: blk <device> [block]");
+            return;
+        }
+        None => 0,
+    };
+    let Some(device) = block::find(name) else {
+        println!("blk: {}: no such device", name);
+        return;
+    };
+    let mut buf = vec![0; device.block_size()];
+    if let Err(err) = device.read_blocks(start, &mut buf) {
+        println!("blk: {}: {}", name, err);
+        return;
+    }
+    println!("{}", hexdump(start as usize * buf.len(), &buf));
+}
+
+fn mount(_ctx: &Context<'_>, args: &[&str]) {
+    if let ["-t", "9p", tag, path] = args {
+        let result = Share::connect(tag).and_then(|share| fs::mount(path, share));
+        if let Err(err) = result {
+            println!("mount: {}: {}", tag, err);
+        }
+        return;
+    }
+    let (Some(&name), Some(&path)) = (args.first(), args.get(1)) else {
+        for (path, fs) in fs::mounts() {
+            println!("{:<6} {}", fs, path);
+        }
+        return;
+    };
+    let Some(device) = block::find(name) else {
+        println!("mount: {}: no such device", name);
+        return;
+    };
+    let fat = match FatFs::mount(device) {
+        Ok(fat) => fat,
+        Err(err) => {
+            println!("mount: {}: {}", name, err);
+            return;
+        }
+    };
+    if let Err(err) = fs::mount(path, FatFileSystem::new(fat)) {
+        println!("mount: {}: {}", path, err);
+    }
+}
+
+fn umount(_ctx: &Context<'_>, args: &[&str]) {
+    let Some(&path) = args.first() else {
+        println!("usage: umount <path>");
+        return;
+    };
+    if let Err(err) = fs::unmount(path) {
+        println!("umount: {}: {}", path, err);
+    }
+}
+
+fn ls(_ctx: &Context<'_>, args: &[&str]) {
+    let path = args.first().copied().unwrap_or("/");
+    let result = fs::lookup(path).and_then(|dir| {
+        for entry in dir.read_dir()? {
+            let (size, suffix) = match entry.kind {
+                VnodeKind::Directory => (0, "/"),
+                VnodeKind::File | VnodeKind::Device => (dir.lookup(&entry.name)?.size(), ""),
+            };
+            println!("{:>10}  {}{}", size, entry.name, suffix);
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        println!("ls: {}: {}", path, err);
+    }
+}
+
+fn cat(_ctx: &Context<'_>, args: &[&str]) {
+    let Some(&path) = args.first() else {
+        println!("usage: cat <path>");
+        return;
+    };
+    let result = fs::open(path, fs::O_RDONLY).and_then(|file| {
+        let mut buf = vec![0; 512];
+        loop {
+            let len = file.read(&mut buf)?;
+            if len == 0 {
+                return Ok(());
+            }
+            io::write(&buf[..len]);
+        }
+    });
+    if let Err(err) = result {
+        println!("cat: {}: {}", path, err);
+    }
+}
+
+fn hexdump_file(_ctx: &Context<'_>, args: &[&str]) {
+    let Some(&path) = args.first() else {
+        println!("usage: hexdump <path>");
+        return;
+    };
+    let result = fs::open(path, fs::O_RDONLY).and_then(|file| {
+        let mut buf = vec![0; 512];
+        let mut offset = 0;
+        loop {
+            // Only whole buffers are printed before the end, so that lines
+            // don't get split.
+            let mut len = 0;
+            while len < buf.len() {
+                match file.read(&mut buf[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            if len == 0 {
+                return Ok(());
+            }
+            println!("{}", hexdump(offset, &buf[..len]));
+            offset += len;
+        }
+    });
+    if let Err(err) = result {
+        println!("hexdump: {}: {}", path, err);
+    }
+}
+
+fn write(_ctx: &Context<'_>, args: &[&str]) {
+    let Some(&path) = args.first() else {
+        println!("usage: write <path> <text>");
+        return;
+    };
+    let mut text = args[1..].join(" ");
+    text.push('\n');
+    let result = fs::open(path, fs::O_WRONLY | fs::O_CREAT | fs::O_TRUNC)
+        .and_then(|file| file.write(text.as_bytes()));
+    if let Err(err) = result {
+        println!("write: {}: {}", path, err);
+    }
+}
+
+fn mkdir(_ctx: &Context<'_>, args: &[&str]) {
+    let Some(&path) = args.first() else {
+        println!("usage: mkdir <path>");
+        return;
+    };
+    if let Err(err) = fs::mkdir(path) {
+        println!("mkdir: {}: {}", path, err);
+    }
+}
+
+fn rm(_ctx: &Context<'_>, args: &[&str]) {
+    let (dir, path) = match args {
+        ["-d", path] => (true, path),
+        [path] => (false, path),
+        _ => {
+            println!("usage: rm [-d] <path>");
+            return;
+        }
+    };
+    if let Err(err) = fs::remove(path, dir) {
+        println!("rm: {}: {}", path, err);
+    }
+}
+
+/// Syncs every filesystem and the block caches they've written to.
+fn sync_all() {
+    if let Err(err) = fs::sync_all() {
+        println!("sync: {}", err);
+    }
+}
+
+fn sync(_ctx: &Context<'_>, _args: &[&str]) {
+    sync_all();
+}
+
+fn ifconfig(_ctx: &Context<'_>, _args: &[&str]) {
+    for interface in net::interfaces() {
+        print!(
+            "{}  {}  mtu {}  ",
+            interface.name(),
+            interface.mac(),
+            interface.mtu()
+        );
+        match interface.config() {
+            Some(config) => println!("{}", config),
+            None => println!("no address"),
+        }
+    }
+}
+
+fn arp(_ctx: &Context<'_>, _args: &[&str]) {
+    for (ip, mac) in net::arp::entries() {
+        println!("{:<15}  {}", ip, mac);
+    }
+}
+
+fn ping(_ctx: &Context<'_>, args: &[&str]) {
+    let Some(Ok(addr)) = args.first().map(|arg| arg.parse::<Ipv4Addr>()) else {
+        println!("usage: ping <addr> [count]");
+        return;
+    };
+    let count = match args.get(1).map(|arg| parse_number(arg)) {
+        Some(Some(count)) => count,
+        Some(None) => {
+            println!("usage: ping <addr> [count]");
+            return;
+        }
+        None => 4,
+    };
+    let mut received = 0;
+    for seq in 0..count {
+        match icmp::ping(addr, seq as u16, Duration::from_secs(1)) {
+            Ok(rtt) => {
+                println!("reply from {}: seq={} time={:?}", addr, seq, rtt);
+                received += 1;
+            }
+            Err(err) => println!("ping {}: seq={}: {}", addr, seq, err),
+        }
+    }
+    println!("{} sent, {} received", count, received);
+}
+
+fn nc(_ctx: &Context<'_>, args: &[&str]) {
+    let result = match args {
+        ["-l", port] => port.parse().ok().map(nc_listen),
+        ["-u", addr, port, text @ ..] => match (addr.parse(), port.parse()) {
+            (Ok(addr), Ok(port)) => Some(nc_udp(addr, port, &text.join(" "))),
+            _ => None,
+        },
+        [addr, port, text @ ..] => match (addr.parse(), port.parse()) {
+            (Ok(addr), Ok(port)) => Some(nc_connect(addr, port, &text.join(" "))),
+            _ => None,
+        },
+        _ => None,
+    };
+    match result {
+        Some(Ok(())) => {}
+        Some(Err(err)) => println!("nc: {}", err),
+        None => println!("usage: nc [-u] <addr> <port> [text] | nc -l <port>"),
+    }
+}
+
+/// Prints what's received on `stream` until the peer closes it, or nothing
+/// comes for `idle`.
+fn nc_print(stream: &TcpStream, idle: Option<Duration>) -> Result<(), net::NetError> {
+    let mut buf = vec![0; 512];
+    loop {
+        match stream.read(&mut buf, idle) {
+            Ok(0) | Err(net::NetError::TimedOut) => return Ok(()),
+            Ok(len) => io::write(&buf[..len]),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn nc_connect(addr: Ipv4Addr, port: u16, text: &str) -> Result<(), net::NetError> {
+    let stream = TcpStream::connect(addr, port)?;
+    if !text.is_empty() {
+        stream.write_all(text.as_bytes())?;
+        stream.write_all(b"\n")?;
+    }
+    nc_print(&stream, Some(Duration::from_secs(5)))
+}
+
+fn nc_listen(port: u16) -> Result<(), net::NetError> {
+    let listener = TcpListener::bind(port)?;
+    let stream = listener.accept(None)?;
+    let (addr, port) = stream.peer();
+    println!("connection from {}:{}", addr, port);
+    nc_print(&stream, None)
+}
+
+fn nc_udp(addr: Ipv4Addr, port: u16, text: &str) -> Result<(), net::NetError> {
+    let socket = UdpSocket::bind(0)?;
+    socket.send_to(format!("{}\n", text).as_bytes(), addr, port)?;
+    let mut buf = vec![0; 1500];
+    let (len, from, from_port) = socket.recv_from(&mut buf, Some(Duration::from_secs(2)))?;
+    println!("reply from {}:{}", from, from_port);
+    io::write(&buf[..len]);
+    Ok(())
+}
+
+fn gdb(_ctx: &Context<'_>, _args: &[&str]) {
+    crate::gdb::breakpoint();
+}
+
+fn reboot(_ctx: &Context<'_>, _args: &[&str]) {
+    sync_all();
+    let err = sbi::reset::reboot();
+    println!("reboot: {}", err);
+}
+
+fn exit(_ctx: &Context<'_>, args: &[&str]) {
+    let code = match args.first() {
+        Some(arg) => match parse_number(arg).and_then(|n| u32::try_from(n).ok()) {
+            Some(code) => code,
+            None => {
+                println!("exit: {}: invalid status", arg);
+                return;
+            }
+        },
+        None => 0,
+    };
+    sync_all();
+    machine::exit(code);
+}
+
+fn shutdown(_ctx: &Context<'_>, _args: &[&str]) {
+    sync_all();
+    sbi::reset::shutdown(ResetReason::None);
+    println!("shutdown: not supported by the SBI implementation");
+}
@@ -0,0 +1,106 @@
+//! Kernel tests. `ktest!` declares a test, which goes in the `.ktests`
+//! section when the kernel is built with the `ktest` feature and is left out
+//! otherwise. A `ktest` kernel runs every test once it has booted instead of
+//! starting the shell, reporting on the console, and then exits QEMU through
+//! the test finisher: `cargo run --features ktest`.
+//!
+//! Tests pass by returning and fail by panicking. There's no unwinding, so
+//! the first failure ends the run, with the panic handler's report and a
+//! failing exit status. `ktest=<substring>` on the command line runs only
+//! the tests whose name contains it, and `ktest=<a>,<b>` those whose name
+//! contains either.
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::{cmdline, machine, print, println, time};
+
+extern "C" {
+    static _sktests: u8;
+    static _ektests: u8;
+}
+
+pub struct Test {
+    /// The test's path, with the module it's in.
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+/// Declares a kernel test: `ktest! { fn name() { ... } }`.
+#[macro_export]
+macro_rules! ktest {
+    ($(#[$attr:meta])* fn $name:ident() $body:block) => {
+        #[cfg(feature = "ktest")]
+        const _: () = {
+            $(#[$attr])*
+            fn $name() $body
+
+            #[used]
+            #[link_section = ".ktests"]
+            static TEST: $crate::ktest::Test = $crate::ktest::Test {
+                name: concat!(module_path!(), "::", stringify!($name)),
+                run: $name,
+            };
+        };
+    };
+}
+
+/// The test that's running, for the panic handler to name.
+static RUNNING: AtomicPtr<Test> = AtomicPtr::new(core::ptr::null_mut());
+
+fn tests() -> &'static [Test] {
+    // SAFETY: the linker puts the tests next to each other between the two
+    // symbols.
+    unsafe {
+        let start = &raw const _sktests as *const Test;
+        let end = &raw const _ektests as *const Test;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// The name of the test that's running, if any.
+pub fn running() -> Option<&'static str> {
+    // SAFETY: `RUNNING` is null or points into `tests()`.
+    unsafe { RUNNING.load(Ordering::Relaxed).as_ref() }.map(|test| test.name)
+}
+
+/// Whether `ktest=` asks for the test `name`, with `filter` its value.
+fn selected(name: &str, filter: &str) -> bool {
+    filter.split(',').any(|part| name.contains(part))
+}
+
+/// Runs the tests and exits with whether they passed.
+pub fn run_all() -> ! {
+    let filter = cmdline::get("ktest").unwrap_or("");
+    let all = tests();
+    let tests = all.iter().filter(|test| selected(test.name, filter));
+    let count = tests.clone().count();
+    println!("running {} tests", count);
+    let start = time::Instant::now();
+    let mut passed = 0;
+    for test in tests {
+        print!("test {} ... ", test.name);
+        RUNNING.store(test as *const Test as *mut Test, Ordering::Relaxed);
+        (test.run)();
+        RUNNING.store(core::ptr::null_mut(), Ordering::Relaxed);
+        println!("ok");
+        passed += 1;
+    }
+    println!(
+        "test result: ok. {} passed; {} filtered out; finished in {:?}",
+        passed,
+        all.len() - count,
+        start.elapsed()
+    );
+    machine::exit(0);
+}
+
+ktest! {
+    fn selects_tests() {
+        let name = "annwn::net::udp::queues_datagrams_for_bound_ports";
+        assert!(selected(name, ""));
+        assert!(selected(name, "net::"));
+        assert!(selected(name, "fs::,udp"));
+        assert!(!selected(name, "fs::"));
+        assert!(!selected(name, "fs::,tcp"));
+    }
+}
@@ -0,0 +1,18 @@
+//! Stopping the machine as a whole.
+
+use crate::drivers::sifive_test;
+use crate::sbi::reset::{self, ResetReason};
+use crate::smp;
+
+/// Powers off the machine, with exit status `code` for whatever started it:
+/// QEMU exits with it when there's a test finisher. Without one the status
+/// can only be told apart as zero or not, as an SBI reset reason.
+pub fn exit(code: u32) -> ! {
+    sifive_test::finish(code);
+    let reason = match code {
+        0 => ResetReason::None,
+        _ => ResetReason::SystemFailure,
+    };
+    reset::shutdown(reason);
+    smp::halt();
+}
@@ -1,59 +1,192 @@
 #![no_std]
 #![no_main]
 
-use dtb::{DeviceTree, DtNode};
+extern crate alloc;
 
-core::arch::global_asm!(include_str!("start.s"));
+use dtb::DeviceTree;
+
+core::arch::global_asm!(concat!(arch::asm_prelude!(), include_str!("start.s")));
 
 #[no_mangle]
 extern "C" fn kmain(hart_id: usize, dtb: *const u8) -> ! {
+    percpu::init_boot_hart(hart_id);
+    io::init_early();
     println!();
     println!("Annwn v{}", env!("CARGO_PKG_VERSION"));
     println!("booting on hart {}", hart_id);
 
-    let dt = unsafe { DeviceTree::from_ptr(dtb).unwrap() };
+    let start = time::Instant::now();
+    trap::init();
+
+    let dtb = mm::phys_to_virt(dtb as usize) as *const u8;
+    let mut dt: DeviceTree<'static> = match unsafe { DeviceTree::from_ptr(dtb) } {
+        Ok(dt) => dt,
+        Err(err) => {
+            log_error!("invalid device tree at {:p}: {}", dtb, err);
+            smp::halt();
+        }
+    };
+
+    if let Some(chosen) = dt.chosen() {
+        if let Some(bootargs) = chosen.bootargs {
+            cmdline::init(bootargs);
+        }
+        io::init_log_level();
+        if let Some(path) = chosen.stdout_path {
+            log_debug!("stdout-path: {}", path);
+        }
+        if let Some(initrd) = chosen.initrd {
+            log_info!("initrd: {:#x}..{:#x}", initrd.start, initrd.end);
+        }
+    }
+    log_info!("Kernel command line: {}", cmdline::as_str());
+    panic::init();
+
     for resv in dt.memory_reservations() {
-        println!(
+        log_debug!(
             "Memory Reservation: address = {:#x}, size = {:#x}",
-            resv.address, resv.size
+            resv.address,
+            resv.size
         );
     }
 
-    let root = dt.root_node();
-    show_node(root, 0);
-
-    fn indent(depth: usize) {
-        for _ in 0..depth {
-            print!("    ");
-        }
+    if io::log_enabled(io::Level::Debug) {
+        cpuinfo::print(&dt, hart_id);
     }
-    loop {}
-
-    fn show_node(node: DtNode<'_>, depth: usize) {
-        indent(depth);
-        println!("{} : {{", node.name);
-        for prop in node.properties() {
-            indent(depth);
-            println!("    {} = {:?};", prop.name, prop.value);
+
+    mm::frame::init(&dt);
+    mm::paging::init(&dt);
+    dt.build_index();
+    crashdump::init(&dt);
+    mm::dma::init(&dt);
+    mm::tlb::init();
+
+    // Move off the stack in the kernel image, which has no guard.
+    mm::stack::init_hart();
+    let stack = mm::stack::KernelStack::new().expect("no memory for the boot stack");
+    // SAFETY: nothing on this stack is used again.
+    unsafe { mm::stack::run_on(stack, move || boot(hart_id, dt, start)) }
+}
+
+fn boot(hart_id: usize, dt: DeviceTree<'static>, start: time::Instant) -> ! {
+    let frames = mm::frame::stats();
+    log_info!(
+        "Frames: {} KiB free of {} KiB",
+        frames.free * mm::frame::FRAME_SIZE / 1024,
+        frames.total * mm::frame::FRAME_SIZE / 1024
+    );
+
+    time::init(&dt);
+    vdso::init();
+    arch::fpu::init(&dt);
+    idle::init(&dt);
+    sbi::timer::init(&dt);
+    log_info!(
+        "Timer: {} Hz timebase, {} Hz tick{}",
+        time::frequency(),
+        sbi::timer::TICK_HZ,
+        if sbi::timer::has_sstc_timer() {
+            ", Sstc"
+        } else {
+            ""
         }
-        for node in node.children() {
-            show_node(node, depth + 1);
+    );
+
+    smp::init(&dt, hart_id);
+    task::init();
+    workqueue::init();
+    executor::init();
+    watchdog::init();
+    block::writeback::init();
+    drivers::probe_all(&dt);
+    // Until now the console's been SBI, as the UART's interrupt needs the
+    // PLIC probed.
+    io::init(&dt);
+    gdb::init(&dt);
+    rand::init();
+    net::init();
+    block::ramdisk::init(&dt);
+    fs::initramfs::init(&dt);
+    fs::ninep::init();
+    fs::devfs::init();
+    pty::init();
+    vt::init();
+    input::init();
+    fs::procfs::init(&dt, hart_id);
+    backtrace::init();
+
+    let root = dt.root_node();
+    for memory in root.children().filter(|node| node.base_name() == "memory") {
+        for reg in memory.reg().into_iter().flatten() {
+            log_debug!(
+                "Memory: address = {:#x}, size = {:#x}",
+                reg.address,
+                reg.size
+            );
         }
-        indent(depth);
-        println!("}};");
     }
-}
 
-#[panic_handler]
-fn panic_handler(_info: &core::panic::PanicInfo) -> ! {
-    loop {}
+    log_info!("Booted in {:?}", start.elapsed());
+
+    if cfg!(feature = "ktest") {
+        ktest::run_all();
+    }
+
+    ksh::run(&ksh::Context {
+        dt,
+        boot_hart: hart_id,
+    });
 }
 
+mod arch;
+mod backtrace;
+mod block;
+mod cmdline;
+mod console;
+mod coredump;
+mod cpuinfo;
+mod crashdump;
+mod drivers;
 mod dtb;
+mod elf;
+mod executor;
+mod fb;
+mod file;
+mod fs;
+mod gdb;
+mod idle;
+mod input;
 mod io;
-
-mod util {
-    pub fn align_up(value: usize, align: usize) -> usize {
-        (value + align - 1) & !(align - 1)
-    }
-}
+mod kmsg;
+mod ksh;
+mod ktest;
+mod machine;
+mod mm;
+mod mqueue;
+mod net;
+mod oom;
+mod panic;
+mod percpu;
+mod perf;
+mod pipe;
+mod process;
+mod profile;
+mod ptrace;
+mod pty;
+mod rand;
+mod rlimit;
+mod sbi;
+mod signal;
+mod smp;
+mod sync;
+mod syscall;
+mod task;
+mod time;
+mod trap;
+mod tty;
+mod user;
+mod util;
+mod vdso;
+mod vt;
+mod watchdog;
+mod workqueue;
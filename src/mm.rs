@@ -0,0 +1,43 @@
+pub mod addr_space;
+pub mod dma;
+pub mod frame;
+pub mod heap;
+pub mod mmio;
+pub mod paging;
+pub mod shm;
+pub mod slab;
+pub mod stack;
+pub mod tlb;
+pub mod uaccess;
+
+pub use mmio::ioremap;
+
+/// All of physical memory is mapped at this offset in the kernel's address
+/// space, and the kernel image is linked inside that mapping. Must match
+/// KERNEL_OFFSET in build.rs. With Sv39 it's the upper half; with Sv32
+/// there's only room for the 3 GiB from 1 GiB up, less the kernel stacks
+/// at the top, and the first 1 GiB is for user space.
+#[cfg(target_arch = "riscv64")]
+pub const PHYS_OFFSET: usize = 0xffff_ffc0_0000_0000;
+#[cfg(target_arch = "riscv32")]
+pub const PHYS_OFFSET: usize = 0x4000_0000;
+
+/// The end of the physical memory mapping, below the kernel stacks. RAM
+/// above it isn't used, which only happens with Sv32.
+pub const PHYS_MAP_END: usize = stack::REGION - PHYS_OFFSET;
+
+pub fn phys_to_virt(paddr: usize) -> usize {
+    paddr + PHYS_OFFSET
+}
+
+/// Only valid for addresses in the physical memory mapping, i.e. the kernel
+/// image and anything returned by `phys_to_virt`, and not for kernel stacks
+/// or anything else mapped on its own; `paging::translate` finds those.
+pub fn virt_to_phys(vaddr: usize) -> usize {
+    debug_assert!(
+        (PHYS_OFFSET..stack::REGION).contains(&vaddr),
+        "{:#x} isn't in the physical memory mapping",
+        vaddr
+    );
+    vaddr - PHYS_OFFSET
+}
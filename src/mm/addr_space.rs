@@ -0,0 +1,920 @@
+//! User address spaces: a page table whose lower half maps pages owned by
+//! the address space, and the list of virtual memory areas (VMAs) saying
+//! what may be mapped where.
+//!
+//! Pages are allocated lazily where possible. A VMA can be reserved up
+//! front and its pages allocated zeroed on the first fault, and `fork`
+//! shares pages between the two address spaces, copying a writable page
+//! only once one of them writes to it. A VMA can instead map shared memory
+//! (see `shm`), whose pages are never copied, even by `fork`, or map the
+//! pages of a file's shared memory privately, copying each the first time
+//! it's written to, as `MAP_PRIVATE` mappings of files do.
+//!
+//! The harts an address space is active on are tracked, so that changes to
+//! it are flushed from their TLBs, and only theirs.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicUsize;
+
+use crate::ktest;
+use crate::mm::frame::{self, FRAME_SIZE};
+use crate::mm::paging::{MapError, PageSize, PageTable, PteFlags, PAGE_SIZE};
+use crate::mm::phys_to_virt;
+use crate::mm::shm::SharedMemory;
+use crate::mm::tlb::{self, TlbBatch};
+use crate::sync::SpinLock;
+use crate::util::{align_down, align_up};
+
+/// User addresses are below this, in the lower half of the Sv39 address
+/// space, or below the physical memory mapping in Sv32's.
+#[cfg(target_arch = "riscv64")]
+pub const USER_END: usize = 1 << 38;
+#[cfg(target_arch = "riscv32")]
+pub const USER_END: usize = crate::mm::PHYS_OFFSET;
+
+/// Nothing is mapped below this by `find_free`, so that null pointer
+/// dereferences fault.
+const MIN_FREE_ADDR: usize = 0x1_0000;
+
+/// Marks a page that was writable before being shared by `fork`.
+const COW: PteFlags = PteFlags::SW;
+
+/// The number of address spaces mapping each frame shared by `fork`, for
+/// frames mapped by more than one.
+static SHARED: SpinLock<BTreeMap<usize, usize>> = SpinLock::new(BTreeMap::new());
+
+/// The kind of access that faulted.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    /// The page flags needed for this access from U-mode.
+    fn required(self) -> PteFlags {
+        match self {
+            Self::Read => PteFlags::U | PteFlags::R,
+            Self::Write => PteFlags::U | PteFlags::R | PteFlags::W,
+            Self::Execute => PteFlags::U | PteFlags::X,
+        }
+    }
+}
+
+/// Why a page fault couldn't be handled.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultError {
+    /// U-mode may not access the page that way.
+    Denied,
+    /// There's no memory for the page.
+    OutOfMemory,
+}
+
+pub struct AddressSpace {
+    satp: usize,
+    /// The mask of harts the address space is active on.
+    active: AtomicUsize,
+    inner: SpinLock<Inner>,
+}
+
+/// A virtual memory area: a range of pages U-mode may access with `flags`.
+/// Its pages are zeroed when first mapped, unless it's shared.
+#[derive(Clone)]
+struct Vma {
+    end: usize,
+    flags: PteFlags,
+    shared: Option<SharedRegion>,
+}
+
+/// Where a shared VMA's pages come from: `memory`, whose page `first` is
+/// mapped at `base`. Parts of a VMA split off keep the same `base`. If
+/// `private`, its pages are copied when written to rather than shared.
+#[derive(Clone)]
+struct SharedRegion {
+    memory: Arc<SharedMemory>,
+    base: usize,
+    first: usize,
+    private: bool,
+}
+
+impl SharedRegion {
+    /// The index of the page of `memory` mapped at `page`.
+    fn index(&self, page: usize) -> usize {
+        self.first + (page - self.base) / PAGE_SIZE
+    }
+}
+
+/// The program break, the end of the heap grown by `brk`.
+#[derive(Clone, Copy)]
+struct Brk {
+    start: usize,
+    current: usize,
+}
+
+struct Inner {
+    table: PageTable,
+    /// The frame mapped at each page of the address space, freed with it
+    /// unless it's still shared.
+    pages: BTreeMap<usize, usize>,
+    /// VMAs by start address. Every mapped page is in one.
+    vmas: BTreeMap<usize, Vma>,
+    brk: Option<Brk>,
+    /// The most the VMAs can add up to, in bytes: `RLIMIT_AS`.
+    size_limit: usize,
+}
+
+impl Inner {
+    fn vma(&self, vaddr: usize) -> Option<&Vma> {
+        self.vmas
+            .range(..=vaddr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vaddr < vma.end)
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.vmas
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, vma)| vma.end > start)
+    }
+
+    fn add_vma(
+        &mut self,
+        start: usize,
+        end: usize,
+        flags: PteFlags,
+        shared: Option<SharedRegion>,
+    ) -> Result<(), MapError> {
+        if self.overlaps(start, end) {
+            return Err(MapError::AlreadyMapped);
+        }
+        let size: usize = self.vmas.iter().map(|(&start, vma)| vma.end - start).sum();
+        if size + (end - start) > self.size_limit {
+            return Err(MapError::OutOfMemory);
+        }
+        self.vmas.insert(start, Vma { end, flags, shared });
+        Ok(())
+    }
+
+    /// Removes `start..end` from the VMAs, splitting those it covers part
+    /// of, and unmaps the pages in it. Returns the VMAs removed, to be
+    /// dropped once the lock is released: dropping the last mapping of a
+    /// file's pages writes them back, which may sleep.
+    #[must_use]
+    fn remove_range(&mut self, start: usize, end: usize, tlb: &mut TlbBatch<'_>) -> Vec<Vma> {
+        let overlapping: Vec<_> = self
+            .vmas
+            .range(..end)
+            .filter(|(_, vma)| vma.end > start)
+            .map(|(&vma_start, vma)| (vma_start, vma.clone()))
+            .collect();
+        let mut removed = Vec::new();
+        for (vma_start, vma) in overlapping {
+            removed.extend(self.vmas.remove(&vma_start));
+            if vma_start < start {
+                self.vmas.insert(
+                    vma_start,
+                    Vma {
+                        end: start,
+                        ..vma.clone()
+                    },
+                );
+            }
+            if vma.end > end {
+                self.vmas.insert(end, vma);
+            }
+        }
+
+        let pages: Vec<_> = self
+            .pages
+            .range(start..end)
+            .map(|(&v, &p)| (v, p))
+            .collect();
+        for &(vaddr, _) in &pages {
+            self.table.unmap(vaddr, tlb);
+            self.pages.remove(&vaddr);
+        }
+        tlb.flush();
+        for (_, paddr) in pages {
+            // SAFETY: the page was just unmapped, and flushed everywhere.
+            unsafe { release_frame(paddr) };
+        }
+        removed
+    }
+
+    /// Whether U-mode may access the page at `page` in the way `access`,
+    /// possibly after a fault.
+    fn allows(&self, page: usize, access: Access) -> bool {
+        match self.table.lookup(page) {
+            Some((_, flags)) => {
+                // Copy-on-write pages are writable, as far as U-mode knows.
+                let flags = if flags.contains(COW) {
+                    flags | PteFlags::W
+                } else {
+                    flags
+                };
+                flags.contains(access.required())
+            }
+            None => self
+                .vma(page)
+                .is_some_and(|vma| (vma.flags | PteFlags::U).contains(access.required())),
+        }
+    }
+
+    /// Gives `page`, a copy-on-write page mapping `paddr`, a frame of its
+    /// own and makes it writable again.
+    fn copy_on_write(
+        &mut self,
+        page: usize,
+        paddr: usize,
+        flags: PteFlags,
+        tlb: &mut TlbBatch<'_>,
+    ) -> bool {
+        self.make_private(page, paddr, (flags | PteFlags::W).without(COW), tlb)
+    }
+
+    /// Gives `page`, mapping `paddr`, a frame of its own if it's shared,
+    /// and maps it with `flags`.
+    fn make_private(
+        &mut self,
+        page: usize,
+        paddr: usize,
+        flags: PteFlags,
+        tlb: &mut TlbBatch<'_>,
+    ) -> bool {
+        if !is_shared(paddr) {
+            // Everyone else has since dropped it, so it's ours.
+            return self.table.set_flags(page, flags, tlb);
+        }
+        let Some(new) = frame::alloc_frame() else {
+            return false;
+        };
+        // SAFETY: both frames are mapped in physical memory, and the new one
+        // was just allocated.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(paddr) as *const u8,
+                phys_to_virt(new) as *mut u8,
+                FRAME_SIZE,
+            )
+        };
+        self.table.unmap(page, tlb);
+        self.table
+            .map(page, new, PageSize::Size4K, flags)
+            .expect("remapping a page failed");
+        self.pages.insert(page, new);
+        tlb.flush();
+        // SAFETY: this address space no longer maps it, even in a TLB.
+        unsafe { release_frame(paddr) };
+        true
+    }
+
+    /// Maps `paddr`, the page of `region`'s shared memory that goes at
+    /// `page`, copy-on-write if the region is private.
+    fn map_shared_page(
+        &mut self,
+        page: usize,
+        paddr: usize,
+        mut flags: PteFlags,
+        region: &SharedRegion,
+    ) -> Result<(), MapError> {
+        if region.private && flags.contains(PteFlags::W) {
+            flags = flags.without(PteFlags::W) | COW;
+        }
+        self.table
+            .map(page, paddr, PageSize::Size4K, flags | PteFlags::U)?;
+        share_frame(paddr);
+        self.pages.insert(page, paddr);
+        Ok(())
+    }
+
+    /// Whether `page` is in a shared VMA, whose pages aren't copied.
+    fn is_shared_page(&self, page: usize) -> bool {
+        self.vma(page)
+            .and_then(|vma| vma.shared.as_ref())
+            .is_some_and(|region| !region.private)
+    }
+
+    /// Allocates and maps a zeroed page at `page`.
+    fn map_zeroed_page(&mut self, page: usize, flags: PteFlags) -> Result<(), MapError> {
+        let paddr = frame::alloc_frame().ok_or(MapError::OutOfMemory)?;
+        // SAFETY: the frame was just allocated and is mapped at
+        // `phys_to_virt(paddr)`.
+        unsafe { core::ptr::write_bytes(phys_to_virt(paddr) as *mut u8, 0, FRAME_SIZE) };
+        if let Err(err) = self
+            .table
+            .map(page, paddr, PageSize::Size4K, flags | PteFlags::U)
+        {
+            // SAFETY: the frame was never mapped.
+            unsafe { frame::free_frame(paddr) };
+            return Err(err);
+        }
+        self.pages.insert(page, paddr);
+        Ok(())
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for &paddr in self.pages.values() {
+            // SAFETY: the address space is no longer in use.
+            unsafe { release_frame(paddr) };
+        }
+    }
+}
+
+fn is_shared(paddr: usize) -> bool {
+    SHARED.lock().contains_key(&paddr)
+}
+
+/// Records that another address space, or shared memory, holds `paddr`.
+pub(super) fn share_frame(paddr: usize) {
+    *SHARED.lock().entry(paddr).or_insert(1) += 1;
+}
+
+/// Drops an address space's reference to `paddr`, freeing it if it was the
+/// last.
+///
+/// SAFETY: the caller's address space must no longer map `paddr`.
+pub(super) unsafe fn release_frame(paddr: usize) {
+    let mut shared = SHARED.lock();
+    match shared.get_mut(&paddr) {
+        Some(2) => {
+            shared.remove(&paddr);
+        }
+        Some(count) => *count -= 1,
+        None => frame::free_frame(paddr),
+    }
+}
+
+fn is_user_range(vaddr: usize, len: usize) -> bool {
+    vaddr.is_multiple_of(PAGE_SIZE)
+        && len.is_multiple_of(PAGE_SIZE)
+        && vaddr.checked_add(len).is_some_and(|end| end <= USER_END)
+}
+
+impl AddressSpace {
+    pub fn new() -> Option<Self> {
+        let table = PageTable::new_user()?;
+        Some(Self {
+            satp: table.satp(),
+            active: AtomicUsize::new(0),
+            inner: SpinLock::new(Inner {
+                table,
+                pages: BTreeMap::new(),
+                vmas: BTreeMap::new(),
+                brk: None,
+                size_limit: usize::MAX,
+            }),
+        })
+    }
+
+    /// The value to write to `satp` to switch to this address space.
+    pub fn satp(&self) -> usize {
+        self.satp
+    }
+
+    /// Notes that this hart is about to switch to the address space.
+    pub fn enter(&self) {
+        tlb::enter(&self.active);
+    }
+
+    /// Notes that this hart has switched away from the address space.
+    pub fn leave(&self) {
+        tlb::leave(&self.active);
+    }
+
+    /// Maps zeroed pages over `len` bytes at the page-aligned `vaddr`,
+    /// accessible to U-mode with `flags`.
+    pub fn map_zeroed(&self, vaddr: usize, len: usize, flags: PteFlags) -> Result<(), MapError> {
+        if !is_user_range(vaddr, len) {
+            return Err(MapError::Misaligned);
+        }
+        let mut inner = self.inner.lock();
+        inner.add_vma(vaddr, vaddr + len, flags, None)?;
+        for page in (vaddr..vaddr + len).step_by(PAGE_SIZE) {
+            inner.map_zeroed_page(page, flags)?;
+        }
+        Ok(())
+    }
+
+    /// Reserves `len` bytes at the page-aligned `vaddr` for zeroed pages
+    /// accessible to U-mode with `flags`, allocated when first accessed.
+    pub fn map_lazy(&self, vaddr: usize, len: usize, flags: PteFlags) -> Result<(), MapError> {
+        if !is_user_range(vaddr, len) {
+            return Err(MapError::Misaligned);
+        }
+        self.inner.lock().add_vma(vaddr, vaddr + len, flags, None)
+    }
+
+    /// Maps `memory` over `len` bytes at the page-aligned `vaddr`,
+    /// accessible to U-mode with `flags`, from its first page. Pages are
+    /// mapped when first accessed.
+    pub fn map_shared(
+        &self,
+        vaddr: usize,
+        len: usize,
+        flags: PteFlags,
+        memory: Arc<SharedMemory>,
+    ) -> Result<(), MapError> {
+        self.map_pages(vaddr, len, flags, memory, 0, false)
+    }
+
+    /// Maps `memory` over `len` bytes at the page-aligned `vaddr`,
+    /// accessible to U-mode with `flags`, from page `first`. If `private`,
+    /// each page is copied the first time it's written to, rather than the
+    /// write going to `memory`. Pages are mapped when first accessed.
+    pub fn map_pages(
+        &self,
+        vaddr: usize,
+        len: usize,
+        flags: PteFlags,
+        memory: Arc<SharedMemory>,
+        first: usize,
+        private: bool,
+    ) -> Result<(), MapError> {
+        if !is_user_range(vaddr, len) {
+            return Err(MapError::Misaligned);
+        }
+        let region = SharedRegion {
+            memory,
+            base: vaddr,
+            first,
+            private,
+        };
+        self.inner
+            .lock()
+            .add_vma(vaddr, vaddr + len, flags, Some(region))
+    }
+
+    /// Unmaps `len` bytes at the page-aligned `vaddr`, any of which may
+    /// already be unmapped.
+    pub fn unmap(&self, vaddr: usize, len: usize) -> Result<(), MapError> {
+        if !is_user_range(vaddr, len) {
+            return Err(MapError::Misaligned);
+        }
+        let mut tlb = TlbBatch::new(&self.active);
+        let removed = self.inner.lock().remove_range(vaddr, vaddr + len, &mut tlb);
+        drop(removed);
+        Ok(())
+    }
+
+    /// Finds `len` bytes of unmapped, page-aligned address space: at `hint`,
+    /// rounded down to a page, if that's free, otherwise ending at or below
+    /// `top`, as high as possible. Either way, not in the lowest pages.
+    pub fn find_free(&self, len: usize, top: usize, hint: Option<usize>) -> Option<usize> {
+        let inner = self.inner.lock();
+        if let Some(hint) = hint.map(|hint| hint & !(PAGE_SIZE - 1)) {
+            if hint >= MIN_FREE_ADDR
+                && is_user_range(hint, len)
+                && !inner.overlaps(hint, hint + len)
+            {
+                return Some(hint);
+            }
+        }
+        let mut end = top;
+        for (&start, vma) in inner.vmas.range(..top).rev() {
+            if vma.end <= end && end - vma.end >= len {
+                break;
+            }
+            end = end.min(start);
+        }
+        end.checked_sub(len).filter(|&start| start >= MIN_FREE_ADDR)
+    }
+
+    /// Stops mappings that would make the address space bigger than `limit`
+    /// bytes. Those already there stay.
+    pub fn set_size_limit(&self, limit: usize) {
+        self.inner.lock().size_limit = limit;
+    }
+
+    /// Sets where the heap starts, just past the loaded program.
+    pub fn init_brk(&self, start: usize) {
+        self.inner.lock().brk = Some(Brk {
+            start,
+            current: start,
+        });
+    }
+
+    /// Moves the program break to `new`, returning where it ends up: at
+    /// `new`, or where it was if it can't be moved there.
+    pub fn set_brk(&self, new: usize) -> usize {
+        let mut inner = self.inner.lock();
+        let Some(brk) = inner.brk else {
+            return 0;
+        };
+        if new < brk.start {
+            return brk.current;
+        }
+        let old_end = align_up(brk.current, PAGE_SIZE);
+        let new_end = align_up(new, PAGE_SIZE);
+        if new_end > old_end {
+            if new_end > USER_END
+                || inner
+                    .add_vma(old_end, new_end, PteFlags::R | PteFlags::W, None)
+                    .is_err()
+            {
+                return brk.current;
+            }
+            // Merge with the heap's VMA so far.
+            if let Some((&start, vma)) = inner.vmas.range(..old_end).next_back() {
+                if vma.end == old_end
+                    && vma.flags == (PteFlags::R | PteFlags::W)
+                    && vma.shared.is_none()
+                {
+                    inner.vmas.remove(&old_end);
+                    inner.vmas.get_mut(&start).unwrap().end = new_end;
+                }
+            }
+        } else if new_end < old_end {
+            let removed = inner.remove_range(new_end, old_end, &mut TlbBatch::new(&self.active));
+            inner.brk = Some(Brk {
+                current: new,
+                ..brk
+            });
+            // Dropped once the lock is released.
+            drop(inner);
+            drop(removed);
+            return new;
+        }
+        inner.brk = Some(Brk {
+            current: new,
+            ..brk
+        });
+        new
+    }
+
+    /// Makes a copy of the address space. Pages are shared until either
+    /// copy writes to them, except those of shared VMAs, which stay shared.
+    /// Returns `None` if there isn't enough memory.
+    pub fn fork(&self) -> Option<Self> {
+        let copy = Self::new()?;
+        // Pages made read-only here must be flushed before either copy
+        // writes to them again.
+        let mut tlb = TlbBatch::new(&self.active);
+        let mut inner = self.inner.lock();
+        let mut copy_guard = copy.inner.lock();
+        let (inner, copy_inner) = (&mut *inner, &mut *copy_guard);
+        // Taken once for every page, rather than page by page.
+        let mut shared = SHARED.lock();
+        for (&start, vma) in &inner.vmas {
+            let stays_shared = vma.shared.as_ref().is_some_and(|region| !region.private);
+            for (&vaddr, &paddr) in inner.pages.range(start..vma.end) {
+                let (_, mut flags) = inner.table.lookup(vaddr)?;
+                if flags.contains(PteFlags::W) && !stays_shared {
+                    flags = flags.without(PteFlags::W) | COW;
+                    inner.table.set_flags(vaddr, flags, &mut tlb);
+                }
+                copy_inner
+                    .table
+                    .map(vaddr, paddr, PageSize::Size4K, flags)
+                    .ok()?;
+                *shared.entry(paddr).or_insert(1) += 1;
+                copy_inner.pages.insert(vaddr, paddr);
+            }
+        }
+        drop(shared);
+        copy_inner.vmas = inner.vmas.clone();
+        copy_inner.brk = inner.brk;
+        copy_inner.size_limit = inner.size_limit;
+        drop(copy_guard);
+        Some(copy)
+    }
+
+    /// Handles a page fault from an `access` to `vaddr`, by U-mode or by the
+    /// kernel on its behalf, mapping a page if the access is allowed.
+    pub fn handle_fault(&self, vaddr: usize, access: Access) -> Result<(), FaultError> {
+        let page = align_down(vaddr, PAGE_SIZE);
+        let mut tlb = TlbBatch::new(&self.active);
+        let mut inner = self.inner.lock();
+        if vaddr >= USER_END || !inner.allows(page, access) {
+            return Err(FaultError::Denied);
+        }
+        let mapped = inner.table.lookup(page);
+        match mapped {
+            Some((paddr, flags)) if access == Access::Write && flags.contains(COW) => inner
+                .copy_on_write(page, paddr, flags, &mut tlb)
+                .then_some(())
+                .ok_or(FaultError::OutOfMemory),
+            // Already mapped as needed, so the fault came from a stale TLB
+            // entry. Setting the flags again flushes it.
+            Some((_, flags)) => inner
+                .table
+                .set_flags(page, flags, &mut tlb)
+                .then_some(())
+                .ok_or(FaultError::Denied),
+            None => {
+                let vma = inner.vma(page).expect("allowed but unmapped").clone();
+                let Some(region) = &vma.shared else {
+                    return inner
+                        .map_zeroed_page(page, vma.flags)
+                        .map_err(|_| FaultError::OutOfMemory);
+                };
+                // Reading the page from a file may sleep.
+                drop(inner);
+                let index = region.index(page);
+                let paddr = region.memory.frame(index).ok_or(FaultError::OutOfMemory)?;
+                if !region.private && vma.flags.contains(PteFlags::W) {
+                    region.memory.mark_dirty(index);
+                }
+                let mut inner = self.inner.lock();
+                // Something else may have mapped the page, or changed what
+                // goes there, meanwhile. Either way, the access is retried.
+                let unchanged = inner
+                    .vma(page)
+                    .and_then(|vma| vma.shared.as_ref())
+                    .is_some_and(|now| {
+                        Arc::ptr_eq(&now.memory, &region.memory) && now.index(page) == index
+                    });
+                if !unchanged || inner.table.lookup(page).is_some() || !inner.allows(page, access) {
+                    return Ok(());
+                }
+                inner
+                    .map_shared_page(page, paddr, vma.flags, region)
+                    .map_err(|_| FaultError::OutOfMemory)
+            }
+        }
+    }
+
+    /// Copies `bytes` into the address space at `vaddr`, which must already
+    /// be mapped and not shared, through the kernel's physical memory
+    /// mapping. Returns false if some of it isn't mapped.
+    pub fn write(&self, vaddr: usize, bytes: &[u8]) -> bool {
+        let inner = self.inner.lock();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let addr = vaddr + offset;
+            let Some((paddr, _)) = inner.table.lookup(addr) else {
+                return false;
+            };
+            let len = (align_down(addr, PAGE_SIZE) + PAGE_SIZE - addr).min(bytes.len() - offset);
+            // SAFETY: `paddr` is one of our frames, and the copy stays inside
+            // its page.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes[offset..].as_ptr(),
+                    phys_to_virt(paddr) as *mut u8,
+                    len,
+                )
+            };
+            offset += len;
+        }
+        true
+    }
+
+    /// The frame mapped at `page` for a debugger to access, faulting it in
+    /// as a read by U-mode would, and first copying it if it's shared and
+    /// `write`, so that other address spaces don't see the write.
+    fn debug_frame(&self, page: usize, write: bool) -> Option<usize> {
+        let mapped = self.inner.lock().table.lookup(page).is_some();
+        if !mapped && self.handle_fault(page, Access::Read).is_err() {
+            return None;
+        }
+        let mut tlb = TlbBatch::new(&self.active);
+        let mut inner = self.inner.lock();
+        if page >= USER_END || !inner.allows(page, Access::Read) {
+            return None;
+        }
+        let (paddr, flags) = inner.table.lookup(page)?;
+        // Writes to shared memory are meant to be seen by everyone.
+        let private = !inner.is_shared_page(page);
+        if write && private && is_shared(paddr) && !inner.make_private(page, paddr, flags, &mut tlb)
+        {
+            return None;
+        }
+        inner.table.lookup(page).map(|(paddr, _)| paddr)
+    }
+
+    /// Copies from `vaddr` in the address space into `buf`, for a debugger.
+    /// Returns false if some of it can't be read by U-mode.
+    pub fn debug_read(&self, vaddr: usize, buf: &mut [u8]) -> bool {
+        let mut offset = 0;
+        while offset < buf.len() {
+            let addr = vaddr.wrapping_add(offset);
+            let page = align_down(addr, PAGE_SIZE);
+            let Some(paddr) = self.debug_frame(page, false) else {
+                return false;
+            };
+            let len = (page + PAGE_SIZE - addr).min(buf.len() - offset);
+            // SAFETY: `paddr` is one of our frames, and the copy stays inside
+            // its page.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (phys_to_virt(paddr) + addr - page) as *const u8,
+                    buf[offset..].as_mut_ptr(),
+                    len,
+                )
+            };
+            offset += len;
+        }
+        true
+    }
+
+    /// Copies `bytes` to `vaddr` in the address space for a debugger, which
+    /// may write anywhere U-mode can read, such as to put breakpoints in
+    /// code. Returns false if some of it can't be read by U-mode.
+    pub fn debug_write(&self, vaddr: usize, bytes: &[u8]) -> bool {
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let addr = vaddr.wrapping_add(offset);
+            let page = align_down(addr, PAGE_SIZE);
+            let Some(paddr) = self.debug_frame(page, true) else {
+                return false;
+            };
+            let len = (page + PAGE_SIZE - addr).min(bytes.len() - offset);
+            // SAFETY: as in `debug_read`, and the frame isn't shared.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes[offset..].as_ptr(),
+                    (phys_to_virt(paddr) + addr - page) as *mut u8,
+                    len,
+                )
+            };
+            offset += len;
+        }
+        true
+    }
+
+    /// Returns true if U-mode may access all of `len` bytes at `vaddr`,
+    /// including writing to them if `write`. Pages may still need to be
+    /// faulted in.
+    pub fn is_accessible(&self, vaddr: usize, len: usize, write: bool) -> bool {
+        let Some(end) = vaddr.checked_add(len) else {
+            return false;
+        };
+        if end > USER_END {
+            return false;
+        }
+        let access = if write { Access::Write } else { Access::Read };
+        let inner = self.inner.lock();
+        (align_down(vaddr, PAGE_SIZE)..end)
+            .step_by(PAGE_SIZE)
+            .all(|page| inner.allows(page, access))
+    }
+
+    /// The shared memory of the shared VMAs in `len` bytes at `vaddr`, for
+    /// `msync` to write back. Returns `None` if some of it isn't mapped.
+    pub fn shared_memory_in(&self, vaddr: usize, len: usize) -> Option<Vec<Arc<SharedMemory>>> {
+        let end = vaddr.checked_add(len)?;
+        let inner = self.inner.lock();
+        let mut memory: Vec<Arc<SharedMemory>> = Vec::new();
+        let mut next = vaddr;
+        for (&start, vma) in inner.vmas.range(..end).filter(|(_, vma)| vma.end > vaddr) {
+            if start > next {
+                return None;
+            }
+            next = vma.end;
+            if let Some(region) = vma.shared.as_ref().filter(|region| !region.private) {
+                if !memory.iter().any(|m| Arc::ptr_eq(m, &region.memory)) {
+                    memory.push(region.memory.clone());
+                }
+            }
+        }
+        (next >= end).then_some(memory)
+    }
+
+    /// How many pages have frames of their own or shared with other address
+    /// spaces.
+    pub fn resident_pages(&self) -> usize {
+        self.inner.lock().pages.len()
+    }
+
+    /// Unmaps everything, freeing the memory of an address space that's
+    /// done with before it's dropped.
+    pub fn clear(&self) {
+        let mut tlb = TlbBatch::new(&self.active);
+        let mut inner = self.inner.lock();
+        let removed = inner.remove_range(0, USER_END, &mut tlb);
+        inner.brk = None;
+        drop(inner);
+        drop(removed);
+    }
+
+    /// The runs of pages that have frames, as `(start, end, flags)` with
+    /// the flags of their VMAs, for a core dump. Pages never touched would
+    /// read as zeroes, so aren't included.
+    pub fn resident_ranges(&self) -> Vec<(usize, usize, PteFlags)> {
+        let inner = self.inner.lock();
+        let mut ranges: Vec<(usize, usize, PteFlags)> = Vec::new();
+        for (&start, vma) in &inner.vmas {
+            for &page in inner.pages.range(start..vma.end).map(|(page, _)| page) {
+                match ranges.last_mut() {
+                    Some((_, end, flags)) if *end == page && *flags == vma.flags => {
+                        *end += PAGE_SIZE;
+                    }
+                    _ => ranges.push((page, page + PAGE_SIZE, vma.flags)),
+                }
+            }
+        }
+        ranges
+    }
+
+    /// Copies the page at `page` into `buf`, which is a page long, if it
+    /// has a frame, without faulting it in or checking that U-mode can
+    /// read it. Returns false if it hasn't.
+    pub fn read_resident(&self, page: usize, buf: &mut [u8]) -> bool {
+        let inner = self.inner.lock();
+        let Some(&paddr) = inner.pages.get(&page) else {
+            return false;
+        };
+        // SAFETY: the frame stays mapped while the lock is held.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(paddr) as *const u8,
+                buf.as_mut_ptr(),
+                buf.len().min(PAGE_SIZE),
+            )
+        };
+        true
+    }
+}
+
+ktest! {
+    fn fork_copies_only_pages_written_to() {
+        let space = AddressSpace::new().unwrap();
+        let (written, untouched) = (0x10000, 0x11000);
+        space
+            .map_lazy(written, 2 * PAGE_SIZE, PteFlags::R | PteFlags::W)
+            .unwrap();
+        assert!(space.debug_write(written, b"parent"));
+        assert!(space.debug_write(untouched, b"shared"));
+        let frame = |space: &AddressSpace, page: usize| space.inner.lock().pages[&page];
+
+        let child = space.fork().unwrap();
+        assert_eq!(frame(&space, written), frame(&child, written));
+        child.handle_fault(written, Access::Write).unwrap();
+        assert_ne!(frame(&space, written), frame(&child, written));
+        assert_eq!(frame(&space, untouched), frame(&child, untouched));
+        assert!(child.debug_write(written, b"child!"));
+        let mut buf = [0; 6];
+        assert!(space.debug_read(written, &mut buf));
+        assert_eq!(&buf, b"parent");
+
+        // Once the child's gone the page is the parent's alone, and is made
+        // writable again without a copy.
+        let before = frame(&space, untouched);
+        drop(child);
+        space.handle_fault(untouched, Access::Write).unwrap();
+        assert_eq!(frame(&space, untouched), before);
+    }
+}
+
+ktest! {
+    fn faults_in_zeroed_pages() {
+        let space = AddressSpace::new().unwrap();
+        let (read_only, writable) = (0x20000, 0x21000);
+        space.map_lazy(read_only, PAGE_SIZE, PteFlags::R).unwrap();
+        space
+            .map_lazy(writable, PAGE_SIZE, PteFlags::R | PteFlags::W)
+            .unwrap();
+        assert_eq!(space.resident_pages(), 0);
+
+        assert_eq!(space.handle_fault(read_only + 8, Access::Write), Err(FaultError::Denied));
+        assert_eq!(space.handle_fault(read_only, Access::Execute), Err(FaultError::Denied));
+        assert_eq!(space.handle_fault(0x30000, Access::Read), Err(FaultError::Denied));
+        assert_eq!(space.resident_pages(), 0);
+
+        space.handle_fault(read_only + 8, Access::Read).unwrap();
+        space.handle_fault(writable, Access::Write).unwrap();
+        assert_eq!(space.resident_pages(), 2);
+        let mut buf = [0xff; 16];
+        assert!(space.debug_read(read_only, &mut buf));
+        assert_eq!(buf, [0; 16]);
+        // Faulting again, as after a stale TLB entry, maps nothing new.
+        space.handle_fault(writable, Access::Read).unwrap();
+        assert_eq!(space.resident_pages(), 2);
+    }
+}
+
+ktest! {
+    fn finds_free_space() {
+        let space = AddressSpace::new().unwrap();
+        let top = 0x100000;
+        space
+            .map_lazy(top - 2 * PAGE_SIZE, PAGE_SIZE, PteFlags::R)
+            .unwrap();
+        // The gap at the top is too small, so it goes below the mapping.
+        assert_eq!(
+            space.find_free(2 * PAGE_SIZE, top, None),
+            Some(top - 4 * PAGE_SIZE)
+        );
+        assert_eq!(space.find_free(PAGE_SIZE, top, None), Some(top - PAGE_SIZE));
+
+        assert_eq!(space.find_free(PAGE_SIZE, top, Some(0x40000)), Some(0x40000));
+        assert_eq!(space.find_free(PAGE_SIZE, top, Some(0x40001)), Some(0x40000));
+        // Taken or too low, so the hint is ignored.
+        let overlapping = Some(top - 3 * PAGE_SIZE);
+        assert_eq!(
+            space.find_free(2 * PAGE_SIZE, top, overlapping),
+            Some(top - 4 * PAGE_SIZE)
+        );
+        let overlapping = Some(top - 2 * PAGE_SIZE + 1);
+        assert_eq!(space.find_free(PAGE_SIZE, top, overlapping), Some(top - PAGE_SIZE));
+        assert_eq!(space.find_free(PAGE_SIZE, top, Some(0)), Some(top - PAGE_SIZE));
+        assert_eq!(space.find_free(top, top, None), None);
+    }
+}
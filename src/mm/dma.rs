@@ -0,0 +1,194 @@
+//! Memory for devices to read and write directly. Buffers come straight
+//! from the frame allocator, so they're physically contiguous and page
+//! aligned, and they're handed to devices by their bus address: where the
+//! device sees them, which differs from the CPU physical address when the
+//! bus the devices sit on has `dma-ranges`.
+//!
+//! Devices on QEMU's virt machine are cache coherent, so nothing needs
+//! flushing, but the CPU's accesses to DMA memory still need ordering
+//! against its accesses to the device's registers: see `to_device` and
+//! `from_device`.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+
+use crate::dtb::DeviceTree;
+use crate::mm::frame::{self, FRAME_SIZE};
+use crate::mm::phys_to_virt;
+use crate::sync::SpinLock;
+use crate::{ktest, log_debug};
+
+/// A window of CPU physical memory devices can reach, and where they see it.
+#[derive(Clone, Copy, Debug)]
+struct DmaRange {
+    cpu: u64,
+    bus: u64,
+    size: u64,
+}
+
+/// The windows from every bus's `dma-ranges`. Empty if there are none, in
+/// which case devices see all of memory where the CPU does.
+static RANGES: SpinLock<Vec<DmaRange>> = SpinLock::new(Vec::new());
+
+fn cells(bytes: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    bytes
+        .chunks_exact(4)
+        .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+}
+
+/// Reads `count` cells from `iter` as one number.
+fn number(iter: &mut impl Iterator<Item = u32>, count: u32) -> Option<u64> {
+    (0..count).try_fold(0, |acc, _| Some(acc << 32 | u64::from(iter.next()?)))
+}
+
+/// Reads the `dma-ranges` of the simple buses in the device tree, which is
+/// where the platform's devices sit.
+pub fn init(dt: &DeviceTree<'_>) {
+    let mut ranges = RANGES.lock();
+    for bus in dt.nodes_compatible("simple-bus") {
+        let Some(prop) = bus.property("dma-ranges") else {
+            continue;
+        };
+        let Some(parent) = dt.parent(&bus) else {
+            continue;
+        };
+        let child_cells = bus.prop_u32("#address-cells").unwrap_or(2);
+        let parent_cells = parent.prop_u32("#address-cells").unwrap_or(2);
+        let size_cells = bus.prop_u32("#size-cells").unwrap_or(1);
+        let mut prop = cells(prop.value).peekable();
+        while prop.peek().is_some() {
+            let (Some(bus_addr), Some(parent_addr), Some(size)) = (
+                number(&mut prop, child_cells),
+                number(&mut prop, parent_cells),
+                number(&mut prop, size_cells),
+            ) else {
+                break;
+            };
+            let Some(cpu) = dt.translate_address(&parent, parent_addr) else {
+                continue;
+            };
+            log_debug!(
+                target: "dma",
+                "{}: {:#x}..{:#x} at bus address {:#x}",
+                bus.name,
+                cpu,
+                cpu + size,
+                bus_addr
+            );
+            ranges.push(DmaRange {
+                cpu,
+                bus: bus_addr,
+                size,
+            });
+        }
+    }
+}
+
+/// The bus address of the `len` bytes of physical memory at `paddr`, or
+/// `None` if devices can't reach all of it.
+pub fn phys_to_bus(paddr: usize, len: usize) -> Option<u64> {
+    to_bus(&RANGES.lock(), paddr as u64, len as u64)
+}
+
+/// The bus address of `len` bytes at `paddr` through `ranges`, which
+/// reach everything if there are none.
+fn to_bus(ranges: &[DmaRange], paddr: u64, len: u64) -> Option<u64> {
+    if ranges.is_empty() {
+        return Some(paddr);
+    }
+    // A range may run right up to the end of the address space, so its
+    // end isn't worked out.
+    ranges
+        .iter()
+        .find(|range| paddr >= range.cpu && len <= range.size.saturating_sub(paddr - range.cpu))
+        .map(|range| paddr - range.cpu + range.bus)
+}
+
+/// Zeroed memory shared with a device, freed when dropped. It's up to the
+/// driver to stop the device using it first.
+pub struct DmaBuffer {
+    paddr: usize,
+    bus_addr: u64,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// Where the CPU accesses the buffer.
+    pub fn vaddr(&self) -> usize {
+        phys_to_virt(self.paddr)
+    }
+
+    /// Where the device accesses the buffer.
+    pub fn bus_addr(&self) -> u64 {
+        self.bus_addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        // SAFETY: the frames were allocated for the buffer, which is gone.
+        unsafe { frame::free_frames(self.paddr, self.len.div_ceil(FRAME_SIZE).max(1)) };
+    }
+}
+
+/// Allocates a buffer of `len` bytes for a device to use alongside the CPU.
+/// Returns `None` if there's no memory the device can reach.
+pub fn alloc_coherent(len: usize) -> Option<DmaBuffer> {
+    let frames = len.div_ceil(FRAME_SIZE).max(1);
+    let paddr = frame::alloc_frames(frames)?;
+    let Some(bus_addr) = phys_to_bus(paddr, frames * FRAME_SIZE) else {
+        // SAFETY: the frames were just allocated and aren't used.
+        unsafe { frame::free_frames(paddr, frames) };
+        return None;
+    };
+    // SAFETY: the frames were just allocated.
+    unsafe { core::ptr::write_bytes(phys_to_virt(paddr) as *mut u8, 0, frames * FRAME_SIZE) };
+    Some(DmaBuffer {
+        paddr,
+        bus_addr,
+        len,
+    })
+}
+
+/// Makes everything the CPU has done to DMA memory so far visible before
+/// its next access to a device's registers, e.g. the one telling the device
+/// there's work to do.
+pub fn to_device() {
+    // SAFETY: a fence has no other effects.
+    unsafe { asm!("fence rw, o") };
+}
+
+/// Keeps the CPU's next accesses to DMA memory after its reads of a
+/// device's registers so far, e.g. of a status saying the device is done.
+pub fn from_device() {
+    // SAFETY: a fence has no other effects.
+    unsafe { asm!("fence i, rw") };
+}
+
+ktest! {
+    fn translates_to_bus_addresses() {
+        assert_eq!(to_bus(&[], 0x8000_1000, 0x1000), Some(0x8000_1000));
+        let ranges = [
+            DmaRange { cpu: 0x8000_0000, bus: 0, size: 0x4000_0000 },
+            DmaRange { cpu: 0xffff_ffff_0000_0000, bus: 0x1_0000_0000, size: 0x1_0000_0000 },
+        ];
+        assert_eq!(to_bus(&ranges, 0x8000_1000, 0x1000), Some(0x1000));
+        assert_eq!(to_bus(&ranges, 0xbfff_f000, 0x1000), Some(0x3fff_f000));
+        assert_eq!(to_bus(&ranges, 0xbfff_f000, 0x2000), None);
+        assert_eq!(to_bus(&ranges, 0x7fff_f000, 0x1000), None);
+        assert_eq!(to_bus(&ranges, 0xffff_ffff_ffff_f000, 0x1000), Some(0x1_ffff_f000));
+
+        let buffer = alloc_coherent(100).unwrap();
+        assert_eq!(buffer.len(), 100);
+        assert_eq!(buffer.vaddr() % FRAME_SIZE, 0);
+        let paddr = crate::mm::virt_to_phys(buffer.vaddr());
+        assert_eq!(Some(buffer.bus_addr()), phys_to_bus(paddr, FRAME_SIZE));
+        // SAFETY: the buffer is a whole frame, and no device has it.
+        let bytes = unsafe { core::slice::from_raw_parts(buffer.vaddr() as *const u8, FRAME_SIZE) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+    }
+}
@@ -0,0 +1,319 @@
+use core::ops::Range;
+
+use crate::dtb::{DeviceTree, DtNode};
+use crate::ktest;
+use crate::mm::{phys_to_virt, virt_to_phys, PHYS_MAP_END};
+use crate::sync::SpinLockIrqSave;
+use crate::util::{align_down, align_up};
+
+pub const FRAME_SIZE: usize = 4096;
+
+static FRAMES: SpinLockIrqSave<Option<FrameAllocator>> = SpinLockIrqSave::new(None);
+
+extern "C" {
+    static _stext: u8;
+    static _eflash: u8;
+    static _sdata: u8;
+    static _sheap: u8;
+}
+
+/// The physical memory used by the kernel: the image as loaded, which on
+/// boards without flash is in RAM, and data, bss and the boot stack.
+fn kernel_image() -> [Range<usize>; 2] {
+    [
+        virt_to_phys(&raw const _stext as usize)..virt_to_phys(&raw const _eflash as usize),
+        virt_to_phys(&raw const _sdata as usize)..virt_to_phys(&raw const _sheap as usize),
+    ]
+}
+
+/// A bitmap allocator for physical memory frames.
+struct FrameAllocator {
+    /// One bit per frame, set if the frame is in use (or not RAM at all).
+    bitmap: &'static mut [u64],
+    /// Physical address of the frame described by bit 0.
+    base: usize,
+    total: usize,
+    free: usize,
+    /// Index of the word to start searching from.
+    next: usize,
+}
+
+impl FrameAllocator {
+    fn frame_index(&self, paddr: usize) -> usize {
+        (paddr - self.base) / FRAME_SIZE
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_used(&mut self, frames: Range<usize>, used: bool) {
+        for index in frames {
+            let bit = 1 << (index % 64);
+            if used {
+                self.bitmap[index / 64] |= bit;
+            } else {
+                self.bitmap[index / 64] &= !bit;
+            }
+        }
+    }
+
+    /// Marks every frame overlapping `range` as used or free, ignoring frames
+    /// outside of the bitmap.
+    fn set_range(&mut self, range: Range<usize>, used: bool) {
+        let (start, end) = if used {
+            (
+                align_down(range.start, FRAME_SIZE),
+                align_up(range.end, FRAME_SIZE),
+            )
+        } else {
+            (
+                align_up(range.start, FRAME_SIZE),
+                align_down(range.end, FRAME_SIZE),
+            )
+        };
+        let limit = self.base + 64 * self.bitmap.len() * FRAME_SIZE;
+        let (start, end) = (start.clamp(self.base, limit), end.clamp(self.base, limit));
+        if start < end {
+            self.set_used(self.frame_index(start)..self.frame_index(end), used);
+        }
+    }
+
+    fn alloc(&mut self, count: usize) -> Option<usize> {
+        if count == 0 || count > self.free {
+            return None;
+        }
+
+        let index = if count == 1 {
+            let words = self.bitmap.len();
+            let word = (0..words)
+                .map(|i| (self.next + i) % words)
+                .find(|&i| self.bitmap[i] != !0)?;
+            self.next = word;
+            64 * word + self.bitmap[word].trailing_ones() as usize
+        } else {
+            let mut run = 0;
+            let end = (0..64 * self.bitmap.len()).find(|&index| {
+                run = if self.is_used(index) { 0 } else { run + 1 };
+                run == count
+            })?;
+            end + 1 - count
+        };
+
+        self.set_used(index..index + count, true);
+        self.free -= count;
+        Some(self.base + index * FRAME_SIZE)
+    }
+
+    fn free(&mut self, paddr: usize, count: usize) {
+        assert!(
+            paddr.is_multiple_of(FRAME_SIZE) && paddr >= self.base,
+            "free of invalid frame {:#x}",
+            paddr
+        );
+        let index = self.frame_index(paddr);
+        for index in index..index + count {
+            assert!(
+                index < 64 * self.bitmap.len() && self.is_used(index),
+                "double free of frame {:#x}",
+                self.base + index * FRAME_SIZE
+            );
+        }
+        self.set_used(index..index + count, false);
+        self.free += count;
+    }
+}
+
+/// Builds the frame allocator from the `/memory` nodes of the device tree,
+/// excluding the memory reservation block, `/reserved-memory` (but not its
+/// disabled regions), the kernel image, the device tree blob itself and the
+/// initrd.
+pub fn init(dt: &DeviceTree<'_>) {
+    let lowest = memory_regions(dt).map(|r| r.start).min();
+    let highest = memory_regions(dt).map(|r| r.end).max();
+    let (Some(lowest), Some(highest)) = (lowest, highest) else {
+        panic!("no memory in device tree");
+    };
+    let base = align_down(lowest, FRAME_SIZE);
+    let frames = (highest - base).div_ceil(FRAME_SIZE);
+    let words = frames.div_ceil(64);
+
+    let bitmap_size = align_up(8 * words, FRAME_SIZE);
+    let bitmap_addr = find_free(dt, bitmap_size).expect("no memory for frame bitmap");
+    // SAFETY: `find_free` returns unused RAM.
+    let bitmap =
+        unsafe { core::slice::from_raw_parts_mut(phys_to_virt(bitmap_addr) as *mut u64, words) };
+    bitmap.fill(!0);
+
+    let mut frames = FrameAllocator {
+        bitmap,
+        base,
+        total: 0,
+        free: 0,
+        next: 0,
+    };
+    for region in memory_regions(dt) {
+        frames.set_range(region, false);
+    }
+    for range in reserved_ranges(dt) {
+        frames.set_range(range, true);
+    }
+    frames.set_range(bitmap_addr..bitmap_addr + bitmap_size, true);
+
+    frames.free = frames.bitmap.iter().map(|w| w.count_zeros() as usize).sum();
+    frames.total = frames.free;
+    *FRAMES.lock() = Some(frames);
+}
+
+/// Allocates a single physical frame, returning its physical address. The
+/// contents of the frame are not cleared.
+pub fn alloc_frame() -> Option<usize> {
+    alloc_frames(1)
+}
+
+/// Allocates `count` physically contiguous frames.
+pub fn alloc_frames(count: usize) -> Option<usize> {
+    FRAMES.lock().as_mut()?.alloc(count)
+}
+
+/// SAFETY: `paddr` must have been returned by `alloc_frame` and not be used
+/// after this call.
+pub unsafe fn free_frame(paddr: usize) {
+    free_frames(paddr, 1)
+}
+
+/// SAFETY: `paddr` must have been returned by `alloc_frames(count)` and not
+/// be used after this call.
+pub unsafe fn free_frames(paddr: usize, count: usize) {
+    FRAMES
+        .lock()
+        .as_mut()
+        .expect("frame allocator not initialized")
+        .free(paddr, count)
+}
+
+pub struct FrameStats {
+    /// Frames managed by the allocator.
+    pub total: usize,
+    pub free: usize,
+}
+
+pub fn stats() -> FrameStats {
+    match FRAMES.lock().as_ref() {
+        Some(frames) => FrameStats {
+            total: frames.total,
+            free: frames.free,
+        },
+        None => FrameStats { total: 0, free: 0 },
+    }
+}
+
+fn to_range(address: u64, size: u64) -> Option<Range<usize>> {
+    let start = usize::try_from(address).ok()?;
+    let end = usize::try_from(address.checked_add(size)?).ok()?;
+    Some(start..end)
+}
+
+/// The RAM from the `/memory` nodes, as far as it's mapped.
+pub(super) fn memory_regions<'a>(dt: &DeviceTree<'a>) -> impl Iterator<Item = Range<usize>> + 'a {
+    dt.root_node()
+        .children()
+        .filter(|node| node.prop_str("device_type") == Some("memory"))
+        .filter(DtNode::is_enabled)
+        .flat_map(|node| node.reg().into_iter().flatten())
+        .filter_map(|reg| to_range(reg.address, reg.size))
+        .map(|range| range.start..range.end.min(PHYS_MAP_END))
+        .filter(|range| !range.is_empty())
+}
+
+fn reserved_ranges<'a>(dt: &DeviceTree<'a>) -> impl Iterator<Item = Range<usize>> + 'a {
+    let memreserve = dt
+        .memory_reservations()
+        .filter_map(|resv| to_range(resv.address, resv.size));
+    let reserved_memory = dt
+        .find_node("/reserved-memory")
+        .into_iter()
+        .flat_map(|node| node.children())
+        // Disabled ones are there for an overlay, say, to turn on.
+        .filter(DtNode::is_enabled)
+        .flat_map(|node| node.reg().into_iter().flatten())
+        .filter_map(|reg| to_range(reg.address, reg.size));
+    let blob = dt.blob().as_ptr_range();
+    let blob = virt_to_phys(blob.start as usize)..virt_to_phys(blob.end as usize);
+    let initrd = dt
+        .chosen()
+        .and_then(|chosen| chosen.initrd)
+        .and_then(|initrd| to_range(initrd.start, initrd.end - initrd.start));
+
+    memreserve
+        .chain(reserved_memory)
+        .chain(kernel_image())
+        .chain([blob])
+        .chain(initrd)
+}
+
+/// Finds `size` bytes of page-aligned RAM that isn't reserved, checking the
+/// addresses just past the kernel image and every reservation.
+fn find_free(dt: &DeviceTree<'_>, size: usize) -> Option<usize> {
+    let candidates = reserved_ranges(dt)
+        .map(|range| range.end)
+        .chain(memory_regions(dt).map(|range| range.start))
+        .map(|start| align_up(start, FRAME_SIZE));
+    for start in candidates {
+        let end = start + size;
+        let in_ram = memory_regions(dt).any(|r| r.start <= start && end <= r.end);
+        let overlaps = reserved_ranges(dt).any(|r| r.start < end && start < r.end);
+        if in_ram && !overlaps {
+            return Some(start);
+        }
+    }
+    None
+}
+
+ktest! {
+    fn alloc_and_free_frames() {
+        let one = alloc_frame().unwrap();
+        let three = alloc_frames(3).unwrap();
+        assert!(one.is_multiple_of(FRAME_SIZE) && three.is_multiple_of(FRAME_SIZE));
+        assert!(one + FRAME_SIZE <= three || three + 3 * FRAME_SIZE <= one);
+        // SAFETY: the frames were just allocated and aren't used.
+        unsafe {
+            free_frame(one);
+            free_frames(three, 3);
+        }
+    }
+}
+
+ktest! {
+    fn reserves_enabled_regions() {
+        use crate::dtb::{FdtBuilder, FdtNode};
+        use alloc::vec::Vec;
+
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.root_mut();
+        root.set_u32("#address-cells", 2);
+        root.set_u32("#size-cells", 2);
+        let memory = root.add_child(FdtNode::new("memory@80000000"));
+        memory.set_str("device_type", "memory");
+        memory.set_cells("reg", &[0, 0x8000_0000, 0, 0x800_0000]);
+        let reserved = root.add_child(FdtNode::new("reserved-memory"));
+        reserved.set_u32("#address-cells", 2);
+        reserved.set_u32("#size-cells", 2);
+        reserved
+            .add_child(FdtNode::new("firmware@80000000"))
+            .set_cells("reg", &[0, 0x8000_0000, 0, 0x4_0000]);
+        let disabled = reserved.add_child(FdtNode::new("spare@87000000"));
+        disabled.set_cells("reg", &[0, 0x8700_0000, 0, 0x10_0000]);
+        disabled.set_str("status", "disabled");
+
+        let blob = fdt.to_aligned_blob();
+        let dt = blob.parse().unwrap();
+
+        let mut regions = memory_regions(&dt);
+        assert_eq!(regions.next(), Some(0x8000_0000..0x8800_0000));
+        assert_eq!(regions.next(), None);
+        let reserved: Vec<_> = reserved_ranges(&dt).collect();
+        assert!(reserved.contains(&(0x8000_0000..0x8004_0000)));
+        assert!(!reserved.contains(&(0x8700_0000..0x8710_0000)));
+    }
+}
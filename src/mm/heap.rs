@@ -0,0 +1,253 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use crate::ktest;
+use crate::mm::frame::{self, FRAME_SIZE};
+use crate::mm::phys_to_virt;
+use crate::sync::SpinLockIrqSave;
+use crate::util::align_up;
+
+/// Every block is a multiple of this size and alignment, which is enough to
+/// hold a `Hole`.
+const MIN_ALIGN: usize = 16;
+
+/// The heap grows by at least this many frames at a time.
+const MIN_GROW_FRAMES: usize = 16;
+
+/// Interrupt handlers may allocate, e.g. to queue deferred work.
+#[global_allocator]
+static HEAP: Heap = Heap(SpinLockIrqSave::new(HoleList::new()));
+
+struct Heap(SpinLockIrqSave<HoleList>);
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut holes = self.0.lock();
+        let ptr = holes.alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        let (size, align) = block_layout(layout);
+        let frames = (size + align).div_ceil(FRAME_SIZE).max(MIN_GROW_FRAMES);
+        let Some(paddr) = frame::alloc_frames(frames) else {
+            return ptr::null_mut();
+        };
+        holes.add_region(phys_to_virt(paddr), frames * FRAME_SIZE);
+        holes.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        self.0.lock().free(ptr as usize, size);
+    }
+
+    /// Resizes the block where it is if it can, so that growing a `Vec`
+    /// that's last in its hole, or shrinking one, doesn't copy it.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (old, _) = block_layout(layout);
+        let (new, _) = block_layout(new_layout);
+        {
+            let mut holes = self.0.lock();
+            if new <= old {
+                if new < old {
+                    holes.free(ptr as usize + new, old - new);
+                }
+                return ptr;
+            }
+            if holes.grow(ptr as usize, old, new) {
+                return ptr;
+            }
+        }
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size());
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+/// The size and alignment of the block actually used for `layout`.
+fn block_layout(layout: Layout) -> (usize, usize) {
+    (
+        align_up(layout.size().max(1), MIN_ALIGN),
+        layout.align().max(MIN_ALIGN),
+    )
+}
+
+/// A free block of heap memory, stored in the block itself.
+struct Hole {
+    size: usize,
+    next: *mut Hole,
+}
+
+/// A first-fit allocator keeping the free blocks in a list sorted by
+/// address, so that neighbouring blocks can be merged when freed.
+struct HoleList {
+    head: *mut Hole,
+    /// Bytes given to the heap by the frame allocator.
+    size: usize,
+    free: usize,
+}
+
+// SAFETY: the holes are only accessed through the list.
+unsafe impl Send for HoleList {}
+
+impl HoleList {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            size: 0,
+            free: 0,
+        }
+    }
+
+    /// SAFETY: `start..start + len` must be unused, `MIN_ALIGN`-aligned
+    /// memory.
+    unsafe fn add_region(&mut self, start: usize, len: usize) {
+        self.size += len;
+        self.free(start, len);
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+
+        let mut link: *mut *mut Hole = &mut self.head;
+        while !(*link).is_null() {
+            let hole = *link;
+            let start = hole as usize;
+            let end = start + (*hole).size;
+            let addr = align_up(start, align);
+
+            if addr + size <= end {
+                let mut next = (*hole).next;
+                if addr + size < end {
+                    let back = (addr + size) as *mut Hole;
+                    back.write(Hole {
+                        size: end - (addr + size),
+                        next,
+                    });
+                    next = back;
+                }
+                if addr > start {
+                    (*hole).size = addr - start;
+                    (*hole).next = next;
+                } else {
+                    *link = next;
+                }
+                self.free -= size;
+                return addr as *mut u8;
+            }
+            link = &mut (*hole).next;
+        }
+        ptr::null_mut()
+    }
+
+    /// Grows the block `addr..addr + size` to `new_size` bytes by taking the
+    /// start of the hole after it, if there's one right after it and it's
+    /// big enough.
+    unsafe fn grow(&mut self, addr: usize, size: usize, new_size: usize) -> bool {
+        let end = addr + size;
+        let needed = new_size - size;
+        let mut link: *mut *mut Hole = &mut self.head;
+        while !(*link).is_null() && (*link as usize) < end {
+            link = &mut (**link).next;
+        }
+        let hole = *link;
+        if hole as usize != end || (*hole).size < needed {
+            return false;
+        }
+        let mut next = (*hole).next;
+        if (*hole).size > needed {
+            let rest = (end + needed) as *mut Hole;
+            rest.write(Hole {
+                size: (*hole).size - needed,
+                next,
+            });
+            next = rest;
+        }
+        *link = next;
+        self.free -= needed;
+        true
+    }
+
+    /// Returns the block `addr..addr + size` to the list, merging it with
+    /// adjacent holes.
+    unsafe fn free(&mut self, addr: usize, size: usize) {
+        self.free += size;
+
+        let mut prev: *mut Hole = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let mut hole = addr as *mut Hole;
+        hole.write(Hole { size, next });
+        if !next.is_null() && addr + size == next as usize {
+            (*hole).size += (*next).size;
+            (*hole).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = hole;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*hole).size;
+            (*prev).next = (*hole).next;
+            hole = prev;
+        } else {
+            (*prev).next = hole;
+        }
+        debug_assert!((*hole).next.is_null() || (hole as usize) < (*hole).next as usize);
+    }
+}
+
+pub struct HeapStats {
+    /// Bytes obtained from the frame allocator.
+    pub size: usize,
+    pub free: usize,
+}
+
+pub fn stats() -> HeapStats {
+    let holes = HEAP.0.lock();
+    HeapStats {
+        size: holes.size,
+        free: holes.free,
+    }
+}
+
+ktest! {
+    fn resizes_blocks_in_place() {
+        #[repr(align(16))]
+        struct Region([u8; 1024]);
+
+        let mut region = Region([0; 1024]);
+        let start = region.0.as_mut_ptr() as usize;
+        let mut holes = HoleList::new();
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        // SAFETY: the holes are only in `region`, which outlives them.
+        unsafe {
+            holes.add_region(start, 1024);
+            let a = holes.alloc(layout(64)) as usize;
+            assert_eq!(a, start);
+            assert!(holes.grow(a, 64, 128));
+            assert_eq!(holes.free, 1024 - 128);
+            let b = holes.alloc(layout(16)) as usize;
+            assert_eq!(b, a + 128);
+            assert!(!holes.grow(a, 128, 144));
+
+            // Shrinking leaves a hole that growing can take back, but no
+            // more than it.
+            holes.free(a + 32, 96);
+            assert!(holes.grow(a, 32, 128));
+            assert!(!holes.grow(a, 128, 144));
+            holes.free(b, 16);
+            assert!(holes.grow(a, 128, 1024));
+            assert_eq!(holes.free, 0);
+            assert!(holes.head.is_null());
+        }
+    }
+}
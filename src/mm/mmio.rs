@@ -0,0 +1,155 @@
+//! Device registers. `ioremap` maps them into the kernel's address space as
+//! an `IoMem`, whose accessors check every offset against the mapping and
+//! make volatile accesses, so drivers never build pointers themselves.
+//! Registers with a fixed layout can also be described by a `#[repr(C)]`
+//! struct of `Volatile` fields, a `RegisterBlock`, and reached with
+//! `IoMem::block`.
+//!
+//! There's no Svpbmt to ask for uncached pages, so the mapping is an
+//! ordinary one and it's the platform's PMAs that keep device regions
+//! uncached and strongly ordered, as they do on QEMU's virt machine.
+
+use core::cell::UnsafeCell;
+use core::mem::{align_of, size_of};
+
+use crate::ktest;
+use crate::mm::paging::{self, MapError};
+
+/// The types registers can be read and written as.
+pub trait Register: Copy {}
+
+impl Register for u8 {}
+impl Register for u16 {}
+impl Register for u32 {}
+impl Register for u64 {}
+
+/// A register, which is only ever read and written whole and volatile.
+#[repr(transparent)]
+pub struct Volatile<T: Register>(UnsafeCell<T>);
+
+// SAFETY: accesses are single volatile loads and stores, which the device
+// sees in some order whichever hart makes them.
+unsafe impl<T: Register> Sync for Volatile<T> {}
+
+impl<T: Register> Volatile<T> {
+    pub fn read(&self) -> T {
+        // SAFETY: the register is part of a live mapping.
+        unsafe { self.0.get().read_volatile() }
+    }
+
+    pub fn write(&self, value: T) {
+        // SAFETY: as for `read`.
+        unsafe { self.0.get().write_volatile(value) }
+    }
+}
+
+/// A layout of registers.
+///
+/// # Safety
+///
+/// The type must be `#[repr(C)]` and made of nothing but `Volatile`
+/// fields and padding.
+pub unsafe trait RegisterBlock {}
+
+/// A mapped region of device registers. Mappings are never torn down, so
+/// it can be copied around freely.
+#[derive(Clone, Copy, Debug)]
+pub struct IoMem {
+    /// Virtual address of the region.
+    base: usize,
+    len: usize,
+}
+
+impl IoMem {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The address of the `T` at `offset`, which must be within the region
+    /// and aligned.
+    fn addr<T>(&self, offset: usize) -> usize {
+        assert!(
+            offset
+                .checked_add(size_of::<T>())
+                .is_some_and(|end| end <= self.len),
+            "register {:#x} outside of {:#x}-byte region",
+            offset,
+            self.len,
+        );
+        let addr = self.base + offset;
+        assert!(
+            addr.is_multiple_of(align_of::<T>()),
+            "misaligned register {:#x}",
+            offset
+        );
+        addr
+    }
+
+    pub fn read<T: Register>(&self, offset: usize) -> T {
+        // SAFETY: the register is within the mapping, and aligned.
+        unsafe { (self.addr::<T>(offset) as *const T).read_volatile() }
+    }
+
+    pub fn write<T: Register>(&self, offset: usize, value: T) {
+        // SAFETY: as for `read`.
+        unsafe { (self.addr::<T>(offset) as *mut T).write_volatile(value) }
+    }
+
+    /// The `len` bytes at `offset`, which must be within the region.
+    pub fn subregion(&self, offset: usize, len: usize) -> IoMem {
+        assert!(
+            offset.checked_add(len).is_some_and(|end| end <= self.len),
+            "subregion {:#x}+{:#x} outside of {:#x}-byte region",
+            offset,
+            len,
+            self.len,
+        );
+        IoMem {
+            base: self.base + offset,
+            len,
+        }
+    }
+
+    /// The registers at the start of the region, laid out as `R`.
+    pub fn block<R: RegisterBlock>(&self) -> &R {
+        // SAFETY: `R` is within the mapping and aligned, and made only of
+        // registers, which can be shared.
+        unsafe { &*(self.addr::<R>(0) as *const R) }
+    }
+}
+
+/// Maps the `len` bytes of device registers at `paddr`.
+pub fn ioremap(paddr: usize, len: usize) -> Result<IoMem, MapError> {
+    let base = paging::map_mmio(paddr, len)?;
+    Ok(IoMem { base, len })
+}
+
+ktest! {
+    fn accesses_registers() {
+        #[repr(C)]
+        struct Registers {
+            id: Volatile<u32>,
+            control: Volatile<u16>,
+            status: Volatile<u16>,
+        }
+        // SAFETY: it's `#[repr(C)]`, and made of registers.
+        unsafe impl RegisterBlock for Registers {}
+
+        // Ordinary memory stands in for a device.
+        let mut words = alloc::vec![0u64; 4];
+        let io = IoMem { base: words.as_mut_ptr() as usize, len: 32 };
+        io.write::<u32>(0, 0x1234_5678);
+        assert_eq!(io.read::<u16>(0), 0x5678);
+        let regs = io.block::<Registers>();
+        assert_eq!(regs.id.read(), 0x1234_5678);
+        regs.status.write(0xbeef);
+        assert_eq!(io.read::<u16>(6), 0xbeef);
+        assert_eq!(regs.control.read(), 0);
+
+        let sub = io.subregion(8, 16);
+        assert_eq!(sub.len(), 16);
+        sub.write::<u64>(8, 7);
+        assert_eq!(io.read::<u64>(16), 7);
+        assert_eq!(words[2], 7);
+    }
+}
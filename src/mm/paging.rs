@@ -0,0 +1,579 @@
+use core::arch::asm;
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::csr;
+use crate::dtb::DeviceTree;
+use crate::ktest;
+use crate::mm::frame::{self, FRAME_SIZE};
+use crate::mm::tlb::{self, TlbBatch};
+use crate::mm::{phys_to_virt, virt_to_phys, PHYS_OFFSET};
+use crate::sync::SpinLock;
+use crate::util::{align_down, align_up};
+
+pub const PAGE_SIZE: usize = FRAME_SIZE;
+
+// Sv39 on RV64 and Sv32 on RV32, which differ only in how many levels of
+// tables there are and how many bits of the address each level takes: a
+// table is a page of XLEN-bit entries either way.
+#[cfg(target_arch = "riscv64")]
+const LEVELS: usize = 3;
+#[cfg(target_arch = "riscv32")]
+const LEVELS: usize = 2;
+#[cfg(target_arch = "riscv64")]
+const SATP_MODE: usize = 8 << 60;
+#[cfg(target_arch = "riscv32")]
+const SATP_MODE: usize = 1 << 31;
+
+const ENTRIES: usize = PAGE_SIZE / size_of::<Pte>();
+const VPN_BITS: usize = ENTRIES.trailing_zeros() as usize;
+
+/// Root entries below this are for user space, and the rest are the
+/// kernel's, shared by every page table.
+const USER_ENTRIES: usize = vpn(PHYS_OFFSET, LEVELS - 1);
+
+static KERNEL_PAGE_TABLE: SpinLock<Option<PageTable>> = SpinLock::new(None);
+/// The kernel page table's `satp`, readable without locking it.
+static KERNEL_SATP: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" {
+    static _stext: u8;
+    static _srodata: u8;
+    static _eflash: u8;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PteFlags(usize);
+
+impl PteFlags {
+    pub const V: Self = Self(1 << 0);
+    pub const R: Self = Self(1 << 1);
+    pub const W: Self = Self(1 << 2);
+    pub const X: Self = Self(1 << 3);
+    pub const U: Self = Self(1 << 4);
+    pub const G: Self = Self(1 << 5);
+    pub const A: Self = Self(1 << 6);
+    pub const D: Self = Self(1 << 7);
+    /// Ignored by hardware, for software to use.
+    pub const SW: Self = Self(1 << 8);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// These flags with those in `other` cleared.
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    const fn is_leaf(self) -> bool {
+        self.0 & (Self::R.0 | Self::W.0 | Self::X.0) != 0
+    }
+}
+
+impl BitOr for PteFlags {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+struct Pte(usize);
+
+impl Pte {
+    const INVALID: Self = Self(0);
+
+    fn new(paddr: usize, flags: PteFlags) -> Self {
+        Self(((paddr >> 12) << 10) | flags.0)
+    }
+
+    fn flags(self) -> PteFlags {
+        PteFlags(self.0 & 0x3ff)
+    }
+
+    /// Sv32 has 34-bit physical addresses, which don't fit; only memory
+    /// below 4 GiB is used.
+    fn paddr(self) -> usize {
+        (self.0 >> 10) << 12
+    }
+
+    fn is_valid(self) -> bool {
+        self.flags().contains(PteFlags::V)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PageSize {
+    Size4K,
+    /// 2 MiB with Sv39, 4 MiB with Sv32.
+    Mega,
+    /// 1 GiB, only with Sv39.
+    #[cfg(target_arch = "riscv64")]
+    Giga,
+}
+
+impl PageSize {
+    /// Every size, largest first.
+    #[cfg(target_arch = "riscv64")]
+    const ALL: [Self; LEVELS] = [Self::Giga, Self::Mega, Self::Size4K];
+    #[cfg(target_arch = "riscv32")]
+    const ALL: [Self; LEVELS] = [Self::Mega, Self::Size4K];
+
+    pub const fn bytes(self) -> usize {
+        PAGE_SIZE << (VPN_BITS * self.level())
+    }
+
+    /// The page table level at which a leaf of this size lives, 0 being the
+    /// last level.
+    const fn level(self) -> usize {
+        match self {
+            PageSize::Size4K => 0,
+            PageSize::Mega => 1,
+            #[cfg(target_arch = "riscv64")]
+            PageSize::Giga => 2,
+        }
+    }
+
+    const fn at_level(level: usize) -> Self {
+        Self::ALL[LEVELS - 1 - level]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MapError {
+    /// The virtual or physical address isn't aligned to the page size, or the
+    /// virtual address isn't canonical.
+    Misaligned,
+    AlreadyMapped,
+    OutOfMemory,
+}
+
+/// An Sv39 or Sv32 page table, identified by the physical address of its
+/// root.
+pub struct PageTable {
+    root: usize,
+}
+
+impl PageTable {
+    pub fn new() -> Option<Self> {
+        Some(Self {
+            root: alloc_table()?,
+        })
+    }
+
+    /// Creates a page table for a user address space. The kernel's half of
+    /// the address space is shared with the kernel page table, so mappings
+    /// added there later (under a new root entry) won't be seen.
+    pub fn new_user() -> Option<Self> {
+        let table = Self::new()?;
+        let guard = KERNEL_PAGE_TABLE.lock();
+        let kernel = guard.as_ref().expect("paging not initialized");
+        table_at(table.root)[USER_ENTRIES..]
+            .copy_from_slice(&table_at(kernel.root)[USER_ENTRIES..]);
+        Some(table)
+    }
+
+    /// The value to write to `satp` to use this page table.
+    pub fn satp(&self) -> usize {
+        SATP_MODE | (self.root >> 12)
+    }
+
+    /// Maps a single page of `size`. Leaves are created with the accessed and
+    /// dirty bits set, as hardware isn't required to manage them.
+    pub fn map(
+        &mut self,
+        vaddr: usize,
+        paddr: usize,
+        size: PageSize,
+        flags: PteFlags,
+    ) -> Result<(), MapError> {
+        if !is_canonical(vaddr)
+            || !vaddr.is_multiple_of(size.bytes())
+            || !paddr.is_multiple_of(size.bytes())
+        {
+            return Err(MapError::Misaligned);
+        }
+
+        let pte = self.walk_create(vaddr, size.level())?;
+        if pte.is_valid() {
+            return Err(MapError::AlreadyMapped);
+        }
+        *pte = Pte::new(paddr, flags | PteFlags::V | PteFlags::A | PteFlags::D);
+        Ok(())
+    }
+
+    /// Maps `len` bytes at `vaddr` to `paddr`, using the largest pages
+    /// possible. Both addresses must be page-aligned.
+    pub fn map_range(
+        &mut self,
+        vaddr: usize,
+        paddr: usize,
+        len: usize,
+        flags: PteFlags,
+    ) -> Result<(), MapError> {
+        let mut offset = 0;
+        while offset < len {
+            let size = PageSize::ALL
+                .into_iter()
+                .find(|size| {
+                    (vaddr + offset).is_multiple_of(size.bytes())
+                        && (paddr + offset).is_multiple_of(size.bytes())
+                        && len - offset >= size.bytes()
+                })
+                .unwrap_or(PageSize::Size4K);
+            self.map(vaddr + offset, paddr + offset, size, flags)?;
+            offset += size.bytes();
+        }
+        Ok(())
+    }
+
+    /// Removes the page containing `vaddr`, returning the physical address
+    /// and size of the page it mapped. The page is added to `tlb`, which
+    /// must be flushed before the memory is reused.
+    pub fn unmap(&mut self, vaddr: usize, tlb: &mut TlbBatch<'_>) -> Option<(usize, PageSize)> {
+        let (pte, size) = self.walk(vaddr)?;
+        // SAFETY: `walk` returns a pointer into one of our tables, and we
+        // have exclusive access to them.
+        let paddr = unsafe { (*pte).paddr() };
+        unsafe { *pte = Pte::INVALID };
+        tlb.add(align_down(vaddr, size.bytes()), size.bytes());
+        Some((paddr, size))
+    }
+
+    /// Changes the flags of the page containing `vaddr`, returning false if
+    /// it isn't mapped. The page is added to `tlb`, which must be flushed
+    /// before the new flags are relied on.
+    pub fn set_flags(&mut self, vaddr: usize, flags: PteFlags, tlb: &mut TlbBatch<'_>) -> bool {
+        let Some((pte, size)) = self.walk(vaddr) else {
+            return false;
+        };
+        // SAFETY: `walk` returns a pointer into one of our tables, and we
+        // have exclusive access to them.
+        unsafe {
+            *pte = Pte::new(
+                (*pte).paddr(),
+                flags | PteFlags::V | PteFlags::A | PteFlags::D,
+            )
+        };
+        tlb.add(align_down(vaddr, size.bytes()), size.bytes());
+        true
+    }
+
+    /// Returns the physical address `vaddr` maps to and the flags of its
+    /// page.
+    pub fn lookup(&self, vaddr: usize) -> Option<(usize, PteFlags)> {
+        let (pte, size) = self.walk(vaddr)?;
+        // SAFETY: `walk` returns a pointer into one of our tables.
+        let pte = unsafe { *pte };
+        Some((pte.paddr() + vaddr % size.bytes(), pte.flags()))
+    }
+
+    /// Translates a virtual address into a physical address.
+    pub fn translate(&self, vaddr: usize) -> Option<usize> {
+        let (pte, size) = self.walk(vaddr)?;
+        // SAFETY: `walk` returns a pointer into one of our tables.
+        Some(unsafe { (*pte).paddr() } + vaddr % size.bytes())
+    }
+
+    /// Finds the leaf mapping `vaddr`.
+    fn walk(&self, vaddr: usize) -> Option<(*mut Pte, PageSize)> {
+        if !is_canonical(vaddr) {
+            return None;
+        }
+        let mut table = self.root;
+        for level in (0..LEVELS).rev() {
+            let pte = &mut table_at(table)[vpn(vaddr, level)];
+            if !pte.is_valid() {
+                return None;
+            }
+            if pte.flags().is_leaf() {
+                return Some((pte, PageSize::at_level(level)));
+            }
+            table = pte.paddr();
+        }
+        None
+    }
+
+    /// Finds the entry for `vaddr` at `level`, allocating intermediate tables
+    /// as needed.
+    fn walk_create(&mut self, vaddr: usize, level: usize) -> Result<&mut Pte, MapError> {
+        let mut table = self.root;
+        for current in (level + 1..LEVELS).rev() {
+            let pte = &mut table_at(table)[vpn(vaddr, current)];
+            if !pte.is_valid() {
+                *pte = Pte::new(alloc_table().ok_or(MapError::OutOfMemory)?, PteFlags::V);
+            } else if pte.flags().is_leaf() {
+                return Err(MapError::AlreadyMapped);
+            }
+            table = pte.paddr();
+        }
+        Ok(&mut table_at(table)[vpn(vaddr, level)])
+    }
+
+    /// Counts the pages, of any size, that are both writable and
+    /// executable.
+    #[cfg(feature = "ktest")]
+    fn writable_and_executable(&self) -> usize {
+        fn count(paddr: usize, level: usize) -> usize {
+            let wx = PteFlags::W | PteFlags::X;
+            table_at(paddr)
+                .iter()
+                .filter(|pte| pte.is_valid())
+                .map(|pte| match pte.flags() {
+                    flags if flags.is_leaf() => usize::from(flags.contains(wx)),
+                    _ if level > 0 => count(pte.paddr(), level - 1),
+                    _ => 0,
+                })
+                .sum()
+        }
+        count(self.root, LEVELS - 1)
+    }
+
+    /// Switches this hart to this page table.
+    ///
+    /// SAFETY: the page table must map the currently executing code, the
+    /// stack and everything else the kernel is about to touch.
+    pub unsafe fn activate(&self) {
+        csr::satp::write(self.satp());
+        asm!("sfence.vma");
+    }
+}
+
+/// Frees the tables of the user part of the address space, and the root.
+/// The rest is shared with the kernel page table. The pages that were
+/// mapped aren't freed.
+impl Drop for PageTable {
+    fn drop(&mut self) {
+        fn free_table(paddr: usize, level: usize) {
+            if level > 0 {
+                for pte in table_at(paddr).iter() {
+                    if pte.is_valid() && !pte.flags().is_leaf() {
+                        free_table(pte.paddr(), level - 1);
+                    }
+                }
+            }
+            // SAFETY: nothing refers to the table any more.
+            unsafe { frame::free_frame(paddr) };
+        }
+
+        for pte in &table_at(self.root)[..USER_ENTRIES] {
+            if pte.is_valid() && !pte.flags().is_leaf() {
+                free_table(pte.paddr(), LEVELS - 2);
+            }
+        }
+        // SAFETY: as above.
+        unsafe { frame::free_frame(self.root) };
+    }
+}
+
+/// Whether the bits of `vaddr` above those translated are copies of the
+/// top one, as Sv39 requires. Sv32 translates every bit.
+fn is_canonical(vaddr: usize) -> bool {
+    let top = (vaddr as isize) >> (12 + VPN_BITS * LEVELS - 1);
+    top == 0 || top == -1
+}
+
+const fn vpn(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + VPN_BITS * level)) % ENTRIES
+}
+
+fn alloc_table() -> Option<usize> {
+    let paddr = frame::alloc_frame()?;
+    table_at(paddr).fill(Pte::INVALID);
+    Some(paddr)
+}
+
+fn table_at(paddr: usize) -> &'static mut [Pte; ENTRIES] {
+    // SAFETY: page tables are only reached through a `PageTable`, whose
+    // methods take `&mut self`.
+    unsafe { &mut *(phys_to_virt(paddr) as *mut [Pte; ENTRIES]) }
+}
+
+/// Builds the kernel page table and switches to it, replacing the boot page
+/// table (and its identity mapping) from start.s.
+///
+/// No kernel memory is both writable and executable: the kernel's text in
+/// flash is mapped read-only and executable, the rest of the image in flash
+/// (rodata and the initial values of data) read-only, and RAM from the
+/// `/memory` nodes, which holds data, bss and the heap, read-write at
+/// `PHYS_OFFSET`, apart from the image on boards that load it into RAM.
+/// The image is linked to run at a fixed address, so where
+/// it's mapped isn't randomized; only kernel stacks are (see `stack`).
+pub fn init(dt: &DeviceTree<'_>) {
+    let mut table = PageTable::new().expect("out of memory for kernel page table");
+
+    let text = align_down(&raw const _stext as usize, PAGE_SIZE);
+    // The linker script starts rodata on a page of its own.
+    let rodata = &raw const _srodata as usize;
+    let flash_end = align_up(&raw const _eflash as usize, PAGE_SIZE);
+    for (start, end, flags) in [
+        (text, rodata, PteFlags::R | PteFlags::X),
+        (rodata, flash_end, PteFlags::R),
+    ] {
+        table
+            .map_range(start, virt_to_phys(start), end - start, flags | PteFlags::G)
+            .expect("failed to map kernel image");
+    }
+
+    // On boards without flash the image is in RAM, and as PHYS_OFFSET is
+    // KERNEL_OFFSET it's already mapped there, as above.
+    let image = virt_to_phys(text)..virt_to_phys(flash_end);
+    for range in frame::memory_regions(dt) {
+        let start = align_down(range.start, PAGE_SIZE);
+        let end = align_up(range.end, PAGE_SIZE);
+        for (start, end) in [(start, end.min(image.start)), (start.max(image.end), end)] {
+            if start >= end {
+                continue;
+            }
+            table
+                .map_range(
+                    phys_to_virt(start),
+                    start,
+                    end - start,
+                    PteFlags::R | PteFlags::W | PteFlags::G,
+                )
+                .expect("failed to map physical memory");
+        }
+    }
+
+    // SAFETY: the kernel image and all of RAM (which holds the stack and
+    // the DTB) are mapped.
+    unsafe { table.activate() };
+    KERNEL_SATP.store(table.satp(), Ordering::Relaxed);
+    *KERNEL_PAGE_TABLE.lock() = Some(table);
+}
+
+/// The `satp` value for the kernel page table, for starting other harts and
+/// running kernel tasks.
+pub fn kernel_satp() -> usize {
+    let satp = KERNEL_SATP.load(Ordering::Relaxed);
+    assert!(satp != 0, "paging not initialized");
+    satp
+}
+
+/// Translates `vaddr` through the kernel page table.
+pub fn translate(vaddr: usize) -> Option<usize> {
+    KERNEL_PAGE_TABLE.lock().as_ref()?.translate(vaddr)
+}
+
+/// Looks `vaddr` up in the kernel page table, returning the physical
+/// address it maps to and the flags of its page.
+pub fn lookup(vaddr: usize) -> Option<(usize, PteFlags)> {
+    KERNEL_PAGE_TABLE.lock().as_ref()?.lookup(vaddr)
+}
+
+/// Like `translate`, but `None` rather than waiting if the kernel page table
+/// is locked, as it may be by the code a trap from S-mode interrupted.
+pub fn try_translate(vaddr: usize) -> Option<usize> {
+    KERNEL_PAGE_TABLE.try_lock()?.as_ref()?.translate(vaddr)
+}
+
+/// Maps the frame at `paddr` read-write at `vaddr`, which must be outside
+/// the physical memory mapping, for kernel memory that needs a layout of its
+/// own, like guarded stacks.
+pub fn map_kernel_page(vaddr: usize, paddr: usize) -> Result<(), MapError> {
+    let mut guard = KERNEL_PAGE_TABLE.lock();
+    let table = guard.as_mut().expect("paging not initialized");
+    table.map(
+        vaddr,
+        paddr,
+        PageSize::Size4K,
+        PteFlags::R | PteFlags::W | PteFlags::G,
+    )?;
+    tlb::flush_local(vaddr);
+    Ok(())
+}
+
+/// Maps the device registers at `paddr` into the kernel's physical memory
+/// mapping, returning their virtual address. Pages that are already mapped
+/// are left as they are. Drivers use `mm::ioremap` instead.
+pub fn map_mmio(paddr: usize, len: usize) -> Result<usize, MapError> {
+    let mut guard = KERNEL_PAGE_TABLE.lock();
+    let table = guard.as_mut().expect("paging not initialized");
+
+    let start = align_down(paddr, PAGE_SIZE);
+    let end = align_up(paddr + len, PAGE_SIZE);
+    for page in (start..end).step_by(PAGE_SIZE) {
+        let vaddr = phys_to_virt(page);
+        if table.translate(vaddr).is_none() {
+            table.map(
+                vaddr,
+                page,
+                PageSize::Size4K,
+                PteFlags::R | PteFlags::W | PteFlags::G,
+            )?;
+            tlb::flush_local(vaddr);
+        }
+    }
+    Ok(phys_to_virt(paddr))
+}
+
+ktest! {
+    fn kernel_w_xor_x() {
+        static RODATA: [u8; 4] = *b"ro\0\0";
+        static DATA: AtomicUsize = AtomicUsize::new(0);
+        let flags = |vaddr: usize| {
+            let (_, flags) = KERNEL_PAGE_TABLE.lock().as_ref().unwrap().lookup(vaddr).unwrap();
+            (flags.contains(PteFlags::W), flags.contains(PteFlags::X))
+        };
+        assert_eq!(flags(init as *const () as usize), (false, true));
+        assert_eq!(flags(RODATA.as_ptr() as usize), (false, false));
+        assert_eq!(flags(&raw const DATA as usize), (true, false));
+        // Nor anything else, mapped since boot or not.
+        assert_eq!(KERNEL_PAGE_TABLE.lock().as_ref().unwrap().writable_and_executable(), 0);
+
+        let mut table = PageTable::new_user().unwrap();
+        let rwx = PteFlags::R | PteFlags::W | PteFlags::X | PteFlags::U;
+        table.map(0x1000, 0x8000_0000, PageSize::Size4K, rwx).unwrap();
+        assert_eq!(table.writable_and_executable(), 1);
+    }
+}
+
+ktest! {
+    fn maps_translates_and_unmaps() {
+        let harts = AtomicUsize::new(0);
+        let mut tlb = TlbBatch::new(&harts);
+        let mut table = PageTable::new_user().unwrap();
+        let mega = PageSize::Mega.bytes();
+        let rw = PteFlags::R | PteFlags::W | PteFlags::U;
+
+        table.map(0x1000, 0x8000_0000, PageSize::Size4K, rw).unwrap();
+        assert_eq!(table.translate(0x1234), Some(0x8000_0234));
+        assert_eq!(table.translate(0x2000), None);
+        assert_eq!(
+            table.map(0x1000, 0x8000_1000, PageSize::Size4K, rw),
+            Err(MapError::AlreadyMapped)
+        );
+        assert_eq!(
+            table.map(0x1800, 0x8000_1000, PageSize::Size4K, rw),
+            Err(MapError::Misaligned)
+        );
+        #[cfg(target_arch = "riscv64")]
+        assert_eq!(
+            table.map(1 << 40, 0x8000_1000, PageSize::Size4K, rw),
+            Err(MapError::Misaligned)
+        );
+
+        // A whole large page in the middle, with small ones either side.
+        table
+            .map_range(mega - PAGE_SIZE, 0x8000_0000 + mega - PAGE_SIZE, mega + 2 * PAGE_SIZE, rw)
+            .unwrap();
+        assert_eq!(table.translate(mega + 0x42), Some(0x8000_0000 + mega + 0x42));
+        assert!(table.set_flags(mega, PteFlags::R | PteFlags::U, &mut tlb));
+        let (_, flags) = table.lookup(mega + mega - 1).unwrap();
+        assert!(flags.contains(PteFlags::R) && !flags.contains(PteFlags::W));
+        assert_eq!(table.unmap(mega + 0x42, &mut tlb), Some((0x8000_0000 + mega, PageSize::Mega)));
+        assert_eq!(table.translate(mega), None);
+        assert_eq!(table.translate(2 * mega), Some(0x8000_0000 + 2 * mega));
+
+        assert_eq!(table.unmap(0x1000, &mut tlb), Some((0x8000_0000, PageSize::Size4K)));
+        assert_eq!(table.unmap(0x1000, &mut tlb), None);
+        assert!(!table.set_flags(0x1000, rw, &mut tlb));
+    }
+}
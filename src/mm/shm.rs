@@ -0,0 +1,239 @@
+//! Shared memory, which address spaces map with `mmap`'s `MAP_SHARED`
+//! rather than each having a copy, so that what one process writes to it
+//! the others see, for IPC between a process and its `fork`ed children.
+//!
+//! Shared memory holds a reference to each of its frames, allocated and
+//! zeroed when any mapping first faults it in, and each address space that
+//! maps one holds another, counted as for the pages `fork` shares. A frame
+//! is freed once the shared memory and every mapping of it are gone. As
+//! for any page, unmapping one is flushed from the TLB of every hart the
+//! address space unmapping it is active on before its reference is dropped.
+//!
+//! Shared memory with a `Backing` is the page cache of a file: its frames
+//! are read from the file rather than zeroed, and those that have been
+//! mapped writable are written back to it by `write_back`, and when it's
+//! dropped.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::ktest;
+use crate::mm::addr_space::release_frame;
+use crate::mm::frame::{self, FRAME_SIZE};
+use crate::mm::phys_to_virt;
+use crate::sync::SpinLock;
+
+/// Where the pages of a file's shared memory come from, and go back to.
+pub trait Backing: Send + Sync {
+    /// Fills `page` with page `index` of the file, zeroing what's past its
+    /// end. Returns false if it can't be read.
+    fn read_page(&self, index: usize, page: &mut [u8]) -> bool;
+
+    /// Writes `page` back to page `index` of the file, without extending
+    /// it. Returns false if it can't be written.
+    fn write_page(&self, index: usize, page: &[u8]) -> bool;
+}
+
+pub struct SharedMemory {
+    /// The frame at each page that's been faulted in.
+    frames: SpinLock<BTreeMap<usize, usize>>,
+    backing: Option<Box<dyn Backing>>,
+    /// The pages that have been mapped writable, which may have been
+    /// written to since they were last written back.
+    dirty: SpinLock<BTreeSet<usize>>,
+}
+
+impl SharedMemory {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            frames: SpinLock::new(BTreeMap::new()),
+            backing: None,
+            dirty: SpinLock::new(BTreeSet::new()),
+        })
+    }
+
+    /// Shared memory holding the pages of a file, read from and written
+    /// back to `backing`.
+    pub fn with_backing(backing: impl Backing + 'static) -> Arc<Self> {
+        Arc::new(Self {
+            frames: SpinLock::new(BTreeMap::new()),
+            backing: Some(Box::new(backing)),
+            dirty: SpinLock::new(BTreeSet::new()),
+        })
+    }
+
+    /// The frame at page `index`, allocated and filled if it hasn't been
+    /// yet. Returns `None` if there's no memory for it or it can't be read
+    /// from the backing file, which may sleep.
+    pub fn frame(&self, index: usize) -> Option<usize> {
+        if let Some(&paddr) = self.frames.lock().get(&index) {
+            return Some(paddr);
+        }
+        let paddr = frame::alloc_frame()?;
+        // SAFETY: the frame was just allocated and is mapped at
+        // `phys_to_virt(paddr)`.
+        let page =
+            unsafe { core::slice::from_raw_parts_mut(phys_to_virt(paddr) as *mut u8, FRAME_SIZE) };
+        let filled = match &self.backing {
+            Some(backing) => backing.read_page(index, page),
+            None => {
+                page.fill(0);
+                true
+            }
+        };
+        let mut frames = self.frames.lock();
+        let existing = frames.get(&index).copied();
+        if !filled || existing.is_some() {
+            // SAFETY: the frame was never mapped.
+            unsafe { frame::free_frame(paddr) };
+            return existing;
+        }
+        frames.insert(index, paddr);
+        Some(paddr)
+    }
+
+    /// Notes that page `index` has been mapped writable, so needs writing
+    /// back.
+    pub fn mark_dirty(&self, index: usize) {
+        if self.backing.is_some() {
+            self.dirty.lock().insert(index);
+        }
+    }
+
+    /// Writes the pages that have been mapped writable back to the backing
+    /// file. They stay dirty, as they may still be written to. Returns
+    /// false if any couldn't be written.
+    pub fn write_back(&self) -> bool {
+        let Some(backing) = &self.backing else {
+            return true;
+        };
+        let dirty: Vec<usize> = self.dirty.lock().iter().copied().collect();
+        let mut ok = true;
+        for index in dirty {
+            let Some(&paddr) = self.frames.lock().get(&index) else {
+                continue;
+            };
+            // SAFETY: frames are only freed once the shared memory is
+            // dropped.
+            let page = unsafe {
+                core::slice::from_raw_parts(phys_to_virt(paddr) as *const u8, FRAME_SIZE)
+            };
+            ok &= backing.write_page(index, page);
+        }
+        ok
+    }
+
+    /// Reads the pages from `first` to `last` that have frames from the
+    /// backing file again, after it's been written to other than through
+    /// them.
+    pub fn refresh(&self, first: usize, last: usize) {
+        let Some(backing) = &self.backing else {
+            return;
+        };
+        let frames: Vec<(usize, usize)> = self
+            .frames
+            .lock()
+            .range(first..=last)
+            .map(|(&index, &paddr)| (index, paddr))
+            .collect();
+        for (index, paddr) in frames {
+            // SAFETY: as in `write_back`.
+            let page = unsafe {
+                core::slice::from_raw_parts_mut(phys_to_virt(paddr) as *mut u8, FRAME_SIZE)
+            };
+            backing.read_page(index, page);
+        }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        self.write_back();
+        for &paddr in self.frames.lock().values() {
+            // SAFETY: only address spaces map the frame now, and they hold
+            // references of their own.
+            unsafe { release_frame(paddr) };
+        }
+    }
+}
+
+ktest! {
+    fn shared_pages_stay_shared_across_fork() {
+        use crate::mm::addr_space::{Access, AddressSpace};
+        use crate::mm::paging::{PteFlags, PAGE_SIZE};
+
+        let shared = 0x10000;
+        let private = 0x20000;
+        let flags = PteFlags::R | PteFlags::W;
+        let parent = AddressSpace::new().unwrap();
+        parent
+            .map_shared(shared, PAGE_SIZE, flags, SharedMemory::new())
+            .unwrap();
+        parent.map_lazy(private, PAGE_SIZE, flags).unwrap();
+        parent.handle_fault(shared, Access::Write).unwrap();
+        parent.handle_fault(private, Access::Write).unwrap();
+
+        let child = parent.fork().unwrap();
+        assert!(child.debug_write(shared, b"hi"));
+        assert!(child.debug_write(private, b"hi"));
+        let mut buf = [0; 2];
+        assert!(parent.debug_read(shared, &mut buf));
+        assert_eq!(&buf, b"hi");
+        assert!(parent.debug_read(private, &mut buf));
+        assert_eq!(buf, [0; 2]);
+    }
+}
+
+ktest! {
+    fn file_pages_are_read_and_written_back() {
+        use alloc::vec;
+
+        use crate::mm::addr_space::AddressSpace;
+        use crate::mm::paging::{PteFlags, PAGE_SIZE};
+
+        struct File(Arc<SpinLock<Vec<u8>>>);
+        impl Backing for File {
+            fn read_page(&self, index: usize, page: &mut [u8]) -> bool {
+                let file = self.0.lock();
+                let start = (index * PAGE_SIZE).min(file.len());
+                let len = (file.len() - start).min(page.len());
+                page[..len].copy_from_slice(&file[start..start + len]);
+                page[len..].fill(0);
+                true
+            }
+            fn write_page(&self, index: usize, page: &[u8]) -> bool {
+                let mut file = self.0.lock();
+                let start = (index * PAGE_SIZE).min(file.len());
+                let len = (file.len() - start).min(page.len());
+                file[start..start + len].copy_from_slice(&page[..len]);
+                true
+            }
+        }
+
+        let mut data = vec![b'a'; PAGE_SIZE];
+        data.extend_from_slice(b"bbb");
+        let data = Arc::new(SpinLock::new(data));
+        let pages = SharedMemory::with_backing(File(data.clone()));
+        let (shared, private) = (0x10000, 0x20000);
+        let flags = PteFlags::R | PteFlags::W;
+        let space = AddressSpace::new().unwrap();
+        space.map_pages(shared, 2 * PAGE_SIZE, flags, pages.clone(), 0, false).unwrap();
+        space.map_pages(private, PAGE_SIZE, flags, pages.clone(), 1, true).unwrap();
+
+        let mut buf = [0; 4];
+        assert!(space.debug_read(shared + PAGE_SIZE, &mut buf));
+        assert_eq!(&buf, b"bbb\0");
+        assert!(space.debug_write(private, b"P"));
+        assert!(space.debug_write(shared + PAGE_SIZE, b"S"));
+        assert!(space.debug_read(private, &mut buf));
+        assert_eq!(&buf, b"Pbb\0");
+
+        // Only the shared mapping's write reaches the file.
+        assert!(pages.write_back());
+        assert_eq!(&data.lock()[PAGE_SIZE..], b"Sbb");
+        // Writing back doesn't extend it.
+        assert_eq!(data.lock().len(), PAGE_SIZE + 3);
+    }
+}
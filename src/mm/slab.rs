@@ -0,0 +1,211 @@
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr::{self, NonNull};
+
+use crate::ktest;
+use crate::mm::frame::{self, FRAME_SIZE};
+use crate::mm::phys_to_virt;
+use crate::sync::SpinLock;
+use crate::util::align_up;
+
+/// Each slab holds at least this many objects.
+const MIN_OBJECTS: usize = 8;
+
+/// A cache of fixed-size objects of type `T`, carved out of slabs of whole
+/// frames. Freed objects go onto the cache's free list and are reused by
+/// later allocations; slabs are never given back to the frame allocator.
+pub struct Cache<T> {
+    name: &'static str,
+    slabs: SpinLock<Slabs>,
+    _marker: PhantomData<T>,
+}
+
+// SAFETY: objects are handed out by value, so the cache can be shared
+// between harts as long as `T` can be sent between them.
+unsafe impl<T: Send> Sync for Cache<T> {}
+
+impl<T> Cache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        assert!(align_of::<T>() <= FRAME_SIZE);
+        Self {
+            name,
+            slabs: SpinLock::new(Slabs::new(
+                Self::OBJECT_SIZE,
+                align_up(Self::OBJECT_SIZE * MIN_OBJECTS, FRAME_SIZE) / FRAME_SIZE,
+            )),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Objects are spaced so that each one is aligned and can hold a free
+    /// list link.
+    const OBJECT_SIZE: usize = {
+        let align = if align_of::<T>() > align_of::<FreeObject>() {
+            align_of::<T>()
+        } else {
+            align_of::<FreeObject>()
+        };
+        let size = if size_of::<T>() > size_of::<FreeObject>() {
+            size_of::<T>()
+        } else {
+            size_of::<FreeObject>()
+        };
+        size.div_ceil(align) * align
+    };
+
+    /// Moves `value` into a new object, returning `None` if a new slab is
+    /// needed and no frames are free.
+    pub fn alloc(&self, value: T) -> Option<NonNull<T>> {
+        let object = self.slabs.lock().alloc()?.cast::<T>();
+        // SAFETY: the object is unused, and sized and aligned for `T`.
+        unsafe { object.write(value) };
+        Some(object)
+    }
+
+    /// Drops the object at `object` and returns it to the cache.
+    ///
+    /// SAFETY: `object` must have been returned by `alloc` on this cache and
+    /// not be used after this call.
+    pub unsafe fn free(&self, object: NonNull<T>) {
+        ptr::drop_in_place(object.as_ptr());
+        self.slabs.lock().free(object.cast());
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let slabs = self.slabs.lock();
+        CacheStats {
+            name: self.name,
+            object_size: slabs.object_size,
+            slabs: slabs.slabs,
+            objects: slabs.objects,
+            in_use: slabs.in_use,
+        }
+    }
+}
+
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    /// Slabs obtained from the frame allocator.
+    pub slabs: usize,
+    /// Objects in all slabs.
+    pub objects: usize,
+    pub in_use: usize,
+}
+
+/// A free object, stored in the object itself.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// The type-independent part of a cache.
+struct Slabs {
+    object_size: usize,
+    frames_per_slab: usize,
+    free_list: *mut FreeObject,
+    slabs: usize,
+    objects: usize,
+    in_use: usize,
+}
+
+// SAFETY: the free objects are only accessed through the free list.
+unsafe impl Send for Slabs {}
+
+impl Slabs {
+    const fn new(object_size: usize, frames_per_slab: usize) -> Self {
+        Self {
+            object_size,
+            frames_per_slab,
+            free_list: ptr::null_mut(),
+            slabs: 0,
+            objects: 0,
+            in_use: 0,
+        }
+    }
+
+    fn alloc(&mut self) -> Option<NonNull<u8>> {
+        if self.free_list.is_null() {
+            self.grow()?;
+        }
+        let object = self.free_list;
+        // SAFETY: objects on the free list are unused and hold a link.
+        self.free_list = unsafe { (*object).next };
+        self.in_use += 1;
+        NonNull::new(object.cast())
+    }
+
+    /// SAFETY: `object` must be an unused object from one of our slabs.
+    unsafe fn free(&mut self, object: NonNull<u8>) {
+        let object = object.cast::<FreeObject>().as_ptr();
+        object.write(FreeObject {
+            next: self.free_list,
+        });
+        self.free_list = object;
+        self.in_use -= 1;
+    }
+
+    /// Adds a new slab, putting all of its objects on the free list.
+    fn grow(&mut self) -> Option<()> {
+        let slab = phys_to_virt(frame::alloc_frames(self.frames_per_slab)?);
+        let count = self.frames_per_slab * FRAME_SIZE / self.object_size;
+        for index in (0..count).rev() {
+            let object = (slab + index * self.object_size) as *mut FreeObject;
+            // SAFETY: the slab was just allocated, and each object is
+            // aligned for a link.
+            unsafe {
+                object.write(FreeObject {
+                    next: self.free_list,
+                })
+            };
+            self.free_list = object;
+        }
+        self.slabs += 1;
+        self.objects += count;
+        Some(())
+    }
+}
+
+ktest! {
+    fn reuses_freed_objects() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+        #[repr(align(32))]
+        struct Object(u8);
+
+        impl Drop for Object {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(usize::from(self.0), Ordering::Relaxed);
+            }
+        }
+
+        // The slab is never given back, as for any cache.
+        let cache = Cache::<Object>::new("test");
+        assert_eq!(Cache::<Object>::OBJECT_SIZE, 32);
+        assert_eq!(Cache::<u8>::OBJECT_SIZE, size_of::<FreeObject>());
+
+        let first = cache.alloc(Object(1)).unwrap();
+        let second = cache.alloc(Object(2)).unwrap();
+        assert!(first.as_ptr().is_aligned() && second.as_ptr().is_aligned());
+        assert_ne!(first, second);
+        let stats = cache.stats();
+        assert_eq!((stats.slabs, stats.in_use), (1, 2));
+        assert_eq!(stats.objects, FRAME_SIZE / 32);
+
+        // SAFETY: `second` came from the cache, and isn't used again.
+        unsafe { cache.free(second) };
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 2);
+        let third = cache.alloc(Object(3)).unwrap();
+        assert_eq!(third, second);
+        assert_eq!(cache.stats().in_use, 2);
+
+        // SAFETY: as above.
+        unsafe {
+            cache.free(first);
+            cache.free(third);
+        }
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 6);
+        assert_eq!(cache.stats().in_use, 0);
+    }
+}
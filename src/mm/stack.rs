@@ -0,0 +1,278 @@
+//! Kernel stacks, for tasks and for harts to boot on. Each one is mapped on
+//! its own in a region of the kernel's address space kept for stacks, with
+//! unmapped pages below it, so running off the end of a stack faults rather
+//! than overwriting whatever is next. trap.s spots when that fault's own
+//! frame would go into the guard and reports it from the hart's overflow
+//! stack instead.
+//!
+//! Stacks are filled with a pattern when they're handed out, so how deep
+//! one has gone is how much of the pattern has been overwritten.
+//!
+//! Each stack starts a random distance below the top of its mapping, so
+//! that where things land on it can't be counted on. `nokaslr` on the
+//! command line starts every stack at the top.
+//!
+//! A stack that's dropped stays mapped to be handed out again, as unmapping
+//! it would leave other harts with stale translations of it.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::{csr, REGBYTES};
+use crate::mm::frame::{self, FRAME_SIZE};
+use crate::mm::{paging, phys_to_virt};
+use crate::sync::SpinLockIrqSave;
+use crate::{backtrace, cmdline, ktest, log_warn, percpu, rand};
+
+/// The size of every kernel stack, in frames.
+pub const STACK_FRAMES: usize = 4;
+pub const STACK_SIZE: usize = STACK_FRAMES * FRAME_SIZE;
+
+/// Each stack is at the top of a slot twice its size, leaving as much again
+/// unmapped below it. The region is under a single root page table entry,
+/// which mapping the boot stack creates before any user address space
+/// copies the kernel's half, and above the physical memory mapping. Must
+/// match STACK_REGION, STACK_REGION_SHIFT and STACK_SHIFT in trap.s. With
+/// Sv32 that's only 4 MiB, and it's below the last 4 MiB so that no stack
+/// ends at the very top of the address space.
+const SLOT_SIZE: usize = 2 * STACK_SIZE;
+#[cfg(target_arch = "riscv64")]
+pub(super) const REGION: usize = 0xffff_ffff_0000_0000;
+#[cfg(target_arch = "riscv64")]
+const REGION_SIZE: usize = 1 << 30;
+#[cfg(target_arch = "riscv32")]
+pub(super) const REGION: usize = 0xff80_0000;
+#[cfg(target_arch = "riscv32")]
+const REGION_SIZE: usize = 1 << 22;
+
+/// The size of each hart's overflow stack, which only has to be big enough
+/// to panic on.
+const OVERFLOW_STACK_FRAMES: usize = 4;
+
+/// The furthest below the top of its mapping a stack can start.
+const MAX_OFFSET: usize = 1024;
+
+/// What unused stack looks like.
+const PATTERN: u64 = 0x5f5f_4b43_4154_535f;
+
+/// A stack that's used more than this is reported when it's dropped.
+const WARN_USAGE: usize = STACK_SIZE * 3 / 4;
+
+percpu! {
+    /// The top of this hart's overflow stack, read by trap.s.
+    #[no_mangle]
+    static OVERFLOW_STACK_TOP: AtomicUsize = AtomicUsize::new(0);
+}
+
+struct Slots {
+    /// The slots of every stack ever made.
+    mapped: usize,
+    /// Of those, the slots of stacks that have been dropped.
+    free: Vec<usize>,
+    /// The slots of stacks in use.
+    live: BTreeSet<usize>,
+}
+
+static SLOTS: SpinLockIrqSave<Slots> = SpinLockIrqSave::new(Slots {
+    mapped: 0,
+    free: Vec::new(),
+    live: BTreeSet::new(),
+});
+
+/// The most any dropped stack used.
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// The lowest address of the stack in `slot`.
+fn bottom(slot: usize) -> usize {
+    REGION + slot * SLOT_SIZE + SLOT_SIZE - STACK_SIZE
+}
+
+/// How many bytes of the stack in `slot` have been overwritten.
+fn usage(slot: usize) -> usize {
+    let words = bottom(slot) as *const u64;
+    let unused = (0..STACK_SIZE / 8)
+        // SAFETY: the stack is mapped, and reading it as it changes can only
+        // get the word wrong.
+        .take_while(|&i| unsafe { words.add(i).read_volatile() } == PATTERN)
+        .count();
+    STACK_SIZE - unused * 8
+}
+
+/// A kernel stack with a guard below it.
+pub struct KernelStack {
+    slot: usize,
+    /// How far below the top of the mapping the stack starts.
+    offset: usize,
+}
+
+impl KernelStack {
+    pub fn new() -> Option<Self> {
+        let mut slots = SLOTS.lock();
+        let slot = match slots.free.pop() {
+            Some(slot) => slot,
+            None => {
+                let slot = slots.mapped;
+                if (slot + 1) * SLOT_SIZE > REGION_SIZE {
+                    return None;
+                }
+                // Pages mapped before running out of memory stay mapped, for
+                // when the slot's tried again.
+                for page in (bottom(slot)..bottom(slot) + STACK_SIZE).step_by(FRAME_SIZE) {
+                    if paging::translate(page).is_some() {
+                        continue;
+                    }
+                    let paddr = frame::alloc_frame()?;
+                    if paging::map_kernel_page(page, paddr).is_err() {
+                        // SAFETY: the frame was just allocated and isn't used.
+                        unsafe { frame::free_frame(paddr) };
+                        return None;
+                    }
+                }
+                slots.mapped += 1;
+                slot
+            }
+        };
+        slots.live.insert(slot);
+        drop(slots);
+
+        // SAFETY: the stack is mapped and nothing uses it.
+        unsafe {
+            core::slice::from_raw_parts_mut(bottom(slot) as *mut u64, STACK_SIZE / 8).fill(PATTERN)
+        };
+        Some(Self {
+            slot,
+            offset: random_offset(),
+        })
+    }
+
+    /// The address just above the stack, where the stack pointer starts.
+    pub fn top(&self) -> usize {
+        bottom(self.slot) + STACK_SIZE - self.offset
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let used = usage(self.slot);
+        PEAK.fetch_max(used, Ordering::Relaxed);
+        if used > WARN_USAGE {
+            log_warn!(
+                target: "stack",
+                "a stack dropped with {} of {} bytes used",
+                used,
+                STACK_SIZE
+            );
+        }
+        let mut slots = SLOTS.lock();
+        slots.live.remove(&self.slot);
+        slots.free.push(self.slot);
+    }
+}
+
+/// How much of a stack is used.
+pub struct Usage {
+    /// The top of the stack.
+    pub top: usize,
+    pub used: usize,
+}
+
+/// How much of every stack in use has been used, and the most any dropped
+/// stack used.
+pub fn usages() -> (Vec<Usage>, usize) {
+    let live: Vec<usize> = SLOTS.lock().live.iter().copied().collect();
+    let usages = live
+        .into_iter()
+        .map(|slot| Usage {
+            top: bottom(slot) + STACK_SIZE,
+            used: usage(slot),
+        })
+        .collect();
+    (usages, PEAK.load(Ordering::Relaxed))
+}
+
+/// A multiple of 16, to keep the stack pointer aligned, up to `MAX_OFFSET`.
+fn random_offset() -> usize {
+    if cmdline::has("nokaslr") {
+        return 0;
+    }
+    rand::below((MAX_OFFSET / 16 + 1) as u32) as usize * 16
+}
+
+/// Gives this hart an overflow stack, which must be done before it runs on a
+/// `KernelStack`, as overflowing one without it faults endlessly.
+pub fn init_hart() {
+    let paddr = frame::alloc_frames(OVERFLOW_STACK_FRAMES).expect("no memory for overflow stack");
+    OVERFLOW_STACK_TOP.get().store(
+        phys_to_virt(paddr) + OVERFLOW_STACK_FRAMES * FRAME_SIZE,
+        Ordering::Relaxed,
+    );
+}
+
+/// Switches to `stack` and runs `f` on it, which mustn't return. The stack
+/// is never freed.
+///
+/// # Safety
+///
+/// Nothing on the current stack may be used again, as it's left
+/// behind for good.
+pub unsafe fn run_on(stack: KernelStack, f: impl FnOnce() + 'static) -> ! {
+    extern "C" fn trampoline(f: *mut Box<dyn FnOnce()>) -> ! {
+        // SAFETY: `f` was leaked by `run_on` for us.
+        let f = unsafe { Box::from_raw(f) };
+        f();
+        panic!("returned from the bottom of a stack");
+    }
+
+    let top = stack.top();
+    core::mem::forget(stack);
+    let f: Box<Box<dyn FnOnce()>> = Box::new(Box::new(f));
+    // Clearing `fp` ends backtraces here.
+    asm!(
+        "mv sp, {top}",
+        "li s0, 0",
+        "jr {trampoline}",
+        top = in(reg) top,
+        trampoline = in(reg) trampoline as extern "C" fn(_) -> !,
+        in("a0") Box::into_raw(f),
+        options(noreturn),
+    )
+}
+
+/// Called by trap.s, on the overflow stack, when a trap from S-mode finds
+/// the stack pointer too close to a guard to save the trap frame. Only the
+/// stack and frame pointers are left of the interrupted code.
+#[no_mangle]
+extern "C" fn kernel_stack_overflow(sp: usize, fp: usize) -> ! {
+    let sepc = csr::sepc::read();
+    // The frame record, the two registers below `fp`, may itself be in the
+    // guard.
+    if paging::try_translate(fp.wrapping_sub(2 * REGBYTES)).is_some() {
+        backtrace::print_from(sepc, fp);
+    }
+    panic!(
+        "kernel stack overflow on hart {}: sp = {:#x}, pc = {:#x}",
+        percpu::hart_id(),
+        sp,
+        sepc
+    );
+}
+
+ktest! {
+    fn fills_and_measures_stacks() {
+        let stack = KernelStack::new().unwrap();
+        let bottom = bottom(stack.slot);
+        assert!((bottom + STACK_SIZE - MAX_OFFSET..=bottom + STACK_SIZE).contains(&stack.top()));
+        assert_eq!(stack.top() % 16, 0);
+        assert_eq!(usage(stack.slot), 0);
+        // The guard below it is unmapped.
+        assert!(paging::translate(bottom - FRAME_SIZE).is_none());
+        assert!(paging::translate(bottom).is_some());
+
+        // SAFETY: the stack is mapped, and nothing runs on it.
+        unsafe { ((stack.top() - 8) as *mut u64).write(0) };
+        assert_eq!(usage(stack.slot), bottom + STACK_SIZE - stack.top() + 8);
+    }
+}
@@ -0,0 +1,155 @@
+//! Flushing stale translations from TLBs, this hart's and others'.
+//!
+//! A change to a page table is only seen by a hart once it has run
+//! `sfence.vma`, and each hart can only run it for itself. Changes are
+//! gathered in a `TlbBatch`, which knows which harts may have the page
+//! table in use, and flushed together: locally with `sfence.vma`, and on
+//! other harts with the SBI RFENCE extension, or, without it, by IPIs that
+//! have them flush everything. When no other hart is involved, as on a
+//! single-hart system, nothing is sent.
+//!
+//! New mappings only need flushing on the hart that first uses them, since
+//! other harts haven't looked them up before, so they needn't be batched.
+
+use core::arch::asm;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+
+use crate::mm::paging::PAGE_SIZE;
+use crate::sbi::rfence::{self, SBI_EID_RFENCE};
+use crate::{ktest, log_debug, log_warn, percpu, sbi, smp};
+
+/// Flushing a range of more pages than this flushes everything instead.
+const MAX_FLUSH_PAGES: usize = 64;
+
+static HAVE_RFENCE: AtomicBool = AtomicBool::new(false);
+
+/// Probes for the RFENCE extension.
+pub fn init() {
+    let have = sbi::probe_extension(SBI_EID_RFENCE);
+    HAVE_RFENCE.store(have, Ordering::Relaxed);
+    if !have {
+        log_debug!(target: "tlb", "no SBI RFENCE, using IPIs");
+    }
+}
+
+/// Flushes this hart's translations of the page containing `vaddr`.
+pub fn flush_local(vaddr: usize) {
+    // SAFETY: a fence has no other effects.
+    unsafe { asm!("sfence.vma {}, zero", in(reg) vaddr) };
+}
+
+/// Flushes all of this hart's translations.
+pub fn flush_local_all() {
+    // SAFETY: a fence has no other effects.
+    unsafe { asm!("sfence.vma") };
+}
+
+/// Pages whose mappings have changed, to be flushed from the TLBs of some
+/// harts. They're flushed when the batch is, or when it's dropped, which
+/// must be before the memory they mapped is reused.
+pub struct TlbBatch<'a> {
+    /// The mask of harts the page table is in use on, read when flushing
+    /// so that harts that switch to it meanwhile are included.
+    harts: &'a AtomicUsize,
+    /// The range of addresses covering every page added.
+    start: usize,
+    end: usize,
+}
+
+impl<'a> TlbBatch<'a> {
+    pub fn new(harts: &'a AtomicUsize) -> Self {
+        Self {
+            harts,
+            start: usize::MAX,
+            end: 0,
+        }
+    }
+
+    /// Adds the page of `size` bytes at `vaddr`. The last page of the
+    /// address space ends at `usize::MAX` rather than wrapping to 0.
+    pub fn add(&mut self, vaddr: usize, size: usize) {
+        self.start = self.start.min(vaddr);
+        self.end = self.end.max(vaddr.saturating_add(size));
+    }
+
+    /// Flushes the pages added so far, waiting for every hart to.
+    pub fn flush(&mut self) {
+        if self.start >= self.end {
+            return;
+        }
+        let (start, end) = (self.start, self.end);
+        self.start = usize::MAX;
+        self.end = 0;
+        let all = (end - start) / PAGE_SIZE > MAX_FLUSH_PAGES;
+
+        // Pairs with the fence in `enter`: either a hart switching to the
+        // page table sees the changes, or it's seen here.
+        fence(Ordering::SeqCst);
+        let harts = self.harts.load(Ordering::Relaxed);
+        let this = 1 << percpu::hart_id();
+        if harts & this != 0 {
+            if all {
+                flush_local_all();
+            } else {
+                (start..end).step_by(PAGE_SIZE).for_each(flush_local);
+            }
+        }
+        let remote = harts & smp::online_mask() & !this;
+        if remote == 0 {
+            return;
+        }
+        if HAVE_RFENCE.load(Ordering::Relaxed) {
+            let size = if all { usize::MAX } else { end - start };
+            match rfence::remote_sfence_vma(remote, 0, start, size) {
+                Ok(()) => return,
+                Err(err) => log_warn!(target: "tlb", "remote fence failed: {}", err),
+            }
+        }
+        smp::call_on(remote, flush_local_all);
+    }
+}
+
+/// Records in `harts` that this hart is switching to the page table it's
+/// the mask of, before it does, so that batches for the page table include
+/// it.
+pub fn enter(harts: &AtomicUsize) {
+    harts.fetch_or(1 << percpu::hart_id(), Ordering::Relaxed);
+    fence(Ordering::SeqCst);
+}
+
+/// Records in `harts` that this hart has switched away from the page table,
+/// once it has flushed everything it had of it, so that batches for the
+/// page table leave it out.
+pub fn leave(harts: &AtomicUsize) {
+    harts.fetch_and(!(1 << percpu::hart_id()), Ordering::Release);
+}
+
+impl Drop for TlbBatch<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+ktest! {
+    fn batches_flushes() {
+        let harts = AtomicUsize::new(0);
+        let this = 1 << percpu::hart_id();
+        enter(&harts);
+        assert_eq!(harts.load(Ordering::Relaxed), this);
+
+        let mut batch = TlbBatch::new(&harts);
+        batch.flush();
+        batch.add(0x5000, PAGE_SIZE);
+        batch.add(0x2000, PAGE_SIZE);
+        assert_eq!((batch.start, batch.end), (0x2000, 0x6000));
+        batch.flush();
+        assert!(batch.start >= batch.end);
+
+        batch.add(usize::MAX - PAGE_SIZE + 1, PAGE_SIZE);
+        assert_eq!(batch.end, usize::MAX);
+        drop(batch);
+
+        leave(&harts);
+        assert_eq!(harts.load(Ordering::Relaxed), 0);
+    }
+}
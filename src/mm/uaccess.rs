@@ -0,0 +1,154 @@
+//! Accessing the running task's user memory from the kernel.
+//!
+//! Addresses are checked against the task's address space before each
+//! copy, and the copy itself tolerates faults, in case another thread
+//! unmaps the memory in between.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::mem::MaybeUninit;
+
+use crate::mm::paging::PAGE_SIZE;
+use crate::trap::TrapFrame;
+use crate::{ktest, task};
+
+global_asm!(include_str!("uaccess.s"));
+
+extern "C" {
+    /// Returns the number of bytes not copied.
+    fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn copy_user_end();
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UserCopyError {
+    /// Some of the memory isn't mapped, or not with the required access.
+    Fault,
+    /// A string didn't end within the maximum length.
+    TooLong,
+}
+
+/// Types that are valid for any bit pattern and have no padding, so can be
+/// copied to and from user memory as bytes.
+///
+/// # Safety
+///
+/// Implementors must not have padding or invalid bit patterns.
+pub unsafe trait Plain: Copy {}
+
+macro_rules! impl_plain {
+    ($($ty:ty),*) => {
+        $(unsafe impl Plain for $ty {})*
+    };
+}
+
+// SAFETY: integers, and arrays of them, have no padding or invalid values.
+impl_plain!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+
+/// Checks that the running task may access `len` bytes at `addr`.
+fn check(addr: usize, len: usize, write: bool) -> Result<(), UserCopyError> {
+    let space = task::address_space().ok_or(UserCopyError::Fault)?;
+    if space.is_accessible(addr, len, write) {
+        Ok(())
+    } else {
+        Err(UserCopyError::Fault)
+    }
+}
+
+/// Fills `dst` from user memory at `src`.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), UserCopyError> {
+    check(src, dst.len(), false)?;
+    // SAFETY: `dst` is kernel memory, and faults on `src` are caught.
+    let left = unsafe { copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) };
+    if left == 0 {
+        Ok(())
+    } else {
+        Err(UserCopyError::Fault)
+    }
+}
+
+/// Copies `src` to user memory at `dst`.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), UserCopyError> {
+    check(dst, src.len(), true)?;
+    // SAFETY: `src` is kernel memory, and faults on `dst` are caught.
+    let left = unsafe { copy_user(dst as *mut u8, src.as_ptr(), src.len()) };
+    if left == 0 {
+        Ok(())
+    } else {
+        Err(UserCopyError::Fault)
+    }
+}
+
+/// Reads a `T` from user memory at `addr`, which needn't be aligned.
+pub fn read_user_struct<T: Plain>(addr: usize) -> Result<T, UserCopyError> {
+    let mut value = MaybeUninit::<T>::uninit();
+    // SAFETY: the bytes are all written before `value` is read, and any bit
+    // pattern is a valid `T`.
+    unsafe {
+        let bytes = core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>());
+        copy_from_user(bytes, addr)?;
+        Ok(value.assume_init())
+    }
+}
+
+/// Writes `value` to user memory at `addr`, which needn't be aligned.
+pub fn write_user_struct<T: Plain>(addr: usize, value: &T) -> Result<(), UserCopyError> {
+    // SAFETY: `T` has no padding, so all of its bytes are initialized.
+    let bytes =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(addr, bytes)
+}
+
+/// Reads a NUL-terminated string of at most `max_len` bytes, excluding the
+/// NUL, from user memory at `addr`. Invalid UTF-8 is replaced.
+pub fn read_user_cstr(addr: usize, max_len: usize) -> Result<String, UserCopyError> {
+    let mut bytes = Vec::new();
+    let mut chunk = [0; 64];
+    while bytes.len() <= max_len {
+        let next = addr.checked_add(bytes.len()).ok_or(UserCopyError::Fault)?;
+        // Don't read past the end of the page, which may be the end of the
+        // string's mapping.
+        let len = chunk.len().min(PAGE_SIZE - next % PAGE_SIZE);
+        copy_from_user(&mut chunk[..len], next)?;
+        if let Some(nul) = chunk[..len].iter().position(|&byte| byte == 0) {
+            bytes.extend_from_slice(&chunk[..nul]);
+            if bytes.len() > max_len {
+                break;
+            }
+            return Ok(String::from_utf8_lossy(&bytes).into_owned());
+        }
+        bytes.extend_from_slice(&chunk[..len]);
+    }
+    Err(UserCopyError::TooLong)
+}
+
+/// Returns true if `pc` is in the code that copies user memory, where
+/// faults are expected.
+pub fn is_user_copy(pc: usize) -> bool {
+    let start = copy_user as *const () as usize;
+    let end = copy_user_end as *const () as usize;
+    (start..end).contains(&pc)
+}
+
+/// Makes a copy that faulted at `frame.sepc` return early, with the number
+/// of bytes it didn't copy.
+pub fn abort_copy(frame: &mut TrapFrame) {
+    frame.sepc = copy_user_end as *const () as usize;
+}
+
+ktest! {
+    fn rejects_kernel_memory() {
+        // Kernel tasks have no user memory at all, and the kernel's own
+        // memory isn't user memory.
+        let kernel = &0u64 as *const u64 as usize;
+        let mut buf = [0; 8];
+        assert_eq!(copy_from_user(&mut buf, kernel), Err(UserCopyError::Fault));
+        assert_eq!(copy_to_user(kernel, &buf), Err(UserCopyError::Fault));
+        assert_eq!(read_user_struct::<u64>(kernel), Err(UserCopyError::Fault));
+        assert_eq!(read_user_cstr(kernel, 16), Err(UserCopyError::Fault));
+        assert_eq!(write_user_struct(0, &1u32), Err(UserCopyError::Fault));
+    }
+}
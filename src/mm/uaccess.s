@@ -0,0 +1,28 @@
+.section .text
+.global copy_user
+.global copy_user_end
+
+.equ SSTATUS_SUM, 1 << 18
+
+# Copies a2 bytes from a1 to a0, one of which is a user address, and returns
+# the number of bytes left uncopied in a0.
+#
+# A page or access fault anywhere before copy_user_end resumes at
+# copy_user_end, see trap.rs, so a fault just ends the copy early. a2 always
+# counts the bytes still to copy.
+copy_user:
+    li t1, SSTATUS_SUM
+    csrs sstatus, t1
+1:
+    beqz a2, copy_user_end
+    lb t0, 0(a1)
+    sb t0, 0(a0)
+    addi a0, a0, 1
+    addi a1, a1, 1
+    addi a2, a2, -1
+    j 1b
+copy_user_end:
+    li t1, SSTATUS_SUM
+    csrc sstatus, t1
+    mv a0, a2
+    ret
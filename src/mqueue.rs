@@ -0,0 +1,328 @@
+//! POSIX message queues: named queues of whole messages, each with a
+//! priority, for processes to pass requests and replies through rather
+//! than framing them on a pipe. A queue holds at most `max_msgs` messages
+//! of up to `msg_size` bytes. Receiving takes the oldest of the highest
+//! priority messages. Sending to a full queue, or receiving from an empty
+//! one, blocks until there's room or a message, or until a deadline, unless
+//! the descriptor is non-blocking.
+//!
+//! A queue lives until it's unlinked and the last descriptor to it closed.
+//! Names are as the `mq_*` syscalls get them, without the leading `/` that
+//! the C library strips.
+
+use alloc::collections::{BTreeMap, BinaryHeap};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+
+use crate::file::File;
+use crate::fs::NAME_MAX;
+use crate::ktest;
+use crate::sync::{SpinLock, WaitQueue};
+use crate::syscall::Errno;
+use crate::time::{Instant, Timer};
+
+/// Priorities are below this.
+pub const MQ_PRIO_MAX: u32 = 32768;
+
+/// The attributes of a queue created without any.
+const DEFAULT_ATTR: Attr = Attr {
+    max_msgs: 10,
+    msg_size: 8192,
+};
+
+/// The largest attributes a queue can be created with.
+const MAX_MSGS: usize = 256;
+const MAX_MSG_SIZE: usize = 65536;
+
+static QUEUES: SpinLock<BTreeMap<String, Arc<MessageQueue>>> = SpinLock::new(BTreeMap::new());
+
+/// How big a queue is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Attr {
+    pub max_msgs: usize,
+    pub msg_size: usize,
+}
+
+struct Message {
+    priority: u32,
+    /// When the message was sent, relative to the others, so that messages
+    /// of the same priority come out in order.
+    seq: u64,
+    data: Vec<u8>,
+}
+
+impl Ord for Message {
+    /// Higher priorities first, then older messages.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Message {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Message {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Message {}
+
+struct State {
+    messages: BinaryHeap<Message>,
+    next_seq: u64,
+}
+
+pub struct MessageQueue {
+    attr: Attr,
+    state: SpinLock<State>,
+    /// Woken when a message is sent.
+    readable: WaitQueue,
+    /// Woken when a message is received.
+    writable: WaitQueue,
+}
+
+impl MessageQueue {
+    fn new(attr: Attr) -> Self {
+        Self {
+            attr,
+            state: SpinLock::new(State {
+                messages: BinaryHeap::new(),
+                next_seq: 0,
+            }),
+            readable: WaitQueue::new(),
+            writable: WaitQueue::new(),
+        }
+    }
+
+    pub fn attr(&self) -> Attr {
+        self.attr
+    }
+
+    /// How many messages are waiting.
+    pub fn len(&self) -> usize {
+        self.state.lock().messages.len()
+    }
+
+    /// Queues `data` with `priority`, waiting for room if the queue is full
+    /// until `deadline`, if any, or failing right away if `!block`.
+    pub fn send(
+        self: &Arc<Self>,
+        data: &[u8],
+        priority: u32,
+        block: bool,
+        deadline: Option<Instant>,
+    ) -> Result<(), Errno> {
+        if data.len() > self.attr.msg_size {
+            return Err(Errno::EMSGSIZE);
+        }
+        if priority >= MQ_PRIO_MAX {
+            return Err(Errno::EINVAL);
+        }
+        let mut message = Some(data.to_vec());
+        wait(&self.writable, block, deadline, self.clone(), || {
+            let mut state = self.state.lock();
+            if state.messages.len() >= self.attr.max_msgs {
+                return false;
+            }
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.messages.push(Message {
+                priority,
+                seq,
+                data: message.take().unwrap(),
+            });
+            true
+        })?;
+        self.readable.wake_one();
+        Ok(())
+    }
+
+    /// Takes the next message into `buf`, which must have room for the
+    /// largest the queue takes, returning its length and priority. Waits as
+    /// `send` does if there are none.
+    pub fn receive(
+        self: &Arc<Self>,
+        buf: &mut [u8],
+        block: bool,
+        deadline: Option<Instant>,
+    ) -> Result<(usize, u32), Errno> {
+        if buf.len() < self.attr.msg_size {
+            return Err(Errno::EMSGSIZE);
+        }
+        let mut received = None;
+        wait(&self.readable, block, deadline, self.clone(), || {
+            received = self.state.lock().messages.pop();
+            received.is_some()
+        })?;
+        self.writable.wake_one();
+        let message = received.unwrap();
+        buf[..message.data.len()].copy_from_slice(&message.data);
+        Ok((message.data.len(), message.priority))
+    }
+}
+
+/// Waits on `wait_queue`, one of `queue`'s, until `cond` returns true,
+/// failing with `EAGAIN` if it doesn't at first and `!block`, or with
+/// `ETIMEDOUT` if it hasn't by `deadline`.
+fn wait(
+    wait_queue: &WaitQueue,
+    block: bool,
+    deadline: Option<Instant>,
+    queue: Arc<MessageQueue>,
+    mut cond: impl FnMut() -> bool,
+) -> Result<(), Errno> {
+    if cond() {
+        return Ok(());
+    }
+    if !block {
+        return Err(Errno::EAGAIN);
+    }
+    let timer = deadline.map(|deadline| {
+        Timer::start(deadline, move || {
+            queue.readable.wake_all();
+            queue.writable.wake_all();
+        })
+    });
+    let mut done = false;
+    wait_queue.wait_until(|| {
+        done = cond();
+        done || timer.as_ref().is_some_and(Timer::has_fired)
+    });
+    if done {
+        Ok(())
+    } else {
+        Err(Errno::ETIMEDOUT)
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Errno> {
+    if name.is_empty() || name.contains('/') {
+        return Err(Errno::EINVAL);
+    }
+    if name.len() > NAME_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    Ok(())
+}
+
+/// Opens the queue called `name`. If `create`, it's created with `attr`,
+/// or the default attributes, if there isn't one yet, and if `exclusive`
+/// too, there mustn't be one.
+pub fn open(
+    name: &str,
+    create: bool,
+    exclusive: bool,
+    attr: Option<Attr>,
+) -> Result<Arc<MessageQueue>, Errno> {
+    check_name(name)?;
+    let mut queues = QUEUES.lock();
+    if let Some(queue) = queues.get(name) {
+        if create && exclusive {
+            return Err(Errno::EEXIST);
+        }
+        return Ok(queue.clone());
+    }
+    if !create {
+        return Err(Errno::ENOENT);
+    }
+    let attr = attr.unwrap_or(DEFAULT_ATTR);
+    if !(1..=MAX_MSGS).contains(&attr.max_msgs) || !(1..=MAX_MSG_SIZE).contains(&attr.msg_size) {
+        return Err(Errno::EINVAL);
+    }
+    let queue = Arc::new(MessageQueue::new(attr));
+    queues.insert(name.to_string(), queue.clone());
+    Ok(queue)
+}
+
+/// Removes the name `name`. The queue goes once it's no longer open.
+pub fn unlink(name: &str) -> Result<(), Errno> {
+    check_name(name)?;
+    QUEUES.lock().remove(name).map(drop).ok_or(Errno::ENOENT)
+}
+
+/// An open message queue, as a file descriptor refers to it. It can't be
+/// read or written as a file, only through the `mq_*` syscalls.
+pub struct Descriptor {
+    pub queue: Arc<MessageQueue>,
+    pub readable: bool,
+    pub writable: bool,
+    nonblocking: AtomicBool,
+}
+
+impl Descriptor {
+    pub fn new(
+        queue: Arc<MessageQueue>,
+        readable: bool,
+        writable: bool,
+        nonblocking: bool,
+    ) -> Self {
+        Self {
+            queue,
+            readable,
+            writable,
+            nonblocking: AtomicBool::new(nonblocking),
+        }
+    }
+
+    pub fn is_nonblocking(&self) -> bool {
+        self.nonblocking.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, AtomicOrdering::Relaxed);
+    }
+}
+
+impl File for Descriptor {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    fn message_queue(&self) -> Option<&Descriptor> {
+        Some(self)
+    }
+}
+
+ktest! {
+    fn message_queues_order_by_priority() {
+        let attr = Attr {
+            max_msgs: 2,
+            msg_size: 8,
+        };
+        let queue = open("ktest-mq", true, true, Some(attr)).unwrap();
+        assert_eq!(open("ktest-mq", true, true, None).err(), Some(Errno::EEXIST));
+        queue.send(b"low", 1, false, None).unwrap();
+        queue.send(b"high", 5, false, None).unwrap();
+        assert_eq!(queue.send(b"full", 9, false, None), Err(Errno::EAGAIN));
+        assert_eq!(
+            queue.send(b"much too long", 0, false, None),
+            Err(Errno::EMSGSIZE)
+        );
+
+        let mut buf = [0; 8];
+        assert_eq!(queue.receive(&mut buf, false, None), Ok((4, 5)));
+        assert_eq!(&buf[..4], b"high");
+        assert_eq!(queue.receive(&mut buf, false, None), Ok((3, 1)));
+        let soon = Instant::now() + crate::time::Duration::from_millis(20);
+        assert_eq!(
+            queue.receive(&mut buf, true, Some(soon)),
+            Err(Errno::ETIMEDOUT)
+        );
+        unlink("ktest-mq").unwrap();
+        assert_eq!(open("ktest-mq", false, false, None).err(), Some(Errno::ENOENT));
+    }
+}
@@ -0,0 +1,406 @@
+//! The network stack: Ethernet with ARP, IPv4, ICMP echo, UDP and TCP, over
+//! the network devices drivers register. A kernel task takes in received
+//! frames and runs the protocols' timers, when a device interrupts or a
+//! timer wakes it; sockets send from the calling task.
+//!
+//! Blocking socket calls poll for what they're waiting for every
+//! `POLL_INTERVAL` or so, which is also how often devices without an
+//! interrupt are polled, with the slack to wake up on the same ticks.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use crate::cmdline;
+use crate::sync::{SpinLock, WaitQueue};
+use crate::task::{self, Priority};
+use crate::time::{Duration, Instant, Timer};
+use crate::{ktest, log_info, log_warn, percpu};
+
+pub mod arp;
+pub mod dhcp;
+mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod tcp;
+pub mod udp;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Set to have the network task run now.
+static WOKEN: AtomicBool = AtomicBool::new(false);
+static WAKE: WaitQueue = WaitQueue::new();
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: Self = Self([0xff; 6]);
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Self = Self([0; 4]);
+    pub const BROADCAST: Self = Self([0xff; 4]);
+
+    pub fn to_bits(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits.to_be_bytes())
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = ();
+
+    /// Parses dotted decimal, e.g. `10.0.2.15`.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut octets = [0; 4];
+        let mut parts = s.split('.');
+        for octet in &mut octets {
+            let part = parts.next().ok_or(())?;
+            // `parse` would take a sign too.
+            if !part.bytes().all(|c| c.is_ascii_digit()) {
+                return Err(());
+            }
+            *octet = part.parse().map_err(|_| ())?;
+        }
+        if parts.next().is_some() {
+            return Err(());
+        }
+        Ok(Self(octets))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NetError {
+    /// The device has no room for another frame.
+    Busy,
+    /// There's no interface with an address to send from, or no route to
+    /// the destination.
+    Unreachable,
+    /// The packet doesn't fit in a frame.
+    TooLarge,
+    AddrInUse,
+    ConnectionRefused,
+    ConnectionReset,
+    NotConnected,
+    TimedOut,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Busy => f.write_str("device busy"),
+            Self::Unreachable => f.write_str("network unreachable"),
+            Self::TooLarge => f.write_str("packet too large"),
+            Self::AddrInUse => f.write_str("address in use"),
+            Self::ConnectionRefused => f.write_str("connection refused"),
+            Self::ConnectionReset => f.write_str("connection reset"),
+            Self::NotConnected => f.write_str("not connected"),
+            Self::TimedOut => f.write_str("timed out"),
+        }
+    }
+}
+
+/// A device sending and receiving Ethernet frames, without the frame check
+/// sequence. Neither call blocks.
+pub trait NetDevice: Send + Sync {
+    fn mac(&self) -> MacAddr;
+
+    /// The largest payload of a frame.
+    fn mtu(&self) -> usize {
+        1500
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Takes the next frame the device has received, if there is one.
+    fn receive(&self) -> Option<Vec<u8>>;
+
+    /// Whether the network task has to poll the device, which otherwise
+    /// calls `wake` when a frame arrives.
+    fn is_polled(&self) -> bool;
+}
+
+/// The address of an interface, and the network it's on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Ipv4Config {
+    pub addr: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Ipv4Config {
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from_bits(
+            u32::MAX
+                .checked_shl(32 - u32::from(self.prefix_len))
+                .unwrap_or(0),
+        )
+    }
+
+    /// Whether `addr` is on the interface's network.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = self.netmask().to_bits();
+        addr.to_bits() & mask == self.addr.to_bits() & mask
+    }
+
+    /// The broadcast address of the network.
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_bits(self.addr.to_bits() | !self.netmask().to_bits())
+    }
+}
+
+impl FromStr for Ipv4Config {
+    type Err = ();
+
+    /// Parses an address and prefix length with an optional gateway, e.g.
+    /// `10.0.2.15/24,10.0.2.2`. The gateway must be on the network.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (network, gateway) = match s.split_once(',') {
+            Some((network, gateway)) => (network, Some(gateway.parse()?)),
+            None => (s, None),
+        };
+        let (addr, prefix_len) = network.split_once('/').ok_or(())?;
+        let prefix_len = prefix_len.parse().map_err(|_| ())?;
+        if prefix_len > 32 {
+            return Err(());
+        }
+        let config = Self {
+            addr: addr.parse()?,
+            prefix_len,
+            gateway,
+        };
+        if gateway.is_some_and(|gateway| !config.contains(gateway)) {
+            return Err(());
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for Ipv4Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        Ok(())
+    }
+}
+
+/// A network device, as the stack sees it.
+pub struct Interface {
+    name: String,
+    device: Arc<dyn NetDevice>,
+    config: SpinLock<Option<Ipv4Config>>,
+}
+
+impl Interface {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.device.mac()
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.device.mtu()
+    }
+
+    pub fn config(&self) -> Option<Ipv4Config> {
+        *self.config.lock()
+    }
+
+    pub fn set_config(&self, config: Option<Ipv4Config>) {
+        *self.config.lock() = config;
+    }
+}
+
+static INTERFACES: SpinLock<Vec<Arc<Interface>>> = SpinLock::new(Vec::new());
+
+/// Adds `device` as the next interface, `eth0`, `eth1` and so on.
+pub fn register(device: Arc<dyn NetDevice>) {
+    let mut interfaces = INTERFACES.lock();
+    let name = format!("eth{}", interfaces.len());
+    log_info!(target: "net", "{}: {}", name, device.mac());
+    interfaces.push(Arc::new(Interface {
+        name,
+        device,
+        config: SpinLock::new(None),
+    }));
+}
+
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+/// Starts the network task and configures the first interface, once the
+/// drivers have registered their devices.
+///
+/// The interface's address is given by `ip=` on the kernel command line:
+/// `ip=addr/prefix[,gateway]` for a static address, `ip=off` for none, or
+/// `ip=dhcp`, the default, to get one by DHCP in the background.
+pub fn init() {
+    let interfaces = interfaces();
+    let Some(first) = interfaces.first().cloned() else {
+        return;
+    };
+    if task::spawn_on(percpu::hart_id(), Priority::Normal, run).is_none() {
+        log_warn!(target: "net", "no memory for the network task");
+        return;
+    }
+    match cmdline::get("ip") {
+        Some("off") => {}
+        None | Some("dhcp") => start_dhcp(first),
+        Some(value) => match value.parse::<Ipv4Config>() {
+            Ok(config) => {
+                first.set_config(Some(config));
+                log_info!(target: "net", "{}: {}", first.name(), config);
+            }
+            Err(()) => {
+                log_warn!(target: "net", "invalid ip {:?}, using DHCP", value);
+                start_dhcp(first);
+            }
+        },
+    }
+}
+
+fn start_dhcp(interface: Arc<Interface>) {
+    if task::spawn_on(percpu::hart_id(), Priority::Normal, || dhcp::run(interface)).is_none() {
+        log_warn!(target: "net", "no memory for the DHCP client");
+    }
+}
+
+/// Has the network task take in received frames and run the protocols'
+/// timers. Can be called from an interrupt handler.
+pub fn wake() {
+    if !WOKEN.swap(true, Ordering::Relaxed) {
+        WAKE.wake_all();
+    }
+}
+
+fn run() {
+    loop {
+        let interfaces = interfaces();
+        for interface in &interfaces {
+            while let Some(frame) = interface.device.receive() {
+                ethernet::receive(interface, &frame);
+            }
+        }
+        tcp::poll();
+
+        let polled = interfaces
+            .iter()
+            .any(|interface| interface.device.is_polled());
+        let timer = polled.then(|| {
+            let deadline = Instant::now() + POLL_INTERVAL;
+            Timer::start_with_slack(deadline, POLL_INTERVAL, || WAKE.wake_all())
+        });
+        WAKE.wait_until(|| {
+            WOKEN.load(Ordering::Relaxed) || timer.as_ref().is_some_and(Timer::has_fired)
+        });
+        WOKEN.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Calls `f` every `POLL_INTERVAL` or so until it returns something, or the
+/// `timeout` passes.
+fn poll_until<T>(timeout: Option<Duration>, mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if let Some(value) = f() {
+            return Some(value);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return None;
+        }
+        task::sleep_with_slack(POLL_INTERVAL, POLL_INTERVAL);
+    }
+}
+
+/// The first port of the dynamic range, which local ports are picked from
+/// when none is asked for.
+const EPHEMERAL_PORTS: u16 = 49152;
+
+static NEXT_EPHEMERAL: AtomicU16 = AtomicU16::new(0);
+
+/// Picks a port from the dynamic range that isn't `in_use`, going round the
+/// range so that ports aren't soon reused.
+fn ephemeral_port(in_use: impl Fn(u16) -> bool) -> Option<u16> {
+    let count = u16::MAX - EPHEMERAL_PORTS + 1;
+    (0..count)
+        .map(|_| EPHEMERAL_PORTS + NEXT_EPHEMERAL.fetch_add(1, Ordering::Relaxed) % count)
+        .find(|&port| !in_use(port))
+}
+
+/// The Internet checksum of `data`, continuing from the partial `sum`.
+fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+ktest! {
+    /// The example from RFC 1071, and an odd length.
+    fn checksum_rfc1071() {
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&data, 0), !0xddf2);
+        assert_eq!(checksum(&data[..7], 0), !0xdcfb);
+    }
+}
+
+ktest! {
+    fn parses_ip_config() {
+        let config: Ipv4Config = "10.0.2.15/24,10.0.2.2".parse().unwrap();
+        assert_eq!(config.addr, Ipv4Addr([10, 0, 2, 15]));
+        assert_eq!(config.netmask(), Ipv4Addr([255, 255, 255, 0]));
+        assert_eq!(config.broadcast(), Ipv4Addr([10, 0, 2, 255]));
+        assert_eq!(format!("{}", config), "10.0.2.15/24 via 10.0.2.2");
+        let config: Ipv4Config = "192.168.1.1/0".parse().unwrap();
+        assert_eq!(config.netmask(), Ipv4Addr::UNSPECIFIED);
+        assert!(config.contains(Ipv4Addr([8, 8, 8, 8])));
+
+        for bad in ["10.0.2.15", "10.0.2.15/33", "10.0.2.15/24,10.0.3.2", "10.0.2/24"] {
+            assert_eq!(bad.parse::<Ipv4Config>(), Err(()));
+        }
+        assert_eq!("1.+2.3.4".parse::<Ipv4Addr>(), Err(()));
+        assert_eq!("1.2.3.256".parse::<Ipv4Addr>(), Err(()));
+        assert_eq!("1.2.3.4.5".parse::<Ipv4Addr>(), Err(()));
+    }
+}
@@ -0,0 +1,139 @@
+//! ARP, for finding the Ethernet addresses of IPv4 hosts on the same
+//! network. Packets for hosts that haven't been resolved yet wait for the
+//! reply.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::ethernet::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use super::{Interface, Ipv4Addr, MacAddr, NetError};
+use crate::sync::SpinLock;
+
+const HTYPE_ETHERNET: u16 = 1;
+
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+
+/// The size of a packet for Ethernet and IPv4 addresses.
+const PACKET_SIZE: usize = 28;
+
+/// How many packets may wait for addresses to be resolved. The oldest is
+/// dropped to make room.
+const MAX_PENDING: usize = 16;
+
+static CACHE: SpinLock<BTreeMap<Ipv4Addr, MacAddr>> = SpinLock::new(BTreeMap::new());
+
+struct Pending {
+    interface: Arc<Interface>,
+    next_hop: Ipv4Addr,
+    packet: Vec<u8>,
+}
+
+static PENDING: SpinLock<Vec<Pending>> = SpinLock::new(Vec::new());
+
+pub fn receive(interface: &Arc<Interface>, packet: &[u8]) {
+    if packet.len() < PACKET_SIZE
+        || u16::from_be_bytes([packet[0], packet[1]]) != HTYPE_ETHERNET
+        || u16::from_be_bytes([packet[2], packet[3]]) != ETHERTYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let op = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender_mac = MacAddr(packet[8..14].try_into().unwrap());
+    let sender_ip = Ipv4Addr(packet[14..18].try_into().unwrap());
+    let target_ip = Ipv4Addr(packet[24..28].try_into().unwrap());
+
+    let ours = interface.config().map(|config| config.addr);
+    if sender_ip != Ipv4Addr::UNSPECIFIED {
+        // Learn from anything sent to us, and keep what we know up to date.
+        let mut cache = CACHE.lock();
+        if Some(target_ip) == ours || cache.contains_key(&sender_ip) {
+            cache.insert(sender_ip, sender_mac);
+        }
+    }
+    if op == OP_REQUEST && Some(target_ip) == ours {
+        let _ = send(interface, OP_REPLY, sender_mac, sender_ip);
+    }
+
+    if !CACHE.lock().contains_key(&sender_ip) {
+        return;
+    }
+    let resolved: Vec<_> = {
+        let mut pending = PENDING.lock();
+        let (resolved, waiting) = core::mem::take(&mut *pending)
+            .into_iter()
+            .partition(|p| p.next_hop == sender_ip && Arc::ptr_eq(&p.interface, interface));
+        *pending = waiting;
+        resolved
+    };
+    for p in resolved {
+        let _ = ethernet::send(interface, sender_mac, ETHERTYPE_IPV4, &p.packet);
+    }
+}
+
+/// Sends an ARP packet of type `op` about our address.
+fn send(
+    interface: &Interface,
+    op: u16,
+    target_mac: MacAddr,
+    target_ip: Ipv4Addr,
+) -> Result<(), NetError> {
+    let ours = interface
+        .config()
+        .map_or(Ipv4Addr::UNSPECIFIED, |config| config.addr);
+    let mut packet = Vec::with_capacity(PACKET_SIZE);
+    packet.extend_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+    packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet.extend_from_slice(&[6, 4]);
+    packet.extend_from_slice(&op.to_be_bytes());
+    packet.extend_from_slice(&interface.mac().0);
+    packet.extend_from_slice(&ours.0);
+    packet.extend_from_slice(&target_mac.0);
+    packet.extend_from_slice(&target_ip.0);
+    let dst = match op {
+        OP_REQUEST => MacAddr::BROADCAST,
+        _ => target_mac,
+    };
+    ethernet::send(interface, dst, ETHERTYPE_ARP, &packet)
+}
+
+/// Sends the IPv4 `packet` to `next_hop`, once its Ethernet address is
+/// known.
+pub fn send_ipv4(
+    interface: &Arc<Interface>,
+    next_hop: Ipv4Addr,
+    packet: Vec<u8>,
+) -> Result<(), NetError> {
+    let broadcast = next_hop == Ipv4Addr::BROADCAST
+        || interface
+            .config()
+            .is_some_and(|config| config.broadcast() == next_hop);
+    if broadcast {
+        return ethernet::send(interface, MacAddr::BROADCAST, ETHERTYPE_IPV4, &packet);
+    }
+    let known = CACHE.lock().get(&next_hop).copied();
+    if let Some(mac) = known {
+        return ethernet::send(interface, mac, ETHERTYPE_IPV4, &packet);
+    }
+
+    {
+        let mut pending = PENDING.lock();
+        if pending.len() >= MAX_PENDING {
+            pending.remove(0);
+        }
+        pending.push(Pending {
+            interface: interface.clone(),
+            next_hop,
+            packet,
+        });
+    }
+    send(interface, OP_REQUEST, MacAddr([0; 6]), next_hop)
+}
+
+/// Every address that's been resolved.
+pub fn entries() -> Vec<(Ipv4Addr, MacAddr)> {
+    CACHE.lock().iter().map(|(&ip, &mac)| (ip, mac)).collect()
+}
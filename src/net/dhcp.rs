@@ -0,0 +1,401 @@
+//! A DHCP client, which gets an interface its address and keeps renewing
+//! the lease. Replies are asked to be broadcast, since we can't take
+//! unicast packets before having an address.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::udp::UdpSocket;
+use super::{Interface, Ipv4Addr, Ipv4Config, MacAddr};
+use crate::time::{Duration, Instant};
+use crate::{ktest, log_info, log_warn};
+use crate::{rand, task};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+
+/// Asks the server to broadcast its replies.
+const FLAG_BROADCAST: u16 = 0x8000;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// The size of a message before its options, and the smallest message
+/// BOOTP relays take.
+const FIXED_SIZE: usize = 236;
+const MIN_SIZE: usize = 300;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+/// How long to wait for a reply at first. Each retry waits twice as long,
+/// up to `MAX_TIMEOUT`.
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_TIMEOUT: Duration = Duration::from_secs(32);
+
+/// How many requests are sent to renew a lease before giving up on it.
+const RENEW_ATTEMPTS: u32 = 3;
+
+/// What a server gave us.
+#[derive(Clone, Copy, Debug)]
+pub struct Lease {
+    pub config: Ipv4Config,
+    pub server: Ipv4Addr,
+    /// How long the address is ours, or `None` if it's for good.
+    pub time: Option<Duration>,
+}
+
+/// What matters of a server's reply.
+struct Reply {
+    kind: u8,
+    yiaddr: Ipv4Addr,
+    server: Option<Ipv4Addr>,
+    netmask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    lease_time: Option<u32>,
+}
+
+fn message(mac: MacAddr, xid: u32, kind: u8, ciaddr: Ipv4Addr, options: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(MIN_SIZE);
+    message.extend_from_slice(&[OP_REQUEST, HTYPE_ETHERNET, 6, 0]);
+    message.extend_from_slice(&xid.to_be_bytes());
+    // secs, which nobody looks at.
+    message.extend_from_slice(&[0, 0]);
+    message.extend_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    message.extend_from_slice(&ciaddr.0);
+    // yiaddr, siaddr and giaddr.
+    message.resize(message.len() + 12, 0);
+    message.extend_from_slice(&mac.0);
+    // The rest of chaddr, then sname and file.
+    message.resize(FIXED_SIZE, 0);
+    message.extend_from_slice(&MAGIC_COOKIE);
+    message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind]);
+    for &(code, data) in options {
+        message.extend_from_slice(&[code, data.len() as u8]);
+        message.extend_from_slice(data);
+    }
+    message.extend_from_slice(&[OPTION_PARAMETERS, 2, OPTION_SUBNET_MASK, OPTION_ROUTER]);
+    message.push(OPTION_END);
+    message.resize(message.len().max(MIN_SIZE), OPTION_PAD);
+    message
+}
+
+/// Parses a reply to the request `xid` from us.
+fn parse(message: &[u8], mac: MacAddr, xid: u32) -> Option<Reply> {
+    if message.len() < FIXED_SIZE + MAGIC_COOKIE.len()
+        || message[0] != OP_REPLY
+        || message[4..8] != xid.to_be_bytes()
+        || message[28..34] != mac.0
+        || message[FIXED_SIZE..FIXED_SIZE + 4] != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let addr = |data: &[u8]| Some(Ipv4Addr(data.get(..4)?.try_into().ok()?));
+    let mut reply = Reply {
+        kind: 0,
+        yiaddr: addr(&message[16..20])?,
+        server: None,
+        netmask: None,
+        router: None,
+        lease_time: None,
+    };
+    let mut options = &message[FIXED_SIZE + 4..];
+    loop {
+        let (code, data) = match *options {
+            [] | [OPTION_END, ..] => break,
+            [OPTION_PAD, ref rest @ ..] => {
+                options = rest;
+                continue;
+            }
+            [code, len, ref rest @ ..] => {
+                let data = rest.get(..usize::from(len))?;
+                options = &rest[data.len()..];
+                (code, data)
+            }
+            _ => return None,
+        };
+        match code {
+            OPTION_MESSAGE_TYPE => reply.kind = *data.first()?,
+            OPTION_SERVER_ID => reply.server = addr(data),
+            OPTION_SUBNET_MASK => reply.netmask = addr(data),
+            OPTION_ROUTER => reply.router = addr(data),
+            OPTION_LEASE_TIME => {
+                reply.lease_time = Some(u32::from_be_bytes(data.get(..4)?.try_into().ok()?))
+            }
+            _ => {}
+        }
+    }
+    (reply.kind != 0).then_some(reply)
+}
+
+impl Reply {
+    fn lease(&self, server: Ipv4Addr) -> Lease {
+        // Without a mask, assume the network's just us.
+        let prefix_len = self
+            .netmask
+            .map_or(32, |mask| mask.to_bits().leading_ones() as u8);
+        Lease {
+            config: Ipv4Config {
+                addr: self.yiaddr,
+                prefix_len,
+                gateway: self.router,
+            },
+            server,
+            time: match self.lease_time {
+                None | Some(u32::MAX) => None,
+                Some(secs) => Some(Duration::from_secs(secs.into())),
+            },
+        }
+    }
+}
+
+struct Client {
+    interface: Arc<Interface>,
+    socket: UdpSocket,
+    xid: u32,
+}
+
+impl Client {
+    /// Broadcasts `message` and waits up to `timeout` for a reply `wanted`
+    /// takes.
+    fn exchange<T>(
+        &self,
+        message: &[u8],
+        timeout: Duration,
+        mut wanted: impl FnMut(Reply) -> Option<T>,
+    ) -> Option<T> {
+        let src = self
+            .interface
+            .config()
+            .map_or(Ipv4Addr::UNSPECIFIED, |config| config.addr);
+        if let Err(err) = self.socket.send_via(
+            &self.interface,
+            src,
+            message,
+            Ipv4Addr::BROADCAST,
+            SERVER_PORT,
+        ) {
+            log_warn!(target: "net", "{}: dhcp: {}", self.interface.name(), err);
+            return None;
+        }
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0; 1024];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            let Ok((len, _, _)) = self.socket.recv_from(&mut buf, Some(deadline - now)) else {
+                continue;
+            };
+            let reply = parse(&buf[..len], self.interface.mac(), self.xid);
+            if let Some(value) = reply.and_then(&mut wanted) {
+                return Some(value);
+            }
+        }
+    }
+
+    /// Asks for an address until a server gives us one.
+    fn acquire(&mut self) -> Lease {
+        let mac = self.interface.mac();
+        let mut timeout = INITIAL_TIMEOUT;
+        loop {
+            self.xid = self.xid.wrapping_add(1);
+            let discover = message(mac, self.xid, DHCPDISCOVER, Ipv4Addr::UNSPECIFIED, &[]);
+            let offer = self.exchange(&discover, timeout, |reply| {
+                Some((reply.yiaddr, reply.server?)).filter(|_| reply.kind == DHCPOFFER)
+            });
+            if let Some((offered, server)) = offer {
+                let request = message(
+                    mac,
+                    self.xid,
+                    DHCPREQUEST,
+                    Ipv4Addr::UNSPECIFIED,
+                    &[
+                        (OPTION_REQUESTED_IP, &offered.0),
+                        (OPTION_SERVER_ID, &server.0),
+                    ],
+                );
+                let ack = self.exchange(&request, timeout, |reply| match reply.kind {
+                    DHCPACK => Some(Some(reply.lease(server))),
+                    DHCPNAK => Some(None),
+                    _ => None,
+                });
+                if let Some(Some(lease)) = ack {
+                    return lease;
+                }
+            }
+            timeout = (timeout * 2).min(MAX_TIMEOUT);
+        }
+    }
+
+    /// Asks the server to extend `lease`, returning the new lease, `None` if
+    /// the server doesn't answer, or `Some(None)` if it refuses.
+    fn renew(&mut self, lease: &Lease) -> Option<Option<Lease>> {
+        let mac = self.interface.mac();
+        for _ in 0..RENEW_ATTEMPTS {
+            self.xid = self.xid.wrapping_add(1);
+            let request = message(mac, self.xid, DHCPREQUEST, lease.config.addr, &[]);
+            let ack = self.exchange(&request, INITIAL_TIMEOUT, |reply| match reply.kind {
+                DHCPACK => Some(Some(reply.lease(reply.server.unwrap_or(lease.server)))),
+                DHCPNAK => Some(None),
+                _ => None,
+            });
+            if ack.is_some() {
+                return ack;
+            }
+        }
+        None
+    }
+}
+
+fn bind(interface: &Interface, lease: &Lease) {
+    if interface.config() == Some(lease.config) {
+        return;
+    }
+    interface.set_config(Some(lease.config));
+    match lease.time {
+        Some(time) => log_info!(
+            target: "net",
+            "{}: {} from {}, leased for {}s",
+            interface.name(),
+            lease.config,
+            lease.server,
+            time.as_secs()
+        ),
+        None => log_info!(
+            target: "net",
+            "{}: {} from {}",
+            interface.name(),
+            lease.config,
+            lease.server
+        ),
+    }
+}
+
+/// Configures `interface` by DHCP, for good: the lease is renewed halfway
+/// through, and if it can't be, the address is given up when it ends and a
+/// new one asked for.
+pub fn run(interface: Arc<Interface>) {
+    let socket = match UdpSocket::bind(CLIENT_PORT) {
+        Ok(socket) => socket,
+        Err(err) => {
+            log_warn!(target: "net", "{}: dhcp: {}", interface.name(), err);
+            return;
+        }
+    };
+    let mut client = Client {
+        interface,
+        socket,
+        xid: rand::u32(),
+    };
+    loop {
+        let mut lease = client.acquire();
+        bind(&client.interface, &lease);
+        while let Some(time) = lease.time {
+            let expires = Instant::now() + time;
+            // Renewing a little late doesn't matter, so it can share a tick.
+            task::sleep_with_slack(time / 2, time / 8);
+            let renewed = client.renew(&lease);
+            match renewed {
+                Some(Some(renewed)) => {
+                    lease = renewed;
+                    bind(&client.interface, &lease);
+                }
+                _ => {
+                    // Keep the address for as long as it's ours, unless the
+                    // server took it back.
+                    let now = Instant::now();
+                    if renewed.is_none() && expires > now {
+                        task::sleep(expires - now);
+                    }
+                    log_warn!(
+                        target: "net",
+                        "{}: lease on {} ended",
+                        client.interface.name(),
+                        lease.config.addr
+                    );
+                    client.interface.set_config(None);
+                    break;
+                }
+            }
+        }
+        if lease.time.is_none() {
+            return;
+        }
+    }
+}
+
+ktest! {
+    fn parses_malformed_replies() {
+        let mac = MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        let xid = 0x1234_5678;
+        let reply = |options: &[u8]| {
+            let mut reply = message(mac, xid, DHCPDISCOVER, Ipv4Addr([0; 4]), &[]);
+            reply[0] = OP_REPLY;
+            reply[16..20].copy_from_slice(&[10, 0, 2, 15]);
+            reply.truncate(FIXED_SIZE + MAGIC_COOKIE.len());
+            reply.extend_from_slice(options);
+            reply
+        };
+        let offer = reply(&[
+            OPTION_PAD,
+            OPTION_MESSAGE_TYPE, 1, DHCPOFFER,
+            OPTION_SERVER_ID, 4, 10, 0, 2, 2,
+            OPTION_SUBNET_MASK, 4, 255, 255, 255, 0,
+            OPTION_ROUTER, 4, 10, 0, 2, 2,
+            OPTION_LEASE_TIME, 4, 0, 0, 0x0e, 0x10,
+            OPTION_END,
+        ]);
+        let parsed = parse(&offer, mac, xid).unwrap();
+        assert_eq!(parsed.kind, DHCPOFFER);
+        let lease = parsed.lease(parsed.server.unwrap());
+        assert_eq!(lease.config.addr, Ipv4Addr([10, 0, 2, 15]));
+        assert_eq!(lease.config.prefix_len, 24);
+        assert_eq!(lease.config.gateway, Some(Ipv4Addr([10, 0, 2, 2])));
+        assert_eq!(lease.time, Some(Duration::from_secs(3600)));
+
+        assert!(parse(&offer[..FIXED_SIZE + 3], mac, xid).is_none());
+        assert!(parse(&offer, mac, xid + 1).is_none());
+        assert!(parse(&offer, MacAddr::BROADCAST, xid).is_none());
+        for at in [0, FIXED_SIZE] {
+            let mut bad = offer.clone();
+            bad[at] ^= 1;
+            assert!(parse(&bad, mac, xid).is_none());
+        }
+
+        // Without a type, with an empty one, and with options cut short.
+        let bad = [
+            &[OPTION_SERVER_ID, 4, 10, 0, 2, 2][..],
+            &[OPTION_MESSAGE_TYPE, 0],
+            &[OPTION_MESSAGE_TYPE, 1, DHCPACK, OPTION_ROUTER],
+            &[OPTION_MESSAGE_TYPE, 1, DHCPACK, OPTION_LEASE_TIME, 8, 0],
+            &[OPTION_MESSAGE_TYPE, 1, DHCPACK, OPTION_LEASE_TIME, 2, 0, 1],
+        ];
+        for options in bad {
+            assert!(parse(&reply(options), mac, xid).is_none());
+        }
+
+        // A short address is left out, and the end is optional.
+        let ack = reply(&[OPTION_MESSAGE_TYPE, 1, DHCPACK, OPTION_ROUTER, 2, 10, 0]);
+        let ack = parse(&ack, mac, xid).unwrap();
+        assert_eq!((ack.kind, ack.router), (DHCPACK, None));
+        assert_eq!(ack.lease(Ipv4Addr([10, 0, 2, 2])).config.prefix_len, 32);
+    }
+}
@@ -0,0 +1,47 @@
+//! Ethernet II framing.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{arp, ipv4, Interface, MacAddr, NetError};
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// The destination and source addresses and the EtherType.
+pub const HEADER_SIZE: usize = 14;
+
+/// Hands a received frame to the protocol it carries.
+pub fn receive(interface: &Arc<Interface>, frame: &[u8]) {
+    if frame.len() < HEADER_SIZE {
+        return;
+    }
+    let dst = MacAddr(frame[0..6].try_into().unwrap());
+    if dst != interface.mac() && dst != MacAddr::BROADCAST {
+        return;
+    }
+    let payload = &frame[HEADER_SIZE..];
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_ARP => arp::receive(interface, payload),
+        ETHERTYPE_IPV4 => ipv4::receive(interface, payload),
+        _ => {}
+    }
+}
+
+/// Sends a frame to `dst` carrying `payload`.
+pub fn send(
+    interface: &Interface,
+    dst: MacAddr,
+    ethertype: u16,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    if payload.len() > interface.mtu() {
+        return Err(NetError::TooLarge);
+    }
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&dst.0);
+    frame.extend_from_slice(&interface.mac().0);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    interface.device.transmit(&frame)
+}
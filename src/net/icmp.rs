@@ -0,0 +1,104 @@
+//! ICMP echo: replying to pings, and sending them. Datagrams for UDP ports
+//! nothing is bound to are answered with "port unreachable".
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::ipv4::{self, Header, PROTOCOL_ICMP};
+use super::{interfaces, Ipv4Addr, NetError};
+use crate::sync::SpinLock;
+use crate::time::{Duration, Instant};
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_DEST_UNREACHABLE: u8 = 3;
+const TYPE_ECHO_REQUEST: u8 = 8;
+
+const CODE_PORT_UNREACHABLE: u8 = 3;
+
+/// How much of a datagram's payload, after its IP header, goes back with
+/// an error about it.
+pub const ERROR_PAYLOAD_SIZE: usize = 8;
+
+/// The type, code, checksum, identifier and sequence number.
+const HEADER_SIZE: usize = 8;
+
+/// The payload of the pings we send, as for `ping` on Linux.
+const PING_DATA_SIZE: usize = 56;
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Pings waiting for replies, by identifier and sequence number, with when
+/// the reply came.
+static PINGS: SpinLock<BTreeMap<(u16, u16), Option<Instant>>> = SpinLock::new(BTreeMap::new());
+
+pub fn receive(header: &Header, message: &[u8]) {
+    if message.len() < HEADER_SIZE || super::checksum(message, 0) != 0 {
+        return;
+    }
+    let id = u16::from_be_bytes([message[4], message[5]]);
+    let seq = u16::from_be_bytes([message[6], message[7]]);
+    match message[0] {
+        TYPE_ECHO_REQUEST => {
+            // Only answer pings to our own address, not broadcasts.
+            let ours = interfaces()
+                .iter()
+                .any(|interface| interface.config().is_some_and(|c| c.addr == header.dst));
+            if ours {
+                let _ = send(
+                    header.src,
+                    TYPE_ECHO_REPLY,
+                    0,
+                    id,
+                    seq,
+                    &message[HEADER_SIZE..],
+                );
+            }
+        }
+        TYPE_ECHO_REPLY => {
+            if let Some(received) = PINGS.lock().get_mut(&(id, seq)) {
+                received.get_or_insert(Instant::now());
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Tells the sender of a UDP datagram that nothing is bound to its port.
+/// `original` is the datagram's IP header and the start of its payload.
+pub fn port_unreachable(header: &Header, original: &[u8]) {
+    let _ = send(
+        header.src,
+        TYPE_DEST_UNREACHABLE,
+        CODE_PORT_UNREACHABLE,
+        0,
+        0,
+        original,
+    );
+}
+
+fn send(dst: Ipv4Addr, kind: u8, code: u8, id: u16, seq: u16, data: &[u8]) -> Result<(), NetError> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + data.len());
+    message.extend_from_slice(&[kind, code, 0, 0]);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&seq.to_be_bytes());
+    message.extend_from_slice(data);
+    let checksum = super::checksum(&message, 0);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    ipv4::send(dst, PROTOCOL_ICMP, &message)
+}
+
+/// Pings `dst`, returning the round-trip time.
+pub fn ping(dst: Ipv4Addr, seq: u16, timeout: Duration) -> Result<Duration, NetError> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let data: Vec<u8> = (0..PING_DATA_SIZE as u8).collect();
+    PINGS.lock().insert((id, seq), None);
+    let sent = Instant::now();
+    let result = send(dst, TYPE_ECHO_REQUEST, 0, id, seq, &data).and_then(|()| {
+        super::poll_until(Some(timeout), || PINGS.lock()[&(id, seq)])
+            .map(|received| received - sent)
+            .ok_or(NetError::TimedOut)
+    });
+    PINGS.lock().remove(&(id, seq));
+    result
+}
@@ -0,0 +1,144 @@
+//! IPv4, without fragmentation: fragments are dropped, and packets are
+//! sent with "don't fragment" set.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::{arp, icmp, interfaces, tcp, udp, Interface, Ipv4Addr, NetError};
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// The size of a header without options.
+pub const HEADER_SIZE: usize = 20;
+
+const TTL: u8 = 64;
+
+/// The "don't fragment" flag, and the "more fragments" flag and fragment
+/// offset.
+const FLAG_DF: u16 = 0x4000;
+const FRAGMENT: u16 = 0x3fff;
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+/// The addresses and protocol of a received packet.
+pub struct Header {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+}
+
+pub fn receive(interface: &Arc<Interface>, packet: &[u8]) {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = usize::from(packet[0] & 0xf) * 4;
+    let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    if header_len < HEADER_SIZE || total_len < header_len || total_len > packet.len() {
+        return;
+    }
+    if super::checksum(&packet[..header_len], 0) != 0 {
+        return;
+    }
+    if u16::from_be_bytes([packet[6], packet[7]]) & FRAGMENT != 0 {
+        return;
+    }
+    let header = Header {
+        src: Ipv4Addr(packet[12..16].try_into().unwrap()),
+        dst: Ipv4Addr(packet[16..20].try_into().unwrap()),
+        protocol: packet[9],
+    };
+    // Interfaces without an address take anything, as they're finding one.
+    let for_us = interface.config().is_none_or(|config| {
+        header.dst == config.addr
+            || header.dst == config.broadcast()
+            || header.dst == Ipv4Addr::BROADCAST
+    });
+    if !for_us {
+        return;
+    }
+
+    let payload = &packet[header_len..total_len];
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::receive(&header, payload),
+        PROTOCOL_TCP => tcp::receive(&header, payload),
+        PROTOCOL_UDP => {
+            // Errors only go back for datagrams sent to us alone.
+            let unicast = interface
+                .config()
+                .is_some_and(|config| header.dst == config.addr);
+            if !udp::receive(&header, payload) && unicast {
+                let len = total_len.min(header_len + icmp::ERROR_PAYLOAD_SIZE);
+                icmp::port_unreachable(&header, &packet[..len]);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The interface to send to `dst` through, the source address to use and
+/// the next hop.
+pub fn route(dst: Ipv4Addr) -> Result<(Arc<Interface>, Ipv4Addr, Ipv4Addr), NetError> {
+    let interfaces = interfaces();
+    let configured = || {
+        interfaces
+            .iter()
+            .filter_map(|interface| Some((interface, interface.config()?)))
+    };
+    if let Some((interface, config)) =
+        configured().find(|(_, config)| config.contains(dst) || dst == Ipv4Addr::BROADCAST)
+    {
+        return Ok((interface.clone(), config.addr, dst));
+    }
+    configured()
+        .find_map(|(interface, config)| Some((interface.clone(), config.addr, config.gateway?)))
+        .ok_or(NetError::Unreachable)
+}
+
+/// Sends `payload` to `dst`, from the address of the interface it's routed
+/// through.
+pub fn send(dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    let (interface, src, next_hop) = route(dst)?;
+    send_via(&interface, src, dst, next_hop, protocol, payload)
+}
+
+/// Sends `payload` from `src` to `dst` through `interface`, to `next_hop`.
+pub fn send_via(
+    interface: &Arc<Interface>,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    next_hop: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let total_len = HEADER_SIZE + payload.len();
+    if total_len > interface.mtu() {
+        return Err(NetError::TooLarge);
+    }
+    let mut packet = Vec::with_capacity(total_len);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+    packet.extend_from_slice(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    packet.extend_from_slice(&FLAG_DF.to_be_bytes());
+    packet.extend_from_slice(&[TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&src.0);
+    packet.extend_from_slice(&dst.0);
+    let checksum = super::checksum(&packet, 0);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    arp::send_ipv4(interface, next_hop, packet)
+}
+
+/// The sum of the pseudo-header TCP and UDP checksums cover, to start their
+/// checksums with.
+pub fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let word = |bytes: [u8; 2]| u32::from(u16::from_be_bytes(bytes));
+    word([src.0[0], src.0[1]])
+        + word([src.0[2], src.0[3]])
+        + word([dst.0[0], dst.0[1]])
+        + word([dst.0[2], dst.0[3]])
+        + u32::from(protocol)
+        + len as u32
+}
@@ -0,0 +1,801 @@
+//! TCP, kept simple: segments arriving out of order are dropped, lost
+//! segments are resent go-back-N style from the oldest unacknowledged byte,
+//! and there's no congestion control beyond the peer's window.
+//!
+//! A connection's timers are wheel timers that wake the network task,
+//! which runs them in `poll`.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+use super::ipv4::{self, Header, PROTOCOL_TCP};
+use super::{Ipv4Addr, NetError};
+use crate::sync::SpinLock;
+use crate::time::{Duration, Instant, Timer};
+use crate::{ktest, rand};
+
+/// The size of a header without options.
+const HEADER_SIZE: usize = 20;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// The segment size assumed if the peer doesn't give one.
+const DEFAULT_MSS: usize = 536;
+
+/// How much is buffered each way for a connection.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(16);
+
+/// How many times a segment is resent before the connection is given up.
+const MAX_RETRIES: u32 = 5;
+
+/// How long a connection stays in TIME-WAIT. This should be twice the
+/// maximum segment lifetime, but nobody wants to wait four minutes.
+const TIME_WAIT: Duration = Duration::from_secs(2);
+
+/// How many established connections a listener holds for `accept`.
+const BACKLOG: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct Key {
+    local_port: u16,
+    remote: Ipv4Addr,
+    remote_port: u16,
+}
+
+/// A connection's transmission control block.
+struct Tcb {
+    state: State,
+    /// Our address, which the connection was made on.
+    local: Ipv4Addr,
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: usize,
+    rcv_nxt: u32,
+    mss: usize,
+    /// The bytes from `snd_una` on: those sent but not acknowledged, then
+    /// those not sent yet.
+    send_buf: VecDeque<u8>,
+    recv_buf: VecDeque<u8>,
+    /// We're done sending, so a FIN follows the data.
+    closing: bool,
+    fin_sent: bool,
+    /// The peer's FIN has been received.
+    peer_closed: bool,
+    /// Armed while there's something sent but not acknowledged.
+    retransmit: Option<Timer>,
+    rto: Duration,
+    retries: u32,
+    time_wait: Option<Timer>,
+    error: Option<NetError>,
+    /// A socket or a listener's backlog still refers to the connection, so
+    /// it's kept once closed.
+    owned: bool,
+    /// The listener the connection came in on, which it's handed to once
+    /// established. Until then, nothing refers to it.
+    listener: Option<u16>,
+}
+
+impl Tcb {
+    fn new(state: State, local: Ipv4Addr) -> Self {
+        let iss = rand::u32();
+        Self {
+            state,
+            local,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: DEFAULT_MSS,
+            rcv_nxt: 0,
+            mss: DEFAULT_MSS,
+            send_buf: VecDeque::new(),
+            recv_buf: VecDeque::new(),
+            closing: false,
+            fin_sent: false,
+            peer_closed: false,
+            retransmit: None,
+            rto: INITIAL_RTO,
+            retries: 0,
+            time_wait: None,
+            error: None,
+            owned: true,
+            listener: None,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (BUFFER_SIZE - self.recv_buf.len()).min(usize::from(u16::MAX)) as u16
+    }
+
+    fn close(&mut self, error: Option<NetError>) {
+        self.state = State::Closed;
+        self.error = self.error.or(error);
+        self.retransmit = None;
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.retransmit = None;
+        self.time_wait = Some(timer(TIME_WAIT));
+    }
+}
+
+struct Tcp {
+    connections: BTreeMap<Key, Tcb>,
+    /// The connections ready to be accepted, by listening port.
+    listeners: BTreeMap<u16, VecDeque<Key>>,
+}
+
+static TCP: SpinLock<Tcp> = SpinLock::new(Tcp {
+    connections: BTreeMap::new(),
+    listeners: BTreeMap::new(),
+});
+
+/// Sends a segment. Failures are left to retransmission.
+#[allow(clippy::too_many_arguments)]
+fn transmit(
+    local: Ipv4Addr,
+    key: &Key,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    data: &[u8],
+) {
+    let segment = build(local, key, seq, ack, flags, window, mss, data);
+    let _ = ipv4::send(key.remote, PROTOCOL_TCP, &segment);
+}
+
+/// Builds a segment from `local` for the connection `key`.
+#[allow(clippy::too_many_arguments)]
+fn build(
+    local: Ipv4Addr,
+    key: &Key,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    data: &[u8],
+) -> Vec<u8> {
+    let options_len = if mss.is_some() { 4 } else { 0 };
+    let len = HEADER_SIZE + options_len + data.len();
+    let mut segment = Vec::with_capacity(len);
+    segment.extend_from_slice(&key.local_port.to_be_bytes());
+    segment.extend_from_slice(&key.remote_port.to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.push((((HEADER_SIZE + options_len) / 4) as u8) << 4);
+    segment.push(flags);
+    segment.extend_from_slice(&window.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = mss {
+        segment.extend_from_slice(&[OPTION_MSS, 4]);
+        segment.extend_from_slice(&mss.to_be_bytes());
+    }
+    segment.extend_from_slice(data);
+    let sum = ipv4::pseudo_header_sum(local, key.remote, PROTOCOL_TCP, len);
+    let checksum = super::checksum(&segment, sum);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    segment
+}
+
+/// The largest segment we can take, which is sent with our SYN.
+fn our_mss(remote: Ipv4Addr) -> u16 {
+    let mtu = ipv4::route(remote).map_or(576, |(interface, _, _)| interface.mtu());
+    (mtu - ipv4::HEADER_SIZE - HEADER_SIZE) as u16
+}
+
+fn send_ack(key: &Key, tcb: &Tcb) {
+    transmit(
+        tcb.local,
+        key,
+        tcb.snd_nxt,
+        tcb.rcv_nxt,
+        ACK,
+        tcb.window(),
+        None,
+        &[],
+    );
+}
+
+/// Sends whatever the state and the peer's window allow.
+fn output(key: &Key, tcb: &mut Tcb) {
+    let sent_any = match tcb.state {
+        State::SynSent | State::SynReceived if tcb.snd_nxt == tcb.iss => {
+            let (flags, ack) = match tcb.state {
+                State::SynSent => (SYN, 0),
+                _ => (SYN | ACK, tcb.rcv_nxt),
+            };
+            let mss = Some(our_mss(key.remote));
+            transmit(tcb.local, key, tcb.iss, ack, flags, tcb.window(), mss, &[]);
+            tcb.snd_nxt = tcb.iss.wrapping_add(1);
+            true
+        }
+        State::Established
+        | State::CloseWait
+        | State::FinWait1
+        | State::Closing
+        | State::LastAck => {
+            let mut sent_any = false;
+            loop {
+                let in_flight = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize;
+                let unsent = tcb.send_buf.len().saturating_sub(in_flight);
+                let len = unsent
+                    .min(tcb.mss)
+                    .min(tcb.snd_wnd.saturating_sub(in_flight));
+                if len == 0 {
+                    break;
+                }
+                let data: Vec<u8> = tcb
+                    .send_buf
+                    .range(in_flight..in_flight + len)
+                    .copied()
+                    .collect();
+                transmit(
+                    tcb.local,
+                    key,
+                    tcb.snd_nxt,
+                    tcb.rcv_nxt,
+                    ACK | PSH,
+                    tcb.window(),
+                    None,
+                    &data,
+                );
+                tcb.snd_nxt = tcb.snd_nxt.wrapping_add(len as u32);
+                sent_any = true;
+            }
+            let all_sent = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize == tcb.send_buf.len();
+            if tcb.closing && !tcb.fin_sent && all_sent {
+                transmit(
+                    tcb.local,
+                    key,
+                    tcb.snd_nxt,
+                    tcb.rcv_nxt,
+                    FIN | ACK,
+                    tcb.window(),
+                    None,
+                    &[],
+                );
+                tcb.snd_nxt = tcb.snd_nxt.wrapping_add(1);
+                tcb.fin_sent = true;
+                tcb.state = match tcb.state {
+                    State::Established => State::FinWait1,
+                    State::CloseWait => State::LastAck,
+                    state => state,
+                };
+                sent_any = true;
+            }
+            sent_any
+        }
+        _ => false,
+    };
+    if sent_any && tcb.retransmit.is_none() {
+        tcb.retransmit = Some(timer(tcb.rto));
+    }
+}
+
+/// Arms a timer to run `poll` after `delay`.
+fn timer(delay: Duration) -> Timer {
+    Timer::start(Instant::now() + delay, super::wake)
+}
+
+/// Runs the timers of every connection, and forgets those that are closed
+/// and no longer used. Called by the network task.
+pub fn poll() {
+    let mut tcp = TCP.lock();
+    for (key, tcb) in &mut tcp.connections {
+        if tcb.time_wait.as_ref().is_some_and(Timer::has_fired) {
+            tcb.time_wait = None;
+            tcb.close(None);
+        }
+        if !tcb.retransmit.as_ref().is_some_and(Timer::has_fired) {
+            continue;
+        }
+        tcb.retries += 1;
+        if tcb.retries > MAX_RETRIES {
+            tcb.close(Some(NetError::TimedOut));
+            continue;
+        }
+        tcb.rto = (tcb.rto * 2).min(MAX_RTO);
+        tcb.retransmit = None;
+        // Start again from what was last acknowledged.
+        tcb.snd_nxt = tcb.snd_una;
+        tcb.fin_sent = false;
+        output(key, tcb);
+    }
+    tcp.connections
+        .retain(|_, tcb| tcb.state != State::Closed || (tcb.owned && tcb.listener.is_none()));
+}
+
+/// A received segment, past its header.
+struct Segment<'a> {
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: usize,
+    mss: Option<usize>,
+    data: &'a [u8],
+}
+
+/// Finds the MSS option, if there is one.
+fn parse_mss(mut options: &[u8]) -> Option<usize> {
+    loop {
+        match *options {
+            [] | [OPTION_END, ..] => return None,
+            [OPTION_NOP, ref rest @ ..] => options = rest,
+            [OPTION_MSS, 4, high, low, ..] => {
+                return Some(usize::from(u16::from_be_bytes([high, low])))
+            }
+            [_, len, ..] if len >= 2 => options = options.get(usize::from(len)..)?,
+            _ => return None,
+        }
+    }
+}
+
+/// Checks the segment in a packet with `header`, returning the connection
+/// it's for and what's in it.
+fn parse<'a>(header: &Header, segment: &'a [u8]) -> Option<(Key, Segment<'a>)> {
+    if segment.len() < HEADER_SIZE {
+        return None;
+    }
+    let sum = ipv4::pseudo_header_sum(header.src, header.dst, PROTOCOL_TCP, segment.len());
+    if super::checksum(segment, sum) != 0 {
+        return None;
+    }
+    let data_offset = usize::from(segment[12] >> 4) * 4;
+    if data_offset < HEADER_SIZE || data_offset > segment.len() {
+        return None;
+    }
+    let word = |at: usize| u32::from_be_bytes(segment[at..at + 4].try_into().unwrap());
+    let key = Key {
+        local_port: u16::from_be_bytes([segment[2], segment[3]]),
+        remote: header.src,
+        remote_port: u16::from_be_bytes([segment[0], segment[1]]),
+    };
+    let seg = Segment {
+        seq: word(4),
+        ack: word(8),
+        flags: segment[13],
+        window: usize::from(u16::from_be_bytes([segment[14], segment[15]])),
+        mss: parse_mss(&segment[HEADER_SIZE..data_offset]),
+        data: &segment[data_offset..],
+    };
+    Some((key, seg))
+}
+
+pub fn receive(header: &Header, segment: &[u8]) {
+    let Some((key, seg)) = parse(header, segment) else {
+        return;
+    };
+
+    let mut tcp = TCP.lock();
+    let tcp = &mut *tcp;
+    if let Some(tcb) = tcp.connections.get_mut(&key) {
+        segment_arrives(&key, tcb, &seg);
+        // Hand newly established connections to their listener.
+        if tcb.state != State::SynReceived {
+            if let Some(port) = tcb.listener.take() {
+                match (tcb.error, tcp.listeners.get_mut(&port)) {
+                    (None, Some(backlog)) => backlog.push_back(key),
+                    (None, None) => {
+                        transmit(tcb.local, &key, tcb.snd_nxt, 0, RST, 0, None, &[]);
+                        tcb.close(Some(NetError::ConnectionReset));
+                        tcb.owned = false;
+                    }
+                    (Some(_), _) => tcb.owned = false,
+                }
+            }
+        }
+        return;
+    }
+
+    let listening = tcp
+        .listeners
+        .get(&key.local_port)
+        .is_some_and(|backlog| backlog.len() < BACKLOG);
+    if seg.flags & (SYN | ACK | RST) == SYN && listening {
+        let mut tcb = Tcb::new(State::SynReceived, header.dst);
+        tcb.rcv_nxt = seg.seq.wrapping_add(1);
+        tcb.snd_wnd = seg.window;
+        tcb.mss = seg
+            .mss
+            .unwrap_or(DEFAULT_MSS)
+            .min(usize::from(our_mss(key.remote)));
+        tcb.listener = Some(key.local_port);
+        output(&key, &mut tcb);
+        tcp.connections.insert(key, tcb);
+    } else if seg.flags & RST == 0 {
+        // Nothing's there, so reset whatever the peer thinks is.
+        if seg.flags & ACK != 0 {
+            transmit(header.dst, &key, seg.ack, 0, RST, 0, None, &[]);
+        } else {
+            let len = seg.data.len()
+                + usize::from(seg.flags & SYN != 0)
+                + usize::from(seg.flags & FIN != 0);
+            let ack = seg.seq.wrapping_add(len as u32);
+            transmit(header.dst, &key, 0, ack, RST | ACK, 0, None, &[]);
+        }
+    }
+}
+
+fn segment_arrives(key: &Key, tcb: &mut Tcb, seg: &Segment<'_>) {
+    match tcb.state {
+        State::Closed => return,
+        State::SynSent => {
+            let acceptable = seg.flags & ACK == 0 || seg.ack == tcb.iss.wrapping_add(1);
+            if !acceptable {
+                if seg.flags & RST == 0 {
+                    transmit(tcb.local, key, seg.ack, 0, RST, 0, None, &[]);
+                }
+                return;
+            }
+            if seg.flags & RST != 0 {
+                if seg.flags & ACK != 0 {
+                    tcb.close(Some(NetError::ConnectionRefused));
+                }
+                return;
+            }
+            // Simultaneous opens, with a SYN but no ACK, aren't supported.
+            if seg.flags & (SYN | ACK) == SYN | ACK {
+                tcb.rcv_nxt = seg.seq.wrapping_add(1);
+                tcb.snd_una = seg.ack;
+                tcb.snd_wnd = seg.window;
+                tcb.mss = seg.mss.unwrap_or(DEFAULT_MSS).min(tcb.mss);
+                tcb.state = State::Established;
+                tcb.retransmit = None;
+                tcb.retries = 0;
+                send_ack(key, tcb);
+                output(key, tcb);
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    // Only take segments starting at the next byte expected, trimming what
+    // we've already had from a resent one.
+    let mut seq = seg.seq;
+    let mut data = seg.data;
+    let behind = tcb.rcv_nxt.wrapping_sub(seq) as usize;
+    if (behind as i32) > 0 && behind <= data.len() {
+        data = &data[behind..];
+        seq = tcb.rcv_nxt;
+    }
+    if seq != tcb.rcv_nxt {
+        if seg.flags & RST == 0 {
+            if tcb.state == State::SynReceived && seg.flags & SYN != 0 {
+                // Our SYN-ACK was lost.
+                tcb.snd_nxt = tcb.iss;
+                output(key, tcb);
+            } else {
+                send_ack(key, tcb);
+            }
+        }
+        return;
+    }
+    if seg.flags & RST != 0 {
+        tcb.close(Some(NetError::ConnectionReset));
+        return;
+    }
+    if seg.flags & SYN != 0 || seg.flags & ACK == 0 {
+        return;
+    }
+
+    if tcb.state == State::SynReceived {
+        if seg.ack != tcb.iss.wrapping_add(1) {
+            transmit(tcb.local, key, seg.ack, 0, RST, 0, None, &[]);
+            return;
+        }
+        tcb.snd_una = seg.ack;
+        tcb.state = State::Established;
+        tcb.retransmit = None;
+        tcb.retries = 0;
+    }
+    let acked = seg.ack.wrapping_sub(tcb.snd_una) as usize;
+    let in_flight = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize;
+    if acked > in_flight {
+        // It acknowledges something we haven't sent.
+        send_ack(key, tcb);
+        return;
+    }
+    if acked > 0 {
+        let fin_acked = tcb.fin_sent && seg.ack == tcb.snd_nxt;
+        let bytes = acked.min(tcb.send_buf.len());
+        tcb.send_buf.drain(..bytes);
+        tcb.snd_una = seg.ack;
+        tcb.rto = INITIAL_RTO;
+        tcb.retries = 0;
+        tcb.retransmit = (tcb.snd_una != tcb.snd_nxt).then(|| timer(tcb.rto));
+        if fin_acked {
+            match tcb.state {
+                State::FinWait1 => tcb.state = State::FinWait2,
+                State::Closing => tcb.enter_time_wait(),
+                State::LastAck => tcb.close(None),
+                _ => {}
+            }
+        }
+    }
+    tcb.snd_wnd = seg.window;
+
+    let mut need_ack = false;
+    let mut taken = 0;
+    let receiving = matches!(
+        tcb.state,
+        State::Established | State::FinWait1 | State::FinWait2
+    );
+    if receiving && !data.is_empty() {
+        taken = data.len().min(BUFFER_SIZE - tcb.recv_buf.len());
+        // Nobody would read it once the socket's closed.
+        if tcb.owned {
+            tcb.recv_buf.extend(&data[..taken]);
+        }
+        tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(taken as u32);
+        need_ack = true;
+    }
+    if seg.flags & FIN != 0 && taken == data.len() && !tcb.peer_closed {
+        tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(1);
+        tcb.peer_closed = true;
+        need_ack = true;
+        match tcb.state {
+            State::Established => tcb.state = State::CloseWait,
+            State::FinWait1 => tcb.state = State::Closing,
+            State::FinWait2 => tcb.enter_time_wait(),
+            _ => {}
+        }
+    }
+    if need_ack {
+        send_ack(key, tcb);
+    }
+    output(key, tcb);
+}
+
+/// A port listening for connections. It stops listening when dropped.
+pub struct TcpListener {
+    port: u16,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<Self, NetError> {
+        let mut tcp = TCP.lock();
+        if tcp.listeners.contains_key(&port) {
+            return Err(NetError::AddrInUse);
+        }
+        tcp.listeners.insert(port, VecDeque::new());
+        Ok(Self { port })
+    }
+
+    /// Waits for a connection, for at most `timeout` if given.
+    pub fn accept(&self, timeout: Option<Duration>) -> Result<TcpStream, NetError> {
+        super::poll_until(timeout, || {
+            let mut tcp = TCP.lock();
+            tcp.listeners.get_mut(&self.port)?.pop_front()
+        })
+        .map(|key| TcpStream { key })
+        .ok_or(NetError::TimedOut)
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut tcp = TCP.lock();
+        let tcp = &mut *tcp;
+        for key in tcp.listeners.remove(&self.port).unwrap_or_default() {
+            if let Some(tcb) = tcp.connections.get_mut(&key) {
+                transmit(tcb.local, &key, tcb.snd_nxt, 0, RST, 0, None, &[]);
+                tcb.close(Some(NetError::ConnectionReset));
+                tcb.owned = false;
+            }
+        }
+    }
+}
+
+/// A connection. Dropping it closes it, and the connection lives on until
+/// the close is done.
+pub struct TcpStream {
+    key: Key,
+}
+
+impl TcpStream {
+    /// Connects to `port` on `remote`, waiting until the connection is
+    /// established or has failed.
+    pub fn connect(remote: Ipv4Addr, port: u16) -> Result<Self, NetError> {
+        let (_, local, _) = ipv4::route(remote)?;
+        let key = {
+            let mut tcp = TCP.lock();
+            let local_port = super::ephemeral_port(|local_port| {
+                tcp.listeners.contains_key(&local_port)
+                    || tcp
+                        .connections
+                        .keys()
+                        .any(|key| key.local_port == local_port)
+            })
+            .ok_or(NetError::AddrInUse)?;
+            let key = Key {
+                local_port,
+                remote,
+                remote_port: port,
+            };
+            let mut tcb = Tcb::new(State::SynSent, local);
+            tcb.mss = usize::from(our_mss(remote));
+            output(&key, &mut tcb);
+            tcp.connections.insert(key, tcb);
+            key
+        };
+        let stream = Self { key };
+        super::poll_until(None, || {
+            stream.with(|tcb| match tcb.state {
+                State::SynSent => None,
+                State::Closed => Some(Err(tcb.error.unwrap_or(NetError::ConnectionRefused))),
+                _ => Some(Ok(())),
+            })
+        })
+        .unwrap()?;
+        Ok(stream)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Tcb) -> R) -> R {
+        let mut tcp = TCP.lock();
+        f(tcp
+            .connections
+            .get_mut(&self.key)
+            .expect("connection forgotten"))
+    }
+
+    /// The address and port of the other end.
+    pub fn peer(&self) -> (Ipv4Addr, u16) {
+        (self.key.remote, self.key.remote_port)
+    }
+
+    /// Reads what's been received into `buf`, waiting for something to
+    /// arrive, for at most `timeout` if given. Returns 0 once the peer has
+    /// closed its end.
+    pub fn read(&self, buf: &mut [u8], timeout: Option<Duration>) -> Result<usize, NetError> {
+        super::poll_until(timeout, || {
+            self.with(|tcb| {
+                if !tcb.recv_buf.is_empty() {
+                    let was_full = tcb.recv_buf.len() > BUFFER_SIZE / 2;
+                    let len = buf.len().min(tcb.recv_buf.len());
+                    for (byte, received) in buf.iter_mut().zip(tcb.recv_buf.drain(..len)) {
+                        *byte = received;
+                    }
+                    // Tell the peer there's room again.
+                    if was_full {
+                        send_ack(&self.key, tcb);
+                    }
+                    Some(Ok(len))
+                } else if tcb.peer_closed {
+                    Some(Ok(0))
+                } else if tcb.state == State::Closed {
+                    Some(Err(tcb.error.unwrap_or(NetError::NotConnected)))
+                } else {
+                    None
+                }
+            })
+        })
+        .unwrap_or(Err(NetError::TimedOut))
+    }
+
+    /// Queues as much of `data` as there's room for to be sent, waiting for
+    /// room if there's none, and returns how much that was.
+    pub fn write(&self, data: &[u8]) -> Result<usize, NetError> {
+        super::poll_until(None, || {
+            self.with(|tcb| {
+                if let Some(err) = tcb.error {
+                    return Some(Err(err));
+                }
+                if tcb.closing || !matches!(tcb.state, State::Established | State::CloseWait) {
+                    return Some(Err(NetError::NotConnected));
+                }
+                let len = data.len().min(BUFFER_SIZE - tcb.send_buf.len());
+                if len == 0 && !data.is_empty() {
+                    return None;
+                }
+                tcb.send_buf.extend(&data[..len]);
+                output(&self.key, tcb);
+                Some(Ok(len))
+            })
+        })
+        .unwrap()
+    }
+
+    /// Writes all of `data`.
+    pub fn write_all(&self, mut data: &[u8]) -> Result<(), NetError> {
+        while !data.is_empty() {
+            let len = self.write(data)?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.with(|tcb| {
+            tcb.closing = true;
+            tcb.owned = false;
+            tcb.recv_buf.clear();
+            match tcb.state {
+                State::SynSent => tcb.close(None),
+                _ => output(&self.key, tcb),
+            }
+        });
+    }
+}
+
+ktest! {
+    fn parses_malformed_segments() {
+        let (a, b) = (Ipv4Addr([10, 0, 2, 15]), Ipv4Addr([10, 0, 2, 2]));
+        let key = Key {
+            local_port: 80,
+            remote: b,
+            remote_port: 1234,
+        };
+        // As `b` sees what `a` sends.
+        let header = Header {
+            src: a,
+            dst: b,
+            protocol: PROTOCOL_TCP,
+        };
+        let segment = build(a, &key, 7, 9, SYN | ACK, 512, Some(1460), b"hi");
+        let (got, seg) = parse(&header, &segment).unwrap();
+        assert_eq!((got.local_port, got.remote, got.remote_port), (1234, a, 80));
+        assert_eq!((seg.seq, seg.ack, seg.flags, seg.window), (7, 9, SYN | ACK, 512));
+        assert_eq!((seg.mss, seg.data), (Some(1460), &b"hi"[..]));
+
+        // Fixes up the checksum after changing the header.
+        let resum = |mut segment: Vec<u8>| {
+            segment[16..18].fill(0);
+            let sum = ipv4::pseudo_header_sum(a, b, PROTOCOL_TCP, segment.len());
+            let checksum = super::checksum(&segment, sum);
+            segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+            segment
+        };
+        assert!(parse(&header, &segment[..HEADER_SIZE - 1]).is_none());
+        let mut corrupt = segment.clone();
+        corrupt[4] ^= 1;
+        assert!(parse(&header, &corrupt).is_none());
+        let mut short_offset = segment.clone();
+        short_offset[12] = 4 << 4;
+        assert!(parse(&header, &resum(short_offset)).is_none());
+        let mut long_offset = segment.clone();
+        long_offset[12] = 15 << 4;
+        assert!(parse(&header, &resum(long_offset)).is_none());
+
+        assert_eq!(parse_mss(&[OPTION_NOP, 3, 3, 0, OPTION_MSS, 4, 0x05, 0xb4]), Some(1460));
+        assert_eq!(parse_mss(&[OPTION_NOP, OPTION_NOP]), None);
+        assert_eq!(parse_mss(&[OPTION_END, OPTION_MSS, 4, 0x05, 0xb4]), None);
+        // Cut short, a zero length, and a length past the end.
+        assert_eq!(parse_mss(&[OPTION_MSS, 4, 0x05]), None);
+        assert_eq!(parse_mss(&[8, 0, OPTION_MSS, 4, 0x05, 0xb4]), None);
+        assert_eq!(parse_mss(&[8, 10, OPTION_MSS, 4, 0x05, 0xb4]), None);
+        // An MSS option of the wrong length is skipped.
+        assert_eq!(parse_mss(&[OPTION_MSS, 3, 0, OPTION_MSS, 4, 0x05, 0xb4]), Some(1460));
+    }
+}
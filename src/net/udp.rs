@@ -0,0 +1,176 @@
+//! UDP sockets.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::ipv4::{self, Header, PROTOCOL_UDP};
+use super::{Interface, Ipv4Addr, NetError};
+use crate::ktest;
+use crate::sync::SpinLock;
+use crate::time::Duration;
+
+/// The ports, length and checksum.
+const HEADER_SIZE: usize = 8;
+
+/// How many datagrams a socket holds before dropping new ones.
+const MAX_QUEUED: usize = 32;
+
+struct Datagram {
+    src: Ipv4Addr,
+    src_port: u16,
+    data: Vec<u8>,
+}
+
+/// The datagrams received for each bound port.
+static SOCKETS: SpinLock<BTreeMap<u16, VecDeque<Datagram>>> = SpinLock::new(BTreeMap::new());
+
+/// Queues a datagram for the socket bound to its port. Returns false if
+/// there's no such socket, for the sender to be told.
+pub fn receive(header: &Header, datagram: &[u8]) -> bool {
+    if datagram.len() < HEADER_SIZE {
+        return true;
+    }
+    let src_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let dst_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let len = usize::from(u16::from_be_bytes([datagram[4], datagram[5]]));
+    if len < HEADER_SIZE || len > datagram.len() {
+        return true;
+    }
+    let datagram = &datagram[..len];
+    // A zero checksum means the sender didn't compute one.
+    let has_checksum = datagram[6..8] != [0, 0];
+    let sum = ipv4::pseudo_header_sum(header.src, header.dst, PROTOCOL_UDP, len);
+    if has_checksum && super::checksum(datagram, sum) != 0 {
+        return true;
+    }
+
+    let mut sockets = SOCKETS.lock();
+    let Some(queue) = sockets.get_mut(&dst_port) else {
+        return false;
+    };
+    if queue.len() < MAX_QUEUED {
+        queue.push_back(Datagram {
+            src: header.src,
+            src_port,
+            data: datagram[HEADER_SIZE..].into(),
+        });
+    }
+    true
+}
+
+/// A bound UDP port. It's unbound when dropped.
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Binds `port`, or a free ephemeral port if it's 0.
+    pub fn bind(port: u16) -> Result<Self, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            0 => super::ephemeral_port(|port| sockets.contains_key(&port))
+                .ok_or(NetError::AddrInUse)?,
+            _ if sockets.contains_key(&port) => return Err(NetError::AddrInUse),
+            _ => port,
+        };
+        sockets.insert(port, VecDeque::new());
+        Ok(Self { port })
+    }
+
+    /// Sends `data` to `port` on `dst`.
+    pub fn send_to(&self, data: &[u8], dst: Ipv4Addr, port: u16) -> Result<(), NetError> {
+        let (interface, src, next_hop) = ipv4::route(dst)?;
+        let datagram = self.datagram(data, src, dst, port)?;
+        ipv4::send_via(&interface, src, dst, next_hop, PROTOCOL_UDP, &datagram)
+    }
+
+    /// Sends `data` to `port` on `dst` through `interface` from `src`, even
+    /// if the interface has no address yet.
+    pub fn send_via(
+        &self,
+        interface: &Arc<Interface>,
+        src: Ipv4Addr,
+        data: &[u8],
+        dst: Ipv4Addr,
+        port: u16,
+    ) -> Result<(), NetError> {
+        let datagram = self.datagram(data, src, dst, port)?;
+        ipv4::send_via(interface, src, dst, dst, PROTOCOL_UDP, &datagram)
+    }
+
+    fn datagram(
+        &self,
+        data: &[u8],
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        port: u16,
+    ) -> Result<Vec<u8>, NetError> {
+        let len = HEADER_SIZE + data.len();
+        let len_field = u16::try_from(len).map_err(|_| NetError::TooLarge)?;
+        let mut datagram = Vec::with_capacity(len);
+        datagram.extend_from_slice(&self.port.to_be_bytes());
+        datagram.extend_from_slice(&port.to_be_bytes());
+        datagram.extend_from_slice(&len_field.to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+        let sum = ipv4::pseudo_header_sum(src, dst, PROTOCOL_UDP, len);
+        // A checksum of 0 is sent as all ones, since 0 means there's none.
+        let checksum = match super::checksum(&datagram, sum) {
+            0 => 0xffff,
+            checksum => checksum,
+        };
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+        Ok(datagram)
+    }
+
+    /// Receives a datagram into `buf`, returning its length and where it's
+    /// from. A longer datagram is cut short. Waits at most `timeout`, if
+    /// given.
+    pub fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<(usize, Ipv4Addr, u16), NetError> {
+        let datagram =
+            super::poll_until(timeout, || SOCKETS.lock().get_mut(&self.port)?.pop_front())
+                .ok_or(NetError::TimedOut)?;
+        let len = datagram.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram.data[..len]);
+        Ok((len, datagram.src, datagram.src_port))
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+ktest! {
+    fn queues_datagrams_for_bound_ports() {
+        let socket = UdpSocket::bind(0).unwrap();
+        let header = Header {
+            src: Ipv4Addr([10, 0, 2, 2]),
+            dst: Ipv4Addr([10, 0, 2, 15]),
+            protocol: PROTOCOL_UDP,
+        };
+        let datagram = |port: u16| {
+            let mut datagram = alloc::vec![0, 53];
+            datagram.extend_from_slice(&port.to_be_bytes());
+            datagram.extend_from_slice(&[0, 10, 0, 0, b'h', b'i']);
+            datagram
+        };
+        assert!(receive(&header, &datagram(socket.port)));
+        let mut buf = [0; 8];
+        let received = socket.recv_from(&mut buf, Some(Duration::ZERO));
+        assert_eq!(received, Ok((2, header.src, 53)));
+        assert_eq!(&buf[..2], b"hi");
+
+        let port = socket.port;
+        drop(socket);
+        assert!(!receive(&header, &datagram(port)));
+        // Malformed datagrams are dropped without an error going back.
+        assert!(receive(&header, &datagram(port)[..6]));
+    }
+}
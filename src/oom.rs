@@ -0,0 +1,100 @@
+//! The out-of-memory killer. When a page fault needs a frame for a user
+//! page and there are none left, rather than failing the fault, a process
+//! is picked and killed to free its memory, and the fault is retried once
+//! it has exited. The kernel's own allocations are left to fail as before.
+//!
+//! The victim is the process with the highest badness: the pages it has,
+//! plus its `oom_score_adj` in thousandths of all memory, as on Linux. A
+//! process can lower its own, or be kept from being picked at all with
+//! `SCORE_ADJ_MIN`, through `/proc/self/oom_score_adj`.
+//!
+//! Only one victim is killed at a time. A victim that's blocked in the
+//! kernel may take a while to see its `SIGKILL`, so after `VICTIM_TIMEOUT`
+//! another is picked.
+
+use alloc::sync::{Arc, Weak};
+
+use crate::mm::frame;
+use crate::process::{self, Process};
+use crate::signal::SIGKILL;
+use crate::sync::SpinLock;
+use crate::task;
+use crate::time::{Duration, Instant};
+use crate::{log_error, log_warn};
+
+/// `oom_score_adj` to never be killed, and to always be killed first.
+pub const SCORE_ADJ_MIN: i16 = -1000;
+pub const SCORE_ADJ_MAX: i16 = 1000;
+
+/// How long a victim is waited for before another is picked.
+const VICTIM_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a fault waits for memory to be freed before it's retried.
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// The last victim, and when it was killed.
+static VICTIM: SpinLock<Option<(Weak<Process>, Instant)>> = SpinLock::new(None);
+
+/// How likely `process` is to be killed, or `None` if it mustn't be: the
+/// pages it has, adjusted by `oom_score_adj` out of `total` pages.
+fn badness(process: &Process, total: usize) -> Option<usize> {
+    let adj = process.oom_score_adj();
+    if adj == SCORE_ADJ_MIN || process.exit_status().is_some() {
+        return None;
+    }
+    let pages = process.address_space().resident_pages() as isize;
+    let score = pages + isize::from(adj) * total as isize / 1000;
+    Some(score.max(1) as usize)
+}
+
+/// Whether the last victim is still on its way out.
+fn victim_pending() -> bool {
+    let victim = VICTIM.lock();
+    let Some((process, killed)) = victim.as_ref() else {
+        return false;
+    };
+    Instant::now() < *killed + VICTIM_TIMEOUT
+        && process
+            .upgrade()
+            .is_some_and(|process| process.exit_status().is_none())
+}
+
+/// Picks the process with the highest badness and kills it.
+fn kill_victim() {
+    let total = frame::stats().total;
+    let victim = process::all()
+        .into_iter()
+        .filter(|process| !process.signals().is_pending(SIGKILL))
+        .filter_map(|process| Some((badness(&process, total)?, process)))
+        .max_by_key(|&(score, _)| score);
+    let Some((score, victim)) = victim else {
+        log_error!(target: "oom", "out of memory, and no process to kill");
+        return;
+    };
+    log_warn!(
+        target: "oom",
+        "out of memory: killing process {}, badness {}, {} pages, oom_score_adj {}",
+        victim.pid(),
+        score,
+        victim.address_space().resident_pages(),
+        victim.oom_score_adj()
+    );
+    victim.send_signal(SIGKILL);
+    *VICTIM.lock() = Some((Arc::downgrade(&victim), Instant::now()));
+}
+
+/// Called when a page fault of the running process's found no memory for
+/// the page. Kills a victim unless one is already dying, and waits a while
+/// for memory to be freed. Returns whether the fault should be retried,
+/// which it shouldn't be if the running process is the one being killed.
+pub fn out_of_memory() -> bool {
+    if !victim_pending() {
+        kill_victim();
+    }
+    let dying = task::process().is_some_and(|process| process.signals().is_pending(SIGKILL));
+    if dying {
+        return false;
+    }
+    task::sleep(RETRY_DELAY);
+    true
+}
@@ -0,0 +1,121 @@
+//! The panic handler. What happens after the panic is reported is chosen by
+//! the `panic=` option: `halt` (the default) stops every hart, `reboot` and
+//! `shutdown` reset the machine, the latter with a failing exit status. A
+//! panic in a kernel test always exits with one. Either way, the report is
+//! saved as a crash dump first, if there's somewhere for it, and `reboot`
+//! tries a warm reboot first so that it survives.
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::csr::{self, Bits};
+use crate::sbi::reset::{self, ResetReason, ResetType};
+use crate::{
+    backtrace, cmdline, crashdump, io, ktest, log_warn, machine, percpu, println, smp, trap,
+};
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Halt,
+    Reboot,
+    Shutdown,
+}
+
+impl Action {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "halt" => Some(Self::Halt),
+            "reboot" => Some(Self::Reboot),
+            "shutdown" => Some(Self::Shutdown),
+            _ => None,
+        }
+    }
+
+    fn from_cmdline() -> Self {
+        cmdline::get("panic")
+            .and_then(Self::parse)
+            .unwrap_or(Self::Halt)
+    }
+}
+
+/// Warns about a `panic=` that isn't understood, which would otherwise only
+/// be noticed when a panic halts instead.
+pub fn init() {
+    if let Some(action) = cmdline::get("panic") {
+        if Action::parse(action).is_none() {
+            log_warn!("invalid panic={:?}, panics will halt", action);
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    trap::disable_interrupts();
+    // Only the first panic is reported, whether it's from another hart or
+    // from reporting this one.
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        smp::halt();
+    }
+    smp::halt_others();
+    // SAFETY: nothing else will use the console until we're done, since the
+    // other harts are halting and we won't return.
+    unsafe { io::force_unlock() };
+    // Even if another virtual console is showing.
+    io::with_console(|console| console.show_log(true));
+
+    println!();
+    println!("panic on hart {}: {}", percpu::hart_id(), info.message());
+    if let Some(location) = info.location() {
+        println!(
+            "    at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        );
+    }
+    println!(
+        "sepc={:#018x} scause={:#018x} stval={:#018x} sstatus={:#018x}",
+        csr::sepc::read(),
+        csr::scause::read(),
+        csr::stval::read(),
+        csr::sstatus::read().bits(),
+    );
+    backtrace::print();
+    crashdump::save();
+
+    if let Some(test) = ktest::running() {
+        println!("test {} FAILED", test);
+        machine::exit(1);
+    }
+
+    match Action::from_cmdline() {
+        Action::Halt => println!("halting"),
+        Action::Reboot => {
+            println!("rebooting");
+            for (ty, name) in [
+                (ResetType::WarmReboot, "warm"),
+                (ResetType::ColdReboot, "cold"),
+            ] {
+                let err = reset::system_reset(ty, ResetReason::SystemFailure);
+                println!("{} reboot failed: {}", name, err);
+            }
+        }
+        Action::Shutdown => {
+            println!("shutting down");
+            machine::exit(1);
+        }
+    }
+    smp::halt();
+}
+
+ktest! {
+    fn parses_actions() {
+        assert!(Action::parse("halt") == Some(Action::Halt));
+        assert!(Action::parse("reboot") == Some(Action::Reboot));
+        assert!(Action::parse("shutdown") == Some(Action::Shutdown));
+        assert!(Action::parse("poweroff").is_none());
+        assert!(Action::parse("").is_none());
+    }
+}
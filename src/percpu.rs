@@ -0,0 +1,135 @@
+//! Per-hart data. Statics declared with `percpu!` are placed in the `.percpu`
+//! section, and every hart gets its own copy of that section, pointed to by
+//! its `tp` register. The boot hart uses the section itself.
+
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ktest;
+use crate::mm::frame::{self, FRAME_SIZE};
+use crate::mm::{phys_to_virt, virt_to_phys};
+
+extern "C" {
+    static _spercpu: u8;
+    static _epercpu: u8;
+    static _sdata: u8;
+    static _sidata: u8;
+}
+
+/// Declares per-hart statics, accessed through [`PerCpu::get`].
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[link_section = ".percpu"]
+            $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+        )*
+    };
+}
+
+percpu! {
+    static HART_ID: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// A per-hart static. The static itself is the boot hart's copy; other harts
+/// find theirs at the same offset from their `tp`. It's laid out as just a
+/// `T`, so assembly can find it by symbol.
+#[repr(transparent)]
+pub struct PerCpu<T> {
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: each hart only accesses its own copy, through a shared reference,
+// so `T` must still be `Sync` for interrupt handlers on the same hart.
+unsafe impl<T: Sync> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.value.get() as usize - &raw const _spercpu as usize
+    }
+
+    /// Returns this hart's copy.
+    pub fn get(&self) -> &T {
+        // SAFETY: `tp` points to this hart's copy of `.percpu`, which is
+        // never freed.
+        unsafe { &*((tp() + self.offset()) as *const T) }
+    }
+
+    /// SAFETY: `area` must be a per-hart area returned by `alloc_area`.
+    unsafe fn get_in(&self, area: usize) -> &T {
+        &*((area + self.offset()) as *const T)
+    }
+}
+
+fn tp() -> usize {
+    let tp: usize;
+    // SAFETY: reading `tp` has no side effects.
+    unsafe { asm!("mv {}, tp", out(reg) tp) };
+    tp
+}
+
+/// The ID of the hart we're running on.
+pub fn hart_id() -> usize {
+    HART_ID.get().load(Ordering::Relaxed)
+}
+
+/// Records the boot hart's ID; its per-hart area is set up by start.s.
+pub fn init_boot_hart(hart_id: usize) {
+    HART_ID.get().store(hart_id, Ordering::Relaxed);
+}
+
+/// Allocates a per-hart area for `hart_id`, returning the value for its `tp`.
+/// The area starts out with the initial values of every per-hart static.
+pub fn alloc_area(hart_id: usize) -> Option<usize> {
+    let start = &raw const _spercpu as usize;
+    let size = &raw const _epercpu as usize - start;
+    let area = phys_to_virt(frame::alloc_frames(size.div_ceil(FRAME_SIZE).max(1))?);
+
+    // The boot hart's area has been written to since boot, so copy from the
+    // load image of .data instead.
+    let image = &raw const _sidata as usize + (start - &raw const _sdata as usize);
+    // SAFETY: the area was just allocated and is at least `size` bytes, and
+    // the load image is mapped read-only along with the kernel image.
+    unsafe { core::ptr::copy_nonoverlapping(image as *const u8, area as *mut u8, size) };
+
+    // SAFETY: `area` was set up above.
+    unsafe { HART_ID.get_in(area) }.store(hart_id, Ordering::Relaxed);
+    Some(area)
+}
+
+/// Frees an area from `alloc_area`.
+///
+/// SAFETY: no hart may be using `area` or ever use it.
+pub unsafe fn free_area(area: usize) {
+    let size = &raw const _epercpu as usize - &raw const _spercpu as usize;
+    frame::free_frames(virt_to_phys(area), size.div_ceil(FRAME_SIZE).max(1));
+}
+
+ktest! {
+    fn copies_initial_values() {
+        percpu! {
+            static COUNT: AtomicUsize = AtomicUsize::new(5);
+        }
+
+        COUNT.get().store(9, Ordering::Relaxed);
+        let area = alloc_area(7).unwrap();
+        assert_ne!(area, tp());
+        // SAFETY: `area` is from `alloc_area`.
+        unsafe {
+            assert_eq!(HART_ID.get_in(area).load(Ordering::Relaxed), 7);
+            assert_eq!(COUNT.get_in(area).load(Ordering::Relaxed), 5);
+            COUNT.get_in(area).store(3, Ordering::Relaxed);
+        }
+        assert_eq!(COUNT.get().load(Ordering::Relaxed), 9);
+        // SAFETY: no hart was ever given the area.
+        unsafe { free_area(area) };
+    }
+}
@@ -0,0 +1,156 @@
+//! Performance counters for user programs to profile themselves, opened
+//! with `perf_event_open` as on Linux: only hardware events of the calling
+//! thread are supported, counted from when it's opened. Reading the file
+//! gives the count as a `u64`.
+//!
+//! A counter only counts while its thread is running: it's stopped when the
+//! thread is switched away from, and started again when it's switched back
+//! to. Hardware counters can also be read straight from their CSR, which
+//! the process is allowed to once it's opened one.
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use crate::arch::csr::Counteren;
+use crate::file::File;
+use crate::mm::uaccess::Plain;
+use crate::sbi::pmu::{self, Counter, CounterInfo, Event, Exclude};
+use crate::syscall::Errno;
+use crate::{percpu, task};
+
+/// `perf_event_attr.type` for the generic hardware events.
+pub const PERF_TYPE_HARDWARE: u32 = 0;
+
+/// `perf_event_attr.flags` bits.
+const ATTR_DISABLED: u64 = 1 << 0;
+const ATTR_EXCLUDE_USER: u64 = 1 << 4;
+const ATTR_EXCLUDE_KERNEL: u64 = 1 << 5;
+const ATTR_EXCLUDE_HV: u64 = 1 << 6;
+
+/// The start of Linux's `perf_event_attr`, as far as it's looked at. The
+/// rest must be zero, as far as `size` goes.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct PerfEventAttr {
+    pub kind: u32,
+    pub size: u32,
+    pub config: u64,
+    pub sample_period: u64,
+    pub sample_type: u64,
+    pub read_format: u64,
+    pub flags: u64,
+}
+
+// SAFETY: all fields are integers, with no padding between them.
+unsafe impl Plain for PerfEventAttr {}
+
+/// A counter opened by a thread.
+pub struct PerfCounter {
+    counter: Counter,
+    /// The hart the counter is on, which is the only one it can be read or
+    /// stopped on.
+    hart: usize,
+}
+
+// SAFETY: the counter is only used on the hart it was started on, which its
+// thread never leaves, and which `read` checks it's on.
+unsafe impl Send for PerfCounter {}
+// SAFETY: as above; its methods only make SBI calls and read a CSR.
+unsafe impl Sync for PerfCounter {}
+
+impl File for PerfCounter {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.len() < size_of::<u64>() {
+            return Err(Errno::EINVAL);
+        }
+        if percpu::hart_id() != self.hart {
+            return Err(Errno::EIO);
+        }
+        let count = self.counter.read().map_err(|_| Errno::EIO)?;
+        buf[..size_of::<u64>()].copy_from_slice(&count.to_ne_bytes());
+        Ok(size_of::<u64>())
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+}
+
+/// The counters a thread has opened, which count only while it runs.
+#[derive(Default)]
+pub struct ThreadCounters(Vec<Weak<PerfCounter>>);
+
+impl ThreadCounters {
+    /// Stops the counters, as the thread is switched away from.
+    pub fn stop(&self) {
+        for counter in self.0.iter().filter_map(Weak::upgrade) {
+            let _ = counter.counter.stop();
+        }
+    }
+
+    /// Starts the counters again, as the thread is switched back to.
+    pub fn resume(&self) {
+        for counter in self.0.iter().filter_map(Weak::upgrade) {
+            let _ = counter.counter.resume();
+        }
+    }
+
+    /// Whether any of the counters are still open.
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|counter| counter.strong_count() == 0)
+    }
+
+    fn add(&mut self, counter: &Arc<PerfCounter>) {
+        self.0.retain(|counter| counter.strong_count() > 0);
+        self.0.push(Arc::downgrade(counter));
+    }
+}
+
+fn event(config: u64) -> Option<Event> {
+    Some(match config {
+        0 => Event::Cycles,
+        1 => Event::Instructions,
+        2 => Event::CacheReferences,
+        3 => Event::CacheMisses,
+        4 => Event::Branches,
+        5 => Event::BranchMisses,
+        _ => return None,
+    })
+}
+
+/// Starts a counter of the event in `attr` for the running thread, which
+/// must be one of a process's.
+pub fn open(attr: &PerfEventAttr) -> Result<Arc<PerfCounter>, Errno> {
+    let supported = ATTR_EXCLUDE_USER | ATTR_EXCLUDE_KERNEL | ATTR_EXCLUDE_HV;
+    // There's no ioctl to start a disabled counter, nor sampling.
+    if attr.kind != PERF_TYPE_HARDWARE
+        || attr.flags & !supported != 0
+        || attr.flags & ATTR_DISABLED != 0
+        || attr.sample_period != 0
+        || attr.sample_type != 0
+        || attr.read_format != 0
+    {
+        return Err(Errno::EINVAL);
+    }
+    let event = event(attr.config).ok_or(Errno::EINVAL)?;
+    if !pmu::is_present() {
+        return Err(Errno::ENOENT);
+    }
+    let exclude = Exclude {
+        user: attr.flags & ATTR_EXCLUDE_USER != 0,
+        supervisor: attr.flags & ATTR_EXCLUDE_KERNEL != 0,
+        machine: attr.flags & ATTR_EXCLUDE_HV != 0,
+    };
+    let counter = Counter::start_excluding(event, exclude).map_err(|_| Errno::ENOENT)?;
+    let process = task::process().expect("perf_event_open from a task without a process");
+    if let CounterInfo::Hardware { csr, .. } = counter.info() {
+        process.allow_counters(Counteren::for_csr(csr));
+        task::update_counter_access();
+    }
+    let counter = Arc::new(PerfCounter {
+        counter,
+        hart: percpu::hart_id(),
+    });
+    task::with_counters(|counters| counters.add(&counter));
+    Ok(counter)
+}
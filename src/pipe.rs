@@ -0,0 +1,181 @@
+//! Pipes: a byte stream from a write end to a read end, through a bounded
+//! buffer. Reads block until there's data, writes until there's space.
+//! Reading once the write end is closed gets end of file, and writing once
+//! the read end is closed fails with `EPIPE`.
+//!
+//! A write of up to `PIPE_BUF` bytes goes into the buffer all at once, so
+//! isn't interleaved with other writers' data, as POSIX requires. Longer
+//! writes go in as space is freed.
+//!
+//! `FIONREAD` on the read end gets how many bytes are waiting.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+use crate::file::File;
+use crate::ktest;
+use crate::mm::uaccess::write_user_struct;
+use crate::sync::{SpinLock, WaitQueue};
+use crate::syscall::Errno;
+
+/// Writers block once this many bytes are waiting to be read.
+const CAPACITY: usize = 4096;
+
+/// Writes of up to this many bytes are atomic.
+const PIPE_BUF: usize = CAPACITY;
+
+/// The `ioctl` request for how many bytes can be read without blocking.
+const FIONREAD: usize = 0x541b;
+
+struct Pipe {
+    state: SpinLock<State>,
+    /// Woken when data arrives or the write end is closed.
+    readable: WaitQueue,
+    /// Woken when space is freed or the read end is closed.
+    writable: WaitQueue,
+}
+
+struct State {
+    buf: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+/// The end of a pipe that's read from. Dropping it closes it.
+pub struct ReadEnd(Arc<Pipe>);
+
+/// The end of a pipe that's written to. Dropping it closes it.
+pub struct WriteEnd(Arc<Pipe>);
+
+/// Creates a pipe, returning its two ends.
+pub fn new() -> (ReadEnd, WriteEnd) {
+    let pipe = Arc::new(Pipe {
+        state: SpinLock::new(State {
+            buf: VecDeque::new(),
+            reader_open: true,
+            writer_open: true,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (ReadEnd(pipe.clone()), WriteEnd(pipe))
+}
+
+impl File for ReadEnd {
+    /// Returns 0 once the pipe is empty and the write end is closed.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.0;
+        let mut len = 0;
+        pipe.readable.wait_until(|| {
+            let mut state = pipe.state.lock();
+            if state.buf.is_empty() {
+                return !state.writer_open;
+            }
+            len = buf.len().min(state.buf.len());
+            for (dst, src) in buf.iter_mut().zip(state.buf.drain(..len)) {
+                *dst = src;
+            }
+            true
+        });
+        if len > 0 {
+            pipe.writable.wake_all();
+        }
+        Ok(len)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    fn ioctl(&self, request: usize, arg: usize) -> Result<usize, Errno> {
+        match request {
+            FIONREAD => {
+                let waiting = self.waiting() as i32;
+                write_user_struct(arg, &waiting)?;
+                Ok(0)
+            }
+            _ => Err(Errno::ENOTTY),
+        }
+    }
+}
+
+impl ReadEnd {
+    /// How many bytes are waiting to be read.
+    fn waiting(&self) -> usize {
+        self.0.state.lock().buf.len()
+    }
+}
+
+impl Drop for ReadEnd {
+    fn drop(&mut self) {
+        self.0.state.lock().reader_open = false;
+        self.0.writable.wake_all();
+    }
+}
+
+impl File for WriteEnd {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    /// Blocks until all of `buf` is written. Fails with `EPIPE` if the read
+    /// end is closed before any of it is.
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let pipe = &self.0;
+        let mut written = 0;
+        while written < buf.len() {
+            let mut closed = false;
+            pipe.writable.wait_until(|| {
+                let mut state = pipe.state.lock();
+                if !state.reader_open {
+                    closed = true;
+                    return true;
+                }
+                let space = CAPACITY - state.buf.len();
+                let n = match buf.len() {
+                    len if len <= PIPE_BUF && space < len => 0,
+                    _ => space.min(buf.len() - written),
+                };
+                state.buf.extend(&buf[written..written + n]);
+                written += n;
+                n > 0
+            });
+            if closed {
+                break;
+            }
+            pipe.readable.wake_all();
+        }
+        if written == 0 && !buf.is_empty() {
+            return Err(Errno::EPIPE);
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for WriteEnd {
+    fn drop(&mut self) {
+        self.0.state.lock().writer_open = false;
+        self.0.readable.wake_all();
+    }
+}
+
+ktest! {
+    fn pipe_reads_to_eof_and_writes_fail_once_closed() {
+        let (read_end, write_end) = new();
+        assert_eq!(write_end.write(b"hello"), Ok(5));
+        let mut buf = [0; 8];
+        assert_eq!(read_end.read(&mut buf[..3]), Ok(3));
+        assert_eq!(read_end.waiting(), 2);
+        drop(write_end);
+        assert_eq!(read_end.read(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(read_end.read(&mut buf), Ok(0));
+
+        let (read_end, write_end) = new();
+        drop(read_end);
+        assert_eq!(write_end.write(b"x"), Err(Errno::EPIPE));
+    }
+}
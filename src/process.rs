@@ -0,0 +1,829 @@
+//! Processes: an isolated user address space, the files it has open, and
+//! the threads running in it. Processes form a tree; a process's parent is
+//! the one that created it, if that's still running.
+//!
+//! A process that has exited stays in its parent's children as a zombie,
+//! holding its exit status, until the parent waits for it. Children of an
+//! exiting process are orphaned: they lose their parent, and zombies among
+//! them are freed.
+//!
+//! Processes are also grouped for job control: each is in a process group,
+//! which signals can be sent to as a whole, and each group in a session,
+//! which can have the console as its controlling terminal (see `tty`). A
+//! child starts in its parent's group and session.
+//!
+//! Each process has resource limits (see `rlimit`), which its children
+//! inherit.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicI16, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::arch::csr::{Bits, Counteren};
+use crate::arch::fpu::ExtState;
+use crate::arch::AtomicU64;
+use crate::file::{self, File};
+use crate::mm::addr_space::AddressSpace;
+use crate::ptrace::Trace;
+use crate::rlimit::{self, Limit, Limits, RLIMIT_AS, RLIMIT_CPU, RLIMIT_NOFILE, RLIMIT_RTPRIO};
+use crate::signal::{Signals, SIGCHLD, SIGCONT, SIGKILL, SIGTRAP, SIGXCPU};
+use crate::sync::{SpinLock, SpinLockGuard, WaitQueue};
+use crate::syscall::Errno;
+use crate::task::{self, TaskId};
+use crate::time::{Instant, Timer};
+use crate::trap::TrapFrame;
+use crate::{fs, ktest, log_info, oom, ptrace, tty, user};
+
+/// File descriptors past this aren't handed out, whatever `RLIMIT_NOFILE`
+/// says.
+const MAX_FILES: usize = 64;
+
+/// Set in a `wait4` status if the process dumped core.
+const WCOREFLAG: u32 = 0x80;
+
+/// The `wait4` status of a process that's been continued.
+const WAIT_CONTINUED: u32 = 0xffff;
+
+/// PIDs go up to this, then start again from 2, skipping those in use. 1 is
+/// only ever init's.
+const PID_MAX: usize = 32768;
+
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+/// Every process that hasn't been freed.
+static PROCESSES: SpinLock<BTreeMap<Pid, Weak<Process>>> = SpinLock::new(BTreeMap::new());
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Pid(usize);
+
+impl Pid {
+    pub fn from_raw(pid: usize) -> Self {
+        Self(pid)
+    }
+
+    pub fn as_raw(self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// How a process ended.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExitStatus {
+    /// It called `exit` with this code.
+    Exited(u8),
+    /// It was killed by this signal.
+    Killed(u8),
+    /// It was killed by this signal, and its core dumped.
+    Dumped(u8),
+}
+
+impl ExitStatus {
+    /// The status as encoded for `wait4`.
+    pub fn wait_status(self) -> u32 {
+        match self {
+            Self::Exited(code) => u32::from(code) << 8,
+            Self::Killed(signal) => u32::from(signal),
+            Self::Dumped(signal) => u32::from(signal) | WCOREFLAG,
+        }
+    }
+}
+
+/// What `wait` found a child had done.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WaitStatus {
+    Exited(ExitStatus),
+    /// It stopped for its tracer, or for job control, with this signal.
+    Stopped(u8),
+    /// It was continued by `SIGCONT` after stopping for job control.
+    Continued,
+}
+
+impl WaitStatus {
+    /// The status as encoded for `wait4`.
+    pub fn wait_status(self) -> u32 {
+        match self {
+            Self::Exited(status) => status.wait_status(),
+            Self::Stopped(signal) => u32::from(signal) << 8 | 0x7f,
+            Self::Continued => WAIT_CONTINUED,
+        }
+    }
+}
+
+/// Which children `wait` waits for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WaitTarget {
+    Any,
+    Pid(Pid),
+    /// Any in this process group.
+    Group(Pid),
+}
+
+/// What `wait` waits for, besides a child exiting or stopping for its
+/// tracer, and whether it blocks.
+#[derive(Clone, Copy, Debug)]
+pub struct WaitOptions {
+    pub block: bool,
+    /// A child stopping for job control.
+    pub stopped: bool,
+    /// A child being continued.
+    pub continued: bool,
+}
+
+/// Where a process is in being stopped and continued for job control.
+struct Job {
+    /// The signal that stopped the process, while it's stopped.
+    stopped: Option<u8>,
+    /// The last change, if `wait` hasn't reported it yet: `Stopped` or
+    /// `Continued`.
+    unreported: Option<WaitStatus>,
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "exited with status {}", code),
+            Self::Killed(signal) => write!(f, "killed by signal {}", signal),
+            Self::Dumped(signal) => write!(f, "killed by signal {} (core dumped)", signal),
+        }
+    }
+}
+
+/// A process's open files, indexed by file descriptor.
+#[derive(Clone)]
+pub struct FdTable {
+    files: Vec<Option<Arc<dyn File>>>,
+    /// File descriptors from this on aren't handed out: the soft
+    /// `RLIMIT_NOFILE`.
+    limit: usize,
+}
+
+impl FdTable {
+    /// A table with the console open as standard input, output and error,
+    /// through `/dev/console` if it's there.
+    fn with_console() -> Self {
+        let console = fs::open("/dev/console", fs::O_RDWR)
+            .unwrap_or_else(|_| Arc::new(file::Console) as Arc<dyn File>);
+        Self {
+            files: alloc::vec![Some(console.clone()), Some(console.clone()), Some(console)],
+            limit: MAX_FILES,
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<Arc<dyn File>> {
+        self.files.get(fd)?.clone()
+    }
+
+    /// Opens `file` at the lowest free descriptor, returning it, or `None`
+    /// if they're all in use.
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Option<usize> {
+        let free = self.files.iter().position(Option::is_none);
+        if let Some(fd) = free.filter(|&fd| fd < self.limit) {
+            self.files[fd] = Some(file);
+            return Some(fd);
+        }
+        if free.is_some() || self.files.len() >= self.limit {
+            return None;
+        }
+        self.files.push(Some(file));
+        Some(self.files.len() - 1)
+    }
+
+    /// Opens `file` at `fd`, returning the file it replaced if any. Returns
+    /// `Err` if `fd` is out of range.
+    pub fn insert_at(
+        &mut self,
+        fd: usize,
+        file: Arc<dyn File>,
+    ) -> Result<Option<Arc<dyn File>>, ()> {
+        if fd >= self.limit {
+            return Err(());
+        }
+        if fd >= self.files.len() {
+            self.files.resize(fd + 1, None);
+        }
+        Ok(self.files[fd].replace(file))
+    }
+
+    /// Stops file descriptors from `limit` on being handed out. Those
+    /// already open stay open.
+    fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.min(MAX_FILES);
+    }
+
+    /// Closes `fd`, returning the file it referred to.
+    pub fn close(&mut self, fd: usize) -> Option<Arc<dyn File>> {
+        self.files.get_mut(fd)?.take()
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    /// The process group, and the session it's in.
+    pgid: AtomicUsize,
+    sid: AtomicUsize,
+    /// Replaced by `exec`.
+    address_space: SpinLock<Arc<AddressSpace>>,
+    files: SpinLock<FdTable>,
+    parent: SpinLock<Weak<Process>>,
+    children: SpinLock<Vec<Arc<Process>>>,
+    /// Woken when a child exits, or stops for its tracer.
+    child_exited: WaitQueue,
+    /// The tasks running in the process. It exits once they all have.
+    threads: SpinLock<Vec<TaskId>>,
+    /// Set once the process has exited.
+    status: SpinLock<Option<ExitStatus>>,
+    signals: SpinLock<Signals>,
+    /// Woken when a signal is sent, to interrupt sleeps, and when the
+    /// tracer resumes the process.
+    signalled: WaitQueue,
+    trace: SpinLock<Trace>,
+    job: SpinLock<Job>,
+    /// The `scounteren` bits of the counters the process can read, which
+    /// children inherit.
+    counter_access: AtomicUsize,
+    /// Added to the process's badness when the OOM killer looks for a
+    /// victim, which children inherit.
+    oom_score_adj: AtomicI16,
+    limits: SpinLock<Limits>,
+    /// The soft and hard `RLIMIT_CPU`, copied from `limits` to be checked
+    /// after every trap without taking its lock.
+    cpu_soft_limit: AtomicU64,
+    cpu_hard_limit: AtomicU64,
+    /// The CPU time the process's threads have taken, in nanoseconds.
+    cpu_time: AtomicU64,
+    /// The CPU time in seconds when `SIGXCPU` was last sent, or 0.
+    xcpu_sent: AtomicU64,
+}
+
+impl Process {
+    /// Creates a process with an empty address space and no threads, as a
+    /// child of `parent` if given. Returns `None` if there's no memory for
+    /// its page table or no free PID.
+    pub fn new(parent: Option<&Arc<Process>>) -> Option<Arc<Self>> {
+        let address_space = Arc::new(AddressSpace::new()?);
+        Self::create(
+            parent,
+            address_space,
+            FdTable::with_console(),
+            Signals::new(),
+        )
+    }
+
+    fn create(
+        parent: Option<&Arc<Process>>,
+        address_space: Arc<AddressSpace>,
+        mut files: FdTable,
+        signals: Signals,
+    ) -> Option<Arc<Self>> {
+        let pid = alloc_pid()?;
+        let (pgid, sid) = parent.map_or((pid, pid), |parent| (parent.pgid(), parent.sid()));
+        let limits = parent.map_or_else(
+            || Limits::new(MAX_FILES),
+            |parent| parent.limits.lock().clone(),
+        );
+        files.set_limit(rlimit::to_usize(limits.soft(RLIMIT_NOFILE)));
+        address_space.set_size_limit(rlimit::to_usize(limits.soft(RLIMIT_AS)));
+        let cpu_limit = limits.get(RLIMIT_CPU).unwrap();
+        let process = Arc::new(Self {
+            pid,
+            pgid: AtomicUsize::new(pgid.0),
+            sid: AtomicUsize::new(sid.0),
+            address_space: SpinLock::new(address_space),
+            files: SpinLock::new(files),
+            parent: SpinLock::new(parent.map_or_else(Weak::new, Arc::downgrade)),
+            children: SpinLock::new(Vec::new()),
+            child_exited: WaitQueue::new(),
+            threads: SpinLock::new(Vec::new()),
+            status: SpinLock::new(None),
+            signals: SpinLock::new(signals),
+            signalled: WaitQueue::new(),
+            trace: SpinLock::new(Trace::new()),
+            job: SpinLock::new(Job {
+                stopped: None,
+                unreported: None,
+            }),
+            counter_access: AtomicUsize::new(
+                parent
+                    .map_or(Counteren::TM, |parent| parent.counter_access())
+                    .bits(),
+            ),
+            oom_score_adj: AtomicI16::new(parent.map_or(0, |parent| parent.oom_score_adj())),
+            limits: SpinLock::new(limits),
+            cpu_soft_limit: AtomicU64::new(cpu_limit.soft),
+            cpu_hard_limit: AtomicU64::new(cpu_limit.hard),
+            cpu_time: AtomicU64::new(0),
+            xcpu_sent: AtomicU64::new(0),
+        });
+        if let Some(parent) = parent {
+            parent.children.lock().push(process.clone());
+        }
+        PROCESSES
+            .lock()
+            .insert(process.pid, Arc::downgrade(&process));
+        Some(process)
+    }
+
+    /// Creates a child of this process with a copy of its address space and
+    /// the same open files and signal actions, but no threads or pending
+    /// signals. Returns `None` if there isn't enough memory or no free PID.
+    pub fn fork(self: &Arc<Self>) -> Option<Arc<Self>> {
+        let address_space = Arc::new(self.address_space().fork()?);
+        let files = self.files.lock().clone();
+        let signals = self.signals.lock().forked();
+        Self::create(Some(self), address_space, files, signals)
+    }
+
+    /// Switches the process, which must be running only the current thread,
+    /// to `space`, for running a new program.
+    pub fn exec(&self, space: Arc<AddressSpace>) {
+        task::set_address_space(space.clone());
+        *self.address_space.lock() = space;
+        self.signals.lock().reset_handlers();
+        // A traced process stops once it's running the new program.
+        if self.trace.lock().is_traced() {
+            self.send_signal(SIGTRAP);
+        }
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn pgid(&self) -> Pid {
+        Pid(self.pgid.load(Ordering::Relaxed))
+    }
+
+    pub fn sid(&self) -> Pid {
+        Pid(self.sid.load(Ordering::Relaxed))
+    }
+
+    /// Moves the process to the group `pgid`, in its session.
+    pub fn set_pgid(&self, pgid: Pid) {
+        self.pgid.store(pgid.0, Ordering::Relaxed);
+    }
+
+    /// Makes the process the leader of a new session, in a new group, both
+    /// with its PID, and without a controlling terminal.
+    pub fn start_session(&self) {
+        self.sid.store(self.pid.0, Ordering::Relaxed);
+        self.pgid.store(self.pid.0, Ordering::Relaxed);
+    }
+
+    /// Whether the process leads its session.
+    pub fn is_session_leader(&self) -> bool {
+        self.sid() == self.pid
+    }
+
+    pub fn address_space(&self) -> Arc<AddressSpace> {
+        self.address_space.lock().clone()
+    }
+
+    /// The file open at `fd`, if any.
+    pub fn file(&self, fd: usize) -> Option<Arc<dyn File>> {
+        self.files.lock().get(fd)
+    }
+
+    pub fn files(&self) -> SpinLockGuard<'_, FdTable> {
+        self.files.lock()
+    }
+
+    /// The parent process, or `None` if it has exited or there never was
+    /// one.
+    pub fn parent(&self) -> Option<Arc<Process>> {
+        self.parent.lock().upgrade()
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.threads.lock().len()
+    }
+
+    pub fn signals(&self) -> SpinLockGuard<'_, Signals> {
+        self.signals.lock()
+    }
+
+    pub fn trace(&self) -> SpinLockGuard<'_, Trace> {
+        self.trace.lock()
+    }
+
+    /// Sends the process `signal`, which it handles when it next returns to
+    /// U-mode, waking it if it's sleeping. `SIGCONT` continues it if it's
+    /// stopped, even if the signal is ignored or blocked.
+    pub fn send_signal(&self, signal: u8) {
+        self.signals.lock().raise(signal);
+        if signal == SIGCONT {
+            let mut job = self.job.lock();
+            if job.stopped.take().is_some() {
+                job.unreported = Some(WaitStatus::Continued);
+                drop(job);
+                self.notify_parent();
+            }
+        }
+        self.signalled.wake_all();
+    }
+
+    /// Stops the process for job control, as `signal`'s default action,
+    /// blocking the calling thread, one of the process's, until it's
+    /// continued by `SIGCONT` or sent `SIGKILL`.
+    pub fn job_stop(&self, signal: u8) {
+        {
+            let mut job = self.job.lock();
+            job.stopped = Some(signal);
+            job.unreported = Some(WaitStatus::Stopped(signal));
+        }
+        self.notify_parent();
+        self.wait_until_signalled(|| {
+            self.job.lock().stopped.is_none() || self.signals.lock().is_pending(SIGKILL)
+        });
+        self.job.lock().stopped = None;
+    }
+
+    /// Blocks the calling thread, one of the process's, until `cond`
+    /// returns true, checking it again whenever a signal is sent or the
+    /// tracer resumes the process.
+    pub fn wait_until_signalled(&self, cond: impl FnMut() -> bool) {
+        self.signalled.wait_until(cond);
+    }
+
+    /// Wakes the process's threads in `wait_until_signalled`.
+    pub fn wake_signalled(&self) {
+        self.signalled.wake_all();
+    }
+
+    /// Tells the parent that the process has exited or stopped.
+    pub fn notify_parent(&self) {
+        if let Some(parent) = self.parent() {
+            parent.send_signal(SIGCHLD);
+            parent.child_exited.wake_all();
+        }
+    }
+
+    /// Blocks the calling thread, one of the process's, until `deadline` or
+    /// until a signal it would be delivered is pending. Returns whether it
+    /// slept until the deadline.
+    pub fn sleep_until(self: &Arc<Self>, deadline: Instant) -> bool {
+        let expired = Arc::new(AtomicBool::new(false));
+        let _timer = Timer::start(deadline, {
+            let expired = expired.clone();
+            let process = self.clone();
+            move || {
+                expired.store(true, Ordering::Release);
+                process.wake_signalled();
+            }
+        });
+        self.wait_until_signalled(|| {
+            expired.load(Ordering::Acquire) || self.signals.lock().has_deliverable()
+        });
+        expired.load(Ordering::Acquire)
+    }
+
+    /// The counters the process's threads can read from U-mode: `time`
+    /// unless it's been revoked, and others once allowed.
+    pub fn counter_access(&self) -> Counteren {
+        Counteren::from_bits(self.counter_access.load(Ordering::Relaxed))
+    }
+
+    /// Lets the process read `counters`, as well as those it could, taking
+    /// effect when one of its threads is next switched to.
+    pub fn allow_counters(&self, counters: Counteren) {
+        self.counter_access
+            .fetch_or(counters.bits(), Ordering::Relaxed);
+    }
+
+    /// Stops the process from reading `counters`, as `allow_counters`.
+    pub fn deny_counters(&self, counters: Counteren) {
+        self.counter_access
+            .fetch_and(!counters.bits(), Ordering::Relaxed);
+    }
+
+    /// The highest real-time priority the process's threads may take, or 0
+    /// if they may not be real-time at all: `RLIMIT_RTPRIO`.
+    pub fn rt_ceiling(&self) -> u8 {
+        self.soft_limit(RLIMIT_RTPRIO)
+            .min(task::RT_PRIORITY_MAX.into()) as u8
+    }
+
+    /// How much more or less likely the OOM killer is to pick the process,
+    /// from `oom::SCORE_ADJ_MIN`, for never, to `oom::SCORE_ADJ_MAX`.
+    pub fn oom_score_adj(&self) -> i16 {
+        self.oom_score_adj.load(Ordering::Relaxed)
+    }
+
+    pub fn set_oom_score_adj(&self, adj: i16) -> Result<(), Errno> {
+        if !(oom::SCORE_ADJ_MIN..=oom::SCORE_ADJ_MAX).contains(&adj) {
+            return Err(Errno::EINVAL);
+        }
+        self.oom_score_adj.store(adj, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The limit on `resource`, or `EINVAL` if there's no such resource.
+    pub fn limit(&self, resource: usize) -> Result<Limit, Errno> {
+        self.limits.lock().get(resource)
+    }
+
+    /// The soft limit on `resource`, which must be one that exists.
+    pub fn soft_limit(&self, resource: usize) -> u64 {
+        self.limits.lock().soft(resource)
+    }
+
+    /// Sets the limit on `resource`, applying it to what's already there
+    /// for the address space and open files.
+    pub fn set_limit(&self, resource: usize, limit: Limit) -> Result<(), Errno> {
+        {
+            let mut limits = self.limits.lock();
+            limits.set(resource, limit)?;
+            if resource == RLIMIT_CPU {
+                self.cpu_soft_limit.store(limit.soft, Ordering::Relaxed);
+                self.cpu_hard_limit.store(limit.hard, Ordering::Relaxed);
+            }
+        }
+        match resource {
+            RLIMIT_NOFILE => self.files().set_limit(rlimit::to_usize(limit.soft)),
+            RLIMIT_AS => self
+                .address_space()
+                .set_size_limit(rlimit::to_usize(limit.soft)),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Counts `ran` as CPU time of the process.
+    pub fn charge_cpu(&self, ran: Duration) {
+        self.cpu_time
+            .fetch_add(ran.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Sends `SIGXCPU` if the process has taken more CPU time than its soft
+    /// `RLIMIT_CPU`, at most once a second, or kills it if more than the
+    /// hard limit.
+    pub fn check_cpu_limit(&self) {
+        let secs = self.cpu_time.load(Ordering::Relaxed) / 1_000_000_000;
+        if secs >= self.cpu_hard_limit.load(Ordering::Relaxed) {
+            self.send_signal(SIGKILL);
+        } else if secs >= self.cpu_soft_limit.load(Ordering::Relaxed)
+            && secs > self.xcpu_sent.load(Ordering::Relaxed)
+        {
+            self.xcpu_sent.store(secs, Ordering::Relaxed);
+            self.send_signal(SIGXCPU);
+        }
+    }
+
+    /// How the process ended, or `None` if it's still running.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        *self.status.lock()
+    }
+
+    /// Starts a thread, entering U-mode with the registers in
+    /// `frame`, and the floating-point and vector registers in `ext_state`
+    /// if given. Returns `None` if there's no memory for it.
+    ///
+    /// SAFETY: the process's address space must map the code and stack.
+    pub unsafe fn spawn_thread(
+        self: &Arc<Self>,
+        frame: TrapFrame,
+        ext_state: Option<Box<ExtState>>,
+    ) -> Option<TaskId> {
+        // Held until the thread is listed, so it can't exit before then.
+        let mut threads = self.threads.lock();
+        let entry = move || {
+            if ext_state.is_some() {
+                task::set_ext_state(ext_state);
+            }
+            // SAFETY: the thread runs in our address space, which maps them.
+            unsafe { user::enter(frame) }
+        };
+        let id = task::spawn_thread(self.clone(), entry)?;
+        threads.push(id);
+        Some(id)
+    }
+
+    /// Drops `child`, which never ran, from this process's children, so
+    /// that it isn't waited for.
+    pub fn forget_child(&self, child: &Arc<Process>) {
+        self.children
+            .lock()
+            .retain(|other| !Arc::ptr_eq(other, child));
+    }
+
+    /// Waits for one of the children `target` picks to exit, and frees it,
+    /// or for a traced child to stop, or for what else `options` asks for.
+    /// Returns `None` if `options.block` is false and none has yet.
+    pub fn wait(
+        &self,
+        target: WaitTarget,
+        options: WaitOptions,
+    ) -> Result<Option<(Pid, WaitStatus)>, Errno> {
+        let mut result = Ok(None);
+        let mut reaped = None;
+        self.child_exited.wait_until(|| {
+            let mut children = self.children.lock();
+            let matching = |child: &Arc<Process>| match target {
+                WaitTarget::Any => true,
+                WaitTarget::Pid(pid) => child.pid == pid,
+                WaitTarget::Group(pgid) => child.pgid() == pgid,
+            };
+            if !children.iter().any(matching) {
+                result = Err(Errno::ECHILD);
+                return true;
+            }
+            let exited = children
+                .iter()
+                .enumerate()
+                .filter(|(_, child)| matching(child))
+                .find_map(|(i, child)| Some((i, child.exit_status()?)));
+            if let Some((i, status)) = exited {
+                let child = children.swap_remove(i);
+                result = Ok(Some((child.pid, WaitStatus::Exited(status))));
+                reaped = Some(child);
+                return true;
+            }
+            // Each stop is reported once.
+            let stopped = children
+                .iter()
+                .filter(|child| matching(child))
+                .find_map(|child| Some((child.pid, child.trace.lock().report_stop()?)));
+            if let Some((pid, signal)) = stopped {
+                result = Ok(Some((pid, WaitStatus::Stopped(signal))));
+                return true;
+            }
+            let changed = children
+                .iter()
+                .filter(|child| matching(child))
+                .find_map(|child| {
+                    let mut job = child.job.lock();
+                    let status = job.unreported.filter(|status| match status {
+                        WaitStatus::Stopped(_) => options.stopped,
+                        WaitStatus::Continued => options.continued,
+                        WaitStatus::Exited(_) => false,
+                    })?;
+                    job.unreported = None;
+                    Some((child.pid, status))
+                });
+            if let Some((pid, status)) = changed {
+                result = Ok(Some((pid, status)));
+                return true;
+            }
+            !options.block
+        });
+        // The zombie is freed here, outside the wait queue's lock.
+        drop(reaped);
+        result
+    }
+
+    /// Called once the last thread has exited. Frees its memory, rather
+    /// than leaving it until the zombie is reaped, records how the process
+    /// ended for its parent, and orphans its children.
+    fn exit(&self, status: ExitStatus) {
+        log_info!("process {} {}", self.pid, status);
+        self.address_space().clear();
+        if self.is_session_leader() {
+            tty::session_ended(self.sid());
+        }
+        *self.status.lock() = Some(status);
+        self.notify_parent();
+        let children = core::mem::take(&mut *self.children.lock());
+        for child in &children {
+            *child.parent.lock() = Weak::new();
+            ptrace::tracer_exited(child);
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        PROCESSES.lock().remove(&self.pid);
+    }
+}
+
+/// Reserves the next free PID, which must then be given a process. A PID
+/// is in use until its process is freed, and while it's the ID of a process
+/// group or session that still has members.
+fn alloc_pid() -> Option<Pid> {
+    for _ in 0..PID_MAX {
+        let pid = {
+            let mut processes = PROCESSES.lock();
+            // Only PIDs from 2 are handed out again, so if those are all
+            // taken there's none left even if PID 1 is free.
+            let init = processes.contains_key(&Pid(1));
+            if processes.len() - usize::from(init) >= PID_MAX - 1 {
+                return None;
+            }
+            let mut pid = NEXT_PID.load(Ordering::Relaxed);
+            while processes.contains_key(&Pid(pid)) {
+                pid = if pid >= PID_MAX { 2 } else { pid + 1 };
+            }
+            NEXT_PID.store(if pid >= PID_MAX { 2 } else { pid + 1 }, Ordering::Relaxed);
+            // Reserved until `create` puts the process there.
+            processes.insert(Pid(pid), Weak::new());
+            Pid(pid)
+        };
+        // Not under the lock, since dropping the last reference to a
+        // process takes it.
+        let grouped = all()
+            .iter()
+            .any(|process| process.pgid() == pid || process.sid() == pid);
+        if !grouped {
+            return Some(pid);
+        }
+        PROCESSES.lock().remove(&pid);
+    }
+    None
+}
+
+/// The process `pid`, if it hasn't been freed.
+pub fn find(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid)?.upgrade()
+}
+
+/// Every process that hasn't been freed, in order of PID.
+pub fn all() -> Vec<Arc<Process>> {
+    PROCESSES
+        .lock()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+/// How many threads user processes are running, for `RLIMIT_NPROC`.
+pub fn thread_total() -> usize {
+    all().iter().map(|process| process.thread_count()).sum()
+}
+
+/// The processes in the group `pgid` that haven't exited.
+pub fn group(pgid: Pid) -> Vec<Arc<Process>> {
+    all()
+        .into_iter()
+        .filter(|process| process.pgid() == pgid && process.exit_status().is_none())
+        .collect()
+}
+
+/// Ends the running task, and with it its process with `status` if it was
+/// the last thread.
+pub fn exit_thread(status: ExitStatus) -> ! {
+    // Scoped since `task::exit` doesn't return to drop the process.
+    if let Some(process) = task::process() {
+        let current = task::current();
+        let mut threads = process.threads.lock();
+        threads.retain(|&id| id != current);
+        let last = threads.is_empty();
+        drop(threads);
+        if last {
+            process.exit(status);
+        }
+    }
+    task::exit();
+}
+
+ktest! {
+    fn reuses_pids() {
+        NEXT_PID.store(PID_MAX, Ordering::Relaxed);
+        let last = Process::new(None).unwrap();
+        let wrapped = Process::new(None).unwrap();
+        assert_eq!(last.pid(), Pid(PID_MAX));
+        assert!(wrapped.pid() >= Pid(2) && wrapped.pid() < last.pid());
+        assert_eq!(find(wrapped.pid()).map(|p| p.pid()), Some(wrapped.pid()));
+
+        // A freed process's PID is free again, unless its group lives on.
+        let pid = wrapped.pid();
+        let member = wrapped.fork().unwrap();
+        drop(wrapped);
+        assert!(find(pid).is_none());
+        NEXT_PID.store(pid.0, Ordering::Relaxed);
+        let next = Process::new(None).unwrap();
+        assert_ne!(next.pid(), pid);
+        drop((member, next));
+        NEXT_PID.store(pid.0, Ordering::Relaxed);
+        assert_eq!(Process::new(None).unwrap().pid(), pid);
+    }
+}
+
+ktest! {
+    fn runs_out_of_pids() {
+        let reserved: Vec<_> = {
+            let mut processes = PROCESSES.lock();
+            let free: Vec<_> = (2..=PID_MAX)
+                .map(Pid)
+                .filter(|pid| !processes.contains_key(pid))
+                .collect();
+            for &pid in &free {
+                processes.insert(pid, Weak::new());
+            }
+            free
+        };
+        assert!(alloc_pid().is_none());
+        let mut processes = PROCESSES.lock();
+        for pid in reserved {
+            processes.remove(&pid);
+        }
+    }
+}
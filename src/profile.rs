@@ -0,0 +1,117 @@
+//! A sampling profiler. While it runs, every timer tick on every hart
+//! records the `pc` it interrupted, and the shell's `profile` shows which
+//! addresses came up most, with the functions they're in if the kernel's
+//! symbols are loaded (see `backtrace`), or to be looked up against the
+//! kernel image with `addr2line` if not. Ticks are `sbi::timer::TICK_HZ`
+//! apart, so a profile needs a while to say much.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::csr::{Bits, Sstatus};
+use crate::ktest;
+use crate::sync::SpinLockIrqSave;
+use crate::trap::TrapFrame;
+
+/// How many samples are kept if `start` isn't told.
+pub const DEFAULT_SAMPLES: usize = 16 * 1024;
+
+struct Samples {
+    /// The kernel `pc`s sampled, up to the buffer's capacity.
+    pcs: Vec<usize>,
+    /// Samples in U-mode, which aren't recorded.
+    user: usize,
+    /// Samples that didn't fit.
+    dropped: usize,
+}
+
+static SAMPLES: SpinLockIrqSave<Samples> = SpinLockIrqSave::new(Samples {
+    pcs: Vec::new(),
+    user: 0,
+    dropped: 0,
+});
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Starts profiling from scratch, keeping up to `capacity` samples.
+pub fn start(capacity: usize) {
+    let pcs = Vec::with_capacity(capacity);
+    let old = core::mem::replace(
+        &mut *SAMPLES.lock(),
+        Samples {
+            pcs,
+            user: 0,
+            dropped: 0,
+        },
+    );
+    RUNNING.store(true, Ordering::Relaxed);
+    drop(old);
+}
+
+/// Stops profiling, keeping the samples.
+pub fn stop() {
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Called by the trap handler on every timer interrupt.
+pub fn sample(frame: &TrapFrame) {
+    if !RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut samples = SAMPLES.lock();
+    if !Sstatus::from_bits(frame.sstatus).contains(Sstatus::SPP) {
+        samples.user += 1;
+    } else if samples.pcs.len() < samples.pcs.capacity() {
+        samples.pcs.push(frame.sepc);
+    } else {
+        samples.dropped += 1;
+    }
+}
+
+pub struct Report {
+    /// Each kernel `pc` sampled and how many times, most often first.
+    pub hot: Vec<(usize, usize)>,
+    pub kernel: usize,
+    pub user: usize,
+    pub dropped: usize,
+}
+
+/// What's been sampled so far.
+pub fn report() -> Report {
+    let (pcs, user, dropped) = {
+        let samples = SAMPLES.lock();
+        (samples.pcs.clone(), samples.user, samples.dropped)
+    };
+    let kernel = pcs.len();
+    Report {
+        hot: tally(pcs),
+        kernel,
+        user,
+        dropped,
+    }
+}
+
+/// Counts each of `pcs`, most often first and then by address.
+fn tally(mut pcs: Vec<usize>) -> Vec<(usize, usize)> {
+    pcs.sort_unstable();
+    let mut hot: Vec<(usize, usize)> = Vec::new();
+    for pc in pcs {
+        match hot.last_mut() {
+            Some((last, count)) if *last == pc => *count += 1,
+            _ => hot.push((pc, 1)),
+        }
+    }
+    hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    hot
+}
+
+ktest! {
+    fn tallies_samples() {
+        let hot = tally(alloc::vec![0x30, 0x10, 0x20, 0x10, 0x30, 0x10]);
+        assert_eq!(hot, [(0x10, 3), (0x30, 2), (0x20, 1)]);
+        assert!(tally(Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+//! Process tracing, as Linux's `ptrace` does it, for debuggers in user
+//! space. A process is traced by its parent, having asked for it with
+//! `PTRACE_TRACEME` or been attached to with `PTRACE_ATTACH`. Whenever a
+//! signal is about to be delivered to a traced process it stops instead,
+//! even for signals it ignores, and its tracer finds out through `wait4`.
+//! While it's stopped the tracer can read and write its registers and
+//! memory, then resume it with the same signal, another or none.
+//!
+//! Breakpoints are `ebreak`s the debugger writes into the tracee's code,
+//! which stop it with `SIGTRAP`. RISC-V can't single-step U-mode, so, as in
+//! the GDB stub, a step puts a breakpoint of the kernel's own on the next
+//! instruction, and takes it out at the next stop.
+
+use alloc::sync::Arc;
+use core::arch::asm;
+
+use crate::arch::insn::{self, C_EBREAK, EBREAK};
+use crate::arch::REGBYTES;
+use crate::mm::addr_space::AddressSpace;
+use crate::process::{self, ExitStatus, Pid, Process};
+use crate::signal::{SIGKILL, SIGSTOP};
+use crate::syscall::Errno;
+use crate::task;
+use crate::trap::TrapFrame;
+
+/// A process's registers as a debugger sees them, Linux's RISC-V
+/// `user_regs_struct`: `pc` then `x1` to `x31`.
+pub type UserRegs = [usize; 32];
+
+/// How the tracer resumes a stopped tracee. Each but `Kill` delivers the
+/// signal given, if any.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Resume {
+    Continue(Option<u8>),
+    /// Stop again after one instruction.
+    Step(Option<u8>),
+    /// Stop tracing it.
+    Detach(Option<u8>),
+    Kill,
+}
+
+/// Where a step put its breakpoint, and what it replaced.
+#[derive(Clone, Copy)]
+struct StepBreakpoint {
+    addr: usize,
+    len: usize,
+    saved: [u8; 4],
+}
+
+/// A tracee stopped for its tracer.
+struct Stop {
+    signal: u8,
+    frame: TrapFrame,
+    /// Whether `wait4` has told the tracer yet.
+    reported: bool,
+}
+
+/// A process's tracing state, kept in its `Process`.
+pub struct Trace {
+    traced: bool,
+    stop: Option<Stop>,
+    /// Set by the tracer to end a stop.
+    resume: Option<Resume>,
+    step: Option<StepBreakpoint>,
+}
+
+impl Trace {
+    pub const fn new() -> Self {
+        Self {
+            traced: false,
+            stop: None,
+            resume: None,
+            step: None,
+        }
+    }
+
+    pub fn is_traced(&self) -> bool {
+        self.traced
+    }
+
+    /// The signal the process has stopped with, the first time it's asked
+    /// for each stop, for `wait4`.
+    pub fn report_stop(&mut self) -> Option<u8> {
+        let stop = self.stop.as_mut().filter(|stop| !stop.reported)?;
+        stop.reported = true;
+        Some(stop.signal)
+    }
+
+    /// Whether the process has been told to carry on, or to stop waiting
+    /// for its tracer, which it checks while stopped.
+    pub fn is_resumed(&self) -> bool {
+        self.resume.is_some() || !self.traced
+    }
+}
+
+/// Has the running process traced by its parent, for `PTRACE_TRACEME`.
+pub fn trace_me() -> Result<(), Errno> {
+    let process = task::process().expect("ptrace from a task without a process");
+    if process.parent().is_none() {
+        return Err(Errno::EPERM);
+    }
+    start_tracing(&process)
+}
+
+/// Starts tracing `pid`, which must be a child of the running process,
+/// stopping it with `SIGSTOP`, for `PTRACE_ATTACH`.
+pub fn attach(pid: Pid) -> Result<(), Errno> {
+    let tracee = child(pid)?;
+    start_tracing(&tracee)?;
+    tracee.send_signal(SIGSTOP);
+    Ok(())
+}
+
+fn start_tracing(process: &Process) -> Result<(), Errno> {
+    let mut trace = process.trace();
+    if trace.traced {
+        return Err(Errno::EPERM);
+    }
+    trace.traced = true;
+    process.signals().set_traced(true);
+    Ok(())
+}
+
+/// The running process's child `pid`.
+fn child(pid: Pid) -> Result<Arc<Process>, Errno> {
+    let tracer = task::process().expect("ptrace from a task without a process");
+    process::find(pid)
+        .filter(|child| {
+            child
+                .parent()
+                .is_some_and(|parent| Arc::ptr_eq(&parent, &tracer))
+        })
+        .ok_or(Errno::ESRCH)
+}
+
+/// The running process's child `pid`, if it's traced and stopped, as every
+/// request but attaching needs it to be.
+fn stopped_tracee(pid: Pid) -> Result<Arc<Process>, Errno> {
+    let tracee = child(pid)?;
+    let stopped = {
+        let trace = tracee.trace();
+        trace.traced && trace.stop.is_some() && trace.resume.is_none()
+    };
+    if !stopped {
+        return Err(Errno::ESRCH);
+    }
+    Ok(tracee)
+}
+
+/// Reads the word at `addr` in the stopped tracee `pid`.
+pub fn peek(pid: Pid, addr: usize) -> Result<usize, Errno> {
+    let tracee = stopped_tracee(pid)?;
+    let mut word = [0; REGBYTES];
+    if !tracee.address_space().debug_read(addr, &mut word) {
+        return Err(Errno::EIO);
+    }
+    Ok(usize::from_le_bytes(word))
+}
+
+/// Writes `word` at `addr` in the stopped tracee `pid`, even in its code.
+pub fn poke(pid: Pid, addr: usize, word: usize) -> Result<(), Errno> {
+    let tracee = stopped_tracee(pid)?;
+    if !tracee
+        .address_space()
+        .debug_write(addr, &word.to_le_bytes())
+    {
+        return Err(Errno::EIO);
+    }
+    Ok(())
+}
+
+/// The registers of the stopped tracee `pid`.
+pub fn regs(pid: Pid) -> Result<UserRegs, Errno> {
+    let tracee = stopped_tracee(pid)?;
+    let trace = tracee.trace();
+    let frame = &trace.stop.as_ref().expect("stopped without a frame").frame;
+    let mut regs = frame.regs;
+    regs[0] = frame.sepc;
+    Ok(regs)
+}
+
+/// Sets the registers of the stopped tracee `pid`, which it resumes with.
+pub fn set_regs(pid: Pid, regs: &UserRegs) -> Result<(), Errno> {
+    let tracee = stopped_tracee(pid)?;
+    let mut trace = tracee.trace();
+    let frame = &mut trace.stop.as_mut().expect("stopped without a frame").frame;
+    frame.regs[1..].copy_from_slice(&regs[1..]);
+    frame.sepc = regs[0];
+    Ok(())
+}
+
+/// Resumes the stopped tracee `pid` as `how` says.
+pub fn resume(pid: Pid, how: Resume) -> Result<(), Errno> {
+    let tracee = stopped_tracee(pid)?;
+    tracee.trace().resume = Some(how);
+    tracee.wake_signalled();
+    Ok(())
+}
+
+/// Stops tracing `process` now that its tracer has exited, letting it
+/// carry on if it's stopped.
+pub fn tracer_exited(process: &Process) {
+    let mut trace = process.trace();
+    if !trace.traced {
+        return;
+    }
+    trace.traced = false;
+    drop(trace);
+    process.signals().set_traced(false);
+    process.wake_signalled();
+}
+
+/// Called as `signal` is about to be delivered to the running process,
+/// which is returning to U-mode with `frame`. If it's traced, it stops for
+/// its tracer until resumed, and `frame` is updated with any registers the
+/// tracer set. Returns the signal to deliver instead, if any.
+pub fn report_signal(frame: &mut TrapFrame, signal: u8) -> Option<u8> {
+    let process = task::process().expect("signal for a task without a process");
+    let space = process.address_space();
+    {
+        let mut trace = process.trace();
+        // Nothing can stop SIGKILL, and it doesn't wait for the tracer.
+        if !trace.traced || signal == SIGKILL {
+            return Some(signal);
+        }
+        if let Some(step) = trace.step.take() {
+            space.debug_write(step.addr, &step.saved[..step.len]);
+        }
+        trace.stop = Some(Stop {
+            signal,
+            frame: frame.clone(),
+            reported: false,
+        });
+        trace.resume = None;
+    }
+    process.notify_parent();
+
+    process.wait_until_signalled(|| {
+        process.trace().is_resumed() || process.signals().is_pending(SIGKILL)
+    });
+    let (stop, resume) = {
+        let mut trace = process.trace();
+        let resume = trace.resume.take();
+        (trace.stop.take().expect("resumed without a stop"), resume)
+    };
+    *frame = stop.frame;
+    // The tracer may have written to code this hart has cached.
+    // SAFETY: a fence has no other effects.
+    unsafe { asm!("fence.i") };
+
+    match resume {
+        // Killed while stopped, or the tracer went away.
+        None if process.signals().is_pending(SIGKILL) => Some(SIGKILL),
+        None => Some(signal),
+        Some(Resume::Kill) => {
+            drop(space);
+            drop(process);
+            process::exit_thread(ExitStatus::Killed(SIGKILL));
+        }
+        Some(Resume::Continue(signal)) => signal,
+        Some(Resume::Step(signal)) => {
+            let step = insert_step(&space, frame);
+            process.trace().step = step;
+            signal
+        }
+        Some(Resume::Detach(signal)) => {
+            process.trace().traced = false;
+            process.signals().set_traced(false);
+            signal
+        }
+    }
+}
+
+/// The instruction at `addr` in `space`, and its length.
+fn instruction(space: &AddressSpace, addr: usize) -> Option<(u32, usize)> {
+    let mut bytes = [0; 4];
+    if !space.debug_read(addr, &mut bytes[..2]) {
+        return None;
+    }
+    let len = insn::len(u16::from_le_bytes([bytes[0], bytes[1]]));
+    if len == 4 && !space.debug_read(addr + 2, &mut bytes[2..]) {
+        return None;
+    }
+    Some((u32::from_le_bytes(bytes), len))
+}
+
+/// Puts a breakpoint on the instruction `frame` goes on to execute after
+/// the one at its `pc`.
+fn insert_step(space: &AddressSpace, frame: &TrapFrame) -> Option<StepBreakpoint> {
+    let (inst, len) = instruction(space, frame.sepc)?;
+    let addr = insn::next_pc(&frame.regs, frame.sepc, inst, len);
+    let (_, len) = instruction(space, addr)?;
+    let mut saved = [0; 4];
+    space.debug_read(addr, &mut saved[..len]);
+    let written = match len {
+        2 => space.debug_write(addr, &C_EBREAK.to_le_bytes()),
+        _ => space.debug_write(addr, &EBREAK.to_le_bytes()),
+    };
+    // SAFETY: a fence has no other effects.
+    unsafe { asm!("fence.i") };
+    written.then_some(StepBreakpoint { addr, len, saved })
+}
@@ -0,0 +1,406 @@
+//! Pseudo-terminals: pairs of a master, which a program such as a terminal
+//! multiplexer or remote shell holds, and a slave, which programs run on
+//! as they would on a real terminal. What's written to the master is the
+//! slave's input, going through its line discipline, and what's written to
+//! the slave, and the echo of its input, is read from the master.
+//!
+//! Opening `/dev/ptmx` creates a pair and gives its master. The slave is
+//! `/dev/pts/<n>`, where `n` is what `TIOCGPTN` on the master says, until
+//! the master is closed. Then the slave hangs up: its reads return 0 and
+//! its writes fail, and its foreground group is sent `SIGHUP`. Once every
+//! opening of the slave has been closed, reads of the master fail.
+//!
+//! Slaves are unlocked from the start, so `TIOCSPTLCK` does nothing.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::file::File;
+use crate::fs::devfs;
+use crate::fs::{DirEntry, Vnode, VnodeKind};
+use crate::ktest;
+use crate::log_error;
+use crate::mm::uaccess::write_user_struct;
+use crate::process::Pid;
+use crate::sync::{SpinLock, WaitQueue};
+use crate::syscall::Errno;
+use crate::task;
+use crate::tty::{Device, Tty};
+
+/// `ioctl` requests on a master.
+const TIOCGPTN: usize = 0x8004_5430;
+const TIOCSPTLCK: usize = 0x4004_5431;
+
+/// How many bytes each direction holds before writers wait.
+const CAPACITY: usize = 4096;
+
+/// Pairs whose master is open, by index.
+static PTYS: SpinLock<BTreeMap<usize, Weak<Pty>>> = SpinLock::new(BTreeMap::new());
+
+/// Bytes going one way between the master and the slave.
+struct Channel {
+    bytes: SpinLock<VecDeque<u8>>,
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            bytes: SpinLock::new(VecDeque::new()),
+            readable: WaitQueue::new(),
+            writable: WaitQueue::new(),
+        }
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let byte = self.bytes.lock().pop_front();
+        if byte.is_some() {
+            self.writable.wake_all();
+        }
+        byte
+    }
+
+    /// Takes as many bytes as there are, up to `buf.len()`.
+    fn take(&self, buf: &mut [u8]) -> usize {
+        let mut bytes = self.bytes.lock();
+        let len = bytes.len().min(buf.len());
+        for (slot, byte) in buf.iter_mut().zip(bytes.drain(..len)) {
+            *slot = byte;
+        }
+        drop(bytes);
+        if len > 0 {
+            self.writable.wake_all();
+        }
+        len
+    }
+
+    /// Adds as many of `bytes` as there's room for, returning how many.
+    fn put(&self, bytes: &[u8]) -> usize {
+        let mut queued = self.bytes.lock();
+        let len = bytes.len().min(CAPACITY - queued.len());
+        queued.extend(&bytes[..len]);
+        drop(queued);
+        if len > 0 {
+            self.readable.wake_all();
+        }
+        len
+    }
+
+    /// Adds all of `bytes`, waiting for room, unless `closed` returns true
+    /// first, in which case it fails with `EIO`.
+    fn put_all(&self, mut bytes: &[u8], closed: impl Fn() -> bool) -> Result<usize, Errno> {
+        let total = bytes.len();
+        while !bytes.is_empty() {
+            if closed() {
+                return Err(Errno::EIO);
+            }
+            let len = self.put(bytes);
+            bytes = &bytes[len..];
+            if !bytes.is_empty() {
+                self.writable
+                    .wait_until(|| self.bytes.lock().len() < CAPACITY || closed());
+            }
+        }
+        Ok(total)
+    }
+}
+
+struct Pty {
+    index: usize,
+    tty: Tty,
+    /// Written by the master, the slave's input.
+    input: Channel,
+    /// Written by the slave, and echoed input, for the master to read.
+    output: Channel,
+    master_open: AtomicBool,
+    /// How many openings of the slave there are.
+    slaves: AtomicUsize,
+    /// Set once the slave has been opened and every opening closed.
+    slave_closed: AtomicBool,
+}
+
+impl Pty {
+    fn hung_up(&self) -> bool {
+        !self.master_open.load(Ordering::Acquire)
+    }
+
+    fn device(self: &Arc<Self>) -> SlaveDevice {
+        SlaveDevice(self.clone())
+    }
+}
+
+/// A pair's slave, as its terminal sees it.
+#[derive(Clone)]
+struct SlaveDevice(Arc<Pty>);
+
+impl Device for SlaveDevice {
+    fn read_byte(&self) -> Option<u8> {
+        self.0.input.pop()
+    }
+
+    fn wait_byte_unless(&self, stop: &mut dyn FnMut() -> bool) -> Option<u8> {
+        let pty = &self.0;
+        loop {
+            if let Some(byte) = pty.input.pop() {
+                return Some(byte);
+            }
+            if pty.hung_up() || stop() {
+                return None;
+            }
+            pty.input
+                .readable
+                .wait_until(|| !pty.input.bytes.lock().is_empty() || pty.hung_up() || stop());
+        }
+    }
+
+    fn wake_readers(&self) {
+        self.0.input.readable.wake_all();
+    }
+
+    fn write(&self, bytes: &[u8]) -> Result<usize, Errno> {
+        let pty = &self.0;
+        pty.output.put_all(bytes, || pty.hung_up())
+    }
+
+    fn echo(&self, bytes: &[u8]) {
+        self.0.output.put(bytes);
+    }
+
+    fn is_hung_up(&self) -> bool {
+        self.0.hung_up()
+    }
+}
+
+/// Creates a pair, returning its master.
+fn open_pair() -> Arc<Master> {
+    let mut ptys = PTYS.lock();
+    let index = (0..)
+        .find(|index| !ptys.contains_key(index))
+        .expect("ran out of pseudo-terminal numbers");
+    let pty = Arc::new(Pty {
+        index,
+        tty: Tty::new(),
+        input: Channel::new(),
+        output: Channel::new(),
+        master_open: AtomicBool::new(true),
+        slaves: AtomicUsize::new(0),
+        slave_closed: AtomicBool::new(false),
+    });
+    ptys.insert(index, Arc::downgrade(&pty));
+    Arc::new(Master(pty))
+}
+
+/// Every pair whose master is open.
+fn all() -> Vec<Arc<Pty>> {
+    PTYS.lock().values().filter_map(Weak::upgrade).collect()
+}
+
+/// Whether a slave is the controlling terminal of session `sid`.
+pub fn has_controlling(sid: Pid) -> bool {
+    all().iter().any(|pty| pty.tty.session() == Some(sid))
+}
+
+/// Releases the slave that's the controlling terminal of session `sid`,
+/// whose leader is exiting, if there is one.
+pub fn session_ended(sid: Pid) {
+    for pty in all() {
+        pty.tty.release(&pty.device(), sid);
+    }
+}
+
+struct Master(Arc<Pty>);
+
+impl File for Master {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let pty = &self.0;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let process = task::process();
+        let mut len = 0;
+        pty.output.readable.wait_until(|| {
+            len = pty.output.take(buf);
+            len > 0
+                || pty.slave_closed.load(Ordering::Acquire)
+                || process
+                    .as_ref()
+                    .is_some_and(|process| process.signals().has_deliverable())
+        });
+        match len {
+            0 if pty.slave_closed.load(Ordering::Acquire) => Err(Errno::EIO),
+            0 => Err(Errno::EINTR),
+            len => Ok(len),
+        }
+    }
+
+    /// Input for the slave. Characters that signal its foreground group do
+    /// so rather than being queued.
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let pty = &self.0;
+        let device = pty.device();
+        let closed = || pty.slave_closed.load(Ordering::Acquire);
+        let mut rest = buf;
+        while let Some(at) = rest
+            .iter()
+            .position(|&byte| pty.tty.signal_for(byte).is_some())
+        {
+            pty.input.put_all(&rest[..at], closed)?;
+            // Looked up again, as the settings may have changed meanwhile.
+            if let Some((pgid, signal)) = pty.tty.signal_for(rest[at]) {
+                pty.tty.interrupt(&device, rest[at], pgid, signal);
+            }
+            rest = &rest[at + 1..];
+        }
+        pty.input.put_all(rest, closed)?;
+        Ok(buf.len())
+    }
+
+    fn ioctl(&self, request: usize, arg: usize) -> Result<usize, Errno> {
+        let pty = &self.0;
+        match request {
+            TIOCGPTN => {
+                write_user_struct(arg, &(pty.index as u32))?;
+                Ok(0)
+            }
+            TIOCSPTLCK => Ok(0),
+            _ => pty.tty.ioctl(&pty.device(), request, arg),
+        }
+    }
+}
+
+impl Drop for Master {
+    fn drop(&mut self) {
+        let pty = &self.0;
+        PTYS.lock().remove(&pty.index);
+        pty.master_open.store(false, Ordering::Release);
+        pty.output.writable.wake_all();
+        pty.tty.hang_up(&pty.device());
+    }
+}
+
+/// An opening of a pair's slave.
+struct Slave(Arc<Pty>);
+
+impl Slave {
+    fn open(pty: Arc<Pty>) -> Arc<Self> {
+        pty.slaves.fetch_add(1, Ordering::Relaxed);
+        Arc::new(Self(pty))
+    }
+}
+
+impl File for Slave {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        self.0.tty.read(&self.0.device(), buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        self.0.tty.write(&self.0.device(), buf)
+    }
+
+    fn ioctl(&self, request: usize, arg: usize) -> Result<usize, Errno> {
+        self.0.tty.ioctl(&self.0.device(), request, arg)
+    }
+}
+
+impl Drop for Slave {
+    fn drop(&mut self) {
+        let pty = &self.0;
+        if pty.slaves.fetch_sub(1, Ordering::Relaxed) == 1 {
+            pty.slave_closed.store(true, Ordering::Release);
+            pty.output.readable.wake_all();
+        }
+    }
+}
+
+/// `/dev/ptmx`, each opening of which creates a pair.
+struct Ptmx;
+
+impl Vnode for Ptmx {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::Device
+    }
+
+    fn open_device(&self) -> Result<Arc<dyn File>, Errno> {
+        Ok(open_pair())
+    }
+}
+
+/// `/dev/pts`, listing the slaves.
+struct PtsDir;
+
+impl Vnode for PtsDir {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, Errno> {
+        let index: usize = name.parse().map_err(|_| Errno::ENOENT)?;
+        let pty = PTYS
+            .lock()
+            .get(&index)
+            .and_then(Weak::upgrade)
+            .ok_or(Errno::ENOENT)?;
+        Ok(Arc::new(SlaveNode(pty)))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Errno> {
+        Ok(all()
+            .iter()
+            .map(|pty| DirEntry {
+                name: format!("{}", pty.index),
+                kind: VnodeKind::Device,
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _kind: VnodeKind) -> Result<Arc<dyn Vnode>, Errno> {
+        Err(Errno::EINVAL)
+    }
+}
+
+struct SlaveNode(Arc<Pty>);
+
+impl Vnode for SlaveNode {
+    fn kind(&self) -> VnodeKind {
+        VnodeKind::Device
+    }
+
+    fn open_device(&self) -> Result<Arc<dyn File>, Errno> {
+        if self.0.hung_up() {
+            return Err(Errno::EIO);
+        }
+        Ok(Slave::open(self.0.clone()))
+    }
+}
+
+/// Adds `/dev/ptmx` and `/dev/pts`.
+pub fn init() {
+    let nodes: [(&str, Arc<dyn Vnode>); 2] = [("ptmx", Arc::new(Ptmx)), ("pts", Arc::new(PtsDir))];
+    for (name, node) in nodes {
+        if let Err(err) = devfs::register(name, node) {
+            log_error!(target: "pty", "couldn't add /dev/{}: {}", name, err);
+        }
+    }
+}
+
+ktest! {
+    fn pty_output_reaches_master() {
+        let master = open_pair();
+        let name = format!("{}", master.0.index);
+        let slave = PtsDir.lookup(&name).unwrap().open_device().unwrap();
+
+        // Output has newlines turned into CR LF.
+        assert_eq!(slave.write(b"hi\n"), Ok(3));
+        let mut buf = [0; 16];
+        assert_eq!(master.read(&mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"hi\r\n");
+
+        drop(master);
+        assert_eq!(slave.write(b"gone"), Err(Errno::EIO));
+        assert!(PtsDir.lookup(&name).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! Random numbers for the kernel, from a ChaCha20 generator. Its key comes
+//! from hardware entropy sources such as virtio-rng, which mix in what they
+//! have with `add_entropy`. Without one it's seeded from the timer, which
+//! is good enough to vary between boots but easy to guess.
+//!
+//! Every block of output also replaces the key, so what's been generated
+//! can't be worked out from the state later.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sync::SpinLockIrqSave;
+use crate::{ktest, log_info, log_warn, time};
+
+const KEY_WORDS: usize = 8;
+
+/// "expand 32-byte k".
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// The output of a ChaCha20 block for `key` at `counter`, with a zero nonce.
+fn chacha20(key: &[u32; KEY_WORDS], counter: u64) -> [u32; 16] {
+    let mut input = [0; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+
+    let mut x = input;
+    let quarter_round = |x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize| {
+        x[a] = x[a].wrapping_add(x[b]);
+        x[d] = (x[d] ^ x[a]).rotate_left(16);
+        x[c] = x[c].wrapping_add(x[d]);
+        x[b] = (x[b] ^ x[c]).rotate_left(12);
+        x[a] = x[a].wrapping_add(x[b]);
+        x[d] = (x[d] ^ x[a]).rotate_left(8);
+        x[c] = x[c].wrapping_add(x[d]);
+        x[b] = (x[b] ^ x[c]).rotate_left(7);
+    };
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 1, 5, 9, 13);
+        quarter_round(&mut x, 2, 6, 10, 14);
+        quarter_round(&mut x, 3, 7, 11, 15);
+        quarter_round(&mut x, 0, 5, 10, 15);
+        quarter_round(&mut x, 1, 6, 11, 12);
+        quarter_round(&mut x, 2, 7, 8, 13);
+        quarter_round(&mut x, 3, 4, 9, 14);
+    }
+    for (x, input) in x.iter_mut().zip(input) {
+        *x = x.wrapping_add(input);
+    }
+    x
+}
+
+struct Generator {
+    key: [u32; KEY_WORDS],
+    counter: u64,
+    /// Output not handed out yet, the last `available` bytes of `buf`.
+    buf: [u8; 32],
+    available: usize,
+    seeded: bool,
+}
+
+impl Generator {
+    /// Generates another block, half of which becomes the next key.
+    fn refill(&mut self) {
+        let block = chacha20(&self.key, self.counter);
+        self.counter = self.counter.wrapping_add(1);
+        self.key.copy_from_slice(&block[..KEY_WORDS]);
+        for (bytes, word) in self.buf.chunks_exact_mut(4).zip(&block[KEY_WORDS..]) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        self.available = self.buf.len();
+    }
+
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(KEY_WORDS * 4) {
+            for (i, &byte) in chunk.iter().enumerate() {
+                self.key[i / 4] ^= u32::from(byte) << (8 * (i % 4));
+            }
+            // Also throws away what was generated with the old key.
+            self.refill();
+        }
+    }
+
+    fn fill(&mut self, mut buf: &mut [u8]) {
+        if !self.seeded {
+            self.mix(&time::read_time().to_le_bytes());
+            self.seeded = true;
+        }
+        while !buf.is_empty() {
+            if self.available == 0 {
+                self.refill();
+            }
+            let len = buf.len().min(self.available);
+            let start = self.buf.len() - self.available;
+            buf[..len].copy_from_slice(&self.buf[start..start + len]);
+            // Nothing handed out stays behind.
+            self.buf[start..start + len].fill(0);
+            self.available -= len;
+            buf = &mut buf[len..];
+        }
+    }
+}
+
+static GENERATOR: SpinLockIrqSave<Generator> = SpinLockIrqSave::new(Generator {
+    key: [0; KEY_WORDS],
+    counter: 0,
+    buf: [0; 32],
+    available: 0,
+    seeded: false,
+});
+
+/// Set once a hardware source has mixed in entropy.
+static HARDWARE_SEEDED: AtomicBool = AtomicBool::new(false);
+
+/// Mixes `data` from a hardware entropy source into the generator.
+pub fn add_entropy(data: &[u8]) {
+    let mut generator = GENERATOR.lock();
+    generator.mix(data);
+    generator.seeded = true;
+    HARDWARE_SEEDED.store(true, Ordering::Relaxed);
+}
+
+/// Mixes in `data` that may or may not be random, e.g. what user programs
+/// write to `/dev/random`. It can only make the output harder to guess.
+pub fn add_data(data: &[u8]) {
+    GENERATOR.lock().mix(data);
+}
+
+/// Says where the generator was seeded from, once the drivers have had a
+/// chance to add entropy.
+pub fn init() {
+    if HARDWARE_SEEDED.load(Ordering::Relaxed) {
+        log_info!(target: "rand", "seeded from hardware");
+    } else {
+        log_warn!(target: "rand", "no entropy source, seeded from the timer");
+    }
+}
+
+pub fn fill_bytes(buf: &mut [u8]) {
+    GENERATOR.lock().fill(buf);
+}
+
+pub fn u32() -> u32 {
+    let mut bytes = [0; 4];
+    fill_bytes(&mut bytes);
+    u32::from_le_bytes(bytes)
+}
+
+/// A number below `bound`, each as likely as the others.
+pub fn below(bound: u32) -> u32 {
+    assert!(bound > 0);
+    // Numbers below this would make the first `2^32 % bound` results more
+    // likely than the rest.
+    let threshold = bound.wrapping_neg() % bound;
+    loop {
+        let n = u32();
+        if n >= threshold {
+            return n % bound;
+        }
+    }
+}
+
+ktest! {
+    /// The first block of RFC 8439's test vector A.1, with an all-zero key.
+    fn chacha20_zero_key() {
+        let block = chacha20(&[0; KEY_WORDS], 0);
+        assert_eq!(
+            block[..4],
+            [0xade0_b876, 0x903d_f1a0, 0xe56a_5d40, 0x28bd_8653]
+        );
+    }
+}
+
+ktest! {
+    fn below_stays_in_range() {
+        let mut seen = [false; 5];
+        for _ in 0..1000 {
+            seen[below(5) as usize] = true;
+        }
+        assert!(seen.iter().all(|&seen| seen));
+        assert_eq!(below(1), 0);
+        assert!(below(u32::MAX) < u32::MAX);
+    }
+}
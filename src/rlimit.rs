@@ -0,0 +1,108 @@
+//! Per-process resource limits, as set by `setrlimit` and `prlimit64`.
+//! Each has a soft limit, which is what's enforced, and a hard limit, the
+//! most the soft limit can be raised to. Hard limits can only be lowered.
+//!
+//! Limits are inherited by children and kept across `exec`. Every Linux
+//! resource can be read and set, but only these are enforced:
+//!
+//! - `RLIMIT_AS`: the size of the address space, beyond which mappings and
+//!   `brk` fail.
+//! - `RLIMIT_NOFILE`: one more than the highest file descriptor that can
+//!   be opened, at most 64.
+//! - `RLIMIT_CPU`: the CPU time in seconds, after which the process is sent
+//!   `SIGXCPU` every second until the hard limit, when it's killed.
+//! - `RLIMIT_NPROC`: the number of user threads, counting every process's,
+//!   as they'd all belong to the same user, beyond which `fork` fails.
+//! - `RLIMIT_RTPRIO`: the highest real-time priority `sched_setscheduler`
+//!   lets a thread take.
+
+use crate::ktest;
+use crate::syscall::Errno;
+
+pub const RLIMIT_CPU: usize = 0;
+pub const RLIMIT_NPROC: usize = 6;
+pub const RLIMIT_NOFILE: usize = 7;
+pub const RLIMIT_AS: usize = 9;
+pub const RLIMIT_RTPRIO: usize = 14;
+/// How many resources there are.
+const RLIM_NLIMITS: usize = 16;
+
+/// No limit.
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Limit {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+impl Limit {
+    const INFINITE: Self = Self {
+        soft: RLIM_INFINITY,
+        hard: RLIM_INFINITY,
+    };
+}
+
+#[derive(Clone)]
+pub struct Limits([Limit; RLIM_NLIMITS]);
+
+impl Limits {
+    /// The limits of the first process: none, other than `max_files` open
+    /// files.
+    pub fn new(max_files: usize) -> Self {
+        let mut limits = [Limit::INFINITE; RLIM_NLIMITS];
+        limits[RLIMIT_NOFILE] = Limit {
+            soft: max_files as u64,
+            hard: max_files as u64,
+        };
+        Self(limits)
+    }
+
+    /// The limit on `resource`, or `EINVAL` if there's no such resource.
+    pub fn get(&self, resource: usize) -> Result<Limit, Errno> {
+        self.0.get(resource).copied().ok_or(Errno::EINVAL)
+    }
+
+    /// The soft limit on `resource`, which must be one that exists.
+    pub fn soft(&self, resource: usize) -> u64 {
+        self.0[resource].soft
+    }
+
+    /// Sets the limit on `resource`. The soft limit can't be above the hard
+    /// limit, and the hard limit can't be raised.
+    pub fn set(&mut self, resource: usize, limit: Limit) -> Result<(), Errno> {
+        let old = self.0.get_mut(resource).ok_or(Errno::EINVAL)?;
+        if limit.soft > limit.hard {
+            return Err(Errno::EINVAL);
+        }
+        if limit.hard > old.hard {
+            return Err(Errno::EPERM);
+        }
+        *old = limit;
+        Ok(())
+    }
+}
+
+/// `limit` as a count or size in the kernel, where infinite is the most
+/// there can be.
+pub fn to_usize(limit: u64) -> usize {
+    usize::try_from(limit).unwrap_or(usize::MAX)
+}
+
+ktest! {
+    fn hard_limits_only_go_down() {
+        let mut limits = Limits::new(64);
+        assert_eq!(limits.get(RLIMIT_NOFILE), Ok(Limit { soft: 64, hard: 64 }));
+        assert_eq!(limits.get(RLIM_NLIMITS), Err(Errno::EINVAL));
+
+        let limit = Limit { soft: 10, hard: 20 };
+        assert_eq!(limits.set(RLIMIT_CPU, limit), Ok(()));
+        assert_eq!(limits.soft(RLIMIT_CPU), 10);
+        assert_eq!(limits.set(RLIMIT_CPU, Limit { soft: 20, hard: 20 }), Ok(()));
+        assert_eq!(
+            limits.set(RLIMIT_CPU, Limit { soft: 30, hard: 20 }),
+            Err(Errno::EINVAL)
+        );
+        assert_eq!(limits.set(RLIMIT_CPU, Limit::INFINITE), Err(Errno::EPERM));
+    }
+}
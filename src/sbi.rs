@@ -0,0 +1,168 @@
+use core::arch::asm;
+use core::fmt;
+
+pub mod hsm;
+pub mod ipi;
+pub mod pmu;
+pub mod reset;
+pub mod rfence;
+pub mod timer;
+
+const SBI_EID_BASE: u32 = 0x10;
+
+const SBI_FID_BASE_GET_SPEC_VERSION: u32 = 0;
+const SBI_FID_BASE_GET_IMPL_ID: u32 = 1;
+const SBI_FID_BASE_GET_IMPL_VERSION: u32 = 2;
+const SBI_FID_BASE_PROBE_EXTENSION: u32 = 3;
+const SBI_FID_BASE_GET_MVENDORID: u32 = 4;
+const SBI_FID_BASE_GET_MARCHID: u32 = 5;
+const SBI_FID_BASE_GET_MIMPID: u32 = 6;
+
+/// Functions from the base extension are always present and never fail, so
+/// only the value is returned.
+fn sbi_base_call(fid: u32, arg: usize) -> usize {
+    let value: usize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a7") SBI_EID_BASE,
+            in("a6") fid,
+            inlateout("a0") arg => _,
+            lateout("a1") value,
+        );
+    }
+    value
+}
+
+/// An error returned by an SBI call.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SbiError(pub isize);
+
+impl SbiError {
+    pub const FAILED: Self = Self(-1);
+    pub const NOT_SUPPORTED: Self = Self(-2);
+    pub const INVALID_PARAM: Self = Self(-3);
+    pub const DENIED: Self = Self(-4);
+    pub const INVALID_ADDRESS: Self = Self(-5);
+    pub const ALREADY_AVAILABLE: Self = Self(-6);
+    pub const ALREADY_STARTED: Self = Self(-7);
+    pub const ALREADY_STOPPED: Self = Self(-8);
+}
+
+impl fmt::Display for SbiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Self::FAILED => "failed",
+            Self::NOT_SUPPORTED => "not supported",
+            Self::INVALID_PARAM => "invalid parameter",
+            Self::DENIED => "denied",
+            Self::INVALID_ADDRESS => "invalid address",
+            Self::ALREADY_AVAILABLE => "already available",
+            Self::ALREADY_STARTED => "already started",
+            Self::ALREADY_STOPPED => "already stopped",
+            _ => return write!(f, "error {}", self.0),
+        };
+        f.write_str(name)
+    }
+}
+
+/// Makes an SBI call with up to three arguments, returning the value on
+/// success.
+///
+/// SAFETY: the call must not violate memory safety, e.g. by having the SBI
+/// implementation write to memory we're using.
+pub unsafe fn call(eid: u32, fid: u32, args: [usize; 3]) -> Result<usize, SbiError> {
+    let error: isize;
+    let value: usize;
+    asm!(
+        "ecall",
+        in("a7") eid,
+        in("a6") fid,
+        inlateout("a0") args[0] => error,
+        inlateout("a1") args[1] => value,
+        in("a2") args[2],
+    );
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(SbiError(error))
+    }
+}
+
+/// Makes an SBI call with up to six arguments, as `call` does with three.
+///
+/// SAFETY: as for `call`.
+pub unsafe fn call6(eid: u32, fid: u32, args: [usize; 6]) -> Result<usize, SbiError> {
+    let error: isize;
+    let value: usize;
+    asm!(
+        "ecall",
+        in("a7") eid,
+        in("a6") fid,
+        inlateout("a0") args[0] => error,
+        inlateout("a1") args[1] => value,
+        in("a2") args[2],
+        in("a3") args[3],
+        in("a4") args[4],
+        in("a5") args[5],
+    );
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(SbiError(error))
+    }
+}
+
+pub fn probe_extension(eid: u32) -> bool {
+    sbi_base_call(SBI_FID_BASE_PROBE_EXTENSION, eid as usize) != 0
+}
+
+pub struct SpecVersion {
+    pub major: usize,
+    pub minor: usize,
+}
+
+pub fn spec_version() -> SpecVersion {
+    let version = sbi_base_call(SBI_FID_BASE_GET_SPEC_VERSION, 0);
+    SpecVersion {
+        major: (version >> 24) & 0x7f,
+        minor: version & 0xff_ffff,
+    }
+}
+
+pub fn impl_id() -> usize {
+    sbi_base_call(SBI_FID_BASE_GET_IMPL_ID, 0)
+}
+
+pub fn impl_version() -> usize {
+    sbi_base_call(SBI_FID_BASE_GET_IMPL_VERSION, 0)
+}
+
+pub fn impl_name(id: usize) -> &'static str {
+    match id {
+        0 => "Berkeley Boot Loader",
+        1 => "OpenSBI",
+        2 => "Xvisor",
+        3 => "KVM",
+        4 => "RustSBI",
+        5 => "Diosix",
+        6 => "Coffer",
+        7 => "Xen Project",
+        8 => "PolarFire Hart Software Services",
+        _ => "unknown",
+    }
+}
+
+/// These return the values of the `mvendorid`, `marchid` and `mimpid` CSRs of
+/// the calling hart.
+pub fn mvendorid() -> usize {
+    sbi_base_call(SBI_FID_BASE_GET_MVENDORID, 0)
+}
+
+pub fn marchid() -> usize {
+    sbi_base_call(SBI_FID_BASE_GET_MARCHID, 0)
+}
+
+pub fn mimpid() -> usize {
+    sbi_base_call(SBI_FID_BASE_GET_MIMPID, 0)
+}
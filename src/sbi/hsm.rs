@@ -0,0 +1,66 @@
+//! The Hart State Management extension, for starting and stopping harts.
+
+use crate::sbi::{self, SbiError};
+
+pub const SBI_EID_HSM: u32 = 0x48534d;
+
+const SBI_FID_HSM_HART_START: u32 = 0;
+const SBI_FID_HSM_HART_GET_STATUS: u32 = 2;
+const SBI_FID_HSM_HART_SUSPEND: u32 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+}
+
+/// Starts `hart` at the physical address `start_addr` with the MMU off, `a0`
+/// set to its hart ID and `a1` to `opaque`.
+///
+/// SAFETY: `start_addr` must be code that can run in that state.
+pub unsafe fn hart_start(hart: usize, start_addr: usize, opaque: usize) -> Result<(), SbiError> {
+    sbi::call(
+        SBI_EID_HSM,
+        SBI_FID_HSM_HART_START,
+        [hart, start_addr, opaque],
+    )
+    .map(|_| ())
+}
+
+pub fn hart_get_status(hart: usize) -> Result<HartState, SbiError> {
+    // SAFETY: getting the status has no effect on memory.
+    let status = unsafe { sbi::call(SBI_EID_HSM, SBI_FID_HSM_HART_GET_STATUS, [hart, 0, 0]) }?;
+    match status {
+        0 => Ok(HartState::Started),
+        1 => Ok(HartState::Stopped),
+        2 => Ok(HartState::StartPending),
+        3 => Ok(HartState::StopPending),
+        4 => Ok(HartState::Suspended),
+        5 => Ok(HartState::SuspendPending),
+        6 => Ok(HartState::ResumePending),
+        _ => Err(SbiError::FAILED),
+    }
+}
+
+/// Suspends this hart in the retentive state `suspend_type` until an
+/// interrupt enabled in `sie` is pending, like `wfi` but possibly saving
+/// more power. Non-retentive types are rejected, as they resume elsewhere.
+pub fn hart_suspend_retentive(suspend_type: u32) -> Result<(), SbiError> {
+    if suspend_type & 1 << 31 != 0 {
+        return Err(SbiError::INVALID_PARAM);
+    }
+    // SAFETY: a retentive suspend returns with everything as it was.
+    unsafe {
+        sbi::call(
+            SBI_EID_HSM,
+            SBI_FID_HSM_HART_SUSPEND,
+            [suspend_type as usize, 0, 0],
+        )
+    }
+    .map(|_| ())
+}
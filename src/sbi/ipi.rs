@@ -0,0 +1,29 @@
+//! The IPI extension, for sending supervisor software interrupts to other
+//! harts.
+
+use crate::arch::csr::{self, Interrupts};
+use crate::sbi::{self, SbiError};
+
+pub const SBI_EID_IPI: u32 = 0x735049;
+
+const SBI_FID_IPI_SEND_IPI: u32 = 0;
+
+/// Raises a supervisor software interrupt on each hart `hart_mask_base + i`
+/// for which bit `i` of `hart_mask` is set.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+    // SAFETY: sending an IPI has no effect on memory.
+    unsafe {
+        sbi::call(
+            SBI_EID_IPI,
+            SBI_FID_IPI_SEND_IPI,
+            [hart_mask, hart_mask_base, 0],
+        )
+    }
+    .map(|_| ())
+}
+
+/// Clears this hart's pending supervisor software interrupt.
+pub fn clear_ipi() {
+    // SAFETY: clearing `sip.SSIP` has no effect on memory.
+    unsafe { csr::sip::clear(Interrupts::SOFTWARE) };
+}
@@ -0,0 +1,293 @@
+//! The Performance Monitoring Unit extension, for counting hardware events
+//! such as cycles and cache misses. The SBI implementation picks a counter
+//! that can count the event and starts it, after which hardware counters are
+//! read straight from their CSRs.
+
+use core::arch::asm;
+use core::fmt;
+use core::marker::PhantomData;
+
+use crate::sbi::{self, SbiError};
+
+pub const SBI_EID_PMU: u32 = 0x504d55;
+
+const SBI_FID_PMU_NUM_COUNTERS: u32 = 0;
+const SBI_FID_PMU_COUNTER_GET_INFO: u32 = 1;
+const SBI_FID_PMU_COUNTER_CONFIG_MATCHING: u32 = 2;
+const SBI_FID_PMU_COUNTER_START: u32 = 3;
+const SBI_FID_PMU_COUNTER_STOP: u32 = 4;
+const SBI_FID_PMU_COUNTER_FW_READ: u32 = 5;
+#[cfg(target_arch = "riscv32")]
+const SBI_FID_PMU_COUNTER_FW_READ_HI: u32 = 6;
+
+const CFG_FLAG_CLEAR_VALUE: usize = 1 << 1;
+const CFG_FLAG_AUTO_START: usize = 1 << 2;
+const CFG_FLAG_SET_UINH: usize = 1 << 5;
+const CFG_FLAG_SET_SINH: usize = 1 << 6;
+const CFG_FLAG_SET_MINH: usize = 1 << 7;
+const STOP_FLAG_RESET: usize = 1 << 0;
+
+/// Hardware events every PMU should be able to count, if it counts any.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    Cycles,
+    Instructions,
+    CacheReferences,
+    CacheMisses,
+    Branches,
+    BranchMisses,
+}
+
+impl Event {
+    pub const ALL: [Event; 6] = [
+        Event::Cycles,
+        Event::Instructions,
+        Event::CacheReferences,
+        Event::CacheMisses,
+        Event::Branches,
+        Event::BranchMisses,
+    ];
+
+    /// The event's index, of type 0: a general hardware event.
+    fn index(self) -> usize {
+        match self {
+            Event::Cycles => 1,
+            Event::Instructions => 2,
+            Event::CacheReferences => 3,
+            Event::CacheMisses => 4,
+            Event::Branches => 5,
+            Event::BranchMisses => 6,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Cycles => "cycles",
+            Event::Instructions => "instructions",
+            Event::CacheReferences => "cache-references",
+            Event::CacheMisses => "cache-misses",
+            Event::Branches => "branches",
+            Event::BranchMisses => "branch-misses",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Privilege modes a counter doesn't count events in.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Exclude {
+    pub user: bool,
+    pub supervisor: bool,
+    pub machine: bool,
+}
+
+impl Exclude {
+    fn flags(self) -> usize {
+        let mut flags = 0;
+        if self.user {
+            flags |= CFG_FLAG_SET_UINH;
+        }
+        if self.supervisor {
+            flags |= CFG_FLAG_SET_SINH;
+        }
+        if self.machine {
+            flags |= CFG_FLAG_SET_MINH;
+        }
+        flags
+    }
+}
+
+/// What a counter is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CounterInfo {
+    /// A hardware counter, read from `csr`, `width + 1` bits wide.
+    Hardware { csr: u16, width: u8 },
+    /// A counter kept by the SBI implementation, e.g. of SBI calls.
+    Firmware,
+}
+
+/// Whether the PMU extension is present.
+pub fn is_present() -> bool {
+    sbi::probe_extension(SBI_EID_PMU)
+}
+
+/// The number of counters, hardware and firmware.
+pub fn num_counters() -> Result<usize, SbiError> {
+    // SAFETY: getting the number has no effect on memory.
+    unsafe { sbi::call(SBI_EID_PMU, SBI_FID_PMU_NUM_COUNTERS, [0; 3]) }
+}
+
+pub fn counter_info(index: usize) -> Result<CounterInfo, SbiError> {
+    // SAFETY: getting the info has no effect on memory.
+    let info = unsafe { sbi::call(SBI_EID_PMU, SBI_FID_PMU_COUNTER_GET_INFO, [index, 0, 0]) }?;
+    if info >> (usize::BITS - 1) != 0 {
+        Ok(CounterInfo::Firmware)
+    } else {
+        Ok(CounterInfo::Hardware {
+            csr: (info & 0xfff) as u16,
+            width: ((info >> 12) & 0x3f) as u8,
+        })
+    }
+}
+
+/// Reads the counter CSR numbered `csr`, if it's one. On RV32 its top half
+/// is in the CSR numbered `csr + 0x80`.
+fn read_counter_csr(csr: u16) -> Option<u64> {
+    #[cfg(target_arch = "riscv64")]
+    macro_rules! read {
+        ($($csr:literal)*) => {
+            match csr {
+                $($csr => {
+                    let value: u64;
+                    // SAFETY: reading a counter has no side effects.
+                    unsafe { asm!(concat!("csrr {}, ", stringify!($csr)), out(reg) value) };
+                    Some(value)
+                })*
+                _ => None,
+            }
+        };
+    }
+    #[cfg(target_arch = "riscv32")]
+    macro_rules! read {
+        ($($csr:literal)*) => {
+            match csr {
+                $($csr => loop {
+                    let (high, low, again): (u32, u32, u32);
+                    // SAFETY: as above.
+                    unsafe {
+                        asm!(
+                            concat!("csrr {}, ", stringify!($csr), " + 0x80"),
+                            concat!("csrr {}, ", stringify!($csr)),
+                            concat!("csrr {}, ", stringify!($csr), " + 0x80"),
+                            out(reg) high,
+                            out(reg) low,
+                            out(reg) again,
+                        )
+                    };
+                    // Unless the bottom half carried into the top in between.
+                    if high == again {
+                        break Some(u64::from(high) << 32 | u64::from(low));
+                    }
+                })*
+                _ => None,
+            }
+        };
+    }
+    read!(
+        0xc00 0xc01 0xc02 0xc03 0xc04 0xc05 0xc06 0xc07 0xc08 0xc09 0xc0a 0xc0b 0xc0c 0xc0d 0xc0e
+        0xc0f 0xc10 0xc11 0xc12 0xc13 0xc14 0xc15 0xc16 0xc17 0xc18 0xc19 0xc1a 0xc1b 0xc1c 0xc1d
+        0xc1e 0xc1f
+    )
+}
+
+/// A counter counting an event on the hart that started it, which is the
+/// only one it can be read on. It's stopped and freed when dropped.
+pub struct Counter {
+    index: usize,
+    info: CounterInfo,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Counter {
+    /// Starts a counter from zero counting `event` on this hart.
+    pub fn start(event: Event) -> Result<Counter, SbiError> {
+        Self::start_excluding(event, Exclude::default())
+    }
+
+    /// Starts a counter from zero counting `event` on this hart, except in
+    /// the modes in `exclude`.
+    pub fn start_excluding(event: Event, exclude: Exclude) -> Result<Counter, SbiError> {
+        let count = num_counters()?;
+        let mask = if count >= usize::BITS as usize {
+            usize::MAX
+        } else {
+            (1 << count) - 1
+        };
+        // SAFETY: configuring a counter has no effect on memory.
+        let index = unsafe {
+            sbi::call6(
+                SBI_EID_PMU,
+                SBI_FID_PMU_COUNTER_CONFIG_MATCHING,
+                [
+                    0,
+                    mask,
+                    CFG_FLAG_CLEAR_VALUE | CFG_FLAG_AUTO_START | exclude.flags(),
+                    event.index(),
+                    0,
+                    0,
+                ],
+            )
+        }?;
+        Ok(Counter {
+            index,
+            info: counter_info(index)?,
+            _not_send: PhantomData,
+        })
+    }
+
+    pub fn read(&self) -> Result<u64, SbiError> {
+        match self.info {
+            CounterInfo::Hardware { csr, .. } => read_counter_csr(csr).ok_or(SbiError::FAILED),
+            // SAFETY: reading a counter has no effect on memory.
+            CounterInfo::Firmware => {
+                let low = unsafe {
+                    sbi::call(SBI_EID_PMU, SBI_FID_PMU_COUNTER_FW_READ, [self.index, 0, 0])
+                }?;
+                // RV32 reads the top half on its own, where there is one.
+                #[cfg(target_arch = "riscv32")]
+                let high = unsafe {
+                    sbi::call(
+                        SBI_EID_PMU,
+                        SBI_FID_PMU_COUNTER_FW_READ_HI,
+                        [self.index, 0, 0],
+                    )
+                }
+                .unwrap_or(0);
+                #[cfg(target_arch = "riscv64")]
+                let high = 0;
+                Ok((high as u64) << 32 | low as u64)
+            }
+        }
+    }
+
+    pub fn info(&self) -> CounterInfo {
+        self.info
+    }
+
+    /// Stops counting, leaving the count as it is.
+    pub fn stop(&self) -> Result<(), SbiError> {
+        // SAFETY: stopping a counter has no effect on memory.
+        unsafe { sbi::call(SBI_EID_PMU, SBI_FID_PMU_COUNTER_STOP, [self.index, 1, 0]) }.map(|_| ())
+    }
+
+    /// Starts counting again after `stop`, from where it stopped.
+    pub fn resume(&self) -> Result<(), SbiError> {
+        // SAFETY: starting a counter has no effect on memory.
+        unsafe {
+            sbi::call6(
+                SBI_EID_PMU,
+                SBI_FID_PMU_COUNTER_START,
+                [self.index, 1, 0, 0, 0, 0],
+            )
+        }
+        .map(|_| ())
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        // SAFETY: as for `stop`.
+        let _ = unsafe {
+            sbi::call(
+                SBI_EID_PMU,
+                SBI_FID_PMU_COUNTER_STOP,
+                [self.index, 1, STOP_FLAG_RESET],
+            )
+        };
+    }
+}
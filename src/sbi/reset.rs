@@ -0,0 +1,60 @@
+//! The System Reset extension, for powering off and rebooting the machine.
+
+use crate::sbi::{self, SbiError};
+
+const SBI_EID_SRST: u32 = 0x53525354;
+const SBI_FID_SRST_SYSTEM_RESET: u32 = 0;
+
+/// The legacy `sbi_shutdown`, for implementations without SRST.
+const SBI_EID_LEGACY_SHUTDOWN: u32 = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetReason {
+    None = 0,
+    SystemFailure = 1,
+}
+
+/// Resets the machine. Returns only if the reset failed, e.g. because the
+/// SRST extension is missing or doesn't support `ty`.
+pub fn system_reset(ty: ResetType, reason: ResetReason) -> SbiError {
+    if !sbi::probe_extension(SBI_EID_SRST) {
+        return SbiError::NOT_SUPPORTED;
+    }
+    // SAFETY: on success this doesn't return, and nothing is left to clean up.
+    let result = unsafe {
+        sbi::call(
+            SBI_EID_SRST,
+            SBI_FID_SRST_SYSTEM_RESET,
+            [ty as usize, reason as usize, 0],
+        )
+    };
+    result.err().unwrap_or(SbiError::FAILED)
+}
+
+/// Powers off the machine, falling back to the legacy SBI shutdown call if
+/// SRST isn't available. Returns only if both fail.
+pub fn shutdown(reason: ResetReason) {
+    system_reset(ResetType::Shutdown, reason);
+    // SAFETY: as above. The legacy call doesn't return on success either.
+    unsafe {
+        let _ = sbi::call(SBI_EID_LEGACY_SHUTDOWN, 0, [0; 3]);
+    }
+}
+
+/// Reboots the machine, with a warm reboot if a cold one isn't supported.
+/// Returns only if SRST is missing or the reset failed.
+pub fn reboot() -> SbiError {
+    match system_reset(ResetType::ColdReboot, ResetReason::None) {
+        // Implementations needn't support every reset type, and report one
+        // they don't as an invalid parameter.
+        SbiError::INVALID_PARAM => system_reset(ResetType::WarmReboot, ResetReason::None),
+        err => err,
+    }
+}
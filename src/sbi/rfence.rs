@@ -0,0 +1,27 @@
+//! The RFENCE extension, for flushing other harts' TLBs.
+
+use crate::sbi::{self, SbiError};
+
+pub const SBI_EID_RFENCE: u32 = 0x5246_4e43;
+
+const SBI_FID_RFENCE_REMOTE_SFENCE_VMA: u32 = 1;
+
+/// Has each hart `hart_mask_base + i` for which bit `i` of `hart_mask` is
+/// set run `sfence.vma` over the `size` bytes at `start`, returning once
+/// they have. A `size` of `usize::MAX` flushes everything.
+pub fn remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+) -> Result<(), SbiError> {
+    // SAFETY: flushing TLBs has no effect on memory.
+    unsafe {
+        sbi::call6(
+            SBI_EID_RFENCE,
+            SBI_FID_RFENCE_REMOTE_SFENCE_VMA,
+            [hart_mask, hart_mask_base, start, size, 0, 0],
+        )
+    }
+    .map(|_| ())
+}
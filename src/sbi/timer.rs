@@ -0,0 +1,164 @@
+//! The timer interrupt, from the SBI TIME extension, or the legacy
+//! `sbi_set_timer`, or, on harts with Sstc, from `stimecmp` without an SBI
+//! call at all.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::csr::{self, Interrupts};
+use crate::arch::AtomicU64;
+use crate::dtb::{DeviceTree, DtNode};
+use crate::sbi;
+use crate::sync::SpinLockIrqSave;
+use crate::time::{self, read_time};
+use crate::{ktest, log_error, percpu, trap};
+
+const SBI_EID_TIME: u32 = 0x54494d45;
+const SBI_FID_TIME_SET_TIMER: u32 = 0;
+
+/// The legacy `sbi_set_timer`, for implementations without the TIME
+/// extension.
+const SBI_EID_LEGACY_SET_TIMER: u32 = 0;
+
+/// Timer interrupts per second.
+pub const TICK_HZ: u64 = 100;
+
+static HAS_TIME: AtomicBool = AtomicBool::new(false);
+/// Every hart has Sstc, so `stimecmp` can be written directly.
+static HAS_SSTC: AtomicBool = AtomicBool::new(false);
+static INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+percpu! {
+    static DEADLINE: AtomicU64 = AtomicU64::new(0);
+}
+
+static TICK_HANDLER: SpinLockIrqSave<Option<fn()>> = SpinLockIrqSave::new(None);
+
+/// Requests a timer interrupt once the `time` CSR reaches `stime`, replacing
+/// any earlier request. This also clears a pending timer interrupt.
+pub fn set_timer(stime: u64) {
+    if HAS_SSTC.load(Ordering::Relaxed) {
+        // SAFETY: the firmware has let S-mode have `stimecmp`, or Sstc
+        // wouldn't be in the tree, and it affects nothing but the timer
+        // interrupt.
+        unsafe { csr::stimecmp::write(stime) };
+        return;
+    }
+    let eid = if HAS_TIME.load(Ordering::Relaxed) {
+        SBI_EID_TIME
+    } else {
+        SBI_EID_LEGACY_SET_TIMER
+    };
+    // On RV32 the time is split across two arguments, low half first.
+    #[cfg(target_arch = "riscv64")]
+    let args = [stime as usize, 0, 0];
+    #[cfg(target_arch = "riscv32")]
+    let args = [stime as usize, (stime >> 32) as usize, 0];
+    // SAFETY: setting the timer has no effect on memory. The legacy call
+    // ignores the function ID, and returns zero in a0 on success too.
+    let result = unsafe { sbi::call(eid, SBI_FID_TIME_SET_TIMER, args) };
+    if let Err(error) = result {
+        log_error!(target: "timer", "setting the timer failed: {}", error);
+    }
+}
+
+/// Whether `cpu`'s ISA has Sstc, from its `riscv,isa-extensions` or the
+/// multi-letter extensions of its `riscv,isa`.
+fn has_sstc(cpu: &DtNode<'_>) -> bool {
+    if let Some(mut extensions) = cpu.prop_string_list("riscv,isa-extensions") {
+        return extensions.any(|ext| ext == "sstc");
+    }
+    cpu.prop_str("riscv,isa")
+        .is_some_and(|isa| isa.split('_').skip(1).any(|ext| ext == "sstc"))
+}
+
+/// Whether the timer is set through `stimecmp` rather than the SBI.
+pub fn has_sstc_timer() -> bool {
+    HAS_SSTC.load(Ordering::Relaxed)
+}
+
+/// Starts the periodic tick on the boot hart, enabling interrupts. The
+/// timebase frequency must be known, i.e. `time::init` must have been called.
+pub fn init(dt: &DeviceTree<'_>) {
+    HAS_TIME.store(sbi::probe_extension(SBI_EID_TIME), Ordering::Relaxed);
+    let mut cpus = dt
+        .find_node("/cpus")
+        .into_iter()
+        .flat_map(|cpus| cpus.children())
+        .filter(|node| node.prop_str("device_type") == Some("cpu"))
+        .filter(DtNode::is_enabled)
+        .peekable();
+    let sstc = cpus.peek().is_some() && cpus.all(|cpu| has_sstc(&cpu));
+    HAS_SSTC.store(sstc, Ordering::Relaxed);
+    INTERVAL.store(time::frequency() / TICK_HZ, Ordering::Relaxed);
+    init_hart();
+}
+
+/// Starts the periodic tick on another hart, once `init` has been called.
+pub fn init_hart() {
+    let deadline = read_time() + INTERVAL.load(Ordering::Relaxed);
+    DEADLINE.get().store(deadline, Ordering::Relaxed);
+    set_timer(deadline);
+
+    // SAFETY: the trap vector is installed and handles timer interrupts.
+    unsafe {
+        csr::sie::set(Interrupts::TIMER);
+        trap::enable_interrupts();
+    }
+}
+
+/// When this hart's next tick is due, in ticks of the `time` CSR.
+pub fn next_tick() -> u64 {
+    DEADLINE.get().load(Ordering::Relaxed)
+}
+
+/// Sets the function called on every tick of every hart, from interrupt
+/// context.
+pub fn set_tick_handler(handler: fn()) {
+    *TICK_HANDLER.lock() = Some(handler);
+}
+
+/// Called by the trap handler on a supervisor timer interrupt.
+pub fn handle_interrupt() {
+    // Deadlines are kept on a fixed grid so ticks don't drift, unless we've
+    // fallen behind by a whole tick.
+    let interval = INTERVAL.load(Ordering::Relaxed);
+    let mut deadline = DEADLINE.get().load(Ordering::Relaxed) + interval;
+    let now = read_time();
+    if deadline <= now {
+        deadline = now + interval;
+    }
+    DEADLINE.get().store(deadline, Ordering::Relaxed);
+    set_timer(deadline);
+
+    let handler = *TICK_HANDLER.lock();
+    if let Some(handler) = handler {
+        handler();
+    }
+}
+
+ktest! {
+    fn finds_sstc() {
+        use crate::dtb::{FdtBuilder, FdtNode};
+
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.root_mut();
+        root.add_child(FdtNode::new("isa")).set_str("riscv,isa", "rv64imafdc_zicsr_sstc");
+        root.add_child(FdtNode::new("letters")).set_str("riscv,isa", "rv64imafdcsstc");
+        root.add_child(FdtNode::new("other")).set_str("riscv,isa", "rv64gc_sstc2_zba");
+        let list = root.add_child(FdtNode::new("list"));
+        list.set_str("riscv,isa", "rv64imac");
+        list.set_string_list("riscv,isa-extensions", &["i", "m", "sstc"]);
+        let unlisted = root.add_child(FdtNode::new("unlisted"));
+        unlisted.set_str("riscv,isa", "rv64imac_sstc");
+        unlisted.set_string_list("riscv,isa-extensions", &["i", "m"]);
+
+        let blob = fdt.to_aligned_blob();
+        let dt = blob.parse().unwrap();
+        let has = |node: &str| has_sstc(&dt.root_node().child(node).unwrap());
+
+        assert!(has("isa") && has("list"));
+        assert!(!has("letters") && !has("other"));
+        // The list is preferred.
+        assert!(!has("unlisted"));
+    }
+}
@@ -0,0 +1,444 @@
+//! Signals sent to user processes. A signal is delivered when the process
+//! next returns to U-mode from a trap: its handler is called on the user
+//! stack, or the default action taken, which here is either terminating
+//! the process, for some signals dumping its core first (see `coredump`),
+//! stopping it until it's sent `SIGCONT`, for job control, or ignoring the
+//! signal. A stopped process is continued by `SIGCONT` however it handles
+//! the signal, and sending either discards the other if it's pending.
+//!
+//! A process blocked in the kernel isn't interrupted, so only sees a signal
+//! once it's woken for some other reason, except in `Process::sleep_until`,
+//! which a signal wakes. A traced process stops for its tracer before each
+//! signal is delivered, see `ptrace`.
+
+use crate::coredump;
+use crate::ktest;
+use crate::mm::uaccess::{self, Plain};
+use crate::process::{self, ExitStatus};
+use crate::ptrace;
+use crate::syscall::Errno;
+use crate::task;
+use crate::trap::{Trap, TrapFrame};
+use crate::user;
+
+pub const SIGHUP: u8 = 1;
+pub const SIGINT: u8 = 2;
+pub const SIGQUIT: u8 = 3;
+pub const SIGILL: u8 = 4;
+pub const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+pub const SIGBUS: u8 = 7;
+const SIGFPE: u8 = 8;
+pub const SIGKILL: u8 = 9;
+pub const SIGSEGV: u8 = 11;
+pub const SIGPIPE: u8 = 13;
+pub const SIGTERM: u8 = 15;
+pub const SIGCHLD: u8 = 17;
+pub const SIGCONT: u8 = 18;
+pub const SIGSTOP: u8 = 19;
+pub const SIGTSTP: u8 = 20;
+const SIGTTIN: u8 = 21;
+const SIGTTOU: u8 = 22;
+const SIGURG: u8 = 23;
+pub const SIGXCPU: u8 = 24;
+const SIGXFSZ: u8 = 25;
+const SIGWINCH: u8 = 28;
+const SIGSYS: u8 = 31;
+
+/// Signals are numbered from 1 to this.
+pub const NSIG: u8 = 64;
+
+/// `sigaction` handler values for the default action and ignoring.
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+
+/// `sigaction` flags: don't block the signal while its handler runs, and
+/// reset the action to the default once it's been delivered.
+const SA_NODEFER: usize = 0x4000_0000;
+const SA_RESETHAND: usize = 0x8000_0000;
+
+fn bit(signal: u8) -> u64 {
+    1 << (signal - 1)
+}
+
+/// Signals that can't be caught, blocked or ignored.
+fn unblockable() -> u64 {
+    bit(SIGKILL) | bit(SIGSTOP)
+}
+
+fn ignored_by_default(signal: u8) -> bool {
+    matches!(signal, SIGCHLD | SIGCONT | SIGURG | SIGWINCH)
+}
+
+/// Signals whose default action stops the process.
+fn stops_by_default(signal: u8) -> bool {
+    matches!(signal, SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU)
+}
+
+fn stop_signals() -> u64 {
+    bit(SIGSTOP) | bit(SIGTSTP) | bit(SIGTTIN) | bit(SIGTTOU)
+}
+
+/// Signals whose default action dumps core as well as terminating.
+fn dumps_core_by_default(signal: u8) -> bool {
+    matches!(
+        signal,
+        SIGQUIT
+            | SIGILL
+            | SIGTRAP
+            | SIGABRT
+            | SIGBUS
+            | SIGFPE
+            | SIGSEGV
+            | SIGXCPU
+            | SIGXFSZ
+            | SIGSYS
+    )
+}
+
+/// The signal a U-mode fault sends, as on Linux.
+pub fn for_fault(trap: Trap) -> u8 {
+    match trap {
+        Trap::Exception(2) => SIGILL,
+        Trap::Exception(3) => SIGTRAP,
+        Trap::Exception(0 | 4 | 6) => SIGBUS,
+        _ => SIGSEGV,
+    }
+}
+
+/// What to do when a signal is delivered.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Default,
+    Ignore,
+    /// Call `handler`, with `mask` added to the blocked signals meanwhile.
+    Handler {
+        handler: usize,
+        flags: usize,
+        mask: u64,
+    },
+}
+
+/// `struct sigaction` as the RISC-V Linux ABI lays it out.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SigAction {
+    handler: usize,
+    flags: usize,
+    mask: u64,
+}
+
+// SAFETY: all fields are integers, with no padding between them.
+unsafe impl Plain for SigAction {}
+
+impl From<Action> for SigAction {
+    fn from(action: Action) -> Self {
+        let (handler, flags, mask) = match action {
+            Action::Default => (SIG_DFL, 0, 0),
+            Action::Ignore => (SIG_IGN, 0, 0),
+            Action::Handler {
+                handler,
+                flags,
+                mask,
+            } => (handler, flags, mask),
+        };
+        Self {
+            handler,
+            flags,
+            mask,
+        }
+    }
+}
+
+impl From<SigAction> for Action {
+    fn from(action: SigAction) -> Self {
+        match action.handler {
+            SIG_DFL => Self::Default,
+            SIG_IGN => Self::Ignore,
+            handler => Self::Handler {
+                handler,
+                flags: action.flags,
+                mask: action.mask & !unblockable(),
+            },
+        }
+    }
+}
+
+/// A process's signal state.
+#[derive(Clone)]
+pub struct Signals {
+    pending: u64,
+    blocked: u64,
+    actions: [Action; NSIG as usize],
+    /// Whether the process is traced, in which case its tracer sees even
+    /// the signals it ignores.
+    traced: bool,
+}
+
+impl Signals {
+    pub fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: [Action::Default; NSIG as usize],
+            traced: false,
+        }
+    }
+
+    /// Marks `signal` pending, unless it would be ignored anyway.
+    pub fn raise(&mut self, signal: u8) {
+        if signal == SIGCONT {
+            self.pending &= !stop_signals();
+        } else if stops_by_default(signal) {
+            self.pending &= !bit(SIGCONT);
+        }
+        let ignored = match self.action(signal) {
+            Action::Ignore => true,
+            Action::Default => ignored_by_default(signal),
+            Action::Handler { .. } => false,
+        };
+        if !ignored || self.traced {
+            self.pending |= bit(signal);
+        }
+    }
+
+    /// Marks `signal` pending for a fault, unblocking it, and taking the
+    /// default action rather than ignoring it, so it can't be lost.
+    fn force(&mut self, signal: u8) {
+        self.blocked &= !bit(signal);
+        if self.action(signal) == Action::Ignore {
+            self.actions[usize::from(signal - 1)] = Action::Default;
+        }
+        self.pending |= bit(signal);
+    }
+
+    pub fn is_pending(&self, signal: u8) -> bool {
+        self.pending & bit(signal) != 0
+    }
+
+    /// The pending and blocked signals, as bitmasks.
+    pub fn masks(&self) -> (u64, u64) {
+        (self.pending, self.blocked)
+    }
+
+    /// Replaces the blocked signals with `blocked`, less those that can't
+    /// be, returning the old set.
+    pub fn set_blocked(&mut self, blocked: u64) -> u64 {
+        core::mem::replace(&mut self.blocked, blocked & !unblockable())
+    }
+
+    pub fn set_traced(&mut self, traced: bool) {
+        self.traced = traced;
+    }
+
+    pub fn action(&self, signal: u8) -> Action {
+        self.actions[usize::from(signal - 1)]
+    }
+
+    /// Sets the action for `signal`, returning the old one. Fails for
+    /// signals whose action can't be changed.
+    pub fn set_action(&mut self, signal: u8, action: Action) -> Result<Action, Errno> {
+        if bit(signal) & unblockable() != 0 {
+            return Err(Errno::EINVAL);
+        }
+        let old = core::mem::replace(&mut self.actions[usize::from(signal - 1)], action);
+        if action == Action::Ignore {
+            self.pending &= !bit(signal);
+        }
+        Ok(old)
+    }
+
+    /// The state a forked child starts with: the same actions and blocked
+    /// signals, but nothing pending, and not traced.
+    pub fn forked(&self) -> Self {
+        Self {
+            pending: 0,
+            traced: false,
+            ..self.clone()
+        }
+    }
+
+    /// After `exec`, handlers no longer exist, but ignored signals stay
+    /// ignored.
+    pub fn reset_handlers(&mut self) {
+        for action in &mut self.actions {
+            if matches!(action, Action::Handler { .. }) {
+                *action = Action::Default;
+            }
+        }
+    }
+
+    fn is_blocked(&self, signal: u8) -> bool {
+        self.blocked & bit(signal) & !unblockable() != 0
+    }
+
+    /// Whether a pending signal isn't blocked, so would be delivered on the
+    /// way back to U-mode.
+    pub fn has_deliverable(&self) -> bool {
+        self.deliverable() != 0
+    }
+
+    fn deliverable(&self) -> u64 {
+        self.pending & !(self.blocked & !unblockable())
+    }
+
+    /// Takes the lowest-numbered pending signal that isn't blocked.
+    fn take_next(&mut self) -> Option<u8> {
+        let deliverable = self.deliverable();
+        if deliverable == 0 {
+            return None;
+        }
+        let signal = deliverable.trailing_zeros() as u8 + 1;
+        self.pending &= !bit(signal);
+        Some(signal)
+    }
+}
+
+/// What's pushed onto the user stack when calling a handler, and restored
+/// by `rt_sigreturn`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SigFrame {
+    regs: [usize; 32],
+    pc: usize,
+    blocked: u64,
+}
+
+// SAFETY: all fields are integers, with no padding between them.
+unsafe impl Plain for SigFrame {}
+
+/// Kills the running process with `signal`, which it took at `frame`,
+/// first dumping its core if the signal's default action does.
+fn kill(frame: &TrapFrame, signal: u8) -> ! {
+    if dumps_core_by_default(signal) && coredump::dump(frame, signal) {
+        process::exit_thread(ExitStatus::Dumped(signal));
+    }
+    process::exit_thread(ExitStatus::Killed(signal));
+}
+
+/// Sends the running process a signal for a fault it took at `frame`. The
+/// fault can't be ignored or blocked, so unless a handler will be called,
+/// or its tracer will see it first, the process is killed right away.
+pub fn send_fault(frame: &TrapFrame, signal: u8) {
+    let process = task::process().expect("fault from a task without a process");
+    let mut signals = process.signals();
+    let handled =
+        matches!(signals.action(signal), Action::Handler { .. }) && !signals.is_blocked(signal);
+    if handled || signals.traced {
+        signals.force(signal);
+        return;
+    }
+    drop(signals);
+    drop(process);
+    kill(frame, signal);
+}
+
+/// Delivers the running process's pending signals before it returns to
+/// U-mode with `frame`: takes the default action, or sets up `frame` to
+/// call a handler.
+pub fn deliver(frame: &mut TrapFrame) {
+    let Some(process) = task::process() else {
+        return;
+    };
+    loop {
+        let Some(signal) = process.signals().take_next() else {
+            return;
+        };
+        let Some(signal) = ptrace::report_signal(frame, signal) else {
+            continue;
+        };
+        let mut signals = process.signals();
+        match signals.action(signal) {
+            Action::Ignore => {}
+            Action::Default if ignored_by_default(signal) => {}
+            Action::Default if stops_by_default(signal) => {
+                drop(signals);
+                process.job_stop(signal);
+            }
+            Action::Default => {
+                drop(signals);
+                drop(process);
+                kill(frame, signal);
+            }
+            Action::Handler {
+                handler,
+                flags,
+                mask,
+            } => {
+                let sig_frame = SigFrame {
+                    regs: frame.regs,
+                    pc: frame.sepc,
+                    blocked: signals.blocked,
+                };
+                let sp = frame.regs[2].wrapping_sub(size_of::<SigFrame>()) & !15;
+                if uaccess::write_user_struct(sp, &sig_frame).is_err() {
+                    drop(signals);
+                    drop(process);
+                    kill(frame, SIGSEGV);
+                }
+                signals.blocked |= mask;
+                if flags & SA_NODEFER == 0 {
+                    signals.blocked |= bit(signal);
+                }
+                if flags & SA_RESETHAND != 0 {
+                    signals.actions[usize::from(signal - 1)] = Action::Default;
+                }
+
+                // handler(signal, NULL, NULL), returning to the trampoline
+                // that calls `rt_sigreturn`.
+                frame.regs[1] = user::SIGRETURN_TRAMPOLINE;
+                frame.regs[2] = sp;
+                frame.regs[10] = usize::from(signal);
+                frame.regs[11] = 0;
+                frame.regs[12] = 0;
+                frame.sepc = handler;
+                return;
+            }
+        }
+    }
+}
+
+/// Returns from a handler by restoring the registers and blocked signals
+/// saved below the handler's stack pointer in `frame`.
+pub fn sigreturn(frame: &mut TrapFrame) -> Result<(), Errno> {
+    let sig_frame: SigFrame = uaccess::read_user_struct(frame.regs[2])?;
+    frame.regs[1..].copy_from_slice(&sig_frame.regs[1..]);
+    frame.sepc = sig_frame.pc;
+    let process = task::process().expect("syscall from a task without a process");
+    process.signals().blocked = sig_frame.blocked & !unblockable();
+    Ok(())
+}
+
+ktest! {
+    fn continuing_discards_pending_stops() {
+        let mut signals = Signals::new();
+        signals.raise(SIGTSTP);
+        assert!(signals.is_pending(SIGTSTP));
+        signals.raise(SIGCONT);
+        assert!(!signals.is_pending(SIGTSTP));
+        // Ignored by default, so not left pending either.
+        assert!(!signals.is_pending(SIGCONT));
+
+        let handler = Action::Handler { handler: 0x1000, flags: 0, mask: 0 };
+        signals.set_action(SIGCONT, handler).unwrap();
+        signals.raise(SIGCONT);
+        signals.raise(SIGSTOP);
+        assert!(!signals.is_pending(SIGCONT));
+        assert_eq!(signals.take_next(), Some(SIGSTOP));
+    }
+}
+
+ktest! {
+    fn blocks_all_but_kill_and_stop() {
+        let mut signals = Signals::new();
+        assert_eq!(signals.set_blocked(u64::MAX), 0);
+        signals.raise(SIGTERM);
+        signals.raise(SIGKILL);
+        assert_eq!(signals.masks().1, !(bit(SIGKILL) | bit(SIGSTOP)));
+        assert_eq!(signals.take_next(), Some(SIGKILL));
+        assert_eq!(signals.take_next(), None);
+
+        signals.set_blocked(0);
+        assert_eq!(signals.take_next(), Some(SIGTERM));
+    }
+}
@@ -0,0 +1,366 @@
+//! Starting the secondary harts, and running functions on them.
+
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::arch::csr::{self, Interrupts};
+use crate::dtb::{DeviceTree, DtNode};
+use crate::mm::stack::KernelStack;
+use crate::mm::{self, paging, virt_to_phys};
+use crate::sbi::hsm::{self, HartState};
+use crate::sbi::ipi;
+use crate::sync::{SpinLock, SpinLockIrqSave};
+use crate::time::{Duration, Instant};
+use crate::{ktest, log_info, log_warn, percpu, sbi, task, trap, workqueue};
+
+/// How long to wait for a hart to come online.
+const START_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long `call_on` waits for other harts before warning that they're
+/// slow.
+const CALL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Harts with IDs from this on can't be represented in a hart mask, so
+/// aren't started.
+pub const MAX_HARTS: usize = usize::BITS as usize;
+
+/// A set of harts, such as the ones a task may run on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CpuMask(usize);
+
+impl CpuMask {
+    pub const ALL: Self = Self(usize::MAX);
+
+    pub const fn from_bits(bits: usize) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> usize {
+        self.0
+    }
+
+    pub const fn single(hart: usize) -> Self {
+        Self(1 << hart)
+    }
+
+    pub const fn contains(self, hart: usize) -> bool {
+        hart < MAX_HARTS && self.0 & 1 << hart != 0
+    }
+
+    pub const fn intersect(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = usize> {
+        (0..MAX_HARTS).filter(move |&hart| self.contains(hart))
+    }
+}
+
+/// Mask of the harts that have reached `secondary_main`, plus the boot hart.
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Set to make every hart that takes an IPI halt.
+static HALT: AtomicBool = AtomicBool::new(false);
+
+/// Set while a hart has the others stopped, for the debugger.
+static PAUSE: AtomicBool = AtomicBool::new(false);
+/// Mask of the harts waiting in `handle_ipi` for `PAUSE` to clear.
+static PAUSED: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes `call_on`s, since there is only one `CALL`.
+static CALL_LOCK: SpinLock<()> = SpinLock::new(());
+static CALL: SpinLockIrqSave<Call> = SpinLockIrqSave::new(Call {
+    func: None,
+    pending: 0,
+});
+
+/// The function being run by `call_on`.
+struct Call {
+    func: Option<fn()>,
+    /// Mask of the harts that are yet to run `func`.
+    pending: usize,
+}
+
+extern "C" {
+    fn _start_secondary();
+}
+
+/// What a secondary hart needs before it can run Rust code, read by
+/// `_start_secondary` in start.s.
+#[repr(C)]
+struct HartBoot {
+    satp: usize,
+    stack_top: usize,
+    /// The hart's per-hart area.
+    tp: usize,
+}
+
+/// The hart IDs of the usable harts listed under `/cpus`.
+pub fn harts<'a>(dt: &DeviceTree<'a>) -> impl Iterator<Item = usize> + 'a {
+    dt.find_node("/cpus")
+        .into_iter()
+        .flat_map(|cpus| cpus.children())
+        .filter(|node| node.prop_str("device_type") == Some("cpu"))
+        .filter(DtNode::is_enabled)
+        .filter_map(|node| Some(node.reg()?.next()?.address as usize))
+}
+
+/// Starts every hart other than `boot_hart` and waits for them to come
+/// online. Each then runs its own tasks, see `task`.
+pub fn init(dt: &DeviceTree<'_>, boot_hart: usize) {
+    ONLINE.store(1 << boot_hart, Ordering::Release);
+    let has_ipi = sbi::probe_extension(ipi::SBI_EID_IPI);
+    if has_ipi {
+        enable_ipi();
+    } else {
+        log_warn!("no SBI IPI extension, can't call other harts");
+    }
+    if !sbi::probe_extension(hsm::SBI_EID_HSM) {
+        log_warn!("no SBI HSM extension, only hart {} will run", boot_hart);
+        return;
+    }
+
+    for hart in harts(dt).filter(|&hart| hart != boot_hart) {
+        if hart >= MAX_HARTS {
+            log_warn!("hart {} is out of range, not starting it", hart);
+            continue;
+        }
+        match hsm::hart_get_status(hart) {
+            Ok(HartState::Stopped) => {}
+            Ok(state) => {
+                log_warn!("hart {} is {:?}, not starting it", hart, state);
+                continue;
+            }
+            Err(err) => {
+                log_warn!("hart {}: {}", hart, err);
+                continue;
+            }
+        }
+
+        let Some(stack) = KernelStack::new() else {
+            log_warn!("no memory for hart {}'s stack", hart);
+            break;
+        };
+        let Some(tp) = percpu::alloc_area(hart) else {
+            log_warn!("no memory for hart {}'s per-hart area", hart);
+            break;
+        };
+        let boot = Box::new(HartBoot {
+            satp: paging::kernel_satp(),
+            stack_top: stack.top(),
+            tp,
+        });
+
+        let entry = virt_to_phys(_start_secondary as *const () as usize);
+        let opaque = virt_to_phys(&*boot as *const HartBoot as usize);
+        // SAFETY: `_start_secondary` expects to be entered like this, and
+        // `boot`, the stack and the per-hart area are kept if it starts.
+        if let Err(err) = unsafe { hsm::hart_start(hart, entry, opaque) } {
+            log_warn!("failed to start hart {}: {}", hart, err);
+            // SAFETY: the hart didn't start, so nothing uses the area.
+            unsafe { percpu::free_area(tp) };
+            continue;
+        }
+        // The hart uses them from now on, even if it's too slow to come
+        // online, and never gives them back.
+        Box::leak(boot);
+        core::mem::forget(stack);
+
+        let start = Instant::now();
+        while ONLINE.load(Ordering::Acquire) & 1 << hart == 0 {
+            if start.elapsed() > START_TIMEOUT {
+                log_warn!("hart {} didn't come online", hart);
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    log_info!("{} harts online", online_harts());
+}
+
+pub fn online_harts() -> usize {
+    online_mask().count_ones() as usize
+}
+
+/// Mask of the harts that are online.
+pub fn online_mask() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Lets this hart take IPIs.
+fn enable_ipi() {
+    // SAFETY: the trap handler handles supervisor software interrupts.
+    unsafe {
+        csr::sie::set(Interrupts::SOFTWARE);
+        trap::enable_interrupts();
+    }
+}
+
+/// Runs `func` on each online hart in `hart_mask`, including this one, and
+/// waits for them all to return. Other harts run it from interrupt context.
+pub fn call_on(hart_mask: usize, func: fn()) {
+    let this = 1 << percpu::hart_id();
+    let targets = hart_mask & online_mask();
+
+    // Run any call made to us while we wait, in case its caller is waiting
+    // for us with interrupts disabled.
+    let _guard = loop {
+        if let Some(guard) = CALL_LOCK.try_lock() {
+            break guard;
+        }
+        handle_ipi();
+        core::hint::spin_loop();
+    };
+
+    let remote = targets & !this;
+    if remote != 0 {
+        *CALL.lock() = Call {
+            func: Some(func),
+            pending: remote,
+        };
+        if let Err(err) = ipi::send_ipi(remote, 0) {
+            log_warn!("failed to send IPIs: {}", err);
+            CALL.lock().pending = 0;
+        }
+    }
+    if targets & this != 0 {
+        func();
+    }
+    let start = Instant::now();
+    let mut warned = false;
+    loop {
+        let pending = CALL.lock().pending;
+        if pending == 0 {
+            break;
+        }
+        if !warned && start.elapsed() > CALL_TIMEOUT {
+            // Keep waiting: `func` may still be running, or the harts may
+            // just have interrupts disabled for a while.
+            log_warn!("harts {:#x} haven't run a cross-hart call", pending);
+            warned = true;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Called by the trap handler on a supervisor software interrupt.
+pub fn handle_ipi() {
+    ipi::clear_ipi();
+    if HALT.load(Ordering::Acquire) {
+        halt();
+    }
+    let this = 1 << percpu::hart_id();
+    if PAUSE.load(Ordering::Acquire) {
+        PAUSED.fetch_or(this, Ordering::AcqRel);
+        while PAUSE.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        PAUSED.fetch_and(!this, Ordering::AcqRel);
+        // The code we return to may have been patched meanwhile.
+        // SAFETY: a fence has no other effects.
+        unsafe { asm!("fence.i") };
+    }
+    let func = {
+        let call = CALL.lock();
+        if call.pending & this == 0 {
+            return;
+        }
+        call.func
+    };
+    if let Some(func) = func {
+        func();
+    }
+    CALL.lock().pending &= !this;
+}
+
+/// Stops every other online hart until `resume_others`, waiting up to a
+/// second for them to stop. They spin in their IPI handler meanwhile, so a
+/// hart with interrupts disabled won't stop until it enables them.
+pub fn pause_others() {
+    PAUSE.store(true, Ordering::Release);
+    let others = online_mask() & !(1 << percpu::hart_id());
+    if others == 0 || ipi::send_ipi(others, 0).is_err() {
+        return;
+    }
+    let start = Instant::now();
+    while PAUSED.load(Ordering::Acquire) != others && start.elapsed() < START_TIMEOUT {
+        core::hint::spin_loop();
+    }
+}
+
+/// Lets the harts stopped by `pause_others` go.
+pub fn resume_others() {
+    PAUSE.store(false, Ordering::Release);
+}
+
+/// Makes every other online hart halt, without waiting for them.
+pub fn halt_others() {
+    HALT.store(true, Ordering::Release);
+    let others = online_mask() & !(1 << percpu::hart_id());
+    if others != 0 {
+        let _ = ipi::send_ipi(others, 0);
+    }
+}
+
+/// Stops this hart for good.
+pub fn halt() -> ! {
+    trap::disable_interrupts();
+    loop {
+        // SAFETY: waiting for an interrupt has no effect on memory. It can
+        // still return when one is pending, hence the loop.
+        unsafe { asm!("wfi") };
+    }
+}
+
+#[no_mangle]
+extern "C" fn secondary_main() -> ! {
+    mm::stack::init_hart();
+    trap::init();
+    log_info!("hart {} online", percpu::hart_id());
+    if sbi::probe_extension(ipi::SBI_EID_IPI) {
+        enable_ipi();
+    }
+    ONLINE.fetch_or(1 << percpu::hart_id(), Ordering::Release);
+
+    // This hart only runs tasks from now on, and its idle task takes over
+    // from here; the boot stack is leaked.
+    sbi::timer::init_hart();
+    task::init();
+    workqueue::init();
+    task::exit();
+}
+
+ktest! {
+    fn lists_usable_harts() {
+        use crate::dtb::{FdtBuilder, FdtNode};
+
+        let mut fdt = FdtBuilder::new();
+        let cpus = fdt.root_mut().add_child(FdtNode::new("cpus"));
+        cpus.set_u32("#address-cells", 1);
+        cpus.set_u32("#size-cells", 0);
+        for (name, hart, status) in [
+            ("cpu@0", 0, None),
+            ("cpu@1", 1, Some("okay")),
+            ("cpu@2", 2, Some("disabled")),
+            ("cpu@3", 3, Some("ok")),
+        ] {
+            let cpu = cpus.add_child(FdtNode::new(name));
+            cpu.set_str("device_type", "cpu");
+            cpu.set_cells("reg", &[hart]);
+            if let Some(status) = status {
+                cpu.set_str("status", status);
+            }
+        }
+        cpus.add_child(FdtNode::new("cpu-map")).set_cells("reg", &[4]);
+        cpus.add_child(FdtNode::new("cpu@5")).set_str("device_type", "cpu");
+
+        let blob = fdt.to_aligned_blob();
+        let dt = blob.parse().unwrap();
+        assert!(harts(&dt).eq([0, 1, 3]));
+    }
+}
@@ -1,25 +1,29 @@
 .section .text.init
 .global _start
 
+# satp's mode field for Sv39 or Sv32, as the boot page table is.
+.if REGBYTES == 8
+.equ SATP_MODE, 8 << 60
+.else
+.equ SATP_MODE, 1 << 31
+.endif
+
+# The kernel is linked at KERNEL_OFFSET but entered at its physical address
+# with the MMU off. Until paging is enabled `la` (which is PC-relative)
+# yields physical addresses; relaxation must be off as gp isn't valid yet.
 _start:
-    # set gp
     .option push
     .option norelax
-    la gp, __global_pointer$
-    .option pop
-
-    # set sp
-    la sp, _sstack
 
     # move data to ram
     la t0, _sidata
     la t1, _sdata
     la t2, _edata
 1:  beq t1, t2, 2f
-    ld t3, 0(t0)
-    sd t3, 0(t1)
-    addi t0, t0, 8
-    addi t1, t1, 8
+    REG_L t3, 0(t0)
+    REG_S t3, 0(t1)
+    addi t0, t0, REGBYTES
+    addi t1, t1, REGBYTES
     j 1b
 2:
 
@@ -27,10 +31,113 @@ _start:
     la t1, _sbss
     la t2, _ebss
 3:  beq t1, t2, 4f
-    sd zero, 0(t1)
-    addi t1, t1, 8
+    REG_S zero, 0(t1)
+    addi t1, t1, REGBYTES
     j 3b
 4:
 
-    # jump to our Rust code
-    j kmain
+    # enable paging with the boot page table
+    la t0, boot_page_table
+    srli t0, t0, 12
+    li t1, SATP_MODE
+    or t0, t0, t1
+    csrw satp, t0
+    sfence.vma
+
+    # continue at our linked (virtual) address
+    la t0, 5f
+    REG_L t1, kernel_offset
+    add t0, t0, t1
+    jr t0
+5:
+
+    # set gp
+    la gp, __global_pointer$
+    .option pop
+
+    # set sp
+    la sp, _sstack
+
+    # the boot hart uses the per-hart area in .data
+    la tp, _spercpu
+
+    # no frame record to return to, for backtraces
+    li s0, 0
+
+    # jump to our Rust code, a0 = hart id, a1 = physical address of the DTB
+    tail kmain
+
+# Secondary harts are started here by SBI HSM, with a0 = hart id and a1 = the
+# physical address of their HartBoot (see src/smp.rs).
+.global _start_secondary
+.balign 4
+_start_secondary:
+    .option push
+    .option norelax
+
+    # data is already set up; enable the boot page table as above
+    la t0, boot_page_table
+    srli t0, t0, 12
+    li t1, SATP_MODE
+    or t0, t0, t1
+    csrw satp, t0
+    sfence.vma
+
+    la t0, 6f
+    REG_L t1, kernel_offset
+    add t0, t0, t1
+    jr t0
+6:
+
+    la gp, __global_pointer$
+    .option pop
+
+    # a1 = virtual address of HartBoot (satp, stack_top, then tp)
+    add a1, a1, t1
+    REG_L sp, 1*REGBYTES(a1)
+    REG_L tp, 2*REGBYTES(a1)
+    REG_L t0, 0(a1)
+    csrw satp, t0
+    sfence.vma
+
+    li s0, 0
+    tail secondary_main
+
+.balign REGBYTES
+kernel_offset:
+.if REGBYTES == 8
+    .quad KERNEL_OFFSET
+.else
+    .word KERNEL_OFFSET
+.endif
+
+# The boot page table, replaced by the kernel page table once Rust code has
+# set it up. It maps physical memory with RWX leaves both at address 0, for
+# the jump above, and at KERNEL_OFFSET.
+.section .data
+.balign 4096
+boot_page_table:
+.if REGBYTES == 8
+    # Sv39: the first 4 GiB, in gigapages, at 0 and at KERNEL_OFFSET.
+    .rept 2
+    .quad (0x00000000 >> 2) | 0xcf
+    .quad (0x40000000 >> 2) | 0xcf
+    .quad (0x80000000 >> 2) | 0xcf
+    .quad (0xc0000000 >> 2) | 0xcf
+    .zero 8 * 252
+    .endr
+.else
+    # Sv32: in 4 MiB megapages, the 1 GiB below KERNEL_OFFSET (which is
+    # 1 GiB) at 0, and the 3 GiB above it from 0, so the image has to be
+    # loaded in the first 1 GiB.
+    .set paddr, 0
+    .rept 256
+    .word (paddr >> 2) | 0xcf
+    .set paddr, paddr + (1 << 22)
+    .endr
+    .set paddr, 0
+    .rept 768
+    .word (paddr >> 2) | 0xcf
+    .set paddr, paddr + (1 << 22)
+    .endr
+.endif
@@ -0,0 +1,430 @@
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::future::poll_fn;
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::{Poll, Waker};
+
+use crate::{ktest, task, trap, watchdog};
+
+/// A mutual exclusion lock which busy-waits until it's available.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the lock ensures only one hart can access `value` at a time.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            while self.locked.load(Ordering::Relaxed) {
+                watchdog::spinning(self as *const Self as *const ());
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Holding the lock disables preemption on this hart.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        task::preempt_disable();
+        let locked = self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+        if !locked {
+            task::preempt_enable();
+            return None;
+        }
+        Some(SpinLockGuard { lock: self })
+    }
+
+    /// Releases the lock, whoever holds it. This hart's preemption count is
+    /// left alone.
+    ///
+    /// SAFETY: the holder must never touch the value again, e.g. because it
+    /// has been halted or has forgotten its guard.
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: we hold the lock
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: we hold the lock
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        task::preempt_enable();
+    }
+}
+
+/// A `SpinLock` that also disables interrupts on this hart while held, for
+/// data shared with interrupt handlers. Otherwise a handler could spin
+/// forever on a lock held by the code it interrupted.
+pub struct SpinLockIrqSave<T> {
+    lock: SpinLock<T>,
+}
+
+impl<T> SpinLockIrqSave<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            lock: SpinLock::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockIrqSaveGuard<'_, T> {
+        let enabled = trap::disable_interrupts();
+        SpinLockIrqSaveGuard {
+            guard: ManuallyDrop::new(self.lock.lock()),
+            enabled,
+        }
+    }
+
+    /// See `SpinLock::force_unlock`.
+    pub unsafe fn force_unlock(&self) {
+        self.lock.force_unlock();
+    }
+}
+
+pub struct SpinLockIrqSaveGuard<'a, T> {
+    guard: ManuallyDrop<SpinLockGuard<'a, T>>,
+    /// Whether interrupts were enabled before locking.
+    enabled: bool,
+}
+
+impl<T> Deref for SpinLockIrqSaveGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for SpinLockIrqSaveGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for SpinLockIrqSaveGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: the guard isn't used again. It must be dropped before
+        // interrupts are restored.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        trap::restore_interrupts(self.enabled);
+    }
+}
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value that is initialized at most once, usable in statics.
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// SAFETY: the value is written once, before `state` becomes COMPLETE, and is
+// only shared after that.
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the value, initializing it with `f` if this is the first call.
+    /// Concurrent callers spin until the first call's `f` has returned.
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                // SAFETY: we're the only one to get RUNNING.
+                unsafe { (*self.value.get()).write(f()) };
+                self.state.store(COMPLETE, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != COMPLETE {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        // SAFETY: the value is initialized.
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// Returns the value if it has been initialized.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == COMPLETE {
+            // SAFETY: the value is initialized.
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            // SAFETY: the value is initialized, and never used again.
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// Tasks, or futures, waiting for a condition to become true. Whoever
+/// makes it true calls `wake_one` or `wake_all`, which may be done from
+/// interrupt context.
+pub struct WaitQueue {
+    waiters: SpinLock<VecDeque<Waiter>>,
+}
+
+enum Waiter {
+    Task(task::Blocked),
+    Future(Waker),
+}
+
+impl Waiter {
+    fn wake(self) {
+        match self {
+            Self::Task(task) => task::wake(task),
+            Self::Future(waker) => waker.wake(),
+        }
+    }
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: SpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks until `cond` returns true. `cond` is checked with interrupts
+    /// disabled and must not use this queue, but since it's checked before
+    /// each wait a wakeup between the check and blocking isn't lost.
+    pub fn wait_until(&self, mut cond: impl FnMut() -> bool) {
+        loop {
+            // Interrupts stay disabled until this task is queued, so an
+            // interrupt handler can't try to wake it halfway.
+            let enabled = trap::disable_interrupts();
+            let mut waiters = self.waiters.lock();
+            if cond() {
+                drop(waiters);
+                trap::restore_interrupts(enabled);
+                return;
+            }
+            task::block(move |task| waiters.push_back(Waiter::Task(task)));
+            trap::restore_interrupts(enabled);
+        }
+    }
+
+    /// `wait_until` for a future, which is woken to check `cond` again
+    /// rather than blocking the task that polls it.
+    pub async fn until(&self, mut cond: impl FnMut() -> bool) {
+        poll_fn(|cx| {
+            let enabled = trap::disable_interrupts();
+            let mut waiters = self.waiters.lock();
+            let ready = cond();
+            if !ready {
+                waiters.push_back(Waiter::Future(cx.waker().clone()));
+            }
+            drop(waiters);
+            trap::restore_interrupts(enabled);
+            if ready {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Wakes whatever has waited longest, if anything.
+    pub fn wake_one(&self) {
+        let enabled = trap::disable_interrupts();
+        let waiter = self.waiters.lock().pop_front();
+        trap::restore_interrupts(enabled);
+        if let Some(waiter) = waiter {
+            waiter.wake();
+        }
+    }
+
+    /// Wakes everything waiting.
+    pub fn wake_all(&self) {
+        let enabled = trap::disable_interrupts();
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        trap::restore_interrupts(enabled);
+        for waiter in waiters {
+            waiter.wake();
+        }
+    }
+}
+
+/// A mutual exclusion lock which blocks the task until it's available. It
+/// must not be used from interrupt context.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the lock ensures only one task can access `value` at a time.
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            self.waiters
+                .wait_until(|| !self.locked.load(Ordering::Relaxed));
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { lock: self })
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: we hold the lock
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: we hold the lock
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        self.lock.waiters.wake_one();
+    }
+}
+
+ktest! {
+    fn once_runs_once() {
+        let once = Once::new();
+        assert_eq!(once.get(), None);
+        assert_eq!(*once.call_once(|| 1), 1);
+        assert_eq!(*once.call_once(|| 2), 1);
+        assert_eq!(once.get(), Some(&1));
+    }
+}
+
+ktest! {
+    fn spinlocks_exclude() {
+        let lock = SpinLock::new(1);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.try_lock().is_none());
+        }
+        assert_eq!(*lock.try_lock().unwrap(), 2);
+
+        let irq_lock = SpinLockIrqSave::new(0);
+        let enabled = trap::disable_interrupts();
+        trap::restore_interrupts(enabled);
+        {
+            let mut guard = irq_lock.lock();
+            *guard += 1;
+            assert!(!trap::disable_interrupts());
+            assert!(irq_lock.lock.try_lock().is_none());
+        }
+        // Interrupts are back as they were before locking.
+        assert_eq!(trap::disable_interrupts(), enabled);
+        trap::restore_interrupts(enabled);
+        assert_eq!(*irq_lock.lock(), 1);
+    }
+}
+
+ktest! {
+    fn blocking_locks_wait() {
+        use alloc::sync::Arc;
+        use core::sync::atomic::AtomicUsize;
+
+        use crate::percpu;
+
+        // Each task yields while holding the mutex, so the other blocks on
+        // it, and every increment still counts.
+        let count = Arc::new(Mutex::new(0));
+        let done = Arc::new((AtomicUsize::new(0), WaitQueue::new()));
+        for _ in 0..2 {
+            let (count, done) = (count.clone(), done.clone());
+            task::spawn_on(percpu::hart_id(), task::Priority::Normal, move || {
+                for _ in 0..3 {
+                    let mut count = count.lock();
+                    let seen = *count;
+                    task::yield_now();
+                    *count = seen + 1;
+                }
+                done.0.fetch_add(1, Ordering::Release);
+                done.1.wake_all();
+            })
+            .unwrap();
+        }
+        done.1.wait_until(|| done.0.load(Ordering::Acquire) == 2);
+        assert_eq!(*count.lock(), 6);
+    }
+}
@@ -0,0 +1,1430 @@
+//! System calls from U-mode. The numbers and calling convention follow
+//! Linux on RISC-V: the number in `a7`, arguments in `a0` to `a5`, and the
+//! result or a negated errno in `a0`.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::arch::csr::Counteren;
+use crate::elf::ElfError;
+use crate::file::{File, SeekFrom};
+use crate::fs::{self, VnodeKind};
+use crate::mm::addr_space::AddressSpace;
+use crate::mm::paging::{MapError, PteFlags, PAGE_SIZE};
+use crate::mm::shm::SharedMemory;
+use crate::mm::uaccess::{
+    copy_from_user, copy_to_user, read_user_cstr, read_user_struct, write_user_struct,
+    UserCopyError,
+};
+use crate::perf::{self, PerfEventAttr};
+use crate::process::{self, ExitStatus, Pid, Process, WaitOptions, WaitTarget};
+use crate::ptrace::{self, Resume};
+use crate::rlimit::{self, Limit, RLIMIT_AS, RLIMIT_NPROC, RLIM_INFINITY};
+use crate::signal::{self, Action, SigAction, NSIG, SIGCHLD, SIGPIPE};
+use crate::smp::{self, CpuMask};
+use crate::task::{AffinityError, Policy};
+use crate::time::{self, Duration, Instant};
+use crate::trap::{self, TrapFrame};
+use crate::{ktest, log_warn, mqueue, pipe, task, user};
+
+const SYS_DUP: usize = 23;
+const SYS_DUP3: usize = 24;
+const SYS_IOCTL: usize = 29;
+const SYS_MKDIRAT: usize = 34;
+const SYS_UNLINKAT: usize = 35;
+const SYS_FTRUNCATE: usize = 46;
+const SYS_OPENAT: usize = 56;
+const SYS_CLOSE: usize = 57;
+const SYS_PIPE2: usize = 59;
+const SYS_GETDENTS64: usize = 61;
+const SYS_LSEEK: usize = 62;
+const SYS_READ: usize = 63;
+const SYS_WRITE: usize = 64;
+const SYS_SYNC: usize = 81;
+const SYS_FSYNC: usize = 82;
+const SYS_FDATASYNC: usize = 83;
+const SYS_EXIT: usize = 93;
+const SYS_EXIT_GROUP: usize = 94;
+const SYS_NANOSLEEP: usize = 101;
+const SYS_CLOCK_GETTIME: usize = 113;
+const SYS_CLOCK_GETRES: usize = 114;
+const SYS_CLOCK_NANOSLEEP: usize = 115;
+const SYS_PTRACE: usize = 117;
+const SYS_SCHED_SETSCHEDULER: usize = 119;
+const SYS_SCHED_GETSCHEDULER: usize = 120;
+const SYS_SCHED_GETPARAM: usize = 121;
+const SYS_SCHED_SETAFFINITY: usize = 122;
+const SYS_SCHED_GETAFFINITY: usize = 123;
+const SYS_SCHED_YIELD: usize = 124;
+const SYS_SCHED_GET_PRIORITY_MAX: usize = 125;
+const SYS_SCHED_GET_PRIORITY_MIN: usize = 126;
+const SYS_SCHED_RR_GET_INTERVAL: usize = 127;
+const SYS_KILL: usize = 129;
+const SYS_RT_SIGACTION: usize = 134;
+const SYS_RT_SIGPROCMASK: usize = 135;
+const SYS_RT_SIGRETURN: usize = 139;
+const SYS_SETPRIORITY: usize = 140;
+const SYS_GETPRIORITY: usize = 141;
+const SYS_SETPGID: usize = 154;
+const SYS_GETPGID: usize = 155;
+const SYS_GETSID: usize = 156;
+const SYS_SETSID: usize = 157;
+const SYS_GETRLIMIT: usize = 163;
+const SYS_SETRLIMIT: usize = 164;
+const SYS_PRCTL: usize = 167;
+const SYS_GETPID: usize = 172;
+const SYS_GETPPID: usize = 173;
+const SYS_MQ_OPEN: usize = 180;
+const SYS_MQ_UNLINK: usize = 181;
+const SYS_MQ_TIMEDSEND: usize = 182;
+const SYS_MQ_TIMEDRECEIVE: usize = 183;
+const SYS_MQ_GETSETATTR: usize = 185;
+const SYS_BRK: usize = 214;
+const SYS_MUNMAP: usize = 215;
+const SYS_CLONE: usize = 220;
+const SYS_EXECVE: usize = 221;
+const SYS_MMAP: usize = 222;
+const SYS_MSYNC: usize = 227;
+const SYS_PERF_EVENT_OPEN: usize = 241;
+const SYS_WAIT4: usize = 260;
+const SYS_PRLIMIT64: usize = 261;
+
+/// Scheduling policies.
+const SCHED_OTHER: usize = 0;
+const SCHED_FIFO: usize = 1;
+const SCHED_RR: usize = 2;
+
+/// `setpriority` and `getpriority`'s `which` for a process.
+const PRIO_PROCESS: usize = 0;
+
+/// `rt_sigprocmask` operations on the blocked signals.
+const SIG_BLOCK: usize = 0;
+const SIG_UNBLOCK: usize = 1;
+const SIG_SETMASK: usize = 2;
+
+/// `ptrace` requests.
+const PTRACE_TRACEME: usize = 0;
+const PTRACE_PEEKTEXT: usize = 1;
+const PTRACE_PEEKDATA: usize = 2;
+const PTRACE_POKETEXT: usize = 4;
+const PTRACE_POKEDATA: usize = 5;
+const PTRACE_CONT: usize = 7;
+const PTRACE_KILL: usize = 8;
+const PTRACE_SINGLESTEP: usize = 9;
+const PTRACE_ATTACH: usize = 16;
+const PTRACE_DETACH: usize = 17;
+const PTRACE_GETREGSET: usize = 0x4204;
+const PTRACE_SETREGSET: usize = 0x4205;
+
+/// The register set `PTRACE_GETREGSET` reads for the general registers.
+const NT_PRSTATUS: usize = 1;
+
+/// `prctl` options to let the process read `cycle` and `instret`, or not.
+const PR_TASK_PERF_EVENTS_DISABLE: usize = 31;
+const PR_TASK_PERF_EVENTS_ENABLE: usize = 32;
+
+/// `wait4` options: return 0 rather than block if no child has exited,
+/// and report children stopping for job control, and being continued.
+const WNOHANG: usize = 1;
+const WUNTRACED: usize = 2;
+const WCONTINUED: usize = 8;
+
+/// Longest path accepted by a syscall, including the NUL.
+const PATH_MAX: usize = 4096;
+
+/// The `dirfd` meaning paths are relative to the working directory, which
+/// is always the root.
+const AT_FDCWD: isize = -100;
+
+/// `unlinkat` removes a directory rather than a file.
+const AT_REMOVEDIR: usize = 0x200;
+
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+const SEEK_END: usize = 2;
+
+/// `d_type`s of the entries returned by `getdents64`.
+const DT_CHR: u8 = 2;
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+
+const PROT_READ: usize = 1;
+const PROT_WRITE: usize = 2;
+const PROT_EXEC: usize = 4;
+
+const MAP_SHARED: usize = 0x01;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+const MS_ASYNC: usize = 1;
+const MS_INVALIDATE: usize = 2;
+const MS_SYNC: usize = 4;
+
+/// An error returned to U-mode, by its Linux number.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Errno(isize);
+
+impl Errno {
+    pub const EPERM: Self = Self(1);
+    pub const ENOENT: Self = Self(2);
+    pub const ESRCH: Self = Self(3);
+    pub const EINTR: Self = Self(4);
+    pub const EIO: Self = Self(5);
+    pub const E2BIG: Self = Self(7);
+    pub const ENOEXEC: Self = Self(8);
+    pub const EBADF: Self = Self(9);
+    pub const ECHILD: Self = Self(10);
+    pub const EAGAIN: Self = Self(11);
+    pub const ENOMEM: Self = Self(12);
+    pub const EACCES: Self = Self(13);
+    pub const EFAULT: Self = Self(14);
+    pub const EBUSY: Self = Self(16);
+    pub const EEXIST: Self = Self(17);
+    pub const ENODEV: Self = Self(19);
+    pub const ENOTDIR: Self = Self(20);
+    pub const EISDIR: Self = Self(21);
+    pub const EINVAL: Self = Self(22);
+    pub const EMFILE: Self = Self(24);
+    pub const ENOTTY: Self = Self(25);
+    pub const EFBIG: Self = Self(27);
+    pub const ENOSPC: Self = Self(28);
+    pub const ESPIPE: Self = Self(29);
+    pub const EROFS: Self = Self(30);
+    pub const EPIPE: Self = Self(32);
+    pub const ENAMETOOLONG: Self = Self(36);
+    pub const ENOSYS: Self = Self(38);
+    pub const ENOTEMPTY: Self = Self(39);
+    pub const EMSGSIZE: Self = Self(90);
+    pub const ETIMEDOUT: Self = Self(110);
+
+    /// The error with Linux number `errno`, as a 9P server reports it.
+    pub fn from_raw(errno: u32) -> Self {
+        Self(errno as isize)
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match *self {
+            Self::EPERM => "operation not permitted",
+            Self::ENOENT => "no such file or directory",
+            Self::ESRCH => "no such process",
+            Self::EINTR => "interrupted",
+            Self::EIO => "I/O error",
+            Self::E2BIG => "argument list too long",
+            Self::ENOEXEC => "not an executable",
+            Self::EBADF => "bad file descriptor",
+            Self::ECHILD => "no child processes",
+            Self::EAGAIN => "try again",
+            Self::ENOMEM => "out of memory",
+            Self::EACCES => "permission denied",
+            Self::EFAULT => "bad address",
+            Self::EBUSY => "busy",
+            Self::EEXIST => "file exists",
+            Self::ENODEV => "no such device",
+            Self::ENOTDIR => "not a directory",
+            Self::EISDIR => "is a directory",
+            Self::EINVAL => "invalid argument",
+            Self::EMFILE => "too many open files",
+            Self::ENOTTY => "not a terminal",
+            Self::EFBIG => "file too large",
+            Self::ENOSPC => "no space left on device",
+            Self::ESPIPE => "not seekable",
+            Self::EROFS => "read-only filesystem",
+            Self::EPIPE => "broken pipe",
+            Self::ENAMETOOLONG => "name too long",
+            Self::ENOSYS => "not implemented",
+            Self::ENOTEMPTY => "directory not empty",
+            Self::EMSGSIZE => "message too long",
+            Self::ETIMEDOUT => "timed out",
+            Self(errno) => return write!(f, "error {}", errno),
+        };
+        f.write_str(message)
+    }
+}
+
+impl From<UserCopyError> for Errno {
+    fn from(err: UserCopyError) -> Self {
+        match err {
+            UserCopyError::Fault => Self::EFAULT,
+            UserCopyError::TooLong => Self::ENAMETOOLONG,
+        }
+    }
+}
+
+type SyscallResult = Result<usize, Errno>;
+
+/// Called by the trap handler on an `ecall` from U-mode.
+pub fn handle(frame: &mut TrapFrame) {
+    // Return past the `ecall`.
+    frame.sepc += 4;
+    // SAFETY: the user context is saved, and the trap vector handles traps
+    // taken from here.
+    unsafe { trap::enable_interrupts() };
+
+    let args = [
+        frame.regs[10],
+        frame.regs[11],
+        frame.regs[12],
+        frame.regs[13],
+        frame.regs[14],
+        frame.regs[15],
+    ];
+    let result = match frame.regs[17] {
+        SYS_DUP => dup(args[0]),
+        SYS_DUP3 => dup3(args[0], args[1], args[2]),
+        SYS_IOCTL => file(args[0]).and_then(|file| file.ioctl(args[1], args[2])),
+        SYS_MKDIRAT => mkdirat(args[0] as isize, args[1]),
+        SYS_UNLINKAT => unlinkat(args[0] as isize, args[1], args[2]),
+        SYS_FTRUNCATE => file(args[0])
+            .and_then(|file| file.truncate(args[1] as u64))
+            .map(|()| 0),
+        SYS_OPENAT => openat(args[0] as isize, args[1], args[2]),
+        SYS_CLOSE => close(args[0]),
+        SYS_PIPE2 => pipe2(args[0], args[1]),
+        SYS_GETDENTS64 => getdents64(args[0], args[1], args[2]),
+        SYS_LSEEK => lseek(args[0], args[1] as i64, args[2]),
+        SYS_READ => read(args[0], args[1], args[2]),
+        SYS_WRITE => write(args[0], args[1], args[2]),
+        SYS_SYNC => {
+            // Linux's can't fail, so neither does this.
+            if let Err(err) = fs::sync_all() {
+                log_warn!("sync: {}", err);
+            }
+            Ok(0)
+        }
+        // A file's entry is written with its data either way.
+        SYS_FSYNC | SYS_FDATASYNC => file(args[0]).and_then(|file| file.sync()).map(|()| 0),
+        // Processes only have one thread, so these are the same.
+        SYS_EXIT | SYS_EXIT_GROUP => process::exit_thread(ExitStatus::Exited(args[0] as u8)),
+        SYS_NANOSLEEP => nanosleep(args[0], args[1]),
+        SYS_CLOCK_GETTIME => clock_gettime(args[0], args[1]),
+        SYS_CLOCK_GETRES => clock_getres(args[0], args[1]),
+        SYS_CLOCK_NANOSLEEP => clock_nanosleep(args[0], args[1], args[2], args[3]),
+        SYS_PTRACE => ptrace(args[0], args[1], args[2], args[3]),
+        SYS_SCHED_SETSCHEDULER => sched_setscheduler(args[0], args[1], args[2]),
+        SYS_SCHED_GETSCHEDULER => sched_getscheduler(args[0]),
+        SYS_SCHED_GETPARAM => sched_getparam(args[0], args[1]),
+        SYS_SCHED_SETAFFINITY => sched_setaffinity(args[0], args[1], args[2]),
+        SYS_SCHED_GETAFFINITY => sched_getaffinity(args[0], args[1], args[2]),
+        SYS_SCHED_YIELD => {
+            task::yield_now();
+            Ok(0)
+        }
+        SYS_SCHED_GET_PRIORITY_MAX => sched_priority_range(args[0]).map(|(_, max)| max),
+        SYS_SCHED_GET_PRIORITY_MIN => sched_priority_range(args[0]).map(|(min, _)| min),
+        SYS_SCHED_RR_GET_INTERVAL => sched_rr_get_interval(args[0], args[1]),
+        SYS_KILL => kill(args[0] as isize, args[1]),
+        SYS_RT_SIGACTION => sigaction(args[0], args[1], args[2], args[3]),
+        SYS_RT_SIGPROCMASK => sigprocmask(args[0], args[1], args[2], args[3]),
+        SYS_RT_SIGRETURN => signal::sigreturn(frame).map(|()| frame.regs[10]),
+        SYS_SETPRIORITY => setpriority(args[0], args[1], args[2] as isize),
+        SYS_GETPRIORITY => getpriority(args[0], args[1]),
+        SYS_SETPGID => setpgid(args[0], args[1] as isize),
+        SYS_GETPGID => process_or_current(args[0]).map(|process| process.pgid().as_raw()),
+        SYS_GETSID => process_or_current(args[0]).map(|process| process.sid().as_raw()),
+        SYS_SETSID => setsid(),
+        SYS_GETRLIMIT => getrlimit(args[0], args[1]),
+        SYS_SETRLIMIT => setrlimit(args[0], args[1]),
+        SYS_PRCTL => prctl(args[0], args[1]),
+        SYS_MQ_OPEN => mq_open(args[0], args[1], args[3]),
+        SYS_MQ_UNLINK => mq_unlink(args[0]),
+        SYS_MQ_TIMEDSEND => mq_timedsend(args[0], args[1], args[2], args[3], args[4]),
+        SYS_MQ_TIMEDRECEIVE => mq_timedreceive(args[0], args[1], args[2], args[3], args[4]),
+        SYS_MQ_GETSETATTR => mq_getsetattr(args[0], args[1], args[2]),
+        SYS_GETPID => Ok(current_process().pid().as_raw()),
+        SYS_GETPPID => Ok(current_process()
+            .parent()
+            .map_or(0, |parent| parent.pid().as_raw())),
+        SYS_BRK => Ok(current_address_space().set_brk(args[0])),
+        SYS_MUNMAP => munmap(args[0], args[1]),
+        SYS_CLONE => clone(frame, args[0], args[1]),
+        SYS_MMAP => mmap(
+            args[0],
+            args[1],
+            args[2],
+            args[3],
+            args[4] as isize,
+            args[5],
+        ),
+        SYS_MSYNC => msync(args[0], args[1], args[2]),
+        SYS_EXECVE => execve(frame, args[0]),
+        SYS_PERF_EVENT_OPEN => perf_event_open(
+            args[0],
+            args[1] as isize,
+            args[2] as isize,
+            args[3] as isize,
+            args[4],
+        ),
+        SYS_WAIT4 => wait4(args[0] as isize, args[1], args[2]),
+        SYS_PRLIMIT64 => prlimit64(args[0], args[1], args[2], args[3]),
+        _ => Err(Errno::ENOSYS),
+    };
+    frame.regs[10] = match result {
+        Ok(value) => value,
+        Err(errno) => (-errno.0) as usize,
+    };
+}
+
+fn current_process() -> Arc<Process> {
+    // Only processes' threads run in U-mode.
+    task::process().expect("syscall from a task without a process")
+}
+
+fn current_address_space() -> Arc<AddressSpace> {
+    task::address_space().expect("syscall from a task without an address space")
+}
+
+/// The file open at `fd` in the running process.
+fn file(fd: usize) -> Result<Arc<dyn File>, Errno> {
+    current_process().file(fd).ok_or(Errno::EBADF)
+}
+
+fn dup(fd: usize) -> SyscallResult {
+    let process = current_process();
+    let mut files = process.files();
+    let file = files.get(fd).ok_or(Errno::EBADF)?;
+    files.insert(file).ok_or(Errno::EMFILE)
+}
+
+/// `flags` may only be 0: descriptors aren't closed on exec.
+fn dup3(old: usize, new: usize, flags: usize) -> SyscallResult {
+    if flags != 0 || old == new {
+        return Err(Errno::EINVAL);
+    }
+    let process = current_process();
+    let mut files = process.files();
+    let file = files.get(old).ok_or(Errno::EBADF)?;
+    let replaced = files.insert_at(new, file).map_err(|()| Errno::EBADF)?;
+    // The replaced file is closed once the table is unlocked.
+    drop(files);
+    drop(replaced);
+    Ok(new)
+}
+
+/// Reads the path at `path`, which `dirfd` must leave relative to the root.
+fn read_path(dirfd: isize, path: usize) -> Result<String, Errno> {
+    let path = read_user_cstr(path, PATH_MAX - 1)?;
+    if dirfd != AT_FDCWD && !path.starts_with('/') {
+        // Paths relative to an open directory aren't supported.
+        return Err(Errno::EINVAL);
+    }
+    Ok(path)
+}
+
+/// The file mode is ignored, as files have no permissions.
+fn openat(dirfd: isize, path: usize, flags: usize) -> SyscallResult {
+    let path = read_path(dirfd, path)?;
+    let file = fs::open(&path, flags as u32)?;
+    current_process().files().insert(file).ok_or(Errno::EMFILE)
+}
+
+fn mkdirat(dirfd: isize, path: usize) -> SyscallResult {
+    let path = read_path(dirfd, path)?;
+    fs::mkdir(&path)?;
+    Ok(0)
+}
+
+fn unlinkat(dirfd: isize, path: usize, flags: usize) -> SyscallResult {
+    if flags & !AT_REMOVEDIR != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let path = read_path(dirfd, path)?;
+    fs::remove(&path, flags & AT_REMOVEDIR != 0)?;
+    Ok(0)
+}
+
+fn close(fd: usize) -> SyscallResult {
+    let file = current_process().files().close(fd).ok_or(Errno::EBADF)?;
+    drop(file);
+    Ok(0)
+}
+
+/// `flags` may only be 0, as for `dup3`.
+fn pipe2(fds: usize, flags: usize) -> SyscallResult {
+    if flags != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let (read_end, write_end) = pipe::new();
+    let process = current_process();
+    let mut files = process.files();
+    let read_fd = files.insert(Arc::new(read_end)).ok_or(Errno::EMFILE)?;
+    let Some(write_fd) = files.insert(Arc::new(write_end)) else {
+        files.close(read_fd);
+        return Err(Errno::EMFILE);
+    };
+    drop(files);
+    let written = write_user_struct(fds, &[read_fd as i32, write_fd as i32]);
+    if written.is_err() {
+        let mut files = process.files();
+        files.close(read_fd);
+        files.close(write_fd);
+    }
+    written?;
+    Ok(0)
+}
+
+/// Files are read and written through a kernel buffer of this size.
+const CHUNK_SIZE: usize = 128;
+
+fn read(fd: usize, buf: usize, len: usize) -> SyscallResult {
+    let file = file(fd)?;
+    let mut chunk = [0; CHUNK_SIZE];
+    let n = file.read(&mut chunk[..len.min(CHUNK_SIZE)])?;
+    copy_to_user(buf, &chunk[..n])?;
+    Ok(n)
+}
+
+/// Writes in chunks. A chunk that faults or fails after others have been
+/// written ends the write short rather than failing it, as what was written
+/// can't be taken back.
+fn write(fd: usize, buf: usize, len: usize) -> SyscallResult {
+    let file = file(fd)?;
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut written = 0;
+    while written < len {
+        let n = CHUNK_SIZE.min(len - written);
+        let done = copy_from_user(&mut chunk[..n], buf + written)
+            .map_err(Errno::from)
+            .and_then(|()| file.write(&chunk[..n]));
+        let done = match done {
+            Ok(done) => done,
+            Err(_) if written > 0 => break,
+            Err(err) => {
+                if err == Errno::EPIPE {
+                    current_process().send_signal(SIGPIPE);
+                }
+                return Err(err);
+            }
+        };
+        written += done;
+        if done < n {
+            break;
+        }
+    }
+    Ok(written)
+}
+
+fn lseek(fd: usize, offset: i64, whence: usize) -> SyscallResult {
+    let pos = match whence {
+        SEEK_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| Errno::EINVAL)?),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return Err(Errno::EINVAL),
+    };
+    Ok(file(fd)?.seek(pos)? as usize)
+}
+
+/// Entries are gathered in a kernel buffer of at most this size.
+const DIRENT_BUF_SIZE: usize = 4096;
+
+/// Fills `buf` with `struct linux_dirent64`s: the inode number, the offset
+/// of the next entry, the record's length, the type and the NUL-terminated
+/// name, padded to 8 bytes.
+fn getdents64(fd: usize, buf: usize, len: usize) -> SyscallResult {
+    let file = file(fd)?;
+    let len = len.min(DIRENT_BUF_SIZE);
+    // The offset of a directory counts its entries.
+    let mut offset = file.seek(SeekFrom::Current(0))?;
+    let mut records = Vec::new();
+    file.read_dir(&mut |entry| {
+        let reclen = (19 + entry.name.len() + 1).next_multiple_of(8);
+        if records.len() + reclen > len {
+            return false;
+        }
+        offset += 1;
+        let d_type = match entry.kind {
+            VnodeKind::File => DT_REG,
+            VnodeKind::Directory => DT_DIR,
+            VnodeKind::Device => DT_CHR,
+        };
+        let start = records.len();
+        // There are no inode numbers, so entries are just numbered.
+        records.extend_from_slice(&offset.to_ne_bytes());
+        records.extend_from_slice(&offset.to_ne_bytes());
+        records.extend_from_slice(&(reclen as u16).to_ne_bytes());
+        records.push(d_type);
+        records.extend_from_slice(entry.name.as_bytes());
+        records.resize(start + reclen, 0);
+        true
+    })?;
+    if records.is_empty() {
+        // Either the directory has been read to the end, or the first entry
+        // didn't fit.
+        let mut more = false;
+        file.read_dir(&mut |_| {
+            more = true;
+            false
+        })?;
+        if more {
+            return Err(Errno::EINVAL);
+        }
+    }
+    copy_to_user(buf, &records)?;
+    Ok(records.len())
+}
+
+/// `struct timespec`: seconds and nanoseconds.
+type Timespec = [i64; 2];
+
+fn to_timespec(duration: Duration) -> Timespec {
+    [
+        duration.as_secs() as i64,
+        i64::from(duration.subsec_nanos()),
+    ]
+}
+
+/// Sleeps for the time in `req` on the monotonic clock, as
+/// `clock_nanosleep` does.
+fn nanosleep(req: usize, rem: usize) -> SyscallResult {
+    clock_nanosleep(CLOCK_MONOTONIC, 0, req, rem)
+}
+
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+const CLOCK_MONOTONIC_RAW: usize = 4;
+const CLOCK_REALTIME_COARSE: usize = 5;
+const CLOCK_MONOTONIC_COARSE: usize = 6;
+const CLOCK_BOOTTIME: usize = 7;
+
+/// `clock_nanosleep`'s flag for `req` being a time on the clock rather than
+/// a duration.
+const TIMER_ABSTIME: usize = 1;
+
+/// The time on `clock`. The monotonic clocks are all the same, as nothing
+/// adjusts them and the machine never suspends.
+fn clock_now(clock: usize) -> Result<Duration, Errno> {
+    match clock {
+        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => Ok(time::realtime()),
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => {
+            Ok(Instant::now().since_boot())
+        }
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+fn clock_gettime(clock: usize, tp: usize) -> SyscallResult {
+    let now = clock_now(clock)?;
+    write_user_struct(tp, &to_timespec(now))?;
+    Ok(0)
+}
+
+/// Every clock counts ticks of the `time` CSR.
+fn clock_getres(clock: usize, res: usize) -> SyscallResult {
+    clock_now(clock)?;
+    if res != 0 {
+        let nanos = 1_000_000_000u64.div_ceil(time::frequency());
+        write_user_struct(res, &to_timespec(Duration::from_nanos(nanos)))?;
+    }
+    Ok(0)
+}
+
+/// Sleeps for the time in `req`, or until it on `clock` with
+/// `TIMER_ABSTIME`. A signal the process will handle ends the sleep early,
+/// with the time left written to `rem` if it isn't null and the sleep was
+/// relative. An absolute sleep on the realtime clock isn't moved if the
+/// clock is set meanwhile.
+fn clock_nanosleep(clock: usize, flags: usize, req: usize, rem: usize) -> SyscallResult {
+    let now = clock_now(clock)?;
+    let [secs, nanos]: Timespec = read_user_struct(req)?;
+    if secs < 0 || !(0..1_000_000_000).contains(&nanos) {
+        return Err(Errno::EINVAL);
+    }
+    let request = Duration::new(secs as u64, nanos as u32);
+    let absolute = flags & TIMER_ABSTIME != 0;
+    let duration = if absolute {
+        request.saturating_sub(now)
+    } else {
+        request
+    };
+    let deadline = Instant::now() + duration;
+    if current_process().sleep_until(deadline) {
+        return Ok(0);
+    }
+    if rem != 0 && !absolute {
+        write_user_struct(rem, &to_timespec(deadline - Instant::now()))?;
+    }
+    Err(Errno::EINTR)
+}
+
+/// The priorities `policy` takes.
+fn sched_priority_range(policy: usize) -> Result<(usize, usize), Errno> {
+    match policy {
+        SCHED_OTHER => Ok((0, 0)),
+        SCHED_FIFO | SCHED_RR => Ok((task::RT_PRIORITY_MIN.into(), task::RT_PRIORITY_MAX.into())),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// Sets the calling process's thread's policy, with the `int` priority at
+/// `param`, which may not be above the process's `RLIMIT_RTPRIO`.
+fn sched_setscheduler(pid: usize, policy: usize, param: usize) -> SyscallResult {
+    check_sched_target(pid)?;
+    let (min, max) = sched_priority_range(policy)?;
+    let priority = read_user_struct::<i32>(param)?;
+    let priority = usize::try_from(priority).map_err(|_| Errno::EINVAL)?;
+    if !(min..=max).contains(&priority) {
+        return Err(Errno::EINVAL);
+    }
+    if priority > current_process().rt_ceiling().into() {
+        return Err(Errno::EPERM);
+    }
+    let priority = priority as u8;
+    task::set_policy(match policy {
+        SCHED_FIFO => Policy::Fifo(priority),
+        SCHED_RR => Policy::RoundRobin(priority),
+        _ => Policy::Fair,
+    });
+    Ok(0)
+}
+
+fn sched_getscheduler(pid: usize) -> SyscallResult {
+    check_sched_target(pid)?;
+    Ok(match task::policy() {
+        Some(Policy::Fifo(_)) => SCHED_FIFO,
+        Some(Policy::RoundRobin(_)) => SCHED_RR,
+        Some(Policy::Fair) | None => SCHED_OTHER,
+    })
+}
+
+/// Writes the real-time priority, or 0, as an `int`.
+fn sched_getparam(pid: usize, param: usize) -> SyscallResult {
+    check_sched_target(pid)?;
+    let priority = match task::policy() {
+        Some(Policy::Fifo(priority) | Policy::RoundRobin(priority)) => priority,
+        Some(Policy::Fair) | None => 0,
+    };
+    write_user_struct(param, &i32::from(priority))?;
+    Ok(0)
+}
+
+/// Writes the round-robin time slice, or zero for other policies.
+fn sched_rr_get_interval(pid: usize, interval: usize) -> SyscallResult {
+    check_sched_target(pid)?;
+    let slice = match task::policy() {
+        Some(Policy::RoundRobin(_)) => task::RT_TIME_SLICE,
+        _ => Duration::ZERO,
+    };
+    write_user_struct(interval, &to_timespec(slice))?;
+    Ok(0)
+}
+
+/// Checks that `pid` is 0 or the calling process's, as only its own
+/// thread's scheduling can be changed.
+fn check_sched_target(pid: usize) -> Result<(), Errno> {
+    if pid != 0 && pid != current_process().pid().as_raw() {
+        return Err(Errno::ESRCH);
+    }
+    Ok(())
+}
+
+/// The mask is a `usize` with a bit for each hart. A shorter one leaves the
+/// harts past it out; a longer one must leave the rest zero. Harts that
+/// aren't online are ignored, but at least one must be.
+fn sched_setaffinity(pid: usize, len: usize, mask: usize) -> SyscallResult {
+    check_sched_target(pid)?;
+    if len > PAGE_SIZE {
+        return Err(Errno::EINVAL);
+    }
+    let mut bytes = [0; size_of::<usize>()];
+    let known = len.min(bytes.len());
+    copy_from_user(&mut bytes[..known], mask)?;
+    let mut rest = vec![0; len - known];
+    copy_from_user(&mut rest, mask + known)?;
+    if rest.iter().any(|&byte| byte != 0) {
+        return Err(Errno::EINVAL);
+    }
+    let mask = CpuMask::from_bits(usize::from_ne_bytes(bytes));
+    if mask
+        .intersect(CpuMask::from_bits(smp::online_mask()))
+        .is_empty()
+    {
+        return Err(Errno::EINVAL);
+    }
+    task::set_affinity(mask).map_err(|err| match err {
+        AffinityError::Counting => Errno::EBUSY,
+        AffinityError::NoHarts | AffinityError::Pinned => Errno::EINVAL,
+    })?;
+    Ok(0)
+}
+
+/// Writes the mask as a `usize`, returning its size, as Linux's syscall
+/// does.
+fn sched_getaffinity(pid: usize, len: usize, mask: usize) -> SyscallResult {
+    check_sched_target(pid)?;
+    if len < size_of::<usize>() {
+        return Err(Errno::EINVAL);
+    }
+    let online = CpuMask::from_bits(smp::online_mask());
+    write_user_struct(mask, &task::affinity().intersect(online).bits())?;
+    Ok(size_of::<usize>())
+}
+
+/// Traces and controls a child process, as a debugger does, see `ptrace`.
+/// As with Linux's syscall, rather than libc's wrapper, a peek writes the
+/// word to `data`. Resuming takes the signal to deliver in `data`, and the
+/// register sets take a `struct iovec` there.
+fn ptrace(request: usize, pid: usize, addr: usize, data: usize) -> SyscallResult {
+    let pid = Pid::from_raw(pid);
+    let resume_signal = || match data {
+        0 => Ok(None),
+        signal => u8::try_from(signal)
+            .ok()
+            .filter(|&signal| signal <= NSIG)
+            .map(Some)
+            .ok_or(Errno::EIO),
+    };
+    match request {
+        PTRACE_TRACEME => ptrace::trace_me()?,
+        PTRACE_ATTACH => ptrace::attach(pid)?,
+        PTRACE_PEEKTEXT | PTRACE_PEEKDATA => {
+            write_user_struct(data, &ptrace::peek(pid, addr)?)?;
+        }
+        PTRACE_POKETEXT | PTRACE_POKEDATA => ptrace::poke(pid, addr, data)?,
+        PTRACE_GETREGSET | PTRACE_SETREGSET => {
+            if addr != NT_PRSTATUS {
+                return Err(Errno::EINVAL);
+            }
+            let [base, len]: [usize; 2] = read_user_struct(data)?;
+            let len = len.min(size_of::<ptrace::UserRegs>());
+            if request == PTRACE_GETREGSET {
+                let regs = ptrace::regs(pid)?;
+                let bytes: Vec<u8> = regs.iter().flat_map(|reg| reg.to_le_bytes()).collect();
+                copy_to_user(base, &bytes[..len])?;
+                // The length is updated to what was written.
+                write_user_struct(data + size_of::<usize>(), &len)?;
+            } else {
+                let mut regs = ptrace::regs(pid)?;
+                let mut bytes = vec![0; len];
+                copy_from_user(&mut bytes, base)?;
+                for (reg, chunk) in regs.iter_mut().zip(bytes.chunks_exact(size_of::<usize>())) {
+                    *reg = usize::from_le_bytes(chunk.try_into().unwrap());
+                }
+                ptrace::set_regs(pid, &regs)?;
+            }
+        }
+        PTRACE_CONT => ptrace::resume(pid, Resume::Continue(resume_signal()?))?,
+        PTRACE_SINGLESTEP => ptrace::resume(pid, Resume::Step(resume_signal()?))?,
+        PTRACE_DETACH => ptrace::resume(pid, Resume::Detach(resume_signal()?))?,
+        PTRACE_KILL => ptrace::resume(pid, Resume::Kill)?,
+        _ => return Err(Errno::EIO),
+    }
+    Ok(0)
+}
+
+/// Checks that `which` and `who` name the calling process, as no other's
+/// can be changed.
+fn check_prio_target(which: usize, who: usize) -> Result<(), Errno> {
+    if which != PRIO_PROCESS {
+        return Err(Errno::EINVAL);
+    }
+    if who != 0 && who != current_process().pid().as_raw() {
+        return Err(Errno::ESRCH);
+    }
+    Ok(())
+}
+
+/// Sets the nice value, clamped to the range, of the calling process's
+/// thread.
+fn setpriority(which: usize, who: usize, nice: isize) -> SyscallResult {
+    check_prio_target(which, who)?;
+    let nice = nice.clamp(task::NICE_MIN.into(), task::NICE_MAX.into()) as i8;
+    // A process's thread is always fair.
+    task::set_nice(nice);
+    Ok(0)
+}
+
+/// Returns 20 less the nice value, from 1 to 40, as Linux's syscall does so
+/// as not to be negative.
+fn getpriority(which: usize, who: usize) -> SyscallResult {
+    check_prio_target(which, who)?;
+    let nice = task::nice();
+    Ok((20 - isize::from(nice)) as usize)
+}
+
+/// Only the options for counters are supported. `time` can be read unless
+/// it's been denied by a parent; `cycle` and `instret` once enabled here.
+fn prctl(option: usize, _arg: usize) -> SyscallResult {
+    let counters = Counteren::CY | Counteren::IR;
+    match option {
+        PR_TASK_PERF_EVENTS_ENABLE => current_process().allow_counters(counters),
+        PR_TASK_PERF_EVENTS_DISABLE => current_process().deny_counters(counters),
+        _ => return Err(Errno::EINVAL),
+    }
+    task::update_counter_access();
+    Ok(0)
+}
+
+/// `struct mq_attr`: flags, the most messages, the biggest message, the
+/// messages queued, then padding.
+type MqAttr = [isize; 8];
+
+fn read_mq_name(name: usize) -> Result<String, Errno> {
+    Ok(read_user_cstr(name, fs::NAME_MAX + 1)?)
+}
+
+/// The message queue descriptor `fd`.
+fn mq_file(fd: usize) -> Result<Arc<dyn File>, Errno> {
+    let file = file(fd)?;
+    if file.message_queue().is_none() {
+        return Err(Errno::EBADF);
+    }
+    Ok(file)
+}
+
+/// The `CLOCK_REALTIME` deadline at `timeout`, as an `Instant`, or `None`
+/// for no deadline if `timeout` is null.
+fn realtime_deadline(timeout: usize) -> Result<Option<Instant>, Errno> {
+    if timeout == 0 {
+        return Ok(None);
+    }
+    let [secs, nanos]: Timespec = read_user_struct(timeout)?;
+    if secs < 0 || !(0..1_000_000_000).contains(&nanos) {
+        return Err(Errno::EINVAL);
+    }
+    let deadline = Duration::new(secs as u64, nanos as u32);
+    Ok(Some(
+        Instant::now() + deadline.saturating_sub(time::realtime()),
+    ))
+}
+
+/// The mode is ignored, as there are no users.
+fn mq_open(name: usize, flags: usize, attr: usize) -> SyscallResult {
+    let name = read_mq_name(name)?;
+    let flags = flags as u32;
+    let create = flags & fs::O_CREAT != 0;
+    let attr = if create && attr != 0 {
+        let [_, max_msgs, msg_size, ..]: MqAttr = read_user_struct(attr)?;
+        Some(mqueue::Attr {
+            max_msgs: usize::try_from(max_msgs).map_err(|_| Errno::EINVAL)?,
+            msg_size: usize::try_from(msg_size).map_err(|_| Errno::EINVAL)?,
+        })
+    } else {
+        None
+    };
+    let (readable, writable) = match flags & fs::O_ACCMODE {
+        fs::O_RDONLY => (true, false),
+        fs::O_WRONLY => (false, true),
+        fs::O_RDWR => (true, true),
+        _ => return Err(Errno::EINVAL),
+    };
+    let queue = mqueue::open(&name, create, flags & fs::O_EXCL != 0, attr)?;
+    let nonblocking = flags & fs::O_NONBLOCK != 0;
+    let descriptor = mqueue::Descriptor::new(queue, readable, writable, nonblocking);
+    current_process()
+        .files()
+        .insert(Arc::new(descriptor))
+        .ok_or(Errno::EMFILE)
+}
+
+fn mq_unlink(name: usize) -> SyscallResult {
+    mqueue::unlink(&read_mq_name(name)?)?;
+    Ok(0)
+}
+
+fn mq_timedsend(
+    fd: usize,
+    msg: usize,
+    len: usize,
+    priority: usize,
+    timeout: usize,
+) -> SyscallResult {
+    let file = mq_file(fd)?;
+    let descriptor = file.message_queue().unwrap();
+    if !descriptor.writable {
+        return Err(Errno::EBADF);
+    }
+    if len > descriptor.queue.attr().msg_size {
+        return Err(Errno::EMSGSIZE);
+    }
+    let mut data = vec![0; len];
+    copy_from_user(&mut data, msg)?;
+    let priority = u32::try_from(priority).map_err(|_| Errno::EINVAL)?;
+    let deadline = realtime_deadline(timeout)?;
+    descriptor
+        .queue
+        .send(&data, priority, !descriptor.is_nonblocking(), deadline)?;
+    Ok(0)
+}
+
+fn mq_timedreceive(
+    fd: usize,
+    msg: usize,
+    len: usize,
+    priority: usize,
+    timeout: usize,
+) -> SyscallResult {
+    let file = mq_file(fd)?;
+    let descriptor = file.message_queue().unwrap();
+    if !descriptor.readable {
+        return Err(Errno::EBADF);
+    }
+    let msg_size = descriptor.queue.attr().msg_size;
+    if len < msg_size {
+        return Err(Errno::EMSGSIZE);
+    }
+    let deadline = realtime_deadline(timeout)?;
+    let mut buf = vec![0; msg_size];
+    let (received, message_priority) =
+        descriptor
+            .queue
+            .receive(&mut buf, !descriptor.is_nonblocking(), deadline)?;
+    copy_to_user(msg, &buf[..received])?;
+    if priority != 0 {
+        write_user_struct(priority, &message_priority)?;
+    }
+    Ok(received)
+}
+
+/// Only `O_NONBLOCK` can be set.
+fn mq_getsetattr(fd: usize, new: usize, old: usize) -> SyscallResult {
+    let file = mq_file(fd)?;
+    let descriptor = file.message_queue().unwrap();
+    if old != 0 {
+        let attr = descriptor.queue.attr();
+        let flags = if descriptor.is_nonblocking() {
+            fs::O_NONBLOCK as isize
+        } else {
+            0
+        };
+        let current: MqAttr = [
+            flags,
+            attr.max_msgs as isize,
+            attr.msg_size as isize,
+            descriptor.queue.len() as isize,
+            0,
+            0,
+            0,
+            0,
+        ];
+        write_user_struct(old, &current)?;
+    }
+    if new != 0 {
+        let [flags, ..]: MqAttr = read_user_struct(new)?;
+        descriptor.set_nonblocking(flags as u32 & fs::O_NONBLOCK != 0);
+    }
+    Ok(0)
+}
+
+/// Only counts the calling thread's hardware events: `pid` must be 0 and
+/// `cpu` -1, with no group or flags.
+fn perf_event_open(
+    attr_addr: usize,
+    pid: isize,
+    cpu: isize,
+    group: isize,
+    flags: usize,
+) -> SyscallResult {
+    if pid != 0 || cpu != -1 || group != -1 || flags != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let attr: PerfEventAttr = read_user_struct(attr_addr)?;
+    // Newer, bigger versions of the struct are taken for this one, as long
+    // as what it doesn't have is zero, as Linux does.
+    let known = size_of::<PerfEventAttr>();
+    let size = attr.size as usize;
+    if size < known || size > PAGE_SIZE {
+        return Err(Errno::E2BIG);
+    }
+    let mut rest = vec![0; size - known];
+    copy_from_user(&mut rest, attr_addr + known)?;
+    if rest.iter().any(|&byte| byte != 0) {
+        return Err(Errno::E2BIG);
+    }
+    let counter = perf::open(&attr)?;
+    current_process()
+        .files()
+        .insert(counter)
+        .ok_or(Errno::EMFILE)
+}
+
+/// Only supports forking: `flags` must be `SIGCHLD`, the signal sent to the
+/// parent when the child exits, with no new stack.
+fn clone(frame: &TrapFrame, flags: usize, stack: usize) -> SyscallResult {
+    if flags != usize::from(SIGCHLD) || stack != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let process = current_process();
+    if process::thread_total() >= rlimit::to_usize(process.soft_limit(RLIMIT_NPROC)) {
+        return Err(Errno::EAGAIN);
+    }
+    let child = process.fork().ok_or(Errno::ENOMEM)?;
+    // The child returns from the same syscall, with 0.
+    let mut child_frame = frame.clone();
+    child_frame.regs[10] = 0;
+    // SAFETY: the child's address space is a copy of ours, running `frame`.
+    if unsafe { child.spawn_thread(child_frame, task::ext_state()) }.is_none() {
+        // Otherwise it would be a child that never exits.
+        process.forget_child(&child);
+        return Err(Errno::ENOMEM);
+    }
+    Ok(child.pid().as_raw())
+}
+
+/// Runs the executable at `path`, or the built-in program it names if
+/// there's no such file, with the arguments and environment ignored.
+/// Returns only on failure; otherwise `frame` is replaced to start the
+/// program.
+fn execve(frame: &mut TrapFrame, path: usize) -> SyscallResult {
+    let path = read_user_cstr(path, PATH_MAX - 1)?;
+    let file = match fs::read_to_end(&path) {
+        Ok(file) => Some(file),
+        Err(Errno::ENOENT) => None,
+        Err(err) => return Err(err),
+    };
+    let image = match &file {
+        Some(file) => file,
+        None => user::program(path.trim_start_matches('/')).ok_or(Errno::ENOENT)?,
+    };
+    let space = AddressSpace::new().ok_or(Errno::ENOMEM)?;
+    space.set_size_limit(rlimit::to_usize(current_process().soft_limit(RLIMIT_AS)));
+    let new_frame = user::load(&space, image).map_err(|err| match err {
+        ElfError::Map(MapError::OutOfMemory) => Errno::ENOMEM,
+        _ => Errno::ENOEXEC,
+    })?;
+
+    current_process().exec(Arc::new(space));
+    // The program starts without floating-point or vector registers.
+    task::set_ext_state(None);
+    let sstatus = frame.sstatus;
+    *frame = new_frame;
+    frame.sstatus = sstatus;
+    Ok(0)
+}
+
+fn wait4(pid: isize, status: usize, options: usize) -> SyscallResult {
+    if options & !(WNOHANG | WUNTRACED | WCONTINUED) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let process = current_process();
+    let target = match pid {
+        1.. => WaitTarget::Pid(Pid::from_raw(pid as usize)),
+        0 => WaitTarget::Group(process.pgid()),
+        -1 => WaitTarget::Any,
+        _ => WaitTarget::Group(Pid::from_raw(pid.unsigned_abs())),
+    };
+    let options = WaitOptions {
+        block: options & WNOHANG == 0,
+        stopped: options & WUNTRACED != 0,
+        continued: options & WCONTINUED != 0,
+    };
+    let Some((pid, exit)) = process.wait(target, options)? else {
+        return Ok(0);
+    };
+    if status != 0 {
+        write_user_struct(status, &exit.wait_status())?;
+    }
+    Ok(pid.as_raw())
+}
+
+/// Maps anonymous memory, or the file open at `fd` from `offset` through
+/// its page cache, private or shared. Without `MAP_FIXED` the address is
+/// only a hint, rounded down to a page.
+fn mmap(
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: isize,
+    offset: usize,
+) -> SyscallResult {
+    let sharing = flags & (MAP_SHARED | MAP_PRIVATE);
+    let anonymous = flags & MAP_ANONYMOUS != 0;
+    if len == 0
+        || flags & !(MAP_SHARED | MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS) != 0
+        || (sharing != MAP_SHARED && sharing != MAP_PRIVATE)
+        || (anonymous && fd != -1)
+        || !offset.is_multiple_of(PAGE_SIZE)
+    {
+        return Err(Errno::EINVAL);
+    }
+    let file_pages = if anonymous {
+        None
+    } else {
+        let file = usize::try_from(fd)
+            .map_err(|_| Errno::EBADF)
+            .and_then(file)?;
+        Some(file.mmap(sharing == MAP_SHARED && prot & PROT_WRITE != 0)?)
+    };
+    let len = len
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(Errno::ENOMEM)?;
+
+    let mut page_flags = PteFlags::empty();
+    // Pages can't be writable but not readable.
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        page_flags = page_flags | PteFlags::R;
+    }
+    if prot & PROT_WRITE != 0 {
+        page_flags = page_flags | PteFlags::W;
+    }
+    if prot & PROT_EXEC != 0 {
+        page_flags = page_flags | PteFlags::X;
+    }
+
+    let space = current_address_space();
+    let addr = if flags & MAP_FIXED != 0 {
+        space.unmap(addr, len).map_err(|_| Errno::EINVAL)?;
+        addr
+    } else {
+        // Like Linux, `addr` is a hint, taken if the page it's in is free.
+        let hint = (addr != 0).then_some(addr);
+        space
+            .find_free(len, user::MMAP_TOP, hint)
+            .ok_or(Errno::ENOMEM)?
+    };
+    let mapped = match file_pages {
+        Some(pages) => space.map_pages(
+            addr,
+            len,
+            page_flags,
+            pages,
+            offset / PAGE_SIZE,
+            sharing == MAP_PRIVATE,
+        ),
+        None if sharing == MAP_SHARED => {
+            space.map_shared(addr, len, page_flags, SharedMemory::new())
+        }
+        None => space.map_lazy(addr, len, page_flags),
+    };
+    mapped.map_err(|_| Errno::ENOMEM)?;
+    Ok(addr)
+}
+
+/// Writes the file pages mapped shared in `len` bytes at `addr` back to
+/// their files. `MS_ASYNC` waits for them too.
+fn msync(addr: usize, len: usize, flags: usize) -> SyscallResult {
+    if !addr.is_multiple_of(PAGE_SIZE)
+        || flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0
+        || flags & (MS_ASYNC | MS_SYNC) == MS_ASYNC | MS_SYNC
+    {
+        return Err(Errno::EINVAL);
+    }
+    let memory = current_address_space()
+        .shared_memory_in(addr, len)
+        .ok_or(Errno::ENOMEM)?;
+    for memory in memory {
+        if !memory.write_back() {
+            return Err(Errno::EIO);
+        }
+    }
+    Ok(0)
+}
+
+fn munmap(addr: usize, len: usize) -> SyscallResult {
+    let len = len
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(Errno::EINVAL)?;
+    current_address_space()
+        .unmap(addr, len)
+        .map_err(|_| Errno::EINVAL)?;
+    Ok(0)
+}
+
+/// Only sending to a single process is supported, not to groups.
+/// Sends `signal` to the process `pid`, or if it's 0, to the caller's
+/// process group, if -1, to every process but the caller, and if less, to
+/// the group `-pid`.
+fn kill(pid: isize, signal: usize) -> SyscallResult {
+    let signal = u8::try_from(signal)
+        .ok()
+        .filter(|&signal| signal <= NSIG)
+        .ok_or(Errno::EINVAL)?;
+    let caller = current_process();
+    let targets = match pid {
+        1.. => process::find(Pid::from_raw(pid as usize))
+            .filter(|process| process.exit_status().is_none())
+            .into_iter()
+            .collect(),
+        0 => process::group(caller.pgid()),
+        -1 => process::all()
+            .into_iter()
+            .filter(|process| !Arc::ptr_eq(process, &caller) && process.exit_status().is_none())
+            .collect(),
+        _ => process::group(Pid::from_raw(pid.unsigned_abs())),
+    };
+    if targets.is_empty() {
+        return Err(Errno::ESRCH);
+    }
+    // Signal 0 only checks that the processes exist.
+    if signal != 0 {
+        for process in targets {
+            process.send_signal(signal);
+        }
+    }
+    Ok(0)
+}
+
+/// The process `pid`, or the caller if it's 0.
+fn process_or_current(pid: usize) -> Result<Arc<Process>, Errno> {
+    if pid == 0 {
+        return Ok(current_process());
+    }
+    process::find(Pid::from_raw(pid))
+        .filter(|process| process.exit_status().is_none())
+        .ok_or(Errno::ESRCH)
+}
+
+/// A limit as `getrlimit` and `setrlimit` pass it, in `unsigned long`s
+/// with all ones for infinity.
+type Rlimit = [usize; 2];
+
+fn getrlimit(resource: usize, rlim: usize) -> SyscallResult {
+    let limit = current_process().limit(resource)?;
+    let rlimit: Rlimit = [rlimit::to_usize(limit.soft), rlimit::to_usize(limit.hard)];
+    write_user_struct(rlim, &rlimit)?;
+    Ok(0)
+}
+
+fn setrlimit(resource: usize, rlim: usize) -> SyscallResult {
+    let from_user = |value| match value {
+        usize::MAX => RLIM_INFINITY,
+        _ => value as u64,
+    };
+    let [soft, hard]: Rlimit = read_user_struct(rlim)?;
+    current_process().set_limit(
+        resource,
+        Limit {
+            soft: from_user(soft),
+            hard: from_user(hard),
+        },
+    )?;
+    Ok(0)
+}
+
+/// Sets the limit on `resource` of the process `pid`, or the caller if
+/// it's 0, to `new` unless it's null, writing the old one to `old` unless
+/// it's null. Only the caller's own limits can be read or set, as
+/// processes have no owners to check another's against.
+fn prlimit64(pid: usize, resource: usize, new: usize, old: usize) -> SyscallResult {
+    let process = process_or_current(pid)?;
+    if !Arc::ptr_eq(&process, &current_process()) {
+        return Err(Errno::EPERM);
+    }
+    let new = match new {
+        0 => None,
+        _ => Some(read_user_struct::<[u64; 2]>(new)?),
+    };
+    let limit = process.limit(resource)?;
+    if let Some([soft, hard]) = new {
+        process.set_limit(resource, Limit { soft, hard })?;
+    }
+    if old != 0 {
+        write_user_struct(old, &[limit.soft, limit.hard])?;
+    }
+    Ok(0)
+}
+
+/// Moves the process `pid`, the caller or a child of it in its session, to
+/// the group `pgid`, which is new, with `pid` as its ID, or in the same
+/// session. Either being 0 means the caller's PID.
+fn setpgid(pid: usize, pgid: isize) -> SyscallResult {
+    if pgid < 0 {
+        return Err(Errno::EINVAL);
+    }
+    let caller = current_process();
+    let process = process_or_current(pid)?;
+    let is_child = process
+        .parent()
+        .is_some_and(|parent| Arc::ptr_eq(&parent, &caller));
+    if !Arc::ptr_eq(&process, &caller) && !is_child {
+        return Err(Errno::ESRCH);
+    }
+    if process.is_session_leader() || process.sid() != caller.sid() {
+        return Err(Errno::EPERM);
+    }
+    let pgid = match pgid {
+        0 => process.pid(),
+        _ => Pid::from_raw(pgid as usize),
+    };
+    let joinable = pgid == process.pid()
+        || process::group(pgid)
+            .iter()
+            .any(|member| member.sid() == caller.sid());
+    if !joinable {
+        return Err(Errno::EPERM);
+    }
+    process.set_pgid(pgid);
+    Ok(0)
+}
+
+/// Starts a new session, led by the caller, which mustn't lead a process
+/// group already.
+fn setsid() -> SyscallResult {
+    let process = current_process();
+    if !process::group(process.pid()).is_empty() {
+        return Err(Errno::EPERM);
+    }
+    process.start_session();
+    Ok(process.pid().as_raw())
+}
+
+fn sigaction(signal: usize, act: usize, old_act: usize, sigset_size: usize) -> SyscallResult {
+    if sigset_size != size_of::<u64>() {
+        return Err(Errno::EINVAL);
+    }
+    let signal = u8::try_from(signal)
+        .ok()
+        .filter(|&signal| (1..=NSIG).contains(&signal))
+        .ok_or(Errno::EINVAL)?;
+    let new = match act {
+        0 => None,
+        act => Some(Action::from(read_user_struct::<SigAction>(act)?)),
+    };
+
+    let process = current_process();
+    let mut signals = process.signals();
+    let old = match new {
+        Some(action) => signals.set_action(signal, action)?,
+        None => signals.action(signal),
+    };
+    drop(signals);
+    if old_act != 0 {
+        write_user_struct(old_act, &SigAction::from(old))?;
+    }
+    Ok(0)
+}
+
+fn sigprocmask(how: usize, set: usize, old_set: usize, sigset_size: usize) -> SyscallResult {
+    if sigset_size != size_of::<u64>() {
+        return Err(Errno::EINVAL);
+    }
+    let set = match set {
+        0 => None,
+        set => Some(read_user_struct::<u64>(set)?),
+    };
+
+    let process = current_process();
+    let mut signals = process.signals();
+    let (_, blocked) = signals.masks();
+    if let Some(set) = set {
+        let new = match how {
+            SIG_BLOCK => blocked | set,
+            SIG_UNBLOCK => blocked & !set,
+            SIG_SETMASK => set,
+            _ => return Err(Errno::EINVAL),
+        };
+        signals.set_blocked(new);
+    }
+    drop(signals);
+    if old_set != 0 {
+        write_user_struct(old_set, &blocked)?;
+    }
+    Ok(0)
+}
+
+ktest! {
+    fn describes_errors() {
+        use alloc::string::ToString;
+
+        assert_eq!(Errno::EPERM.to_string(), "operation not permitted");
+        assert_eq!(Errno::E2BIG.to_string(), "argument list too long");
+        assert_eq!(Errno::from_raw(2), Errno::ENOENT);
+        assert_eq!(Errno::from_raw(95).to_string(), "error 95");
+        assert_eq!(Errno::from(UserCopyError::Fault), Errno::EFAULT);
+    }
+}
@@ -0,0 +1,965 @@
+//! Kernel threads. Each hart has its own run queue and picks which of its
+//! tasks to run again on every timer tick, or its idle task if none are
+//! ready. Kernel tasks take turns round-robin by priority, and the threads
+//! of processes share what time's left fairly (see `fair`), so that they
+//! can't hold off the kernel's, unless they're real-time (see `realtime`).
+//! In all, from first to last: high priority tasks, real-time ones, normal
+//! priority ones, fair ones and low priority ones. A task that's made ready
+//! ahead of the one running on its hart preempts it.
+//!
+//! Tasks stay on the hart they were spawned on unless their affinity, the
+//! harts they may run on, is changed to leave it out. There's no balancing
+//! beyond putting each new thread on its least loaded allowed hart. The
+//! task a hart is running is kept in its per-hart `CURRENT`, every other
+//! task is owned by a queue.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::arch::csr::{self, Bits, Counteren};
+use crate::arch::fpu::{self, ExtState};
+use crate::mm::addr_space::AddressSpace;
+use crate::mm::stack::KernelStack;
+use crate::mm::{paging, tlb};
+use crate::perf::ThreadCounters;
+use crate::process::Process;
+use crate::sbi::{ipi, timer};
+use crate::smp::{CpuMask, MAX_HARTS};
+use crate::sync::{SpinLock, SpinLockGuard, WaitQueue};
+use crate::time::{self, Duration, Instant, Timer};
+use crate::{idle, ktest, percpu, trap};
+
+mod fair;
+mod realtime;
+
+use fair::FairQueue;
+pub use fair::{NICE_MAX, NICE_MIN};
+use realtime::RtQueue;
+pub use realtime::{
+    PRIORITY_MAX as RT_PRIORITY_MAX, PRIORITY_MIN as RT_PRIORITY_MIN, TIME_SLICE as RT_TIME_SLICE,
+};
+
+global_asm!(concat!(crate::arch::asm_prelude!(), include_str!("task.s")));
+
+extern "C" {
+    fn switch_context(old: *mut Context, new: *const Context);
+    fn task_trampoline();
+}
+
+type Entry = Box<dyn FnOnce() + Send>;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Each hart's tasks, other than the one it's running. Only locked with
+/// interrupts disabled.
+static RUN_QUEUES: [SpinLock<RunQueue>; MAX_HARTS] = [const {
+    SpinLock::new(RunQueue {
+        ready: [const { VecDeque::new() }; Priority::COUNT],
+        realtime: RtQueue::new(),
+        fair: FairQueue::new(),
+        switched_at: None,
+        running: 0,
+        exited: Vec::new(),
+        migrating: None,
+        idle: None,
+    })
+}; MAX_HARTS];
+
+/// Harts that have called `init`, and so run tasks.
+static STARTED: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+
+/// Harts whose running task should be preempted, set along with an IPI.
+static NEED_RESCHED: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+
+percpu! {
+    /// The task running on this hart, owned by the hart while it runs.
+    static CURRENT: AtomicPtr<Task> = AtomicPtr::new(core::ptr::null_mut());
+    /// Preemption is disabled while this is non-zero, e.g. while a
+    /// `SpinLock` is held.
+    static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+    /// The task whose floating-point and vector registers are loaded.
+    static EXT_OWNER: AtomicUsize = AtomicUsize::new(usize::MAX);
+}
+
+// Boxed since a task's context is saved to after it has been queued.
+#[allow(clippy::vec_box)]
+struct RunQueue {
+    ready: [VecDeque<Box<Task>>; Priority::COUNT],
+    realtime: RtQueue,
+    fair: FairQueue,
+    /// When the running task was switched to.
+    switched_at: Option<Instant>,
+    /// The rank of the running task, see `Task::rank`.
+    running: u8,
+    /// Tasks that have returned, freed once another task is running.
+    exited: Vec<Box<Task>>,
+    /// A task moving to another hart, queued there once it's switched away
+    /// from.
+    migrating: Option<Box<Task>>,
+    /// Run when no other task is ready. `None` while it's running.
+    idle: Option<Box<Task>>,
+}
+
+impl RunQueue {
+    /// Takes the next task to run, the first in the order in the module
+    /// docs.
+    fn pop(&mut self, now: Instant) -> Option<Box<Task>> {
+        let [low, normal, high] = &mut self.ready;
+        high.pop_front()
+            .or_else(|| self.realtime.pop(now))
+            .or_else(|| normal.pop_front())
+            .or_else(|| self.fair.pop())
+            .or_else(|| low.pop_front())
+            .or_else(|| self.idle.take())
+    }
+
+    fn push(&mut self, task: Box<Task>) {
+        if task.is_idle {
+            self.idle = Some(task);
+            return;
+        }
+        match task.class {
+            Class::RoundRobin(priority) => self.ready[priority as usize].push_back(task),
+            Class::Realtime { priority, .. } => self.realtime.push_back(task, priority),
+            Class::Fair => self.fair.push(task),
+        }
+    }
+
+    /// Queues a task that was preempted rather than having yielded: a
+    /// real-time task stays at the front of its queue unless its time
+    /// slice has run out.
+    fn push_preempted(&mut self, mut task: Box<Task>) {
+        match task.class {
+            Class::Realtime {
+                round_robin: true, ..
+            } if task.slice_left.is_zero() => {
+                task.slice_left = realtime::TIME_SLICE;
+                self.push(task);
+            }
+            Class::Realtime { priority, .. } => self.realtime.push_front(task, priority),
+            _ => self.push(task),
+        }
+    }
+
+    /// How many tasks the hart has other than its idle task, including the
+    /// one it's running.
+    fn load(&self) -> usize {
+        let ready: usize = self.ready.iter().map(VecDeque::len).sum();
+        // The idle task is only queued while another is running.
+        ready + self.realtime.len() + self.fair.len() + usize::from(self.idle.is_some())
+    }
+
+    /// Counts the time since the last switch as `task`'s, as it's switched
+    /// away from.
+    fn account(&mut self, task: &mut Task, now: Instant) {
+        let ran = self.switched_at.map_or(Duration::ZERO, |at| now - at);
+        if let Some(process) = &task.process {
+            process.charge_cpu(ran);
+        }
+        match task.class {
+            Class::Fair => {
+                let delta = fair::vruntime_delta(ran, task.nice);
+                task.vruntime = task.vruntime.saturating_add(delta);
+            }
+            Class::Realtime { .. } => {
+                self.realtime.charge(ran, now);
+                task.slice_left = task.slice_left.saturating_sub(ran);
+            }
+            Class::RoundRobin(_) => {}
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TaskId(usize);
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Ready tasks of a higher priority always run before those of a lower one.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+}
+
+/// How a task is picked to run.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Class {
+    /// Round-robin with the other ready tasks of the same priority.
+    RoundRobin(Priority),
+    /// A share of the time weighted by the task's nice value.
+    Fair,
+    /// Real-time, from `RT_PRIORITY_MIN` to `RT_PRIORITY_MAX`.
+    Realtime { round_robin: bool, priority: u8 },
+}
+
+/// How a process's thread is scheduled, as `sched_setscheduler` sets it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Policy {
+    /// `SCHED_OTHER`: the fair class.
+    Fair,
+    /// `SCHED_FIFO` at a real-time priority.
+    Fifo(u8),
+    /// `SCHED_RR` at a real-time priority.
+    RoundRobin(u8),
+}
+
+/// The registers saved by `switch_context`. The layout must match task.s.
+#[repr(C)]
+#[derive(Default)]
+struct Context {
+    ra: usize,
+    sp: usize,
+    /// `s0` to `s11`.
+    s: [usize; 12],
+}
+
+struct Task {
+    id: TaskId,
+    /// The hart the task runs on.
+    hart: usize,
+    /// The harts the task may run on.
+    affinity: CpuMask,
+    class: Class,
+    /// From `NICE_MIN` for the biggest share to `NICE_MAX` for the least,
+    /// while the task is fair.
+    nice: i8,
+    /// The time the task has run, in nanoseconds scaled by its weight, if
+    /// it's fair.
+    vruntime: u64,
+    /// How much longer the task runs before the next of its priority, if
+    /// it's real-time round-robin.
+    slice_left: Duration,
+    is_idle: bool,
+    context: Context,
+    /// The task's stack, or `None` for a hart's boot thread, which runs on
+    /// the stack it was started with.
+    stack: Option<KernelStack>,
+    entry: Option<Entry>,
+    /// The user address space the task runs in, if any.
+    address_space: Option<Arc<AddressSpace>>,
+    /// The process the task is a thread of, if any.
+    process: Option<Arc<Process>>,
+    /// The task's floating-point and vector registers as last saved, or
+    /// `None` if it's never used them.
+    ext_state: Option<Box<ExtState>>,
+    /// The performance counters the task has opened.
+    counters: ThreadCounters,
+}
+
+impl Task {
+    /// Where the task comes in the order in the module docs, higher first.
+    fn rank(&self) -> u8 {
+        match self.class {
+            _ if self.is_idle => 0,
+            Class::RoundRobin(Priority::Low) => 1,
+            Class::Fair => 2,
+            Class::RoundRobin(Priority::Normal) => 3,
+            Class::Realtime { priority, .. } => 3 + priority,
+            Class::RoundRobin(Priority::High) => 4 + realtime::PRIORITY_MAX,
+        }
+    }
+
+    fn satp(&self) -> usize {
+        self.address_space
+            .as_ref()
+            .map_or_else(paging::kernel_satp, |space| space.satp())
+    }
+}
+
+impl Task {
+    fn new(hart: usize, class: Class, entry: Entry) -> Option<Box<Self>> {
+        let stack = KernelStack::new()?;
+        Some(Box::new(Task {
+            id: new_id(),
+            hart,
+            affinity: CpuMask::single(hart),
+            class,
+            nice: 0,
+            vruntime: 0,
+            slice_left: realtime::TIME_SLICE,
+            is_idle: false,
+            context: Context {
+                ra: task_trampoline as *const () as usize,
+                sp: stack.top(),
+                s: [0; 12],
+            },
+            stack: Some(stack),
+            entry: Some(entry),
+            address_space: None,
+            process: None,
+            ext_state: None,
+            counters: ThreadCounters::default(),
+        }))
+    }
+}
+
+fn new_id() -> TaskId {
+    TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Starts scheduling tasks on this hart, making the code running on it the
+/// first task. The boot hart calls this once it has a heap, the others once
+/// they're online.
+pub fn init() {
+    let hart = percpu::hart_id();
+    let task = Box::new(Task {
+        id: new_id(),
+        hart,
+        affinity: CpuMask::single(hart),
+        class: Class::RoundRobin(Priority::Normal),
+        nice: 0,
+        vruntime: 0,
+        slice_left: realtime::TIME_SLICE,
+        is_idle: false,
+        context: Context::default(),
+        stack: None,
+        entry: None,
+        address_space: None,
+        process: None,
+        ext_state: None,
+        counters: ThreadCounters::default(),
+    });
+    CURRENT.get().store(Box::into_raw(task), Ordering::Relaxed);
+
+    let mut idle = Task::new(hart, Class::RoundRobin(Priority::Low), Box::new(idle))
+        .expect("no memory for the idle task");
+    idle.is_idle = true;
+    let enabled = trap::disable_interrupts();
+    RUN_QUEUES[hart].lock().idle = Some(idle);
+    trap::restore_interrupts(enabled);
+
+    STARTED[hart].store(true, Ordering::Release);
+    timer::set_tick_handler(tick);
+}
+
+fn idle() {
+    loop {
+        // Anything made ready comes with an interrupt, if only an IPI, so
+        // there's nothing to do until one.
+        idle::wait_until(timer::next_tick());
+        yield_now();
+    }
+}
+
+/// Starts a task running `entry` on `hart`, which must run tasks. Returns
+/// `None` if it doesn't or there's no memory for the task's stack.
+pub fn spawn_on(
+    hart: usize,
+    priority: Priority,
+    entry: impl FnOnce() + Send + 'static,
+) -> Option<TaskId> {
+    if !STARTED.get(hart)?.load(Ordering::Acquire) {
+        return None;
+    }
+    let task = Task::new(hart, Class::RoundRobin(priority), Box::new(entry))?;
+    let id = task.id;
+
+    make_ready(task);
+    Some(id)
+}
+
+/// Starts a thread of `process`, running `entry` in the process's address
+/// space. If the running task is a process's thread, the new one takes its
+/// policy, nice value and affinity; otherwise it's fair at nice 0 and may
+/// run on any hart. It starts on the least loaded hart it may run on.
+pub fn spawn_thread(
+    process: Arc<Process>,
+    entry: impl FnOnce() + Send + 'static,
+) -> Option<TaskId> {
+    let (class, nice, affinity) = {
+        // SAFETY: `CURRENT` points to the running task.
+        let current = unsafe { &*CURRENT.get().load(Ordering::Relaxed) };
+        match current.process {
+            Some(_) => (current.class, current.nice, current.affinity),
+            None => (Class::Fair, 0, CpuMask::ALL),
+        }
+    };
+    let hart = least_loaded(affinity).unwrap_or(percpu::hart_id());
+    let mut task = Task::new(hart, class, Box::new(entry))?;
+    task.nice = nice;
+    task.affinity = affinity;
+    let id = task.id;
+    task.address_space = Some(process.address_space());
+    task.process = Some(process);
+
+    make_ready(task);
+    Some(id)
+}
+
+/// The hart in `mask` that runs tasks and has the fewest, preferring this
+/// one, or `None` if none in it runs tasks.
+fn least_loaded(mask: CpuMask) -> Option<usize> {
+    let this = percpu::hart_id();
+    let others = mask.iter().filter(|&hart| hart != this);
+    core::iter::once(this)
+        .filter(|&hart| mask.contains(hart))
+        .chain(others)
+        .filter(|&hart| STARTED[hart].load(Ordering::Acquire))
+        .min_by_key(|&hart| {
+            let enabled = trap::disable_interrupts();
+            let load = RUN_QUEUES[hart].lock().load();
+            trap::restore_interrupts(enabled);
+            load
+        })
+}
+
+/// Queues `task` on its hart, waking the hart in case it's idle, and having
+/// it preempt its running task if `task` comes first.
+fn make_ready(task: Box<Task>) {
+    let hart = task.hart;
+    let enabled = trap::disable_interrupts();
+    let preempt = {
+        let mut queue = RUN_QUEUES[hart].lock();
+        let preempt = task.rank() > queue.running;
+        queue.push(task);
+        preempt
+    };
+    trap::restore_interrupts(enabled);
+    if preempt {
+        NEED_RESCHED[hart].store(true, Ordering::Relaxed);
+    }
+    // An IPI to this hart is taken as soon as it has interrupts enabled.
+    if preempt || hart != percpu::hart_id() {
+        let _ = ipi::send_ipi(1 << hart, 0);
+    }
+}
+
+/// Called on an IPI, from interrupt context, to preempt the running task if
+/// a task that comes first has been made ready.
+pub fn preempt_if_needed() {
+    let hart = percpu::hart_id();
+    if STARTED[hart].load(Ordering::Acquire)
+        && NEED_RESCHED[hart].load(Ordering::Relaxed)
+        && preemptible()
+    {
+        preempt();
+    }
+}
+
+/// A task that isn't queued to run, until it's passed to `wake`.
+pub struct Blocked(Box<Task>);
+
+/// Blocks the running task, handing it to `put` to keep until it should
+/// run again. `put` is called with this hart's run queue locked and
+/// interrupts disabled, so it mustn't block or take a lock that interrupt
+/// handlers might hold. Returns once the task has been woken and scheduled.
+pub fn block(put: impl FnOnce(Blocked)) {
+    schedule(|_, task| {
+        assert!(!task.is_idle, "idle task blocked");
+        put(Blocked(task));
+    });
+}
+
+/// Makes a blocked task ready to run again.
+pub fn wake(task: Blocked) {
+    make_ready(task.0);
+}
+
+/// The ID of the running task.
+pub fn current() -> TaskId {
+    // SAFETY: `CURRENT` is only changed by this hart, and always points to
+    // a live task once `init` has been called.
+    unsafe { (*CURRENT.get().load(Ordering::Relaxed)).id }
+}
+
+/// The process the running task is a thread of, if any.
+pub fn process() -> Option<Arc<Process>> {
+    // SAFETY: `CURRENT` points to the running task, and only this hart
+    // changes it.
+    unsafe { (*CURRENT.get().load(Ordering::Relaxed)).process.clone() }
+}
+
+/// The top of the running task's kernel stack, or `None` if it's running on
+/// its hart's boot stack.
+pub fn stack_top() -> Option<usize> {
+    // SAFETY: `CURRENT` points to the running task.
+    let task = unsafe { &*CURRENT.get().load(Ordering::Relaxed) };
+    task.stack.as_ref().map(KernelStack::top)
+}
+
+/// The running task's user address space, if it has one.
+pub fn address_space() -> Option<Arc<AddressSpace>> {
+    // SAFETY: `CURRENT` points to the running task, and only this hart
+    // changes it.
+    unsafe {
+        (*CURRENT.get().load(Ordering::Relaxed))
+            .address_space
+            .clone()
+    }
+}
+
+/// Moves the running task into `space`, switching to it.
+pub fn set_address_space(space: Arc<AddressSpace>) {
+    let enabled = trap::disable_interrupts();
+    // SAFETY: as above. Interrupts are disabled so we aren't switched away
+    // in between.
+    unsafe {
+        let task = &mut *CURRENT.get().load(Ordering::Relaxed);
+        switch_address_space(task.address_space.as_deref(), Some(&space));
+        task.address_space = Some(space);
+    }
+    trap::restore_interrupts(enabled);
+}
+
+/// Switches this hart from the address space `from` to `to`, where `None`
+/// is the kernel's own, noting that it has for TLB shootdowns.
+///
+/// SAFETY: `to` must map the kernel, as every address space does.
+unsafe fn switch_address_space(from: Option<&AddressSpace>, to: Option<&AddressSpace>) {
+    if let Some(to) = to {
+        to.enter();
+    }
+    csr::satp::write(to.map_or_else(paging::kernel_satp, AddressSpace::satp));
+    tlb::flush_local_all();
+    if let Some(from) = from {
+        from.leave();
+    }
+}
+
+/// Gives the running task `state` as its floating-point and vector
+/// registers, loading them, or takes them away if `None`.
+pub fn set_ext_state(state: Option<Box<ExtState>>) {
+    let enabled = trap::disable_interrupts();
+    // SAFETY: as in `set_address_space`.
+    let task = unsafe { &mut *CURRENT.get().load(Ordering::Relaxed) };
+    task.ext_state = state;
+    match &task.ext_state {
+        Some(state) => {
+            // SAFETY: interrupts are disabled, and the registers are
+            // becoming this task's.
+            unsafe { state.restore() };
+            EXT_OWNER.get().store(task.id.0, Ordering::Relaxed);
+        }
+        None => fpu::disable(),
+    }
+    trap::restore_interrupts(enabled);
+}
+
+/// A copy of the running task's floating-point and vector registers, or
+/// `None` if it's never used them.
+pub fn ext_state() -> Option<Box<ExtState>> {
+    let enabled = trap::disable_interrupts();
+    // SAFETY: as in `set_address_space`.
+    let task = unsafe { &mut *CURRENT.get().load(Ordering::Relaxed) };
+    let state = task.ext_state.as_mut().map(|state| {
+        // SAFETY: the running task's registers are loaded, and interrupts
+        // are disabled.
+        unsafe { state.save() };
+        state.clone()
+    });
+    trap::restore_interrupts(enabled);
+    state
+}
+
+/// The running task's nice value.
+pub fn nice() -> i8 {
+    // SAFETY: `CURRENT` points to the running task.
+    unsafe { (*CURRENT.get().load(Ordering::Relaxed)).nice }
+}
+
+/// Sets the running task's nice value, clamped to the range, which it's
+/// scheduled by while it's fair.
+pub fn set_nice(nice: i8) {
+    let enabled = trap::disable_interrupts();
+    // SAFETY: as in `set_address_space`.
+    let task = unsafe { &mut *CURRENT.get().load(Ordering::Relaxed) };
+    // The time it's run so far is still counted at the new weight, as it's
+    // only accounted once it's switched away from.
+    task.nice = nice.clamp(NICE_MIN, NICE_MAX);
+    trap::restore_interrupts(enabled);
+}
+
+/// The running task's policy, or `None` if it's a kernel task, which isn't
+/// scheduled by one.
+pub fn policy() -> Option<Policy> {
+    // SAFETY: `CURRENT` points to the running task.
+    match unsafe { (*CURRENT.get().load(Ordering::Relaxed)).class } {
+        Class::RoundRobin(_) => None,
+        Class::Fair => Some(Policy::Fair),
+        Class::Realtime {
+            round_robin: false,
+            priority,
+        } => Some(Policy::Fifo(priority)),
+        Class::Realtime {
+            round_robin: true,
+            priority,
+        } => Some(Policy::RoundRobin(priority)),
+    }
+}
+
+/// Sets the running task's policy, which must have a real-time priority in
+/// range, if any. Tasks that now come ahead of it get to run.
+pub fn set_policy(policy: Policy) {
+    let class = match policy {
+        Policy::Fair => Class::Fair,
+        Policy::Fifo(priority) => Class::Realtime {
+            round_robin: false,
+            priority,
+        },
+        Policy::RoundRobin(priority) => Class::Realtime {
+            round_robin: true,
+            priority,
+        },
+    };
+    if let Class::Realtime { priority, .. } = class {
+        assert!((RT_PRIORITY_MIN..=RT_PRIORITY_MAX).contains(&priority));
+    }
+    let enabled = trap::disable_interrupts();
+    // SAFETY: as in `set_address_space`.
+    let task = unsafe { &mut *CURRENT.get().load(Ordering::Relaxed) };
+    task.class = class;
+    task.slice_left = realtime::TIME_SLICE;
+    trap::restore_interrupts(enabled);
+    preempt();
+}
+
+/// The harts the running task may run on.
+pub fn affinity() -> CpuMask {
+    // SAFETY: `CURRENT` points to the running task.
+    unsafe { (*CURRENT.get().load(Ordering::Relaxed)).affinity }
+}
+
+/// Why a task's affinity couldn't be changed.
+#[derive(Debug)]
+pub enum AffinityError {
+    /// None of the harts run tasks.
+    NoHarts,
+    /// The task has performance counters open, which can only be used on
+    /// the hart they were opened on.
+    Counting,
+    /// The task is a hart's boot or idle task, which can't leave it.
+    Pinned,
+}
+
+/// Lets the running task run only on the harts in `mask`, moving it to the
+/// least loaded of them if this isn't one. Returns once it's running there.
+/// A fair task that moves is put among the other hart's as if it had just
+/// woken.
+pub fn set_affinity(mask: CpuMask) -> Result<(), AffinityError> {
+    let hart = percpu::hart_id();
+    let enabled = trap::disable_interrupts();
+    // SAFETY: as in `set_address_space`.
+    let task = unsafe { &mut *CURRENT.get().load(Ordering::Relaxed) };
+    let moves = !mask.contains(hart);
+    let result = if !moves {
+        Ok(None)
+    } else if task.stack.is_none() || task.is_idle {
+        Err(AffinityError::Pinned)
+    } else if !task.counters.is_empty() {
+        Err(AffinityError::Counting)
+    } else {
+        least_loaded(mask).ok_or(AffinityError::NoHarts).map(Some)
+    };
+    if result.is_ok() {
+        task.affinity = mask;
+    }
+    trap::restore_interrupts(enabled);
+
+    let Some(target) = result? else {
+        return Ok(());
+    };
+    schedule(|queue, mut task| {
+        task.hart = target;
+        task.vruntime = 0;
+        // Its registers may be changed on the other hart, so aren't its
+        // here any more once saved.
+        let owner = EXT_OWNER.get();
+        if owner.load(Ordering::Relaxed) == task.id.0 {
+            owner.store(usize::MAX, Ordering::Relaxed);
+        }
+        queue.migrating = Some(task);
+    });
+    Ok(())
+}
+
+/// Whether the running task has floating-point and vector registers.
+pub fn has_ext_state() -> bool {
+    // SAFETY: `CURRENT` points to the running task.
+    unsafe { (*CURRENT.get().load(Ordering::Relaxed)).ext_state.is_some() }
+}
+
+/// Calls `f` with the running task's performance counters.
+pub fn with_counters<T>(f: impl FnOnce(&mut ThreadCounters) -> T) -> T {
+    let enabled = trap::disable_interrupts();
+    // SAFETY: as in `set_address_space`.
+    let result = f(unsafe { &mut (*CURRENT.get().load(Ordering::Relaxed)).counters });
+    trap::restore_interrupts(enabled);
+    result
+}
+
+/// Lets the running task read the counters its process can, as it's
+/// switched to, after they've changed.
+pub fn update_counter_access() {
+    // SAFETY: `CURRENT` points to the running task.
+    let task = unsafe { &*CURRENT.get().load(Ordering::Relaxed) };
+    let access = task
+        .process
+        .as_ref()
+        .map_or(Counteren::from_bits(0), |process| process.counter_access());
+    // SAFETY: U-mode reading counters has no effect on the kernel.
+    unsafe { csr::scounteren::write(access) };
+}
+
+/// Saves `prev`'s floating-point and vector registers if it's dirtied them,
+/// and gives the hart `next`'s, loading them unless they're still there.
+///
+/// SAFETY: `prev` must be this hart's current task, about to be switched
+/// away from. Interrupts must be disabled.
+unsafe fn switch_ext_state(prev: &mut Task, next: &Task) {
+    if let Some(state) = &mut prev.ext_state {
+        state.save();
+    }
+    let owner = EXT_OWNER.get();
+    match &next.ext_state {
+        Some(_) if owner.load(Ordering::Relaxed) == next.id.0 => fpu::mark_clean(),
+        Some(state) => {
+            state.restore();
+            owner.store(next.id.0, Ordering::Relaxed);
+        }
+        None => fpu::disable(),
+    }
+}
+
+/// Disables preemption on this hart until a matching `preempt_enable`.
+pub fn preempt_disable() {
+    PREEMPT_COUNT.get().fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn preempt_enable() {
+    PREEMPT_COUNT.get().fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Whether the code running on this hart can be preempted.
+pub fn preemptible() -> bool {
+    PREEMPT_COUNT.get().load(Ordering::Relaxed) == 0
+}
+
+/// Lets the next ready task of at least the same priority run, if there is
+/// one, or for a fair task whichever has run the least. Returns when this
+/// task is next scheduled.
+pub fn yield_now() {
+    schedule(|queue, task| queue.push(task));
+}
+
+/// Switches to the task that should run now, which may be this one. Unlike
+/// `yield_now`, a real-time task stays ahead of others of its priority.
+fn preempt() {
+    schedule(|queue, task| queue.push_preempted(task));
+}
+
+/// Blocks this task for at least `duration`.
+pub fn sleep(duration: Duration) {
+    sleep_with_slack(duration, Duration::ZERO);
+}
+
+/// Blocks this task for at least `duration`, and at most about `slack`
+/// more, so that its wakeup can share a tick with others.
+pub fn sleep_with_slack(duration: Duration, slack: Duration) {
+    let woken = Arc::new((AtomicBool::new(false), WaitQueue::new()));
+    let _timer = Timer::start_with_slack(Instant::now() + duration, slack, {
+        let woken = woken.clone();
+        move || {
+            woken.0.store(true, Ordering::Release);
+            woken.1.wake_all();
+        }
+    });
+    woken.1.wait_until(|| woken.0.load(Ordering::Acquire));
+}
+
+/// Called on every timer tick, from interrupt context.
+fn tick() {
+    let hart = percpu::hart_id();
+    if !STARTED[hart].load(Ordering::Acquire) {
+        return;
+    }
+    time::run_timers(Instant::now());
+    // Code holding a `SpinLock` isn't preempted, since whatever runs next
+    // might spin on it until the next tick.
+    if preemptible() {
+        preempt();
+    }
+}
+
+/// Hands the current task to `put`, which should queue it somewhere, and
+/// switches to the next task on this hart. Returns once the current task is
+/// switched back to, which may be right away.
+fn schedule(put: impl FnOnce(&mut RunQueue, Box<Task>)) {
+    let enabled = trap::disable_interrupts();
+    let hart = percpu::hart_id();
+    NEED_RESCHED[hart].store(false, Ordering::Relaxed);
+    let mut queue = RUN_QUEUES[hart].lock();
+    let prev = CURRENT.get().load(Ordering::Relaxed);
+    let now = Instant::now();
+    // SAFETY: `prev` was leaked from a box by whoever made it current.
+    let mut prev_box = unsafe { Box::from_raw(prev) };
+    queue.account(&mut prev_box, now);
+    put(&mut queue, prev_box);
+    queue.switched_at = Some(now);
+
+    // There's always a next task: the idle task is only missing while it's
+    // running, and then it has just been queued.
+    let next = queue.pop(now).expect("no task to run");
+    queue.running = next.rank();
+    let next = Box::into_raw(next);
+    if next != prev {
+        CURRENT.get().store(next, Ordering::Relaxed);
+        // SAFETY: both tasks are alive, as above, and `prev` was current.
+        let (prev_task, next_task) = unsafe { (&mut *prev, &*next) };
+        // SAFETY: interrupts are disabled.
+        unsafe { switch_ext_state(prev_task, next_task) };
+        // Each task's counters only count while it runs.
+        prev_task.counters.stop();
+        next_task.counters.resume();
+        update_counter_access();
+        if next_task.satp() != prev_task.satp() {
+            // SAFETY: every address space maps the kernel.
+            unsafe {
+                switch_address_space(
+                    prev_task.address_space.as_deref(),
+                    next_task.address_space.as_deref(),
+                )
+            };
+        }
+        // SAFETY: `prev` stays queued until it's switched to, and we're
+        // switching away from it. Interrupts are disabled.
+        unsafe { switch_to(queue, prev, next) };
+    } else {
+        CURRENT.get().store(next, Ordering::Relaxed);
+        drop(queue);
+    }
+    trap::restore_interrupts(enabled);
+}
+
+/// Switches from `prev` to `next`, keeping the run queue locked until `next`
+/// is running so that `prev` can't be picked up before its context is saved.
+/// Returns once `prev` is switched back to.
+///
+/// SAFETY: `prev` must have been this hart's current task, `next` now is.
+/// Interrupts must be disabled.
+unsafe fn switch_to(queue: SpinLockGuard<'_, RunQueue>, prev: *mut Task, next: *mut Task) {
+    core::mem::forget(queue);
+    switch_context(&raw mut (*prev).context, &raw const (*next).context);
+    finish_switch();
+}
+
+/// Run by a task after it's switched to: releases this hart's run queue,
+/// which was locked by whichever task switched away, frees exited tasks,
+/// and queues a task that's moving on the hart it's moving to, now that its
+/// context is saved.
+fn finish_switch() {
+    let queue = &RUN_QUEUES[percpu::hart_id()];
+    // SAFETY: the task that switched to us held the lock and forgot the
+    // guard, so it never undid its `preempt_disable` either.
+    unsafe { queue.force_unlock() };
+    preempt_enable();
+    let (exited, migrating) = {
+        let mut queue = queue.lock();
+        (core::mem::take(&mut queue.exited), queue.migrating.take())
+    };
+    drop(exited);
+    if let Some(task) = migrating {
+        make_ready(task);
+    }
+}
+
+#[no_mangle]
+extern "C" fn task_main() -> ! {
+    finish_switch();
+    // SAFETY: the trap vector is installed, and interrupts are enabled once
+    // a hart's tick has started.
+    unsafe { trap::enable_interrupts() };
+
+    // SAFETY: we're the current task, so `CURRENT` points to us.
+    let entry = unsafe { (*CURRENT.get().load(Ordering::Relaxed)).entry.take() };
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+/// Ends the running task.
+pub fn exit() -> ! {
+    schedule(|queue, task| {
+        assert!(!task.is_idle, "idle task exited");
+        queue.exited.push(task);
+    });
+    unreachable!("exited task was switched back to");
+}
+
+ktest! {
+    fn spawned_tasks_take_turns() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let count = count.clone();
+            let id = spawn_on(percpu::hart_id(), Priority::Normal, move || {
+                for _ in 0..3 {
+                    count.fetch_add(1, Ordering::Relaxed);
+                    yield_now();
+                }
+            });
+            ids.push(id.unwrap());
+        }
+        assert_ne!(ids[0], ids[1]);
+        assert!(!ids.contains(&current()));
+
+        let start = Instant::now();
+        while count.load(Ordering::Relaxed) < 6 {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            yield_now();
+        }
+    }
+}
+
+ktest! {
+    fn preempts_and_sleeps() {
+        // The task never yields, so this one only runs again once a tick
+        // preempts it.
+        let stop = Arc::new(AtomicBool::new(false));
+        spawn_on(percpu::hart_id(), Priority::Normal, {
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    core::hint::spin_loop();
+                }
+            }
+        })
+        .unwrap();
+        yield_now();
+        stop.store(true, Ordering::Relaxed);
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        spawn_on(percpu::hart_id(), Priority::Normal, {
+            let (runs, done) = (runs.clone(), done.clone());
+            move || {
+                while !done.load(Ordering::Relaxed) {
+                    runs.fetch_add(1, Ordering::Relaxed);
+                    yield_now();
+                }
+            }
+        })
+        .unwrap();
+        let start = Instant::now();
+        sleep(Duration::from_millis(20));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        // Sleeping let the other task run.
+        assert!(runs.load(Ordering::Relaxed) > 0);
+        done.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,27 @@
+.section .text
+.global switch_context
+.global task_trampoline
+
+# Saves the callee-saved registers in the Context at a0, then loads the ones
+# in the Context at a1 and returns into that task. The layout must match
+# Context in src/task.rs.
+.balign 4
+switch_context:
+    REG_S ra, 0*REGBYTES(a0)
+    REG_S sp, 1*REGBYTES(a0)
+    .irp n, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11
+    REG_S s\n, (\n+2)*REGBYTES(a0)
+    .endr
+
+    REG_L ra, 0*REGBYTES(a1)
+    REG_L sp, 1*REGBYTES(a1)
+    .irp n, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11
+    REG_L s\n, (\n+2)*REGBYTES(a1)
+    .endr
+    ret
+
+# New tasks are first switched to here. s0 is zero, so backtraces end at
+# task_main.
+.balign 4
+task_trampoline:
+    tail task_main
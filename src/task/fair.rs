@@ -0,0 +1,101 @@
+//! The fair class, for user threads: each task has a virtual runtime, the
+//! time it has run scaled down by its weight, and the ready task that's run
+//! the least is run next. On every tick the running task is put back and
+//! the least is picked again, so over time each gets a share of the hart in
+//! proportion to its weight, and one that's ready all the time can't keep
+//! one that mostly sleeps waiting behind it.
+//!
+//! Weights come from nice values, as on Linux: each level up is worth
+//! about 10% less of the hart than the one below.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::time::Duration;
+
+use super::Task;
+use crate::ktest;
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+/// The weight of nice 0, whose virtual runtime goes at the real rate.
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// The weight of each nice value from `NICE_MIN` to `NICE_MAX`, Linux's
+/// `sched_prio_to_weight`.
+#[rustfmt::skip]
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+    9548, 7620, 6100, 4904, 3906,
+    3121, 2501, 1991, 1586, 1277,
+    1024, 820, 655, 526, 423,
+    335, 272, 215, 172, 137,
+    110, 87, 70, 56, 45,
+    36, 29, 23, 18, 15,
+];
+
+/// How far behind the least virtual runtime in the queue a task can be
+/// when it's queued, in nanoseconds. A task that's been asleep runs ahead
+/// of those that haven't, but can't bank more than this while it sleeps.
+const SLEEP_CREDIT: u64 = 3_000_000;
+
+/// A hart's ready fair tasks.
+pub(super) struct FairQueue {
+    /// By virtual runtime, then ID for tasks that have the same. Boxed
+    /// since a task's context is saved to after it has been queued.
+    tasks: BTreeMap<(u64, usize), Box<Task>>,
+    /// The virtual runtime of the last task taken, which never goes back.
+    min_vruntime: u64,
+}
+
+impl FairQueue {
+    pub const fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            min_vruntime: 0,
+        }
+    }
+
+    pub fn push(&mut self, mut task: Box<Task>) {
+        task.vruntime = task
+            .vruntime
+            .max(self.min_vruntime.saturating_sub(SLEEP_CREDIT));
+        self.tasks.insert((task.vruntime, task.id.0), task);
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Takes the task that's run the least.
+    pub fn pop(&mut self) -> Option<Box<Task>> {
+        let (_, task) = self.tasks.pop_first()?;
+        self.min_vruntime = self.min_vruntime.max(task.vruntime);
+        Some(task)
+    }
+}
+
+fn weight(nice: i8) -> u64 {
+    WEIGHTS[(nice.clamp(NICE_MIN, NICE_MAX) - NICE_MIN) as usize]
+}
+
+/// How much virtual runtime running for `ran` at `nice` is, in nanoseconds.
+pub(super) fn vruntime_delta(ran: Duration, nice: i8) -> u64 {
+    let scaled = ran.as_nanos() * u128::from(NICE_0_WEIGHT) / u128::from(weight(nice));
+    u64::try_from(scaled).unwrap_or(u64::MAX)
+}
+
+ktest! {
+    fn weights_scale_virtual_runtime() {
+        let ms = Duration::from_millis(1);
+        assert_eq!(vruntime_delta(ms, 0), 1_000_000);
+        // Five levels apart is about three times the share.
+        let (low, high) = (vruntime_delta(ms, 5), vruntime_delta(ms, -5));
+        assert!((2_900_000..3_200_000).contains(&low));
+        assert!((300_000..350_000).contains(&high));
+        // Out of range values are clamped.
+        assert_eq!(vruntime_delta(ms, i8::MAX), vruntime_delta(ms, NICE_MAX));
+        assert_eq!(vruntime_delta(ms, i8::MIN), vruntime_delta(ms, NICE_MIN));
+    }
+}
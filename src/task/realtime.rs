@@ -0,0 +1,122 @@
+//! The real-time classes, for threads that ask for them, as POSIX's
+//! `SCHED_FIFO` and `SCHED_RR`: the ready task of the highest priority
+//! always runs, ahead of every fair task, and preempts them as soon as it's
+//! ready. A FIFO task runs until it blocks, yields or is preempted by a
+//! higher one; a round-robin task also goes to the back of its priority's
+//! queue once it's run for `TIME_SLICE`. A task that's preempted otherwise
+//! goes back to the front.
+//!
+//! So that a runaway real-time task can't lock up its hart, the class is
+//! throttled: in each `PERIOD` its tasks run for at most `RUNTIME`, leaving
+//! the rest for everything else, even if that's only the idle task.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use super::Task;
+use crate::time::Instant;
+use crate::{ktest, log_warn};
+
+pub const PRIORITY_MIN: u8 = 1;
+pub const PRIORITY_MAX: u8 = 99;
+
+/// How long a round-robin task runs before the next of its priority.
+pub const TIME_SLICE: Duration = Duration::from_millis(100);
+
+const PERIOD: Duration = Duration::from_secs(1);
+const RUNTIME: Duration = Duration::from_millis(950);
+
+/// Whether any hart has been throttled yet, which is only logged once.
+static THROTTLED: AtomicBool = AtomicBool::new(false);
+
+/// A hart's ready real-time tasks.
+pub(super) struct RtQueue {
+    /// By priority, highest first, then in order. Keys from the front count
+    /// down from zero and ones from the back up, so the order holds without
+    /// moving any. Boxed since a task's context is saved to after it has
+    /// been queued.
+    tasks: BTreeMap<(Reverse<u8>, i64), Box<Task>>,
+    front: i64,
+    back: i64,
+    /// When the current period started, if one has.
+    period_start: Option<Instant>,
+    /// How long real-time tasks have run in the current period.
+    used: Duration,
+}
+
+impl RtQueue {
+    pub const fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            front: 0,
+            back: 0,
+            period_start: None,
+            used: Duration::ZERO,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn push_back(&mut self, task: Box<Task>, priority: u8) {
+        self.back += 1;
+        self.tasks.insert((Reverse(priority), self.back), task);
+    }
+
+    pub fn push_front(&mut self, task: Box<Task>, priority: u8) {
+        self.front -= 1;
+        self.tasks.insert((Reverse(priority), self.front), task);
+    }
+
+    /// Takes the task to run next, unless the class is throttled until the
+    /// next period.
+    pub fn pop(&mut self, now: Instant) -> Option<Box<Task>> {
+        if self.tasks.is_empty() || self.throttled(now) {
+            return None;
+        }
+        self.tasks.pop_first().map(|(_, task)| task)
+    }
+
+    /// Counts `ran` against the current period's runtime.
+    pub fn charge(&mut self, ran: Duration, now: Instant) {
+        self.start_period(now);
+        self.used += ran;
+    }
+
+    fn throttled(&mut self, now: Instant) -> bool {
+        self.start_period(now);
+        if self.used < RUNTIME {
+            return false;
+        }
+        if !THROTTLED.swap(true, Ordering::Relaxed) {
+            log_warn!(target: "sched", "real-time tasks throttled");
+        }
+        true
+    }
+
+    /// Starts a new period if the current one is over.
+    fn start_period(&mut self, now: Instant) {
+        if self.period_start.is_none_or(|start| now - start >= PERIOD) {
+            self.period_start = Some(now);
+            self.used = Duration::ZERO;
+        }
+    }
+}
+
+ktest! {
+    fn throttles_each_period() {
+        let mut queue = RtQueue::new();
+        let start = Instant::now();
+        assert!(!queue.throttled(start));
+        queue.charge(RUNTIME - Duration::from_millis(1), start);
+        assert!(!queue.throttled(start));
+        queue.charge(Duration::from_millis(1), start);
+        assert!(queue.throttled(start + Duration::from_millis(10)));
+        // The next period starts over.
+        assert!(!queue.throttled(start + PERIOD));
+    }
+}
@@ -0,0 +1,146 @@
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::Ordering;
+pub use core::time::Duration;
+
+use crate::arch::{csr, AtomicU64};
+use crate::dtb::DeviceTree;
+use crate::ktest;
+
+mod wheel;
+
+pub use wheel::{run_timers, Timer};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Frequency of the `time` CSR, from `timebase-frequency` in `/cpus`.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The realtime clock at boot, in nanoseconds since the Unix epoch.
+static BOOT_REALTIME: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the timebase frequency from the device tree.
+pub fn init(dt: &DeviceTree<'_>) {
+    let frequency = dt
+        .find_node("/cpus")
+        .and_then(|cpus| cpus.property("timebase-frequency")?.as_number())
+        .expect("no timebase-frequency in /cpus");
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+/// The frequency of the `time` CSR in Hz.
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Sets the realtime clock, e.g. from an RTC, to `now` since the Unix
+/// epoch.
+pub fn set_realtime(now: Duration) {
+    let boot = now.saturating_sub(Instant::now().since_boot());
+    BOOT_REALTIME.store(boot.as_nanos() as u64, Ordering::Relaxed);
+    crate::vdso::update();
+}
+
+/// The realtime clock at boot, which `realtime` adds to the time since.
+pub fn boot_realtime() -> Duration {
+    Duration::from_nanos(BOOT_REALTIME.load(Ordering::Relaxed))
+}
+
+/// The time since the Unix epoch. Without an RTC to set it, the clock
+/// starts at the epoch at boot.
+pub fn realtime() -> Duration {
+    boot_realtime() + Instant::now().since_boot()
+}
+
+/// Reads the `time` CSR.
+pub fn read_time() -> u64 {
+    csr::time::read()
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = u128::from(ticks) * NANOS_PER_SEC / u128::from(frequency());
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = duration.as_nanos() * u128::from(frequency()) / NANOS_PER_SEC;
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+/// A point in time, measured by the `time` CSR. The epoch is unspecified but
+/// the same for every hart.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(read_time())
+    }
+
+    /// The time since boot, or at least since the timer started counting.
+    pub fn since_boot(self) -> Duration {
+        ticks_to_duration(self.0)
+    }
+
+    /// The time from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(self) -> Duration {
+        Self::now().duration_since(self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration_to_ticks(rhs)))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_sub(duration_to_ticks(rhs)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+ktest! {
+    fn converts_ticks_and_saturates() {
+        let hz = frequency();
+        assert_eq!(duration_to_ticks(Duration::from_secs(1)), hz);
+        assert_eq!(ticks_to_duration(hz), Duration::from_secs(1));
+        assert_eq!(ticks_to_duration(3 * hz), Duration::from_secs(3));
+        // Partial ticks are dropped, so a round trip never comes out later.
+        let duration = Duration::from_nanos(1_234_567_891);
+        assert!(ticks_to_duration(duration_to_ticks(duration)) <= duration);
+        assert_eq!(duration_to_ticks(Duration::MAX), u64::MAX);
+
+        let start = Instant(1000);
+        let later = start + Duration::from_secs(2);
+        assert_eq!(later.0, 1000 + 2 * hz);
+        assert_eq!(later - start, Duration::from_secs(2));
+        assert_eq!(start - later, Duration::ZERO);
+        assert_eq!(start - Duration::from_secs(1), Instant(0));
+        assert_eq!(Instant(u64::MAX - 1) + Duration::from_secs(1), Instant(u64::MAX));
+        let mut moved = start;
+        moved += Duration::from_secs(2);
+        assert_eq!(moved, later);
+    }
+}
@@ -0,0 +1,248 @@
+//! Timers, kept in a hierarchical timing wheel that every hart's tick turns.
+//! A timer runs its callback, from interrupt context, on the first tick at
+//! or after its deadline.
+//!
+//! The wheel has `LEVELS` levels of `SLOTS` slots. A slot of the first level
+//! holds the timers due on one tick, and each slot of a level above covers a
+//! whole turn of the level below, so a timer further off goes in a coarser
+//! slot and is cascaded down as its turn comes. Arming and cancelling take
+//! constant time however many timers there are, and a tick only looks at
+//! the timers it's due to run.
+//!
+//! A timer can be given slack, as much later than its deadline as it may
+//! run. It's then put on the roundest tick in range, so that timers with
+//! slack that are due about the same time go off on the same tick, together.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::{Duration, Instant, NANOS_PER_SEC};
+use crate::ktest;
+use crate::sbi::timer::TICK_HZ;
+use crate::sync::SpinLockIrqSave;
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+
+/// How many ticks off the furthest slot is, about 46 hours at 100 Hz. A
+/// timer further off is put back in it each time it comes round.
+const MAX_DELTA: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+const PENDING: u8 = 0;
+const RUNNING: u8 = 1;
+const DONE: u8 = 2;
+
+struct Entry {
+    /// The tick the timer runs on.
+    expires: u64,
+    state: AtomicU8,
+    /// Taken by whichever of running and cancelling the timer comes first.
+    callback: SpinLockIrqSave<Option<Callback>>,
+}
+
+impl Entry {
+    fn run(&self) {
+        if self
+            .state
+            .compare_exchange(PENDING, RUNNING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        let callback = self.callback.lock().take();
+        if let Some(callback) = callback {
+            callback();
+        }
+        self.state.store(DONE, Ordering::Release);
+    }
+}
+
+struct Wheel {
+    /// The last tick run, or `None` before the first.
+    now: Option<u64>,
+    /// Timers in the slots, including cancelled ones not yet cleared out.
+    count: usize,
+    slots: [[Vec<Arc<Entry>>; SLOTS]; LEVELS],
+}
+
+impl Wheel {
+    fn insert(&mut self, now: u64, entry: Arc<Entry>) {
+        let delta = entry.expires.saturating_sub(now).min(MAX_DELTA);
+        let expires = now + delta;
+        let level = (0..LEVELS)
+            .find(|&level| delta < (SLOTS as u64) << (SLOT_BITS * level as u32))
+            .unwrap_or(LEVELS - 1);
+        let slot = (expires >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+        self.slots[level][slot].push(entry);
+    }
+
+    /// Turns the wheel on to `tick`, adding the timers due to `expired`.
+    fn advance(&mut self, tick: u64, expired: &mut Vec<Arc<Entry>>) {
+        let Some(mut now) = self.now else {
+            self.now = Some(tick);
+            return;
+        };
+        while now < tick {
+            if self.count == 0 {
+                now = tick;
+                break;
+            }
+            now += 1;
+            // Each level above is cascaded as the one below it comes round,
+            // the highest first so its timers can fall through.
+            for level in (1..LEVELS).rev() {
+                let below = now & ((1 << (SLOT_BITS * level as u32)) - 1);
+                if below != 0 {
+                    continue;
+                }
+                let slot = (now >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+                for entry in core::mem::take(&mut self.slots[level][slot]) {
+                    if entry.state.load(Ordering::Relaxed) == PENDING {
+                        self.insert(now, entry);
+                    } else {
+                        self.count -= 1;
+                    }
+                }
+            }
+            let slot = now as usize % SLOTS;
+            let due = core::mem::take(&mut self.slots[0][slot]);
+            self.count -= due.len();
+            expired.extend(due);
+        }
+        self.now = Some(now);
+    }
+}
+
+static WHEEL: SpinLockIrqSave<Wheel> = SpinLockIrqSave::new(Wheel {
+    now: None,
+    count: 0,
+    slots: [const { [const { Vec::new() }; SLOTS] }; LEVELS],
+});
+
+/// The tick `at` falls in.
+fn tick_floor(at: Instant) -> u64 {
+    (at.since_boot().as_nanos() * u128::from(TICK_HZ) / NANOS_PER_SEC) as u64
+}
+
+/// The first tick at or after `at`.
+fn tick_ceil(at: Instant) -> u64 {
+    let nanos = at.since_boot().as_nanos() * u128::from(TICK_HZ);
+    nanos.div_ceil(NANOS_PER_SEC) as u64
+}
+
+/// The roundest tick from `earliest` to `latest`: the latest that's a
+/// multiple of the biggest power of two that leaves one in range.
+fn coalesce(earliest: u64, latest: u64) -> u64 {
+    let span = latest - earliest + 1;
+    let granule = 1 << (u64::BITS - 1 - span.leading_zeros());
+    latest & !(granule - 1)
+}
+
+/// An armed timer. Dropping it cancels it.
+pub struct Timer {
+    entry: Arc<Entry>,
+}
+
+impl Timer {
+    /// Arms a timer to run `callback` at `deadline`, to the tick. `callback`
+    /// runs in interrupt context, so mustn't block, and mustn't cancel its
+    /// own timer.
+    pub fn start(deadline: Instant, callback: impl FnOnce() + Send + 'static) -> Self {
+        Self::start_with_slack(deadline, Duration::ZERO, callback)
+    }
+
+    /// Arms a timer to run `callback` from `deadline` to `slack` after it,
+    /// coalesced with other timers due then.
+    pub fn start_with_slack(
+        deadline: Instant,
+        slack: Duration,
+        callback: impl FnOnce() + Send + 'static,
+    ) -> Self {
+        let callback: Callback = Box::new(callback);
+        let earliest = tick_ceil(deadline);
+        let latest = tick_floor(deadline + slack).max(earliest);
+        let mut wheel = WHEEL.lock();
+        // Before the first tick, the wheel starts from now.
+        let now = *wheel.now.get_or_insert_with(|| tick_floor(Instant::now()));
+        let entry = Arc::new(Entry {
+            // The tick that's been run is over, so one that's passed is due
+            // on the next.
+            expires: coalesce(earliest, latest).max(now + 1),
+            state: AtomicU8::new(PENDING),
+            callback: SpinLockIrqSave::new(Some(callback)),
+        });
+        wheel.count += 1;
+        wheel.insert(now, entry.clone());
+        Self { entry }
+    }
+
+    /// Whether the timer has run, or started to.
+    pub fn has_fired(&self) -> bool {
+        self.entry.state.load(Ordering::Acquire) != PENDING
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let cancelled = self
+            .entry
+            .state
+            .compare_exchange(PENDING, DONE, Ordering::Acquire, Ordering::Acquire)
+            .is_ok();
+        if cancelled {
+            // It stays in its slot until it comes round, but doesn't hold on
+            // to what its callback does.
+            drop(self.entry.callback.lock().take());
+        } else {
+            while self.entry.state.load(Ordering::Acquire) == RUNNING {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+/// Runs the timers due by `now`. Called on every tick of every hart, from
+/// interrupt context; whichever hart gets there first runs them.
+pub fn run_timers(now: Instant) {
+    let mut expired = Vec::new();
+    WHEEL.lock().advance(tick_floor(now), &mut expired);
+    // Run with the wheel unlocked, so that callbacks can arm timers.
+    for entry in expired {
+        entry.run();
+    }
+}
+
+ktest! {
+    fn timers_run_once_and_cancel() {
+        use core::sync::atomic::AtomicUsize;
+
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let soon = Instant::now() + Duration::from_millis(20);
+        let fired = Timer::start(soon, || {
+            RUNS.fetch_add(1, Ordering::Relaxed);
+        });
+        let cancelled = Timer::start(soon, || {
+            RUNS.fetch_add(10, Ordering::Relaxed);
+        });
+        drop(cancelled);
+        crate::task::sleep(Duration::from_millis(50));
+        assert!(fired.has_fired());
+        drop(fired);
+        assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+    }
+}
+
+ktest! {
+    fn slack_coalesces_onto_round_ticks() {
+        assert_eq!(coalesce(5, 5), 5);
+        assert_eq!(coalesce(5, 12), 8);
+        assert_eq!(coalesce(100, 130), 128);
+        // Overlapping ranges meet on the same tick.
+        assert_eq!(coalesce(97, 140), coalesce(110, 150));
+    }
+}
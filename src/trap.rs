@@ -0,0 +1,327 @@
+use core::arch::global_asm;
+use core::fmt;
+
+use crate::arch::csr::{self, Bits, Sstatus};
+use crate::arch::fpu::{self, ExtState};
+use crate::arch::insn;
+use crate::mm::addr_space::{Access, FaultError};
+use crate::mm::paging::{self, PAGE_SIZE};
+use crate::mm::uaccess;
+use crate::util::{align_down, hexdump};
+use crate::{
+    backtrace, drivers, gdb, log_info, log_warn, oom, print, println, profile, sbi, signal, smp,
+    syscall, task, watchdog,
+};
+
+global_asm!(concat!(crate::arch::asm_prelude!(), include_str!("trap.s")));
+
+extern "C" {
+    fn trap_entry();
+    fn trap_vector();
+}
+
+const STVEC_VECTORED: usize = 1;
+
+const SCAUSE_INTERRUPT: usize = 1 << (usize::BITS - 1);
+
+/// The registers saved by `trap_entry`. The layout must match trap.s.
+#[repr(C)]
+#[derive(Clone)]
+pub struct TrapFrame {
+    /// `x0` to `x31`; `regs[0]` is unused.
+    pub regs: [usize; 32],
+    pub sstatus: usize,
+    pub sepc: usize,
+    pub scause: usize,
+    pub stval: usize,
+}
+
+const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "scause = {:#018x} ({}), sepc = {:#018x}, stval = {:#018x}",
+            self.scause,
+            Trap::from_scause(self.scause),
+            self.sepc,
+            self.stval
+        )?;
+        writeln!(f, "sstatus = {:#018x}", self.sstatus)?;
+        for (i, (name, value)) in REG_NAMES.iter().zip(self.regs).enumerate().skip(1) {
+            write!(f, "{:>4} = {:#018x}", name, value)?;
+            if i % 4 == 3 {
+                writeln!(f)?;
+            } else {
+                write!(f, "  ")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Trap {
+    Interrupt(usize),
+    Exception(usize),
+}
+
+impl Trap {
+    pub const SUPERVISOR_SOFTWARE: Self = Self::Interrupt(1);
+    pub const SUPERVISOR_TIMER: Self = Self::Interrupt(5);
+    pub const SUPERVISOR_EXTERNAL: Self = Self::Interrupt(9);
+
+    pub const ILLEGAL_INSTRUCTION: Self = Self::Exception(2);
+    pub const BREAKPOINT: Self = Self::Exception(3);
+    pub const USER_ECALL: Self = Self::Exception(8);
+
+    /// Whether this is a page fault on an instruction fetch, load or store.
+    pub fn is_page_fault(self) -> bool {
+        matches!(self, Self::Exception(12 | 13 | 15))
+    }
+
+    /// Whether this is a page or access fault on a load or store.
+    pub fn is_memory_fault(self) -> bool {
+        matches!(self, Self::Exception(5 | 7 | 13 | 15))
+    }
+
+    pub fn from_scause(scause: usize) -> Self {
+        if scause & SCAUSE_INTERRUPT != 0 {
+            Self::Interrupt(scause & !SCAUSE_INTERRUPT)
+        } else {
+            Self::Exception(scause)
+        }
+    }
+
+    /// The name of this trap in the privileged spec.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Interrupt(1) => "supervisor software interrupt",
+            Self::Interrupt(5) => "supervisor timer interrupt",
+            Self::Interrupt(9) => "supervisor external interrupt",
+            Self::Interrupt(13) => "counter overflow interrupt",
+            Self::Interrupt(_) => "unknown interrupt",
+            Self::Exception(0) => "instruction address misaligned",
+            Self::Exception(1) => "instruction access fault",
+            Self::Exception(2) => "illegal instruction",
+            Self::Exception(3) => "breakpoint",
+            Self::Exception(4) => "load address misaligned",
+            Self::Exception(5) => "load access fault",
+            Self::Exception(6) => "store/AMO address misaligned",
+            Self::Exception(7) => "store/AMO access fault",
+            Self::Exception(8) => "environment call from U-mode",
+            Self::Exception(9) => "environment call from S-mode",
+            Self::Exception(12) => "instruction page fault",
+            Self::Exception(13) => "load page fault",
+            Self::Exception(15) => "store/AMO page fault",
+            Self::Exception(18) => "software check",
+            Self::Exception(19) => "hardware error",
+            Self::Exception(_) => "unknown exception",
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Installs the trap vector on this hart. In vectored mode, the default,
+/// interrupts that have a handler enter `interrupt_handler` through entries
+/// of their own, and everything else `trap_handler` through `trap_entry`.
+/// The `trap-direct` feature, or a hart without vectored mode, has every
+/// trap enter `trap_entry`, which is easier to follow in a debugger.
+pub fn init() {
+    // Until a thread that uses them runs.
+    fpu::disable();
+    if cfg!(not(feature = "trap-direct")) {
+        // SAFETY: `trap_vector` is 256-byte aligned, and each of its slots
+        // jumps to an entry that can handle the trap.
+        unsafe { csr::stvec::write(trap_vector as *const () as usize | STVEC_VECTORED) };
+        // The mode is WARL, so an unsupported one doesn't stick.
+        if csr::stvec::read() & STVEC_VECTORED != 0 {
+            return;
+        }
+    }
+    // SAFETY: `trap_entry` is 4-byte aligned and can handle any trap taken
+    // from S-mode.
+    unsafe { csr::stvec::write(trap_entry as *const () as usize) };
+}
+
+/// Enables interrupts on this hart.
+///
+/// SAFETY: the trap vector must be installed, and every interrupt enabled in
+/// `sie` must be handled.
+pub unsafe fn enable_interrupts() {
+    csr::sstatus::set(Sstatus::SIE);
+}
+
+/// Disables interrupts on this hart, returning whether they were enabled.
+pub fn disable_interrupts() -> bool {
+    // SAFETY: disabling interrupts is always safe.
+    unsafe { csr::sstatus::clear(Sstatus::SIE) }.contains(Sstatus::SIE)
+}
+
+/// Re-enables interrupts if `enabled`, as returned by `disable_interrupts`.
+pub fn restore_interrupts(enabled: bool) {
+    if enabled {
+        // SAFETY: interrupts were enabled before.
+        unsafe { enable_interrupts() };
+    }
+}
+
+/// Tries to resolve a page fault at `addr` in the running task's address
+/// space, returning true if the access can be retried.
+fn handle_user_page_fault(trap: Trap, addr: usize, from_user: bool) -> bool {
+    let access = match trap {
+        Trap::Exception(12) => Access::Execute,
+        Trap::Exception(13) => Access::Read,
+        _ => Access::Write,
+    };
+    let Some(space) = task::address_space() else {
+        return false;
+    };
+    match space.handle_fault(addr, access) {
+        Ok(()) => true,
+        Err(FaultError::Denied) => false,
+        // Retried once the OOM killer has freed some memory. If the process
+        // is the one killed, U-mode gets the `SIGKILL` before retrying, but
+        // a copy for a syscall has to fail.
+        Err(FaultError::OutOfMemory) => oom::out_of_memory() || from_user,
+    }
+}
+
+#[no_mangle]
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    let trap = Trap::from_scause(frame.scause);
+    let from_user = !Sstatus::from_bits(frame.sstatus).contains(Sstatus::SPP);
+    // Page faults on user memory may just need a page mapped, after which
+    // the access is retried.
+    let resolved = trap.is_page_fault()
+        && (from_user || uaccess::is_user_copy(frame.sepc))
+        && handle_user_page_fault(trap, frame.stval, from_user);
+
+    match trap {
+        _ if resolved => {}
+        Trap::BREAKPOINT if !from_user && gdb::handle_breakpoint(frame) => {}
+        Trap::BREAKPOINT if !from_user => {
+            log_info!("breakpoint at {:#x}", frame.sepc);
+            frame.sepc += instruction_len(frame.sepc);
+        }
+        Trap::Interrupt(_) if handle_interrupt(frame, trap) => {}
+        Trap::USER_ECALL => syscall::handle(frame),
+        // A debugger's breakpoint, or one stepping, stops the process for it.
+        Trap::BREAKPOINT
+            if from_user && task::process().is_some_and(|process| process.trace().is_traced()) =>
+        {
+            signal::send_fault(frame, signal::SIGTRAP)
+        }
+        trap if trap.is_memory_fault() && uaccess::is_user_copy(frame.sepc) => {
+            uaccess::abort_copy(frame)
+        }
+        // Perhaps the thread's first floating-point or vector instruction:
+        // it's given registers and retried, and traps again if it wasn't.
+        Trap::ILLEGAL_INSTRUCTION
+            if from_user && fpu::is_off(frame.sstatus) && !task::has_ext_state() =>
+        {
+            task::set_ext_state(Some(ExtState::new()));
+        }
+        trap if from_user => {
+            log_warn!(
+                "task {}: {} at {:#x}, stval = {:#x}",
+                task::current(),
+                trap,
+                frame.sepc,
+                frame.stval
+            );
+            signal::send_fault(frame, signal::for_fault(trap));
+        }
+        _ => {
+            print!("{}", frame);
+            print_stack(frame.regs[2]);
+            backtrace::print_from(frame.sepc, frame.regs[8]);
+            panic!("unhandled trap: {}", trap);
+        }
+    }
+
+    finish(frame, trap, from_user);
+}
+
+/// Entered in vectored mode for interrupts `trap_vector` has an entry for.
+#[no_mangle]
+extern "C" fn interrupt_handler(frame: &mut TrapFrame, cause: usize) {
+    let trap = Trap::Interrupt(cause);
+    let handled = handle_interrupt(frame, trap);
+    debug_assert!(handled, "no handler for {}", trap);
+    let from_user = !Sstatus::from_bits(frame.sstatus).contains(Sstatus::SPP);
+    finish(frame, trap, from_user);
+}
+
+/// Handles the interrupts the kernel uses, returning false for others.
+fn handle_interrupt(frame: &TrapFrame, trap: Trap) -> bool {
+    match trap {
+        Trap::SUPERVISOR_SOFTWARE => {
+            smp::handle_ipi();
+            task::preempt_if_needed();
+        }
+        Trap::SUPERVISOR_TIMER => {
+            profile::sample(frame);
+            watchdog::tick(frame);
+            sbi::timer::handle_interrupt()
+        }
+        Trap::SUPERVISOR_EXTERNAL => drivers::plic::handle_interrupt(),
+        _ => return false,
+    }
+    true
+}
+
+/// What's done after any trap, before returning from it.
+fn finish(frame: &mut TrapFrame, trap: Trap, from_user: bool) {
+    if matches!(trap, Trap::Interrupt(_)) && !from_user {
+        gdb::handle_interrupt(frame);
+    }
+    if from_user {
+        if let Some(process) = task::process() {
+            process.check_cpu_limit();
+        }
+        signal::deliver(frame);
+        fpu::update_frame(&mut frame.sstatus);
+    }
+}
+
+/// The length of the instruction at `pc`, which is 2 for compressed
+/// instructions and 4 otherwise.
+fn instruction_len(pc: usize) -> usize {
+    // SAFETY: `pc` is the address of an instruction that just trapped, so it
+    // is mapped, and instructions are at least 2-byte aligned.
+    insn::len(unsafe { (pc as *const u16).read() })
+}
+
+/// How much of the stack above the stack pointer to show when the kernel
+/// dies of a trap.
+const STACK_DUMP_LEN: usize = 128;
+
+/// Prints the top of the stack at `sp`, as far as it's mapped. The trap
+/// may have interrupted code holding the kernel page table's lock, so it's
+/// taken to be unmapped if it is.
+fn print_stack(sp: usize) {
+    let end = sp.saturating_add(STACK_DUMP_LEN);
+    let mut mapped_end = sp;
+    while mapped_end < end && paging::try_translate(mapped_end).is_some() {
+        mapped_end = align_down(mapped_end, PAGE_SIZE) + PAGE_SIZE;
+    }
+    let mapped = mapped_end.min(end) - sp;
+    let mut bytes = [0; STACK_DUMP_LEN];
+    for (i, byte) in bytes[..mapped].iter_mut().enumerate() {
+        // SAFETY: the address is mapped, as just checked.
+        *byte = unsafe { ((sp + i) as *const u8).read_volatile() };
+    }
+    println!("stack:");
+    println!("{}", hexdump(sp, &bytes[..mapped]));
+}
@@ -0,0 +1,165 @@
+.section .text
+.global trap_entry
+.global trap_vector
+.global trap_return
+
+# Size of a TrapFrame in src/trap.rs: x0-x31, sstatus, sepc, scause, stval.
+.equ TRAP_FRAME_SIZE, 36 * REGBYTES
+
+.equ SSTATUS_SPP, 1 << 8
+
+# The region kernel stacks are in, and log2 of their size; each is at the
+# top of a slot twice as large. Must match src/mm/stack.rs.
+.if REGBYTES == 8
+.equ STACK_REGION, 0xffffffff00000000
+.equ STACK_REGION_SHIFT, 30
+.else
+.equ STACK_REGION, 0xff800000
+.equ STACK_REGION_SHIFT, 22
+.endif
+.equ STACK_SHIFT, 14
+
+# Saves the interrupted context as a TrapFrame at sp, which is left
+# pointing at it.
+#
+# sscratch is zero while in S-mode. While in U-mode it holds the top of the
+# task's kernel stack, which the frame is saved just below; the word at the
+# top holds the kernel's tp.
+.macro SAVE_FRAME
+    csrrw sp, sscratch, sp
+    bnez sp, 1f
+    # from S-mode: switch back, leaving sscratch zero
+    csrrw sp, sscratch, sp
+
+    # If the frame would go below a kernel stack into its guard, saving it
+    # would fault again, and again. sscratch holds t0 while that's checked.
+    csrw sscratch, t0
+    li t0, STACK_REGION + TRAP_FRAME_SIZE
+    sub t0, sp, t0
+    srli t0, t0, STACK_REGION_SHIFT
+    bnez t0, 5f
+    li t0, STACK_REGION + TRAP_FRAME_SIZE
+    sub t0, sp, t0
+    srli t0, t0, STACK_SHIFT
+    andi t0, t0, 1
+    beqz t0, stack_overflow
+5:  csrrw t0, sscratch, zero
+1:
+    addi sp, sp, -TRAP_FRAME_SIZE
+
+    REG_S x1, 1*REGBYTES(sp)
+    .irp n, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    REG_S x\n, \n*REGBYTES(sp)
+    .endr
+
+    csrr t0, sstatus
+    REG_S t0, 32*REGBYTES(sp)
+
+    # save the sp from before the trap: from U-mode, the user sp swapped
+    # into sscratch, whatever it is; from S-mode, the kernel sp just above
+    # the frame
+    andi t0, t0, SSTATUS_SPP
+    bnez t0, 2f
+    csrr t0, sscratch
+    j 6f
+2:  addi t0, sp, TRAP_FRAME_SIZE
+6:  REG_S t0, 2*REGBYTES(sp)
+    csrw sscratch, zero
+
+    csrr t0, sepc
+    REG_S t0, 33*REGBYTES(sp)
+    csrr t0, scause
+    REG_S t0, 34*REGBYTES(sp)
+    csrr t0, stval
+    REG_S t0, 35*REGBYTES(sp)
+
+    # from U-mode, tp and gp may hold anything
+    REG_L t0, 32*REGBYTES(sp)
+    andi t0, t0, SSTATUS_SPP
+    bnez t0, 3f
+    REG_L tp, TRAP_FRAME_SIZE(sp)
+    .option push
+    .option norelax
+    la gp, __global_pointer$
+    .option pop
+3:
+.endm
+
+# The trap vector for vectored mode (see src/trap.rs). Exceptions enter at
+# its start and interrupt n at 4*n, each slot a jump that mustn't be
+# compressed. It's here rather than in start.s to keep the jumps in range.
+.balign 256
+trap_vector:
+    .option push
+    .option norvc
+    j trap_entry
+    .irp cause, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+    .if \cause == 1 || \cause == 5 || \cause == 9
+    j interrupt_entry_\cause
+    .else
+    j trap_entry
+    .endif
+    .endr
+    .option pop
+
+# The entry for interrupt `cause` in vectored mode, which calls
+# interrupt_handler with the cause rather than trap_handler decoding it.
+.macro INTERRUPT_ENTRY cause
+.balign 4
+interrupt_entry_\cause:
+    SAVE_FRAME
+    mv a0, sp
+    li a1, \cause
+    call interrupt_handler
+    j trap_return
+.endm
+
+INTERRUPT_ENTRY 1
+INTERRUPT_ENTRY 5
+INTERRUPT_ENTRY 9
+
+# Saves the interrupted context, calls trap_handler with a pointer to it,
+# then resumes from the (possibly modified) frame. The only entry in direct
+# mode, and for exceptions in vectored mode.
+.balign 4
+trap_entry:
+    SAVE_FRAME
+    mv a0, sp
+    call trap_handler
+
+# Resumes from the TrapFrame at sp. Also used to enter U-mode for the first
+# time, see src/user.rs.
+trap_return:
+    REG_L t0, 32*REGBYTES(sp)
+    csrw sstatus, t0
+    REG_L t1, 33*REGBYTES(sp)
+    csrw sepc, t1
+
+    # returning to U-mode: leave the kernel stack and tp for the next trap
+    andi t0, t0, SSTATUS_SPP
+    bnez t0, 4f
+    REG_S tp, TRAP_FRAME_SIZE(sp)
+    addi t0, sp, TRAP_FRAME_SIZE
+    csrw sscratch, t0
+4:
+
+    REG_L x1, 1*REGBYTES(sp)
+    .irp n, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    REG_L x\n, \n*REGBYTES(sp)
+    .endr
+    REG_L sp, 2*REGBYTES(sp)
+
+    sret
+
+# Reports a kernel stack overflow from the hart's overflow stack, whose top
+# is in OVERFLOW_STACK_TOP in its per-hart area. Only sp and fp are kept.
+stack_overflow:
+    csrw sscratch, zero
+    mv a0, sp
+    mv a1, s0
+    lla t0, _spercpu
+    sub sp, tp, t0
+    lla t0, OVERFLOW_STACK_TOP
+    add t0, t0, sp
+    REG_L sp, 0(t0)
+    call kernel_stack_overflow
@@ -0,0 +1,701 @@
+//! Terminals, between the devices they're on, one of the console's virtual
+//! consoles (see `vt`) or a pseudo-terminal (see `pty`), and user space.
+//! Input goes through a line discipline, set up with `termios` as on Linux.
+//! In canonical mode, the default, it's edited a line at a time and echoed,
+//! and a read returns at most one line, once it's ended by a newline, or by
+//! EOF, with which a read at the start of a line returns 0. In raw mode,
+//! with `ICANON` cleared, reads return bytes as they come, as `VMIN` and
+//! `VTIME` say. Output has newlines turned into CR LF with `ONLCR`, unless
+//! the device does that itself, as the console does. Input is echoed as it's
+//! read, rather than as it's typed.
+//!
+//! For job control, a session leader makes a terminal its session's
+//! controlling terminal with `TIOCSCTTY`, and the shell then picks which of
+//! the session's process groups is in the foreground with `TIOCSPGRP`.
+//! While there's a foreground group and `ISIG` is set, typing the `VINTR`,
+//! `VSUSP` or `VQUIT` character, Ctrl-C, Ctrl-Z or Ctrl-\ by default, sends
+//! it `SIGINT`, `SIGTSTP` or `SIGQUIT` rather than being read, and discards
+//! the line being edited. When the session leader exits, or the terminal
+//! hangs up, the foreground group is sent `SIGHUP` and the terminal is
+//! released.
+//!
+//! Console keys arrive in interrupt context, where processes can't be
+//! signalled, so the signals are sent from the workqueue. Processes in the
+//! background can still read their terminal.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::ktest;
+use crate::mm::uaccess::{read_user_struct, write_user_struct, Plain};
+use crate::process::{self, Pid, Process};
+use crate::signal::{SIGCONT, SIGHUP, SIGINT, SIGQUIT, SIGTSTP};
+use crate::sync::{SpinLock, SpinLockIrqSave};
+use crate::syscall::Errno;
+use crate::time::{Duration, Instant, Timer};
+use crate::{io, pty, task, vt, workqueue};
+
+/// `ioctl` requests.
+const TCGETS: usize = 0x5401;
+const TCSETS: usize = 0x5402;
+const TCSETSW: usize = 0x5403;
+const TCSETSF: usize = 0x5404;
+const TIOCSCTTY: usize = 0x540e;
+const TIOCGPGRP: usize = 0x540f;
+const TIOCSPGRP: usize = 0x5410;
+const TIOCGSID: usize = 0x5429;
+
+/// `c_iflag` bits.
+const INLCR: u32 = 0o100;
+const IGNCR: u32 = 0o200;
+const ICRNL: u32 = 0o400;
+
+/// `c_oflag` bits.
+const OPOST: u32 = 0o1;
+const ONLCR: u32 = 0o4;
+
+/// `c_cflag` bits.
+const B38400: u32 = 0o17;
+const CS8: u32 = 0o60;
+const CREAD: u32 = 0o200;
+
+/// `c_lflag` bits.
+const ISIG: u32 = 0o1;
+const ICANON: u32 = 0o2;
+const ECHO: u32 = 0o10;
+const ECHOE: u32 = 0o20;
+const ECHOK: u32 = 0o40;
+const ECHONL: u32 = 0o100;
+const ECHOCTL: u32 = 0o1000;
+const ECHOKE: u32 = 0o4000;
+const IEXTEN: u32 = 0o100000;
+
+/// Indices of the special characters in `c_cc`.
+const VINTR: usize = 0;
+const VQUIT: usize = 1;
+const VERASE: usize = 2;
+const VKILL: usize = 3;
+const VEOF: usize = 4;
+const VTIME: usize = 5;
+const VMIN: usize = 6;
+const VSUSP: usize = 10;
+const VEOL: usize = 11;
+const VWERASE: usize = 14;
+
+const NCCS: usize = 19;
+
+/// The longest line that can be edited. Bytes past it are dropped.
+const MAX_CANON: usize = 4096;
+
+/// Linux's `struct termios`, as `TCGETS` and `TCSETS` take it.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Termios {
+    iflag: u32,
+    oflag: u32,
+    cflag: u32,
+    lflag: u32,
+    line: u8,
+    cc: [u8; NCCS],
+}
+
+// SAFETY: all fields are integers, with no padding between them.
+unsafe impl Plain for Termios {}
+
+impl Termios {
+    /// The settings the console starts with, as Linux's.
+    const DEFAULT: Self = Self {
+        iflag: ICRNL,
+        oflag: OPOST | ONLCR,
+        cflag: B38400 | CS8 | CREAD,
+        lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE | IEXTEN,
+        line: 0,
+        cc: [
+            0x03, 0x1c, 0x7f, 0x15, 0x04, 0, 1, 0, 0x11, 0x13, 0x1a, 0, 0x12, 0x0f, 0x17, 0x16, 0,
+            0, 0,
+        ],
+    };
+
+    fn has(&self, lflag: u32) -> bool {
+        self.lflag & lflag != 0
+    }
+
+    /// Whether `byte` is the special character `index`, which is disabled
+    /// if it's 0.
+    fn is_special(&self, index: usize, byte: u8) -> bool {
+        self.cc[index] != 0 && self.cc[index] == byte
+    }
+}
+
+/// Where a terminal's input comes from and its output goes.
+pub trait Device: Clone + Send + Sync + 'static {
+    /// The next byte of input, if any has arrived.
+    fn read_byte(&self) -> Option<u8>;
+
+    /// Waits for the next byte of input, giving up and returning `None`
+    /// once `stop` returns true or the device hangs up. `stop` is checked
+    /// whenever input arrives or `wake_readers` is called.
+    fn wait_byte_unless(&self, stop: &mut dyn FnMut() -> bool) -> Option<u8>;
+
+    /// Wakes the tasks waiting for input, for them to check whether to
+    /// stop.
+    fn wake_readers(&self);
+
+    /// Writes output, blocking until there's room for it all.
+    fn write(&self, bytes: &[u8]) -> Result<usize, Errno>;
+
+    /// Writes echoed input, which mustn't block, so may be dropped.
+    fn echo(&self, bytes: &[u8]);
+
+    /// Whether there's nothing left on the other end, so reads end.
+    fn is_hung_up(&self) -> bool {
+        false
+    }
+
+    /// Whether the device ends lines with CR LF itself, so `ONLCR` is left
+    /// to it.
+    fn ends_lines(&self) -> bool {
+        false
+    }
+}
+
+/// Settings and job control state, which input interrupts look at.
+struct State {
+    /// The session the terminal is the controlling terminal of.
+    session: Option<Pid>,
+    foreground: Option<Pid>,
+    termios: Termios,
+}
+
+/// Input the line discipline has taken in but that hasn't been read.
+struct Input {
+    /// The line being edited, in canonical mode.
+    line: Vec<u8>,
+    /// Lines that have been ended but not read. An empty one is an EOF.
+    lines: VecDeque<Vec<u8>>,
+}
+
+impl Input {
+    const fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            lines: VecDeque::new(),
+        }
+    }
+
+    fn flush(&mut self) {
+        self.line.clear();
+        self.lines.clear();
+    }
+
+    /// Takes in `byte` in canonical mode, editing the line with it or
+    /// ending it, and passing what to echo, if `termios` says to, to
+    /// `echo`.
+    fn receive(&mut self, termios: &Termios, byte: u8, echo: &mut dyn FnMut(&[u8])) {
+        let echoing = termios.has(ECHO);
+        if termios.is_special(VERASE, byte) {
+            if let Some(erased) = self.line.pop() {
+                if echoing && termios.has(ECHOE) {
+                    erase_echo(termios, erased, echo);
+                }
+            }
+        } else if termios.is_special(VWERASE, byte) && termios.has(IEXTEN) {
+            let mut in_word = false;
+            while let Some(&last) = self.line.last() {
+                let space = last == b' ' || last == b'\t';
+                if in_word && space {
+                    break;
+                }
+                in_word |= !space;
+                self.line.pop();
+                if echoing && termios.has(ECHOE) {
+                    erase_echo(termios, last, echo);
+                }
+            }
+        } else if termios.is_special(VKILL, byte) {
+            if echoing && termios.has(ECHOKE) {
+                for &erased in self.line.iter().rev() {
+                    erase_echo(termios, erased, echo);
+                }
+            } else if echoing && termios.has(ECHOK) {
+                echo_byte(termios, byte, echo);
+                echo(b"\n");
+            }
+            self.line.clear();
+        } else if termios.is_special(VEOF, byte) {
+            self.lines.push_back(core::mem::take(&mut self.line));
+        } else if byte == b'\n' || termios.is_special(VEOL, byte) {
+            self.line.push(byte);
+            self.lines.push_back(core::mem::take(&mut self.line));
+            if echoing || termios.has(ECHONL) {
+                echo_byte(termios, byte, echo);
+            }
+        } else if self.line.len() < MAX_CANON - 1 {
+            self.line.push(byte);
+            if echoing {
+                echo_byte(termios, byte, echo);
+            }
+        }
+    }
+
+    /// Copies as much of the next ended line as fits into `buf`, returning
+    /// how much, or `None` if there isn't one.
+    fn take_line(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let line = self.lines.front_mut()?;
+        let len = line.len().min(buf.len());
+        buf[..len].copy_from_slice(&line[..len]);
+        line.drain(..len);
+        if line.is_empty() {
+            self.lines.pop_front();
+        }
+        Some(len)
+    }
+}
+
+/// Whether `byte` echoes as `^` and a letter.
+fn echoes_as_control(termios: &Termios, byte: u8) -> bool {
+    termios.has(ECHOCTL) && (byte < 0x20 && byte != b'\t' && byte != b'\n' || byte == 0x7f)
+}
+
+fn echo_byte(termios: &Termios, byte: u8, echo: &mut dyn FnMut(&[u8])) {
+    if echoes_as_control(termios, byte) {
+        echo(&[b'^', byte ^ 0x40]);
+    } else {
+        echo(&[byte]);
+    }
+}
+
+/// Rubs out the echo of `byte` from the end of the line.
+fn erase_echo(termios: &Termios, byte: u8, echo: &mut dyn FnMut(&[u8])) {
+    let width = if echoes_as_control(termios, byte) {
+        2
+    } else {
+        1
+    };
+    for _ in 0..width {
+        echo(b"\x08 \x08");
+    }
+}
+
+/// Maps `byte` as the input flags say, returning `None` if it's to be
+/// ignored.
+fn map_input(termios: &Termios, byte: u8) -> Option<u8> {
+    match byte {
+        b'\r' if termios.iflag & IGNCR != 0 => None,
+        b'\r' if termios.iflag & ICRNL != 0 => Some(b'\n'),
+        b'\n' if termios.iflag & INLCR != 0 => Some(b'\r'),
+        _ => Some(byte),
+    }
+}
+
+/// Passes `bytes` to `write` as the output flags say, in pieces if
+/// newlines are turned into CR LF.
+fn post_output(
+    termios: &Termios,
+    device: &impl Device,
+    bytes: &[u8],
+    mut write: impl FnMut(&[u8]) -> Result<usize, Errno>,
+) -> Result<usize, Errno> {
+    let onlcr = termios.oflag & (OPOST | ONLCR) == OPOST | ONLCR && !device.ends_lines();
+    if !onlcr {
+        return write(bytes);
+    }
+    let mut written = 0;
+    for piece in bytes.split_inclusive(|&byte| byte == b'\n') {
+        match piece.strip_suffix(b"\n") {
+            Some(text) => {
+                write(text)?;
+                write(b"\r\n")?;
+            }
+            None => {
+                write(piece)?;
+            }
+        }
+        written += piece.len();
+    }
+    Ok(written)
+}
+
+/// Sends `signal` to the group `pgid`.
+fn signal_group(pgid: Pid, signal: u8) {
+    for process in process::group(pgid) {
+        process.send_signal(signal);
+    }
+}
+
+/// A terminal's settings, job control and line discipline, for input from
+/// and output to a `Device`.
+pub struct Tty {
+    state: SpinLockIrqSave<State>,
+    input: SpinLock<Input>,
+}
+
+impl Tty {
+    pub const fn new() -> Self {
+        Self {
+            state: SpinLockIrqSave::new(State {
+                session: None,
+                foreground: None,
+                termios: Termios::DEFAULT,
+            }),
+            input: SpinLock::new(Input::new()),
+        }
+    }
+
+    fn termios(&self) -> Termios {
+        self.state.lock().termios
+    }
+
+    /// The session this is the controlling terminal of, if any.
+    pub fn session(&self) -> Option<Pid> {
+        self.state.lock().session
+    }
+
+    /// Writes echoed input to `device`.
+    fn echo(&self, termios: &Termios, device: &impl Device, bytes: &[u8]) {
+        let _ = post_output(termios, device, bytes, |bytes| {
+            device.echo(bytes);
+            Ok(bytes.len())
+        });
+    }
+
+    /// Waits for the next byte of input from `device` for `process`, giving
+    /// up if it has a signal to take or once `timer` fires.
+    fn wait_input(device: &impl Device, process: &Process, timer: Option<&Timer>) -> Option<u8> {
+        device.wait_byte_unless(&mut || {
+            process.signals().has_deliverable() || timer.is_some_and(Timer::has_fired)
+        })
+    }
+
+    /// Reads input from `device` into `buf` for the running process,
+    /// through the line discipline.
+    pub fn read(&self, device: &impl Device, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let process = task::process().expect("terminal read from a task without a process");
+        loop {
+            let termios = self.termios();
+            if !termios.has(ICANON) {
+                return self.read_raw(device, &process, &termios, buf);
+            }
+            if let Some(len) = self.input.lock().take_line(buf) {
+                return Ok(len);
+            }
+            let Some(byte) = Self::wait_input(device, &process, None) else {
+                return if device.is_hung_up() {
+                    Ok(0)
+                } else {
+                    Err(Errno::EINTR)
+                };
+            };
+            if let Some(byte) = map_input(&termios, byte) {
+                self.input.lock().receive(&termios, byte, &mut |bytes| {
+                    self.echo(&termios, device, bytes);
+                });
+            }
+        }
+    }
+
+    /// Reads in raw mode: returns once there are `VMIN` bytes, or fewer if
+    /// `buf` is smaller, or once input stops for `VTIME` tenths of a
+    /// second, after the first byte if `VMIN` isn't 0. Input left from
+    /// canonical mode is read first.
+    fn read_raw(
+        &self,
+        device: &impl Device,
+        process: &Process,
+        termios: &Termios,
+        buf: &mut [u8],
+    ) -> Result<usize, Errno> {
+        let min = usize::from(termios.cc[VMIN]).min(buf.len());
+        let time = Duration::from_millis(u64::from(termios.cc[VTIME]) * 100);
+        let mut len = {
+            let mut input = self.input.lock();
+            let mut len = 0;
+            while let Some(taken) = input.take_line(&mut buf[len..]).filter(|&n| n > 0) {
+                len += taken;
+            }
+            len
+        };
+        let size = buf.len();
+        let mut take = |byte: u8, len: &mut usize| {
+            if let Some(byte) = map_input(termios, byte) {
+                if termios.has(ECHO) {
+                    echo_byte(termios, byte, &mut |bytes| {
+                        self.echo(termios, device, bytes)
+                    });
+                }
+                buf[*len] = byte;
+                *len += 1;
+            }
+        };
+        loop {
+            while len < size {
+                let Some(byte) = device.read_byte() else {
+                    break;
+                };
+                take(byte, &mut len);
+            }
+            if len == size || len >= min && (len > 0 || time.is_zero()) {
+                return Ok(len);
+            }
+            let timer = (!time.is_zero() && (min == 0 || len > 0)).then(|| {
+                let device = device.clone();
+                Timer::start(Instant::now() + time, move || device.wake_readers())
+            });
+            match Self::wait_input(device, process, timer.as_ref()) {
+                Some(byte) => take(byte, &mut len),
+                None if len > 0 || device.is_hung_up() => return Ok(len),
+                None if timer.as_ref().is_some_and(Timer::has_fired) => return Ok(len),
+                None => return Err(Errno::EINTR),
+            }
+        }
+    }
+
+    /// Writes `buf` to `device`, for the running process.
+    pub fn write(&self, device: &impl Device, buf: &[u8]) -> Result<usize, Errno> {
+        post_output(&self.termios(), device, buf, |bytes| device.write(bytes))
+    }
+
+    /// The group to signal, and the signal, if input `byte` is a character
+    /// that signals the foreground group rather than being read.
+    pub fn signal_for(&self, byte: u8) -> Option<(Pid, u8)> {
+        let state = self.state.lock();
+        let termios = &state.termios;
+        if !termios.has(ISIG) {
+            return None;
+        }
+        let signal = if termios.is_special(VINTR, byte) {
+            SIGINT
+        } else if termios.is_special(VSUSP, byte) {
+            SIGTSTP
+        } else if termios.is_special(VQUIT, byte) {
+            SIGQUIT
+        } else {
+            return None;
+        };
+        Some((state.foreground?, signal))
+    }
+
+    /// Sends `signal` to the group `pgid` for the input `byte`, as
+    /// `signal_for` said to, discarding the line being edited and waking
+    /// readers for them to take the signal.
+    pub fn interrupt(&self, device: &impl Device, byte: u8, pgid: Pid, signal: u8) {
+        let termios = self.termios();
+        self.input.lock().line.clear();
+        if termios.has(ECHO) {
+            echo_byte(&termios, byte, &mut |bytes| {
+                self.echo(&termios, device, bytes)
+            });
+        }
+        signal_group(pgid, signal);
+        device.wake_readers();
+    }
+
+    /// Stops being the controlling terminal of session `sid`, if it is.
+    pub fn release(&self, device: &impl Device, sid: Pid) {
+        if self.session() == Some(sid) {
+            self.hang_up(device);
+        }
+    }
+
+    /// Stops being a controlling terminal, sending its foreground group
+    /// `SIGHUP`, and `SIGCONT` in case it's stopped.
+    pub fn hang_up(&self, device: &impl Device) {
+        let foreground = {
+            let mut state = self.state.lock();
+            state.session = None;
+            state.foreground.take()
+        };
+        if let Some(pgid) = foreground {
+            signal_group(pgid, SIGHUP);
+            signal_group(pgid, SIGCONT);
+        }
+        device.wake_readers();
+    }
+
+    /// The running process, which must be in the session this is the
+    /// controlling terminal of.
+    fn controlling_process(&self) -> Result<Arc<Process>, Errno> {
+        let process = task::process().expect("ioctl from a task without a process");
+        if self.session() != Some(process.sid()) {
+            return Err(Errno::ENOTTY);
+        }
+        Ok(process)
+    }
+
+    /// Carries out `ioctl` `request` for the running process.
+    pub fn ioctl(&self, device: &impl Device, request: usize, arg: usize) -> Result<usize, Errno> {
+        match request {
+            TCGETS => {
+                write_user_struct(arg, &self.termios())?;
+                Ok(0)
+            }
+            // Output is never queued, so there's none to wait for.
+            TCSETS | TCSETSW | TCSETSF => {
+                let termios: Termios = read_user_struct(arg)?;
+                if request == TCSETSF {
+                    self.input.lock().flush();
+                    while device.read_byte().is_some() {}
+                }
+                self.state.lock().termios = termios;
+                Ok(0)
+            }
+            TIOCSCTTY => {
+                let process = task::process().expect("ioctl from a task without a process");
+                if !process.is_session_leader() {
+                    return Err(Errno::EPERM);
+                }
+                let sid = process.sid();
+                if self.session() != Some(sid) && has_controlling_tty(sid) {
+                    return Err(Errno::EPERM);
+                }
+                let mut state = self.state.lock();
+                // Taken by another session, unless its leader has exited.
+                if state.session.is_some_and(|session| session != sid) {
+                    return Err(Errno::EPERM);
+                }
+                state.session = Some(sid);
+                state.foreground = Some(process.pgid());
+                Ok(0)
+            }
+            TIOCGPGRP => {
+                self.controlling_process()?;
+                let pgid = self.state.lock().foreground.map_or(0, Pid::as_raw);
+                write_user_struct(arg, &(pgid as i32))?;
+                Ok(0)
+            }
+            TIOCSPGRP => {
+                let process = self.controlling_process()?;
+                let pgid: i32 = read_user_struct(arg)?;
+                if pgid <= 0 {
+                    return Err(Errno::EINVAL);
+                }
+                let pgid = Pid::from_raw(pgid as usize);
+                let in_session = process::group(pgid)
+                    .iter()
+                    .any(|member| member.sid() == process.sid());
+                if !in_session {
+                    return Err(Errno::EPERM);
+                }
+                self.state.lock().foreground = Some(pgid);
+                Ok(0)
+            }
+            TIOCGSID => {
+                let process = self.controlling_process()?;
+                write_user_struct(arg, &(process.sid().as_raw() as i32))?;
+                Ok(0)
+            }
+            _ => Err(Errno::ENOTTY),
+        }
+    }
+}
+
+/// The console's input and output, through `io`, as virtual console 0
+/// (see `vt`).
+#[derive(Clone)]
+struct ConsoleDevice;
+
+impl Device for ConsoleDevice {
+    fn read_byte(&self) -> Option<u8> {
+        vt::read_byte(0)
+    }
+
+    fn wait_byte_unless(&self, stop: &mut dyn FnMut() -> bool) -> Option<u8> {
+        vt::wait_byte_unless(0, stop)
+    }
+
+    fn wake_readers(&self) {
+        vt::wake_readers();
+    }
+
+    fn write(&self, bytes: &[u8]) -> Result<usize, Errno> {
+        io::write(bytes);
+        Ok(bytes.len())
+    }
+
+    fn echo(&self, bytes: &[u8]) {
+        io::write(bytes);
+    }
+
+    fn ends_lines(&self) -> bool {
+        true
+    }
+}
+
+static CONSOLE: Tty = Tty::new();
+
+/// Reads console input into `buf` for the running process.
+pub fn read(buf: &mut [u8]) -> Result<usize, Errno> {
+    CONSOLE.read(&ConsoleDevice, buf)
+}
+
+/// Writes `buf` to the console, for the running process.
+pub fn write(buf: &[u8]) -> Result<usize, Errno> {
+    CONSOLE.write(&ConsoleDevice, buf)
+}
+
+/// Carries out `ioctl` `request` on the console for the running process.
+pub fn ioctl(request: usize, arg: usize) -> Result<usize, Errno> {
+    vt::ioctl(request, arg).unwrap_or_else(|| CONSOLE.ioctl(&ConsoleDevice, request, arg))
+}
+
+/// Called with each byte of console input for virtual console 0 as it
+/// arrives, maybe in interrupt context. Returns whether it was a character
+/// that signals the foreground group, which isn't to be read.
+pub fn intercept(byte: u8) -> bool {
+    let Some((pgid, signal)) = CONSOLE.signal_for(byte) else {
+        return false;
+    };
+    workqueue::queue(move || CONSOLE.interrupt(&ConsoleDevice, byte, pgid, signal));
+    true
+}
+
+/// Whether session `sid` has a controlling terminal.
+fn has_controlling_tty(sid: Pid) -> bool {
+    CONSOLE.session() == Some(sid) || vt::has_controlling(sid) || pty::has_controlling(sid)
+}
+
+/// Called as the leader of session `sid` exits, releasing its controlling
+/// terminal.
+pub fn session_ended(sid: Pid) {
+    CONSOLE.release(&ConsoleDevice, sid);
+    vt::session_ended(sid);
+    pty::session_ended(sid);
+}
+
+ktest! {
+    fn canonical_mode_edits_lines() {
+        let termios = Termios {
+            lflag: ICANON | IEXTEN | ECHO | ECHOE | ECHOCTL,
+            ..Termios::DEFAULT
+        };
+        let mut input = Input::new();
+        let mut echoed = Vec::new();
+        let mut echo = |bytes: &[u8]| echoed.extend_from_slice(bytes);
+        for &byte in b"echo hi there\x17\x17you\x7f\x7f\x7fall\n" {
+            input.receive(&termios, byte, &mut echo);
+        }
+        for &byte in b"gone\x15\x01" {
+            input.receive(&termios, byte, &mut echo);
+        }
+        input.receive(&termios, 0x04, &mut echo);
+        input.receive(&termios, 0x04, &mut echo);
+        assert!(echoed.ends_with(b"all\ngone^A"));
+
+        let mut buf = [0; 5];
+        assert_eq!(input.take_line(&mut buf), Some(5));
+        assert_eq!(&buf, b"echo ");
+        let mut buf = [0; 16];
+        assert_eq!(input.take_line(&mut buf), Some(4));
+        assert_eq!(&buf[..4], b"all\n");
+        // The line ended by EOF, without a newline.
+        assert_eq!(input.take_line(&mut buf), Some(1));
+        assert_eq!(buf[0], 0x01);
+        // EOF at the start of a line.
+        assert_eq!(input.take_line(&mut buf), Some(0));
+        assert_eq!(input.take_line(&mut buf), None);
+    }
+}
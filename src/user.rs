@@ -0,0 +1,194 @@
+//! Running code in U-mode. A thread enters U-mode for good, coming back
+//! into the kernel only through traps, and ends with its `exit` syscall or
+//! by faulting.
+
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+
+use crate::arch::csr::{self, Bits, Sstatus};
+use crate::elf::{self, ElfError};
+use crate::mm::addr_space::{Access, AddressSpace, FaultError};
+use crate::mm::paging::{MapError, PteFlags, PAGE_SIZE};
+use crate::process::{Pid, Process};
+use crate::trap::{self, TrapFrame};
+use crate::util::{align_down, align_up};
+use crate::{rand, task, vdso};
+
+global_asm!(concat!(
+    crate::arch::asm_prelude!(),
+    include_str!("user/elf.s"),
+    include_str!("user/hello.s"),
+    include_str!("user/init.s"),
+    include_str!("user/sigreturn.s"),
+));
+
+extern "C" {
+    static user_hello_start: u8;
+    static user_hello_end: u8;
+    static user_init_start: u8;
+    static user_init_end: u8;
+    static user_sigreturn_start: u8;
+    static user_sigreturn_end: u8;
+}
+
+/// The names of the programs built into the kernel.
+pub const PROGRAMS: &[&str] = &["hello", "init"];
+
+/// The top of the user stack, and its size. Its pages are allocated as
+/// they're used. On RV32 user memory ends at 1 GiB, so it's lower there.
+#[cfg(target_arch = "riscv64")]
+pub const STACK_TOP: usize = 0x4000_0000;
+#[cfg(target_arch = "riscv32")]
+pub const STACK_TOP: usize = 0x3f00_0000;
+pub const STACK_SIZE: usize = 64 * PAGE_SIZE;
+
+/// Where the code signal handlers return to is mapped, just above the
+/// stack.
+pub const SIGRETURN_TRAMPOLINE: usize = STACK_TOP;
+
+/// `mmap` places mappings below here, leaving a gap under the stack.
+pub const MMAP_TOP: usize = STACK_TOP - 2 * STACK_SIZE;
+
+/// Auxiliary vector entry types.
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_BASE: usize = 7;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+/// Space left at the top of the kernel stack by `enter`, where trap.s keeps
+/// the kernel's `tp` while in U-mode.
+const KERNEL_STACK_RESERVED: usize = 16;
+
+/// SAFETY: `start` and `end` must delimit data in the kernel image.
+unsafe fn image_between(start: *const u8, end: *const u8) -> &'static [u8] {
+    core::slice::from_raw_parts(start, end as usize - start as usize)
+}
+
+/// The ELF image of the built-in program called `name`.
+pub fn program(name: &str) -> Option<&'static [u8]> {
+    let (start, end) = match name {
+        "hello" => (&raw const user_hello_start, &raw const user_hello_end),
+        "init" => (&raw const user_init_start, &raw const user_init_end),
+        _ => return None,
+    };
+    // SAFETY: the symbols delimit the program.
+    Some(unsafe { image_between(start, end) })
+}
+
+/// Starts a process with no parent running the ELF executable `image`.
+/// Returns `None` if it can't be loaded.
+pub fn run(image: &[u8]) -> Option<Pid> {
+    let process = Process::new(None)?;
+    let frame = load(&process.address_space(), image).ok()?;
+    // SAFETY: `load` mapped the code and stack.
+    unsafe { process.spawn_thread(frame, None) }?;
+    Some(process.pid())
+}
+
+/// Loads the ELF executable `image` into the empty `space` and maps a stack
+/// and the signal trampoline, returning the registers to enter it with.
+pub fn load(space: &AddressSpace, image: &[u8]) -> Result<TrapFrame, ElfError> {
+    let loaded = elf::load(space, image)?;
+    space.init_brk(align_up(loaded.end, PAGE_SIZE));
+    space
+        .map_lazy(
+            STACK_TOP - STACK_SIZE,
+            STACK_SIZE,
+            PteFlags::R | PteFlags::W,
+        )
+        .map_err(ElfError::Map)?;
+
+    // SAFETY: the symbols delimit the trampoline's code.
+    let trampoline = unsafe {
+        image_between(
+            &raw const user_sigreturn_start,
+            &raw const user_sigreturn_end,
+        )
+    };
+    space
+        .map_zeroed(SIGRETURN_TRAMPOLINE, PAGE_SIZE, PteFlags::R | PteFlags::X)
+        .map_err(ElfError::Map)?;
+    space.write(SIGRETURN_TRAMPOLINE, trampoline);
+    vdso::map(space).map_err(ElfError::Map)?;
+
+    let sp = initial_stack(space, &loaded)?;
+    Ok(initial_frame(loaded.entry, sp))
+}
+
+/// Writes the initial stack at the top of the stack in `space`, returning
+/// the stack pointer: `argc`, the NULL ending `argv`, the NULL ending
+/// `envp` and the auxiliary vector, below the 16 random bytes `AT_RANDOM`
+/// points to. Arguments aren't passed yet, so `argc` is 0.
+fn initial_stack(space: &AddressSpace, loaded: &elf::Loaded) -> Result<usize, ElfError> {
+    space
+        .handle_fault(STACK_TOP - PAGE_SIZE, Access::Write)
+        .map_err(|err| match err {
+            FaultError::OutOfMemory => ElfError::Map(MapError::OutOfMemory),
+            _ => ElfError::BadSegment,
+        })?;
+    let mut random = [0; 16];
+    rand::fill_bytes(&mut random);
+    let random_addr = STACK_TOP - random.len();
+
+    #[rustfmt::skip]
+    let words = [
+        0, 0, 0,
+        AT_PHDR, loaded.phdr,
+        AT_PHENT, loaded.phent,
+        AT_PHNUM, loaded.phnum,
+        AT_PAGESZ, PAGE_SIZE,
+        // There's no interpreter, whose base this would be.
+        AT_BASE, 0,
+        AT_ENTRY, loaded.entry,
+        AT_RANDOM, random_addr,
+        AT_NULL, 0,
+    ];
+    let mut bytes = Vec::with_capacity(size_of_val(&words));
+    for word in words {
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+    let sp = align_down(random_addr - bytes.len(), 16);
+    if !space.write(random_addr, &random) || !space.write(sp, &bytes) {
+        return Err(ElfError::BadSegment);
+    }
+    Ok(sp)
+}
+
+/// Registers to start running at `pc` with stack pointer `sp`, others zero.
+pub fn initial_frame(pc: usize, sp: usize) -> TrapFrame {
+    let mut regs = [0; 32];
+    regs[2] = sp;
+    TrapFrame {
+        regs,
+        sstatus: 0,
+        sepc: pc,
+        scause: 0,
+        stval: 0,
+    }
+}
+
+/// Drops into U-mode with the registers in `frame`, other than `sstatus`,
+/// discarding the rest of this task's kernel stack.
+///
+/// SAFETY: the running task's address space must map the code and stack.
+pub unsafe fn enter(mut frame: TrapFrame) -> ! {
+    let top = task::stack_top().expect("boot threads can't enter U-mode");
+    let dst = (top - KERNEL_STACK_RESERVED - size_of::<TrapFrame>()) as *mut TrapFrame;
+    let current_sp: usize;
+    asm!("mv {}, sp", out(reg) current_sp);
+    assert!(
+        current_sp < dst as usize,
+        "kernel stack in use by the frame"
+    );
+
+    trap::disable_interrupts();
+    // Return to U-mode with interrupts enabled once there.
+    let sstatus = csr::sstatus::read() | Sstatus::SPIE;
+    frame.sstatus = (sstatus & !Sstatus::SPP & !Sstatus::SIE).bits();
+    dst.write(frame);
+    asm!("mv sp, {}", "j trap_return", in(reg) dst, options(noreturn));
+}
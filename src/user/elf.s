@@ -0,0 +1,65 @@
+# Macros wrapping a built-in user program in a minimal ELF executable for
+# the kernel's XLEN: a single read-only, executable segment loaded at
+# USER_TEXT_BASE, holding the headers followed by the code. Programs are
+# assembled without relaxation, so they don't rely on gp.
+
+.equ USER_TEXT_BASE, 0x10000
+
+.macro elf_begin name
+.section .rodata.user_\name, "a"
+.balign 8
+.global user_\name\()_start
+.global user_\name\()_end
+user_\name\()_start:
+.if REGBYTES == 8
+    # e_ident: magic, ELFCLASS64, ELFDATA2LSB, EV_CURRENT
+    .byte 0x7f, 0x45, 0x4c, 0x46, 2, 1, 1, 0
+    .zero 8
+    # e_type = ET_EXEC, e_machine = EM_RISCV, e_version
+    .half 2, 243
+    .word 1
+    # e_entry, e_phoff, e_shoff
+    .quad USER_TEXT_BASE + (.Luser_\name\()_text - user_\name\()_start)
+    .quad .Luser_\name\()_phdr - user_\name\()_start
+    .quad 0
+    # e_flags, e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum,
+    # e_shstrndx
+    .word 0
+    .half 64, 56, 1, 0, 0, 0
+.Luser_\name\()_phdr:
+    # p_type = PT_LOAD, p_flags = PF_R | PF_X
+    .word 1, 5
+    # p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_align
+    .quad 0, USER_TEXT_BASE, USER_TEXT_BASE
+    .quad user_\name\()_end - user_\name\()_start
+    .quad user_\name\()_end - user_\name\()_start
+    .quad 0x1000
+.else
+    # As above, with ELFCLASS32 and the 32-bit layout
+    .byte 0x7f, 0x45, 0x4c, 0x46, 1, 1, 1, 0
+    .zero 8
+    .half 2, 243
+    .word 1
+    .word USER_TEXT_BASE + (.Luser_\name\()_text - user_\name\()_start)
+    .word .Luser_\name\()_phdr - user_\name\()_start
+    .word 0
+    .word 0
+    .half 52, 32, 1, 0, 0, 0
+.Luser_\name\()_phdr:
+    # p_type, p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_flags,
+    # p_align
+    .word 1, 0, USER_TEXT_BASE, USER_TEXT_BASE
+    .word user_\name\()_end - user_\name\()_start
+    .word user_\name\()_end - user_\name\()_start
+    .word 5, 0x1000
+.endif
+.balign 4
+.Luser_\name\()_text:
+    .option push
+    .option norelax
+.endm
+
+.macro elf_end name
+    .option pop
+user_\name\()_end:
+.endm
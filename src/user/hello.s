@@ -0,0 +1,41 @@
+# A test program for U-mode: prints a greeting three times, sleeping and
+# yielding in between, then exits.
+elf_begin hello
+    li s0, 3
+1:
+    # write(1, msg, len)
+    li a0, 1
+    lla a1, 2f
+    lla a2, 3f
+    sub a2, a2, a1
+    li a7, 64
+    ecall
+
+    # nanosleep(&500ms, NULL), with the 64-bit fields stored in halves
+    addi sp, sp, -16
+    sw zero, 0(sp)
+    sw zero, 4(sp)
+    li t0, 500000000
+    sw t0, 8(sp)
+    sw zero, 12(sp)
+    mv a0, sp
+    li a1, 0
+    li a7, 101
+    ecall
+    addi sp, sp, 16
+
+    # sched_yield()
+    li a7, 124
+    ecall
+
+    addi s0, s0, -1
+    bnez s0, 1b
+
+    # exit(0)
+    li a0, 0
+    li a7, 93
+    ecall
+2:
+    .ascii "hello from user mode\n"
+3:
+elf_end hello
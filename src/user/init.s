@@ -0,0 +1,55 @@
+# Forks, runs hello in the child, and exits with the child's exit status
+# once it has finished.
+elf_begin init
+    # write(1, msg, len)
+    li a0, 1
+    lla a1, .Linit_banner
+    lla a2, .Linit_banner_end
+    sub a2, a2, a1
+    li a7, 64
+    ecall
+
+    # clone(SIGCHLD, 0), i.e. fork()
+    li a0, 17
+    li a1, 0
+    li a7, 220
+    ecall
+    bltz a0, .Linit_fail
+    beqz a0, .Linit_child
+
+    # wait4(pid, &status, 0, NULL)
+    addi sp, sp, -16
+    mv a1, sp
+    li a2, 0
+    li a3, 0
+    li a7, 260
+    ecall
+    lw t0, 0(sp)
+    addi sp, sp, 16
+    bltz a0, .Linit_fail
+
+    # exit(WEXITSTATUS(status))
+    srli a0, t0, 8
+    andi a0, a0, 0xff
+    li a7, 93
+    ecall
+
+.Linit_child:
+    # execve("hello", NULL, NULL)
+    lla a0, .Linit_path
+    li a1, 0
+    li a2, 0
+    li a7, 221
+    ecall
+.Linit_fail:
+    # exit(127)
+    li a0, 127
+    li a7, 93
+    ecall
+
+.Linit_banner:
+    .ascii "init: running hello\n"
+.Linit_banner_end:
+.Linit_path:
+    .asciz "hello"
+elf_end init
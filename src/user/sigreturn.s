@@ -0,0 +1,11 @@
+# Signal handlers return here, see src/signal.rs. It's mapped into every
+# user address space at SIGRETURN_TRAMPOLINE.
+.section .rodata.user_sigreturn, "a"
+.balign 4
+.global user_sigreturn_start
+.global user_sigreturn_end
+user_sigreturn_start:
+    # rt_sigreturn()
+    li a7, 139
+    ecall
+user_sigreturn_end:
@@ -0,0 +1,72 @@
+# The vDSO's code, see src/vdso.rs. It's mapped into every user address
+# space at vdso::CODE, with the data page straight after it.
+.section .rodata.user_vdso, "a"
+.balign 4
+.global user_vdso_start
+.global user_vdso_end
+user_vdso_start:
+# int clock_gettime(clockid_t clock, struct timespec *tp), at the start of
+# the page. The division needs 64-bit registers, so RV32 always takes the
+# syscall.
+.if REGBYTES == 8
+    # The data page, as this is the first instruction of the code page.
+    auipc t0, 1
+    # t6 is set for the realtime clocks, which add the realtime at boot.
+    # Clocks other than these and the monotonic ones take the syscall.
+    li t6, 1
+    beqz a0, 1f                 # CLOCK_REALTIME
+    li t1, 5                    # CLOCK_REALTIME_COARSE
+    beq a0, t1, 1f
+    li t6, 0
+    li t1, 1                    # CLOCK_MONOTONIC
+    beq a0, t1, 1f
+    li t1, 4                    # CLOCK_MONOTONIC_RAW
+    beq a0, t1, 1f
+    li t1, 6                    # CLOCK_MONOTONIC_COARSE
+    beq a0, t1, 1f
+    li t1, 7                    # CLOCK_BOOTTIME
+    beq a0, t1, 1f
+    j 3f
+1:
+    # The sequence number is odd while the kernel updates the page, and
+    # changes if it did while it was read.
+    ld t1, 0(t0)
+    andi t2, t1, 1
+    bnez t2, 1b
+    fence r, r
+    ld t3, 8(t0)                # frequency
+    ld t4, 16(t0)               # realtime at boot, in nanoseconds
+    csrr t5, time
+    fence r, r
+    ld t2, 0(t0)
+    bne t1, t2, 1b
+    # The page hasn't been filled in.
+    beqz t3, 3f
+
+    # ticks / frequency seconds and (ticks % frequency) * 10^9 / frequency
+    # nanoseconds, as the kernel counts them.
+    li a2, 1000000000
+    divu t1, t5, t3
+    remu t2, t5, t3
+    mul t2, t2, a2
+    divu t2, t2, t3
+    beqz t6, 2f
+    divu a3, t4, a2
+    remu a4, t4, a2
+    add t1, t1, a3
+    add t2, t2, a4
+    bltu t2, a2, 2f
+    sub t2, t2, a2
+    addi t1, t1, 1
+2:
+    sd t1, 0(a1)
+    sd t2, 8(a1)
+    li a0, 0
+    ret
+3:
+.endif
+    # clock_gettime(clock, tp)
+    li a7, 113
+    ecall
+    ret
+user_vdso_end:
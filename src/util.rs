@@ -0,0 +1,100 @@
+//! Small helpers with nowhere better to go.
+
+use core::fmt;
+
+use crate::ktest;
+
+pub const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+pub const fn align_down(value: usize, align: usize) -> usize {
+    value & !(align - 1)
+}
+
+/// Bytes displayed as a hexdump, 16 to a line: the address, the bytes in
+/// hex, and the bytes that are printable as ASCII.
+pub struct Hexdump<'a> {
+    addr: usize,
+    bytes: &'a [u8],
+}
+
+/// Formats `bytes` as a hexdump, labelling them as starting at `addr`,
+/// which could be where they are in memory or an offset into something
+/// bigger. Lines start at multiples of 16. There's no newline after the
+/// last line.
+pub fn hexdump(addr: usize, bytes: &[u8]) -> Hexdump<'_> {
+    Hexdump { addr, bytes }
+}
+
+impl fmt::Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Counted from the start of the first line rather than as addresses,
+        // which could run off the top of memory.
+        let first = align_down(self.addr, 16);
+        let skip = self.addr - first;
+        let span = skip + self.bytes.len();
+        // Offsets get short labels, addresses long ones.
+        let last = first + span.max(1) - 1;
+        let width = if last > u32::MAX as usize { 16 } else { 8 };
+        let byte = |offset: usize| {
+            offset
+                .checked_sub(skip)
+                .and_then(|i| self.bytes.get(i))
+                .copied()
+        };
+        for (i, line) in (0..span).step_by(16).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:0width$x}:", first + line, width = width)?;
+            for offset in line..line + 16 {
+                match byte(offset) {
+                    Some(b) => write!(f, " {:02x}", b)?,
+                    None => f.write_str("   ")?,
+                }
+            }
+            f.write_str("  ")?;
+            for offset in (line..line + 16).take_while(|&offset| offset < span) {
+                let c = match byte(offset) {
+                    Some(c) if c.is_ascii_graphic() || c == b' ' => c as char,
+                    Some(_) => '.',
+                    None => ' ',
+                };
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+ktest! {
+    fn formats_hexdumps() {
+        use alloc::format;
+
+        let blank = |n: usize| " ".repeat(n);
+        assert_eq!(
+            format!("{}", hexdump(0x1e, b"ab\0c")),
+            format!(
+                "00000010:{} 61 62  {}ab\n00000020: 00 63{}  .c",
+                blank(3 * 14),
+                blank(14),
+                blank(3 * 14)
+            )
+        );
+        // Right up against the top of memory, whose addresses are as wide as
+        // a register.
+        let width = 2 * core::mem::size_of::<usize>();
+        assert_eq!(
+            format!("{}", hexdump(usize::MAX - 7, b"ABCDEFGH")),
+            format!(
+                "{:0width$x}:{} 41 42 43 44 45 46 47 48  {}ABCDEFGH",
+                usize::MAX & !0xf,
+                blank(3 * 8),
+                blank(8),
+                width = width
+            )
+        );
+        assert_eq!(format!("{}", hexdump(0x20, b"")), "");
+    }
+}
@@ -0,0 +1,113 @@
+//! A pair of pages mapped into every process so that `clock_gettime` can
+//! be answered without trapping into the kernel, as Linux's vDSO does.
+//!
+//! The code page at `CODE` starts with a function taking the syscall's
+//! arguments and returning what it would. It reads the `time` CSR, which
+//! processes may read unless `perf` takes it away, and converts it with
+//! what the kernel keeps in the read-only data page straight after it:
+//!
+//! | offset | contents                                       |
+//! |--------|------------------------------------------------|
+//! | 0      | sequence number, odd while being updated       |
+//! | 8      | frequency of the `time` CSR in Hz              |
+//! | 16     | the realtime clock at boot, in ns since the epoch |
+//!
+//! Clocks it doesn't know, and RV32, fall back to the syscall.
+
+use alloc::sync::Arc;
+use core::arch::global_asm;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::mm::addr_space::AddressSpace;
+use crate::mm::paging::{MapError, PteFlags, PAGE_SIZE};
+use crate::mm::phys_to_virt;
+use crate::mm::shm::SharedMemory;
+use crate::sync::{Once, SpinLockIrqSave};
+use crate::{ktest, time, user};
+
+global_asm!(concat!(
+    crate::arch::asm_prelude!(),
+    include_str!("user/vdso.s"),
+));
+
+extern "C" {
+    static user_vdso_start: u8;
+    static user_vdso_end: u8;
+}
+
+/// Where the code page is mapped, above the signal trampoline, and where
+/// `clock_gettime` is.
+pub const CODE: usize = user::SIGRETURN_TRAMPOLINE + PAGE_SIZE;
+/// Where the data page is mapped.
+pub const DATA: usize = CODE + PAGE_SIZE;
+
+/// The index of each `u64` in the data page.
+const SEQUENCE: usize = 0;
+const FREQUENCY: usize = 1;
+const BOOT_REALTIME: usize = 2;
+
+static PAGE: Once<Arc<SharedMemory>> = Once::new();
+/// Held while the data page is updated, so updates don't interleave.
+static UPDATING: SpinLockIrqSave<()> = SpinLockIrqSave::new(());
+
+/// Fills in the data page, once the timebase frequency is known.
+pub fn init() {
+    PAGE.call_once(SharedMemory::new);
+    update();
+}
+
+/// Brings the data page up to date with the kernel's clocks, after the
+/// realtime clock is set.
+pub fn update() {
+    let Some(paddr) = PAGE.get().and_then(|page| page.frame(0)) else {
+        return;
+    };
+    let words = phys_to_virt(paddr) as *mut u64;
+    let _guard = UPDATING.lock();
+    // SAFETY: the frame is the data page's, which only this writes to.
+    unsafe {
+        let sequence = words.add(SEQUENCE).read_volatile();
+        words.add(SEQUENCE).write_volatile(sequence + 1);
+        fence(Ordering::Release);
+        words.add(FREQUENCY).write_volatile(time::frequency());
+        words
+            .add(BOOT_REALTIME)
+            .write_volatile(time::boot_realtime().as_nanos() as u64);
+        fence(Ordering::Release);
+        words.add(SEQUENCE).write_volatile(sequence + 2);
+    }
+}
+
+/// Maps the code and data pages into `space`.
+pub fn map(space: &AddressSpace) -> Result<(), MapError> {
+    // SAFETY: the symbols delimit the code.
+    let code = unsafe {
+        let start = &raw const user_vdso_start;
+        let end = &raw const user_vdso_end;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+    space.map_zeroed(CODE, PAGE_SIZE, PteFlags::R | PteFlags::X)?;
+    space.write(CODE, code);
+    if let Some(page) = PAGE.get() {
+        space.map_shared(DATA, PAGE_SIZE, PteFlags::R, page.clone())?;
+    }
+    Ok(())
+}
+
+ktest! {
+    fn data_page_holds_the_clocks() {
+        let space = AddressSpace::new().unwrap();
+        map(&space).unwrap();
+        update();
+        let mut words = [0; 3 * size_of::<u64>()];
+        assert!(space.debug_read(DATA, &mut words));
+        let word = |i: usize| u64::from_le_bytes(words[i * 8..i * 8 + 8].try_into().unwrap());
+        assert_eq!(word(SEQUENCE) % 2, 0);
+        assert_eq!(word(FREQUENCY), time::frequency());
+        assert_eq!(word(BOOT_REALTIME), time::boot_realtime().as_nanos() as u64);
+        let mut code = [0; 4];
+        assert!(space.debug_read(CODE, &mut code));
+        // SAFETY: the code is longer than an instruction.
+        assert_eq!(code, unsafe { *(&raw const user_vdso_start as *const [u8; 4]) });
+    }
+}
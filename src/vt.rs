@@ -0,0 +1,280 @@
+//! Virtual consoles, multiplexed over the console's screens and input, so
+//! that the kernel log and interactive programs needn't share one screen.
+//! Console 0 is the kernel log, along with `/dev/console`'s terminal, and
+//! consoles 1 to 3 are terminals of their own, `/dev/tty1` to `/dev/tty3`,
+//! each with its own foreground process group (see `tty`).
+//!
+//! Only the console being shown is written to the screens and gets input.
+//! The others keep what's written to them, the kernel log for console 0
+//! and the last `SCROLLBACK` bytes for the others, which is drawn again
+//! when they're switched to. A panic is written to the screens whichever
+//! console is shown.
+//!
+//! Typing Ctrl-] then a console's number switches to it, as does the `vt`
+//! shell command and the `VT_ACTIVATE` ioctl, which take the number too.
+//! Typing Ctrl-] twice types it once.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::file::File;
+use crate::fs::devfs::{self, CharDevice};
+use crate::process::Pid;
+use crate::sync::{SpinLock, WaitQueue};
+use crate::syscall::Errno;
+use crate::tty::{self, Device, Tty};
+use crate::{io, ktest, log_error, workqueue};
+
+/// How many virtual consoles there are, counting the kernel log's.
+const COUNT: usize = 4;
+
+/// How many bytes of output each console other than the kernel log's
+/// keeps to draw again.
+const SCROLLBACK: usize = 16 * 1024;
+
+/// Typed before a console's number to switch to it.
+const SWITCH_KEY: u8 = 0x1d;
+
+/// `ioctl` request to switch consoles.
+const VT_ACTIVATE: usize = 0x5606;
+
+/// The console being shown.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Held while switching, so switches happen one at a time.
+static SWITCH: SpinLock<()> = SpinLock::new(());
+
+/// Set once `SWITCH_KEY` is typed, until the next key.
+static SWITCH_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Woken when another console is shown, for readers of those that aren't.
+static SWITCHED: WaitQueue = WaitQueue::new();
+
+/// A virtual console other than the kernel log's.
+struct Vt {
+    tty: Tty,
+    scrollback: SpinLock<VecDeque<u8>>,
+}
+
+static VTS: [Vt; COUNT - 1] = [const {
+    Vt {
+        tty: Tty::new(),
+        scrollback: SpinLock::new(VecDeque::new()),
+    }
+}; COUNT - 1];
+
+/// Console `n`, which isn't the kernel log's.
+fn vt(n: usize) -> &'static Vt {
+    &VTS[n - 1]
+}
+
+/// The console being shown.
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Shows console `n` on the screens, clearing them and drawing what it
+/// kept, and gives it the input from now on.
+pub fn switch(n: usize) -> Result<(), Errno> {
+    if n >= COUNT {
+        return Err(Errno::EINVAL);
+    }
+    let _switch = SWITCH.lock();
+    if active() == n {
+        return Ok(());
+    }
+    // Held until the console is active, so nothing it writes meanwhile is
+    // missed or written twice.
+    let scrollback = (n != 0).then(|| vt(n).scrollback.lock());
+    io::with_console(|console| {
+        console.show_log(n == 0);
+        console.clear();
+        match &scrollback {
+            None => console.replay_log(),
+            Some(scrollback) => {
+                let (front, back) = scrollback.as_slices();
+                console.write_bytes(front);
+                console.write_bytes(back);
+            }
+        }
+    });
+    ACTIVE.store(n, Ordering::Release);
+    drop(scrollback);
+    wake_readers();
+    Ok(())
+}
+
+/// Writes `bytes` to console `n`, which isn't the kernel log's, keeping
+/// them to draw again and writing them to the screens if it's shown.
+fn write(n: usize, bytes: &[u8]) {
+    let mut scrollback = vt(n).scrollback.lock();
+    scrollback.extend(bytes);
+    let excess = scrollback.len().saturating_sub(SCROLLBACK);
+    scrollback.drain(..excess);
+    if active() == n {
+        io::with_console(|console| console.write_bytes(bytes));
+    }
+}
+
+/// The next byte of input for console `n`, if it's shown and some has
+/// arrived.
+pub fn read_byte(n: usize) -> Option<u8> {
+    if active() != n {
+        return None;
+    }
+    io::read_byte()
+}
+
+/// Waits for the next byte of input for console `n`, waiting to be
+/// switched to if it isn't shown, unless `stop` returns true first.
+pub fn wait_byte_unless(n: usize, stop: &mut dyn FnMut() -> bool) -> Option<u8> {
+    loop {
+        if active() == n {
+            if let Some(byte) = io::wait_byte_unless(|| stop() || active() != n) {
+                return Some(byte);
+            }
+        } else {
+            SWITCHED.wait_until(|| active() == n || stop());
+        }
+        if stop() {
+            return None;
+        }
+    }
+}
+
+/// Wakes the tasks waiting for input for any console.
+pub fn wake_readers() {
+    io::wake_readers();
+    SWITCHED.wake_all();
+}
+
+/// Called with each byte of console input as it arrives, maybe in
+/// interrupt context. Returns whether it was taken, to switch consoles or
+/// to signal the shown console's foreground group, so isn't to be read.
+pub fn intercept(byte: u8) -> bool {
+    if SWITCH_PENDING.swap(false, Ordering::AcqRel) {
+        if byte != SWITCH_KEY {
+            let n = usize::from(byte.wrapping_sub(b'0'));
+            if n < COUNT {
+                workqueue::queue(move || {
+                    let _ = switch(n);
+                });
+            }
+            return true;
+        }
+    } else if byte == SWITCH_KEY {
+        SWITCH_PENDING.store(true, Ordering::Release);
+        return true;
+    }
+    match active() {
+        0 => tty::intercept(byte),
+        n => {
+            let Some((pgid, signal)) = vt(n).tty.signal_for(byte) else {
+                return false;
+            };
+            workqueue::queue(move || vt(n).tty.interrupt(&VtDevice(n), byte, pgid, signal));
+            true
+        }
+    }
+}
+
+/// Carries out the `ioctl` `request` if it's one any virtual console
+/// takes, or returns `None`.
+pub fn ioctl(request: usize, arg: usize) -> Option<Result<usize, Errno>> {
+    match request {
+        VT_ACTIVATE => Some(switch(arg).map(|()| 0)),
+        _ => None,
+    }
+}
+
+/// Whether a console other than the kernel log's is the controlling
+/// terminal of session `sid`.
+pub fn has_controlling(sid: Pid) -> bool {
+    VTS.iter().any(|vt| vt.tty.session() == Some(sid))
+}
+
+/// Releases the console, other than the kernel log's, that's the
+/// controlling terminal of session `sid`, whose leader is exiting.
+pub fn session_ended(sid: Pid) {
+    for n in 1..COUNT {
+        vt(n).tty.release(&VtDevice(n), sid);
+    }
+}
+
+/// Console `n`, other than the kernel log's, as its terminal sees it.
+#[derive(Clone)]
+struct VtDevice(usize);
+
+impl Device for VtDevice {
+    fn read_byte(&self) -> Option<u8> {
+        read_byte(self.0)
+    }
+
+    fn wait_byte_unless(&self, stop: &mut dyn FnMut() -> bool) -> Option<u8> {
+        wait_byte_unless(self.0, stop)
+    }
+
+    fn wake_readers(&self) {
+        wake_readers();
+    }
+
+    fn write(&self, bytes: &[u8]) -> Result<usize, Errno> {
+        write(self.0, bytes);
+        Ok(bytes.len())
+    }
+
+    fn echo(&self, bytes: &[u8]) {
+        write(self.0, bytes);
+    }
+
+    fn ends_lines(&self) -> bool {
+        true
+    }
+}
+
+/// `/dev/tty<n>`.
+struct VtFile(usize);
+
+impl File for VtFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        vt(self.0).tty.read(&VtDevice(self.0), buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        vt(self.0).tty.write(&VtDevice(self.0), buf)
+    }
+
+    fn ioctl(&self, request: usize, arg: usize) -> Result<usize, Errno> {
+        ioctl(request, arg).unwrap_or_else(|| vt(self.0).tty.ioctl(&VtDevice(self.0), request, arg))
+    }
+}
+
+/// Adds `/dev/tty1` and so on for the consoles other than the kernel
+/// log's.
+pub fn init() {
+    for n in 1..COUNT {
+        let name = format!("tty{}", n);
+        if let Err(err) = devfs::register(&name, CharDevice::new(Arc::new(VtFile(n)))) {
+            log_error!(target: "vt", "couldn't add /dev/{}: {}", name, err);
+        }
+    }
+}
+
+ktest! {
+    fn hidden_consoles_keep_their_scrollback() {
+        let n = COUNT - 1;
+        // Hidden, so it gets no input and writes only to its scrollback.
+        assert_eq!(read_byte(n), None);
+        let line = [b'x'; 1000];
+        for _ in 0..SCROLLBACK / line.len() + 1 {
+            write(n, &line);
+        }
+        write(n, b"end");
+        let mut scrollback = vt(n).scrollback.lock();
+        assert_eq!(scrollback.len(), SCROLLBACK);
+        assert!(scrollback.iter().rev().take(3).eq(b"dne".iter()));
+        scrollback.clear();
+    }
+}
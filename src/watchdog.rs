@@ -0,0 +1,172 @@
+//! A watchdog for stalled harts. Every timer tick is a heartbeat of the
+//! hart it's on, and two kinds of stall are looked for:
+//!
+//! - A hart whose heartbeat stops has had interrupts disabled for too long.
+//!   The watchdog task, on the boot hart, notices and asks the hart for a
+//!   backtrace, which it prints if it's spinning on a lock, as it most
+//!   likely is, or once it takes an interrupt again.
+//! - A hart that still ticks but hasn't been preemptible for too long is
+//!   holding a lock for too long. Its tick notices and prints a backtrace
+//!   of the code it interrupted.
+//!
+//! Each stall is reported once. A stall of the boot hart with interrupts
+//! disabled isn't noticed, as the watchdog can't run. `nowatchdog` on the
+//! command line turns it off.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::arch::csr::{Bits, Sstatus};
+use crate::arch::AtomicU64;
+use crate::sbi::timer::TICK_HZ;
+use crate::smp::MAX_HARTS;
+use crate::task::{self, Priority};
+use crate::trap::TrapFrame;
+use crate::{backtrace, cmdline, ktest, log_error, log_warn, percpu, print, smp};
+
+/// How long a hart can go without a tick, or without being preemptible,
+/// before it's reported.
+const STALL_SECS: u64 = 10;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Each hart's ticks so far.
+static HEARTBEATS: [AtomicU64; MAX_HARTS] = [const { AtomicU64::new(0) }; MAX_HARTS];
+
+/// The mask of harts asked for a backtrace, having stopped ticking.
+static DUMP: AtomicUsize = AtomicUsize::new(0);
+
+percpu! {
+    /// How many ticks in a row have interrupted non-preemptible code.
+    static HELD_TICKS: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Starts the watchdog on this hart, once it runs tasks.
+pub fn init() {
+    if cmdline::has("nowatchdog") {
+        return;
+    }
+    // It runs at high priority so that busy tasks can't hold it off.
+    if task::spawn_on(percpu::hart_id(), Priority::High, watchdog).is_none() {
+        log_warn!(target: "watchdog", "no memory for the watchdog");
+        return;
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// What the watchdog task knows of a hart's heartbeat.
+#[derive(Clone, Copy, Default)]
+struct Pulse {
+    /// The heartbeat when last checked.
+    last: u64,
+    /// How many checks in a row it's been the same.
+    missed: u64,
+}
+
+impl Pulse {
+    /// Notes the hart's heartbeat, a second after the last check, returning
+    /// true once it's been the same for `STALL_SECS`.
+    fn check(&mut self, heartbeat: u64) -> bool {
+        if heartbeat != self.last {
+            *self = Pulse {
+                last: heartbeat,
+                missed: 0,
+            };
+            return false;
+        }
+        self.missed += 1;
+        self.missed == STALL_SECS
+    }
+}
+
+fn watchdog() {
+    // Counting checks rather than time means a machine stopped in a
+    // debugger isn't taken for stalled.
+    let mut pulses = [Pulse::default(); MAX_HARTS];
+    loop {
+        task::sleep_with_slack(Duration::from_secs(1), Duration::from_millis(100));
+        let online = smp::online_mask();
+        for hart in (0..MAX_HARTS).filter(|hart| online & (1 << hart) != 0) {
+            if pulses[hart].check(HEARTBEATS[hart].load(Ordering::Relaxed)) {
+                log_error!(
+                    target: "watchdog",
+                    "hart {} stalled: no timer interrupts for {} s",
+                    hart,
+                    STALL_SECS
+                );
+                DUMP.fetch_or(1 << hart, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Called on every timer tick, from interrupt context, with the frame of
+/// the code it interrupted.
+pub fn tick(frame: &TrapFrame) {
+    let hart = percpu::hart_id();
+    HEARTBEATS[hart].fetch_add(1, Ordering::Relaxed);
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let from_user = !Sstatus::from_bits(frame.sstatus).contains(Sstatus::SPP);
+
+    if DUMP.fetch_and(!(1 << hart), Ordering::Relaxed) & (1 << hart) != 0 {
+        log_error!(
+            target: "watchdog",
+            "hart {} has interrupts again, at {:#x}",
+            hart,
+            frame.sepc
+        );
+        if !from_user {
+            backtrace::print_from(frame.sepc, frame.regs[8]);
+        }
+    }
+
+    let held = HELD_TICKS.get();
+    if task::preemptible() {
+        held.store(0, Ordering::Relaxed);
+        return;
+    }
+    if held.fetch_add(1, Ordering::Relaxed) + 1 == STALL_SECS * TICK_HZ {
+        log_error!(
+            target: "watchdog",
+            "hart {} stalled: not preemptible for {} s, holding a lock?",
+            hart,
+            STALL_SECS
+        );
+        print!("{}", frame);
+        backtrace::print_from(frame.sepc, frame.regs[8]);
+    }
+}
+
+/// Called while spinning on the lock at `lock`, to print a backtrace if the
+/// watchdog has asked this hart for one.
+pub fn spinning(lock: *const ()) {
+    if DUMP.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let hart = percpu::hart_id();
+    if DUMP.fetch_and(!(1 << hart), Ordering::Relaxed) & (1 << hart) == 0 {
+        return;
+    }
+    log_error!(
+        target: "watchdog",
+        "hart {} is spinning on the lock at {:p}",
+        hart,
+        lock
+    );
+    backtrace::print();
+}
+
+ktest! {
+    fn notices_stopped_heartbeats() {
+        let mut pulse = Pulse::default();
+        assert!(!pulse.check(5));
+        let stalled: usize = (0..2 * STALL_SECS).filter(|_| pulse.check(5)).count();
+        // Reported once, however long it goes on.
+        assert_eq!(stalled, 1);
+        assert!(!pulse.check(6));
+        assert!((1..STALL_SECS).all(|_| !pulse.check(6)));
+        assert!(pulse.check(6));
+    }
+}
@@ -0,0 +1,66 @@
+//! Deferred work. Interrupt handlers queue work to run later on the same
+//! hart, in a worker task, with interrupts enabled.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use crate::smp::MAX_HARTS;
+use crate::sync::{SpinLockIrqSave, WaitQueue};
+use crate::task::{self, Priority};
+use crate::{ktest, log_warn, percpu};
+
+type Work = Box<dyn FnOnce() + Send>;
+
+static QUEUES: [SpinLockIrqSave<VecDeque<Work>>; MAX_HARTS] =
+    [const { SpinLockIrqSave::new(VecDeque::new()) }; MAX_HARTS];
+
+/// Each hart's worker waits here for work to be queued.
+static WORKERS: [WaitQueue; MAX_HARTS] = [const { WaitQueue::new() }; MAX_HARTS];
+
+/// Starts this hart's worker, once it runs tasks. Workers run at high
+/// priority, so queued work is done before returning to normal tasks.
+pub fn init() {
+    if task::spawn_on(percpu::hart_id(), Priority::High, worker).is_none() {
+        log_warn!("no memory for hart {}'s worker", percpu::hart_id());
+    }
+}
+
+/// Queues `work` to run on this hart's worker.
+pub fn queue(work: impl FnOnce() + Send + 'static) {
+    let hart = percpu::hart_id();
+    QUEUES[hart].lock().push_back(Box::new(work));
+    WORKERS[hart].wake_one();
+}
+
+fn worker() {
+    let hart = percpu::hart_id();
+    loop {
+        WORKERS[hart].wait_until(|| !QUEUES[hart].lock().is_empty());
+        // Take one item at a time, so the lock isn't held while it runs.
+        while let Some(work) = QUEUES[hart].lock().pop_front() {
+            work();
+        }
+    }
+}
+
+ktest! {
+    fn runs_queued_work() {
+        use alloc::sync::Arc;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::sync::WaitQueue;
+
+        let hart = Arc::new(AtomicUsize::new(usize::MAX));
+        let done = Arc::new((AtomicUsize::new(0), WaitQueue::new()));
+        for _ in 0..2 {
+            let (hart, done) = (hart.clone(), done.clone());
+            queue(move || {
+                hart.store(percpu::hart_id(), Ordering::Relaxed);
+                done.0.fetch_add(1, Ordering::Release);
+                done.1.wake_all();
+            });
+        }
+        done.1.wait_until(|| done.0.load(Ordering::Acquire) == 2);
+        assert_eq!(hart.load(Ordering::Relaxed), percpu::hart_id());
+    }
+}